@@ -1,15 +1,17 @@
-use std::time::Instant;
+use std::sync::{Arc, RwLock};
+use criterion::{BatchSize, BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
 use rand::Rng;
-use timestamping::storage::{HashStore, Hash512};
+use sha2::{Digest, Sha512};
+use timestamping::storage::{Blake3Hasher, HashStore, Hash512, Hash512Ops, Hasher, LeafEncoding, MerkleTree, MultiThreadedHashStore, Sha512Hasher, salt_batch};
 
-static SALT: Hash512 = [0, 0, 0, 0, 0, 0, 0, 0];
+static SALT: Hash512 = Hash512([0, 0, 0, 0, 0, 0, 0, 0]);
 
 // Generate a random 512-bit hash
 fn generate_random_hash() -> Hash512 {
     let mut rng = rand::thread_rng();
     let mut hash = [0u64; 8];
     rng.fill(&mut hash);
-    hash
+    Hash512(hash)
 }
 
 // Generate a vector of random hashes
@@ -17,110 +19,344 @@ fn generate_random_hashes(count: usize) -> Vec<Hash512> {
     (0..count).map(|_| generate_random_hash()).collect()
 }
 
-// Benchmark hash insertion speed
-fn benchmark_insertion_speed() {
-    println!("=== Hash Insertion Speed Benchmark ===");
-    println!("Testing different store configurations and hash counts\n");
+// Insertion into an empty store of the single-threaded store with different index sizes
+fn bench_insertion(c: &mut Criterion) {
+    let mut group = c.benchmark_group("insertion");
+    group.sample_size(10);
 
-    let test_sizes = vec![
-        10_000,      // 10K hashes
-        100_000,     // 100K hashes
-        500_000,     // 500K hashes
-        1_000_000,   // 1M hashes
-        2_000_000,   // 2M hashes
-    ];
-
-    for size in test_sizes {
-        println!("Testing with {} hashes:", size);
+    for size in [10_000, 100_000, 1_000_000] {
         let hashes = generate_random_hashes(size);
+        group.throughput(Throughput::Elements(size as u64));
+        group.bench_with_input(BenchmarkId::new("16-bit index", size), &hashes, |b, hashes| {
+            b.iter_batched(|| HashStore::<16, 0>::new(SALT), |store| {
+                for hash in hashes {
+                    store.add_hash(*hash).unwrap();
+                }
+                store
+            }, BatchSize::PerIteration);
+        });
+        group.bench_with_input(BenchmarkId::new("20-bit index", size), &hashes, |b, hashes| {
+            b.iter_batched(|| HashStore::<20, 0>::new(SALT), |store| {
+                for hash in hashes {
+                    store.add_hash(*hash).unwrap();
+                }
+                store
+            }, BatchSize::PerIteration);
+        });
+    }
+    group.finish();
+}
 
-        // Test 16-bit index (65,536 buckets)
-        let store_16 = HashStore::<16, 0>::new(SALT);
-        let start = Instant::now();
-        for hash in &hashes {
-            store_16.add_hash(*hash);
-        }
-        let duration_16 = start.elapsed();
-        let hashes_per_second_16 = size as f64 / duration_16.as_secs_f64();
-        println!("  16-bit index: {:.2} hashes/sec ({:.2?})", hashes_per_second_16, duration_16);
-
-        // Test 20-bit index (1,048,576 buckets)
-        let store_20 = HashStore::<20, 0>::new(SALT);
-        let start = Instant::now();
-        for hash in &hashes {
-            store_20.add_hash(*hash);
-        }
-        let duration_20 = start.elapsed();
-        let hashes_per_second_20 = size as f64 / duration_20.as_secs_f64();
-        println!("  20-bit index: {:.2} hashes/sec ({:.2?})", hashes_per_second_20, duration_20);
-
-        // Test 24-bit index (16,777,216 buckets)
-        let store_24 = HashStore::<24, 0>::new(SALT);
-        let start = Instant::now();
-        for hash in &hashes {
-            store_24.add_hash(*hash);
-        }
-        let duration_24 = start.elapsed();
-        let hashes_per_second_24 = size as f64 / duration_24.as_secs_f64();
-        println!("  24-bit index: {:.2} hashes/sec ({:.2?})", hashes_per_second_24, duration_24);
+// Single-threaded store against the worker threads of the multi-threaded store, one hash per call
+fn bench_store_threads(c: &mut Criterion) {
+    let mut group = c.benchmark_group("store threads");
+    group.sample_size(10);
+
+    let size = 100_000;
+    let hashes = generate_random_hashes(size);
+    group.throughput(Throughput::Elements(size as u64));
+    group.bench_function("single-threaded", |b| {
+        b.iter_batched(|| HashStore::<20, 0>::new(SALT), |store| {
+            for hash in &hashes {
+                store.add_hash(*hash).unwrap();
+            }
+            store
+        }, BatchSize::PerIteration);
+    });
+    for num_threads in [1, 4, 8] {
+        group.bench_with_input(BenchmarkId::new("multi-threaded", num_threads), &num_threads, |b, &num_threads| {
+            b.iter_batched(|| MultiThreadedHashStore::<20, 0>::new(num_threads, SALT), |store| {
+                for hash in &hashes {
+                    store.add_hash(*hash).unwrap();
+                }
+                store
+            }, BatchSize::PerIteration);
+        });
+    }
+    group.finish();
+}
+
+// Batches sent to the multi-threaded store, as done by /add
+fn bench_batch_insertion(c: &mut Criterion) {
+    let mut group = c.benchmark_group("batch insertion");
+    group.sample_size(10);
+
+    let size = 100_000;
+    let hashes = generate_random_hashes(size);
+    group.throughput(Throughput::Elements(size as u64));
+    for batch_size in [1, 100, 10_000] {
+        group.bench_with_input(BenchmarkId::from_parameter(batch_size), &batch_size, |b, &batch_size| {
+            b.iter_batched(|| MultiThreadedHashStore::<20, 0>::new(8, SALT), |store| {
+                for batch in hashes.chunks(batch_size) {
+                    store.add_batch(batch).unwrap();
+                }
+                store
+            }, BatchSize::PerIteration);
+        });
+    }
+    group.finish();
+}
 
-        println!();
+// Salting of one large batch before it reaches the workers, on one thread and in parallel
+fn bench_batch_salting(c: &mut Criterion) {
+    let mut group = c.benchmark_group("batch salting");
+    group.sample_size(10);
+
+    let size = 100_000;
+    let hashes = generate_random_hashes(size);
+    group.throughput(Throughput::Elements(size as u64));
+    for threads in [1, 4, 8] {
+        group.bench_with_input(BenchmarkId::from_parameter(threads), &threads, |b, &threads| {
+            b.iter(|| salt_batch(&Sha512Hasher, &hashes, &SALT, LeafEncoding::V1, threads));
+        });
+    }
+    for threads in [1, 8] {
+        group.bench_with_input(BenchmarkId::new("batch insertion", threads), &threads, |b, &threads| {
+            b.iter_batched(|| {
+                let store = MultiThreadedHashStore::<20, 0>::new(8, SALT);
+                store.set_salting_threads(threads);
+                store
+            }, |store| {
+                store.add_batch(&hashes).unwrap();
+                store
+            }, BatchSize::PerIteration);
+        });
     }
+    group.finish();
 }
 
-// Benchmark lookup performance
-fn benchmark_lookup_performance() {
-    println!("=== Lookup Performance Benchmark ===");
-    println!("Testing hash lookup speed after insertion\n");
+// Concurrent insertion into a single store from several threads
+fn bench_concurrent_insertion(c: &mut Criterion) {
+    let mut group = c.benchmark_group("concurrent insertion");
+    group.sample_size(10);
 
+    let size = 1_000_000;
+    let hashes = Arc::new(generate_random_hashes(size));
+    group.throughput(Throughput::Elements(size as u64));
+    for num_threads in [1, 2, 4, 8] {
+        group.bench_with_input(BenchmarkId::from_parameter(num_threads), &num_threads, |b, &num_threads| {
+            b.iter_batched(|| Arc::new(HashStore::<20, 0>::new(SALT)), |store| {
+                let chunk_size = size / num_threads;
+                let handles: Vec<_> = (0..num_threads).map(|i| {
+                    let store = Arc::clone(&store);
+                    let hashes = Arc::clone(&hashes);
+                    std::thread::spawn(move || {
+                        for hash in &hashes[i * chunk_size..(i + 1) * chunk_size] {
+                            store.add_hash(*hash).unwrap();
+                        }
+                    })
+                }).collect();
+                for handle in handles {
+                    handle.join().unwrap();
+                }
+                store
+            }, BatchSize::PerIteration);
+        });
+    }
+    group.finish();
+}
+
+// Lookups in a filled store, half of them for stored hashes
+fn bench_lookup(c: &mut Criterion) {
     let insert_count = 100_000;
     let lookup_count = 10_000;
 
-    // Insert hashes
     let store = HashStore::<16, 0>::new(SALT);
     let hashes = generate_random_hashes(insert_count);
     for hash in &hashes {
-        store.add_hash(*hash);
+        store.add_hash(*hash).unwrap();
     }
-
-    // Generate lookup hashes (mix of existing and non-existing)
-    let mut lookup_hashes = Vec::new();
     let mut rng = rand::thread_rng();
-    let mut num_existing = 0;
-
-    for _ in 0..lookup_count {
-        if rng.gen_bool(0.5) {
-            // 50% chance to lookup existing hash
-            lookup_hashes.push(hashes[rng.gen_range(0..insert_count)]);
-            num_existing += 1;
-        } else {
-            // 50% chance to lookup non-existing hash
-            lookup_hashes.push(generate_random_hash());
+    let lookup_hashes: Vec<Hash512> = (0..lookup_count)
+        .map(|_| if rng.gen_bool(0.5) { hashes[rng.gen_range(0..insert_count)] } else { generate_random_hash() })
+        .collect();
+
+    let mut group = c.benchmark_group("lookup");
+    group.throughput(Throughput::Elements(lookup_count as u64));
+    group.bench_function("16-bit index", |b| {
+        b.iter(|| lookup_hashes.iter().filter(|hash| store.contains(hash)).count());
+    });
+    group.finish();
+}
+
+// Reference store with the previous bucket layout: one sorted linked list per bucket
+struct LinkedListNode {
+    hash: Hash512,
+    next: Option<Box<LinkedListNode>>,
+}
+
+struct LinkedListHashStore<const INDEX_SIZE: usize> {
+    data: RwLock<Vec<Option<Box<LinkedListNode>>>>,
+    salt: Hash512,
+}
+
+impl<const INDEX_SIZE: usize> LinkedListHashStore<INDEX_SIZE> {
+    fn new(salt: Hash512) -> Self {
+        Self { data: RwLock::new((0..1 << INDEX_SIZE).map(|_| None).collect()), salt }
+    }
+
+    fn salted(&self, hash: &Hash512) -> Hash512 {
+        let mut hasher = Sha512::new();
+        hasher.update(hash.to_bytes());
+        hasher.update(self.salt.to_bytes());
+        Hash512::from_bytes(&hasher.finalize()).unwrap()
+    }
+
+    fn add_hash(&self, hash: Hash512) -> bool {
+        let salted_hash = self.salted(&hash);
+        let mut data = self.data.write().unwrap();
+        let mut current = &mut data[salted_hash.to_index(0, INDEX_SIZE)];
+        while current.as_ref().is_some_and(|node| node.hash < salted_hash) {
+            current = &mut current.as_mut().unwrap().next;
         }
+        if current.as_ref().is_some_and(|node| node.hash == salted_hash) {
+            return false;
+        }
+        let next = current.take();
+        *current = Some(Box::new(LinkedListNode { hash: salted_hash, next }));
+        true
+    }
+
+    fn contains(&self, hash: &Hash512) -> bool {
+        let salted_hash = self.salted(hash);
+        let data = self.data.read().unwrap();
+        let mut current = &data[salted_hash.to_index(0, INDEX_SIZE)];
+        while let Some(node) = current {
+            if node.hash == salted_hash {
+                return true;
+            }
+            current = &node.next;
+        }
+        false
+    }
+}
+
+// Sorted vector buckets of HashStore against linked list buckets on long chains
+fn bench_bucket_layout(c: &mut Criterion) {
+    let size = 200_000;
+    let hashes = generate_random_hashes(size);
+    let lookup_hashes = generate_random_hashes(size);
+
+    // 1,024 buckets, ~200 hashes per bucket
+    let mut group = c.benchmark_group("bucket layout");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(size as u64));
+    group.bench_function("insert linked list", |b| {
+        b.iter_batched(|| LinkedListHashStore::<10>::new(SALT), |store| {
+            for hash in &hashes {
+                store.add_hash(*hash);
+            }
+            store
+        }, BatchSize::PerIteration);
+    });
+    group.bench_function("insert sorted vec", |b| {
+        b.iter_batched(|| HashStore::<10, 0>::new(SALT), |store| {
+            for hash in &hashes {
+                store.add_hash(*hash).unwrap();
+            }
+            store
+        }, BatchSize::PerIteration);
+    });
+
+    let linked_list = LinkedListHashStore::<10>::new(SALT);
+    let sorted_vec = HashStore::<10, 0>::new(SALT);
+    for hash in &hashes {
+        linked_list.add_hash(*hash);
+        sorted_vec.add_hash(*hash).unwrap();
     }
+    group.throughput(Throughput::Elements(2 * size as u64));
+    group.bench_function("lookup linked list", |b| {
+        b.iter(|| lookup_hashes.iter().chain(&hashes).filter(|hash| linked_list.contains(hash)).count());
+    });
+    group.bench_function("lookup sorted vec", |b| {
+        b.iter(|| lookup_hashes.iter().chain(&hashes).filter(|hash| sorted_vec.contains(hash)).count());
+    });
+    group.finish();
+}
 
-    // Benchmark lookups
-    let start = Instant::now();
-    let mut found_count = 0;
-    for hash in &lookup_hashes {
-        if store.contains(hash) {
-            found_count += 1;
+// Merkle tree builds with the available hashers
+fn bench_tree_construction(c: &mut Criterion) {
+    let mut group = c.benchmark_group("tree construction");
+    group.sample_size(10);
+
+    for size in [10_000, 1_000_000] {
+        let leaves = generate_random_hashes(size);
+        group.throughput(Throughput::Elements(size as u64));
+        let hashers: [Arc<dyn Hasher>; 2] = [Arc::new(Sha512Hasher), Arc::new(Blake3Hasher)];
+        for hasher in hashers {
+            group.bench_with_input(BenchmarkId::new(hasher.name(), size), &leaves, |b, leaves| {
+                b.iter_batched(|| leaves.clone(), |leaves| MerkleTree::with_hasher(leaves, SALT, Arc::clone(&hasher)), BatchSize::LargeInput);
+            });
         }
     }
-    let duration = start.elapsed();
-    let lookups_per_second = lookup_count as f64 / duration.as_secs_f64();
-    assert_eq!(num_existing, found_count);
+    group.finish();
+}
 
-    println!("Lookup performance: {:.2} lookups/sec ({:.2?})", lookups_per_second, duration);
-    println!();
+// Field of /proc/self/status in kB, only available on Linux
+fn proc_status_kb(field: &str) -> Option<usize> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with(field))?;
+    line[field.len()..].trim().trim_end_matches("kB").trim().parse().ok()
 }
 
-fn main() {
-    println!("Hash Store Performance Benchmark");
-    println!("================================\n");
+// Trees rebuilt from the workers of a store like `update_merkle_tree` does, printing how far the resident
+// memory peaks above what the store already holds
+fn bench_tree_rebuild(c: &mut Criterion) {
+    let size = 1_000_000;
+    let store = MultiThreadedHashStore::<20, 0>::new(4, SALT);
+    store.add_batch(&generate_random_hashes(size)).unwrap();
 
-    benchmark_insertion_speed();
-    benchmark_lookup_performance();
+    // Writing 5 resets the peak to the current resident memory
+    if std::fs::write("/proc/self/clear_refs", "5").is_ok()
+        && let Some(before) = proc_status_kb("VmRSS:")
+    {
+        let tree = MerkleTree::from_storage(&store).unwrap().0;
+        if let Some(peak) = proc_status_kb("VmHWM:") {
+            println!("tree rebuild of {} leaves: peak resident memory {} MiB above the store, leaves alone {} MiB",
+                tree.leaf_count, (peak - before) / 1024, size * size_of::<Hash512>() / (1 << 20));
+        }
+    }
 
-    println!("Benchmark completed!");
+    let mut group = c.benchmark_group("tree rebuild");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(size as u64));
+    group.bench_function("1M leaves, 4 workers", |b| {
+        b.iter(|| MerkleTree::from_storage(&store).unwrap().0.leaf_count);
+    });
+    group.finish();
 }
+
+// Proofs for random leaves of a tree, the leaves being the salted hashes as in a store
+fn bench_proof_generation(c: &mut Criterion) {
+    let size = 1_000_000;
+    let store = HashStore::<20, 0>::new(SALT);
+    let hashes = generate_random_hashes(size);
+    for hash in &hashes {
+        store.add_hash(*hash).unwrap();
+    }
+    let tree = MerkleTree::new(store.to_array(), SALT);
+    let mut rng = rand::thread_rng();
+    let proof_hashes: Vec<Hash512> = (0..1_000).map(|_| hashes[rng.gen_range(0..size)]).collect();
+
+    let mut group = c.benchmark_group("proof generation");
+    group.throughput(Throughput::Elements(proof_hashes.len() as u64));
+    group.bench_function("1M leaves", |b| {
+        b.iter(|| proof_hashes.iter().map(|hash| tree.get(hash).unwrap().len()).sum::<usize>());
+    });
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_insertion,
+    bench_store_threads,
+    bench_batch_insertion,
+    bench_batch_salting,
+    bench_concurrent_insertion,
+    bench_lookup,
+    bench_bucket_layout,
+    bench_tree_construction,
+    bench_tree_rebuild,
+    bench_proof_generation,
+);
+criterion_main!(benches);
@@ -0,0 +1,91 @@
+use std::sync::Arc;
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+use timestamping::receipt::{Receipt, ReceiptError, VerifyingKey};
+use timestamping::verify::{self, Hash512, Hash512Ops, HashAlgorithm, Hasher, LeafEncoding, hasher_from_name};
+
+// Python module `timestamping_verify`, see the README for how to build it. Hashes are 64-byte `bytes`,
+// invalid arguments and receipts that don't verify raise `ValueError`.
+
+fn hash(bytes: &[u8]) -> PyResult<Hash512> {
+    Hash512::from_bytes(bytes).map_err(|e| PyValueError::new_err(e.to_string()))
+}
+
+fn hasher(name: &str) -> PyResult<Arc<dyn Hasher>> {
+    hasher_from_name(name).ok_or_else(|| PyValueError::new_err(format!("Unknown tree hasher {}", name)))
+}
+
+fn encoding(leaf_version: u8) -> PyResult<LeafEncoding> {
+    LeafEncoding::from_version(leaf_version).ok_or_else(|| PyValueError::new_err(format!("Unknown leaf version {}", leaf_version)))
+}
+
+fn receipt_error(error: ReceiptError) -> PyErr {
+    PyValueError::new_err(error.to_string())
+}
+
+// Check a receipt, binary or JSON: the proof, the tree head signature if `public_key` is given and that the
+// receipt is for the document with `digest` if it is given
+#[pyfunction]
+#[pyo3(signature = (receipt, public_key=None, digest=None))]
+fn verify_receipt(receipt: &[u8], public_key: Option<&[u8]>, digest: Option<&[u8]>) -> PyResult<()> {
+    let receipt = Receipt::parse(receipt).map_err(receipt_error)?;
+    if let Some(digest) = digest {
+        receipt.verify_digest(digest).map_err(receipt_error)?;
+    }
+    match public_key {
+        Some(key) => {
+            let key = <&[u8; 32]>::try_from(key).ok()
+                .and_then(|key| VerifyingKey::from_bytes(key).ok())
+                .ok_or_else(|| PyValueError::new_err("Invalid public key"))?;
+            receipt.verify(&key).map_err(receipt_error)
+        }
+        None => receipt.verify_proof().map_err(receipt_error),
+    }
+}
+
+// The 64-byte value a digest is stored as, see `HashAlgorithm::normalize`
+#[pyfunction]
+#[pyo3(signature = (digest, hash_algorithm="sha512"))]
+fn normalize<'py>(py: Python<'py>, digest: &[u8], hash_algorithm: &str) -> PyResult<Bound<'py, PyBytes>> {
+    let algorithm = HashAlgorithm::from_name(hash_algorithm)
+        .ok_or_else(|| PyValueError::new_err(format!("Unknown hash algorithm {}", hash_algorithm)))?;
+    let hash = algorithm.normalize(digest).map_err(|e| PyValueError::new_err(e.to_string()))?;
+    Ok(PyBytes::new(py, &hash.to_bytes()))
+}
+
+// The leaf of a stored hash in a tree with the given salt
+#[pyfunction]
+#[pyo3(signature = (hash, salt, leaf_version=1, tree_hasher="sha512"))]
+fn leaf<'py>(py: Python<'py>, hash: &[u8], salt: &[u8], leaf_version: u8, tree_hasher: &str) -> PyResult<Bound<'py, PyBytes>> {
+    let leaf = encoding(leaf_version)?.leaf_with_hasher(&*self::hasher(tree_hasher)?, &self::hash(hash)?, &self::hash(salt)?);
+    Ok(PyBytes::new(py, &leaf.to_bytes()))
+}
+
+// The parent of two nodes
+#[pyfunction]
+#[pyo3(signature = (left, right, tree_hasher="sha512"))]
+fn node<'py>(py: Python<'py>, left: &[u8], right: &[u8], tree_hasher: &str) -> PyResult<Bound<'py, PyBytes>> {
+    let node = hasher(tree_hasher)?.node(&hash(left)?, &hash(right)?);
+    Ok(PyBytes::new(py, &node.to_bytes()))
+}
+
+// Check a proof in the format of `/check`, a list of (left, right) pairs starting with the hash and the salt
+#[pyfunction]
+#[pyo3(signature = (hash, proof, root, leaf_version=1, tree_hasher="sha512"))]
+fn verify_proof(hash: &[u8], proof: Vec<(Vec<u8>, Vec<u8>)>, root: &[u8], leaf_version: u8, tree_hasher: &str) -> PyResult<bool> {
+    let proof = proof.iter()
+        .map(|(left, right)| Ok((self::hash(left)?, self::hash(right)?)))
+        .collect::<PyResult<Vec<_>>>()?;
+    Ok(verify::verify_proof_with_hasher(&self::hash(hash)?, &proof, &self::hash(root)?, encoding(leaf_version)?, &*hasher(tree_hasher)?))
+}
+
+#[pymodule]
+fn timestamping_verify(module: &Bound<'_, PyModule>) -> PyResult<()> {
+    module.add_function(wrap_pyfunction!(verify_receipt, module)?)?;
+    module.add_function(wrap_pyfunction!(normalize, module)?)?;
+    module.add_function(wrap_pyfunction!(leaf, module)?)?;
+    module.add_function(wrap_pyfunction!(node, module)?)?;
+    module.add_function(wrap_pyfunction!(verify_proof, module)?)?;
+    Ok(())
+}
@@ -0,0 +1,40 @@
+# Checks the built module against a receipt issued by a server, see bindings/test.sh
+import hashlib
+import json
+from pathlib import Path
+
+import timestamping_verify
+
+FIXTURES = Path(__file__).resolve().parents[2] / "fixtures"
+# Of the signing key [1; 32] the fixture was signed with
+PUBLIC_KEY = bytes.fromhex("8a88e3dd7409f195fd52db2d3cba5d72ca6709bf1d94121bf3748801b40f6f5c")
+
+
+def fails(function, *args):
+    try:
+        function(*args)
+    except ValueError:
+        return True
+    return False
+
+
+receipt = (FIXTURES / "receipt.json").read_bytes()
+digest = hashlib.sha256((FIXTURES / "document.txt").read_bytes()).digest()
+timestamping_verify.verify_receipt(receipt)
+timestamping_verify.verify_receipt(receipt, PUBLIC_KEY, digest)
+assert fails(timestamping_verify.verify_receipt, receipt, bytes(32))
+assert fails(timestamping_verify.verify_receipt, receipt, PUBLIC_KEY, hashlib.sha256(b"another document").digest())
+
+# The signature covers the tree head
+tampered = json.loads(receipt)
+tampered["last_tree_update"] += 1
+assert fails(timestamping_verify.verify_receipt, json.dumps(tampered).encode(), PUBLIC_KEY)
+
+# The proof leads from the stored hash to the root
+fields = json.loads(receipt)
+proof = [(bytes.fromhex(left), bytes.fromhex(right)) for left, right in fields["merkle_proof"]]
+hash, root = bytes.fromhex(fields["hash"]), bytes.fromhex(fields["merkle_tree_root"])
+assert hash == timestamping_verify.normalize(digest, "sha256")
+assert timestamping_verify.verify_proof(hash, proof, root, fields["leaf_version"], fields["tree_hasher"])
+assert not timestamping_verify.verify_proof(hash, proof, bytes(64), fields["leaf_version"], fields["tree_hasher"])
+print("Python bindings verify the fixture receipt")
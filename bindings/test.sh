@@ -0,0 +1,13 @@
+#!/usr/bin/env bash
+# Build both bindings and check them against the receipt in fixtures/. Needs maturin and Python for the Python
+# module, wasm-pack and Node.js for the WebAssembly package.
+set -euo pipefail
+cd "$(dirname "$0")"
+
+python3 -m venv target/venv
+target/venv/bin/pip install --quiet "maturin>=1.0,<2.0"
+(cd python && VIRTUAL_ENV="$PWD/../target/venv" ../target/venv/bin/maturin develop --quiet)
+target/venv/bin/python python/tests/smoke.py
+
+(cd wasm && wasm-pack build --target nodejs)
+node wasm/tests/smoke.cjs
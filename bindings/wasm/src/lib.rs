@@ -0,0 +1,77 @@
+use std::sync::Arc;
+use wasm_bindgen::prelude::*;
+use timestamping::receipt::{Receipt, ReceiptError, VerifyingKey};
+use timestamping::verify::{self, Hash512, Hash512Ops, HashAlgorithm, Hasher, LeafEncoding, hasher_from_name};
+
+// JavaScript bindings, see the README for how to build them. Hashes are 64-byte `Uint8Array`s,
+// invalid arguments and receipts that don't verify throw an `Error`.
+
+fn hash(bytes: &[u8]) -> Result<Hash512, JsError> {
+    Hash512::from_bytes(bytes).map_err(|e| JsError::new(&e.to_string()))
+}
+
+fn hasher(name: &str) -> Result<Arc<dyn Hasher>, JsError> {
+    hasher_from_name(name).ok_or_else(|| JsError::new(&format!("Unknown tree hasher {}", name)))
+}
+
+fn encoding(leaf_version: u8) -> Result<LeafEncoding, JsError> {
+    LeafEncoding::from_version(leaf_version).ok_or_else(|| JsError::new(&format!("Unknown leaf version {}", leaf_version)))
+}
+
+fn receipt_error(error: ReceiptError) -> JsError {
+    JsError::new(&error.to_string())
+}
+
+// Check a receipt, binary or JSON: the proof, the tree head signature if `publicKey` is given and that the
+// receipt is for the document with `digest` if it is given
+#[wasm_bindgen(js_name = verifyReceipt)]
+pub fn verify_receipt(receipt: &[u8], public_key: Option<Vec<u8>>, digest: Option<Vec<u8>>) -> Result<(), JsError> {
+    let receipt = Receipt::parse(receipt).map_err(receipt_error)?;
+    if let Some(digest) = digest {
+        receipt.verify_digest(&digest).map_err(receipt_error)?;
+    }
+    match public_key {
+        Some(key) => {
+            let key = <&[u8; 32]>::try_from(key.as_slice()).ok()
+                .and_then(|key| VerifyingKey::from_bytes(key).ok())
+                .ok_or_else(|| JsError::new("Invalid public key"))?;
+            receipt.verify(&key).map_err(receipt_error)
+        }
+        None => receipt.verify_proof().map_err(receipt_error),
+    }
+}
+
+// The 64-byte value a digest is stored as, see `HashAlgorithm::normalize`
+#[wasm_bindgen]
+pub fn normalize(digest: &[u8], hash_algorithm: &str) -> Result<Vec<u8>, JsError> {
+    let algorithm = HashAlgorithm::from_name(hash_algorithm)
+        .ok_or_else(|| JsError::new(&format!("Unknown hash algorithm {}", hash_algorithm)))?;
+    let hash = algorithm.normalize(digest).map_err(|e| JsError::new(&e.to_string()))?;
+    Ok(hash.to_bytes())
+}
+
+// The leaf of a stored hash in a tree with the given salt
+#[wasm_bindgen]
+pub fn leaf(hash: &[u8], salt: &[u8], leaf_version: u8, tree_hasher: &str) -> Result<Vec<u8>, JsError> {
+    let leaf = encoding(leaf_version)?.leaf_with_hasher(&*self::hasher(tree_hasher)?, &self::hash(hash)?, &self::hash(salt)?);
+    Ok(leaf.to_bytes())
+}
+
+// The parent of two nodes
+#[wasm_bindgen]
+pub fn node(left: &[u8], right: &[u8], tree_hasher: &str) -> Result<Vec<u8>, JsError> {
+    Ok(hasher(tree_hasher)?.node(&hash(left)?, &hash(right)?).to_bytes())
+}
+
+// Check a proof in the format of `/check`, with its (left, right) pairs concatenated into 128 bytes each,
+// starting with the hash and the salt
+#[wasm_bindgen(js_name = verifyProof)]
+pub fn verify_proof(hash: &[u8], proof: &[u8], root: &[u8], leaf_version: u8, tree_hasher: &str) -> Result<bool, JsError> {
+    if !proof.len().is_multiple_of(128) {
+        return Err(JsError::new("Proof length is not a multiple of 128 bytes"));
+    }
+    let proof = proof.chunks(128)
+        .map(|pair| Ok((self::hash(&pair[..64])?, self::hash(&pair[64..])?)))
+        .collect::<Result<Vec<_>, JsError>>()?;
+    Ok(verify::verify_proof_with_hasher(&self::hash(hash)?, &proof, &self::hash(root)?, encoding(leaf_version)?, &*hasher(tree_hasher)?))
+}
@@ -0,0 +1,32 @@
+// Checks the package built for Node.js against a receipt issued by a server, see bindings/test.sh
+const assert = require("node:assert");
+const crypto = require("node:crypto");
+const fs = require("node:fs");
+const path = require("node:path");
+const timestamping = require("../pkg/timestamping_wasm.js");
+
+const fixtures = path.join(__dirname, "..", "..", "fixtures");
+// Of the signing key [1; 32] the fixture was signed with
+const publicKey = Buffer.from("8a88e3dd7409f195fd52db2d3cba5d72ca6709bf1d94121bf3748801b40f6f5c", "hex");
+
+const receipt = fs.readFileSync(path.join(fixtures, "receipt.json"));
+const digest = crypto.createHash("sha256").update(fs.readFileSync(path.join(fixtures, "document.txt"))).digest();
+timestamping.verifyReceipt(receipt);
+timestamping.verifyReceipt(receipt, publicKey, digest);
+assert.throws(() => timestamping.verifyReceipt(receipt, Buffer.alloc(32)));
+assert.throws(() => timestamping.verifyReceipt(receipt, publicKey, crypto.createHash("sha256").update("another document").digest()));
+
+// The signature covers the tree head
+const tampered = JSON.parse(receipt);
+tampered.last_tree_update += 1;
+assert.throws(() => timestamping.verifyReceipt(Buffer.from(JSON.stringify(tampered)), publicKey));
+
+// The proof leads from the stored hash to the root, its pairs concatenated
+const fields = JSON.parse(receipt);
+const proof = Buffer.concat(fields.merkle_proof.flat().map((hash) => Buffer.from(hash, "hex")));
+const hash = Buffer.from(fields.hash, "hex");
+const root = Buffer.from(fields.merkle_tree_root, "hex");
+assert.deepStrictEqual(Buffer.from(timestamping.normalize(digest, "sha256")), hash);
+assert.ok(timestamping.verifyProof(hash, proof, root, fields.leaf_version, fields.tree_hasher));
+assert.ok(!timestamping.verifyProof(hash, proof, Buffer.alloc(64), fields.leaf_version, fields.tree_hasher));
+console.log("WebAssembly bindings verify the fixture receipt");
@@ -0,0 +1,236 @@
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::Path;
+use std::time::{Duration, Instant};
+use sha2::{Digest, Sha256, Sha512};
+use timestamping::backup::{self, Credentials, ObjectStore};
+use timestamping::client::TimestampingClient;
+use timestamping::manifest::Manifest;
+use timestamping::receipt::{Receipt, VerifyingKey};
+use timestamping::storage::{Hash512, Hash512Ops, HashAlgorithm, decode_hex};
+
+const DEFAULT_SERVER: &str = "http://127.0.0.1:3427";
+const DEFAULT_WAIT_TIMEOUT: Duration = Duration::from_secs(600);
+
+const USAGE: &str = "Usage: timestamping-cli [--server URL] <command>
+
+Commands:
+  hash <file>                                 Print the SHA-512 hash of a file
+  submit <file>                               Hash a file and submit it to the server, saving the
+                                              server's signed acknowledgment if it sends one
+  receipt <file> [--wait [SECS]] [-o PATH]    Download a receipt for a submitted file,
+                                              optionally waiting until it is included in a tree
+  verify <file> <receipt> [--root HEX] [--key HEX] [--witness HEX...]
+                                              Verify a receipt offline, against a trusted root or
+                                              the server's public key from /info if given, and that
+                                              every witness key cosigned its tree head
+  manifest <dir> [-o PATH]                    Hash every file below a directory into a manifest,
+                                              written to <dir>.manifest by default, and submit it.
+                                              Its receipt is downloaded like that of any file
+  verify-manifest <dir> <manifest> <receipt> [--root HEX] [--key HEX] [--witness HEX...]
+                                              Check that the files below the directory are those
+                                              of the manifest and verify the manifest's receipt
+  restore <dir>                               Download the server backup at $TIMESTAMPING_BACKUP_URL
+                                              into a new data directory
+
+The server defaults to $TIMESTAMPING_SERVER or http://127.0.0.1:3427,
+$TIMESTAMPING_API_KEY is sent with every request if the server requires a key.
+Backups are read with the credentials in $AWS_ACCESS_KEY_ID and $AWS_SECRET_ACCESS_KEY
+from the region $TIMESTAMPING_BACKUP_REGION, us-east-1 by default";
+
+fn hash_file(path: &Path) -> io::Result<Hash512> {
+    Ok(Hash512::from_bytes(&digest_file::<Sha512>(path)?).unwrap())
+}
+
+fn digest_file<D: Digest + io::Write>(path: &Path) -> io::Result<Vec<u8>> {
+    let mut hasher = D::new();
+    io::copy(&mut BufReader::new(File::open(path)?), &mut hasher)?;
+    Ok(hasher.finalize().to_vec())
+}
+
+// The hash the server stored for a file hashed with `algorithm`, see `HashAlgorithm::normalize`
+fn stored_hash(path: &Path, algorithm: HashAlgorithm) -> Result<Hash512, String> {
+    let digest = match algorithm {
+        HashAlgorithm::Sha512 => digest_file::<Sha512>(path),
+        HashAlgorithm::Sha256 => digest_file::<Sha256>(path),
+        HashAlgorithm::Blake3 => File::open(path).and_then(|file| {
+            let mut hasher = blake3::Hasher::new();
+            io::copy(&mut BufReader::new(file), &mut hasher)?;
+            Ok(hasher.finalize().as_bytes().to_vec())
+        }),
+        other => return Err(format!("The receipt is for a {} digest, which this tool can't compute", other.name())),
+    };
+    Ok(algorithm.normalize(&digest.map_err(|e| e.to_string())?).unwrap())
+}
+
+async fn submit(client: &TimestampingClient, path: &Path) -> Result<(), String> {
+    let hash = hash_file(path).map_err(|e| e.to_string())?;
+    let result = client.add(&hash).await.map_err(|e| e.to_string())?;
+    if result.new_hashes > 0 {
+        println!("Submitted {}", hash);
+    } else {
+        println!("Already submitted {}", hash);
+    }
+    // Evidence of the submission until a receipt can be downloaded
+    if let Some(acknowledgment) = result.acknowledgments.first() {
+        let output = format!("{}.ack.json", path.display());
+        std::fs::write(&output, serde_json::to_string_pretty(acknowledgment).unwrap()).map_err(|e| e.to_string())?;
+        println!("Acknowledgment written to {}", output);
+    }
+    Ok(())
+}
+
+async fn receipt(client: &TimestampingClient, path: &Path, wait: Option<Duration>, output: Option<&str>) -> Result<(), String> {
+    let hash = hash_file(path).map_err(|e| e.to_string())?;
+    let start = Instant::now();
+
+    let receipt = match wait {
+        None => client.receipt(&hash).await.map_err(|e| e.to_string())?
+            .ok_or("Hash is not included in the current merkle tree yet, try again with --wait")?,
+        // The server answers long waits in parts, so keep asking until the timeout is used up
+        Some(timeout) => loop {
+            let remaining = timeout.saturating_sub(start.elapsed());
+            if remaining.is_zero() {
+                return Err("Timed out waiting for the hash to be included in a merkle tree".to_string());
+            }
+            if let Some(receipt) = client.wait(&hash, remaining).await.map_err(|e| e.to_string())? {
+                break receipt;
+            }
+        },
+    };
+
+    let output = output.map(str::to_string).unwrap_or_else(|| format!("{}.receipt.json", path.display()));
+    std::fs::write(&output, receipt.to_json()).map_err(|e| e.to_string())?;
+    println!("Receipt written to {}", output);
+    Ok(())
+}
+
+async fn manifest(client: &TimestampingClient, dir: &Path, output: Option<&str>) -> Result<(), String> {
+    let manifest = Manifest::build(dir).map_err(|e| e.to_string())?;
+    let output = output.map(str::to_string).unwrap_or_else(|| format!("{}.manifest", dir.display()));
+    std::fs::write(&output, manifest.to_bytes()).map_err(|e| e.to_string())?;
+    println!("Manifest of {} files written to {}", manifest.files.len(), output);
+    submit(client, Path::new(&output)).await
+}
+
+fn verify_manifest(dir: &Path, manifest_path: &Path, receipt_path: &Path, trusted_root: Option<&str>, trusted_key: Option<&str>, witnesses: &[String]) -> Result<(), String> {
+    let manifest = Manifest::parse(&std::fs::read(manifest_path).map_err(|e| e.to_string())?).map_err(|e| e.to_string())?;
+    let diff = manifest.diff(&Manifest::build(dir).map_err(|e| e.to_string())?);
+    if !diff.is_empty() {
+        for (label, paths) in [("Changed", &diff.changed), ("Missing", &diff.missing), ("Added", &diff.added)] {
+            for path in paths {
+                eprintln!("{}: {}", label, path);
+            }
+        }
+        return Err(format!("{} does not match the manifest", dir.display()));
+    }
+    println!("All {} files match the manifest", manifest.files.len());
+    verify(manifest_path, receipt_path, trusted_root, trusted_key, witnesses)
+}
+
+fn from_hex_key(hex: &str) -> Result<VerifyingKey, String> {
+    let bytes: [u8; 32] = decode_hex(hex.as_bytes()).and_then(|bytes| bytes.try_into().ok())
+        .ok_or(format!("Invalid key '{}': expected 64 hex characters", hex))?;
+    VerifyingKey::from_bytes(&bytes).map_err(|_| format!("Invalid key '{}': not an ed25519 public key", hex))
+}
+
+fn verify(path: &Path, receipt_path: &Path, trusted_root: Option<&str>, trusted_key: Option<&str>, witnesses: &[String]) -> Result<(), String> {
+    let receipt = Receipt::parse(&std::fs::read(receipt_path).map_err(|e| e.to_string())?).map_err(|e| e.to_string())?;
+
+    let hash = stored_hash(path, receipt.hash_algorithm)?;
+    if hash != receipt.hash {
+        return Err("File does not match the hash in the receipt".to_string());
+    }
+
+    if let Some(root) = trusted_root
+        && root.parse::<Hash512>().map_err(|e| format!("Invalid hash '{}': {}", root, e))? != receipt.merkle_tree_root
+    {
+        return Err("Receipt is invalid for this root".to_string());
+    }
+    match trusted_key {
+        Some(key) => receipt.verify(&from_hex_key(key)?).map_err(|e| e.to_string())?,
+        None => {
+            if trusted_root.is_none() {
+                eprintln!("Warning: no --root or --key given, verifying against the unauthenticated root stored in the receipt");
+            }
+            receipt.verify_proof().map_err(|e| e.to_string())?;
+        }
+    }
+    println!("Receipt is valid for root {}", receipt.merkle_tree_root);
+    if let (Some(_), Some(version), Some(timestamp)) = (trusted_key, receipt.tree_version, receipt.timestamp) {
+        println!("Signed tree head: version {}, published at {}", version, timestamp);
+        if let Some(time_source) = &receipt.time_source {
+            println!("Timestamp source: {}", time_source);
+        }
+    }
+    for witness in witnesses {
+        let cosigned_at = receipt.verify_cosignature(&from_hex_key(witness)?).map_err(|e| format!("{} for witness {}", e, witness))?;
+        println!("Cosigned by {} at {}", witness, cosigned_at);
+    }
+    Ok(())
+}
+
+async fn restore(dir: &Path) -> Result<(), String> {
+    let url = std::env::var("TIMESTAMPING_BACKUP_URL").map_err(|_| "restore needs $TIMESTAMPING_BACKUP_URL")?;
+    let credentials = Credentials::from_env().ok_or("restore needs $AWS_ACCESS_KEY_ID and $AWS_SECRET_ACCESS_KEY")?;
+    let region = std::env::var("TIMESTAMPING_BACKUP_REGION").unwrap_or_else(|_| "us-east-1".to_string());
+    let store = ObjectStore::new(&url, &region, credentials).map_err(|e| e.to_string())?;
+    let files = backup::restore(&store, dir).await.map_err(|e| e.to_string())?;
+    println!("Restored {} files from {} into {}, the signing key has to be copied there separately", files, store.url(), dir.display());
+    Ok(())
+}
+
+async fn run(args: Vec<String>) -> Result<(), String> {
+    let mut server = std::env::var("TIMESTAMPING_SERVER").unwrap_or_else(|_| DEFAULT_SERVER.to_string());
+    let mut wait = None;
+    let mut output = None;
+    let mut root = None;
+    let mut key = None;
+    let mut witnesses = Vec::new();
+    let mut positional = Vec::new();
+
+    let mut args = args.into_iter().peekable();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--server" => server = args.next().ok_or("--server needs a URL")?,
+            "-o" | "--output" => output = Some(args.next().ok_or("--output needs a path")?),
+            "--root" => root = Some(args.next().ok_or("--root needs a hex hash")?),
+            "--key" => key = Some(args.next().ok_or("--key needs a hex public key")?),
+            "--witness" => witnesses.push(args.next().ok_or("--witness needs a hex public key")?),
+            "--wait" => {
+                let seconds = args.next_if(|next| next.parse::<u64>().is_ok()).map(|next| next.parse().unwrap());
+                wait = Some(seconds.map(Duration::from_secs).unwrap_or(DEFAULT_WAIT_TIMEOUT));
+            }
+            "-h" | "--help" => return Err(USAGE.to_string()),
+            _ => positional.push(arg),
+        }
+    }
+
+    let client = match std::env::var("TIMESTAMPING_API_KEY") {
+        Ok(key) if !key.is_empty() => TimestampingClient::new(&server).with_api_key(&key),
+        _ => TimestampingClient::new(&server),
+    };
+    match positional.iter().map(String::as_str).collect::<Vec<_>>().as_slice() {
+        ["hash", file] => {
+            println!("{}", hash_file(Path::new(file)).map_err(|e| e.to_string())?);
+            Ok(())
+        }
+        ["submit", file] => submit(&client, Path::new(file)).await,
+        ["receipt", file] => receipt(&client, Path::new(file), wait, output.as_deref()).await,
+        ["verify", file, receipt] => verify(Path::new(file), Path::new(receipt), root.as_deref(), key.as_deref(), &witnesses),
+        ["manifest", dir] => manifest(&client, Path::new(dir), output.as_deref()).await,
+        ["verify-manifest", dir, manifest, receipt] => {
+            verify_manifest(Path::new(dir), Path::new(manifest), Path::new(receipt), root.as_deref(), key.as_deref(), &witnesses)
+        }
+        ["restore", dir] => restore(Path::new(dir)).await,
+        _ => Err(USAGE.to_string()),
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    if let Err(message) = run(std::env::args().skip(1).collect()).await {
+        eprintln!("{}", message);
+        std::process::exit(1);
+    }
+}
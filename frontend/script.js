@@ -39,6 +39,7 @@ let fileStatuses = new Map(); // Map of file name to status info
 let fileProofs = new Map(); // Map of file name to merkle proof
 let activeTab = null;
 let currentMerkleRoot = '';
+let currentTreeHasher = ''; // Tree hasher reported by /check, nodes of "-rfc6962" hashers are prefixed with 0x01
 
 // Event listeners
 fileInput.addEventListener('change', handleFilesSelect);
@@ -356,7 +357,7 @@ async function addAllHashesToStore() {
 
         const result = await response.json();
 
-        if (response.ok && result.success) {
+        if (response.ok) {
             // Update all files with success status
             for (const fileName of fileHashes.keys()) {
                 updateFileStatus(fileName, 'upload', 'success', 'Successfully added to store', '');
@@ -367,7 +368,7 @@ async function addAllHashesToStore() {
         } else {
             // Handle batch failure
             for (const fileName of fileHashes.keys()) {
-                updateFileStatus(fileName, 'upload', 'error', `Batch failed: ${result.message}`, '');
+                updateFileStatus(fileName, 'upload', 'error', `Batch failed: ${result.error.message}`, '');
             }
         }
 
@@ -408,7 +409,8 @@ async function checkAllHashes() {
 
                 const result = await response.json();
 
-                if (response.ok && result.success) {
+                if (response.ok) {
+                    currentTreeHasher = result.tree_hasher;
                     if (result.exists) {
                         const proofInfo = result.merkle_proof ?
                             ` (${result.merkle_proof.length} proof levels)` :
@@ -418,7 +420,7 @@ async function checkAllHashes() {
                         updateFileStatus(fileName, 'check', 'warning', 'Not found in store', '');
                     }
                 } else {
-                    updateFileStatus(fileName, 'check', 'error', `Failed: ${result.message}`, '');
+                    updateFileStatus(fileName, 'check', 'error', `Failed: ${result.error.message}`, '');
                 }
             } catch (error) {
                 updateFileStatus(fileName, 'check', 'error', `Network error: ${error.message}`, '');
@@ -442,7 +444,7 @@ async function updateMerkleTree() {
 
         const result = await response.json();
 
-        if (response.ok && result.success) {
+        if (response.ok) {
             await refreshStats();
         }
     } catch (error) {
@@ -547,7 +549,7 @@ async function checkHash(hashBytes) {
 
         const result = await response.json();
 
-        if (response.ok && result.success) {
+        if (response.ok) {
             if (result.exists) {
                 let message = 'Hash exists in the store';
                 if (result.merkle_proof) {
@@ -560,7 +562,7 @@ async function checkHash(hashBytes) {
                 showManualResult('info', 'Hash not found', 'Hash does not exist in the store');
             }
         } else {
-            showManualResult('error', 'Failed to check hash', result.message);
+            showManualResult('error', 'Failed to check hash', result.error.message);
         }
     } catch (error) {
         showManualResult('error', 'Network error', error.message);
@@ -666,10 +668,13 @@ async function displayMerkleProof(leafHashBytes, proof, expectedRoot) {
                 operation = 'Concatenate as right child with left sibling';
             }
 
-            // Concatenate byte arrays (same as backend hasher.update() calls)
-            const combined = new Uint8Array(leftHashBytes.length + rightHashBytes.length);
-            combined.set(leftHashBytes);
-            combined.set(rightHashBytes, leftHashBytes.length);
+            // Concatenate byte arrays (same as backend hasher.update() calls). The first level is the
+            // leaf, the others are nodes, which domain separated hashers prefix with 0x01.
+            const prefix = i > 0 && currentTreeHasher.endsWith('-rfc6962') ? [1] : [];
+            const combined = new Uint8Array(prefix.length + leftHashBytes.length + rightHashBytes.length);
+            combined.set(prefix);
+            combined.set(leftHashBytes, prefix.length);
+            combined.set(rightHashBytes, prefix.length + leftHashBytes.length);
 
             // Hash the concatenated bytes
             const hashBuffer = await crypto.subtle.digest('SHA-512', combined);
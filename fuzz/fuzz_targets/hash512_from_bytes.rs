@@ -0,0 +1,11 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use timestamping::storage::{Hash512, Hash512Ops};
+
+fuzz_target!(|data: &[u8]| {
+    match Hash512::from_bytes(data) {
+        Ok(hash) => assert_eq!(hash.to_bytes(), data),
+        Err(_) => assert_ne!(data.len(), 64),
+    }
+});
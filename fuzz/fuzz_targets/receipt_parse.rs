@@ -0,0 +1,13 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use timestamping::receipt::Receipt;
+
+// Every receipt that parses has to survive both serializations unchanged
+fuzz_target!(|data: &[u8]| {
+    if let Ok(receipt) = Receipt::parse(data) {
+        assert_eq!(Receipt::parse(&receipt.serialize()).unwrap(), receipt);
+        assert_eq!(Receipt::parse(receipt.to_json().as_bytes()).unwrap(), receipt);
+        let _ = receipt.verify_proof();
+    }
+});
@@ -0,0 +1,24 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use timestamping::storage::{HASHER_NAMES, Hash512, Hash512Ops, LeafEncoding, MerkleTree, hasher_from_name};
+
+// The input is read as 64-byte hashes: the leaf encoding in the first byte, then the hash, the root and the proof pairs
+fuzz_target!(|data: &[u8]| {
+    let Some((&version, data)) = data.split_first() else {
+        return;
+    };
+    let Some(encoding) = LeafEncoding::from_version(version % 4) else {
+        return;
+    };
+    let hashes: Vec<Hash512> = data.chunks_exact(64).map(|chunk| Hash512::from_bytes(chunk).unwrap()).collect();
+    let [hash, root, proof @ ..] = hashes.as_slice() else {
+        return;
+    };
+    let proof: Vec<(Hash512, Hash512)> = proof.chunks_exact(2).map(|pair| (pair[0], pair[1])).collect();
+
+    for name in HASHER_NAMES {
+        let hasher = hasher_from_name(name).unwrap();
+        MerkleTree::verify_proof_with_hasher(hash, &proof, root, encoding, &*hasher);
+    }
+});
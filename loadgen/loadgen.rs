@@ -0,0 +1,231 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use timestamping::client::{ClientError, TimestampingClient};
+use timestamping::storage::Hash512;
+
+const DEFAULT_SERVER: &str = "http://127.0.0.1:3427";
+
+const USAGE: &str = "Usage: timestamping-loadgen [options]
+
+Drives a running server over HTTP and prints the throughput and latency percentiles of its adds and checks.
+
+Options:
+  --server URL            Server to load, $TIMESTAMPING_SERVER or http://127.0.0.1:3427 by default
+  --concurrency N         Requests in flight at once (16)
+  --duration SECS         How long to send requests (10)
+  --batch-size N          Hashes per add and check request (1)
+  --read-ratio R          Share of requests that are checks of hashes added before, 0 to 1 (0.5)
+  --duplicate-ratio R     Share of added hashes that were added before, 0 to 1 (0.1)
+  --update-tree SECS      Publish a tree this often, which needs an admin key
+
+$TIMESTAMPING_API_KEY is sent with every request if the server requires a key";
+
+#[derive(Debug, Clone)]
+struct Options {
+    server: String,
+    concurrency: usize,
+    duration: Duration,
+    batch_size: usize,
+    read_ratio: f64,
+    duplicate_ratio: f64,
+    update_interval: Option<Duration>,
+}
+
+fn parse<T: std::str::FromStr>(flag: &str, value: Option<String>) -> Result<T, String> {
+    let value = value.ok_or_else(|| format!("{} needs a value", flag))?;
+    value.parse().map_err(|_| format!("Invalid value '{}' for {}", value, flag))
+}
+
+fn parse_ratio(flag: &str, value: Option<String>) -> Result<f64, String> {
+    let ratio: f64 = parse(flag, value)?;
+    if !(0.0..=1.0).contains(&ratio) {
+        return Err(format!("{} has to be between 0 and 1", flag));
+    }
+    Ok(ratio)
+}
+
+fn parse_options(args: Vec<String>) -> Result<Options, String> {
+    let mut options = Options {
+        server: std::env::var("TIMESTAMPING_SERVER").unwrap_or_else(|_| DEFAULT_SERVER.to_string()),
+        concurrency: 16,
+        duration: Duration::from_secs(10),
+        batch_size: 1,
+        read_ratio: 0.5,
+        duplicate_ratio: 0.1,
+        update_interval: None,
+    };
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--server" => options.server = args.next().ok_or("--server needs a URL")?,
+            "--concurrency" => options.concurrency = parse(&arg, args.next())?,
+            "--duration" => options.duration = Duration::from_secs_f64(parse(&arg, args.next())?),
+            "--batch-size" => options.batch_size = parse(&arg, args.next())?,
+            "--read-ratio" => options.read_ratio = parse_ratio(&arg, args.next())?,
+            "--duplicate-ratio" => options.duplicate_ratio = parse_ratio(&arg, args.next())?,
+            "--update-tree" => options.update_interval = Some(Duration::from_secs_f64(parse(&arg, args.next())?)),
+            _ => return Err(USAGE.to_string()),
+        }
+    }
+    if options.concurrency == 0 || options.batch_size == 0 {
+        return Err("--concurrency and --batch-size have to be at least 1".to_string());
+    }
+    Ok(options)
+}
+
+// Latencies and outcomes of one kind of request
+#[derive(Debug, Default)]
+struct RequestStats {
+    latencies: Vec<Duration>,
+    hashes: u64,
+    errors: BTreeMap<String, u64>,
+}
+
+impl RequestStats {
+    fn record<T>(&mut self, start: Instant, hashes: usize, result: Result<T, ClientError>) {
+        self.latencies.push(start.elapsed());
+        match result {
+            Ok(_) => self.hashes += hashes as u64,
+            Err(e) => {
+                let kind = match e {
+                    ClientError::Server { status, code, .. } => code.unwrap_or_else(|| status.to_string()),
+                    ClientError::Http(_) => "http".to_string(),
+                    ClientError::InvalidResponse(_) => "invalid_response".to_string(),
+                };
+                *self.errors.entry(kind).or_default() += 1;
+            }
+        }
+    }
+
+    fn merge(&mut self, other: RequestStats) {
+        self.latencies.extend(other.latencies);
+        self.hashes += other.hashes;
+        for (kind, count) in other.errors {
+            *self.errors.entry(kind).or_default() += count;
+        }
+    }
+
+    fn print(&mut self, name: &str, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        let errors: u64 = self.errors.values().sum();
+        println!(
+            "{}: {} requests ({:.0}/s), {} hashes ({:.0}/s), {} errors",
+            name, self.latencies.len(), self.latencies.len() as f64 / seconds, self.hashes, self.hashes as f64 / seconds, errors,
+        );
+        for (kind, count) in &self.errors {
+            println!("  {}: {}", kind, count);
+        }
+        if self.latencies.is_empty() {
+            return;
+        }
+        self.latencies.sort_unstable();
+        let percentiles: Vec<String> = [50, 90, 99, 100].iter()
+            .map(|&p| format!("p{} {:.2}ms", p, percentile(&self.latencies, p).as_secs_f64() * 1000.0))
+            .collect();
+        println!("  latency: {}", percentiles.join(", "));
+    }
+}
+
+// Nearest-rank percentile of sorted, non-empty latencies
+fn percentile(sorted: &[Duration], p: usize) -> Duration {
+    let rank = (sorted.len() * p).div_ceil(100).max(1);
+    sorted[rank - 1]
+}
+
+// Hashes of this run are numbered, so earlier ones can be checked and added again
+fn numbered_hash(run: u64, index: u64) -> Hash512 {
+    Hash512([run, index, 0, 0, 0, 0, 0, 0])
+}
+
+// One of the hashes numbered so far, `None` before the first one
+fn earlier_hash(run: u64, next_index: &AtomicU64) -> Option<Hash512> {
+    let numbered = next_index.load(Ordering::Relaxed);
+    (numbered > 0).then(|| numbered_hash(run, rand::random::<u64>() % numbered))
+}
+
+async fn send_requests(client: TimestampingClient, options: Arc<Options>, run: u64, next_index: Arc<AtomicU64>, deadline: Instant) -> (RequestStats, RequestStats) {
+    let (mut adds, mut checks) = (RequestStats::default(), RequestStats::default());
+    while Instant::now() < deadline {
+        let read = rand::random::<f64>() < options.read_ratio;
+        if read && let Some(first) = earlier_hash(run, &next_index) {
+            let hashes: Vec<Hash512> = std::iter::once(first)
+                .chain((1..options.batch_size).filter_map(|_| earlier_hash(run, &next_index)))
+                .collect();
+            let start = Instant::now();
+            let result = match hashes.as_slice() {
+                [hash] => client.check(hash).await.map(drop),
+                hashes => client.check_batch(hashes).await.map(drop),
+            };
+            checks.record(start, hashes.len(), result);
+            continue;
+        }
+        let hashes: Vec<Hash512> = (0..options.batch_size)
+            .map(|_| match earlier_hash(run, &next_index).filter(|_| rand::random::<f64>() < options.duplicate_ratio) {
+                Some(hash) => hash,
+                None => numbered_hash(run, next_index.fetch_add(1, Ordering::Relaxed)),
+            })
+            .collect();
+        let start = Instant::now();
+        let result = client.add_batch(&hashes).await;
+        adds.record(start, hashes.len(), result);
+    }
+    (adds, checks)
+}
+
+async fn run(args: Vec<String>) -> Result<(), String> {
+    let options = Arc::new(parse_options(args)?);
+    let client = match std::env::var("TIMESTAMPING_API_KEY") {
+        Ok(key) if !key.is_empty() => TimestampingClient::new(&options.server).with_api_key(&key),
+        _ => TimestampingClient::new(&options.server),
+    };
+    client.info().await.map_err(|e| format!("Can't reach {}: {}", options.server, e))?;
+    println!(
+        "Loading {} for {:?} with {} requests in flight, {} hashes each, {:.0}% checks and {:.0}% duplicate adds",
+        options.server, options.duration, options.concurrency, options.batch_size, options.read_ratio * 100.0, options.duplicate_ratio * 100.0,
+    );
+
+    // A random run number keeps the hashes of separate runs against the same server apart
+    let run = rand::random();
+    let next_index = Arc::new(AtomicU64::new(0));
+    let start = Instant::now();
+    let deadline = start + options.duration;
+    let tasks: Vec<_> = (0..options.concurrency)
+        .map(|_| tokio::spawn(send_requests(client.clone(), Arc::clone(&options), run, Arc::clone(&next_index), deadline)))
+        .collect();
+    let mut updates = RequestStats::default();
+    if let Some(interval) = options.update_interval {
+        while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+            tokio::time::sleep(interval.min(remaining)).await;
+            if remaining > interval {
+                let update_start = Instant::now();
+                let result = client.update_tree().await;
+                updates.record(update_start, 0, result);
+            }
+        }
+    }
+
+    let (mut adds, mut checks) = (RequestStats::default(), RequestStats::default());
+    for task in tasks {
+        let (task_adds, task_checks) = task.await.map_err(|e| e.to_string())?;
+        adds.merge(task_adds);
+        checks.merge(task_checks);
+    }
+    let elapsed = start.elapsed();
+    println!("{} hashes numbered in {:.1}s", next_index.load(Ordering::Relaxed), elapsed.as_secs_f64());
+    adds.print("add", elapsed);
+    checks.print("check", elapsed);
+    if options.update_interval.is_some() {
+        updates.print("update-tree", elapsed);
+    }
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() {
+    if let Err(message) = run(std::env::args().skip(1).collect()).await {
+        eprintln!("{}", message);
+        std::process::exit(1);
+    }
+}
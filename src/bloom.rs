@@ -0,0 +1,159 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use serde::Serialize;
+use utoipa::ToSchema;
+use crate::storage::Hash512;
+
+// More hash functions hardly lower the false-positive rate but make every lookup slower
+const MAX_HASH_FUNCTIONS: usize = 16;
+
+// Set of salted hashes answering "definitely not stored" without asking a worker. Bits are only ever set,
+// so removed hashes stay in the filter and merely count as false positives.
+#[derive(Debug)]
+pub struct BloomFilter {
+    bits: Vec<AtomicU64>,
+    hash_functions: usize,
+    capacity: usize,
+    false_positive_rate: f64,
+    items: AtomicUsize,
+    // Lookups answered by the filter alone, and those it passed on for hashes that weren't stored
+    negatives: AtomicUsize,
+    false_positives: AtomicUsize,
+}
+
+// Size and effectiveness of a Bloom filter, as reported in `/stats`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, ToSchema)]
+pub struct BloomFilterStats {
+    pub capacity: usize,
+    pub bits: usize,
+    pub hash_functions: usize,
+    // Salted hashes inserted, counting every hash once even if it was removed since
+    pub items: usize,
+    pub target_false_positive_rate: f64,
+    // Expected rate at the current number of items, exceeds the target once more than `capacity` hashes are stored
+    pub estimated_false_positive_rate: f64,
+    pub negatives: usize,
+    pub false_positives: usize,
+}
+
+impl BloomFilter {
+    // Filter for `capacity` hashes that wrongly reports a hash as possibly stored with probability
+    // `false_positive_rate` once it holds that many
+    pub fn new(capacity: usize, false_positive_rate: f64) -> Self {
+        assert!(false_positive_rate > 0.0 && false_positive_rate < 1.0, "False-positive rate has to be between 0 and 1");
+        let capacity = capacity.max(1);
+        let ln2 = std::f64::consts::LN_2;
+        let bits = (-(capacity as f64) * false_positive_rate.ln() / (ln2 * ln2)).ceil().max(64.0) as usize;
+        let words = bits.div_ceil(64);
+        let hash_functions = ((words * 64) as f64 / capacity as f64 * ln2).round().clamp(1.0, MAX_HASH_FUNCTIONS as f64) as usize;
+        Self {
+            bits: (0..words).map(|_| AtomicU64::new(0)).collect(),
+            hash_functions,
+            capacity,
+            false_positive_rate,
+            items: AtomicUsize::new(0),
+            negatives: AtomicUsize::new(0),
+            false_positives: AtomicUsize::new(0),
+        }
+    }
+
+    // Bit positions of a salted hash. Its words are already uniformly distributed, so the last two are
+    // combined instead of hashing it again, the first one selects the bucket.
+    fn positions(&self, salted_hash: &Hash512) -> impl Iterator<Item = usize> + use<> {
+        let bits = (self.bits.len() * 64) as u64;
+        let (first, step) = (salted_hash.0[6], salted_hash.0[7] | 1);
+        (0..self.hash_functions as u64).map(move |i| (first.wrapping_add(i.wrapping_mul(step)) % bits) as usize)
+    }
+
+    pub fn insert(&self, salted_hash: &Hash512) {
+        let mut new = false;
+        for position in self.positions(salted_hash) {
+            let mask = 1 << (position % 64);
+            new |= self.bits[position / 64].fetch_or(mask, Ordering::Release) & mask == 0;
+        }
+        if new {
+            self.items.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    // False only if the hash was never inserted
+    pub fn may_contain(&self, salted_hash: &Hash512) -> bool {
+        let found = self.positions(salted_hash).all(|position| self.bits[position / 64].load(Ordering::Acquire) & (1 << (position % 64)) != 0);
+        if !found {
+            self.negatives.fetch_add(1, Ordering::Relaxed);
+        }
+        found
+    }
+
+    // Count a hash `may_contain` passed on that turned out not to be stored
+    pub fn record_false_positive(&self) {
+        self.false_positives.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn memory_usage(&self) -> usize {
+        self.bits.len() * size_of::<AtomicU64>()
+    }
+
+    pub fn stats(&self) -> BloomFilterStats {
+        let bits = self.bits.len() * 64;
+        let items = self.items.load(Ordering::Relaxed);
+        let k = self.hash_functions as f64;
+        BloomFilterStats {
+            capacity: self.capacity,
+            bits,
+            hash_functions: self.hash_functions,
+            items,
+            target_false_positive_rate: self.false_positive_rate,
+            estimated_false_positive_rate: (1.0 - (-k * items as f64 / bits as f64).exp()).powf(k),
+            negatives: self.negatives.load(Ordering::Relaxed),
+            false_positives: self.false_positives.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn random_hash() -> Hash512 {
+        Hash512(rand::random())
+    }
+
+    #[test]
+    fn test_no_false_negatives() {
+        let filter = BloomFilter::new(1000, 0.01);
+        let hashes: Vec<Hash512> = (0..1000).map(|_| random_hash()).collect();
+        for hash in &hashes {
+            filter.insert(hash);
+        }
+        assert!(hashes.iter().all(|hash| filter.may_contain(hash)));
+        assert_eq!(filter.stats().negatives, 0);
+    }
+
+    #[test]
+    fn test_false_positive_rate() {
+        let filter = BloomFilter::new(10_000, 0.01);
+        for _ in 0..10_000 {
+            filter.insert(&random_hash());
+        }
+        let false_positives = (0..10_000).filter(|_| filter.may_contain(&random_hash())).count();
+        // 1% expected, far below 3% unless the positions are correlated
+        assert!(false_positives < 300, "{}", false_positives);
+
+        let stats = filter.stats();
+        assert_eq!(stats.hash_functions, 7);
+        assert!(stats.items > 9_900 && stats.items <= 10_000);
+        assert!((stats.estimated_false_positive_rate - 0.01).abs() < 0.002, "{}", stats.estimated_false_positive_rate);
+        assert_eq!(stats.negatives, 10_000 - false_positives);
+    }
+
+    #[test]
+    fn test_sizing() {
+        let loose = BloomFilter::new(1 << 20, 0.1);
+        let tight = BloomFilter::new(1 << 20, 0.001);
+        // About 4.8 and 14.4 bits per hash
+        assert_eq!((loose.stats().bits >> 20, loose.stats().hash_functions), (4, 3));
+        assert_eq!((tight.stats().bits >> 20, tight.stats().hash_functions), (14, 10));
+        assert_eq!(tight.memory_usage(), tight.stats().bits / 8);
+        assert_eq!(BloomFilter::new(0, 0.5).stats().bits, 64);
+    }
+}
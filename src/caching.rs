@@ -0,0 +1,123 @@
+// Validators for conditional GET requests, see RFC 9110 section 13
+
+const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"]; // 1970-01-01 was a Thursday
+const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+// Whether a response with `etag` and `last_modified` is still fresh for a request with these headers.
+// If-Modified-Since is only used without If-None-Match, and ETags are compared weakly.
+pub fn not_modified(if_none_match: Option<&str>, if_modified_since: Option<&str>, etag: &str, last_modified: Option<u64>) -> bool {
+    match (if_none_match, if_modified_since, last_modified) {
+        (Some(if_none_match), _, _) => etag_matches(if_none_match, etag),
+        (None, Some(since), Some(last_modified)) => parse_http_date(since).is_some_and(|since| last_modified <= since),
+        _ => false,
+    }
+}
+
+// Whether an If-None-Match header lists `etag` or is `*`
+pub fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    if_none_match.trim() == "*" || if_none_match.split(',').any(|tag| opaque(tag) == opaque(etag))
+}
+
+// Unix time as an IMF-fixdate, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`
+pub fn format_http_date(unix: u64) -> String {
+    let (days, seconds) = (unix / 86400, unix % 86400);
+    let (year, month, day) = civil_from_days(days);
+    format!(
+        "{}, {:02} {} {:04} {:02}:{:02}:{:02} GMT",
+        WEEKDAYS[(days % 7) as usize], day, MONTHS[month as usize - 1], year, seconds / 3600, seconds / 60 % 60, seconds % 60,
+    )
+}
+
+// Unix time of an IMF-fixdate. The obsolete formats recipients may ignore give `None`, like anything invalid.
+pub fn parse_http_date(date: &str) -> Option<u64> {
+    let (weekday, rest) = date.split_once(", ")?;
+    let parts: Vec<&str> = rest.split(' ').collect();
+    let [day, month, year, time, "GMT"] = parts.as_slice() else {
+        return None;
+    };
+    let number = |digits: &str, len: usize| (digits.len() == len && digits.bytes().all(|b| b.is_ascii_digit())).then(|| digits.parse::<u64>().unwrap());
+    let (day, year) = (number(day, 2)?, number(year, 4)?);
+    let month = MONTHS.iter().position(|name| name == month)? as u64 + 1;
+    let [hours, minutes, seconds] = time.split(':').collect::<Vec<_>>()[..] else {
+        return None;
+    };
+    let (hours, minutes, seconds) = (number(hours, 2)?, number(minutes, 2)?, number(seconds, 2)?);
+    if year < 1970 || day == 0 || hours > 23 || minutes > 59 || seconds > 60 {
+        return None;
+    }
+    let days = days_from_civil(year, month, day);
+    // Catches days past the end of their month, which move the date into the next one
+    if civil_from_days(days) != (year, month, day) || WEEKDAYS[(days % 7) as usize] != weekday {
+        return None;
+    }
+    Some(days * 86400 + hours * 3600 + minutes * 60 + seconds)
+}
+
+// Civil date from days since 1970-01-01 and back, see http://howardhinnant.github.io/date_algorithms.html
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let day_of_era = z - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+    (year_of_era + era * 400 + u64::from(month <= 2), month, day)
+}
+
+fn days_from_civil(year: u64, month: u64, day: u64) -> u64 {
+    let year = year - u64::from(month <= 2);
+    let era = year / 400;
+    let year_of_era = year - era * 400;
+    let shifted_month = if month > 2 { month - 3 } else { month + 9 };
+    let day_of_year = (153 * shifted_month + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_http_date() {
+        assert_eq!(format_http_date(0), "Thu, 01 Jan 1970 00:00:00 GMT");
+        assert_eq!(format_http_date(784111777), "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(format_http_date(1709210096), "Thu, 29 Feb 2024 12:34:56 GMT");
+        for unix in [0, 784111777, 951782400, 1709210096, 4102444799] {
+            assert_eq!(parse_http_date(&format_http_date(unix)), Some(unix));
+        }
+        for invalid in [
+            "Sunday, 06-Nov-94 08:49:37 GMT",
+            "Sun Nov  6 08:49:37 1994",
+            "Sun, 06 Nov 1994 08:49:37 UTC",
+            "Mon, 06 Nov 1994 08:49:37 GMT",
+            "Thu, 31 Feb 2024 00:00:00 GMT",
+            "Sun, 06 Nov 1994 24:00:00 GMT",
+            "Sun, 6 Nov 1994 08:49:37 GMT",
+        ] {
+            assert_eq!(parse_http_date(invalid), None, "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_not_modified() {
+        let etag = "\"roots-7\"";
+        assert!(not_modified(Some("\"roots-7\""), None, etag, None));
+        assert!(not_modified(Some("\"a\", W/\"roots-7\""), None, etag, None));
+        assert!(not_modified(Some("*"), None, etag, None));
+        assert!(!not_modified(Some("\"roots-6\""), None, etag, None));
+        assert!(not_modified(Some("W/\"stats-1\""), None, "W/\"stats-1\"", None));
+
+        // The date only counts without an ETag to compare
+        let since = format_http_date(1000);
+        assert!(not_modified(None, Some(&since), etag, Some(1000)));
+        assert!(!not_modified(None, Some(&since), etag, Some(1001)));
+        assert!(!not_modified(None, Some(&since), etag, None));
+        assert!(!not_modified(Some("\"roots-6\""), Some(&since), etag, Some(1000)));
+        assert!(!not_modified(None, Some("yesterday"), etag, Some(1000)));
+        assert!(!not_modified(None, None, etag, Some(1000)));
+    }
+}
@@ -0,0 +1,624 @@
+use std::time::Duration;
+use base64::Engine;
+use serde::Deserialize;
+use crate::receipt::{Acknowledgment, Cosignature, Receipt, VerifyingKey};
+use crate::clock::TimeAttestation;
+use crate::peering::SignedTreeHead;
+use crate::pow;
+use crate::storage::{AddStatus, Hash512, Hash512Ops, HashAlgorithm, HashMetadata, LeafEncoding, MerkleTree, Partitioning, Resubmissions, TransparencyLogEntry, TreeHead, hasher_from_name};
+
+#[derive(Debug, thiserror::Error)]
+pub enum ClientError {
+    #[error("HTTP request failed: {0}")]
+    Http(#[from] reqwest::Error),
+    // `code` is the machine-readable reason of the server's error response, e.g. `store_full`,
+    // missing if the response had none
+    #[error("Server returned {status}: {message}")]
+    Server { status: u16, code: Option<String>, message: String },
+    #[error("Invalid server response: {0}")]
+    InvalidResponse(&'static str),
+}
+
+// Merkle proof in the format returned by `MerkleTree::get`
+pub type MerkleProof = Vec<(Hash512, Hash512)>;
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct AddResult {
+    pub total_hashes: usize,
+    pub new_hashes: usize,
+    pub existing_hashes: usize,
+    // Missing in responses of servers that didn't deduplicate batches
+    #[serde(default)]
+    pub duplicate_hashes: usize,
+    pub leaf_version: u8,
+    #[serde(default)]
+    pub statuses: Vec<AddStatus>,
+    // Missing if the server doesn't sign acknowledgments
+    #[serde(default)]
+    pub acknowledgments: Vec<Acknowledgment>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CheckResult {
+    pub exists: bool,
+    pub merkle_proof: Option<MerkleProof>,
+    pub metadata: Option<HashMetadata>,
+    pub leaf_encoding: LeafEncoding,
+    pub tree_version: Option<u64>,
+    // Name of the hasher the server builds its trees with, see `storage::hasher_from_name`
+    pub tree_hasher: String,
+    // Order the server stored the hash in, see `storage::HashStore::sequence_with_encoding`. Not reported by `check_batch`.
+    pub sequence: Option<u64>,
+    // Submissions after the first one, see `storage::Resubmissions`. Not reported by `check_batch`.
+    pub resubmissions: Option<Resubmissions>,
+}
+
+// Root published for one tree version
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PublishedRoot {
+    pub version: u64,
+    pub root: Option<Hash512>,
+    pub tree_size: usize,
+    pub leaf_count: usize,
+    pub timestamp: u64,
+    pub anchor_txid: Option<String>,
+    // Signature over the tree head, see `receipt::tree_head_message`
+    pub signature: Option<Vec<u8>>,
+    pub transparency_log: Option<TransparencyLogEntry>,
+    pub time_source: Option<TimeAttestation>,
+    // Cosignatures of the server's peers over the tree head
+    pub cosignatures: Vec<Cosignature>,
+}
+
+// Parameters a verifier needs to recompute leaves and check proofs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerInfo {
+    pub software_version: String,
+    pub tree_hasher: String,
+    pub default_leaf_version: u8,
+    pub salt: Hash512,
+    pub index_size: usize,
+    pub prefix_size: usize,
+    pub threads: usize,
+    pub partitioning: Partitioning,
+    // Key the tree heads are signed with, `None` for servers that don't sign
+    pub public_key: Option<VerifyingKey>,
+}
+
+// Published head of a primary and the leaf log lengths of every worker its tree was built from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReplicationHead {
+    pub head: TreeHead,
+    pub leaf_log_lengths: Vec<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Stats {
+    pub count: usize,
+    pub slots: usize,
+    pub total_slots: usize,
+    pub merkle_tree_size: usize,
+    pub merkle_tree_root: Option<Hash512>,
+    pub last_tree_update: Option<u64>,
+}
+
+// Raw JSON shapes of the server responses
+#[derive(Debug, Deserialize)]
+struct ErrorResponse {
+    error: ErrorBody,
+}
+
+#[derive(Debug, Deserialize)]
+struct ErrorBody {
+    code: Option<String>,
+    message: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChallengeResponse {
+    challenge: String,
+    difficulty: u32,
+}
+
+#[derive(Debug, Deserialize)]
+struct AddPrivateResponse {
+    nonces: Vec<Vec<u8>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CheckResponse {
+    exists: bool,
+    merkle_proof: Option<Vec<(Vec<u8>, Vec<u8>)>>,
+    #[serde(default)]
+    metadata: Option<HashMetadata>,
+    leaf_version: u8,
+    #[serde(default)]
+    tree_version: Option<u64>,
+    #[serde(default = "default_tree_hasher")]
+    tree_hasher: String,
+    #[serde(default)]
+    sequence: Option<u64>,
+    #[serde(default)]
+    resubmissions: Option<Resubmissions>,
+}
+
+// Servers from before the tree hasher could be chosen always used SHA-512
+fn default_tree_hasher() -> String {
+    "sha512".to_string()
+}
+
+#[derive(Debug, Deserialize)]
+struct CheckBatchEntry {
+    exists: bool,
+    merkle_proof: Option<Vec<(Vec<u8>, Vec<u8>)>>,
+    metadata: Option<HashMetadata>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CheckBatchResponse {
+    results: Vec<CheckBatchEntry>,
+    leaf_version: u8,
+    tree_version: Option<u64>,
+    tree_hasher: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct RootEntry {
+    version: u64,
+    root: Option<Vec<u8>>,
+    tree_size: usize,
+    leaf_count: usize,
+    timestamp: u64,
+    anchor_txid: Option<String>,
+    #[serde(default)]
+    signature: Option<Vec<u8>>,
+    #[serde(default)]
+    transparency_log: Option<TransparencyLogEntry>,
+    #[serde(default)]
+    time_source: Option<TimeAttestation>,
+    #[serde(default)]
+    cosignatures: Vec<Cosignature>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RootResponse {
+    root: Option<RootEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+struct InfoResponse {
+    software_version: String,
+    tree_hasher: String,
+    default_leaf_version: u8,
+    salt: Vec<u8>,
+    index_size: usize,
+    prefix_size: usize,
+    threads: usize,
+    // Not reported by servers from before it was configurable, which all partition by the unsalted prefix
+    #[serde(default)]
+    partitioning: Partitioning,
+    #[serde(default)]
+    public_key: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ReplicationHeadResponse {
+    version: u64,
+    root: Option<Vec<u8>>,
+    tree_size: usize,
+    leaf_count: usize,
+    timestamp: u64,
+    leaf_log_lengths: Vec<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct StatsResponse {
+    count: usize,
+    slots: usize,
+    total_slots: usize,
+    merkle_tree_size: usize,
+    merkle_tree_root: Option<Vec<u8>>,
+    last_tree_update: Option<u64>,
+}
+
+fn parse_hash(bytes: &[u8]) -> Result<Hash512, ClientError> {
+    Hash512::from_bytes(bytes).map_err(|_| ClientError::InvalidResponse("hash is not 64 bytes"))
+}
+
+fn parse_proof(proof: Vec<(Vec<u8>, Vec<u8>)>) -> Result<MerkleProof, ClientError> {
+    proof.iter()
+        .map(|(left, right)| Ok((parse_hash(left)?, parse_hash(right)?)))
+        .collect()
+}
+
+// Typed client for the timestamping HTTP API
+#[derive(Debug, Clone)]
+pub struct TimestampingClient {
+    http: reqwest::Client,
+    base_url: String,
+    leaf_encoding: Option<LeafEncoding>,
+}
+
+impl TimestampingClient {
+    pub fn new(base_url: &str) -> Self {
+        Self {
+            http: reqwest::Client::new(),
+            base_url: base_url.trim_end_matches('/').to_string(),
+            leaf_encoding: None,
+        }
+    }
+
+    // Send `key` as `Authorization: Bearer <key>` with every request, for servers that require API keys
+    pub fn with_api_key(mut self, key: &str) -> Self {
+        let mut value = reqwest::header::HeaderValue::from_str(&format!("Bearer {}", key)).expect("API key is not a valid header value");
+        value.set_sensitive(true);
+        let headers = reqwest::header::HeaderMap::from_iter([(reqwest::header::AUTHORIZATION, value)]);
+        self.http = reqwest::Client::builder().default_headers(headers).build().expect("Failed to build the HTTP client");
+        self
+    }
+
+    // Request a specific leaf encoding for adds and checks instead of the server default
+    pub fn with_leaf_encoding(mut self, encoding: LeafEncoding) -> Self {
+        self.leaf_encoding = Some(encoding);
+        self
+    }
+
+    pub async fn add(&self, hash: &Hash512) -> Result<AddResult, ClientError> {
+        self.add_batch(std::slice::from_ref(hash)).await
+    }
+
+    pub async fn add_batch(&self, hashes: &[Hash512]) -> Result<AddResult, ClientError> {
+        self.post_hashes("/add", hashes).await
+    }
+
+    // Add hashes in privacy mode. The returned nonces are needed to check the hashes later
+    // and are not stored anywhere else, so they have to be kept by the caller.
+    pub async fn add_private(&self, hashes: &[Hash512]) -> Result<Vec<Hash512>, ClientError> {
+        let response: AddPrivateResponse = self.post_hashes("/add-private", hashes).await?;
+        if response.nonces.len() != hashes.len() {
+            return Err(ClientError::InvalidResponse("wrong number of nonces"));
+        }
+        response.nonces.iter().map(|nonce| parse_hash(nonce)).collect()
+    }
+
+    pub async fn check(&self, hash: &Hash512) -> Result<CheckResult, ClientError> {
+        self.check_path("/check", hash).await
+    }
+
+    // Check a hash added with `add_private`, using the nonce it got
+    pub async fn check_private(&self, hash: &Hash512, nonce: &Hash512) -> Result<CheckResult, ClientError> {
+        let body = [hash.to_bytes(), nonce.to_bytes()].concat();
+        let response: CheckResponse = self.post("/check-private", body).await?;
+        Self::check_result(response)
+    }
+
+    // Like `check`, but with the proof for an older tree version, as long as the server still keeps that tree
+    pub async fn check_at_version(&self, hash: &Hash512, tree_version: u64) -> Result<CheckResult, ClientError> {
+        self.check_path(&format!("/check?tree_version={}", tree_version), hash).await
+    }
+
+    async fn check_path(&self, path: &str, hash: &Hash512) -> Result<CheckResult, ClientError> {
+        let response: CheckResponse = self.post(path, hash.to_bytes()).await?;
+        Self::check_result(response)
+    }
+
+    fn check_result(response: CheckResponse) -> Result<CheckResult, ClientError> {
+        Ok(CheckResult {
+            exists: response.exists,
+            merkle_proof: response.merkle_proof.map(parse_proof).transpose()?,
+            metadata: response.metadata,
+            leaf_encoding: LeafEncoding::from_version(response.leaf_version)
+                .ok_or(ClientError::InvalidResponse("unknown leaf encoding version"))?,
+            tree_version: response.tree_version,
+            tree_hasher: response.tree_hasher,
+            sequence: response.sequence,
+            resubmissions: response.resubmissions,
+        })
+    }
+
+    // Check many hashes in one request, all proofs are from the same tree version.
+    // The server accepts at most 10000 hashes per request.
+    pub async fn check_batch(&self, hashes: &[Hash512]) -> Result<Vec<CheckResult>, ClientError> {
+        let body: Vec<u8> = hashes.iter().flat_map(|hash| hash.to_bytes()).collect();
+        let response: CheckBatchResponse = self.post("/check-batch", body).await?;
+        if response.results.len() != hashes.len() {
+            return Err(ClientError::InvalidResponse("wrong number of results"));
+        }
+        let leaf_encoding = LeafEncoding::from_version(response.leaf_version)
+            .ok_or(ClientError::InvalidResponse("unknown leaf encoding version"))?;
+        response.results.into_iter()
+            .map(|entry| Ok(CheckResult {
+                exists: entry.exists,
+                merkle_proof: entry.merkle_proof.map(parse_proof).transpose()?,
+                metadata: entry.metadata,
+                leaf_encoding,
+                tree_version: response.tree_version,
+                tree_hasher: response.tree_hasher.clone(),
+                sequence: None,
+                resubmissions: None,
+            }))
+            .collect()
+    }
+
+    // Proof of inclusion in the current merkle tree, `None` if the hash is not part of it yet
+    pub async fn get_proof(&self, hash: &Hash512) -> Result<Option<MerkleProof>, ClientError> {
+        Ok(self.check(hash).await?.merkle_proof)
+    }
+
+    pub async fn update_tree(&self) -> Result<(), ClientError> {
+        let response = self.http.post(format!("{}/update-tree", self.base_url)).send().await?;
+        Self::parse_response::<serde::de::IgnoredAny>(response).await?;
+        Ok(())
+    }
+
+    pub async fn stats(&self) -> Result<Stats, ClientError> {
+        let response = self.http.get(format!("{}/stats", self.base_url)).send().await?;
+        let stats: StatsResponse = Self::parse_response(response).await?;
+        Ok(Stats {
+            count: stats.count,
+            slots: stats.slots,
+            total_slots: stats.total_slots,
+            merkle_tree_size: stats.merkle_tree_size,
+            merkle_tree_root: stats.merkle_tree_root.as_deref().map(parse_hash).transpose()?,
+            last_tree_update: stats.last_tree_update,
+        })
+    }
+
+    pub async fn info(&self) -> Result<ServerInfo, ClientError> {
+        let response = self.http.get(format!("{}/info", self.base_url)).send().await?;
+        let info: InfoResponse = Self::parse_response(response).await?;
+        Ok(ServerInfo {
+            software_version: info.software_version,
+            tree_hasher: info.tree_hasher,
+            default_leaf_version: info.default_leaf_version,
+            salt: parse_hash(&info.salt)?,
+            index_size: info.index_size,
+            prefix_size: info.prefix_size,
+            threads: info.threads,
+            partitioning: info.partitioning,
+            public_key: info.public_key
+                .map(|key| VerifyingKey::try_from(key.as_slice()).map_err(|_| ClientError::InvalidResponse("invalid public key")))
+                .transpose()?,
+        })
+    }
+
+    // What a replica needs to catch up to the current tree of this server, `None` before the first tree
+    pub async fn replication_head(&self) -> Result<Option<ReplicationHead>, ClientError> {
+        let response = self.http.get(format!("{}/replication/head", self.base_url)).send().await?;
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let head: ReplicationHeadResponse = Self::parse_response(response).await?;
+        Ok(Some(ReplicationHead {
+            head: TreeHead {
+                version: head.version,
+                root: head.root.as_deref().map(parse_hash).transpose()?,
+                tree_size: head.tree_size,
+                leaf_count: head.leaf_count,
+                timestamp: head.timestamp,
+            },
+            leaf_log_lengths: head.leaf_log_lengths,
+        }))
+    }
+
+    // Salted hashes from the leaf log of one worker, starting at record `offset`
+    pub async fn replication_leaves(&self, worker: usize, offset: u64, limit: u64) -> Result<Vec<Hash512>, ClientError> {
+        let response = self.http
+            .get(format!("{}/replication/leaves/{}", self.base_url, worker))
+            .query(&[("offset", offset), ("limit", limit)])
+            .send()
+            .await?;
+        let status = response.status();
+        if !status.is_success() {
+            return Err(ClientError::Server { status: status.as_u16(), code: None, message: response.text().await.unwrap_or_default() });
+        }
+        let bytes = response.bytes().await?;
+        if !bytes.len().is_multiple_of(64) {
+            return Err(ClientError::InvalidResponse("leaves are not a multiple of 64 bytes"));
+        }
+        bytes.chunks_exact(64).map(parse_hash).collect()
+    }
+
+    // Root published for a tree version, `None` if the version doesn't exist
+    pub async fn root(&self, version: u64) -> Result<Option<PublishedRoot>, ClientError> {
+        let response = self.http.get(format!("{}/roots/{}", self.base_url, version)).send().await?;
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let Some(entry) = Self::parse_response::<RootResponse>(response).await?.root else {
+            return Ok(None);
+        };
+        Ok(Some(PublishedRoot {
+            version: entry.version,
+            root: entry.root.as_deref().map(parse_hash).transpose()?,
+            tree_size: entry.tree_size,
+            leaf_count: entry.leaf_count,
+            timestamp: entry.timestamp,
+            anchor_txid: entry.anchor_txid,
+            signature: entry.signature,
+            transparency_log: entry.transparency_log,
+            time_source: entry.time_source,
+            cosignatures: entry.cosignatures,
+        }))
+    }
+
+    // Have the server cosign a tree head of one of its peers, see `peering::Witness`. The cosignature isn't checked.
+    pub async fn cosign(&self, head: &SignedTreeHead) -> Result<Cosignature, ClientError> {
+        let response = self.http.post(format!("{}/cosign", self.base_url)).json(head).send().await?;
+        Self::parse_response(response).await
+    }
+
+    // Receipt for a hash in the current tree, `None` if it's not included in a tree yet.
+    // The proof and, if the server signs its tree heads, the signature are checked before it is returned.
+    pub async fn receipt(&self, hash: &Hash512) -> Result<Option<Receipt>, ClientError> {
+        let check = self.check(hash).await?;
+        let (Some(merkle_proof), Some(tree_version)) = (check.merkle_proof, check.tree_version) else {
+            return Ok(None);
+        };
+        let Some(published) = self.root(tree_version).await? else {
+            return Ok(None);
+        };
+        let Some(merkle_tree_root) = published.root else {
+            return Ok(None);
+        };
+        let info = self.info().await?;
+
+        let receipt = Receipt {
+            hash: *hash,
+            // The client only submits SHA-512 digests
+            hash_algorithm: HashAlgorithm::Sha512,
+            leaf_encoding: check.leaf_encoding,
+            tree_hasher: check.tree_hasher,
+            merkle_proof,
+            merkle_tree_root,
+            tree_version: Some(tree_version),
+            leaf_count: Some(published.leaf_count as u64),
+            timestamp: Some(published.timestamp),
+            signature: published.signature,
+            anchor_txid: published.anchor_txid,
+            server_version: Some(info.software_version.clone()),
+            time_source: published.time_source.as_ref().map(TimeAttestation::summary),
+            cosignatures: published.cosignatures,
+        };
+        if receipt.verify_proof().is_err() {
+            return Ok(None);
+        }
+        Self::check_receipt(&receipt, &info)?;
+        Ok(Some(receipt))
+    }
+
+    // Wait on the server up to `timeout` until the hash is included in a published tree and return its receipt,
+    // `None` if it wasn't in time. The server caps the wait at a few minutes. The receipt is checked like in `receipt`.
+    pub async fn wait(&self, hash: &Hash512, timeout: Duration) -> Result<Option<Receipt>, ClientError> {
+        let mut request = self.http.post(format!("{}/wait", self.base_url)).query(&[("timeout", timeout.as_secs())]);
+        if let Some(encoding) = self.leaf_encoding {
+            request = request.query(&[("leaf_version", encoding.version())]);
+        }
+        let response = request
+            .header(reqwest::header::CONTENT_TYPE, "application/octet-stream")
+            .body(hash.to_bytes())
+            .send()
+            .await?;
+        if response.status() == reqwest::StatusCode::REQUEST_TIMEOUT {
+            return Ok(None);
+        }
+        let text = Self::parse_response_text(response).await?;
+        let receipt = Receipt::from_json(&text).map_err(|_| ClientError::InvalidResponse("invalid receipt"))?;
+        if receipt.hash != *hash || receipt.verify_proof().is_err() {
+            return Err(ClientError::InvalidResponse("receipt doesn't prove the inclusion of the hash"));
+        }
+        Self::check_receipt(&receipt, &self.info().await?)?;
+        Ok(Some(receipt))
+    }
+
+    // Check a receipt with a valid proof against the salt and public key the server publishes
+    fn check_receipt(receipt: &Receipt, info: &ServerInfo) -> Result<(), ClientError> {
+        if receipt.salt() != Some(info.salt) {
+            return Err(ClientError::InvalidResponse("proof uses a different salt than the one published by the server"));
+        }
+        if let Some(key) = &info.public_key
+            && receipt.signature.is_some()
+            && receipt.verify(key).is_err()
+        {
+            return Err(ClientError::InvalidResponse("tree head signature doesn't match the public key of the server"));
+        }
+        Ok(())
+    }
+
+    // Fetch the proof and the current root and verify the proof locally.
+    // The tree may be rebuilt between both requests, in which case this returns false and can be retried.
+    pub async fn verify(&self, hash: &Hash512) -> Result<bool, ClientError> {
+        let check = self.check(hash).await?;
+        let Some(proof) = check.merkle_proof else {
+            return Ok(false);
+        };
+        let Some(root) = self.stats().await?.merkle_tree_root else {
+            return Ok(false);
+        };
+        Self::verify_proof_with_hasher(hash, &proof, &root, check.leaf_encoding, &check.tree_hasher)
+    }
+
+    // Verify a proof against a root obtained from a trusted source, without contacting the server
+    pub fn verify_proof(hash: &Hash512, proof: &[(Hash512, Hash512)], root: &Hash512, encoding: LeafEncoding) -> bool {
+        MerkleTree::verify_proof_with_encoding(hash, proof, root, encoding)
+    }
+
+    // Like `verify_proof`, for trees built with the named hasher
+    pub fn verify_proof_with_hasher(hash: &Hash512, proof: &[(Hash512, Hash512)], root: &Hash512, encoding: LeafEncoding, tree_hasher: &str) -> Result<bool, ClientError> {
+        let hasher = hasher_from_name(tree_hasher).ok_or(ClientError::InvalidResponse("unknown tree hasher"))?;
+        Ok(MerkleTree::verify_proof_with_hasher(hash, proof, root, encoding, &*hasher))
+    }
+
+    // Post hashes to an add route. If the server requires a proof of work, the request is repeated
+    // with a solved challenge, which takes a while for large difficulties.
+    async fn post_hashes<T: serde::de::DeserializeOwned>(&self, path: &str, hashes: &[Hash512]) -> Result<T, ClientError> {
+        let body: Vec<u8> = hashes.iter().flat_map(|hash| hash.to_bytes()).collect();
+        match self.post(path, body.clone()).await {
+            Err(ClientError::Server { code: Some(code), .. }) if code == "proof_of_work_required" => {
+                let response = self.http.get(format!("{}/challenge", self.base_url)).send().await?;
+                let challenge: ChallengeResponse = Self::parse_response(response).await?;
+                let bytes = base64::engine::general_purpose::STANDARD.decode(&challenge.challenge)
+                    .map_err(|_| ClientError::InvalidResponse("challenge is not base64"))?;
+                let hashes = hashes.to_vec();
+                let nonce = tokio::task::spawn_blocking(move || pow::solve(&bytes, &hashes, challenge.difficulty)).await
+                    .map_err(|_| ClientError::InvalidResponse("solving the challenge failed"))?;
+                self.post_with_work(path, body, Some((&challenge.challenge, nonce))).await
+            }
+            result => result,
+        }
+    }
+
+    async fn post<T: serde::de::DeserializeOwned>(&self, path: &str, body: Vec<u8>) -> Result<T, ClientError> {
+        self.post_with_work(path, body, None).await
+    }
+
+    // `work` is a challenge as sent by the server and the nonce solving it
+    async fn post_with_work<T: serde::de::DeserializeOwned>(&self, path: &str, body: Vec<u8>, work: Option<(&str, u64)>) -> Result<T, ClientError> {
+        let mut request = self.http.post(format!("{}{}", self.base_url, path));
+        if let Some(encoding) = self.leaf_encoding {
+            request = request.query(&[("leaf_version", encoding.version())]);
+        }
+        if let Some((challenge, nonce)) = work {
+            request = request.header("x-challenge", challenge).header("x-challenge-nonce", nonce);
+        }
+        let response = request
+            .header(reqwest::header::CONTENT_TYPE, "application/octet-stream")
+            .body(body)
+            .send()
+            .await?;
+        Self::parse_response(response).await
+    }
+
+    async fn parse_response<T: serde::de::DeserializeOwned>(response: reqwest::Response) -> Result<T, ClientError> {
+        Ok(Self::successful(response).await?.json().await?)
+    }
+
+    async fn parse_response_text(response: reqwest::Response) -> Result<String, ClientError> {
+        Ok(Self::successful(response).await?.text().await?)
+    }
+
+    async fn successful(response: reqwest::Response) -> Result<reqwest::Response, ClientError> {
+        let status = response.status();
+        if !status.is_success() {
+            let (code, message) = response.json::<ErrorResponse>().await
+                .map(|response| (response.error.code, response.error.message))
+                .unwrap_or_else(|_| (None, status.to_string()));
+            return Err(ClientError::Server { status: status.as_u16(), code, message });
+        }
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_proof() {
+        let hash = Hash512([1u64, 2, 3, 4, 5, 6, 7, 8]);
+        let proof = parse_proof(vec![(hash.to_bytes(), Hash512([0u64; 8]).to_bytes())]).unwrap();
+        assert_eq!(proof, vec![(hash, Hash512([0u64; 8]))]);
+
+        assert!(parse_proof(vec![(vec![0u8; 32], vec![0u8; 64])]).is_err());
+    }
+}
@@ -0,0 +1,371 @@
+use std::collections::HashMap;
+use std::net::UdpSocket;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use ed25519_dalek::Verifier;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha512};
+use utoipa::ToSchema;
+use crate::receipt::VerifyingKey;
+
+// How long a time server gets to answer before the system time is used instead
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Seconds from the NTP epoch (1900) to the unix epoch
+const NTP_UNIX_OFFSET: u64 = 2_208_988_800;
+
+// Roughtime requests are padded to this size, so servers can't be used to amplify traffic
+const ROUGHTIME_REQUEST_SIZE: usize = 1024;
+const ROUGHTIME_RESPONSE_CONTEXT: &[u8] = b"RoughTime v1 response signature\0";
+const ROUGHTIME_DELEGATION_CONTEXT: &[u8] = b"RoughTime v1 delegation signature--\0";
+
+// How a timestamp was obtained, included in the signed tree heads built with it, so consumers know how
+// far to trust their timestamps
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct TimeAttestation {
+    // "system", "ntp" or "roughtime"
+    pub source: String,
+    pub server: Option<String>,
+    // Uncertainty of the timestamp, `None` if unknown
+    pub radius_ms: Option<u64>,
+    // Nonce and signed response of a Roughtime server, which prove the time to anyone trusting its key
+    pub roughtime_nonce: Option<Vec<u8>>,
+    pub roughtime_response: Option<Vec<u8>>,
+}
+
+impl TimeAttestation {
+    fn system() -> Self {
+        Self { source: "system".to_string(), server: None, radius_ms: None, roughtime_nonce: None, roughtime_response: None }
+    }
+
+    // Compact form signed as part of a tree head, e.g. "ntp pool.ntp.org:123 ±12ms"
+    pub fn summary(&self) -> String {
+        let mut summary = self.source.clone();
+        if let Some(server) = &self.server {
+            summary += &format!(" {}", server);
+        }
+        if let Some(radius) = self.radius_ms {
+            summary += &format!(" ±{}ms", radius);
+        }
+        summary
+    }
+}
+
+// Clock tree heads are timestamped with
+pub trait TimeSource: std::fmt::Debug + Send + Sync {
+    // Current time and how it was obtained. Sources asking a server fall back to the system time
+    // if it doesn't answer, which their attestation then says.
+    fn now(&self) -> (SystemTime, TimeAttestation);
+}
+
+// The local clock, unverified
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemTimeSource;
+
+impl TimeSource for SystemTimeSource {
+    fn now(&self) -> (SystemTime, TimeAttestation) {
+        (SystemTime::now(), TimeAttestation::system())
+    }
+}
+
+// The local clock, checked against an NTP server. If it is off by more than `max_offset` or the server
+// doesn't answer, the time is attested as unverified system time.
+#[derive(Debug, Clone)]
+pub struct NtpTimeSource {
+    server: String,
+    max_offset: Duration,
+}
+
+impl NtpTimeSource {
+    pub fn new(server: &str, max_offset: Duration) -> Self {
+        Self { server: server.to_string(), max_offset }
+    }
+
+    // Offset of the server's clock from the local one in milliseconds and the round trip time
+    fn query(&self) -> std::io::Result<(i64, Duration)> {
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        socket.set_read_timeout(Some(QUERY_TIMEOUT))?;
+        socket.connect(&self.server)?;
+        // Version 3, client mode
+        let mut request = [0u8; 48];
+        request[0] = 0x1b;
+        let sent = SystemTime::now();
+        socket.send(&request)?;
+        let mut response = [0u8; 48];
+        let len = socket.recv(&mut response)?;
+        let received = SystemTime::now();
+        let server_time = ntp_transmit_time(&response[..len])
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, "invalid NTP response"))?;
+
+        let round_trip = received.duration_since(sent).unwrap_or_default();
+        let local_time = sent + round_trip / 2;
+        let offset = match server_time.duration_since(local_time) {
+            Ok(ahead) => ahead.as_millis() as i64,
+            Err(behind) => -(behind.duration().as_millis() as i64),
+        };
+        Ok((offset, round_trip))
+    }
+}
+
+impl TimeSource for NtpTimeSource {
+    fn now(&self) -> (SystemTime, TimeAttestation) {
+        match self.query() {
+            Ok((offset, round_trip)) if offset.unsigned_abs() <= self.max_offset.as_millis() as u64 => {
+                let attestation = TimeAttestation {
+                    source: "ntp".to_string(),
+                    server: Some(self.server.clone()),
+                    radius_ms: Some(offset.unsigned_abs() + round_trip.as_millis() as u64 / 2),
+                    ..TimeAttestation::system()
+                };
+                (SystemTime::now(), attestation)
+            }
+            Ok((offset, _)) => {
+                eprintln!("System clock is {}ms off the time of {}, using it unverified", offset, self.server);
+                SystemTimeSource.now()
+            }
+            Err(e) => {
+                eprintln!("Failed to query NTP server {}: {}", self.server, e);
+                SystemTimeSource.now()
+            }
+        }
+    }
+}
+
+// Transmit timestamp of an NTP server response
+fn ntp_transmit_time(response: &[u8]) -> Option<SystemTime> {
+    // Server mode, and a zero stratum means the server refused to answer
+    if response.len() < 48 || response[0] & 0x07 != 4 || response[1] == 0 {
+        return None;
+    }
+    let seconds = u32::from_be_bytes(response[40..44].try_into().unwrap()) as u64;
+    let fraction = u32::from_be_bytes(response[44..48].try_into().unwrap()) as u64;
+    let nanos = (fraction * 1_000_000_000) >> 32;
+    Some(UNIX_EPOCH + Duration::from_secs(seconds.checked_sub(NTP_UNIX_OFFSET)?) + Duration::from_nanos(nanos))
+}
+
+// Time from a Roughtime server, whose responses are signed over a nonce of the request so they can
+// be shown to others. Uses the original Roughtime protocol with 64 byte nonces.
+#[derive(Debug, Clone)]
+pub struct RoughtimeTimeSource {
+    server: String,
+    public_key: VerifyingKey,
+}
+
+impl RoughtimeTimeSource {
+    pub fn new(server: &str, public_key: VerifyingKey) -> Self {
+        Self { server: server.to_string(), public_key }
+    }
+
+    fn query(&self, nonce: &[u8; 64]) -> Result<(RoughtimeResponse, Vec<u8>), String> {
+        let socket = UdpSocket::bind("0.0.0.0:0").map_err(|e| e.to_string())?;
+        socket.set_read_timeout(Some(QUERY_TIMEOUT)).map_err(|e| e.to_string())?;
+        socket.connect(&self.server).map_err(|e| e.to_string())?;
+        socket.send(&roughtime_request(nonce)).map_err(|e| e.to_string())?;
+        let mut response = vec![0u8; 4096];
+        let len = socket.recv(&mut response).map_err(|e| e.to_string())?;
+        response.truncate(len);
+        let parsed = verify_roughtime_response(&response, nonce, &self.public_key)?;
+        Ok((parsed, response))
+    }
+}
+
+impl TimeSource for RoughtimeTimeSource {
+    fn now(&self) -> (SystemTime, TimeAttestation) {
+        let nonce: [u8; 64] = std::array::from_fn(|_| rand::random());
+        match self.query(&nonce) {
+            Ok((response, bytes)) => {
+                let attestation = TimeAttestation {
+                    source: "roughtime".to_string(),
+                    server: Some(self.server.clone()),
+                    radius_ms: Some(response.radius_us.div_ceil(1000) as u64),
+                    roughtime_nonce: Some(nonce.to_vec()),
+                    roughtime_response: Some(bytes),
+                };
+                (UNIX_EPOCH + Duration::from_micros(response.midpoint_us), attestation)
+            }
+            Err(e) => {
+                eprintln!("Failed to get the time from Roughtime server {}: {}", self.server, e);
+                SystemTimeSource.now()
+            }
+        }
+    }
+}
+
+// Time a Roughtime server signed, both in microseconds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RoughtimeResponse {
+    pub midpoint_us: u64,
+    pub radius_us: u32,
+}
+
+fn tag(name: &[u8; 4]) -> u32 {
+    u32::from_le_bytes(*name)
+}
+
+// Roughtime message: number of tags, the offsets of all values but the first, the tags in
+// ascending order, then the values. All integers are little-endian.
+fn encode_message(fields: &[(&[u8; 4], &[u8])]) -> Vec<u8> {
+    let mut message = (fields.len() as u32).to_le_bytes().to_vec();
+    let mut offset = 0;
+    for (_, value) in &fields[..fields.len() - 1] {
+        offset += value.len() as u32;
+        message.extend_from_slice(&offset.to_le_bytes());
+    }
+    for (name, _) in fields {
+        message.extend_from_slice(*name);
+    }
+    for (_, value) in fields {
+        message.extend_from_slice(value);
+    }
+    message
+}
+
+fn parse_message(bytes: &[u8]) -> Result<HashMap<u32, &[u8]>, String> {
+    let invalid = || "invalid Roughtime message".to_string();
+    let word = |index: usize| bytes.get(index * 4..index * 4 + 4).map(|word| u32::from_le_bytes(word.try_into().unwrap()));
+    let count = word(0).ok_or_else(invalid)? as usize;
+    if count == 0 || count > bytes.len() / 8 {
+        return Err(invalid());
+    }
+    let header_len = 8 * count;
+    let values_len = bytes.len() - header_len;
+    let mut offsets = vec![0];
+    for index in 1..count {
+        offsets.push(word(index).ok_or_else(invalid)? as usize);
+    }
+    offsets.push(values_len);
+
+    let mut fields = HashMap::new();
+    let mut previous_tag = None;
+    for index in 0..count {
+        let tag = word(count + index).ok_or_else(invalid)?;
+        let (start, end) = (offsets[index], offsets[index + 1]);
+        if previous_tag.is_some_and(|previous| previous >= tag) || start > end || end > values_len || start % 4 != 0 {
+            return Err(invalid());
+        }
+        previous_tag = Some(tag);
+        fields.insert(tag, &bytes[header_len + start..header_len + end]);
+    }
+    Ok(fields)
+}
+
+fn field<'a>(fields: &HashMap<u32, &'a [u8]>, name: &[u8; 4], len: Option<usize>) -> Result<&'a [u8], String> {
+    let value = fields.get(&tag(name)).ok_or_else(|| format!("Roughtime message is missing {}", String::from_utf8_lossy(name)))?;
+    match len {
+        Some(len) if value.len() != len => Err(format!("Roughtime {} has the wrong length", String::from_utf8_lossy(name))),
+        _ => Ok(value),
+    }
+}
+
+fn u64_field(fields: &HashMap<u32, &[u8]>, name: &[u8; 4]) -> Result<u64, String> {
+    Ok(u64::from_le_bytes(field(fields, name, Some(8))?.try_into().unwrap()))
+}
+
+fn verify_signature(key: &VerifyingKey, context: &[u8], message: &[u8], signature: &[u8]) -> Result<(), String> {
+    let signature = ed25519_dalek::Signature::from_slice(signature).map_err(|e| e.to_string())?;
+    key.verify(&[context, message].concat(), &signature).map_err(|_| "invalid Roughtime signature".to_string())
+}
+
+pub fn roughtime_request(nonce: &[u8; 64]) -> Vec<u8> {
+    // Header of two tags and the nonce, padded up to the request size
+    let padding = vec![0u8; ROUGHTIME_REQUEST_SIZE - 16 - nonce.len()];
+    encode_message(&[(b"NONC", nonce), (b"PAD\xff", &padding)])
+}
+
+// Check that a response is signed by a key the server's long-term key delegated to, that the signed
+// time lies within the delegation and that the signed merkle tree contains `nonce`
+pub fn verify_roughtime_response(response: &[u8], nonce: &[u8; 64], public_key: &VerifyingKey) -> Result<RoughtimeResponse, String> {
+    let fields = parse_message(response)?;
+    let cert = parse_message(field(&fields, b"CERT", None)?)?;
+    let delegation_bytes = field(&cert, b"DELE", None)?;
+    verify_signature(public_key, ROUGHTIME_DELEGATION_CONTEXT, delegation_bytes, field(&cert, b"SIG\0", Some(64))?)?;
+    let delegation = parse_message(delegation_bytes)?;
+    let delegated_key = VerifyingKey::from_bytes(field(&delegation, b"PUBK", Some(32))?.try_into().unwrap()).map_err(|e| e.to_string())?;
+
+    let signed_bytes = field(&fields, b"SREP", None)?;
+    verify_signature(&delegated_key, ROUGHTIME_RESPONSE_CONTEXT, signed_bytes, field(&fields, b"SIG\0", Some(64))?)?;
+    let signed = parse_message(signed_bytes)?;
+    let midpoint_us = u64_field(&signed, b"MIDP")?;
+    let radius_us = u32::from_le_bytes(field(&signed, b"RADI", Some(4))?.try_into().unwrap());
+    if midpoint_us < u64_field(&delegation, b"MINT")? || midpoint_us > u64_field(&delegation, b"MAXT")? {
+        return Err("Roughtime midpoint is outside the delegation".to_string());
+    }
+
+    // Responses to batched requests sign a tree of the nonces, the path leads from ours to the root
+    let mut index = u32::from_le_bytes(field(&fields, b"INDX", Some(4))?.try_into().unwrap());
+    let path = field(&fields, b"PATH", None)?;
+    if path.len() % 64 != 0 {
+        return Err("Roughtime PATH has the wrong length".to_string());
+    }
+    let mut node: Vec<u8> = Sha512::new().chain_update([0]).chain_update(nonce).finalize().to_vec();
+    for sibling in path.chunks(64) {
+        let (left, right) = if index & 1 == 0 { (&node[..], sibling) } else { (sibling, &node[..]) };
+        node = Sha512::new().chain_update([1]).chain_update(left).chain_update(right).finalize().to_vec();
+        index >>= 1;
+    }
+    if node != field(&signed, b"ROOT", Some(64))? {
+        return Err("Roughtime response is not for this nonce".to_string());
+    }
+    Ok(RoughtimeResponse { midpoint_us, radius_us })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::Signer;
+    use crate::receipt::SigningKey;
+
+    // Response of a server with `long_term` key to a request with `nonce` alone
+    fn roughtime_response(long_term: &SigningKey, nonce: &[u8; 64], midpoint: u64) -> Vec<u8> {
+        let online = SigningKey::from_bytes(&[2; 32]);
+        let delegation = encode_message(&[
+            (b"PUBK", online.verifying_key().as_bytes()),
+            (b"MINT", &0u64.to_le_bytes()),
+            (b"MAXT", &u64::MAX.to_le_bytes()),
+        ]);
+        let cert_signature = long_term.sign(&[ROUGHTIME_DELEGATION_CONTEXT, &delegation].concat()).to_bytes();
+        let cert = encode_message(&[(b"SIG\0", &cert_signature), (b"DELE", &delegation)]);
+        let root = Sha512::new().chain_update([0]).chain_update(nonce).finalize();
+        let signed = encode_message(&[(b"RADI", &1_000_000u32.to_le_bytes()), (b"MIDP", &midpoint.to_le_bytes()), (b"ROOT", &root)]);
+        let signature = online.sign(&[ROUGHTIME_RESPONSE_CONTEXT, &signed].concat()).to_bytes();
+        encode_message(&[(b"SIG\0", &signature), (b"PATH", &[]), (b"SREP", &signed), (b"CERT", &cert), (b"INDX", &0u32.to_le_bytes())])
+    }
+
+    #[test]
+    fn test_roughtime_response() {
+        let long_term = SigningKey::from_bytes(&[1; 32]);
+        let nonce = [7u8; 64];
+        let response = roughtime_response(&long_term, &nonce, 1_700_000_000_000_000);
+        assert_eq!(
+            verify_roughtime_response(&response, &nonce, &long_term.verifying_key()),
+            Ok(RoughtimeResponse { midpoint_us: 1_700_000_000_000_000, radius_us: 1_000_000 })
+        );
+        assert!(verify_roughtime_response(&response, &[8u8; 64], &long_term.verifying_key()).is_err());
+        assert!(verify_roughtime_response(&response, &nonce, &SigningKey::from_bytes(&[3; 32]).verifying_key()).is_err());
+        assert!(verify_roughtime_response(&response[..100], &nonce, &long_term.verifying_key()).is_err());
+
+        let request = roughtime_request(&nonce);
+        assert_eq!(request.len(), ROUGHTIME_REQUEST_SIZE);
+        assert_eq!(parse_message(&request).unwrap()[&tag(b"NONC")], &nonce[..]);
+    }
+
+    #[test]
+    fn test_ntp_transmit_time() {
+        let mut response = [0u8; 48];
+        response[0] = 0x1c;
+        response[1] = 2;
+        response[40..44].copy_from_slice(&((NTP_UNIX_OFFSET + 1_700_000_000) as u32).to_be_bytes());
+        response[44..48].copy_from_slice(&(1u32 << 31).to_be_bytes());
+        assert_eq!(ntp_transmit_time(&response), Some(UNIX_EPOCH + Duration::from_millis(1_700_000_000_500)));
+
+        // Kiss-o'-death packets have stratum 0
+        response[1] = 0;
+        assert_eq!(ntp_transmit_time(&response), None);
+    }
+
+    #[test]
+    fn test_attestation_summary() {
+        assert_eq!(SystemTimeSource.now().1.summary(), "system");
+        let attestation = TimeAttestation { source: "ntp".to_string(), server: Some("pool.ntp.org:123".to_string()), radius_ms: Some(12), ..TimeAttestation::system() };
+        assert_eq!(attestation.summary(), "ntp pool.ntp.org:123 ±12ms");
+    }
+}
@@ -0,0 +1,200 @@
+use std::sync::{Arc, RwLock};
+use crate::client::{AddResult, CheckResult, ClientError, TimestampingClient};
+use crate::storage::{Hash512, Hasher, LeafEncoding, MerkleTree, hasher_from_name};
+
+// Root of a shard's tree when it has no tree yet
+const EMPTY_SHARD_ROOT: Hash512 = Hash512([0; 8]);
+
+// Tree head of one shard that is part of a cluster tree
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShardHead {
+    pub version: u64,
+    pub root: Option<Hash512>,
+    pub leaf_count: usize,
+}
+
+// Top-level tree over the roots of all shards. A proof from a shard's tree, extended by the
+// path of that shard's root in this tree, proves inclusion under the cluster root.
+#[derive(Debug, Clone)]
+pub struct ClusterTree {
+    pub version: u64,
+    // `None` for shards that hadn't built a tree yet, their leaf is `EMPTY_SHARD_ROOT`
+    pub shards: Vec<Option<ShardHead>>,
+    pub tree: MerkleTree,
+}
+
+impl ClusterTree {
+    pub fn root(&self) -> Option<Hash512> {
+        self.tree.root()
+    }
+
+    pub fn leaf_count(&self) -> usize {
+        self.shards.iter().flatten().map(|head| head.leaf_count).sum()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClusterStats {
+    pub count: usize,
+    pub shard_counts: Vec<usize>,
+}
+
+// Coordinator of several timestamping servers, each storing the hashes of one prefix. Hashes are
+// routed by the low bits of their first word, i.e. the first bytes of the digest, so the shard
+// doesn't correlate with the worker thread inside the shard, which uses the high bits.
+#[derive(Debug)]
+pub struct ClusterCoordinator {
+    shards: Vec<TimestampingClient>,
+    urls: Vec<String>,
+    hasher: Arc<dyn Hasher>,
+    tree: RwLock<Option<Arc<ClusterTree>>>,
+    // Serializes tree updates, so versions are assigned in order
+    update_lock: tokio::sync::Mutex<()>,
+}
+
+impl ClusterCoordinator {
+    // Connect to the shards, which all have to build their trees with the same hasher
+    pub async fn connect(urls: &[String]) -> Result<Self, ClientError> {
+        assert!(urls.len().is_power_of_two(), "Number of shards must be a power of 2");
+        let shards: Vec<TimestampingClient> = urls.iter().map(|url| TimestampingClient::new(url)).collect();
+
+        let mut tree_hasher = None;
+        for shard in &shards {
+            let info = shard.info().await?;
+            if tree_hasher.as_ref().is_some_and(|name| *name != info.tree_hasher) {
+                return Err(ClientError::InvalidResponse("shards use different tree hashers"));
+            }
+            tree_hasher = Some(info.tree_hasher);
+        }
+        let hasher = hasher_from_name(&tree_hasher.unwrap()).ok_or(ClientError::InvalidResponse("unknown tree hasher"))?;
+
+        Ok(Self {
+            shards,
+            urls: urls.to_vec(),
+            hasher,
+            tree: RwLock::new(None),
+            update_lock: tokio::sync::Mutex::new(()),
+        })
+    }
+
+    pub fn shard_index(&self, hash: &Hash512) -> usize {
+        hash.0[0] as usize & (self.shards.len() - 1)
+    }
+
+    pub fn urls(&self) -> &[String] {
+        &self.urls
+    }
+
+    pub fn hasher(&self) -> &Arc<dyn Hasher> {
+        &self.hasher
+    }
+
+    // The latest cluster tree, `None` before the first update
+    pub fn tree(&self) -> Option<Arc<ClusterTree>> {
+        self.tree.read().unwrap().clone()
+    }
+
+    fn shard(&self, index: usize, encoding: LeafEncoding) -> TimestampingClient {
+        self.shards[index].clone().with_leaf_encoding(encoding)
+    }
+
+    // Add hashes to their shards, the counts are summed over all shards
+    pub async fn add_batch(&self, hashes: &[Hash512], encoding: LeafEncoding) -> Result<AddResult, ClientError> {
+        let mut partitions = vec![Vec::new(); self.shards.len()];
+        for hash in hashes {
+            partitions[self.shard_index(hash)].push(*hash);
+        }
+
+        let mut result = AddResult {
+            total_hashes: 0,
+            new_hashes: 0,
+            existing_hashes: 0,
+            duplicate_hashes: 0,
+            leaf_version: encoding.version(),
+            statuses: Vec::new(),
+            acknowledgments: Vec::new(),
+        };
+        let mut shard_statuses = Vec::with_capacity(partitions.len());
+        let mut shard_acknowledgments = Vec::with_capacity(partitions.len());
+        for (index, partition) in partitions.iter().enumerate() {
+            if partition.is_empty() {
+                shard_statuses.push(Vec::new().into_iter());
+                shard_acknowledgments.push(Vec::new().into_iter());
+                continue;
+            }
+            let shard_result = self.shard(index, encoding).add_batch(partition).await?;
+            result.total_hashes += shard_result.total_hashes;
+            result.new_hashes += shard_result.new_hashes;
+            result.existing_hashes += shard_result.existing_hashes;
+            result.duplicate_hashes += shard_result.duplicate_hashes;
+            shard_statuses.push(shard_result.statuses.into_iter());
+            shard_acknowledgments.push(shard_result.acknowledgments.into_iter());
+        }
+        // Copies of a hash go to the same shard, so its statuses are those of the whole batch.
+        // Shards that don't report statuses leave them out entirely.
+        let statuses: Option<Vec<_>> = hashes.iter().map(|hash| shard_statuses[self.shard_index(hash)].next()).collect();
+        result.statuses = statuses.unwrap_or_default();
+        // Signed by the shards' own keys, and also left out if any shard doesn't sign
+        let acknowledgments: Option<Vec<_>> = hashes.iter().map(|hash| shard_acknowledgments[self.shard_index(hash)].next()).collect();
+        result.acknowledgments = acknowledgments.unwrap_or_default();
+        Ok(result)
+    }
+
+    // Check a hash on its shard. The proof is taken from the shard tree that is part of the
+    // current cluster tree and extended up to the cluster root.
+    pub async fn check(&self, hash: &Hash512, encoding: LeafEncoding) -> Result<CheckResult, ClientError> {
+        let index = self.shard_index(hash);
+        let shard = self.shard(index, encoding);
+        let tree = self.tree();
+        let Some((tree, head)) = tree.as_ref().and_then(|tree| Some((tree, tree.shards[index].as_ref()?))) else {
+            let mut result = shard.check(hash).await?;
+            result.merkle_proof = None;
+            result.tree_version = None;
+            return Ok(result);
+        };
+
+        let mut result = shard.check_at_version(hash, head.version).await?;
+        if let Some(proof) = &mut result.merkle_proof {
+            proof.extend(tree.tree.proof_at(index).unwrap());
+        }
+        result.tree_version = Some(tree.version);
+        Ok(result)
+    }
+
+    // Update the trees of all shards and combine their roots into a new cluster tree
+    pub async fn update_tree(&self) -> Result<Arc<ClusterTree>, ClientError> {
+        let _guard = self.update_lock.lock().await;
+
+        let mut heads = Vec::with_capacity(self.shards.len());
+        for shard in &self.shards {
+            shard.update_tree().await?;
+            let head = shard.replication_head().await?.map(|replication| ShardHead {
+                version: replication.head.version,
+                root: replication.head.root,
+                leaf_count: replication.head.leaf_count,
+            });
+            heads.push(head);
+        }
+
+        let leaves = heads.iter()
+            .map(|head| head.as_ref().and_then(|head| head.root).unwrap_or(EMPTY_SHARD_ROOT))
+            .collect();
+        let version = self.tree().map_or(0, |tree| tree.version + 1);
+        let tree = Arc::new(ClusterTree {
+            version,
+            shards: heads,
+            // The leaves are roots rather than submitted hashes, so they aren't salted
+            tree: MerkleTree::with_hasher(leaves, EMPTY_SHARD_ROOT, Arc::clone(&self.hasher)),
+        });
+        *self.tree.write().unwrap() = Some(Arc::clone(&tree));
+        Ok(tree)
+    }
+
+    pub async fn stats(&self) -> Result<ClusterStats, ClientError> {
+        let mut shard_counts = Vec::with_capacity(self.shards.len());
+        for shard in &self.shards {
+            shard_counts.push(shard.stats().await?.count);
+        }
+        Ok(ClusterStats { count: shard_counts.iter().sum(), shard_counts })
+    }
+}
@@ -0,0 +1,182 @@
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use crate::storage::{Hash512, Hash512Ops};
+
+// Start of every cold index file, followed by the bound and the number of records
+const MAGIC: &[u8; 4] = b"TSCI";
+const HEADER_SIZE: u64 = MAGIC.len() as u64 + 16;
+// Salted hash and its sequence number
+const RECORD_SIZE: usize = 72;
+// Records per fence pointer. A lookup reads one block, 4.5 KiB.
+const BLOCK_RECORDS: usize = 64;
+
+// Salted hashes of one worker moved out of memory, as a file of records sorted by hash: the 64 byte hash and its
+// 8 byte big-endian sequence number. The first hash of every block is kept in memory (fence pointers), so a lookup
+// reads a single block. Written once and replaced as a whole when more hashes are moved.
+#[derive(Debug)]
+pub struct ColdIndex {
+    path: PathBuf,
+    file: Mutex<File>,
+    fences: Vec<Hash512>,
+    len: usize,
+    // Every hash of the worker with a lower sequence number is in the index, the others are in memory
+    bound: u64,
+}
+
+impl ColdIndex {
+    pub fn path(dir: &Path, thread_index: usize) -> PathBuf {
+        dir.join(format!("cold-{}.bin", thread_index))
+    }
+
+    // Write `records`, sorted by hash, to `path` and open it. Written to a temporary file first,
+    // so a crash leaves the previous index intact.
+    pub fn write(path: &Path, bound: u64, records: &[(Hash512, u64)]) -> io::Result<Self> {
+        let tmp_path = path.with_extension("bin.tmp");
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        writer.write_all(MAGIC)?;
+        writer.write_all(&bound.to_be_bytes())?;
+        writer.write_all(&(records.len() as u64).to_be_bytes())?;
+        for (hash, sequence) in records {
+            writer.write_all(&hash.to_bytes())?;
+            writer.write_all(&sequence.to_be_bytes())?;
+        }
+        writer.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        std::fs::rename(&tmp_path, path)?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            file: Mutex::new(File::open(path)?),
+            fences: records.iter().step_by(BLOCK_RECORDS).map(|(hash, _)| *hash).collect(),
+            len: records.len(),
+            bound,
+        })
+    }
+
+    // Open the index at `path`, `None` if there is none
+    pub fn open(path: &Path) -> io::Result<Option<Self>> {
+        let mut file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let mut header = [0u8; HEADER_SIZE as usize];
+        file.read_exact(&mut header)?;
+        if &header[..4] != MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} is not a cold index", path.display())));
+        }
+        let bound = u64::from_be_bytes(header[4..12].try_into().unwrap());
+        let len = u64::from_be_bytes(header[12..20].try_into().unwrap()) as usize;
+        if file.metadata()?.len() != HEADER_SIZE + (len * RECORD_SIZE) as u64 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} is truncated", path.display())));
+        }
+        let mut fences = Vec::with_capacity(len.div_ceil(BLOCK_RECORDS));
+        let mut record = [0u8; RECORD_SIZE];
+        for block in 0..len.div_ceil(BLOCK_RECORDS) {
+            file.seek(SeekFrom::Start(HEADER_SIZE + (block * BLOCK_RECORDS * RECORD_SIZE) as u64))?;
+            file.read_exact(&mut record)?;
+            fences.push(Hash512::from_bytes(&record[..64]).unwrap());
+        }
+        Ok(Some(Self { path: path.to_path_buf(), file: Mutex::new(file), fences, len, bound }))
+    }
+
+    // Sequence number of a salted hash, `None` if it isn't in the index
+    pub fn get(&self, salted_hash: &Hash512) -> io::Result<Option<u64>> {
+        let block = match self.fences.partition_point(|first| first <= salted_hash) {
+            0 => return Ok(None),
+            after => after - 1,
+        };
+        let records = BLOCK_RECORDS.min(self.len - block * BLOCK_RECORDS);
+        let mut bytes = vec![0u8; records * RECORD_SIZE];
+        {
+            let mut file = self.file.lock().unwrap();
+            file.seek(SeekFrom::Start(HEADER_SIZE + (block * BLOCK_RECORDS * RECORD_SIZE) as u64))?;
+            file.read_exact(&mut bytes)?;
+        }
+        let records: Vec<(Hash512, u64)> = bytes.chunks_exact(RECORD_SIZE).map(parse_record).collect();
+        Ok(records.binary_search_by(|(hash, _)| hash.cmp(salted_hash)).ok().map(|index| records[index].1))
+    }
+
+    // All records, sorted by hash
+    pub fn records(&self) -> io::Result<Vec<(Hash512, u64)>> {
+        self.iter()?.collect()
+    }
+
+    // The records of `records`, read from disk as they are iterated. The file stays open, so the iterator keeps
+    // returning the records of this index even after it is replaced.
+    pub fn iter(&self) -> io::Result<impl Iterator<Item = io::Result<(Hash512, u64)>> + use<>> {
+        let mut reader = BufReader::new(File::open(&self.path)?);
+        reader.seek(SeekFrom::Start(HEADER_SIZE))?;
+        Ok((0..self.len).map(move |_| {
+            let mut record = [0u8; RECORD_SIZE];
+            reader.read_exact(&mut record)?;
+            Ok(parse_record(&record))
+        }))
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn bound(&self) -> u64 {
+        self.bound
+    }
+
+    // Bytes of the fence pointers, the records themselves stay on disk
+    pub fn memory_usage(&self) -> usize {
+        self.fences.capacity() * size_of::<Hash512>()
+    }
+}
+
+fn parse_record(record: &[u8]) -> (Hash512, u64) {
+    (Hash512::from_bytes(&record[..64]).unwrap(), u64::from_be_bytes(record[64..].try_into().unwrap()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("timestamping-cold-{}-{}.bin", name, std::process::id()))
+    }
+
+    #[test]
+    fn test_write_and_lookup() {
+        let path = temp_path("lookup");
+        let mut records: Vec<(Hash512, u64)> = (0..1000).map(|sequence| (Hash512(rand::random()), sequence)).collect();
+        records.sort();
+        let index = ColdIndex::write(&path, 1000, &records).unwrap();
+        assert_eq!((index.len(), index.bound()), (1000, 1000));
+        assert_eq!(index.memory_usage(), 16 * size_of::<Hash512>());
+        for (hash, sequence) in &records {
+            assert_eq!(index.get(hash).unwrap(), Some(*sequence));
+        }
+        assert_eq!(index.get(&Hash512([0; 8])).unwrap(), None);
+        assert_eq!(index.get(&Hash512([u64::MAX; 8])).unwrap(), None);
+        assert_eq!(index.get(&Hash512(rand::random())).unwrap(), None);
+
+        let reopened = ColdIndex::open(&path).unwrap().unwrap();
+        assert_eq!(reopened.fences, index.fences);
+        assert_eq!(reopened.records().unwrap(), records);
+        std::fs::remove_file(&path).unwrap();
+        assert!(ColdIndex::open(&path).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_truncated() {
+        let path = temp_path("truncated");
+        ColdIndex::write(&path, 3, &[(Hash512([1; 8]), 0), (Hash512([2; 8]), 2)]).unwrap();
+        let file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(HEADER_SIZE + RECORD_SIZE as u64).unwrap();
+        assert_eq!(ColdIndex::open(&path).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        std::fs::remove_file(&path).unwrap();
+
+        let empty = ColdIndex::write(&path, 0, &[]).unwrap();
+        assert_eq!(empty.get(&Hash512([1; 8])).unwrap(), None);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1 +1,46 @@
+pub mod verify;
+pub mod receipt;
+#[cfg(feature = "server")]
 pub mod storage;
+#[cfg(feature = "server")]
+pub mod bloom;
+#[cfg(feature = "server")]
+pub mod cold;
+#[cfg(feature = "server")]
+pub mod pruned;
+#[cfg(feature = "server")]
+pub mod proof_cache;
+#[cfg(feature = "server")]
+pub mod tenants;
+#[cfg(feature = "server")]
+pub mod pow;
+#[cfg(feature = "server")]
+pub mod test_vectors;
+#[cfg(feature = "server")]
+pub mod spec;
+#[cfg(feature = "server")]
+pub mod clock;
+#[cfg(feature = "server")]
+pub mod metrics;
+#[cfg(feature = "server")]
+pub mod peering;
+#[cfg(feature = "server")]
+pub mod webhooks;
+#[cfg(feature = "server")]
+pub mod snapshot;
+#[cfg(feature = "server")]
+pub mod root_log;
+#[cfg(feature = "server")]
+pub mod caching;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+#[cfg(feature = "client")]
+pub mod client;
+#[cfg(feature = "client")]
+pub mod cluster;
+#[cfg(feature = "client")]
+pub mod transparency;
+#[cfg(feature = "client")]
+pub mod manifest;
+#[cfg(feature = "client")]
+pub mod backup;
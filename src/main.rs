@@ -1,209 +1,5194 @@
 use axum::{
-    body::Bytes,
-    extract::{Json, State},
-    http::{Method, StatusCode, header},
+    body::{Body, BodyDataStream, Bytes, HttpBody},
+    extract::{ConnectInfo, Extension, DefaultBodyLimit, FromRequest, FromRequestParts, Json, Multipart, Path as UrlPath, Query, Request, State, ws::{Message, WebSocket, WebSocketUpgrade}},
+    http::{HeaderMap, HeaderName, HeaderValue, Method, StatusCode, header, request::Parts},
+    middleware::{self, Next},
+    response::{Html, IntoResponse, Response, sse::{Event, KeepAlive, Sse}},
     routing::{get, post},
     Router,
 };
-use tower_http::cors::{Any, CorsLayer};
-use serde::Serialize;
-use std::sync::Arc;
+use base64::Engine;
+use futures_util::{Stream, StreamExt};
+use tower::ServiceExt;
+use tower_http::compression::{CompressionLayer, DefaultPredicate, Predicate, predicate::SizeAbove};
+use tower_http::cors::{AllowOrigin, Any, CorsLayer};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use sha2::{Digest, Sha512};
+use std::collections::{HashMap, HashSet};
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast::{self, error::RecvError};
+use utoipa::{IntoParams, Modify, OpenApi, ToSchema};
 
-mod storage;
-use crate::storage::{TimestampingService, Hash512, Hash512Ops};
+#[cfg(feature = "client")]
+use timestamping::client::TimestampingClient;
+#[cfg(feature = "client")]
+use timestamping::receipt::tree_head_message;
+#[cfg(feature = "client")]
+use timestamping::transparency::TransparencyLog;
+#[cfg(feature = "client")]
+use timestamping::backup::{Backup, Credentials, ObjectStore};
+use timestamping::receipt::{Acknowledgment, Cosignature, Receipt, SaltMigration, VerifyingKey};
+use timestamping::peering::{CosignError, Peer, SignedTreeHead, Witness};
+use timestamping::webhooks::{WebhookError, Webhooks};
+use timestamping::snapshot::{SnapshotError, SnapshotWriter, read_snapshot};
+use timestamping::root_log::{RootLog, RootLogEntry};
+use timestamping::caching::{format_http_date, not_modified};
+#[cfg(feature = "client")]
+use timestamping::webhooks::{self, WebhookEvent};
+use timestamping::bloom::BloomFilterStats;
+use timestamping::proof_cache::ProofCacheStats;
+use timestamping::tenants::{TenantTree, Tenants};
+use timestamping::pow;
+use timestamping::test_vectors::{self, TestVectors};
+use timestamping::spec::{self, ProofSpec};
+use timestamping::metrics::{LatencyHistogram, RequestLatencySummary};
+use timestamping::clock::{NtpTimeSource, RoughtimeTimeSource, TimeAttestation, TimeSource};
+use timestamping::storage::{TimestampingService, TreeHead, AcceptedHash, AddStatus, AdminAction, AdminLog, CHAIN_LENGTH_HISTOGRAM_SIZE, ChainLimit, ChainOverflow, EpochLog, EpochSummary, AuditReport, Hash512, Hash512Ops, HashAlgorithm, LeafPruning, HashMetadata, Hasher, LeafEncoding, MemoryUsage, Partitioning, PublishedTree, ResizePolicy, Resubmissions, ResizeProgress, ServiceError, StorageError, StoreCheck, StoreConfig, StoreLimits, TransparencyLogEntry, DEFAULT_QUEUE_CAPACITY, LEAF_ORDER, hasher_from_name, decode_hex, load_or_create_signing_key};
 
-#[derive(Debug, Serialize)]
+// Machine-readable reason of an error response, each with its own status code
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+enum ErrorCode {
+    InvalidRequest, // Malformed query parameters or path
+    InvalidHash, // The body isn't the expected digests
+    InvalidMetadata,
+    UnsupportedLeafVersion,
+    LeafVersionMismatch, // The hash isn't stored with the requested leaf version, but with another one
+    BatchTooLarge,
+    NotFound,
+    TreeVersionUnavailable,
+    WaitTimeout, // No tree included the hash within the timeout of /wait
+    StoreFull,
+    BucketFull, // The hash's bucket holds $TIMESTAMPING_MAX_BUCKET_LENGTH hashes with $TIMESTAMPING_BUCKET_OVERFLOW=reject
+    Saturated, // The workers are behind, retry after the `Retry-After` header
+    RateLimited, // The client sent too many requests, retry after the `Retry-After` header
+    Timeout, // The response wasn't ready within $TIMESTAMPING_REQUEST_TIMEOUT
+    WorkerUnavailable, // The worker responsible for some of the hashes failed
+    StreamReadFailed,
+    Unauthorized,
+    ReadOnly,
+    ProofOfWorkRequired, // Adds need a nonce solving a challenge from /challenge
+    IdempotencyKeyInUse, // A request with the same key is still being handled
+    IdempotencyKeyReused, // The key was used for a different request
+    AdminLogFailed,
+    ResaltRefused, // No signed tree to link from, or a re-salted store is already waiting to be switched to
+    Forbidden, // The role of the API key doesn't allow the route
+    CosignRefused, // The tree head of a peer is invalid, too far from the current time or inconsistent with an earlier one
+    ReplicationUnsupported, // Trees are published from a mountain range, which replicas can't rebuild from the leaf logs
+    #[cfg(feature = "client")]
+    ShardUnavailable,
+    Internal,
+}
+
+impl ErrorCode {
+    fn status(self) -> StatusCode {
+        match self {
+            ErrorCode::InvalidRequest
+            | ErrorCode::InvalidHash
+            | ErrorCode::InvalidMetadata
+            | ErrorCode::UnsupportedLeafVersion
+            | ErrorCode::StreamReadFailed => StatusCode::BAD_REQUEST,
+            ErrorCode::BatchTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            ErrorCode::NotFound | ErrorCode::TreeVersionUnavailable => StatusCode::NOT_FOUND,
+            ErrorCode::WaitTimeout => StatusCode::REQUEST_TIMEOUT,
+            ErrorCode::StoreFull | ErrorCode::BucketFull => StatusCode::INSUFFICIENT_STORAGE,
+            ErrorCode::Saturated | ErrorCode::WorkerUnavailable | ErrorCode::Timeout => StatusCode::SERVICE_UNAVAILABLE,
+            ErrorCode::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+            ErrorCode::Unauthorized => StatusCode::UNAUTHORIZED,
+            ErrorCode::ReadOnly | ErrorCode::ProofOfWorkRequired | ErrorCode::Forbidden => StatusCode::FORBIDDEN,
+            ErrorCode::IdempotencyKeyInUse | ErrorCode::ResaltRefused | ErrorCode::CosignRefused | ErrorCode::LeafVersionMismatch => StatusCode::CONFLICT,
+            ErrorCode::IdempotencyKeyReused => StatusCode::UNPROCESSABLE_ENTITY,
+            ErrorCode::AdminLogFailed | ErrorCode::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorCode::ReplicationUnsupported => StatusCode::NOT_IMPLEMENTED,
+            #[cfg(feature = "client")]
+            ErrorCode::ShardUnavailable => StatusCode::BAD_GATEWAY,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+struct ErrorBody {
+    code: ErrorCode,
+    message: String,
+    // Context of the error, e.g. how many hashes of a stream were added before it failed
+    #[schema(value_type = Option<Object>)]
+    details: Option<serde_json::Value>,
+}
+
+// Body of every error response
+#[derive(Debug, Serialize, ToSchema)]
+struct ErrorResponse {
+    error: ErrorBody,
+}
+
+// Error of a handler, sent as an `ErrorResponse` with the status of its code
+#[derive(Debug)]
+struct ApiError(ErrorBody);
+
+impl ApiError {
+    fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self(ErrorBody { code, message: message.into(), details: None })
+    }
+
+    fn with_details(mut self, details: serde_json::Value) -> Self {
+        self.0.details = Some(details);
+        self
+    }
+}
+
+impl From<StorageError> for ApiError {
+    fn from(error: StorageError) -> Self {
+        match error {
+            StorageError::Saturated(_) => ApiError::new(ErrorCode::Saturated, MSG_SATURATED),
+            StorageError::MaxHashes(_) | StorageError::MaxMemory(_) => ApiError::new(ErrorCode::StoreFull, MSG_STORE_FULL),
+            StorageError::WorkerUnavailable(_) => ApiError::new(ErrorCode::WorkerUnavailable, MSG_WORKER_UNAVAILABLE),
+            StorageError::BucketFull(_) => ApiError::new(ErrorCode::BucketFull, MSG_BUCKET_FULL),
+            StorageError::Backend(_) => ApiError::new(ErrorCode::Internal, MSG_STORAGE_BACKEND_FAILED),
+            StorageError::Unsupported(what) => ApiError::new(ErrorCode::Internal, format!("The store doesn't support {}", what)),
+        }
+    }
+}
+
+impl From<ServiceError> for ApiError {
+    fn from(error: ServiceError) -> Self {
+        match error {
+            ServiceError::Storage(error) => error.into(),
+            ServiceError::TreeVersionUnavailable(_) => tree_version_unavailable(),
+            ServiceError::AdminLog(error) => {
+                log_request(format!("Failed to write admin log: {}", error));
+                ApiError::new(ErrorCode::AdminLogFailed, MSG_ADMIN_LOG_FAILED)
+            }
+            ServiceError::ResaltRefused(reason) => ApiError::new(ErrorCode::ResaltRefused, format!("Salt migration refused - {}", reason)),
+            ServiceError::UnknownHashes(count) => ApiError::new(ErrorCode::InvalidHash, MSG_RESALT_UNKNOWN_HASHES)
+                .with_details(serde_json::json!({ "unknown_hashes": count })),
+            ServiceError::Resalt(error) => {
+                log_request(format!("Failed to create the re-salted store: {}", error));
+                ApiError::new(ErrorCode::Internal, MSG_RESALT_FAILED)
+            }
+        }
+    }
+}
+
+impl From<CosignError> for ApiError {
+    fn from(error: CosignError) -> Self {
+        match error {
+            CosignError::UnknownPeer => ApiError::new(ErrorCode::Forbidden, MSG_UNKNOWN_PEER),
+            error => ApiError::new(ErrorCode::CosignRefused, format!("Cosigning refused - {}", error)),
+        }
+    }
+}
+
+impl From<WebhookError> for ApiError {
+    fn from(error: WebhookError) -> Self {
+        match error {
+            WebhookError::InvalidUrl => ApiError::new(ErrorCode::InvalidRequest, MSG_INVALID_WEBHOOK_URL),
+            WebhookError::Configured => ApiError::new(ErrorCode::InvalidRequest, MSG_CONFIGURED_WEBHOOK),
+            WebhookError::Io(error) => {
+                log_request(format!("Failed to write the registered webhooks: {}", error));
+                ApiError::new(ErrorCode::Internal, MSG_WEBHOOKS_FAILED)
+            }
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let mut response = (self.0.code.status(), Json(ErrorResponse { error: self.0 })).into_response();
+        if response.status() == StatusCode::SERVICE_UNAVAILABLE {
+            response.headers_mut().insert(header::RETRY_AFTER, HeaderValue::from(SATURATED_RETRY_AFTER.as_secs()));
+        }
+        response
+    }
+}
+
+// `Query` and `Path` with their rejections sent as an `ErrorResponse`
+struct ApiQuery<T>(T);
+struct ApiPath<T>(T);
+
+impl<T: DeserializeOwned, S: Send + Sync> FromRequestParts<S> for ApiQuery<T> {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, ApiError> {
+        match Query::<T>::from_request_parts(parts, state).await {
+            Ok(Query(query)) => Ok(ApiQuery(query)),
+            Err(rejection) => Err(ApiError::new(ErrorCode::InvalidRequest, MSG_INVALID_QUERY).with_details(rejection.body_text().into())),
+        }
+    }
+}
+
+impl<T: DeserializeOwned + Send, S: Send + Sync> FromRequestParts<S> for ApiPath<T> {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, ApiError> {
+        match UrlPath::<T>::from_request_parts(parts, state).await {
+            Ok(UrlPath(path)) => Ok(ApiPath(path)),
+            Err(rejection) => Err(ApiError::new(ErrorCode::InvalidRequest, MSG_INVALID_PATH).with_details(rejection.body_text().into())),
+        }
+    }
+}
+
+// OpenAPI description of the server, served at /openapi.json. The admin routes only exist
+// if an admin token is configured, replicas refuse the routes tagged `add`, the routes tagged
+// `tenants` only exist if tenants are configured, /challenge only with a proof of work difficulty
+// /cosign only with peers and the webhook routes only with the `client` feature on primaries.
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "Timestamping", description = "Submit hashes, publish merkle trees over them and get proofs of their inclusion"),
+    paths(
+        add, add_batch, get_job, add_stream, add_data, add_private, check, check_private, check_batch, check_stream, validate_batch, wait, get_hash, update_tree,
+        get_stats, get_healthz, get_epochs, get_hashes, get_feed, get_roots, get_root, get_audit_log, ws, get_version, get_info, get_test_vectors, get_spec,
+        get_replication_head, get_replication_leaves, admin_remove, admin_clear, admin_resalt, admin_log,
+        admin_webhooks, admin_add_webhook, admin_remove_webhook, admin_export, admin_verify_store,
+        get_tenants, get_tenant_proof, update_tenant_trees, get_challenge, cosign,
+    ),
+    components(schemas(ErrorCode)),
+    modifiers(&ServerSpec),
+)]
+struct ApiDoc;
+
+// Adds the security scheme of the admin routes and drops the license, which the crate doesn't declare
+struct ServerSpec;
+
+impl Modify for ServerSpec {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        use utoipa::openapi::security::{Http, HttpAuthScheme, SecurityScheme};
+        openapi.info.license = None;
+        openapi.components.get_or_insert_with(Default::default)
+            .add_security_scheme("admin_token", SecurityScheme::Http(Http::new(HttpAuthScheme::Bearer)));
+    }
+}
+
+// Swagger UI for /openapi.json, with its assets served from $TIMESTAMPING_SWAGGER_UI_DIR by `get_docs_asset`
+const SWAGGER_UI_HTML: &str = r##"<!DOCTYPE html>
+<html>
+<head>
+    <meta charset="utf-8">
+    <title>Timestamping API</title>
+    <link rel="stylesheet" href="docs/swagger-ui.css">
+</head>
+<body>
+    <div id="swagger-ui"></div>
+    <script src="docs/swagger-ui-bundle.js"></script>
+    <script>SwaggerUIBundle({ url: "openapi.json", dom_id: "#swagger-ui" });</script>
+</body>
+</html>
+"##;
+
+// Files of a swagger-ui-dist release that the Swagger UI page loads, with their content types
+const SWAGGER_UI_ASSETS: [(&str, &str); 2] = [("swagger-ui.css", "text/css"), ("swagger-ui-bundle.js", "text/javascript")];
+
+// Without Swagger UI, /docs lists the routes of /openapi.json with a script of its own, so no page loads code from elsewhere
+const API_REFERENCE_HTML: &str = r##"<!DOCTYPE html>
+<html>
+<head>
+    <meta charset="utf-8">
+    <title>Timestamping API</title>
+    <style>body { font-family: sans-serif; margin: 2em; } code { font-size: 1.1em; } .route { margin: 1.5em 0; } li { margin: 0.2em 0; }</style>
+</head>
+<body>
+    <h1>Timestamping API</h1>
+    <p>Routes of <a href="openapi.json">openapi.json</a>.</p>
+    <div id="routes"></div>
+    <script>
+        const add = (parent, tag, text) => {
+            const element = parent.appendChild(document.createElement(tag));
+            if (text) element.textContent = text;
+            return element;
+        };
+        fetch("openapi.json").then(response => response.json()).then(spec => {
+            const routes = document.getElementById("routes");
+            for (const [path, item] of Object.entries(spec.paths)) {
+                for (const [method, operation] of Object.entries(item)) {
+                    const route = add(routes, "div");
+                    route.className = "route";
+                    add(add(route, "h3"), "code", method.toUpperCase() + " " + path);
+                    if (operation.description) add(route, "p", operation.description);
+                    const parameters = add(route, "ul");
+                    for (const parameter of operation.parameters || []) {
+                        const required = parameter.required ? ", required" : "";
+                        add(parameters, "li", parameter.name + " (" + parameter.in + required + ")" + (parameter.description ? ": " + parameter.description : ""));
+                    }
+                    for (const [status, response] of Object.entries(operation.responses || {})) {
+                        add(parameters, "li", status + ": " + (response.description || ""));
+                    }
+                }
+            }
+        });
+    </script>
+</body>
+</html>
+"##;
+
+async fn get_openapi() -> Json<utoipa::openapi::OpenApi> {
+    Json(ApiDoc::openapi())
+}
+
+// Directory of an unpacked swagger-ui-dist release, from $TIMESTAMPING_SWAGGER_UI_DIR
+fn swagger_ui_dir() -> Option<PathBuf> {
+    std::env::var_os("TIMESTAMPING_SWAGGER_UI_DIR").map(PathBuf::from)
+}
+
+// Refuse to start with a Swagger UI directory that lacks the assets the page loads
+fn check_swagger_ui_dir() {
+    let Some(dir) = swagger_ui_dir() else {
+        return;
+    };
+    for (name, _) in SWAGGER_UI_ASSETS {
+        if !dir.join(name).is_file() {
+            panic!("TIMESTAMPING_SWAGGER_UI_DIR has to contain {} of a swagger-ui-dist release, {} doesn't", name, dir.display());
+        }
+    }
+    println!("Serving Swagger UI from {} at GET /docs", dir.display());
+}
+
+async fn get_docs() -> Html<&'static str> {
+    Html(if swagger_ui_dir().is_some() { SWAGGER_UI_HTML } else { API_REFERENCE_HTML })
+}
+
+async fn get_docs_asset(UrlPath(name): UrlPath<String>) -> Response {
+    let asset = SWAGGER_UI_ASSETS.iter().find(|(asset, _)| *asset == name);
+    match (asset, swagger_ui_dir()) {
+        (Some((name, content_type)), Some(dir)) => match tokio::fs::read(dir.join(name)).await {
+            Ok(bytes) => ([(header::CONTENT_TYPE, *content_type), (header::CACHE_CONTROL, "public, max-age=86400")], bytes).into_response(),
+            Err(_) => StatusCode::NOT_FOUND.into_response(),
+        },
+        _ => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+const DASHBOARD_STYLE: &str = "body { font-family: sans-serif; margin: 2em; } td, th { padding: 0.2em 0.8em; text-align: left; } \
+    code { word-break: break-all; } .bar { background: #4a7fb5; height: 0.8em; }";
+
+// Status page for operators at /, rendered on the server so it needs neither the frontend nor a metrics stack
+async fn get_dashboard(
+    State(service): State<Arc<TimestampingService<INDEX_SIZE, PREFIX_SIZE>>>,
+) -> Html<String> {
+    let stats = service.hash_store.stats();
+    let recent: Vec<EpochSummary> = {
+        let epochs = service.epochs.read().unwrap();
+        epochs.page(epochs.len().saturating_sub(DASHBOARD_ROOTS), DASHBOARD_ROOTS).to_vec()
+    };
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |duration| duration.as_secs());
+    let time = |timestamp: u64| format!("{} ({}s ago)", timestamp, now.saturating_sub(timestamp));
+
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<meta http-equiv=\"refresh\" content=\"{}\">\n\
+        <title>Timestamping status</title>\n<style>{}</style>\n</head>\n<body>\n<h1>Timestamping {}</h1>\n<table>\n",
+        DASHBOARD_REFRESH.as_secs(), DASHBOARD_STYLE, env!("CARGO_PKG_VERSION"),
+    );
+    let published = service.published_tree();
+    let root = published.as_ref().and_then(|published| published.head.root)
+        .map_or("-".to_string(), |root| format!("<code>{}</code>", root));
+    let last_update = published.as_ref().map_or("-".to_string(), |published| time(published.updated_at));
+    let rate = ingestion_rate(&recent).map_or("-".to_string(), |rate| format!("{:.2} hashes/s over the last {} tree updates", rate, recent.len() - 1));
+    let rows = [
+        ("Root", root),
+        ("Tree size", published.as_ref().map_or(0, |published| published.head.tree_size).to_string()),
+        ("Hashes", stats.hashes.to_string()),
+        ("Last tree update", last_update),
+        ("Ingestion", rate),
+    ];
+    for (name, value) in rows {
+        html += &format!("<tr><th>{}</th><td>{}</td></tr>\n", name, value);
+    }
+
+    html += "</table>\n<h2>Bucket occupancy</h2>\n<table>\n<tr><th>Hashes</th><th>Buckets</th><th></th></tr>\n";
+    let largest = stats.chain_lengths.iter().copied().max().unwrap_or(0).max(1);
+    for (len, count) in stats.chain_lengths.iter().enumerate() {
+        let label = if len == CHAIN_LENGTH_HISTOGRAM_SIZE - 1 { format!("{}+", len) } else { len.to_string() };
+        html += &format!("<tr><td>{}</td><td>{}</td><td style=\"width: 20em\"><div class=\"bar\" style=\"width: {}%\"></div></td></tr>\n",
+            label, count, count * 100 / largest);
+    }
+
+    html += "</table>\n<h2>Recent roots</h2>\n<table>\n<tr><th>Version</th><th>Time</th><th>Leaves</th><th>New hashes</th><th>Root</th></tr>\n";
+    for epoch in recent.iter().rev() {
+        let root = epoch.root.map_or("-".to_string(), |root| format!("<code>{}</code>", root));
+        html += &format!("<tr><td><a href=\"roots/{0}\">{0}</a></td><td>{1}</td><td>{2}</td><td>{3}</td><td>{4}</td></tr>\n",
+            epoch.epoch, time(epoch.timestamp), epoch.leaf_count, epoch.new_hashes, root);
+    }
+    html += "</table>\n<p><a href=\"stats\">Stats</a> · <a href=\"docs\">API</a></p>\n</body>\n</html>\n";
+    Html(html)
+}
+
+// New hashes per second from the first to the last of `epochs`, `None` without time between them
+fn ingestion_rate(epochs: &[EpochSummary]) -> Option<f64> {
+    let (first, last) = (epochs.first()?, epochs.last()?);
+    let seconds = last.timestamp.checked_sub(first.timestamp).filter(|&seconds| seconds > 0)?;
+    Some(epochs[1..].iter().map(|epoch| epoch.new_hashes).sum::<usize>() as f64 / seconds as f64)
+}
+
+#[derive(Debug, Serialize, ToSchema)]
 struct AddResponse {
-    success: bool,
     message: String,
     total_hashes: usize,
     new_hashes: usize,
     existing_hashes: usize,
+    leaf_version: u8,
+    hash_algorithm: HashAlgorithm,
+}
+
+// Response of /add, whose hashes are deduplicated within the batch
+#[derive(Debug, Serialize, ToSchema)]
+struct AddBatchResponse {
+    #[serde(flatten)]
+    add: AddResponse,
+    // Repeated hashes of the request, not counted as new or existing
+    duplicate_hashes: usize,
+    // In the order of the submitted hashes
+    statuses: Vec<AddStatus>,
+    input_encoding: InputEncoding,
+    // Signed right away, in the order of the submitted hashes. Empty if the server has no signing key.
+    acknowledgments: Vec<Acknowledgment>,
+}
+
+impl AddBatchResponse {
+    fn new(add: AddResponse, statuses: Vec<AddStatus>, input_encoding: InputEncoding, acknowledgments: Vec<Acknowledgment>) -> Self {
+        let duplicate_hashes = statuses.iter().filter(|&&status| status == AddStatus::Duplicate).count();
+        Self { add, duplicate_hashes, statuses, input_encoding, acknowledgments }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+enum JobState {
+    Running,
+    Completed,
+    // Stopped at an error, the hashes before `processed_hashes` were added
+    Failed,
+}
+
+// Progress of a batch added by /add-batch in the background
+#[derive(Debug, Clone, Serialize, ToSchema)]
+struct JobProgress {
+    id: String,
+    state: JobState,
+    // Received so far, all of the batch once /add-batch answered
+    total_hashes: usize,
+    processed_hashes: usize,
+    new_hashes: usize,
+    existing_hashes: usize,
+    duplicate_hashes: usize,
+    leaf_version: u8,
+    hash_algorithm: HashAlgorithm,
+    error: Option<String>,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct JobQuery {
+    // Range of the statuses to return
+    offset: Option<usize>,
+    limit: Option<usize>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+struct JobResponse {
+    #[serde(flatten)]
+    progress: JobProgress,
+    offset: usize,
+    // Of the processed hashes from `offset` on, in the order they were submitted
+    statuses: Vec<AddStatus>,
+}
+
+// Outcome /add would have for one entry of a batch
+#[derive(Debug, Serialize, ToSchema)]
+struct ValidatedEntry {
+    // `None` for invalid entries
+    status: Option<AddStatus>,
+    error: Option<&'static str>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+struct ValidateBatchResponse {
+    message: String,
+    // Whether /add would accept the batch as it is
+    valid: bool,
+    total_entries: usize,
+    new_hashes: usize,
+    existing_hashes: usize,
+    duplicate_hashes: usize,
+    invalid_entries: usize,
+    // In the order of the submitted entries
+    entries: Vec<ValidatedEntry>,
+    input_encoding: InputEncoding,
+    leaf_version: u8,
+    hash_algorithm: HashAlgorithm,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+struct AddDataResponse {
+    #[serde(flatten)]
+    add: AddResponse,
+    digests: Vec<Vec<u8>>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+struct AddPrivateResponse {
+    #[serde(flatten)]
+    add: AddResponse,
+    nonces: Vec<Vec<u8>>,
+}
+
+// Challenge for the proof of work of one add
+#[derive(Debug, Serialize, ToSchema)]
+struct ChallengeResponse {
+    // Base64 encoded, sent back as the X-Challenge header
+    challenge: String,
+    // Leading zero bits of the work over a single hash, one more for every doubling of the hashes
+    difficulty: u32,
+    // Seconds until the challenge can't be used anymore
+    expires_in: u64,
+    format: &'static str,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
 struct CheckHashResponse {
-    success: bool,
     message: &'static str,
     exists: bool,
     merkle_proof: Option<Vec<(Vec<u8>, Vec<u8>)>>,
+    metadata: Option<HashMetadata>,
+    leaf_version: u8,
+    tree_version: Option<u64>,
+    hash_algorithm: HashAlgorithm,
+    tree_hasher: &'static str,
+    // Order the hash was stored in, numbers increase with every new hash. Not shown for private hashes, like their metadata.
+    sequence: Option<u64>,
+    // How often the hash was submitted again and when last, `None` if only once. Not shown for private hashes either.
+    resubmissions: Option<Resubmissions>,
+    // Only set by /check, the other routes have a fixed encoding
+    #[serde(skip_serializing_if = "Option::is_none")]
+    input_encoding: Option<InputEncoding>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+struct CheckBatchEntry {
+    exists: bool,
+    merkle_proof: Option<Vec<(Vec<u8>, Vec<u8>)>>,
+    metadata: Option<HashMetadata>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+struct CheckBatchResponse {
+    message: &'static str,
+    results: Vec<CheckBatchEntry>,
+    leaf_version: u8,
+    tree_version: Option<u64>,
+    hash_algorithm: HashAlgorithm,
+    tree_hasher: &'static str,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct CheckQuery {
+    leaf_version: Option<u8>,
+    tree_version: Option<u64>,
+    #[serde(default)]
+    hash_algorithm: HashAlgorithm,
+    encoding: Option<InputEncoding>,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct CheckStreamQuery {
+    leaf_version: Option<u8>,
+    tree_version: Option<u64>,
+    #[serde(default)]
+    format: StreamFormat,
+    #[serde(default)]
+    hash_algorithm: HashAlgorithm,
+}
+
+// First line of a /check-stream response, followed by a `CheckBatchEntry` line for every hash
+#[derive(Debug, Serialize, ToSchema)]
+struct CheckStreamHead {
+    leaf_version: u8,
+    tree_version: Option<u64>,
+    hash_algorithm: HashAlgorithm,
+    tree_hasher: &'static str,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct WaitQuery {
+    leaf_version: Option<u8>,
+    #[serde(default)]
+    hash_algorithm: HashAlgorithm,
+    encoding: Option<InputEncoding>,
+    // Seconds to wait for a tree including the hash, at most 300
+    timeout: Option<u64>,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct AdminRemoveQuery {
+    leaf_version: Option<u8>,
+    #[serde(default)]
+    hash_algorithm: HashAlgorithm,
+    reason: Option<String>,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct AdminClearQuery {
+    reason: Option<String>,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct AdminResaltQuery {
+    leaf_version: Option<u8>,
+    #[serde(default)]
+    format: StreamFormat,
+    #[serde(default)]
+    hash_algorithm: HashAlgorithm,
+    reason: Option<String>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+struct AdminResaltResponse {
+    message: &'static str,
+    // Directory of the re-salted store, relative to the working directory of the server
+    dir: String,
+    migration: SaltMigration,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+struct AdminResponse {
+    message: &'static str,
+    removed: usize,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+struct AdminLogResponse {
+    actions: Vec<AdminAction>,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct WebhookQuery {
+    url: String,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+struct AdminWebhooksResponse {
+    message: &'static str,
+    // From $TIMESTAMPING_WEBHOOKS, these can't be removed
+    configured: Vec<String>,
+    registered: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct AddQuery {
+    leaf_version: Option<u8>,
+    #[serde(default)]
+    hash_algorithm: HashAlgorithm,
+    encoding: Option<InputEncoding>,
+    submitter: Option<String>,
+    label: Option<String>,
+    content_type: Option<String>,
+    // Hashes of namespaces in $TIMESTAMPING_RETENTION are removed once they are older than its retention
+    namespace: Option<String>,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct AddPrivateQuery {
+    leaf_version: Option<u8>,
+    #[serde(default)]
+    hash_algorithm: HashAlgorithm,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct AddDataQuery {
+    leaf_version: Option<u8>,
+    submitter: Option<String>,
+    label: Option<String>,
+    content_type: Option<String>,
+    namespace: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+enum StreamFormat {
+    #[default]
+    Raw, // Concatenated digests
+    Base64, // One base64 encoded digest per line
+    Json, // JSON array of hex or base64 encoded digests
+}
+
+// Where a streamed JSON array has been read up to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum JsonPosition {
+    BeforeArray,
+    // After `[`, where the array can also end
+    FirstValue,
+    // After `,`
+    NextValue,
+    AfterValue,
+    AfterArray,
+}
+
+// Encoding of the body of /add and /check
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+enum InputEncoding {
+    Raw, // Concatenated digests
+    Hex, // Hex encoded digests, separated by whitespace
+    Base64, // Base64 encoded digests, separated by whitespace
+}
+
+impl InputEncoding {
+    // The `encoding` parameter wins. Otherwise `text/plain` bodies are hex or base64, told apart by
+    // the length of their first digest, and everything else is raw, as curl sends raw files as forms.
+    fn negotiate(requested: Option<Self>, headers: &HeaderMap, bytes: &[u8], algorithm: HashAlgorithm) -> Self {
+        if let Some(encoding) = requested {
+            return encoding;
+        }
+        let is_text = headers.get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.trim_start().to_ascii_lowercase().starts_with("text/plain"));
+        if !is_text {
+            return InputEncoding::Raw;
+        }
+        let first = bytes.split(u8::is_ascii_whitespace).find(|token| !token.is_empty()).unwrap_or_default();
+        if first.len() == 2 * algorithm.digest_len() && first.iter().all(u8::is_ascii_hexdigit) {
+            InputEncoding::Hex
+        } else {
+            InputEncoding::Base64
+        }
+    }
+
+    fn decode_hashes(self, bytes: &[u8], algorithm: HashAlgorithm) -> Result<Vec<Hash512>, ApiError> {
+        if self == InputEncoding::Raw && !bytes.len().is_multiple_of(algorithm.digest_len()) {
+            return Err(ApiError::new(ErrorCode::InvalidHash, MSG_INVALID_BATCH_SIZE));
+        }
+        self.decode_entries(bytes, algorithm).into_iter()
+            .collect::<Result<_, _>>()
+            .map_err(|message| ApiError::new(ErrorCode::InvalidHash, message))
+    }
+
+    // Every entry of a batch on its own, with the reason it is invalid. A raw body ends in an
+    // invalid entry if its length isn't a multiple of the digest length.
+    fn decode_entries(self, bytes: &[u8], algorithm: HashAlgorithm) -> Vec<Result<Hash512, &'static str>> {
+        if self == InputEncoding::Raw {
+            return bytes.chunks(algorithm.digest_len())
+                .map(|chunk| algorithm.normalize(chunk).map_err(|_| MSG_INVALID_BATCH_SIZE))
+                .collect();
+        }
+        bytes.split(u8::is_ascii_whitespace)
+            .filter(|token| !token.is_empty())
+            .map(|token| {
+                let digest = match self {
+                    InputEncoding::Hex => decode_hex(token),
+                    _ => base64::engine::general_purpose::STANDARD.decode(token).ok(),
+                };
+                digest.and_then(|digest| algorithm.normalize(&digest).ok()).ok_or(MSG_INVALID_TEXT_HASH)
+            })
+            .collect()
+    }
+
+    // Exactly one digest, as expected by /check
+    fn decode_hash(self, bytes: &[u8], algorithm: HashAlgorithm) -> Result<Hash512, ApiError> {
+        let invalid_length = || ApiError::new(ErrorCode::InvalidHash, MSG_INVALID_LENGTH);
+        if self == InputEncoding::Raw {
+            return algorithm.normalize(bytes).map_err(|_| invalid_length());
+        }
+        match self.decode_hashes(bytes, algorithm)?[..] {
+            [hash] => Ok(hash),
+            _ => Err(invalid_length()),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct AddStreamQuery {
+    leaf_version: Option<u8>,
+    #[serde(default)]
+    format: StreamFormat,
+    #[serde(default)]
+    hash_algorithm: HashAlgorithm,
+    submitter: Option<String>,
+    label: Option<String>,
+    content_type: Option<String>,
+    namespace: Option<String>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+struct LeafEncodingInfo {
+    version: u8,
+    description: &'static str,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+struct HashAlgorithmInfo {
+    name: &'static str,
+    digest_len: usize,
+    description: &'static str,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+struct VersionResponse {
+    software_version: &'static str,
+    default_leaf_version: u8,
+    tree_hasher: &'static str,
+    // How the tree hasher combines two nodes, domain separated from leaves unless the store predates it
+    node_encoding: &'static str,
+    leaf_encodings: Vec<LeafEncodingInfo>,
+    hash_algorithms: Vec<HashAlgorithmInfo>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+struct InfoResponse {
+    #[serde(flatten)]
+    version: VersionResponse,
+    salt: Vec<u8>,
+    index_size: usize,
+    prefix_size: usize,
+    threads: usize,
+    // How hashes are spread over the workers, which replicas have to copy
+    partitioning: Partitioning,
+    proof_format: &'static str,
+    // "tree" for trees rebuilt on every update, "mmr" for a merkle mountain range with proofs against every version
+    accumulator: &'static str,
+    // Order of the salted hashes the leaves of a tree are made of, the same on every server with the same hashes
+    leaf_order: &'static str,
+    // Ed25519 key tree heads are signed with, `None` for replicas
+    public_key: Option<Vec<u8>>,
+    // Seconds hashes of each namespace are kept, from $TIMESTAMPING_RETENTION
+    retention: HashMap<String, u64>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
 struct UpdateTreeResponse {
-    success: bool,
     message: String,
     tree_size: usize,
     hash_count: usize,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, ToSchema)]
 struct GetStatsResponse {
     count: usize,
     slots: usize,
     total_slots: usize,
+    total_adds: usize,
+    duplicate_adds: usize,
+    worker_hashes: Vec<usize>,
     merkle_tree_size: usize,
     merkle_tree_root: Option<Vec<u8>>,
     last_tree_update: Option<u64>,
+    last_audit: Option<AuditReport>,
+    audit_divergences: usize,
+    replication_divergences: usize,
+    memory: MemoryUsage,
+    memory_total: usize,
+    max_hashes: Option<usize>,
+    max_memory: Option<usize>,
+    // Removed hashes, still counted in `count` and included in the trees
+    tombstones: usize,
+    // Hashes moved to disk after $TIMESTAMPING_COLD_AFTER_EPOCHS tree updates, still counted in `count`
+    cold_hashes: usize,
+    // Commands waiting for each worker, adds are refused while one has `queue_capacity`
+    worker_queue_depths: Vec<usize>,
+    queue_capacity: usize,
+    // Index bits of each worker's bucket table, which grows once chains get longer than $TIMESTAMPING_MAX_CHAIN_LENGTH
+    worker_index_sizes: Vec<usize>,
+    resizes: Vec<ResizeProgress>,
+    // Times each worker panicked and was restarted, see /healthz
+    worker_restarts: Vec<usize>,
+    // Present with $TIMESTAMPING_BLOOM_FILTER_CAPACITY set, `negatives` are checks answered without asking a worker
+    bloom_filter: Option<BloomFilterStats>,
+    // Proofs of hot hashes kept until the next tree update, absent with $TIMESTAMPING_PROOF_CACHE_SIZE=0
+    proof_cache: Option<ProofCacheStats>,
+    // Rolling percentiles of the last one to two minutes, to tell slow ingestion from slow proofs or tree builds
+    latencies: RequestLatencySummary,
+    // Bucket slots by the number of hashes they hold, the last entry includes longer chains. Long chains while
+    // `count / total_slots` is low hint at a weak salt or inputs crafted to land in the same buckets.
+    chain_lengths: Vec<usize>,
+    // New hashes whose bucket held $TIMESTAMPING_MAX_BUCKET_LENGTH hashes already
+    chain_overflows: usize,
+    // Hashes kept outside their full bucket with $TIMESTAMPING_BUCKET_OVERFLOW=spill, still counted in `count`
+    spilled_hashes: usize,
 }
 
-const INDEX_SIZE: usize = 28;
-const PREFIX_SIZE: usize = 0;
-const NUM_THREADS: usize = 8; // Number of threads for hash distribution
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct EpochsQuery {
+    offset: Option<usize>,
+    limit: Option<usize>,
+}
 
-// Pre-allocated response messages
-const MSG_HASH_FOUND: &str = "Hash found in store";
-const MSG_HASH_NOT_FOUND: &str = "Hash not found in store";
-const MSG_INVALID_LENGTH: &str = "Invalid hash length - must be exactly 64 bytes";
-const MSG_INVALID_BATCH_SIZE: &str = "Invalid batch size - must be multiple of 64 bytes";
+#[derive(Debug, Serialize, ToSchema)]
+struct GetEpochsResponse {
+    total: usize,
+    offset: usize,
+    limit: usize,
+    epochs: Vec<EpochSummary>,
+}
 
-#[tokio::main]
-async fn main() {
-    let timestamping_service = Arc::new(TimestampingService::<INDEX_SIZE, PREFIX_SIZE>::with_threads(NUM_THREADS));
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct HashesQuery {
+    offset: Option<usize>,
+    limit: Option<usize>,
+    since: Option<u64>,
+}
 
-    let cors = CorsLayer::new()
-        .allow_methods([Method::GET, Method::POST, Method::OPTIONS])
-        .allow_headers([header::CONTENT_TYPE])
-        .allow_origin(Any);
+#[derive(Debug, Serialize, ToSchema)]
+struct StoredHashEntry {
+    salted_hash: Vec<u8>,
+    metadata: Option<HashMetadata>,
+}
 
-    let app = Router::new()
-        .route("/add", post(add))
-        .route("/check", post(check))
-        .route("/update-tree", post(update_tree))
-        .route("/stats", get(get_stats))
-        .layer(cors)
-        .with_state(timestamping_service);
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct FeedQuery {
+    // Only hashes whose salted hash starts with these hex encoded bytes
+    prefix: Option<String>,
+    // Only hashes submitted to this namespace
+    namespace: Option<String>,
+}
 
-    println!("Server starting on http://127.0.0.1:3427");
-    println!("POST /add - Add multiple 512-bit hashes (raw bytes, multiple of 64 bytes)");
-    println!("POST /check - Check if hash exists and get merkle proof (raw bytes, 64 bytes)");
-    println!("POST /update-tree - Update the merkle tree");
-    println!("GET /stats - Get storage statistics");
-    println!("Using {} threads for hash distribution", NUM_THREADS);
+// Data of a `hash` event of /feed
+#[derive(Debug, Serialize, ToSchema)]
+struct FeedEvent {
+    salted_hash: Vec<u8>,
+    // Order in which the store accepted its hashes, as in the leaf logs
+    sequence: Option<u64>,
+    metadata: Option<HashMetadata>,
+}
 
-    let listener = tokio::net::TcpListener::bind("127.0.0.1:3427")
-        .await
-        .unwrap();
+impl From<AcceptedHash> for FeedEvent {
+    fn from(accepted: AcceptedHash) -> Self {
+        Self {
+            salted_hash: accepted.salted_hash.to_bytes(),
+            sequence: accepted.sequence,
+            metadata: accepted.metadata.map(|metadata| (*metadata).clone()),
+        }
+    }
+}
 
-    axum::serve(listener, app).await.unwrap();
+#[derive(Debug, Serialize, ToSchema)]
+struct GetHashesResponse {
+    total: usize,
+    offset: usize,
+    limit: usize,
+    since: Option<u64>,
+    hashes: Vec<StoredHashEntry>,
 }
 
-async fn add(
-    State(service): State<Arc<TimestampingService<INDEX_SIZE, PREFIX_SIZE>>>,
-    bytes: Bytes,
-) -> (StatusCode, Json<AddResponse>) {
-    // Check that the total size is a multiple of 64 bytes
-    if bytes.len() % 64 != 0 {
-        return (
-            StatusCode::BAD_REQUEST,
-            Json(AddResponse {
-                success: false,
-                message: MSG_INVALID_BATCH_SIZE.to_string(),
-                total_hashes: 0,
-                new_hashes: 0,
-                existing_hashes: 0,
-            }),
-        );
-    }
+#[derive(Debug, Serialize, ToSchema)]
+struct RootEntry {
+    version: u64,
+    root: Option<Vec<u8>>,
+    tree_size: usize,
+    leaf_count: usize,
+    timestamp: u64,
+    anchor_txid: Option<String>,
+    signature: Option<Vec<u8>>,
+    // Where the signed tree head was submitted to with $TIMESTAMPING_TRANSPARENCY_LOG
+    transparency_log: Option<TransparencyLogEntry>,
+    // How the timestamp was obtained, see $TIMESTAMPING_NTP_SERVER and $TIMESTAMPING_ROUGHTIME_SERVER
+    time_source: Option<TimeAttestation>,
+    // Cosignatures of the tree head by the servers in $TIMESTAMPING_PEERS
+    cosignatures: Vec<Cosignature>,
+}
 
-    let total_hashes = bytes.len() / 64;
-    let mut new_hashes = 0;
-    let mut existing_hashes = 0;
-
-    // Process hashes in chunks of 64 bytes
-    for i in 0..total_hashes {
-        let start = i * 64;
-        let end = start + 64;
-        let hash = Hash512::from_bytes(&bytes[start..end]).unwrap();
-        let is_new = service.hash_store.add_hash(hash);
-        if is_new {
-            new_hashes += 1;
-        } else {
-            existing_hashes += 1;
+impl From<&EpochSummary> for RootEntry {
+    fn from(summary: &EpochSummary) -> Self {
+        Self {
+            version: summary.epoch,
+            root: summary.root.map(|root| root.to_bytes()),
+            tree_size: summary.tree_size,
+            leaf_count: summary.leaf_count,
+            timestamp: summary.timestamp,
+            anchor_txid: summary.anchor_txid.clone(),
+            signature: summary.signature.clone(),
+            transparency_log: summary.transparency_log.clone(),
+            time_source: summary.time_source.clone(),
+            cosignatures: summary.cosignatures.clone(),
         }
     }
+}
 
-    let message = format!(
-        "Batch processed: {} total, {} new, {} existing",
-        total_hashes, new_hashes, existing_hashes
-    );
+#[derive(Debug, Serialize, ToSchema)]
+struct HealthResponse {
+    // Whether every worker answered within WORKER_HEALTH_TIMEOUT
+    healthy: bool,
+    responsive_workers: Vec<bool>,
+    worker_restarts: Vec<usize>,
+    // What an operator should look into, empty while nothing went wrong
+    alerts: Vec<String>,
+}
 
-    (
-        StatusCode::OK,
-        Json(AddResponse {
-            success: true,
-            message,
-            total_hashes,
-            new_hashes,
-            existing_hashes,
-        }),
-    )
+#[derive(Debug, Serialize, ToSchema)]
+struct GetRootsResponse {
+    total: usize,
+    offset: usize,
+    limit: usize,
+    roots: Vec<RootEntry>,
 }
 
-async fn check(
-    State(service): State<Arc<TimestampingService<INDEX_SIZE, PREFIX_SIZE>>>,
-    bytes: Bytes,
-) -> (StatusCode, Json<CheckHashResponse>) {
-    // Check the length of the raw bytes
-    if bytes.len() != 64 {
-        return (
-            StatusCode::BAD_REQUEST,
-            Json(CheckHashResponse {
-                success: false,
-                message: MSG_INVALID_LENGTH,
-                exists: false,
-                merkle_proof: None,
-            }),
-        );
-    }
+#[derive(Debug, Serialize, ToSchema)]
+struct GetAuditLogResponse {
+    total: usize,
+    offset: usize,
+    limit: usize,
+    entries: Vec<RootLogEntry>,
+}
 
-    let hash = Hash512::from_bytes(&bytes).unwrap();
-    let exists = service.hash_store.contains(&hash);
-    let merkle_proof = if exists {
-        service.get_merkle_proof(&hash)
-    } else {
-        None
-    };
+#[derive(Debug, Serialize, ToSchema)]
+struct GetRootResponse {
+    message: &'static str,
+    root: RootEntry,
+}
 
-    (
-        StatusCode::OK,
-        Json(CheckHashResponse {
-            success: true,
-            message: if exists { MSG_HASH_FOUND } else { MSG_HASH_NOT_FOUND },
-            exists,
-            merkle_proof,
-        }),
-    )
+#[derive(Debug, Serialize, ToSchema)]
+struct ReplicationHeadResponse {
+    version: u64,
+    root: Option<Vec<u8>>,
+    tree_size: usize,
+    leaf_count: usize,
+    timestamp: u64,
+    leaf_log_lengths: Vec<u64>,
 }
 
-async fn update_tree(
-    State(service): State<Arc<TimestampingService<INDEX_SIZE, PREFIX_SIZE>>>,
-) -> (StatusCode, Json<UpdateTreeResponse>) {
-    let hash_count = service.hash_store.len();
-    service.update_merkle_tree();
-    let tree_size = service.get_merkle_tree_size();
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct ReplicationLeavesQuery {
+    offset: Option<u64>,
+    limit: Option<u64>,
+}
 
-    (
-        StatusCode::OK,
-        Json(UpdateTreeResponse {
-            success: true,
-            message: format!("Merkle tree updated with {} hashes", hash_count),
-            tree_size,
-            hash_count,
-        }),
-    )
+#[derive(Debug, Serialize, ToSchema)]
+struct TreeUpdateEvent {
+    version: u64,
+    merkle_tree_root: Option<Vec<u8>>,
+    merkle_tree_size: usize,
+    hash_count: usize,
+    timestamp: u64,
+    // Signature of the tree head, as in /roots/{version}
+    signature: Option<Vec<u8>>,
+    // Ed25519 public key of the signature, as in /info, `None` for replicas
+    key_id: Option<Vec<u8>>,
 }
 
-async fn get_stats(
+impl TreeUpdateEvent {
+    fn new(head: TreeHead, service: &TimestampingService<INDEX_SIZE, PREFIX_SIZE>) -> Self {
+        // The epoch summary is written before the update is sent
+        let signature = service.epochs.read().unwrap().get(head.version).and_then(|summary| summary.signature.clone());
+        Self {
+            version: head.version,
+            merkle_tree_root: head.root.map(|root| root.to_bytes()),
+            merkle_tree_size: head.tree_size,
+            hash_count: head.leaf_count,
+            timestamp: head.timestamp,
+            signature,
+            key_id: service.verifying_key().map(|key| key.to_bytes().to_vec()),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+struct TenantEntry {
+    name: String,
+    // Version of the tenant's own tree that is part of the tenant tree, `None` while it's empty
+    tree_version: Option<u64>,
+    merkle_tree_root: Option<Vec<u8>>,
+    leaf_count: usize,
+}
+
+impl From<&(String, Option<TreeHead>)> for TenantEntry {
+    fn from((name, head): &(String, Option<TreeHead>)) -> Self {
+        Self {
+            name: name.clone(),
+            tree_version: head.map(|head| head.version),
+            merkle_tree_root: head.and_then(|head| head.root).map(|root| root.to_bytes()),
+            leaf_count: head.map_or(0, |head| head.leaf_count),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+struct GetTenantsResponse {
+    // `None` before the first /tenants/update-tree
+    version: Option<u64>,
+    merkle_tree_root: Option<Vec<u8>>,
+    tenants: Vec<TenantEntry>,
+}
+
+impl From<&TenantTree> for GetTenantsResponse {
+    fn from(tree: &TenantTree) -> Self {
+        Self {
+            version: Some(tree.version),
+            merkle_tree_root: tree.root().map(|root| root.to_bytes()),
+            tenants: tree.tenants.iter().map(TenantEntry::from).collect(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+struct TenantProofResponse {
+    version: u64,
+    merkle_tree_root: Option<Vec<u8>>,
+    tenant: TenantEntry,
+    // The (left, right) pairs from the tenant's root up to the root of the tenant tree, appended to
+    // a proof of the tenant's tree version they prove inclusion under that root
+    proof: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+const DEFAULT_BIND_ADDRESS: &str = "127.0.0.1:3427"; // Overridden by $TIMESTAMPING_BIND, a host:port or unix:<socket path>
+const INDEX_SIZE: usize = 28; // Index bits tables start with, unless $TIMESTAMPING_INDEX_SIZE says otherwise
+const PREFIX_SIZE: usize = 3; // Top bits of the salted hashes skipped by the bucket index, enough for prefix partitioning over NUM_THREADS
+const NUM_THREADS: usize = 8; // Number of threads for hash distribution
+const DEFAULT_MAX_CHAIN_LENGTH: usize = 16; // Average hashes per bucket at which a worker's table doubles
+const DEFAULT_MAX_INDEX_SIZE: usize = 32; // Index bits at which tables stop growing
+const DATA_DIR: &str = "data"; // Directory for persisted state
+const TENANTS_DIR: &str = "tenants"; // Stores of the tenants in the data directory, one directory each
+const RESALT_DIR: &str = "resalted"; // Store created by /admin/resalt in the data directory, until it replaces the current one
+const TENANT_THREADS: usize = 2; // Number of threads for hash distribution in the store of each tenant
+const SIGNING_KEY_FILE: &str = "signing.key"; // Tree head signing key in the data directory, created on first start
+const WEBHOOKS_FILE: &str = "webhooks.json"; // Webhooks registered with /admin/webhooks in the data directory
+const TREE_HASHER: &str = "sha512-rfc6962"; // Hash function for salting and tree nodes of new stores, see `storage::hasher_from_name"
+const DEFAULT_LEAF_ENCODING: LeafEncoding = LeafEncoding::V1; // Used when a request doesn't ask for a version
+const AUDIT_INTERVAL: Duration = Duration::from_secs(600); // How often the published tree is rebuilt from the leaf logs
+const WORKER_HEALTH_TIMEOUT: Duration = Duration::from_secs(1); // Workers answering /healthz later count as unresponsive
+const RETAINED_TREES: usize = 2; // Previous trees kept in memory for proofs against older versions
+const JOB_CHUNK_SIZE: usize = 10_000; // Hashes of a job added at once, its progress is updated after each chunk
+const JOB_QUEUED_CHUNKS: usize = 4; // Chunks of a job waiting to be added, the body of /add-batch is read no further meanwhile
+const MAX_JOBS: usize = 1000; // Jobs kept at once per store, running ones and those finished within JOB_RETENTION
+const JOB_RETENTION: Duration = Duration::from_secs(3600); // How long /jobs/{id} reports a job after it finished
+const MAX_JOB_STATUSES: usize = 100_000; // Statuses per /jobs/{id} response, bounds its size
+const DEFAULT_EPOCHS_LIMIT: usize = 100;
+const MAX_EPOCHS_LIMIT: usize = 1000;
+const DASHBOARD_ROOTS: usize = 10; // Recent roots shown on the dashboard, the ingestion rate is averaged over them
+const DASHBOARD_REFRESH: Duration = Duration::from_secs(10); // How often the dashboard reloads itself
+const DEFAULT_HASHES_LIMIT: usize = 100;
+const MAX_HASHES_LIMIT: usize = 1000;
+#[cfg(feature = "client")]
+const REPLICATION_INTERVAL: Duration = Duration::from_secs(5); // How often a replica polls its primary for new trees
+const MAX_REPLICATION_LEAVES: u64 = 1 << 16; // Leaf log records per /replication/leaves response
+const STREAM_BATCH_SIZE: usize = 4096; // Hashes collected from a stream before they are sent to the store
+const MAX_BASE64_LINE_LENGTH: usize = 1024; // Longer lines can't be a hash and are rejected without buffering them
+const MAX_METADATA_FIELD_LENGTH: usize = 256;
+const MAX_DATA_UPLOAD_SIZE: usize = 1 << 30; // Limit for multipart uploads to /add-data, raw bodies are unlimited
+const MAX_CHECK_BATCH_SIZE: usize = 10_000; // Hashes per /check-batch request, bounds the size of the response
+const PROOF_STREAM_BATCH_SIZE: usize = 256; // Hashes checked at once by /check-stream, bounds the proofs held per response chunk
+const EXPORT_PART_SIZE: usize = 1 << 20; // Hashes /admin/export sorts and sends at once, about as many are in memory
+const DEFAULT_IDEMPOTENCY_WINDOW: Duration = Duration::from_secs(3600); // Overridden by $TIMESTAMPING_IDEMPOTENCY_WINDOW (seconds)
+const SATURATED_RETRY_AFTER: Duration = Duration::from_secs(1); // Sent with adds refused because the worker queues are full
+const MAX_IDEMPOTENCY_KEYS: usize = 100_000; // Keys remembered at once, requests with further keys aren't cached
+const MAX_IDEMPOTENCY_KEY_LENGTH: usize = 255;
+const CHALLENGE_LIFETIME: Duration = Duration::from_secs(300); // How long a challenge from /challenge can be used, once
+const MAX_CHALLENGES: usize = 100_000; // Challenges pending at once, no new ones are issued while all of them are valid
+const MAX_POW_DIFFICULTY: u32 = 64;
+const MAX_RATE_LIMITED_CLIENTS: usize = 100_000; // Clients tracked at once, those with a full allowance are forgotten to make room
+const MAX_IDEMPOTENT_BODY_SIZE: usize = 2 << 20; // The default body limit of /add, bodies are buffered to compare retries
+const MAX_IDEMPOTENT_BATCH_SIZE: usize = 64 << 20; // Like MAX_IDEMPOTENT_BODY_SIZE for /add-batch, larger batches can be sent without a key
+const MAX_IDEMPOTENT_RESPONSE_SIZE: usize = 64 << 10; // Larger responses, or those of unknown size, aren't cached
+const MAX_CBOR_BODY_SIZE: usize = 64 << 20; // Larger JSON responses, or those of unknown size, aren't re-encoded as CBOR
+const MIN_COMPRESSED_SIZE: u16 = 1024; // Smaller responses aren't compressed
+const MAX_REQUEST_ID_LENGTH: usize = 64;
+const DEFAULT_WAIT_TIMEOUT: Duration = Duration::from_secs(30); // How long /wait blocks without a timeout parameter
+const MAX_WAIT_TIMEOUT: Duration = Duration::from_secs(300);
+const DEFAULT_BLOOM_FILTER_FP_RATE: f64 = 0.01; // Overridden by $TIMESTAMPING_BLOOM_FILTER_FP_RATE
+const DEFAULT_PROOF_CACHE_SIZE: usize = 4096; // Proofs cached for hot hashes, overridden by $TIMESTAMPING_PROOF_CACHE_SIZE
+const COSIGN_MAX_SKEW: Duration = Duration::from_secs(300); // Tree heads of peers further from this server's clock aren't cosigned
+#[cfg(feature = "client")]
+const COSIGN_INTERVAL: Duration = Duration::from_secs(60); // How often the latest tree head is sent again to peers that didn't cosign it
+const DEFAULT_BACKUP_INTERVAL: Duration = Duration::from_secs(300); // Overridden by $TIMESTAMPING_BACKUP_INTERVAL (seconds)
+const NTP_MAX_OFFSET: Duration = Duration::from_secs(1); // Larger offsets of the system clock from the NTP server are attested as unverified
+
+const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+const API_KEY_HEADER: HeaderName = HeaderName::from_static("x-api-key");
+const FORWARDED_FOR_HEADER: HeaderName = HeaderName::from_static("x-forwarded-for");
+const CBOR_CONTENT_TYPE: &str = "application/cbor";
+const CHALLENGE_HEADER: HeaderName = HeaderName::from_static("x-challenge");
+const CHALLENGE_NONCE_HEADER: HeaderName = HeaderName::from_static("x-challenge-nonce");
+const IDEMPOTENCY_KEY_HEADER: HeaderName = HeaderName::from_static("idempotency-key");
+
+// Random per process, so validators change with a restart and the configuration it may bring, and the time it started
+static SERVER_START: LazyLock<(u32, u64)> = LazyLock::new(|| (rand::random(), SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |duration| duration.as_secs())));
+const IDEMPOTENT_REPLAYED_HEADER: HeaderName = HeaderName::from_static("idempotent-replayed");
+
+const POW_FORMAT: &str = "sha512(challenge || nonce as 8 bytes big-endian || every hash as its 64 byte digest) has to start with difficulty + ceil(log2(number of hashes)) zero bits, send the challenge as X-Challenge and the nonce in decimal as X-Challenge-Nonce";
+const PROOF_FORMAT: &str = "[(hash, salt), (left, right)...]: the submitted hash and the salt, then the children of every node on the path from the salted leaf to the root, combined as in node_encoding";
+
+// Pre-allocated response messages
+const MSG_HASH_FOUND: &str = "Hash found in store";
+const MSG_HASH_NOT_FOUND: &str = "Hash not found in store";
+const MSG_BATCH_CHECKED: &str = "Batch checked";
+const MSG_CHECK_BATCH_TOO_LARGE: &str = "Too many hashes - at most 10000 per request";
+const MSG_INVALID_LENGTH: &str = "Invalid hash length - must be exactly one digest (64 bytes, 32 for sha256 and blake3)";
+const MSG_INVALID_PRIVATE_CHECK: &str = "Invalid length - must be one digest followed by its 64 byte nonce";
+const MSG_INVALID_BATCH_SIZE: &str = "Invalid batch size - must be a multiple of the digest length (64 bytes, 32 for sha256 and blake3)";
+const MSG_UNSUPPORTED_LEAF_VERSION: &str = "Unsupported leaf encoding version - see /version";
+const MSG_LEAF_VERSION_MISMATCH: &str = "Hash not stored with this leaf version - check it with one of stored_leaf_versions";
+const MSG_ROOT_FOUND: &str = "Tree version found";
+const MSG_ROOT_NOT_FOUND: &str = "Tree version not found";
+const MSG_WAIT_TIMEOUT: &str = "Hash was not included in a merkle tree within the timeout - retry to keep waiting";
+const MSG_TREE_VERSION_UNAVAILABLE: &str = "Tree version is not available for proofs - only the most recent trees are kept";
+const MSG_INVALID_METADATA: &str = "Invalid metadata - submitter, label, content_type and namespace are limited to 256 bytes";
+const MSG_INVALID_HASH_ENCODING: &str = "Invalid hash - must be a url-safe base64 encoded digest";
+const MSG_INVALID_TEXT_HASH: &str = "Invalid hash - must be a hex or base64 encoded digest";
+const MSG_INVALID_BASE64_LINE: &str = "Invalid line - must be a base64 encoded digest";
+const MSG_INVALID_JSON_ENTRY: &str = "Invalid JSON - must be an array of hex or base64 encoded digests";
+const MSG_STREAM_READ_FAILED: &str = "Failed to read request body";
+const MSG_STORE_FULL: &str = "Store is full - no new hashes are accepted";
+const MSG_BUCKET_FULL: &str = "The bucket of a hash is full - it can't be stored";
+const MSG_SATURATED: &str = "Too many hashes are waiting to be stored - retry later";
+const MSG_RATE_LIMITED: &str = "Too many requests - retry later";
+const MSG_TIMEOUT: &str = "The response took too long - retry later";
+const MSG_WORKER_UNAVAILABLE: &str = "A storage worker failed - some hashes couldn't be stored";
+const MSG_STORAGE_BACKEND_FAILED: &str = "The storage backend failed";
+const MSG_UNAUTHORIZED: &str = "Missing or invalid key - send the admin token or an API key as 'Authorization: Bearer <key>'";
+const MSG_FORBIDDEN: &str = "The role of this API key doesn't allow this route";
+const MSG_UNKNOWN_PEER: &str = "The tree head isn't signed with the key of a peer of this server";
+const MSG_INVALID_TREE_HEAD: &str = "Invalid tree head - must be JSON with the fields of a signed tree head and the peer's public key";
+const MSG_INVALID_API_KEY: &str = "Invalid API key - send the key of a tenant as 'X-Api-Key', or no key for the default store";
+const MSG_TENANT_NOT_FOUND: &str = "Tenant not found in the tenant tree";
+const MSG_INVALID_REMOVE: &str = "Invalid length - must be one digest, optionally followed by its 64 byte nonce for hashes added with /add-private";
+const MSG_INVALID_REASON: &str = "Invalid reason - limited to 256 bytes";
+const MSG_HASH_REMOVED: &str = "Hash removed - it stays in the merkle trees, but is no longer reported as stored";
+const MSG_STORE_CLEARED: &str = "All hashes removed - they stay in the merkle trees, but are no longer reported as stored";
+const MSG_ADMIN_LOG_FAILED: &str = "Failed to record the action in the admin log";
+const MSG_RESALT_UNKNOWN_HASHES: &str = "Some hashes are not stored - only stored hashes can be moved to the new salt";
+const MSG_RESALT_FAILED: &str = "Failed to create the re-salted store";
+const MSG_RESALTED: &str = "Hashes moved to a store with a new salt - restart the server on it to switch, see salt-migration.json in it";
+const MSG_PROOF_OF_WORK_REQUIRED: &str = "Proof of work required - send a challenge from /challenge as 'X-Challenge' and a nonce solving it for these hashes as 'X-Challenge-Nonce'";
+const MSG_INVALID_PROOF_OF_WORK: &str = "Invalid proof of work - the nonce doesn't solve the challenge for these hashes, see /challenge";
+const MSG_CHALLENGE_EXPIRED: &str = "Unknown or expired challenge - each challenge can be used once, within 300 seconds";
+const MSG_STREAM_WITHOUT_WORK: &str = "Streamed adds and uploads aren't accepted while adds need a proof of work - use /add or /add-private";
+const MSG_TOO_MANY_CHALLENGES: &str = "Too many challenges are pending - retry later";
+const MSG_INVALID_WEBHOOK_URL: &str = "Invalid webhook url - must start with http:// or https://";
+const MSG_CONFIGURED_WEBHOOK: &str = "The webhook is configured in TIMESTAMPING_WEBHOOKS - remove it there";
+const MSG_WEBHOOKS_FAILED: &str = "Failed to write the registered webhooks";
+const MSG_WEBHOOKS: &str = "Webhooks notified of every published tree";
+const MSG_WEBHOOK_ADDED: &str = "Webhook registered - it is notified of every published tree from now on";
+const MSG_WEBHOOK_EXISTS: &str = "Webhook was registered already";
+const MSG_WEBHOOK_REMOVED: &str = "Webhook removed";
+const MSG_WEBHOOK_NOT_FOUND: &str = "Webhook is not registered";
+const MSG_TOO_MANY_JOBS: &str = "Too many batches are being added - retry later";
+const MSG_JOB_BODY_INCOMPLETE: &str = "The request body wasn't read to the end";
+const MSG_JOB_NOT_FOUND: &str = "Job not found - finished jobs are kept for an hour";
+const MSG_READ_ONLY: &str = "This server is a read-only replica - submit hashes to its primary";
+const MSG_INVALID_QUERY: &str = "Invalid query parameters";
+const MSG_INVALID_PATH: &str = "Invalid path parameter";
+const MSG_INVALID_PREFIX: &str = "Invalid prefix - must be at most 64 hex encoded bytes";
+const MSG_NO_TREE: &str = "No merkle tree was published yet";
+const MSG_WORKER_NOT_FOUND: &str = "Worker not found";
+const MSG_REPLICATION_UNSUPPORTED: &str = "Trees published from a mountain range can't be replicated - use TIMESTAMPING_ACCUMULATOR=tree on the primary";
+const MSG_READ_LEAVES_FAILED: &str = "Failed to read the leaf log";
+const MSG_INVALID_IDEMPOTENCY_KEY: &str = "Invalid Idempotency-Key - must be 1 to 255 visible ascii characters";
+const MSG_IDEMPOTENT_BODY_TOO_LARGE: &str = "Body too large for a request with an Idempotency-Key - at most 2 MiB, 64 MiB for /add-batch";
+const MSG_IDEMPOTENCY_KEY_IN_USE: &str = "A request with this Idempotency-Key is still being processed - retry later";
+const MSG_IDEMPOTENCY_KEY_REUSED: &str = "This Idempotency-Key was already used for a different request";
+
+#[tokio::main]
+async fn main() {
+    LazyLock::force(&SERVER_START);
+    check_client_settings();
+    // A coordinator only routes requests to the shards at $TIMESTAMPING_SHARDS and has no store of its own
+    if let Ok(shards) = std::env::var("TIMESTAMPING_SHARDS") {
+        let shards: Vec<String> = shards.split(',').map(str::trim).filter(|url| !url.is_empty()).map(str::to_string).collect();
+        run_coordinator(shards).await;
+        return;
+    }
+    // $TIMESTAMPING_STORAGE picks the store of the hashes: `memory` (the default) or `sqlite`
+    match std::env::var("TIMESTAMPING_STORAGE").as_deref() {
+        Err(_) | Ok("memory") => {}
+        Ok("sqlite") => {
+            run_sqlite_store().await;
+            return;
+        }
+        Ok(other) => panic!("Unknown TIMESTAMPING_STORAGE {:?}, must be memory or sqlite", other),
+    }
+
+    std::fs::create_dir_all(DATA_DIR).unwrap();
+    // A replica follows the primary at $TIMESTAMPING_PRIMARY and refuses writes
+    let primary = std::env::var("TIMESTAMPING_PRIMARY").ok();
+    let epochs_path = Path::new(DATA_DIR).join("epochs.jsonl");
+    let epochs = match primary {
+        Some(_) => EpochLog::open_replica(&epochs_path),
+        None => EpochLog::open(&epochs_path),
+    };
+    let epochs = epochs.unwrap_or_else(|e| panic!("Failed to load the epoch log: {}", e));
+    let service = match &primary {
+        Some(primary) => open_replica(primary).await,
+        None => TimestampingService::<INDEX_SIZE, PREFIX_SIZE>::open_partitioned(NUM_THREADS, Path::new(DATA_DIR), store_hasher(Path::new(DATA_DIR), TREE_HASHER), store_partitioning(Path::new(DATA_DIR)), initial_index_size())
+            .and_then(|service| Ok(service.with_signing_key(load_or_create_signing_key(&Path::new(DATA_DIR).join(SIGNING_KEY_FILE))?))),
+    };
+    let service = service.unwrap();
+    let time_source = time_source();
+    let service = match time_source.clone() {
+        Some(_) if primary.is_some() => panic!("Replicas take the timestamps of their primary, TIMESTAMPING_NTP_SERVER and TIMESTAMPING_ROUGHTIME_SERVER are only supported on primaries"),
+        Some(time_source) => service.with_time_source(time_source),
+        None => service,
+    };
+    // $TIMESTAMPING_ACCUMULATOR=mmr appends new hashes to a merkle mountain range instead of rebuilding the tree
+    let service = match std::env::var("TIMESTAMPING_ACCUMULATOR").as_deref() {
+        Ok("mmr") if primary.is_some() => panic!("Replicas can't rebuild the mountain range of their primary, TIMESTAMPING_ACCUMULATOR=mmr is only supported on primaries"),
+        Ok("mmr") => service.with_mountain_range(),
+        Ok("tree") | Err(_) => service,
+        Ok(other) => panic!("TIMESTAMPING_ACCUMULATOR has to be tree or mmr, got {}", other),
+    };
+    let service = match bloom_filter_config() {
+        Some((capacity, false_positive_rate)) => service.with_bloom_filter(capacity, false_positive_rate),
+        None => service,
+    };
+    let service = match cold_after_epochs() {
+        Some(epochs) => service.with_cold_storage(epochs),
+        None => service,
+    };
+    // With a transparency log, trees are pruned once their head was recorded there
+    let service = match leaf_pruning() {
+        Some(_) if service.uses_mountain_range() => panic!("Trees published from a mountain range can't be pruned, TIMESTAMPING_PRUNE_LEAVES is only supported with TIMESTAMPING_ACCUMULATOR=tree"),
+        Some(pruning) => service.with_leaf_pruning(pruning, std::env::var("TIMESTAMPING_TRANSPARENCY_LOG").is_ok()),
+        None => service,
+    };
+    let service = if rotate_bucket_salts() { service.with_bucket_salt_rotation() } else { service };
+    let service = service.with_retention(retention());
+    let service = match chain_limit() {
+        Some(limit) => service.with_chain_limit(limit),
+        None => service,
+    };
+    let service = match proof_cache_size() {
+        0 => service,
+        size => service.with_proof_cache(size),
+    };
+    let timestamping_service = Arc::new(
+        service
+            .with_epoch_log(epochs)
+            .with_retained_trees(RETAINED_TREES)
+            .with_store_limits(store_limits())
+            .with_queue_capacity(queue_capacity())
+            .with_resize_policy(resize_policy())
+            .with_admin_log(AdminLog::open(&Path::new(DATA_DIR).join("admin.jsonl")).unwrap())
+            .with_root_log(RootLog::open(&Path::new(DATA_DIR).join("roots.jsonl")).unwrap())
+    );
+    // `--self-check` checks the invariants of the store, e.g. after restoring a backup, and exits instead of serving
+    if std::env::args().skip(1).any(|arg| arg == "--self-check") {
+        std::process::exit(self_check(&timestamping_service));
+    }
+    // `--import <snapshot>` adds the hashes of a snapshot from /admin/export of a store with the same salt and exits
+    let mut args = std::env::args().skip(1);
+    if args.any(|arg| arg == "--import") {
+        let path = args.next().unwrap_or_else(|| panic!("--import needs the path of a snapshot"));
+        std::process::exit(import_snapshot(&timestamping_service, Path::new(&path)));
+    }
+    // Trees published from a mountain range can't be rebuilt from the leaf logs
+    if timestamping_service.uses_mountain_range() {
+        println!("Publishing from a merkle mountain range: published trees aren't audited and /replication is unavailable");
+    } else {
+        timestamping_service.spawn_audit_job(AUDIT_INTERVAL);
+    }
+    if let Some(primary) = &primary {
+        spawn_replication(primary, Arc::clone(&timestamping_service));
+    }
+    // $TIMESTAMPING_TRANSPARENCY_LOG is the base url of a Rekor-compatible log that signed tree heads are submitted to
+    let transparency_log = std::env::var("TIMESTAMPING_TRANSPARENCY_LOG").ok();
+    if let Some(url) = &transparency_log {
+        if primary.is_some() {
+            panic!("Replicas don't sign tree heads, TIMESTAMPING_TRANSPARENCY_LOG is only supported on primaries");
+        }
+        spawn_transparency_log(url, Arc::clone(&timestamping_service));
+    }
+    // $TIMESTAMPING_BACKUP_URL is an S3-compatible bucket, optionally with a prefix, the data directory is uploaded to
+    let backup_url = std::env::var("TIMESTAMPING_BACKUP_URL").ok();
+    if let Some(url) = &backup_url {
+        spawn_backup(url, Arc::clone(&timestamping_service));
+    }
+    // $TIMESTAMPING_PEERS are other servers that cosign the tree heads of this one and have theirs cosigned by it
+    let peers = peers();
+    let witness = if peers.is_empty() {
+        None
+    } else {
+        if primary.is_some() {
+            panic!("Replicas don't sign tree heads, TIMESTAMPING_PEERS is only supported on primaries");
+        }
+        spawn_cosigning(peers.clone(), Arc::clone(&timestamping_service));
+        let key = load_or_create_signing_key(&Path::new(DATA_DIR).join(SIGNING_KEY_FILE)).unwrap();
+        Some(Arc::new(Witness::new(key, peers, COSIGN_MAX_SKEW)))
+    };
+    // $TIMESTAMPING_WEBHOOKS are urls that a signed tree head is POSTed to after every publication, admins can register more
+    let webhooks = Arc::new(Webhooks::open(&Path::new(DATA_DIR).join(WEBHOOKS_FILE), webhook_urls()).unwrap());
+    if !webhooks.configured().is_empty() && primary.is_some() {
+        panic!("Replicas don't sign tree heads, TIMESTAMPING_WEBHOOKS is only supported on primaries");
+    }
+    let notifies_webhooks = cfg!(feature = "client") && primary.is_none();
+    if notifies_webhooks || !webhooks.configured().is_empty() {
+        spawn_webhooks(Arc::clone(&webhooks), Arc::clone(&timestamping_service));
+    }
+    let peering_routes = match &witness {
+        Some(witness) => Router::new().route("/cosign", post(cosign)).with_state(Arc::clone(witness)),
+        None => Router::new(),
+    };
+
+    // $TIMESTAMPING_POW_DIFFICULTY makes adds prove work over their hashes and a challenge from /challenge
+    let challenges = pow_difficulty().map(|difficulty| Arc::new(Challenges::new(difficulty)));
+    if challenges.is_some() && primary.is_some() {
+        panic!("Replicas refuse adds, TIMESTAMPING_POW_DIFFICULTY is only supported on primaries");
+    }
+
+    // $TIMESTAMPING_TENANTS gives every tenant its own store and trees, requests select one with their X-Api-Key header
+    let tenant_keys = tenant_keys();
+    if !tenant_keys.is_empty() && primary.is_some() {
+        panic!("Replicas only copy the default store of their primary, TIMESTAMPING_TENANTS is only supported on primaries");
+    }
+    // New tenants use the hasher of the default store, the tenant tree needs the same one for all of them
+    let hasher = Arc::clone(timestamping_service.hash_store.hasher());
+    let tenants = Arc::new(Tenants::new(
+        tenant_keys.iter().map(|(name, _)| (name.clone(), Arc::new(open_tenant(name, time_source.clone(), hasher.name())))).collect(),
+        hasher,
+    ));
+    let tenant_routers: Vec<(String, Router)> = tenant_keys.iter()
+        .map(|(name, key)| {
+            let tenant = Arc::clone(tenants.get(name).unwrap());
+            tenant.spawn_audit_job(AUDIT_INTERVAL);
+            (key.clone(), service_routes(&tenant, false, challenges.as_ref()).with_state(tenant))
+        })
+        .collect();
+    let tenant_routes = if tenant_keys.is_empty() {
+        Router::new()
+    } else {
+        Router::new()
+            .route("/tenants", get(get_tenants))
+            .route("/tenants/{name}/proof", get(get_tenant_proof))
+            .route("/tenants/update-tree", post(update_tenant_trees))
+            .with_state(Arc::clone(&tenants))
+    };
+
+    // Admin routes only exist if $TIMESTAMPING_ADMIN_TOKEN is set or $TIMESTAMPING_API_KEYS has an admin key
+    let access = Arc::new(access());
+    let admin = if access.has_admin() {
+        Router::new()
+            .route("/admin/remove", post(admin_remove))
+            .route("/admin/clear", post(admin_clear))
+            .route("/admin/resalt", if primary.is_some() { post(read_only) } else { post(admin_resalt) })
+            .route("/admin/log", get(admin_log))
+            .route("/admin/export", get(admin_export))
+            .route("/admin/verify-store", post(admin_verify_store))
+    } else {
+        Router::new()
+    };
+    let webhook_routes = if access.has_admin() && notifies_webhooks {
+        Router::new()
+            .route("/admin/webhooks", get(admin_webhooks).post(admin_add_webhook).delete(admin_remove_webhook))
+            .with_state(Arc::clone(&webhooks))
+    } else {
+        Router::new()
+    };
+    let app = service_routes(&timestamping_service, primary.is_some(), challenges.as_ref())
+        .merge(admin)
+        .route("/replication/head", get(get_replication_head))
+        .route("/replication/leaves/{worker}", get(get_replication_leaves))
+        .with_state(timestamping_service.clone())
+        .merge(tenant_routes)
+        .merge(peering_routes)
+        .merge(webhook_routes)
+        .layer(middleware::from_fn_with_state(Arc::new(tenant_routers), route_to_tenant))
+        .layer(middleware::from_fn_with_state(Arc::clone(&access), authorize));
+    let app = with_common_layers(app);
+
+    let (bind_address, tls_files) = listen_config();
+    println!("Server starting on {}://{}", if tls_files.is_some() { "https" } else { "http" }, bind_address);
+    if let Some(primary) = &primary {
+        println!("Running as read-only replica of {}, add and update requests are refused", primary);
+    }
+    println!("POST /add?leaf_version=&hash_algorithm=&encoding=raw|hex|base64&submitter=&label=&content_type=&namespace= - Add multiple hashes (raw bytes, multiple of 64 bytes, 32 for sha256 and blake3, or whitespace separated hex or base64)");
+    println!("POST /add-batch?leaf_version=&hash_algorithm=&format=raw|base64|json&submitter=&label=&content_type=&namespace= - Add a batch of any size in the background, returns a job id once the body is read");
+    println!("GET /jobs/{{id}}?offset=&limit= - Get the progress of a batch added with /add-batch and the statuses of its processed hashes");
+    println!("POST /add-stream?leaf_version=&hash_algorithm=&format=raw|base64&submitter=&label=&content_type=&namespace= - Add a stream of hashes (raw bytes or base64 lines) without buffering the whole body");
+    println!("POST /add-data?leaf_version=&submitter=&label=&content_type=&namespace= - Hash raw data or multipart file uploads with SHA-512 on the server and add the digests");
+    println!("POST /add-private?leaf_version=&hash_algorithm= - Add hashes salted with a random nonce each, returned only to the submitter (raw bytes, multiple digests)");
+    println!("POST /check?leaf_version=&hash_algorithm=&tree_version=&encoding=raw|hex|base64 - Check if hash exists and get merkle proof, against the latest or a recent tree (one digest, raw bytes, hex or base64)");
+    println!("POST /check-private?leaf_version=&hash_algorithm=&tree_version= - Check a hash added with /add-private and get its merkle proof (raw bytes, digest and nonce)");
+    println!("POST /validate-batch?leaf_version=&hash_algorithm=&encoding=raw|hex|base64&submitter=&label=&content_type=&namespace= - Check what /add would do with a batch without storing it: every entry's status or why it is invalid");
+    println!("POST /check-batch?leaf_version=&hash_algorithm=&tree_version= - Check many hashes and get their merkle proofs from one tree (raw bytes, multiple digests)");
+    println!("POST /check-stream?leaf_version=&hash_algorithm=&tree_version=&format=raw|base64 - Check a stream of any number of hashes and stream their merkle proofs from one tree back as NDJSON");
+    println!("POST /wait?leaf_version=&hash_algorithm=&encoding=raw|hex|base64&timeout= - Wait until a hash is included in a published tree and get its receipt (one digest, raw bytes, hex or base64)");
+    println!("GET /hash/{{base64}}?leaf_version=&hash_algorithm=&tree_version= - Get existence, metadata and merkle proof of a hash (url-safe base64)");
+    println!("POST /update-tree - Update the merkle tree");
+    println!("GET /stats - Get storage statistics and p50/p95/p99 latencies of add, check and update-tree");
+    println!("GET /stats/epochs?offset=&limit= - Get per-epoch tree build summaries");
+    println!("GET /hashes?offset=&limit=&since= - Page through the stored (salted) hashes, optionally only those first stored since a unix timestamp");
+    println!("GET /feed?prefix=&namespace= - Follow the (salted) hashes stored from now on as server-sent events, optionally only those with a hex prefix or namespace");
+    println!("GET /roots?offset=&limit= - Get the history of published merkle roots");
+    println!("GET /roots/{{version}} - Get the merkle root published as the given tree version and its signed tree head");
+    println!("GET /audit-log?offset=&limit= - Get the hash-chained log of every published tree head");
+    println!("GET /ws - Subscribe to merkle tree updates (WebSocket, JSON messages)");
+    println!("GET /version - Get software version, tree hasher, supported leaf encodings and hash algorithms");
+    println!("GET /replication/head - Get the current tree head and the leaf log lengths it was built from, for replicas");
+    println!("GET /replication/leaves/{{worker}}?offset=&limit= - Get salted hashes from the leaf log of a worker included in the current tree (raw bytes)");
+    println!("GET /info - Get everything needed to recompute leaves and verify proofs: version info, salt, index parameters, proof format and the public key tree heads are signed with");
+    println!("GET /test-vectors - Get leaves, nodes, roots and proofs over fixed hashes for every tree hasher and leaf encoding, to test other verifiers against");
+    println!("GET /spec - Get a machine-readable description of how digests, leaves, nodes and proofs are computed, with worked examples");
+    println!("GET / - Status page with the current root, tree size, hash count, bucket occupancy, ingestion rate and recent roots (HTML)");
+    println!("GET /openapi.json - Get the OpenAPI description of these routes, browsable at GET /docs");
+    check_swagger_ui_dir();
+    if access.has_admin() {
+        println!("POST /admin/remove?leaf_version=&hash_algorithm=&reason= - Remove a hash, keeping it in the merkle trees (raw bytes, digest and the nonce for private hashes, admin token)");
+        println!("POST /admin/clear?reason= - Remove all hashes, keeping them in the merkle trees (admin token)");
+        println!("POST /admin/resalt?leaf_version=&hash_algorithm=&format=raw|base64&reason= - Move the given stored hashes to a new store with a new salt in data/resalted and sign the link between the roots (admin token)");
+        println!("GET /admin/log - Get the log of administrative actions (admin token)");
+        println!("GET /admin/export - Export every stored salted hash as a checksummed snapshot (admin token)");
+        println!("POST /admin/verify-store - Check that the buckets are sorted, free of duplicates and counted right, and rebuild the latest tree to compare its root (admin token)");
+        if notifies_webhooks {
+            println!("GET /admin/webhooks - List the webhooks notified of published trees (admin token)");
+            println!("POST /admin/webhooks?url= - Register a webhook that a signed tree head is POSTed to after every publication (admin token)");
+            println!("DELETE /admin/webhooks?url= - Remove a registered webhook (admin token)");
+        }
+    }
+    if notifies_webhooks && !webhooks.urls().is_empty() {
+        println!("Notifying {} webhooks of every published tree", webhooks.urls().len());
+    }
+    if access.enforced {
+        println!("Requiring an API key for every route but /healthz, /version and the docs, {} keys configured", access.keys.len());
+    }
+    if !tenant_keys.is_empty() {
+        println!("GET /tenants - Get the roots of all tenants and the root of the tree over them");
+        println!("GET /tenants/{{name}}/proof - Get the proof from a tenant's root up to the root of the tenant tree");
+        println!("POST /tenants/update-tree - Update the merkle trees of all tenants and the tree over their roots");
+        println!("Serving {} tenants with their own stores in {}, selected by the X-Api-Key header", tenant_keys.len(), Path::new(DATA_DIR).join(TENANTS_DIR).display());
+    }
+    if let Some(witness) = &witness {
+        println!("POST /cosign - Cosign a signed tree head of a peer, if its timestamp is within {} seconds of this server's clock (JSON)", COSIGN_MAX_SKEW.as_secs());
+        println!("Exchanging cosignatures of tree heads with {} peers, see cosignatures in /roots and receipts", witness.peers().len());
+    }
+    if let Some(challenges) = &challenges {
+        println!("GET /challenge - Get a challenge for the proof of work that /add and /add-private need, /add-stream and /add-data are refused");
+        println!("Requiring proofs of work of {} bits plus one per doubling of the hashes for adds", challenges.difficulty);
+    }
+    println!("Replaying /add and /add-batch responses for retried Idempotency-Key headers within {} seconds", idempotency_window().as_secs());
+    if timestamping_service.uses_mountain_range() {
+        println!("Appending hashes to a merkle mountain range, proofs are available for every tree version since the start");
+    }
+    if let Some(time_source) = &time_source {
+        println!("Timestamping tree heads with {:?}, see time_source in /roots", time_source);
+    }
+    if let Some(url) = &transparency_log {
+        println!("Submitting signed tree heads to the transparency log at {}, see transparency_log in /roots", url);
+    }
+    if let Some(url) = &backup_url {
+        println!("Backing up the data directory to {} every {} seconds, restore it with `timestamping-cli restore`", url, backup_interval().as_secs());
+    }
+    println!("Using {} threads for hash distribution", NUM_THREADS);
+    let limits = timestamping_service.hash_store.limits();
+    if let Some(max_hashes) = limits.max_hashes {
+        println!("Accepting at most {} hashes", max_hashes);
+    }
+    if let Some(max_memory) = limits.max_memory {
+        println!("Accepting new hashes while buckets and metadata use less than {} bytes", max_memory);
+    }
+    println!("Refusing adds while a worker has {} commands queued", timestamping_service.hash_store.queue_capacity());
+    let policy = resize_policy();
+    println!("Doubling a worker's bucket table in the background once its chains average {} hashes, up to {} index bits", policy.max_chain_length, policy.max_index_size);
+    println!("Restored {} hashes from {}", timestamping_service.hash_store.len(), DATA_DIR);
+
+    serve(app, &bind_address, tls_files).await;
+
+    if primary.is_some() {
+        // Replicas only publish trees of their primary
+        println!("Shutting down...");
+        timestamping_service.close();
+    } else {
+        println!("Shutting down, publishing final merkle tree...");
+        publish(&timestamping_service, |service| service.shutdown()).await;
+        publish(&tenants, |tenants| tenants.services().for_each(|(_, tenant)| tenant.shutdown())).await;
+    }
+    println!("Shutdown complete");
+}
+
+// Routes of the store of `service`, a replica refuses its writes. The replication and admin routes only exist for the default store.
+// With `challenges`, adds need a proof of work over a challenge from them.
+fn service_routes(
+    service: &Arc<TimestampingService<INDEX_SIZE, PREFIX_SIZE>>,
+    replica: bool,
+    challenges: Option<&Arc<Challenges>>,
+) -> Router<Arc<TimestampingService<INDEX_SIZE, PREFIX_SIZE>>> {
+    let jobs = Arc::new(Jobs::new());
+    let writes = if replica {
+        Router::new()
+            .route("/add", post(read_only))
+            .route("/add-batch", post(read_only))
+            .route("/add-stream", post(read_only))
+            .route("/add-data", post(read_only))
+            .route("/add-private", post(read_only))
+            .route("/update-tree", post(read_only))
+    } else {
+        Router::new()
+            .route("/add", post(add)
+                .layer(middleware::from_fn_with_state(Arc::new(IdempotencyCache::new(idempotency_window())), idempotent))
+                .layer(middleware::from_fn_with_state(Arc::clone(&service.latencies.add), record_latency)))
+            .route("/add-batch", post(add_batch).layer(middleware::from_fn_with_state(
+                Arc::new(IdempotencyCache::new(idempotency_window()).with_max_body_size(MAX_IDEMPOTENT_BATCH_SIZE)),
+                idempotent,
+            )))
+            .route("/add-stream", post(add_stream))
+            .route("/add-data", post(add_data).layer(DefaultBodyLimit::max(MAX_DATA_UPLOAD_SIZE)))
+            .route("/add-private", post(add_private))
+            .route("/update-tree", post(update_tree).layer(middleware::from_fn_with_state(Arc::clone(&service.latencies.update_tree), record_latency)))
+    };
+    let writes = match challenges {
+        Some(challenges) => writes.route("/challenge", get(get_challenge)).layer(Extension(Arc::clone(challenges))),
+        None => writes,
+    };
+    writes
+        .route("/check", post(check).layer(middleware::from_fn_with_state(Arc::clone(&service.latencies.check), record_latency)))
+        .route("/check-private", post(check_private))
+        .route("/check-batch", post(check_batch))
+        .route("/check-stream", post(check_stream))
+        .route("/validate-batch", post(validate_batch))
+        .route("/jobs/{id}", get(get_job))
+        .route("/wait", post(wait))
+        .route("/hash/{hash}", get(get_hash))
+        .route("/stats", get(get_stats))
+        .route("/healthz", get(get_healthz))
+        .route("/stats/epochs", get(get_epochs))
+        .route("/hashes", get(get_hashes))
+        .route("/feed", get(get_feed))
+        .route("/roots", get(get_roots))
+        .route("/roots/{version}", get(get_root))
+        .route("/audit-log", get(get_audit_log))
+        .route("/ws", get(ws))
+        .route("/version", get(get_version))
+        .route("/info", get(get_info))
+        .route("/test-vectors", get(get_test_vectors))
+        .route("/spec", get(get_spec))
+        .route("/openapi.json", get(get_openapi))
+        .route("/docs", get(get_docs))
+        .route("/docs/{asset}", get(get_docs_asset))
+        .route("/", get(get_dashboard))
+        .layer(Extension(jobs))
+}
+
+// Layers around the routes of every server, from the innermost: rate limits, request timeouts, the response format,
+// CORS and request ids
+fn with_common_layers(app: Router) -> Router {
+    let app = match rate_limiter() {
+        Some(limiter) => {
+            println!("Limiting each client to {} requests per second, trusting X-Forwarded-For from {:?}", limiter.rate, limiter.trusted_proxies);
+            app.layer(middleware::from_fn_with_state(Arc::new(limiter), limit_rate))
+        }
+        None => app,
+    };
+    let app = match request_timeout() {
+        Some(timeout) => {
+            println!("Answering requests whose response takes longer than {} seconds with a timeout", timeout.as_secs_f64());
+            app.layer(middleware::from_fn_with_state(timeout, limit_duration))
+        }
+        None => app,
+    };
+    app.layer(middleware::from_fn(negotiate_format))
+        .layer(compression())
+        .layer(cors())
+        .layer(middleware::from_fn(assign_request_id))
+}
+
+// Compress responses with gzip or zstd for clients that accept either in their Accept-Encoding header, except
+// small ones, server-sent events and images
+fn compression() -> CompressionLayer<impl Predicate> {
+    CompressionLayer::new().compress_when(DefaultPredicate::new().and(SizeAbove::new(MIN_COMPRESSED_SIZE)))
+}
+
+// Browser origins allowed to call the server, from $TIMESTAMPING_CORS_ORIGINS as comma separated origins
+// like https://example.com. Any origin if unset or *, none if empty.
+fn cors() -> CorsLayer {
+    let origins = match std::env::var("TIMESTAMPING_CORS_ORIGINS") {
+        Ok(origins) if origins.trim() != "*" => AllowOrigin::list(origins.split(',').map(str::trim).filter(|origin| !origin.is_empty()).map(|origin| {
+            HeaderValue::from_str(origin).unwrap_or_else(|_| panic!("TIMESTAMPING_CORS_ORIGINS has to be comma separated origins, got {}", origin))
+        })),
+        _ => AllowOrigin::from(Any),
+    };
+    CorsLayer::new()
+        .allow_methods([Method::GET, Method::POST, Method::DELETE, Method::OPTIONS])
+        .allow_headers([header::CONTENT_TYPE, IDEMPOTENCY_KEY_HEADER, REQUEST_ID_HEADER, API_KEY_HEADER, CHALLENGE_HEADER, CHALLENGE_NONCE_HEADER, header::IF_NONE_MATCH, header::IF_MODIFIED_SINCE])
+        .expose_headers([REQUEST_ID_HEADER, IDEMPOTENT_REPLAYED_HEADER, header::RETRY_AFTER, header::ETAG])
+        .allow_origin(origins)
+}
+
+// Requests per second each client may send, in bursts of as many, from $TIMESTAMPING_RATE_LIMIT. Unlimited if unset.
+// Clients are told apart by their address, or the one in X-Forwarded-For for proxies listed in $TIMESTAMPING_TRUSTED_PROXIES.
+fn rate_limiter() -> Option<RateLimiter> {
+    let rate = std::env::var("TIMESTAMPING_RATE_LIMIT").ok()?;
+    let rate = rate.parse().ok().filter(|rate: &f64| *rate > 0.0)
+        .unwrap_or_else(|| panic!("TIMESTAMPING_RATE_LIMIT has to be a positive number of requests per second, got {}", rate));
+    let trusted_proxies = match std::env::var("TIMESTAMPING_TRUSTED_PROXIES") {
+        Ok(proxies) => proxies.split(',').map(str::trim).filter(|proxy| !proxy.is_empty()).map(|proxy| {
+            proxy.parse().unwrap_or_else(|_| panic!("TIMESTAMPING_TRUSTED_PROXIES has to be comma separated IP addresses, got {}", proxy))
+        }).collect(),
+        Err(_) => Vec::new(),
+    };
+    Some(RateLimiter::new(rate, trusted_proxies))
+}
+
+// Leading zero bits the proof of work of an add over a single hash needs, from $TIMESTAMPING_POW_DIFFICULTY.
+// Adds need no work if unset.
+fn pow_difficulty() -> Option<u32> {
+    let difficulty = std::env::var("TIMESTAMPING_POW_DIFFICULTY").ok()?;
+    Some(difficulty.parse().ok().filter(|difficulty| *difficulty <= MAX_POW_DIFFICULTY)
+        .unwrap_or_else(|| panic!("TIMESTAMPING_POW_DIFFICULTY has to be a number of bits up to {}, got {}", MAX_POW_DIFFICULTY, difficulty)))
+}
+
+// Time until a response has to be ready, from $TIMESTAMPING_REQUEST_TIMEOUT (seconds). Unlimited if unset.
+fn request_timeout() -> Option<Duration> {
+    let timeout = std::env::var("TIMESTAMPING_REQUEST_TIMEOUT").ok()?;
+    Some(timeout.parse().ok().filter(|timeout: &f64| *timeout > 0.0).map(Duration::from_secs_f64)
+        .unwrap_or_else(|| panic!("TIMESTAMPING_REQUEST_TIMEOUT has to be a positive number of seconds, got {}", timeout)))
+}
+
+// Limits for new hashes from $TIMESTAMPING_MAX_HASHES and $TIMESTAMPING_MAX_MEMORY (bytes), unlimited if unset
+fn store_limits() -> StoreLimits {
+    let limit = |name: &str| std::env::var(name).ok().map(|value| {
+        value.parse().unwrap_or_else(|_| panic!("{} has to be a number, got {}", name, value))
+    });
+    StoreLimits {
+        max_hashes: limit("TIMESTAMPING_MAX_HASHES"),
+        max_memory: limit("TIMESTAMPING_MAX_MEMORY"),
+    }
+}
+
+// Commands each worker queues before adds are refused, from $TIMESTAMPING_QUEUE_CAPACITY
+fn queue_capacity() -> usize {
+    match std::env::var("TIMESTAMPING_QUEUE_CAPACITY") {
+        Ok(value) => value.parse().unwrap_or_else(|_| panic!("TIMESTAMPING_QUEUE_CAPACITY has to be a number, got {}", value)),
+        Err(_) => DEFAULT_QUEUE_CAPACITY,
+    }
+}
+
+// Expected hashes and false-positive rate of the Bloom filter checked before lookups, from $TIMESTAMPING_BLOOM_FILTER_CAPACITY
+// and $TIMESTAMPING_BLOOM_FILTER_FP_RATE. No filter is used without a capacity.
+fn bloom_filter_config() -> Option<(usize, f64)> {
+    let capacity = std::env::var("TIMESTAMPING_BLOOM_FILTER_CAPACITY").ok()?;
+    let capacity = capacity.parse().unwrap_or_else(|_| panic!("TIMESTAMPING_BLOOM_FILTER_CAPACITY has to be a number, got {}", capacity));
+    let false_positive_rate = match std::env::var("TIMESTAMPING_BLOOM_FILTER_FP_RATE") {
+        Ok(value) => value.parse().ok().filter(|rate| *rate > 0.0 && *rate < 1.0)
+            .unwrap_or_else(|| panic!("TIMESTAMPING_BLOOM_FILTER_FP_RATE has to be a number between 0 and 1, got {}", value)),
+        Err(_) => DEFAULT_BLOOM_FILTER_FP_RATE,
+    };
+    Some((capacity, false_positive_rate))
+}
+
+// Tree updates after which hashes are moved from memory to disk, from $TIMESTAMPING_COLD_AFTER_EPOCHS. All stay in memory if unset.
+fn cold_after_epochs() -> Option<u64> {
+    let epochs = std::env::var("TIMESTAMPING_COLD_AFTER_EPOCHS").ok()?;
+    Some(epochs.parse().unwrap_or_else(|_| panic!("TIMESTAMPING_COLD_AFTER_EPOCHS has to be a number, got {}", epochs)))
+}
+
+// $TIMESTAMPING_PRUNE_LEAVES=disk keeps the leaves of published trees on disk instead of in memory,
+// =discard drops them and only keeps the roots, so no proofs can be made anymore
+fn leaf_pruning() -> Option<LeafPruning> {
+    match std::env::var("TIMESTAMPING_PRUNE_LEAVES").ok()?.as_str() {
+        "disk" => Some(LeafPruning::Disk),
+        "discard" => Some(LeafPruning::Discard),
+        other => panic!("TIMESTAMPING_PRUNE_LEAVES has to be disk or discard, got {}", other),
+    }
+}
+
+// $TIMESTAMPING_BUCKET_SALT=rotate indexes the buckets by a new random salt after every tree update,
+// so hashes crafted with a leaked store salt can't pile up in one bucket
+fn rotate_bucket_salts() -> bool {
+    match std::env::var("TIMESTAMPING_BUCKET_SALT").as_deref() {
+        Ok("rotate") => true,
+        Ok("none") | Err(_) => false,
+        Ok(other) => panic!("TIMESTAMPING_BUCKET_SALT has to be none or rotate, got {}", other),
+    }
+}
+
+// How long hashes of each namespace are kept, from $TIMESTAMPING_RETENTION as comma separated namespace=seconds pairs.
+// Hashes without a namespace or of other namespaces are kept forever.
+fn retention() -> HashMap<String, Duration> {
+    let Ok(value) = std::env::var("TIMESTAMPING_RETENTION") else {
+        return HashMap::new();
+    };
+    value.split(',').filter(|pair| !pair.trim().is_empty()).map(|pair| {
+        let (namespace, seconds) = pair.trim().split_once('=')
+            .unwrap_or_else(|| panic!("TIMESTAMPING_RETENTION has to be comma separated namespace=seconds pairs, got {}", pair));
+        let seconds = seconds.parse().unwrap_or_else(|_| panic!("TIMESTAMPING_RETENTION has to give seconds for namespace {}, got {}", namespace, seconds));
+        (namespace.to_string(), Duration::from_secs(seconds))
+    }).collect()
+}
+
+// Keys that can call the routes of `required_role`, sent as `Authorization: Bearer <key>`
+struct Access {
+    keys: Vec<(String, Role)>,
+    // Set by $TIMESTAMPING_API_KEYS: every route needs a key, otherwise only the admin routes do
+    enforced: bool,
+}
+
+// Each role can do what the ones before it can
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Role {
+    Reader,
+    Submitter,
+    Admin,
+}
+
+impl Role {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "reader" => Some(Role::Reader),
+            "submitter" => Some(Role::Submitter),
+            "admin" => Some(Role::Admin),
+            _ => None,
+        }
+    }
+}
+
+impl Access {
+    // Every key is compared, so the timing doesn't reveal which one matched
+    fn role(&self, given: &str) -> Option<Role> {
+        self.keys.iter().fold(None, |found, (key, role)| if secrets_equal(given.as_bytes(), key.as_bytes()) { Some(*role) } else { found })
+    }
+
+    fn has_admin(&self) -> bool {
+        self.keys.iter().any(|&(_, role)| role == Role::Admin)
+    }
+}
+
+// API keys from $TIMESTAMPING_API_KEYS as comma separated role=key pairs with the roles reader, submitter and admin,
+// plus $TIMESTAMPING_ADMIN_TOKEN as an admin key
+fn access() -> Access {
+    let value = std::env::var("TIMESTAMPING_API_KEYS").unwrap_or_default();
+    let mut keys: Vec<(String, Role)> = value.split(',').filter(|pair| !pair.trim().is_empty()).map(|pair| {
+        let (role, key) = pair.trim().split_once('=')
+            .and_then(|(role, key)| Some((Role::from_name(role)?, key)))
+            .filter(|(_, key)| !key.is_empty())
+            .unwrap_or_else(|| panic!("TIMESTAMPING_API_KEYS has to be comma separated role=key pairs with the roles reader, submitter and admin, got {}", pair));
+        (key.to_string(), role)
+    }).collect();
+    let enforced = !keys.is_empty();
+    if let Some(token) = std::env::var("TIMESTAMPING_ADMIN_TOKEN").ok().filter(|token| !token.is_empty()) {
+        keys.push((token, Role::Admin));
+    }
+    for (index, (key, _)) in keys.iter().enumerate() {
+        if keys[..index].iter().any(|(other, _)| other == key) {
+            panic!("TIMESTAMPING_API_KEYS and TIMESTAMPING_ADMIN_TOKEN have to give every key one role, a key is repeated");
+        }
+    }
+    Access { keys, enforced }
+}
+
+// Peers from $TIMESTAMPING_PEERS as comma separated key@url pairs, with the hex public key from the peer's /info
+fn peers() -> Vec<Peer> {
+    let Ok(value) = std::env::var("TIMESTAMPING_PEERS") else {
+        return Vec::new();
+    };
+    value.split(',').filter(|pair| !pair.trim().is_empty()).map(|pair| {
+        let (key, url) = pair.trim().split_once('@')
+            .and_then(|(key, url)| Some((decode_hex(key.as_bytes())?, url)))
+            .and_then(|(key, url)| Some((VerifyingKey::try_from(key.as_slice()).ok()?, url)))
+            .filter(|(_, url)| !url.is_empty())
+            .unwrap_or_else(|| panic!("TIMESTAMPING_PEERS has to be comma separated key@url pairs with hex Ed25519 public keys, got {}", pair));
+        Peer { url: url.to_string(), public_key: key }
+    }).collect()
+}
+
+// Urls from $TIMESTAMPING_WEBHOOKS, comma separated
+fn webhook_urls() -> Vec<String> {
+    let Ok(value) = std::env::var("TIMESTAMPING_WEBHOOKS") else {
+        return Vec::new();
+    };
+    value.split(',').map(str::trim).filter(|url| !url.is_empty()).map(|url| {
+        if !timestamping::webhooks::is_valid_url(url) {
+            panic!("TIMESTAMPING_WEBHOOKS has to be comma separated http:// or https:// urls, got {}", url);
+        }
+        url.to_string()
+    }).collect()
+}
+
+// Tenants and their API keys from $TIMESTAMPING_TENANTS as comma separated name=key pairs. Names are used as directory names.
+fn tenant_keys() -> Vec<(String, String)> {
+    let Ok(value) = std::env::var("TIMESTAMPING_TENANTS") else {
+        return Vec::new();
+    };
+    let tenants: Vec<(String, String)> = value.split(',').filter(|pair| !pair.trim().is_empty()).map(|pair| {
+        let (name, key) = pair.trim().split_once('=').filter(|(name, key)| {
+            !name.is_empty() && name.bytes().all(|byte| byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_') && !key.is_empty()
+        }).unwrap_or_else(|| panic!("TIMESTAMPING_TENANTS has to be comma separated name=key pairs with names of letters, digits, - and _, got {}", pair));
+        (name.to_string(), key.to_string())
+    }).collect();
+    for (index, (name, key)) in tenants.iter().enumerate() {
+        if tenants[..index].iter().any(|(other_name, other_key)| other_name == name || other_key == key) {
+            panic!("TIMESTAMPING_TENANTS has to give every tenant its own name and key, {} is repeated", name);
+        }
+    }
+    tenants
+}
+
+// Hasher of the store in `dir`, which is fixed when the store is created, or the one named `default` for a new store.
+// Stores created before nodes were domain separated keep their plain hasher.
+fn store_hasher(dir: &Path, default: &str) -> Arc<dyn Hasher> {
+    let path = StoreConfig::path(dir);
+    let name = match std::fs::read_to_string(&path) {
+        Ok(json) => serde_json::from_str::<StoreConfig>(&json).unwrap_or_else(|e| panic!("Invalid {}: {}", path.display(), e)).hasher,
+        Err(_) => default.to_string(),
+    };
+    hasher_from_name(&name).unwrap_or_else(|| panic!("Unknown tree hasher {} in {}", name, path.display()))
+}
+
+// Partitioning of the store in `dir`, which is fixed when the store is created, or the one from
+// $TIMESTAMPING_PARTITIONING (salted-prefix) for a new store
+fn store_partitioning(dir: &Path) -> Partitioning {
+    let path = StoreConfig::path(dir);
+    if let Ok(json) = std::fs::read_to_string(&path) {
+        return serde_json::from_str::<StoreConfig>(&json).unwrap_or_else(|e| panic!("Invalid {}: {}", path.display(), e)).partitioning;
+    }
+    match std::env::var("TIMESTAMPING_PARTITIONING") {
+        Ok(name) => Partitioning::from_name(&name).unwrap_or_else(|| panic!(
+            "TIMESTAMPING_PARTITIONING has to be one of {}, got {}",
+            Partitioning::ALL.map(|partitioning| partitioning.name()).join(", "), name
+        )),
+        Err(_) => Partitioning::SaltedPrefix,
+    }
+}
+
+// Store of a tenant in its own directory, with its own epoch log, configured like the default store.
+// Tree heads are signed with the key of the server.
+fn open_tenant(name: &str, time_source: Option<Arc<dyn TimeSource>>, tree_hasher: &str) -> TimestampingService<INDEX_SIZE, PREFIX_SIZE> {
+    let dir = Path::new(DATA_DIR).join(TENANTS_DIR).join(name);
+    std::fs::create_dir_all(&dir).unwrap();
+    let hasher = store_hasher(&dir, tree_hasher);
+    if hasher.name() != tree_hasher {
+        panic!("The store of tenant {} uses the tree hasher {}, but the default store {} - all stores need the same one", name, hasher.name(), tree_hasher);
+    }
+    let service = TimestampingService::<INDEX_SIZE, PREFIX_SIZE>::open_partitioned(TENANT_THREADS, &dir, hasher, store_partitioning(&dir), initial_index_size())
+        .and_then(|service| Ok(service
+            .with_signing_key(load_or_create_signing_key(&Path::new(DATA_DIR).join(SIGNING_KEY_FILE))?)
+            .with_epoch_log(EpochLog::open(&dir.join("epochs.jsonl"))?)
+            .with_root_log(RootLog::open(&dir.join("roots.jsonl"))?)))
+        .unwrap();
+    let service = match time_source {
+        Some(time_source) => service.with_time_source(time_source),
+        None => service,
+    };
+    let service = if rotate_bucket_salts() { service.with_bucket_salt_rotation() } else { service };
+    let service = match proof_cache_size() {
+        0 => service,
+        size => service.with_proof_cache(size),
+    };
+    let service = match leaf_pruning() {
+        Some(pruning) => service.with_leaf_pruning(pruning, false),
+        None => service,
+    };
+    let service = match chain_limit() {
+        Some(limit) => service.with_chain_limit(limit),
+        None => service,
+    };
+    service
+        .with_retention(retention())
+        .with_retained_trees(RETAINED_TREES)
+        .with_store_limits(store_limits())
+        .with_queue_capacity(queue_capacity())
+        .with_resize_policy(resize_policy())
+}
+
+// Proofs kept for recently checked hashes, from $TIMESTAMPING_PROOF_CACHE_SIZE. 0 disables the cache.
+fn proof_cache_size() -> usize {
+    match std::env::var("TIMESTAMPING_PROOF_CACHE_SIZE") {
+        Ok(value) => value.parse().unwrap_or_else(|_| panic!("TIMESTAMPING_PROOF_CACHE_SIZE has to be a number, got {}", value)),
+        Err(_) => DEFAULT_PROOF_CACHE_SIZE,
+    }
+}
+
+// Clock for tree heads, checked against the NTP server at $TIMESTAMPING_NTP_SERVER (host:port) or taken from the
+// Roughtime server at $TIMESTAMPING_ROUGHTIME_SERVER (host:port) with the base64 Ed25519 key $TIMESTAMPING_ROUGHTIME_KEY.
+// The unverified system time if neither is set.
+fn time_source() -> Option<Arc<dyn TimeSource>> {
+    match (std::env::var("TIMESTAMPING_NTP_SERVER"), std::env::var("TIMESTAMPING_ROUGHTIME_SERVER")) {
+        (Ok(_), Ok(_)) => panic!("Set only one of TIMESTAMPING_NTP_SERVER and TIMESTAMPING_ROUGHTIME_SERVER"),
+        (Ok(server), Err(_)) => Some(Arc::new(NtpTimeSource::new(&server, NTP_MAX_OFFSET))),
+        (Err(_), Ok(server)) => {
+            let key = std::env::var("TIMESTAMPING_ROUGHTIME_KEY")
+                .expect("TIMESTAMPING_ROUGHTIME_KEY has to be set to the public key of the Roughtime server");
+            let key = base64::engine::general_purpose::STANDARD.decode(key.trim()).ok()
+                .and_then(|key| timestamping::receipt::VerifyingKey::from_bytes(&key.try_into().ok()?).ok())
+                .expect("TIMESTAMPING_ROUGHTIME_KEY has to be a base64 encoded Ed25519 public key");
+            Some(Arc::new(RoughtimeTimeSource::new(&server, key)))
+        }
+        (Err(_), Err(_)) => None,
+    }
+}
+
+// Index bits the bucket tables of the workers start with, from $TIMESTAMPING_INDEX_SIZE. Small tables start
+// faster and with less memory, and grow like any other under the resize policy.
+fn initial_index_size() -> usize {
+    let Ok(value) = std::env::var("TIMESTAMPING_INDEX_SIZE") else {
+        return INDEX_SIZE;
+    };
+    match value.parse() {
+        Ok(index_size) if (6..=64 - PREFIX_SIZE).contains(&index_size) => index_size,
+        _ => panic!("TIMESTAMPING_INDEX_SIZE has to be a number from 6 to {}, got {}", 64 - PREFIX_SIZE, value),
+    }
+}
+
+// When bucket tables grow, from $TIMESTAMPING_MAX_CHAIN_LENGTH and $TIMESTAMPING_MAX_INDEX_SIZE
+fn resize_policy() -> ResizePolicy {
+    let setting = |name: &str, default: usize| match std::env::var(name) {
+        Ok(value) => value.parse().unwrap_or_else(|_| panic!("{} has to be a number, got {}", name, value)),
+        Err(_) => default,
+    };
+    ResizePolicy {
+        max_chain_length: setting("TIMESTAMPING_MAX_CHAIN_LENGTH", DEFAULT_MAX_CHAIN_LENGTH),
+        max_index_size: setting("TIMESTAMPING_MAX_INDEX_SIZE", DEFAULT_MAX_INDEX_SIZE),
+    }
+}
+
+// Most hashes per bucket from $TIMESTAMPING_MAX_BUCKET_LENGTH and what happens to new ones beyond it from
+// $TIMESTAMPING_BUCKET_OVERFLOW (resize), unlimited if the length isn't set
+fn chain_limit() -> Option<ChainLimit> {
+    let max_length = std::env::var("TIMESTAMPING_MAX_BUCKET_LENGTH").ok()?;
+    let max_length = match max_length.parse() {
+        Ok(0) | Err(_) => panic!("TIMESTAMPING_MAX_BUCKET_LENGTH has to be a positive number, got {}", max_length),
+        Ok(max_length) => max_length,
+    };
+    let overflow = match std::env::var("TIMESTAMPING_BUCKET_OVERFLOW") {
+        Ok(name) => ChainOverflow::from_name(&name).unwrap_or_else(|| panic!("TIMESTAMPING_BUCKET_OVERFLOW has to be resize, reject or spill, got {}", name)),
+        Err(_) => ChainOverflow::Resize,
+    };
+    Some(ChainLimit { max_length, overflow })
+}
+
+// Address to listen on and the TLS certificate and key files, if TLS is enabled.
+// TLS is enabled by giving both a PEM certificate chain and its private key.
+fn listen_config() -> (String, Option<(String, String)>) {
+    let bind_address = std::env::var("TIMESTAMPING_BIND").unwrap_or_else(|_| DEFAULT_BIND_ADDRESS.to_string());
+    let tls_files = match (std::env::var("TIMESTAMPING_TLS_CERT"), std::env::var("TIMESTAMPING_TLS_KEY")) {
+        (Ok(cert), Ok(key)) => Some((cert, key)),
+        (Err(_), Err(_)) => None,
+        _ => panic!("TIMESTAMPING_TLS_CERT and TIMESTAMPING_TLS_KEY have to be set together"),
+    };
+    if tls_files.is_some() && bind_address.starts_with("unix:") {
+        panic!("TLS is terminated by the proxy in front of a unix socket, TIMESTAMPING_TLS_CERT and TIMESTAMPING_TLS_KEY need a TCP address");
+    }
+    (bind_address, tls_files)
+}
+
+// Serve the app until the process is asked to shut down
+async fn serve(app: Router, bind_address: &str, tls_files: Option<(String, String)>) {
+    match (tls_files, bind_address.strip_prefix("unix:")) {
+        (Some((cert, key)), _) => serve_tls(app, bind_address, &cert, &key).await,
+        (None, Some(path)) => serve_unix(app, Path::new(path)).await,
+        (None, None) => {
+            let listener = tokio::net::TcpListener::bind(bind_address)
+                .await
+                .unwrap();
+
+            // The peer address tells clients apart for rate limits
+            axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
+                .with_graceful_shutdown(shutdown_signal())
+                .await
+                .unwrap();
+        }
+    }
+}
+
+// Serve on a unix socket, for a proxy on the same host. A socket left by an earlier run is replaced.
+#[cfg(unix)]
+async fn serve_unix(app: Router, path: &Path) {
+    use std::os::unix::fs::FileTypeExt;
+    if std::fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+        std::fs::remove_file(path).unwrap();
+    }
+    let listener = tokio::net::UnixListener::bind(path).unwrap();
+    axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown_signal())
+        .await
+        .unwrap();
+    let _ = std::fs::remove_file(path);
+}
+
+#[cfg(not(unix))]
+async fn serve_unix(_app: Router, _path: &Path) {
+    panic!("Unix sockets are only supported on unix");
+}
+
+// Client for the primary, with $TIMESTAMPING_PRIMARY_API_KEY if it requires API keys
+#[cfg(feature = "client")]
+fn primary_client(primary: &str) -> TimestampingClient {
+    match std::env::var("TIMESTAMPING_PRIMARY_API_KEY") {
+        Ok(key) if !key.is_empty() => TimestampingClient::new(primary).with_api_key(&key),
+        _ => TimestampingClient::new(primary),
+    }
+}
+
+// Open the local store with the salt, hasher and partitioning of the primary, which have to match for the trees to be equal
+#[cfg(feature = "client")]
+async fn open_replica(primary: &str) -> std::io::Result<TimestampingService<INDEX_SIZE, PREFIX_SIZE>> {
+    let info = primary_client(primary).info().await.map_err(std::io::Error::other)?;
+    // The index size only sizes the bucket tables, trees don't depend on it
+    if (info.threads, info.prefix_size) != (NUM_THREADS, PREFIX_SIZE) {
+        return Err(std::io::Error::other(format!(
+            "Primary uses {} threads with prefix size {}, this build {} threads with {}",
+            info.threads, info.prefix_size, NUM_THREADS, PREFIX_SIZE
+        )));
+    }
+    let hasher = hasher_from_name(&info.tree_hasher)
+        .ok_or_else(|| std::io::Error::other(format!("Unknown tree hasher {}", info.tree_hasher)))?;
+    TimestampingService::open_replica(NUM_THREADS, Path::new(DATA_DIR), hasher, info.partitioning, info.salt, initial_index_size())
+}
+
+#[cfg(feature = "client")]
+fn check_client_settings() {}
+
+// Refuse to start with settings that talk to other servers over the HTTP client of the `client` feature when this
+// build doesn't have it, before anything is opened
+#[cfg(not(feature = "client"))]
+fn check_client_settings() {
+    const CLIENT_SETTINGS: [&str; 6] = [
+        "TIMESTAMPING_PRIMARY", "TIMESTAMPING_SHARDS", "TIMESTAMPING_TRANSPARENCY_LOG", "TIMESTAMPING_BACKUP_URL", "TIMESTAMPING_PEERS", "TIMESTAMPING_WEBHOOKS",
+    ];
+    let set: Vec<&str> = CLIENT_SETTINGS.into_iter().filter(|name| std::env::var_os(name).is_some()).collect();
+    if !set.is_empty() {
+        eprintln!("{} need the `client` feature, which this build doesn't have. Build with `--features client` or unset them.", set.join(", "));
+        std::process::exit(2);
+    }
+}
+
+#[cfg(not(feature = "client"))]
+async fn open_replica(_primary: &str) -> std::io::Result<TimestampingService<INDEX_SIZE, PREFIX_SIZE>> {
+    unreachable!("TIMESTAMPING_PRIMARY is refused by check_client_settings");
+}
+
+// Poll the primary and publish its trees once all their leaves were copied
+#[cfg(feature = "client")]
+fn spawn_replication(primary: &str, service: Arc<TimestampingService<INDEX_SIZE, PREFIX_SIZE>>) {
+    let client = primary_client(primary);
+    tokio::spawn(async move {
+        loop {
+            if let Err(e) = replicate(&client, &service).await {
+                eprintln!("Replication failed: {}", e);
+            }
+            tokio::time::sleep(REPLICATION_INTERVAL).await;
+        }
+    });
+}
+
+#[cfg(not(feature = "client"))]
+fn spawn_replication(_primary: &str, _service: Arc<TimestampingService<INDEX_SIZE, PREFIX_SIZE>>) {}
+
+#[cfg(feature = "client")]
+async fn replicate(client: &TimestampingClient, service: &Arc<TimestampingService<INDEX_SIZE, PREFIX_SIZE>>) -> Result<(), String> {
+    let Some(primary) = client.replication_head().await.map_err(|e| e.to_string())? else {
+        return Ok(());
+    };
+    if service.get_merkle_tree_version() == Some(primary.head.version) && service.get_merkle_tree_root() == primary.head.root {
+        return Ok(());
+    }
+
+    // Replicas don't accept writes, so every local hash was copied from the primary's leaf logs
+    let worker_hashes = service.hash_store.stats().worker_hashes;
+    for (worker, &len) in primary.leaf_log_lengths.iter().enumerate() {
+        let mut offset = worker_hashes[worker] as u64;
+        if offset > len {
+            return Err(format!("Worker {} has more hashes than the primary's leaf log, was the salt rotated?", worker));
+        }
+        while offset < len {
+            let leaves = client.replication_leaves(worker, offset, MAX_REPLICATION_LEAVES).await.map_err(|e| e.to_string())?;
+            if leaves.is_empty() {
+                return Err(format!("Primary returned no leaves for worker {} at {}", worker, offset));
+            }
+            offset += leaves.len() as u64;
+            service.hash_store.add_replicated(worker, leaves).map_err(|e| e.to_string())?;
+        }
+    }
+
+    let published = tokio::task::spawn_blocking({
+        let service = Arc::clone(service);
+        move || service.publish_replicated_tree(&primary.head)
+    }).await.unwrap();
+    if !published.map_err(|e| e.to_string())? {
+        eprintln!("Replicated tree {} does not match the root published by the primary", primary.head.version);
+    }
+    Ok(())
+}
+
+// Submit the tree heads signed after the start to the transparency log at `url` and record where they
+// were included. Heads that couldn't be submitted are retried after the next tree update.
+#[cfg(feature = "client")]
+fn spawn_transparency_log(url: &str, service: Arc<TimestampingService<INDEX_SIZE, PREFIX_SIZE>>) {
+    let log = TransparencyLog::new(url);
+    let key = service.verifying_key().unwrap();
+    let mut updates = service.subscribe_tree_updates();
+    let first_version = service.epochs.read().unwrap().len() as u64;
+    tokio::spawn(async move {
+        // Lagging behind is fine, the epoch log has every head that wasn't submitted yet
+        while !matches!(updates.recv().await, Err(RecvError::Closed)) {
+            let pending: Vec<EpochSummary> = service.epochs.read().unwrap()
+                .page(first_version as usize, usize::MAX)
+                .iter()
+                .filter(|summary| summary.transparency_log.is_none())
+                .cloned()
+                .collect();
+            for summary in pending {
+                let (Some(root), Some(signature)) = (summary.root, &summary.signature) else {
+                    continue;
+                };
+                let time_source = summary.time_source.as_ref().map(TimeAttestation::summary);
+                let message = tree_head_message(service.hash_store.hasher().name(), summary.epoch, summary.leaf_count as u64, summary.timestamp, &root, time_source.as_deref());
+                match log.submit(&message, signature, &key).await {
+                    Ok(entry) => {
+                        if let Err(e) = service.epochs.write().unwrap().set_transparency_log(summary.epoch, entry) {
+                            eprintln!("Failed to record transparency log entry of tree {}: {}", summary.epoch, e);
+                        }
+                        service.prune_tree(summary.epoch);
+                    }
+                    Err(e) => {
+                        eprintln!("Submitting tree {} to {} failed: {}", summary.epoch, log.url(), e);
+                        break;
+                    }
+                }
+            }
+        }
+    });
+}
+
+#[cfg(not(feature = "client"))]
+fn spawn_transparency_log(_url: &str, _service: Arc<TimestampingService<INDEX_SIZE, PREFIX_SIZE>>) {
+    unreachable!("TIMESTAMPING_TRANSPARENCY_LOG is refused by check_client_settings");
+}
+
+// Upload the data directory and the published roots to the object store at `url` every backup interval.
+// Failed uploads are retried in the next run, which continues where the last one stopped.
+#[cfg(feature = "client")]
+fn spawn_backup(url: &str, service: Arc<TimestampingService<INDEX_SIZE, PREFIX_SIZE>>) {
+    let credentials = Credentials::from_env().expect("TIMESTAMPING_BACKUP_URL needs AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY");
+    let region = std::env::var("TIMESTAMPING_BACKUP_REGION").unwrap_or_else(|_| "us-east-1".to_string());
+    let store = ObjectStore::new(url, &region, credentials).unwrap_or_else(|e| panic!("TIMESTAMPING_BACKUP_URL: {}", e));
+    let interval = backup_interval();
+    tokio::spawn(async move {
+        let mut backup = None;
+        loop {
+            if backup.is_none() {
+                match Backup::open(store.clone(), Path::new(DATA_DIR)).await {
+                    Ok(opened) => backup = Some(opened),
+                    Err(e) => eprintln!("Opening the backup at {} failed: {}", store.url(), e),
+                }
+            }
+            if let Some(backup) = &mut backup {
+                let roots = service.epochs.read().unwrap().page(backup.next_root() as usize, usize::MAX).to_vec();
+                if let Err(e) = backup.run(&roots).await {
+                    eprintln!("Backup to {} failed: {}", store.url(), e);
+                }
+            }
+            tokio::time::sleep(interval).await;
+        }
+    });
+}
+
+#[cfg(not(feature = "client"))]
+fn spawn_backup(_url: &str, _service: Arc<TimestampingService<INDEX_SIZE, PREFIX_SIZE>>) {
+    unreachable!("TIMESTAMPING_BACKUP_URL is refused by check_client_settings");
+}
+
+// Send the latest signed tree head to the peers that didn't cosign it yet, after every tree update and every
+// COSIGN_INTERVAL, and record their cosignatures. Peers only cosign recent heads, so older ones aren't retried.
+#[cfg(feature = "client")]
+fn spawn_cosigning(peers: Vec<Peer>, service: Arc<TimestampingService<INDEX_SIZE, PREFIX_SIZE>>) {
+    let public_key = service.verifying_key().unwrap().to_bytes().to_vec();
+    let peers: Vec<(Peer, TimestampingClient)> = peers.into_iter().map(|peer| {
+        let client = TimestampingClient::new(&peer.url);
+        (peer, client)
+    }).collect();
+    let mut updates = service.subscribe_tree_updates();
+    tokio::spawn(async move {
+        loop {
+            let latest = service.epochs.read().unwrap().latest().cloned();
+            if let Some(summary) = latest
+                && let Some(head) = signed_tree_head(&service, &summary, &public_key)
+            {
+                for (peer, client) in &peers {
+                    if summary.cosignatures.iter().any(|cosignature| cosignature.public_key == peer.public_key.as_bytes()) {
+                        continue;
+                    }
+                    match client.cosign(&head).await {
+                        Ok(cosignature) if cosignature.verify(&peer.public_key, &head.message()).is_ok() => {
+                            if let Err(e) = service.epochs.write().unwrap().add_cosignature(summary.epoch, cosignature) {
+                                eprintln!("Failed to record the cosignature of tree {} by {}: {}", summary.epoch, peer.url, e);
+                            }
+                        }
+                        Ok(_) => eprintln!("Peer {} returned an invalid cosignature for tree {}", peer.url, summary.epoch),
+                        Err(e) => eprintln!("Cosigning tree {} by {} failed: {}", summary.epoch, peer.url, e),
+                    }
+                }
+            }
+            // Lagging behind is fine, only the latest head is sent
+            if let Ok(Err(RecvError::Closed)) = tokio::time::timeout(COSIGN_INTERVAL, updates.recv()).await {
+                break;
+            }
+        }
+    });
+}
+
+// The tree head of `summary` as it is sent to peers and webhooks, if it was signed
+#[cfg(feature = "client")]
+fn signed_tree_head(service: &TimestampingService<INDEX_SIZE, PREFIX_SIZE>, summary: &EpochSummary, public_key: &[u8]) -> Option<SignedTreeHead> {
+    Some(SignedTreeHead {
+        tree_hasher: service.hash_store.hasher().name().to_string(),
+        version: summary.epoch,
+        leaf_count: summary.leaf_count as u64,
+        timestamp: summary.timestamp,
+        root: summary.root?,
+        time_source: summary.time_source.as_ref().map(TimeAttestation::summary),
+        public_key: public_key.to_vec(),
+        signature: summary.signature.clone()?,
+    })
+}
+
+// POST the signed tree head of every published tree to the webhooks, each delivery retried on its own
+// with growing delays. Trees published while lagging behind are skipped, their heads are in /roots.
+#[cfg(feature = "client")]
+fn spawn_webhooks(hooks: Arc<Webhooks>, service: Arc<TimestampingService<INDEX_SIZE, PREFIX_SIZE>>) {
+    let public_key = service.verifying_key().unwrap().to_bytes().to_vec();
+    let http = reqwest::Client::new();
+    let mut updates = service.subscribe_tree_updates();
+    tokio::spawn(async move {
+        while !matches!(updates.recv().await, Err(RecvError::Closed)) {
+            let latest = service.epochs.read().unwrap().latest().cloned();
+            let Some(head) = latest.and_then(|summary| signed_tree_head(&service, &summary, &public_key)) else {
+                continue;
+            };
+            let event = Arc::new(WebhookEvent::tree_published(head));
+            for url in hooks.urls() {
+                let (http, event) = (http.clone(), Arc::clone(&event));
+                tokio::spawn(async move {
+                    if let Err(e) = webhooks::deliver(&http, &url, &event).await {
+                        eprintln!("Notifying {} of tree {} failed: {}", url, event.tree_head.version, e);
+                    }
+                });
+            }
+        }
+    });
+}
+
+#[cfg(not(feature = "client"))]
+fn spawn_webhooks(_hooks: Arc<Webhooks>, _service: Arc<TimestampingService<INDEX_SIZE, PREFIX_SIZE>>) {
+    unreachable!("TIMESTAMPING_WEBHOOKS is refused by check_client_settings");
+}
+
+#[cfg(not(feature = "client"))]
+fn spawn_cosigning(_peers: Vec<Peer>, _service: Arc<TimestampingService<INDEX_SIZE, PREFIX_SIZE>>) {
+    unreachable!("TIMESTAMPING_PEERS is refused by check_client_settings");
+}
+
+#[cfg(feature = "tls")]
+async fn serve_tls(app: Router, bind_address: &str, cert: &str, key: &str) {
+    // Fails if a provider was installed already, which is just as good
+    let _ = rustls::crypto::ring::default_provider().install_default();
+    let config = axum_server::tls_rustls::RustlsConfig::from_pem_file(cert, key)
+        .await
+        .unwrap();
+    let address: std::net::SocketAddr = bind_address.parse().unwrap();
+
+    let handle = axum_server::Handle::new();
+    let shutdown_handle = handle.clone();
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        shutdown_handle.graceful_shutdown(None);
+    });
+
+    axum_server::bind_rustls(address, config)
+        .handle(handle)
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+        .await
+        .unwrap();
+}
+
+#[cfg(not(feature = "tls"))]
+async fn serve_tls(_app: Router, _bind_address: &str, _cert: &str, _key: &str) {
+    panic!("TLS support is not compiled in, build with `--features tls`");
+}
+
+// Resolve once the process receives Ctrl+C or SIGTERM
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c().await.unwrap();
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .unwrap()
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
+// Resolve the requested leaf encoding, falling back to the server default
+fn leaf_encoding(leaf_version: Option<u8>) -> Result<LeafEncoding, ApiError> {
+    match leaf_version {
+        Some(version) => LeafEncoding::from_version(version).ok_or_else(|| {
+            ApiError::new(ErrorCode::UnsupportedLeafVersion, MSG_UNSUPPORTED_LEAF_VERSION).with_details(serde_json::json!({
+                "leaf_version": version,
+                "supported_leaf_versions": LeafEncoding::ALL.map(|encoding| encoding.version()),
+            }))
+        }),
+        None => Ok(DEFAULT_LEAF_ENCODING),
+    }
+}
+
+// Metadata to attach to submitted hashes, tagged with the algorithm of their digests. `None` if the request
+// didn't give any and the digests are SHA-512, which untagged hashes are taken to be.
+fn submitted_metadata(
+    submitter: Option<String>,
+    label: Option<String>,
+    content_type: Option<String>,
+    namespace: Option<String>,
+    algorithm: HashAlgorithm,
+) -> Result<Option<HashMetadata>, ApiError> {
+    let fields = [&submitter, &label, &content_type, &namespace];
+    if fields.iter().any(|field| field.as_ref().is_some_and(|field| field.len() > MAX_METADATA_FIELD_LENGTH)) {
+        return Err(ApiError::new(ErrorCode::InvalidMetadata, MSG_INVALID_METADATA));
+    }
+    if fields.iter().all(|field| field.is_none()) && algorithm == HashAlgorithm::Sha512 {
+        return Ok(None);
+    }
+    Ok(Some(HashMetadata::new(submitter, label, content_type).with_namespace(namespace).with_hash_algorithm(algorithm)))
+}
+
+#[utoipa::path(
+    post, path = "/add", tag = "add",
+    params(AddQuery, ("Idempotency-Key" = Option<String>, Header, description = "Replay the response of an earlier request with this key instead of adding again")),
+    request_body(
+        description = "Concatenated raw digests, or whitespace separated hex or base64 digests",
+        content((Vec<u8> = "application/octet-stream"), (String = "text/plain")),
+    ),
+    responses(
+        (status = 200, body = AddBatchResponse),
+        (status = 400, body = ErrorResponse),
+        (status = 403, description = "Read-only replica, or a missing or invalid proof of work", body = ErrorResponse),
+        (status = 409, description = "A request with this idempotency key is still running", body = ErrorResponse),
+        (status = 422, description = "The idempotency key was used for a different request", body = ErrorResponse),
+        (status = 503, description = "Too many hashes are queued, retry after the Retry-After header", body = ErrorResponse),
+        (status = 507, description = "Store is full", body = ErrorResponse),
+    ),
+)]
+async fn add(
+    State(service): State<Arc<TimestampingService<INDEX_SIZE, PREFIX_SIZE>>>,
+    ApiQuery(query): ApiQuery<AddQuery>,
+    challenges: Option<Extension<Arc<Challenges>>>,
+    headers: HeaderMap,
+    bytes: Bytes,
+) -> Result<Json<AddBatchResponse>, ApiError> {
+    let algorithm = query.hash_algorithm;
+    let input_encoding = InputEncoding::negotiate(query.encoding, &headers, &bytes, algorithm);
+    let encoding = leaf_encoding(query.leaf_version)?;
+    let metadata = submitted_metadata(query.submitter, query.label, query.content_type, query.namespace, algorithm)?;
+    let hashes = input_encoding.decode_hashes(&bytes, algorithm)?;
+    check_work(challenges, &headers, &hashes)?;
+    let total_hashes = hashes.len();
+    let statuses = service.hash_store.add_batch_deduplicated(&hashes, encoding, metadata)?;
+    let new_hashes = statuses.iter().filter(|&&status| status == AddStatus::New).count();
+    let existing_hashes = statuses.iter().filter(|&&status| status == AddStatus::Existing).count();
+    let acknowledgments = service.acknowledge(&hashes, encoding)?;
+
+    let message = format!(
+        "Batch processed: {} total, {} new, {} existing, {} duplicate",
+        total_hashes, new_hashes, existing_hashes, total_hashes - new_hashes - existing_hashes
+    );
+
+    Ok(Json(AddBatchResponse::new(
+        AddResponse {
+            message,
+            total_hashes,
+            new_hashes,
+            existing_hashes,
+            leaf_version: encoding.version(),
+            hash_algorithm: algorithm,
+        },
+        statuses,
+        input_encoding,
+        acknowledgments,
+    )))
+}
+
+// Add a batch of any size in the background, reading the body chunk by chunk like /add-stream. Chunks are handed
+// to the job as they are decoded, which holds back reading while the store is behind, and the response has the
+// job to follow at /jobs/{id}. Acknowledgments aren't signed, receipts are available from /check once the hashes are in a tree.
+#[utoipa::path(
+    post, path = "/add-batch", tag = "add",
+    params(AddStreamQuery, ("Idempotency-Key" = Option<String>, Header, description = "Replay the response of an earlier request with this key instead of starting another job")),
+    request_body(
+        description = "Concatenated raw digests, one base64 digest per line with `format=base64`, or a JSON array of hex or base64 digests with `format=json`",
+        content((Vec<u8> = "application/octet-stream"), (String = "text/plain"), (Vec<String> = "application/json")),
+    ),
+    responses(
+        (status = 202, description = "Body read, the job adds the rest of its hashes in the background. Its url is in the Location header", body = JobProgress),
+        (status = 400, description = "Invalid entry, the details have the job that adds the hashes before it", body = ErrorResponse),
+        (status = 403, description = "Read-only replica, or adds need a proof of work", body = ErrorResponse),
+        (status = 503, description = "Too many jobs are running, retry after the Retry-After header", body = ErrorResponse),
+    ),
+)]
+async fn add_batch(
+    State(service): State<Arc<TimestampingService<INDEX_SIZE, PREFIX_SIZE>>>,
+    Extension(jobs): Extension<Arc<Jobs>>,
+    ApiQuery(query): ApiQuery<AddStreamQuery>,
+    challenges: Option<Extension<Arc<Challenges>>>,
+    body: Body,
+) -> Result<(StatusCode, [(HeaderName, String); 1], Json<JobProgress>), ApiError> {
+    refuse_without_work(challenges)?;
+    let algorithm = query.hash_algorithm;
+    let encoding = leaf_encoding(query.leaf_version)?;
+    let metadata = submitted_metadata(query.submitter, query.label, query.content_type, query.namespace, algorithm)?;
+    let job = jobs.create(encoding.version(), algorithm)
+        .ok_or_else(|| ApiError::new(ErrorCode::Saturated, MSG_TOO_MANY_JOBS))?;
+    let (inputs, received) = tokio::sync::mpsc::channel(JOB_QUEUED_CHUNKS);
+    tokio::task::spawn_blocking({
+        let job = Arc::clone(&job);
+        move || run_job(&service, &job, received, encoding, metadata)
+    });
+
+    let mut decoder = HashStreamDecoder::new(query.format, algorithm);
+    let mut stream = body.into_data_stream();
+    let mut hashes = Vec::with_capacity(JOB_CHUNK_SIZE);
+    let error = loop {
+        let chunk = match stream.next().await {
+            Some(Ok(chunk)) => chunk,
+            Some(Err(_)) => break Some(ApiError::new(ErrorCode::StreamReadFailed, MSG_STREAM_READ_FAILED)),
+            None => break decoder.finish(&mut hashes).err().map(|message| ApiError::new(ErrorCode::InvalidHash, message)),
+        };
+        if let Err(message) = decoder.feed(&chunk, &mut hashes) {
+            break Some(ApiError::new(ErrorCode::InvalidHash, message));
+        }
+        while hashes.len() >= JOB_CHUNK_SIZE {
+            let rest = hashes.split_off(JOB_CHUNK_SIZE);
+            job.lock().unwrap().progress.total_hashes += JOB_CHUNK_SIZE;
+            // Fails if the job stopped at an error of the store, which it reports
+            if inputs.send(JobInput::Hashes(std::mem::replace(&mut hashes, rest))).await.is_err() {
+                return Ok(job_accepted(&job));
+            }
+        }
+    };
+    // The hashes decoded before an invalid entry are still added
+    if !hashes.is_empty() {
+        job.lock().unwrap().progress.total_hashes += hashes.len();
+        let _ = inputs.send(JobInput::Hashes(hashes)).await;
+    }
+    match error {
+        Some(error) => {
+            let _ = inputs.send(JobInput::Invalid(error.0.message.clone())).await;
+            let progress = job.lock().unwrap().progress.clone();
+            Err(error.with_details(serde_json::json!({ "job": progress.id, "total_hashes": progress.total_hashes })))
+        }
+        None => {
+            let _ = inputs.send(JobInput::End).await;
+            Ok(job_accepted(&job))
+        }
+    }
+}
+
+fn job_accepted(job: &Mutex<Job>) -> (StatusCode, [(HeaderName, String); 1], Json<JobProgress>) {
+    let progress = job.lock().unwrap().progress.clone();
+    (StatusCode::ACCEPTED, [(header::LOCATION, format!("/jobs/{}", progress.id))], Json(progress))
+}
+
+// What /add-batch hands to its job while reading the body
+enum JobInput {
+    Hashes(Vec<Hash512>),
+    End,
+    // The body had an invalid entry after the hashes handed over before
+    Invalid(String),
+}
+
+// Add the hashes of a job chunk by chunk as they arrive. Only repeats within a chunk are reported as duplicates,
+// so memory doesn't grow with the batch, later repeats are existing hashes. Chunks refused because the workers
+// are busy are retried.
+fn run_job(
+    service: &TimestampingService<INDEX_SIZE, PREFIX_SIZE>,
+    job: &Mutex<Job>,
+    mut inputs: tokio::sync::mpsc::Receiver<JobInput>,
+    encoding: LeafEncoding,
+    metadata: Option<HashMetadata>,
+) {
+    loop {
+        let chunk = match inputs.blocking_recv() {
+            Some(JobInput::Hashes(chunk)) => chunk,
+            Some(JobInput::End) => return job.lock().unwrap().finish(None),
+            Some(JobInput::Invalid(message)) => return job.lock().unwrap().finish(Some(message)),
+            None => return job.lock().unwrap().finish(Some(MSG_JOB_BODY_INCOMPLETE.to_string())),
+        };
+        let statuses = loop {
+            match service.hash_store.add_batch_deduplicated(&chunk, encoding, metadata.clone()) {
+                Err(StorageError::Saturated(_)) => std::thread::sleep(SATURATED_RETRY_AFTER),
+                result => break result,
+            }
+        };
+        match statuses {
+            Ok(statuses) => job.lock().unwrap().record(&statuses),
+            Err(e) => return job.lock().unwrap().finish(Some(e.to_string())),
+        }
+    }
+}
+
+#[utoipa::path(
+    get, path = "/jobs/{id}", tag = "add",
+    params(("id" = String, Path, description = "Job id from /add-batch"), JobQuery),
+    responses((status = 200, body = JobResponse), (status = 404, body = ErrorResponse)),
+)]
+async fn get_job(
+    Extension(jobs): Extension<Arc<Jobs>>,
+    ApiPath(id): ApiPath<String>,
+    ApiQuery(query): ApiQuery<JobQuery>,
+) -> Result<Json<JobResponse>, ApiError> {
+    let job = jobs.get(&id).ok_or_else(|| ApiError::new(ErrorCode::NotFound, MSG_JOB_NOT_FOUND))?;
+    let job = job.lock().unwrap();
+    let offset = query.offset.unwrap_or(0).min(job.statuses.len());
+    let limit = query.limit.unwrap_or(MAX_JOB_STATUSES).min(MAX_JOB_STATUSES);
+    let statuses = job.statuses[offset..].iter().take(limit).copied().collect();
+    Ok(Json(JobResponse { progress: job.progress.clone(), offset, statuses }))
+}
+
+// Dry run of /add: decode and check every entry of a batch without storing anything. Statuses are those
+// the batch would get if it was added now, hashes added in between can turn new ones into existing ones.
+#[utoipa::path(
+    post, path = "/validate-batch", tag = "check", params(AddQuery),
+    request_body(
+        description = "Concatenated raw digests, or whitespace separated hex or base64 digests",
+        content((Vec<u8> = "application/octet-stream"), (String = "text/plain")),
+    ),
+    responses(
+        (status = 200, description = "The outcome of every entry, also if some are invalid", body = ValidateBatchResponse),
+        (status = 400, description = "Invalid parameters or metadata, which /add would refuse for any body", body = ErrorResponse),
+        (status = 503, description = "A worker didn't answer, retry after the Retry-After header", body = ErrorResponse),
+    ),
+)]
+async fn validate_batch(
+    State(service): State<Arc<TimestampingService<INDEX_SIZE, PREFIX_SIZE>>>,
+    ApiQuery(query): ApiQuery<AddQuery>,
+    headers: HeaderMap,
+    bytes: Bytes,
+) -> Result<Json<ValidateBatchResponse>, ApiError> {
+    let algorithm = query.hash_algorithm;
+    let input_encoding = InputEncoding::negotiate(query.encoding, &headers, &bytes, algorithm);
+    let encoding = leaf_encoding(query.leaf_version)?;
+    submitted_metadata(query.submitter, query.label, query.content_type, query.namespace, algorithm)?;
+    let entries = input_encoding.decode_entries(&bytes, algorithm);
+
+    // Like `HashStore::add_batch_deduplicated`, only the first of repeated hashes is looked up
+    let mut seen = HashSet::with_capacity(entries.len());
+    let unique: Vec<Hash512> = entries.iter().filter_map(|entry| entry.ok()).filter(|hash| seen.insert(*hash)).collect();
+    let mut exists = service.hash_store.contains_batch_with_encoding(&unique, encoding)?.into_iter();
+    seen.clear();
+    let entries: Vec<ValidatedEntry> = entries.into_iter()
+        .map(|entry| match entry {
+            Ok(hash) => ValidatedEntry {
+                status: Some(match seen.insert(hash) {
+                    false => AddStatus::Duplicate,
+                    true if exists.next().unwrap() => AddStatus::Existing,
+                    true => AddStatus::New,
+                }),
+                error: None,
+            },
+            Err(message) => ValidatedEntry { status: None, error: Some(message) },
+        })
+        .collect();
+
+    let count = |status| entries.iter().filter(|entry| entry.status == Some(status)).count();
+    let (new_hashes, existing_hashes, duplicate_hashes) = (count(AddStatus::New), count(AddStatus::Existing), count(AddStatus::Duplicate));
+    let invalid_entries = entries.iter().filter(|entry| entry.status.is_none()).count();
+    Ok(Json(ValidateBatchResponse {
+        message: format!(
+            "Batch validated: {} total, {} new, {} existing, {} duplicate, {} invalid",
+            entries.len(), new_hashes, existing_hashes, duplicate_hashes, invalid_entries
+        ),
+        valid: invalid_entries == 0,
+        total_entries: entries.len(),
+        new_hashes,
+        existing_hashes,
+        duplicate_hashes,
+        invalid_entries,
+        entries,
+        input_encoding,
+        leaf_version: encoding.version(),
+        hash_algorithm: algorithm,
+    }))
+}
+
+// Privacy mode: the salted leaves can't be linked to the hashes without the returned nonces,
+// so no one else can check for a submission. Metadata would identify it, so none is accepted.
+#[utoipa::path(
+    post, path = "/add-private", tag = "add", params(AddPrivateQuery),
+    request_body(description = "Concatenated raw digests", content = Vec<u8>, content_type = "application/octet-stream"),
+    responses(
+        (status = 200, body = AddPrivateResponse),
+        (status = 400, body = ErrorResponse),
+        (status = 403, description = "Read-only replica, or a missing or invalid proof of work", body = ErrorResponse),
+        (status = 503, description = "Too many hashes are queued, retry after the Retry-After header", body = ErrorResponse),
+        (status = 507, description = "Store is full", body = ErrorResponse),
+    ),
+)]
+async fn add_private(
+    State(service): State<Arc<TimestampingService<INDEX_SIZE, PREFIX_SIZE>>>,
+    ApiQuery(query): ApiQuery<AddPrivateQuery>,
+    challenges: Option<Extension<Arc<Challenges>>>,
+    headers: HeaderMap,
+    bytes: Bytes,
+) -> Result<Json<AddPrivateResponse>, ApiError> {
+    let encoding = leaf_encoding(query.leaf_version)?;
+    let algorithm = query.hash_algorithm;
+    let hashes = InputEncoding::Raw.decode_hashes(&bytes, algorithm)?;
+    check_work(challenges, &headers, &hashes)?;
+    let nonces = service.hash_store.add_blinded(&hashes, encoding)?;
+
+    Ok(Json(AddPrivateResponse {
+        add: AddResponse {
+            message: format!("Batch processed: {} total, {} new, 0 existing", hashes.len(), hashes.len()),
+            total_hashes: hashes.len(),
+            new_hashes: hashes.len(),
+            existing_hashes: 0,
+            leaf_version: encoding.version(),
+            hash_algorithm: algorithm,
+        },
+        nonces: nonces.iter().map(|nonce| nonce.to_bytes()).collect(),
+    }))
+}
+
+// Issue a challenge, to be solved with `pow::solve` for the hashes of the next add
+#[utoipa::path(
+    get, path = "/challenge", tag = "add",
+    responses(
+        (status = 200, body = ChallengeResponse),
+        (status = 503, description = "Too many challenges are pending, retry after the Retry-After header", body = ErrorResponse),
+    ),
+)]
+async fn get_challenge(Extension(challenges): Extension<Arc<Challenges>>) -> Result<Json<ChallengeResponse>, ApiError> {
+    let challenge = challenges.issue().ok_or_else(|| ApiError::new(ErrorCode::Saturated, MSG_TOO_MANY_CHALLENGES))?;
+    Ok(Json(ChallengeResponse {
+        challenge: base64::engine::general_purpose::STANDARD.encode(challenge),
+        difficulty: challenges.difficulty,
+        expires_in: CHALLENGE_LIFETIME.as_secs(),
+        format: POW_FORMAT,
+    }))
+}
+
+// If adds need a proof of work, check the one sent with `hashes` and use up its challenge
+fn check_work(challenges: Option<Extension<Arc<Challenges>>>, headers: &HeaderMap, hashes: &[Hash512]) -> Result<(), ApiError> {
+    let Some(Extension(challenges)) = challenges else {
+        return Ok(());
+    };
+    let required = || ApiError::new(ErrorCode::ProofOfWorkRequired, MSG_PROOF_OF_WORK_REQUIRED);
+    let challenge = headers.get(CHALLENGE_HEADER)
+        .and_then(|value| base64::engine::general_purpose::STANDARD.decode(value.as_bytes()).ok())
+        .ok_or_else(required)?;
+    let nonce = headers.get(CHALLENGE_NONCE_HEADER)
+        .and_then(|value| value.to_str().ok()?.parse().ok())
+        .ok_or_else(required)?;
+    // The work is checked first, so wrong nonces don't use up the challenge
+    if !pow::verify(&challenge, nonce, hashes, challenges.difficulty) {
+        return Err(ApiError::new(ErrorCode::ProofOfWorkRequired, MSG_INVALID_PROOF_OF_WORK));
+    }
+    if !challenges.redeem(&challenge) {
+        return Err(ApiError::new(ErrorCode::ProofOfWorkRequired, MSG_CHALLENGE_EXPIRED));
+    }
+    Ok(())
+}
+
+// Streamed adds store hashes before the whole body is read, so no work over all of them can be checked first
+fn refuse_without_work(challenges: Option<Extension<Arc<Challenges>>>) -> Result<(), ApiError> {
+    match challenges {
+        Some(_) => Err(ApiError::new(ErrorCode::ProofOfWorkRequired, MSG_STREAM_WITHOUT_WORK)),
+        None => Ok(()),
+    }
+}
+
+// Splits a streamed body into hashes, keeping incomplete records between chunks
+struct HashStreamDecoder {
+    format: StreamFormat,
+    algorithm: HashAlgorithm,
+    pending: Vec<u8>,
+    json: JsonPosition,
+}
+
+impl HashStreamDecoder {
+    fn new(format: StreamFormat, algorithm: HashAlgorithm) -> Self {
+        Self { format, algorithm, pending: Vec::new(), json: JsonPosition::BeforeArray }
+    }
+
+    fn feed(&mut self, chunk: &[u8], hashes: &mut Vec<Hash512>) -> Result<(), &'static str> {
+        self.pending.extend_from_slice(chunk);
+        let consumed = match self.format {
+            StreamFormat::Raw => {
+                let records = self.pending.chunks_exact(self.algorithm.digest_len());
+                let consumed = self.pending.len() - records.remainder().len();
+                hashes.extend(records.map(|record| self.algorithm.normalize(record).unwrap()));
+                consumed
+            }
+            StreamFormat::Base64 => {
+                let Some(end) = self.pending.iter().rposition(|&byte| byte == b'\n') else {
+                    if self.pending.len() > MAX_BASE64_LINE_LENGTH {
+                        return Err(MSG_INVALID_BASE64_LINE);
+                    }
+                    return Ok(());
+                };
+                for line in self.pending[..end].split(|&byte| byte == b'\n') {
+                    self.decode_line(line, hashes)?;
+                }
+                end + 1
+            }
+            StreamFormat::Json => self.decode_json(hashes)?,
+        };
+        self.pending.drain(..consumed);
+        Ok(())
+    }
+
+    // Decode the complete entries of the pending JSON and return how many bytes were read
+    fn decode_json(&mut self, hashes: &mut Vec<Hash512>) -> Result<usize, &'static str> {
+        let mut position = 0;
+        loop {
+            while self.pending.get(position).is_some_and(u8::is_ascii_whitespace) {
+                position += 1;
+            }
+            let Some(&byte) = self.pending.get(position) else {
+                return Ok(position);
+            };
+            self.json = match (self.json, byte) {
+                (JsonPosition::BeforeArray, b'[') => JsonPosition::FirstValue,
+                (JsonPosition::FirstValue | JsonPosition::AfterValue, b']') => JsonPosition::AfterArray,
+                (JsonPosition::AfterValue, b',') => JsonPosition::NextValue,
+                (JsonPosition::FirstValue | JsonPosition::NextValue, b'"') => {
+                    let Some(length) = self.pending[position + 1..].iter().position(|&byte| byte == b'"') else {
+                        if self.pending.len() - position > MAX_BASE64_LINE_LENGTH {
+                            return Err(MSG_INVALID_JSON_ENTRY);
+                        }
+                        return Ok(position);
+                    };
+                    let entry = &self.pending[position + 1..position + 1 + length];
+                    let digest = if entry.len() == 2 * self.algorithm.digest_len() && entry.iter().all(u8::is_ascii_hexdigit) {
+                        decode_hex(entry)
+                    } else {
+                        base64::engine::general_purpose::STANDARD.decode(entry).ok()
+                    };
+                    hashes.push(digest.and_then(|digest| self.algorithm.normalize(&digest).ok()).ok_or(MSG_INVALID_JSON_ENTRY)?);
+                    position += length + 1;
+                    JsonPosition::AfterValue
+                }
+                _ => return Err(MSG_INVALID_JSON_ENTRY),
+            };
+            position += 1;
+        }
+    }
+
+    // Handle the data left after the last chunk
+    fn finish(self, hashes: &mut Vec<Hash512>) -> Result<(), &'static str> {
+        match self.format {
+            StreamFormat::Raw if self.pending.is_empty() => Ok(()),
+            StreamFormat::Raw => Err(MSG_INVALID_BATCH_SIZE),
+            StreamFormat::Base64 => self.decode_line(&self.pending, hashes),
+            StreamFormat::Json if self.json == JsonPosition::AfterArray && self.pending.trim_ascii().is_empty() => Ok(()),
+            StreamFormat::Json => Err(MSG_INVALID_JSON_ENTRY),
+        }
+    }
+
+    fn decode_line(&self, line: &[u8], hashes: &mut Vec<Hash512>) -> Result<(), &'static str> {
+        let line = line.trim_ascii();
+        if line.is_empty() {
+            return Ok(());
+        }
+        let bytes = base64::engine::general_purpose::STANDARD
+            .decode(line)
+            .map_err(|_| MSG_INVALID_BASE64_LINE)?;
+        hashes.push(self.algorithm.normalize(&bytes).map_err(|_| MSG_INVALID_BASE64_LINE)?);
+        Ok(())
+    }
+}
+
+// Add hashes from a body of any size, processing it chunk by chunk.
+// Hashes before an invalid record are kept, the details of an error report how many were processed.
+#[utoipa::path(
+    post, path = "/add-stream", tag = "add", params(AddStreamQuery),
+    request_body(
+        description = "Concatenated raw digests, or one base64 digest per line with `format=base64`",
+        content((Vec<u8> = "application/octet-stream"), (String = "text/plain")),
+    ),
+    responses(
+        (status = 200, body = AddResponse),
+        (status = 400, description = "Invalid record, the details count the hashes added before it", body = ErrorResponse),
+        (status = 403, description = "Read-only replica, or adds need a proof of work", body = ErrorResponse),
+        (status = 503, description = "Too many hashes are queued, retry after the Retry-After header", body = ErrorResponse),
+        (status = 507, description = "Store is full", body = ErrorResponse),
+    ),
+)]
+async fn add_stream(
+    State(service): State<Arc<TimestampingService<INDEX_SIZE, PREFIX_SIZE>>>,
+    ApiQuery(query): ApiQuery<AddStreamQuery>,
+    challenges: Option<Extension<Arc<Challenges>>>,
+    body: Body,
+) -> Result<Json<AddResponse>, ApiError> {
+    refuse_without_work(challenges)?;
+    let algorithm = query.hash_algorithm;
+    let encoding = leaf_encoding(query.leaf_version)?;
+    let metadata = submitted_metadata(query.submitter, query.label, query.content_type, query.namespace, algorithm)?;
+
+    let mut decoder = HashStreamDecoder::new(query.format, algorithm);
+    let mut stream = body.into_data_stream();
+    let mut hashes = Vec::with_capacity(STREAM_BATCH_SIZE);
+    let mut total_hashes = 0;
+    let mut new_hashes = 0;
+    let mut add = |hashes: &mut Vec<Hash512>| {
+        let results = service.hash_store.add_batch_with_metadata(hashes, encoding, metadata.clone());
+        hashes.clear();
+        let results = results?;
+        total_hashes += results.len();
+        new_hashes += results.into_iter().filter(|&is_new| is_new).count();
+        Ok(())
+    };
+
+    let invalid_hash = |message| ApiError::new(ErrorCode::InvalidHash, message);
+    let result = loop {
+        let chunk = match stream.next().await {
+            Some(Ok(chunk)) => chunk,
+            Some(Err(_)) => break Err(ApiError::new(ErrorCode::StreamReadFailed, MSG_STREAM_READ_FAILED)),
+            None => break decoder.finish(&mut hashes).map_err(invalid_hash),
+        };
+        if let Err(message) = decoder.feed(&chunk, &mut hashes) {
+            break Err(invalid_hash(message));
+        }
+        if hashes.len() >= STREAM_BATCH_SIZE
+            && let Err(e) = add(&mut hashes)
+        {
+            break Err(e);
+        }
+    };
+    let result = result.and(add(&mut hashes));
+
+    let existing_hashes = total_hashes - new_hashes;
+    if let Err(error) = result {
+        return Err(error.with_details(serde_json::json!({
+            "total_hashes": total_hashes,
+            "new_hashes": new_hashes,
+            "existing_hashes": existing_hashes,
+        })));
+    }
+
+    Ok(Json(AddResponse {
+        message: format!("Stream processed: {} total, {} new, {} existing", total_hashes, new_hashes, existing_hashes),
+        total_hashes,
+        new_hashes,
+        existing_hashes,
+        leaf_version: encoding.version(),
+        hash_algorithm: algorithm,
+    }))
+}
+
+// Hash a raw body, or every field of a multipart upload, with SHA-512 and add the digests.
+// For uploads, file names and content types are used as metadata unless the query overrides them.
+#[utoipa::path(
+    post, path = "/add-data", tag = "add", params(AddDataQuery),
+    request_body(
+        description = "Data to hash, or a multipart upload with one file per field",
+        content((Vec<u8> = "application/octet-stream"), (Vec<u8> = "multipart/form-data")),
+    ),
+    responses(
+        (status = 200, body = AddDataResponse),
+        (status = 400, body = ErrorResponse),
+        (status = 403, description = "Read-only replica, or adds need a proof of work", body = ErrorResponse),
+        (status = 503, description = "Too many hashes are queued, retry after the Retry-After header", body = ErrorResponse),
+        (status = 507, description = "Store is full", body = ErrorResponse),
+    ),
+)]
+async fn add_data(
+    State(service): State<Arc<TimestampingService<INDEX_SIZE, PREFIX_SIZE>>>,
+    ApiQuery(query): ApiQuery<AddDataQuery>,
+    challenges: Option<Extension<Arc<Challenges>>>,
+    request: Request,
+) -> Result<Json<AddDataResponse>, ApiError> {
+    refuse_without_work(challenges)?;
+    let read_failed = || ApiError::new(ErrorCode::StreamReadFailed, MSG_STREAM_READ_FAILED);
+    let encoding = leaf_encoding(query.leaf_version)?;
+    let field_metadata = |label: Option<String>, content_type: Option<String>| submitted_metadata(
+        query.submitter.clone(),
+        query.label.clone().or(label),
+        query.content_type.clone().or(content_type),
+        query.namespace.clone(),
+        HashAlgorithm::Sha512,
+    );
+
+    let is_multipart = request.headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("multipart/form-data"));
+
+    // Digests to add, each with its metadata
+    let mut uploads = Vec::new();
+    if is_multipart {
+        let mut multipart = Multipart::from_request(request, &()).await.map_err(|_| read_failed())?;
+        while let Some(mut field) = multipart.next_field().await.map_err(|_| read_failed())? {
+            let metadata = field_metadata(field.file_name().map(str::to_string), field.content_type().map(str::to_string))?;
+            let mut hasher = Sha512::new();
+            while let Some(chunk) = field.chunk().await.map_err(|_| read_failed())? {
+                hasher.update(&chunk);
+            }
+            uploads.push((Hash512::from_bytes(&hasher.finalize()).unwrap(), metadata));
+        }
+    } else {
+        let metadata = field_metadata(None, None)?;
+        let mut stream = request.into_body().into_data_stream();
+        let mut hasher = Sha512::new();
+        while let Some(chunk) = stream.next().await {
+            hasher.update(&chunk.map_err(|_| read_failed())?);
+        }
+        uploads.push((Hash512::from_bytes(&hasher.finalize()).unwrap(), metadata));
+    }
+
+    let total_hashes = uploads.len();
+    let mut new_hashes = 0;
+    let mut digests = Vec::with_capacity(total_hashes);
+    for (hash, metadata) in uploads {
+        if service.hash_store.add_batch_with_metadata(&[hash], encoding, metadata)?[0] {
+            new_hashes += 1;
+        }
+        digests.push(hash.to_bytes());
+    }
+    let existing_hashes = total_hashes - new_hashes;
+
+    Ok(Json(AddDataResponse {
+        add: AddResponse {
+            message: format!("Data hashed: {} total, {} new, {} existing", total_hashes, new_hashes, existing_hashes),
+            total_hashes,
+            new_hashes,
+            existing_hashes,
+            leaf_version: encoding.version(),
+            hash_algorithm: HashAlgorithm::Sha512,
+        },
+        digests,
+    }))
+}
+
+// Existence, merkle proof and metadata of a hash, shared by `/check`, `/check-private` and `/hash/{hash}`.
+// The proof is for the requested tree version, or the latest tree if none is given.
+// Hashes added with `/add-private` are looked up with their nonce and have no metadata.
+fn check_hash(
+    service: &TimestampingService<INDEX_SIZE, PREFIX_SIZE>,
+    hash: &Hash512,
+    nonce: Option<&Hash512>,
+    query: &CheckQuery,
+) -> Result<CheckHashResponse, ApiError> {
+    let encoding = leaf_encoding(query.leaf_version)?;
+
+    // Without a requested version the proof comes from the tree loaded here, even if a newer one is published meanwhile
+    let published = service.published_tree();
+    let tree_version = query.tree_version.or_else(|| published.as_ref().map(|published| published.head.version));
+    let exists = match nonce {
+        Some(nonce) => service.hash_store.contains_blinded(hash, nonce, encoding)?,
+        None => service.hash_store.contains_with_encoding(hash, encoding)?,
+    };
+    // A hash added with another leaf version would only be reported missing
+    if !exists {
+        let mut stored_versions = Vec::new();
+        for other in LeafEncoding::ALL.into_iter().filter(|&other| other != encoding) {
+            let stored = match nonce {
+                Some(nonce) => service.hash_store.contains_blinded(hash, nonce, other)?,
+                None => service.hash_store.contains_with_encoding(hash, other)?,
+            };
+            if stored {
+                stored_versions.push(other.version());
+            }
+        }
+        if !stored_versions.is_empty() {
+            return Err(ApiError::new(ErrorCode::LeafVersionMismatch, MSG_LEAF_VERSION_MISMATCH).with_details(serde_json::json!({
+                "leaf_version": encoding.version(),
+                "stored_leaf_versions": stored_versions,
+            })));
+        }
+    }
+    let proof_at_version = |version| match nonce {
+        Some(nonce) => service.get_blinded_merkle_proof_at_version(hash, nonce, encoding, version),
+        None => service.get_merkle_proof_at_version(hash, encoding, version),
+    };
+    let merkle_proof = match (query.tree_version, &published) {
+        _ if !exists => None,
+        (Some(version), _) => proof_at_version(version)?,
+        (None, Some(published)) => service.get_merkle_proof_from(published, hash, nonce, encoding),
+        (None, None) => None,
+    };
+    let metadata = if exists && nonce.is_none() { service.hash_store.metadata_with_encoding(hash, encoding)? } else { None };
+    let sequence = if exists && nonce.is_none() { service.hash_store.sequence_with_encoding(hash, encoding)? } else { None };
+    let resubmissions = if exists && nonce.is_none() { service.hash_store.resubmissions_with_encoding(hash, encoding)? } else { None };
+
+    Ok(CheckHashResponse {
+        message: if exists { MSG_HASH_FOUND } else { MSG_HASH_NOT_FOUND },
+        exists,
+        merkle_proof,
+        metadata,
+        leaf_version: encoding.version(),
+        tree_version,
+        hash_algorithm: query.hash_algorithm,
+        tree_hasher: service.hash_store.hasher().name(),
+        sequence,
+        resubmissions,
+        input_encoding: None,
+    })
+}
+
+fn tree_version_unavailable() -> ApiError {
+    ApiError::new(ErrorCode::TreeVersionUnavailable, MSG_TREE_VERSION_UNAVAILABLE)
+}
+
+#[utoipa::path(
+    post, path = "/check", tag = "check", params(CheckQuery),
+    request_body(
+        description = "One raw digest, or one hex or base64 digest",
+        content((Vec<u8> = "application/octet-stream"), (String = "text/plain")),
+    ),
+    responses(
+        (status = 200, body = CheckHashResponse),
+        (status = 400, body = ErrorResponse),
+        (status = 404, description = "The requested tree version is no longer kept", body = ErrorResponse),
+        (status = 409, description = "The hash is only stored with another leaf version", body = ErrorResponse),
+        (status = 503, description = "A worker didn't answer, retry after the Retry-After header", body = ErrorResponse),
+    ),
+)]
+async fn check(
+    State(service): State<Arc<TimestampingService<INDEX_SIZE, PREFIX_SIZE>>>,
+    ApiQuery(query): ApiQuery<CheckQuery>,
+    headers: HeaderMap,
+    bytes: Bytes,
+) -> Result<Json<CheckHashResponse>, ApiError> {
+    let input_encoding = InputEncoding::negotiate(query.encoding, &headers, &bytes, query.hash_algorithm);
+    let hash = input_encoding.decode_hash(&bytes, query.hash_algorithm)?;
+    let response = check_hash(&service, &hash, None, &query)?;
+    Ok(Json(CheckHashResponse { input_encoding: Some(input_encoding), ..response }))
+}
+
+#[utoipa::path(
+    post, path = "/check-private", tag = "check", params(CheckQuery),
+    request_body(description = "The raw digest followed by its 64 byte nonce", content = Vec<u8>, content_type = "application/octet-stream"),
+    responses(
+        (status = 200, body = CheckHashResponse),
+        (status = 400, body = ErrorResponse),
+        (status = 404, description = "The requested tree version is no longer kept", body = ErrorResponse),
+        (status = 409, description = "The hash is only stored with another leaf version", body = ErrorResponse),
+        (status = 503, description = "A worker didn't answer, retry after the Retry-After header", body = ErrorResponse),
+    ),
+)]
+async fn check_private(
+    State(service): State<Arc<TimestampingService<INDEX_SIZE, PREFIX_SIZE>>>,
+    ApiQuery(query): ApiQuery<CheckQuery>,
+    bytes: Bytes,
+) -> Result<Json<CheckHashResponse>, ApiError> {
+    let digest_len = query.hash_algorithm.digest_len();
+    if bytes.len() != digest_len + 64 {
+        return Err(ApiError::new(ErrorCode::InvalidHash, MSG_INVALID_PRIVATE_CHECK));
+    }
+    let hash = query.hash_algorithm.normalize(&bytes[..digest_len]).unwrap();
+    let nonce = Hash512::from_bytes(&bytes[digest_len..]).unwrap();
+
+    check_hash(&service, &hash, Some(&nonce), &query).map(Json)
+}
+
+#[utoipa::path(
+    post, path = "/wait", tag = "check", params(WaitQuery),
+    request_body(
+        description = "One raw digest, or one hex or base64 digest",
+        content((Vec<u8> = "application/octet-stream"), (String = "text/plain")),
+    ),
+    responses(
+        (status = 200, description = "Receipt in the JSON format of the CLI, with the proof and the signed tree head", body = Object),
+        (status = 400, body = ErrorResponse),
+        (status = 408, description = "No tree included the hash within the timeout", body = ErrorResponse),
+    ),
+)]
+async fn wait(
+    State(service): State<Arc<TimestampingService<INDEX_SIZE, PREFIX_SIZE>>>,
+    ApiQuery(query): ApiQuery<WaitQuery>,
+    headers: HeaderMap,
+    bytes: Bytes,
+) -> Result<Response, ApiError> {
+    let input_encoding = InputEncoding::negotiate(query.encoding, &headers, &bytes, query.hash_algorithm);
+    let hash = input_encoding.decode_hash(&bytes, query.hash_algorithm)?;
+    let encoding = leaf_encoding(query.leaf_version)?;
+    let timeout = query.timeout.map_or(DEFAULT_WAIT_TIMEOUT, Duration::from_secs).min(MAX_WAIT_TIMEOUT);
+    let deadline = tokio::time::Instant::now() + timeout;
+
+    // Subscribed before the first look, so a tree published in between isn't missed
+    let mut updates = service.subscribe_tree_updates();
+    loop {
+        if let Some(receipt) = service.get_receipt(&hash, encoding) {
+            let receipt = Receipt { hash_algorithm: query.hash_algorithm, ..receipt };
+            return Ok(([(header::CONTENT_TYPE, "application/json")], receipt.to_json()).into_response());
+        }
+        match tokio::time::timeout_at(deadline, updates.recv()).await {
+            Ok(Ok(_) | Err(RecvError::Lagged(_))) => continue,
+            Ok(Err(RecvError::Closed)) | Err(_) => return Err(ApiError::new(ErrorCode::WaitTimeout, MSG_WAIT_TIMEOUT)),
+        }
+    }
+}
+
+#[utoipa::path(
+    post, path = "/check-batch", tag = "check", params(CheckQuery),
+    request_body(description = "Concatenated raw digests, at most 10000", content = Vec<u8>, content_type = "application/octet-stream"),
+    responses(
+        (status = 200, body = CheckBatchResponse),
+        (status = 400, body = ErrorResponse),
+        (status = 404, description = "The requested tree version is no longer kept", body = ErrorResponse),
+        (status = 413, description = "Too many hashes", body = ErrorResponse),
+        (status = 503, description = "A worker didn't answer, retry after the Retry-After header", body = ErrorResponse),
+    ),
+)]
+async fn check_batch(
+    State(service): State<Arc<TimestampingService<INDEX_SIZE, PREFIX_SIZE>>>,
+    ApiQuery(query): ApiQuery<CheckQuery>,
+    bytes: Bytes,
+) -> Result<Json<CheckBatchResponse>, ApiError> {
+    let encoding = leaf_encoding(query.leaf_version)?;
+    let algorithm = query.hash_algorithm;
+    if bytes.len() / algorithm.digest_len() > MAX_CHECK_BATCH_SIZE {
+        return Err(ApiError::new(ErrorCode::BatchTooLarge, MSG_CHECK_BATCH_TOO_LARGE));
+    }
+    let hashes = InputEncoding::Raw.decode_hashes(&bytes, algorithm)?;
+
+    // All proofs come from the same tree, like in `check_hash`
+    let published = service.published_tree();
+    let tree_version = query.tree_version.or_else(|| published.as_ref().map(|published| published.head.version));
+    let results = check_entries(&service, &hashes, encoding, query.tree_version, published.as_deref())?;
+
+    Ok(Json(CheckBatchResponse {
+        message: MSG_BATCH_CHECKED,
+        results,
+        leaf_version: encoding.version(),
+        tree_version,
+        hash_algorithm: algorithm,
+        tree_hasher: service.hash_store.hasher().name(),
+    }))
+}
+
+// Existence, proof and metadata of every hash, with the proofs from the tree `tree_version`, or else from `published`
+fn check_entries(
+    service: &TimestampingService<INDEX_SIZE, PREFIX_SIZE>,
+    hashes: &[Hash512],
+    encoding: LeafEncoding,
+    tree_version: Option<u64>,
+    published: Option<&PublishedTree>,
+) -> Result<Vec<CheckBatchEntry>, ApiError> {
+    let exists = service.hash_store.contains_batch_with_encoding(hashes, encoding)?;
+    let proofs = match (tree_version, published) {
+        (Some(version), _) => service.get_merkle_proofs_at_version(hashes, encoding, version)?,
+        (None, Some(published)) => hashes.iter().map(|hash| service.get_merkle_proof_from(published, hash, None, encoding)).collect(),
+        (None, None) => vec![None; hashes.len()],
+    };
+
+    let entries = hashes.iter().zip(exists).zip(proofs)
+        .map(|((hash, exists), merkle_proof)| Ok(CheckBatchEntry {
+            exists,
+            merkle_proof: merkle_proof.filter(|_| exists),
+            metadata: if exists { service.hash_store.metadata_with_encoding(hash, encoding)? } else { None },
+        }))
+        .collect::<Result<_, StorageError>>()?;
+    Ok(entries)
+}
+
+fn ndjson_line(value: &impl Serialize) -> Vec<u8> {
+    let mut line = serde_json::to_vec(value).unwrap();
+    line.push(b'\n');
+    line
+}
+
+// Reads the body of a /check-stream request and checks its hashes a batch at a time
+struct CheckStream {
+    service: Arc<TimestampingService<INDEX_SIZE, PREFIX_SIZE>>,
+    body: BodyDataStream,
+    // `None` once the whole body is decoded
+    decoder: Option<HashStreamDecoder>,
+    hashes: Vec<Hash512>,
+    encoding: LeafEncoding,
+    tree_version: Option<u64>,
+    published: Option<Arc<PublishedTree>>,
+    checked_hashes: usize,
+    error: Option<ApiError>,
+}
+
+impl CheckStream {
+    // Lines of the next batch, then the error that ended the stream if there is one
+    async fn next_lines(&mut self) -> Option<Vec<u8>> {
+        while self.hashes.len() < PROOF_STREAM_BATCH_SIZE && self.decoder.is_some() {
+            if let Err(error) = self.read_chunk().await {
+                self.decoder = None;
+                self.error = Some(error);
+            }
+        }
+        if !self.hashes.is_empty() {
+            match self.check_batch() {
+                Ok(lines) => return Some(lines),
+                Err(error) => {
+                    self.hashes.clear();
+                    self.decoder = None;
+                    self.error = Some(error);
+                }
+            }
+        }
+        let error = self.error.take()?.with_details(serde_json::json!({ "checked_hashes": self.checked_hashes }));
+        Some(ndjson_line(&ErrorResponse { error: error.0 }))
+    }
+
+    async fn read_chunk(&mut self) -> Result<(), ApiError> {
+        let invalid_hash = |message| ApiError::new(ErrorCode::InvalidHash, message);
+        match self.body.next().await {
+            Some(Ok(chunk)) => self.decoder.as_mut().unwrap().feed(&chunk, &mut self.hashes).map_err(invalid_hash),
+            Some(Err(_)) => Err(ApiError::new(ErrorCode::StreamReadFailed, MSG_STREAM_READ_FAILED)),
+            None => self.decoder.take().unwrap().finish(&mut self.hashes).map_err(invalid_hash),
+        }
+    }
+
+    fn check_batch(&mut self) -> Result<Vec<u8>, ApiError> {
+        let hashes: Vec<Hash512> = self.hashes.drain(..self.hashes.len().min(PROOF_STREAM_BATCH_SIZE)).collect();
+        let entries = check_entries(&self.service, &hashes, self.encoding, self.tree_version, self.published.as_deref())?;
+        self.checked_hashes += hashes.len();
+        Ok(entries.iter().flat_map(ndjson_line).collect())
+    }
+}
+
+// Check hashes from a body of any size and stream the results back while the body is still read, so memory
+// stays bounded by a batch however many hashes are checked. All proofs come from the same tree, like in `check_batch`.
+// The status is sent before the hashes are read, an error after that ends the response with an `ErrorResponse` line.
+#[utoipa::path(
+    post, path = "/check-stream", tag = "check", params(CheckStreamQuery),
+    request_body(
+        description = "Concatenated raw digests, or one base64 digest per line with `format=base64`",
+        content((Vec<u8> = "application/octet-stream"), (String = "text/plain")),
+    ),
+    responses(
+        (status = 200, description = "A `CheckStreamHead` line, then a `CheckBatchEntry` line for every hash in the order of the body", body = CheckStreamHead, content_type = "application/x-ndjson"),
+        (status = 400, body = ErrorResponse),
+        (status = 404, description = "The requested tree version is no longer kept", body = ErrorResponse),
+    ),
+)]
+async fn check_stream(
+    State(service): State<Arc<TimestampingService<INDEX_SIZE, PREFIX_SIZE>>>,
+    ApiQuery(query): ApiQuery<CheckStreamQuery>,
+    body: Body,
+) -> Result<Response, ApiError> {
+    let encoding = leaf_encoding(query.leaf_version)?;
+    let published = service.published_tree();
+    let tree_version = match query.tree_version {
+        // Refused up front if it isn't kept, a version dropped while streaming ends the stream with an error line
+        Some(version) => Some(service.get_merkle_proofs_at_version(&[], encoding, version).map(|_| version)?),
+        None => published.as_ref().map(|published| published.head.version),
+    };
+    let head = ndjson_line(&CheckStreamHead {
+        leaf_version: encoding.version(),
+        tree_version,
+        hash_algorithm: query.hash_algorithm,
+        tree_hasher: service.hash_store.hasher().name(),
+    });
+
+    let state = CheckStream {
+        decoder: Some(HashStreamDecoder::new(query.format, query.hash_algorithm)),
+        body: body.into_data_stream(),
+        hashes: Vec::with_capacity(PROOF_STREAM_BATCH_SIZE),
+        encoding,
+        tree_version: query.tree_version,
+        published,
+        checked_hashes: 0,
+        error: None,
+        service,
+    };
+    let lines = futures_util::stream::once(async { head })
+        .chain(futures_util::stream::unfold(state, |mut state| async move {
+            state.next_lines().await.map(|lines| (lines, state))
+        }))
+        .map(Ok::<_, Infallible>);
+    Ok(([(header::CONTENT_TYPE, "application/x-ndjson")], Body::from_stream(lines)).into_response())
+}
+
+#[utoipa::path(
+    get, path = "/hash/{hash}", tag = "check",
+    params(("hash" = String, Path, description = "The digest, url-safe base64 encoded"), CheckQuery),
+    responses(
+        (status = 200, body = CheckHashResponse),
+        (status = 400, body = ErrorResponse),
+        (status = 404, description = "The requested tree version is no longer kept", body = ErrorResponse),
+        (status = 409, description = "The hash is only stored with another leaf version", body = ErrorResponse),
+        (status = 503, description = "A worker didn't answer, retry after the Retry-After header", body = ErrorResponse),
+    ),
+)]
+async fn get_hash(
+    State(service): State<Arc<TimestampingService<INDEX_SIZE, PREFIX_SIZE>>>,
+    ApiPath(encoded): ApiPath<String>,
+    ApiQuery(query): ApiQuery<CheckQuery>,
+) -> Result<Json<CheckHashResponse>, ApiError> {
+    let hash = base64::engine::general_purpose::URL_SAFE_NO_PAD
+        .decode(encoded.trim_end_matches('='))
+        .ok()
+        .and_then(|bytes| query.hash_algorithm.normalize(&bytes).ok())
+        .ok_or_else(|| ApiError::new(ErrorCode::InvalidHash, MSG_INVALID_HASH_ENCODING))?;
+    check_hash(&service, &hash, None, &query).map(Json)
+}
+
+#[utoipa::path(
+    post, path = "/update-tree", tag = "tree",
+    responses(
+        (status = 200, body = UpdateTreeResponse),
+        (status = 403, description = "Read-only replica", body = ErrorResponse),
+        (status = 503, description = "A worker didn't answer, retry after the Retry-After header", body = ErrorResponse),
+    ),
+)]
+async fn update_tree(
+    State(service): State<Arc<TimestampingService<INDEX_SIZE, PREFIX_SIZE>>>,
+) -> Result<Json<UpdateTreeResponse>, ApiError> {
+    let hash_count = service.hash_store.len();
+    publish(&service, |service| service.update_merkle_tree()).await?;
+    let tree_size = service.get_merkle_tree_size();
+
+    Ok(Json(UpdateTreeResponse {
+        message: format!("Merkle tree updated with {} hashes", hash_count),
+        tree_size,
+        hash_count,
+    }))
+}
+
+// Run a tree update off the async workers: building the tree blocks, and so does asking an NTP or Roughtime server for its timestamp
+async fn publish<T: Send + Sync + 'static, R: Send + 'static>(service: &Arc<T>, update: impl FnOnce(&T) -> R + Send + 'static) -> R {
+    let service = Arc::clone(service);
+    tokio::task::spawn_blocking(move || update(&service)).await.unwrap()
+}
+
+// The ETag is weak: it changes with the tree, the stored hashes and audits, but not with the memory, queue and latency figures
+#[utoipa::path(
+    get, path = "/stats", tag = "stats",
+    responses((status = 200, body = GetStatsResponse), (status = 304, description = "Unchanged since the request's ETag")),
+)]
+async fn get_stats(
+    State(service): State<Arc<TimestampingService<INDEX_SIZE, PREFIX_SIZE>>>,
+    headers: HeaderMap,
+) -> Response {
+    let store_stats = service.hash_store.stats();
+    // Size, root and update time of the same tree
+    let published = service.published_tree();
+    let memory = service.memory_usage();
+    let limits = service.hash_store.limits();
+    let stats = GetStatsResponse {
+        count: store_stats.hashes,
+        slots: store_stats.occupied_slots,
+        total_slots: store_stats.bucket_slots,
+        total_adds: store_stats.adds,
+        duplicate_adds: store_stats.duplicates,
+        worker_hashes: store_stats.worker_hashes,
+        merkle_tree_size: published.as_ref().map_or(0, |published| published.head.tree_size),
+        merkle_tree_root: published.as_ref().and_then(|published| published.head.root).map(|root| root.to_bytes()),
+        last_tree_update: published.as_ref().map(|published| published.updated_at),
+        last_audit: *service.last_audit.read().unwrap(),
+        audit_divergences: *service.audit_divergences.read().unwrap(),
+        replication_divergences: *service.replication_divergences.read().unwrap(),
+        memory,
+        memory_total: memory.total(),
+        max_hashes: limits.max_hashes,
+        max_memory: limits.max_memory,
+        tombstones: store_stats.tombstones,
+        cold_hashes: store_stats.cold_hashes,
+        worker_queue_depths: store_stats.worker_queue_depths,
+        queue_capacity: service.hash_store.queue_capacity(),
+        worker_index_sizes: store_stats.worker_index_sizes,
+        worker_restarts: store_stats.worker_restarts,
+        resizes: store_stats.resizes,
+        bloom_filter: store_stats.bloom_filter,
+        proof_cache: service.proof_cache_stats(),
+        latencies: service.latencies.summary(),
+        chain_lengths: store_stats.chain_lengths,
+        chain_overflows: store_stats.chain_overflows,
+        spilled_hashes: store_stats.spilled_hashes,
+    };
+    let tag = format!(
+        "stats-{:08x}-{}-{}-{}-{}-{}",
+        SERVER_START.0,
+        published.as_ref().map_or("none".to_string(), |published| published.head.version.to_string()),
+        stats.total_adds,
+        stats.count,
+        stats.chain_overflows,
+        stats.last_audit.map_or(0, |audit| audit.audited_at),
+    );
+    with_validators(&headers, &tag, true, None, || Json(stats))
+}
+
+// Answer with 304 Not Modified while the request's If-None-Match or If-Modified-Since still matches, otherwise with
+// `response`, and let clients revalidate every time. Last-Modified is left out within the second of the last change,
+// which a later change in the same second couldn't be told apart from.
+fn with_validators<R: IntoResponse>(headers: &HeaderMap, tag: &str, weak: bool, last_modified: Option<u64>, response: impl FnOnce() -> R) -> Response {
+    // CBOR is another representation of the same response, see `negotiate_format`
+    let etag = format!("{}\"{}{}\"", if weak { "W/" } else { "" }, tag, if prefers_cbor(headers) { "-cbor" } else { "" });
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |duration| duration.as_secs());
+    let last_modified = last_modified.filter(|&time| time < now);
+    let header = |name| headers.get(name).and_then(|value: &HeaderValue| value.to_str().ok());
+    let mut response = if not_modified(header(header::IF_NONE_MATCH), header(header::IF_MODIFIED_SINCE), &etag, last_modified) {
+        StatusCode::NOT_MODIFIED.into_response()
+    } else {
+        response().into_response()
+    };
+    let response_headers = response.headers_mut();
+    response_headers.insert(header::ETAG, HeaderValue::from_str(&etag).unwrap());
+    if let Some(time) = last_modified {
+        response_headers.insert(header::LAST_MODIFIED, HeaderValue::from_str(&format_http_date(time)).unwrap());
+    }
+    response_headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+    // Tenants' stores are picked by their key
+    response_headers.append(header::VARY, HeaderValue::from(API_KEY_HEADER));
+    response
+}
+
+#[utoipa::path(get, path = "/version", tag = "info", responses((status = 200, body = VersionResponse)))]
+async fn get_version(
+    State(service): State<Arc<TimestampingService<INDEX_SIZE, PREFIX_SIZE>>>,
+) -> Json<VersionResponse> {
+    Json(version_response(&service))
+}
+
+fn version_response(service: &TimestampingService<INDEX_SIZE, PREFIX_SIZE>) -> VersionResponse {
+    let leaf_encodings = LeafEncoding::ALL
+        .iter()
+        .map(|encoding| LeafEncodingInfo {
+            version: encoding.version(),
+            description: encoding.description(),
+        })
+        .collect();
+    let hash_algorithms = HashAlgorithm::ALL
+        .iter()
+        .map(|algorithm| HashAlgorithmInfo {
+            name: algorithm.name(),
+            digest_len: algorithm.digest_len(),
+            description: algorithm.description(),
+        })
+        .collect();
+
+    VersionResponse {
+        software_version: env!("CARGO_PKG_VERSION"),
+        default_leaf_version: DEFAULT_LEAF_ENCODING.version(),
+        tree_hasher: service.hash_store.hasher().name(),
+        node_encoding: service.hash_store.hasher().node_description(),
+        leaf_encodings,
+        hash_algorithms,
+    }
+}
+
+// Role a request to `path` needs, `None` for the routes open to everyone: health checks for load balancers and the docs
+fn required_role(path: &str) -> Option<Role> {
+    match path {
+        // Cosign requests are authenticated by the signature of the peer
+        "/healthz" | "/version" | "/openapi.json" | "/docs" | "/cosign" => None,
+        "/update-tree" | "/tenants/update-tree" => Some(Role::Admin),
+        _ if path.starts_with("/docs/") => None,
+        _ if path.starts_with("/admin/") => Some(Role::Admin),
+        "/add" | "/add-batch" | "/add-stream" | "/add-data" | "/add-private" | "/challenge" => Some(Role::Submitter),
+        _ => Some(Role::Reader),
+    }
+}
+
+// Refuse requests without a key of the role the route needs, see `Access`. Tenants' stores are guarded the same way,
+// their X-Api-Key only selects the store.
+async fn authorize(State(access): State<Arc<Access>>, request: Request, next: Next) -> Response {
+    let path = request.uri().path();
+    // Without $TIMESTAMPING_API_KEYS the admin routes still need a key, or are closed if there is no admin key
+    let required = required_role(path).filter(|&role| access.enforced || path.starts_with("/admin/") || (role == Role::Admin && access.has_admin()));
+    if let Some(required) = required {
+        let role = request.headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .and_then(|given| access.role(given));
+        match role {
+            None => return ApiError::new(ErrorCode::Unauthorized, MSG_UNAUTHORIZED).into_response(),
+            Some(role) if role < required => return ApiError::new(ErrorCode::Forbidden, MSG_FORBIDDEN).into_response(),
+            Some(_) => {}
+        }
+    }
+    next.run(request).await
+}
+
+// Compared without stopping at the first difference, so the timing doesn't reveal the secret
+fn secrets_equal(given: &[u8], secret: &[u8]) -> bool {
+    given.len() == secret.len() && given.iter().zip(secret).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+// Send requests with an X-Api-Key header to the routes of that key's tenant, the others to the default store
+async fn route_to_tenant(State(tenants): State<Arc<Vec<(String, Router)>>>, request: Request, next: Next) -> Response {
+    let Some(key) = request.headers().get(API_KEY_HEADER) else {
+        return next.run(request).await;
+    };
+    match tenants.iter().find(|(tenant_key, _)| secrets_equal(key.as_bytes(), tenant_key.as_bytes())) {
+        Some((_, routes)) => routes.clone().oneshot(request).await.unwrap_or_else(|never| match never {}),
+        None => ApiError::new(ErrorCode::Unauthorized, MSG_INVALID_API_KEY).into_response(),
+    }
+}
+
+// Refuse requests of clients that used up their allowance, telling them when to retry
+async fn limit_rate(State(limiter): State<Arc<RateLimiter>>, request: Request, next: Next) -> Response {
+    let Some(client) = limiter.client_address(&request) else {
+        return next.run(request).await;
+    };
+    match limiter.acquire(client) {
+        Ok(()) => next.run(request).await,
+        Err(retry_after) => {
+            let mut response = ApiError::new(ErrorCode::RateLimited, MSG_RATE_LIMITED).into_response();
+            response.headers_mut().insert(header::RETRY_AFTER, HeaderValue::from(retry_after.as_secs_f64().ceil() as u64));
+            response
+        }
+    }
+}
+
+// Re-encode JSON responses as CBOR for clients that prefer it in their Accept header. Hashes and proofs, sent
+// as arrays of bytes, then take one or two bytes per byte instead of up to four. Responses of unknown size or
+// larger than MAX_CBOR_BODY_SIZE stay JSON, as they would have to be buffered.
+async fn negotiate_format(request: Request, next: Next) -> Response {
+    let cbor = prefers_cbor(request.headers());
+    let mut response = next.run(request).await;
+    response.headers_mut().append(header::VARY, HeaderValue::from_static("accept"));
+    let json = response.headers().get(header::CONTENT_TYPE).is_some_and(|content_type| content_type.as_bytes().starts_with(b"application/json"));
+    let bounded = response.body().size_hint().upper().is_some_and(|size| size <= MAX_CBOR_BODY_SIZE as u64);
+    if !cbor || !json || !bounded {
+        return response;
+    }
+    let (mut parts, body) = response.into_parts();
+    let Ok(body) = axum::body::to_bytes(body, MAX_CBOR_BODY_SIZE).await else {
+        return ApiError::new(ErrorCode::Internal, MSG_STREAM_READ_FAILED).into_response();
+    };
+    let Ok(value) = serde_json::from_slice::<serde_json::Value>(&body) else {
+        return Response::from_parts(parts, Body::from(body));
+    };
+    let mut encoded = Vec::with_capacity(body.len() / 2);
+    ciborium::into_writer(&value, &mut encoded).expect("JSON values can be written as CBOR");
+    parts.headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(CBOR_CONTENT_TYPE));
+    parts.headers.remove(header::CONTENT_LENGTH);
+    Response::from_parts(parts, Body::from(encoded))
+}
+
+// Whether the Accept header ranks CBOR above JSON, by quality and then order
+fn prefers_cbor(headers: &HeaderMap) -> bool {
+    let mut preferred: Option<(f32, bool)> = None;
+    for range in headers.get_all(header::ACCEPT).iter().filter_map(|value| value.to_str().ok()).flat_map(|value| value.split(',')) {
+        let mut params = range.split(';').map(str::trim);
+        let cbor = match params.next() {
+            Some(CBOR_CONTENT_TYPE) => true,
+            Some("application/json" | "application/*" | "*/*") => false,
+            _ => continue,
+        };
+        let quality = params.find_map(|param| param.strip_prefix("q=")).and_then(|q| q.parse().ok()).unwrap_or(1.0);
+        if quality > 0.0 && preferred.is_none_or(|(best, _)| quality > best) {
+            preferred = Some((quality, cbor));
+        }
+    }
+    preferred.is_some_and(|(_, cbor)| cbor)
+}
+
+// Answer with a timeout if the response isn't ready in time. The handler is dropped, so an /add may have stored
+// some of its hashes, and bodies streamed after the response headers aren't limited.
+async fn limit_duration(State(timeout): State<Duration>, request: Request, next: Next) -> Response {
+    match tokio::time::timeout(timeout, next.run(request)).await {
+        Ok(response) => response,
+        Err(_) => {
+            log_request(format!("Response not ready within {} seconds", timeout.as_secs_f64()));
+            ApiError::new(ErrorCode::Timeout, MSG_TIMEOUT).into_response()
+        }
+    }
+}
+
+// Time a request until its response headers are ready, streamed bodies aren't included
+async fn record_latency(State(histogram): State<Arc<LatencyHistogram>>, request: Request, next: Next) -> Response {
+    let start = Instant::now();
+    let response = next.run(request).await;
+    histogram.record(start.elapsed());
+    response
+}
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+// Tag every request with an id, taken from its `X-Request-Id` header or generated. It's sent back
+// in the response and prefixes the lines logged while handling the request.
+async fn assign_request_id(request: Request, next: Next) -> Response {
+    let id = request.headers()
+        .get(&REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= MAX_REQUEST_ID_LENGTH && id.bytes().all(|byte| byte.is_ascii_graphic()))
+        .map(str::to_string)
+        .unwrap_or_else(|| rand::random::<[u8; 8]>().iter().map(|byte| format!("{:02x}", byte)).collect());
+    let value = HeaderValue::from_str(&id).unwrap();
+    let mut response = REQUEST_ID.scope(id, next.run(request)).await;
+    response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    response
+}
+
+// Log a line about the current request
+fn log_request(message: impl std::fmt::Display) {
+    match REQUEST_ID.try_with(Clone::clone) {
+        Ok(id) => eprintln!("[{}] {}", id, message),
+        Err(_) => eprintln!("{}", message),
+    }
+}
+
+// Token bucket of every client: each holds up to `rate` requests and refills by `rate` per second
+struct RateLimiter {
+    rate: f64,
+    trusted_proxies: Vec<IpAddr>,
+    // Requests left and when they were counted
+    clients: Mutex<HashMap<IpAddr, (f64, Instant)>>,
+}
+
+impl RateLimiter {
+    fn new(rate: f64, trusted_proxies: Vec<IpAddr>) -> Self {
+        Self { rate, trusted_proxies, clients: Mutex::new(HashMap::new()) }
+    }
+
+    // The peer, or for trusted proxies the last address in X-Forwarded-For that isn't one of them. Peers on
+    // a unix socket are a proxy on the same host and always trusted. `None` if neither gives an address.
+    fn client_address(&self, request: &Request) -> Option<IpAddr> {
+        let peer = request.extensions().get::<ConnectInfo<SocketAddr>>().map(|ConnectInfo(address)| address.ip());
+        if peer.is_some_and(|peer| !self.trusted_proxies.contains(&peer)) {
+            return peer;
+        }
+        let forwarded: Vec<&str> = request.headers().get_all(FORWARDED_FOR_HEADER).iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .collect();
+        for address in forwarded.into_iter().rev() {
+            match address.parse() {
+                Ok(address) if self.trusted_proxies.contains(&address) => continue,
+                Ok(address) => return Some(address),
+                // Everything before a malformed entry could be made up by the client
+                Err(_) => break,
+            }
+        }
+        peer
+    }
+
+    // Take one request from the client's bucket, or the time until one is available
+    fn acquire(&self, client: IpAddr) -> Result<(), Duration> {
+        let mut clients = self.clients.lock().unwrap();
+        let now = Instant::now();
+        let burst = self.rate.max(1.0);
+        if clients.len() >= MAX_RATE_LIMITED_CLIENTS && !clients.contains_key(&client) {
+            clients.retain(|_, (left, counted)| *left + now.duration_since(*counted).as_secs_f64() * self.rate < burst);
+            // Like the idempotency cache, requests beyond the capacity are handled without tracking them
+            if clients.len() >= MAX_RATE_LIMITED_CLIENTS {
+                return Ok(());
+            }
+        }
+        let (left, counted) = clients.entry(client).or_insert((burst, now));
+        *left = (*left + now.duration_since(*counted).as_secs_f64() * self.rate).min(burst);
+        *counted = now;
+        if *left < 1.0 {
+            return Err(Duration::from_secs_f64((1.0 - *left) / self.rate));
+        }
+        *left -= 1.0;
+        Ok(())
+    }
+}
+
+// Challenges issued by /challenge, each usable once within `CHALLENGE_LIFETIME`
+struct Challenges {
+    difficulty: u32,
+    issued: Mutex<HashMap<[u8; 32], Instant>>,
+}
+
+impl Challenges {
+    fn new(difficulty: u32) -> Self {
+        Self { difficulty, issued: Mutex::new(HashMap::new()) }
+    }
+
+    // A new random challenge, `None` while `MAX_CHALLENGES` valid ones are pending
+    fn issue(&self) -> Option<[u8; 32]> {
+        let mut issued = self.issued.lock().unwrap();
+        let now = Instant::now();
+        if issued.len() >= MAX_CHALLENGES {
+            issued.retain(|_, at| now.duration_since(*at) < CHALLENGE_LIFETIME);
+            if issued.len() >= MAX_CHALLENGES {
+                return None;
+            }
+        }
+        let challenge = rand::random();
+        issued.insert(challenge, now);
+        Some(challenge)
+    }
+
+    // Use up a challenge, false if it wasn't issued, was already used or expired
+    fn redeem(&self, challenge: &[u8]) -> bool {
+        let Ok(challenge) = <[u8; 32]>::try_from(challenge) else {
+            return false;
+        };
+        self.issued.lock().unwrap().remove(&challenge).is_some_and(|at| at.elapsed() < CHALLENGE_LIFETIME)
+    }
+}
+
+// Batches added by /add-batch, by their random id
+struct Jobs {
+    jobs: Mutex<HashMap<String, Arc<Mutex<Job>>>>,
+}
+
+struct Job {
+    progress: JobProgress,
+    statuses: Vec<AddStatus>,
+    finished: Option<Instant>,
+}
+
+impl Jobs {
+    fn new() -> Self {
+        Self { jobs: Mutex::new(HashMap::new()) }
+    }
+
+    // A new running job, `None` while `MAX_JOBS` are running or recently finished
+    fn create(&self, leaf_version: u8, hash_algorithm: HashAlgorithm) -> Option<Arc<Mutex<Job>>> {
+        let mut jobs = self.jobs.lock().unwrap();
+        if jobs.len() >= MAX_JOBS {
+            jobs.retain(|_, job| job.lock().unwrap().finished.is_none_or(|finished| finished.elapsed() < JOB_RETENTION));
+            if jobs.len() >= MAX_JOBS {
+                return None;
+            }
+        }
+        let id: String = rand::random::<[u8; 16]>().iter().map(|byte| format!("{:02x}", byte)).collect();
+        let job = Arc::new(Mutex::new(Job {
+            progress: JobProgress {
+                id: id.clone(),
+                state: JobState::Running,
+                total_hashes: 0,
+                processed_hashes: 0,
+                new_hashes: 0,
+                existing_hashes: 0,
+                duplicate_hashes: 0,
+                leaf_version,
+                hash_algorithm,
+                error: None,
+            },
+            statuses: Vec::new(),
+            finished: None,
+        }));
+        jobs.insert(id, Arc::clone(&job));
+        Some(job)
+    }
+
+    fn get(&self, id: &str) -> Option<Arc<Mutex<Job>>> {
+        let jobs = self.jobs.lock().unwrap();
+        jobs.get(id).filter(|job| job.lock().unwrap().finished.is_none_or(|finished| finished.elapsed() < JOB_RETENTION)).cloned()
+    }
+}
+
+impl Job {
+    fn record(&mut self, statuses: &[AddStatus]) {
+        for status in statuses {
+            match status {
+                AddStatus::New => self.progress.new_hashes += 1,
+                AddStatus::Existing => self.progress.existing_hashes += 1,
+                AddStatus::Duplicate => self.progress.duplicate_hashes += 1,
+            }
+        }
+        self.progress.processed_hashes += statuses.len();
+        self.statuses.extend_from_slice(statuses);
+    }
+
+    fn finish(&mut self, error: Option<String>) {
+        self.progress.state = if error.is_some() { JobState::Failed } else { JobState::Completed };
+        self.progress.error = error;
+        self.finished = Some(Instant::now());
+    }
+}
+
+// Responses to requests with an `Idempotency-Key`, replayed if the request is retried within the window
+struct IdempotencyCache {
+    window: Duration,
+    // Bodies are buffered to fingerprint them, larger ones are refused
+    max_body_size: usize,
+    entries: Mutex<HashMap<String, IdempotencyEntry>>,
+}
+
+struct IdempotencyEntry {
+    // Hash of the method, uri and body, a retry has to match it
+    fingerprint: Vec<u8>,
+    created: Instant,
+    // `None` while the first request is handled
+    response: Option<CachedResponse>,
+}
+
+#[derive(Clone)]
+struct CachedResponse {
+    status: StatusCode,
+    content_type: Option<HeaderValue>,
+    // Where /add-batch created its job
+    location: Option<HeaderValue>,
+    body: Bytes,
+}
+
+enum IdempotencyState<'a> {
+    New(InFlightKey<'a>),
+    Replay(CachedResponse),
+    InFlight,
+    Reused,
+    // No capacity left, the request is handled without caching
+    Uncached,
+}
+
+impl IdempotencyCache {
+    fn new(window: Duration) -> Self {
+        Self { window, max_body_size: MAX_IDEMPOTENT_BODY_SIZE, entries: Mutex::new(HashMap::new()) }
+    }
+
+    fn with_max_body_size(self, max_body_size: usize) -> Self {
+        Self { max_body_size, ..self }
+    }
+
+    fn begin(&self, key: &str, fingerprint: Vec<u8>) -> IdempotencyState<'_> {
+        let mut entries = self.entries.lock().unwrap();
+        let now = Instant::now();
+        if let Some(entry) = entries.get(key).filter(|entry| now.duration_since(entry.created) < self.window) {
+            return match &entry.response {
+                _ if entry.fingerprint != fingerprint => IdempotencyState::Reused,
+                Some(response) => IdempotencyState::Replay(response.clone()),
+                None => IdempotencyState::InFlight,
+            };
+        }
+        if entries.len() >= MAX_IDEMPOTENCY_KEYS {
+            entries.retain(|_, entry| now.duration_since(entry.created) < self.window);
+            if entries.len() >= MAX_IDEMPOTENCY_KEYS {
+                return IdempotencyState::Uncached;
+            }
+        }
+        entries.insert(key.to_string(), IdempotencyEntry { fingerprint, created: now, response: None });
+        IdempotencyState::New(InFlightKey { cache: self, key: key.to_string(), done: false })
+    }
+}
+
+// Key of a request being handled. Its entry is removed again if no response is stored, e.g. if the
+// client disconnected, so a retry runs the request again.
+struct InFlightKey<'a> {
+    cache: &'a IdempotencyCache,
+    key: String,
+    done: bool,
+}
+
+impl InFlightKey<'_> {
+    fn complete(mut self, response: CachedResponse) {
+        if let Some(entry) = self.cache.entries.lock().unwrap().get_mut(&self.key) {
+            entry.response = Some(response);
+        }
+        self.done = true;
+    }
+}
+
+impl Drop for InFlightKey<'_> {
+    fn drop(&mut self) {
+        if !self.done {
+            self.cache.entries.lock().unwrap().remove(&self.key);
+        }
+    }
+}
+
+fn idempotency_window() -> Duration {
+    match std::env::var("TIMESTAMPING_IDEMPOTENCY_WINDOW") {
+        Ok(value) => Duration::from_secs(value.parse().unwrap_or_else(|_| panic!("TIMESTAMPING_IDEMPOTENCY_WINDOW has to be a number of seconds, got {}", value))),
+        Err(_) => DEFAULT_IDEMPOTENCY_WINDOW,
+    }
+}
+
+fn backup_interval() -> Duration {
+    match std::env::var("TIMESTAMPING_BACKUP_INTERVAL") {
+        Ok(value) => Duration::from_secs(value.parse().unwrap_or_else(|_| panic!("TIMESTAMPING_BACKUP_INTERVAL has to be a number of seconds, got {}", value))),
+        Err(_) => DEFAULT_BACKUP_INTERVAL,
+    }
+}
+
+// Replay the stored response if a request with the same `Idempotency-Key` was handled before, so
+// retried batches don't count their hashes as existing. Server errors aren't stored, their retries run again.
+async fn idempotent(State(cache): State<Arc<IdempotencyCache>>, request: Request, next: Next) -> Response {
+    let Some(key) = request.headers().get(&IDEMPOTENCY_KEY_HEADER) else {
+        return next.run(request).await;
+    };
+    let Some(key) = key.to_str().ok()
+        .filter(|key| !key.is_empty() && key.len() <= MAX_IDEMPOTENCY_KEY_LENGTH && key.bytes().all(|byte| byte.is_ascii_graphic()))
+        .map(str::to_string)
+    else {
+        return ApiError::new(ErrorCode::InvalidRequest, MSG_INVALID_IDEMPOTENCY_KEY).into_response();
+    };
+    let (parts, body) = request.into_parts();
+    let Ok(body) = axum::body::to_bytes(body, cache.max_body_size).await else {
+        return ApiError::new(ErrorCode::BatchTooLarge, MSG_IDEMPOTENT_BODY_TOO_LARGE).into_response();
+    };
+    let mut hasher = Sha512::new();
+    hasher.update(parts.method.as_str());
+    hasher.update(parts.uri.to_string());
+    hasher.update(&body);
+    let fingerprint = hasher.finalize().to_vec();
+
+    let in_flight = match cache.begin(&key, fingerprint) {
+        IdempotencyState::New(in_flight) => Some(in_flight),
+        IdempotencyState::Uncached => None,
+        IdempotencyState::Replay(cached) => {
+            let mut response = (cached.status, cached.body).into_response();
+            if let Some(content_type) = cached.content_type {
+                response.headers_mut().insert(header::CONTENT_TYPE, content_type);
+            }
+            if let Some(location) = cached.location {
+                response.headers_mut().insert(header::LOCATION, location);
+            }
+            response.headers_mut().insert(IDEMPOTENT_REPLAYED_HEADER, HeaderValue::from_static("true"));
+            return response;
+        }
+        IdempotencyState::InFlight => return ApiError::new(ErrorCode::IdempotencyKeyInUse, MSG_IDEMPOTENCY_KEY_IN_USE).into_response(),
+        IdempotencyState::Reused => return ApiError::new(ErrorCode::IdempotencyKeyReused, MSG_IDEMPOTENCY_KEY_REUSED).into_response(),
+    };
+
+    let response = next.run(Request::from_parts(parts, Body::from(body))).await;
+    // The responses of /add and /add-batch are small JSON documents, others are passed on without being cached
+    let cacheable = !response.status().is_server_error() && response.body().size_hint().upper().is_some_and(|size| size <= MAX_IDEMPOTENT_RESPONSE_SIZE as u64);
+    let Some(in_flight) = in_flight.filter(|_| cacheable) else {
+        return response;
+    };
+    let (parts, body) = response.into_parts();
+    let Ok(body) = axum::body::to_bytes(body, MAX_IDEMPOTENT_RESPONSE_SIZE).await else {
+        return ApiError::new(ErrorCode::Internal, MSG_STREAM_READ_FAILED).into_response();
+    };
+    in_flight.complete(CachedResponse {
+        status: parts.status,
+        content_type: parts.headers.get(header::CONTENT_TYPE).cloned(),
+        location: parts.headers.get(header::LOCATION).cloned(),
+        body: body.clone(),
+    });
+    Response::from_parts(parts, Body::from(body))
+}
+
+fn check_reason(reason: &Option<String>) -> Result<(), ApiError> {
+    if reason.as_ref().is_some_and(|reason| reason.len() > MAX_METADATA_FIELD_LENGTH) {
+        return Err(ApiError::new(ErrorCode::InvalidRequest, MSG_INVALID_REASON));
+    }
+    Ok(())
+}
+
+#[utoipa::path(
+    post, path = "/admin/remove", tag = "admin", params(AdminRemoveQuery), security(("admin_token" = [])),
+    request_body(description = "The raw digest, followed by its 64 byte nonce for private hashes", content = Vec<u8>, content_type = "application/octet-stream"),
+    responses(
+        (status = 200, body = AdminResponse),
+        (status = 400, body = ErrorResponse),
+        (status = 401, body = ErrorResponse),
+        (status = 404, description = "Hash not stored", body = ErrorResponse),
+        (status = 500, description = "Admin log not writable", body = ErrorResponse),
+        (status = 503, description = "A worker didn't answer, retry after the Retry-After header", body = ErrorResponse),
+    ),
+)]
+async fn admin_remove(
     State(service): State<Arc<TimestampingService<INDEX_SIZE, PREFIX_SIZE>>>,
-) -> (StatusCode, Json<GetStatsResponse>) {
-    let stats = GetStatsResponse {
-        count: service.hash_store.len(),
-        slots: service.hash_store.occupied_slots(),
-        total_slots: 1 << INDEX_SIZE,
-        merkle_tree_size: service.get_merkle_tree_size(),
-        merkle_tree_root: service.get_merkle_tree_root_bytes(),
-        last_tree_update: service.get_last_update_timestamp(),
-    };
-    (StatusCode::OK, Json(stats))
+    ApiQuery(query): ApiQuery<AdminRemoveQuery>,
+    bytes: Bytes,
+) -> Result<Json<AdminResponse>, ApiError> {
+    let encoding = leaf_encoding(query.leaf_version)?;
+    check_reason(&query.reason)?;
+    let digest_len = query.hash_algorithm.digest_len();
+    let nonce = match bytes.len() {
+        len if len == digest_len => None,
+        len if len == digest_len + 64 => Some(Hash512::from_bytes(&bytes[digest_len..]).unwrap()),
+        _ => return Err(ApiError::new(ErrorCode::InvalidHash, MSG_INVALID_REMOVE)),
+    };
+    let hash = query.hash_algorithm.normalize(&bytes[..digest_len]).unwrap();
+
+    if !service.remove_hash(&hash, nonce.as_ref(), encoding, query.reason)? {
+        return Err(ApiError::new(ErrorCode::NotFound, MSG_HASH_NOT_FOUND));
+    }
+    Ok(Json(AdminResponse { message: MSG_HASH_REMOVED, removed: 1 }))
+}
+
+#[utoipa::path(
+    post, path = "/admin/clear", tag = "admin", params(AdminClearQuery), security(("admin_token" = [])),
+    responses(
+        (status = 200, body = AdminResponse),
+        (status = 400, body = ErrorResponse),
+        (status = 401, body = ErrorResponse),
+        (status = 500, description = "Admin log not writable", body = ErrorResponse),
+        (status = 503, description = "A worker didn't answer, retry after the Retry-After header", body = ErrorResponse),
+    ),
+)]
+async fn admin_clear(
+    State(service): State<Arc<TimestampingService<INDEX_SIZE, PREFIX_SIZE>>>,
+    ApiQuery(query): ApiQuery<AdminClearQuery>,
+) -> Result<Json<AdminResponse>, ApiError> {
+    check_reason(&query.reason)?;
+    let removed = service.remove_all_hashes(query.reason)?;
+    Ok(Json(AdminResponse { message: MSG_STORE_CLEARED, removed }))
+}
+
+// Move the stored hashes to a new store with a new salt, see `TimestampingService::resalt`.
+// The body has the submitted hashes, which the server can't recover from the salted ones it stores.
+#[utoipa::path(
+    post, path = "/admin/resalt", tag = "admin", params(AdminResaltQuery), security(("admin_token" = [])),
+    request_body(
+        description = "Concatenated raw digests, or one base64 digest per line with `format=base64`",
+        content((Vec<u8> = "application/octet-stream"), (String = "text/plain")),
+    ),
+    responses(
+        (status = 200, body = AdminResaltResponse),
+        (status = 400, description = "Invalid body, or hashes that aren't stored", body = ErrorResponse),
+        (status = 401, body = ErrorResponse),
+        (status = 403, description = "Read-only replica", body = ErrorResponse),
+        (status = 409, description = "No signed tree yet, or a re-salted store already exists", body = ErrorResponse),
+        (status = 500, description = "The new store or the admin log couldn't be written", body = ErrorResponse),
+        (status = 503, description = "A worker didn't answer, retry after the Retry-After header", body = ErrorResponse),
+    ),
+)]
+async fn admin_resalt(
+    State(service): State<Arc<TimestampingService<INDEX_SIZE, PREFIX_SIZE>>>,
+    ApiQuery(query): ApiQuery<AdminResaltQuery>,
+    body: Body,
+) -> Result<Json<AdminResaltResponse>, ApiError> {
+    let encoding = leaf_encoding(query.leaf_version)?;
+    check_reason(&query.reason)?;
+    let mut decoder = HashStreamDecoder::new(query.format, query.hash_algorithm);
+    let mut stream = body.into_data_stream();
+    let mut hashes = Vec::new();
+    let invalid_hash = |message| ApiError::new(ErrorCode::InvalidHash, message);
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.map_err(|_| ApiError::new(ErrorCode::StreamReadFailed, MSG_STREAM_READ_FAILED))?;
+        decoder.feed(&chunk, &mut hashes).map_err(invalid_hash)?;
+    }
+    decoder.finish(&mut hashes).map_err(invalid_hash)?;
+
+    let dir = Path::new(DATA_DIR).join(RESALT_DIR);
+    let migration = service.resalt(&hashes, encoding, &dir, query.reason)?;
+    Ok(Json(AdminResaltResponse { message: MSG_RESALTED, dir: dir.display().to_string(), migration }))
+}
+
+#[utoipa::path(
+    get, path = "/admin/log", tag = "admin", security(("admin_token" = [])),
+    responses((status = 200, body = AdminLogResponse), (status = 401, body = ErrorResponse)),
+)]
+async fn admin_log(
+    State(service): State<Arc<TimestampingService<INDEX_SIZE, PREFIX_SIZE>>>,
+) -> Json<AdminLogResponse> {
+    Json(AdminLogResponse { actions: service.admin_actions() })
+}
+
+// Every stored salted hash in the snapshot format of `snapshot.rs`, including those not in a published tree yet.
+// The store is sent in ranges of about EXPORT_PART_SIZE hashes that follow each other in leaf order, each sorted
+// on its own, so the export doesn't hold the whole store. Hashes added meanwhile are left out. A range that can't
+// be read ends the body early, which readers of the snapshot refuse as truncated.
+#[utoipa::path(
+    get, path = "/admin/export", tag = "admin", security(("admin_token" = [])),
+    responses(
+        (status = 200, description = "Snapshot of the store", body = Vec<u8>, content_type = "application/octet-stream"),
+        (status = 401, body = ErrorResponse),
+        (status = 503, description = "A worker didn't answer, retry after the Retry-After header", body = ErrorResponse),
+    ),
+)]
+async fn admin_export(
+    State(service): State<Arc<TimestampingService<INDEX_SIZE, PREFIX_SIZE>>>,
+) -> Result<impl IntoResponse, ApiError> {
+    let bound = service.hash_store.next_sequence();
+    let parts = service.hash_store.len().div_ceil(EXPORT_PART_SIZE).max(1);
+    let sizes = Arc::new(tokio::task::spawn_blocking({
+        let service = Arc::clone(&service);
+        move || service.hash_store.part_sizes(bound, parts)
+    }).await.unwrap()?);
+    let writer = SnapshotWriter::new(Vec::new(), service.hash_store.salt(), sizes.iter().sum::<usize>() as u64).unwrap();
+
+    let chunks = futures_util::stream::unfold((Some(writer), 0), move |(writer, part)| {
+        let (service, sizes) = (Arc::clone(&service), Arc::clone(&sizes));
+        async move {
+            let mut writer = writer?;
+            let chunk = tokio::task::spawn_blocking(move || {
+                if part == sizes.len() {
+                    return writer.finish().map(|(trailer, _)| (trailer, None));
+                }
+                let leaves = service.hash_store.sorted_part(bound, sizes.len(), part).map_err(std::io::Error::other)?;
+                if leaves.len() != sizes[part] {
+                    return Err(std::io::Error::other(format!("Range {} of the store changed during the export", part)));
+                }
+                writer.write(&leaves)?;
+                Ok((std::mem::take(writer.get_mut()), Some(writer)))
+            }).await.unwrap();
+            Some(match chunk {
+                Ok((bytes, writer)) => (Ok(Bytes::from(bytes)), (writer, part + 1)),
+                Err(e) => (Err(e), (None, part + 1)),
+            })
+        }
+    });
+    Ok(([(header::CONTENT_TYPE, "application/octet-stream")], Body::from_stream(chunks)))
+}
+
+// Add the hashes of a snapshot to the store and close it, returning the exit code of `--import`. The whole snapshot
+// is checked before the first hash is added, and the hashes are published with the next tree.
+fn import_snapshot(service: &TimestampingService<INDEX_SIZE, PREFIX_SIZE>, path: &Path) -> i32 {
+    let snapshot = std::fs::File::open(path)
+        .map_err(SnapshotError::from)
+        .and_then(|file| read_snapshot(std::io::BufReader::new(file), Some(service.hash_store.salt())));
+    let (header, leaves) = match snapshot {
+        Ok(snapshot) => snapshot,
+        Err(e) => {
+            eprintln!("Import of {} failed: {}", path.display(), e);
+            return 2;
+        }
+    };
+    let imported = service.hash_store.import_salted(leaves);
+    service.close();
+    match imported {
+        Ok(imported) => {
+            println!("Imported {} new of the {} hashes in {}", imported, header.count, path.display());
+            0
+        }
+        Err(e) => {
+            eprintln!("Import of {} failed: {}", path.display(), e);
+            2
+        }
+    }
+}
+
+// Print the result of `TimestampingService::verify_store` and close the store, returning the exit code of `--self-check`
+fn self_check(service: &TimestampingService<INDEX_SIZE, PREFIX_SIZE>) -> i32 {
+    let check = match service.verify_store() {
+        Ok(check) => check,
+        Err(e) => {
+            eprintln!("Self-check failed: {}", e);
+            return 2;
+        }
+    };
+    service.close();
+    println!("{} hashes stored", check.hashes);
+    match (check.tree_version, check.root_matches) {
+        (Some(version), Some(true)) => println!("Tree version {} rebuilt with the published root", version),
+        (Some(_), _) => {}
+        _ => println!("No published tree rebuilt"),
+    }
+    for discrepancy in &check.discrepancies {
+        eprintln!("{}", discrepancy);
+    }
+    if check.is_consistent() {
+        println!("Store is consistent");
+        0
+    } else {
+        eprintln!("Store is inconsistent: {} problems", check.discrepancies.len());
+        1
+    }
+}
+
+// Check the invariants of the store, see `TimestampingService::verify_store`. Problems are reported in the body, not as an error.
+#[utoipa::path(
+    post, path = "/admin/verify-store", tag = "admin", security(("admin_token" = [])),
+    responses(
+        (status = 200, body = StoreCheck),
+        (status = 401, body = ErrorResponse),
+        (status = 503, description = "A worker didn't answer, retry after the Retry-After header", body = ErrorResponse),
+    ),
+)]
+async fn admin_verify_store(
+    State(service): State<Arc<TimestampingService<INDEX_SIZE, PREFIX_SIZE>>>,
+) -> Result<Json<StoreCheck>, ApiError> {
+    let check = tokio::task::spawn_blocking(move || service.verify_store()).await.unwrap()?;
+    Ok(Json(check))
+}
+
+#[utoipa::path(
+    get, path = "/admin/webhooks", tag = "admin", security(("admin_token" = [])),
+    responses((status = 200, body = AdminWebhooksResponse), (status = 401, body = ErrorResponse)),
+)]
+async fn admin_webhooks(State(webhooks): State<Arc<Webhooks>>) -> Json<AdminWebhooksResponse> {
+    Json(AdminWebhooksResponse { message: MSG_WEBHOOKS, configured: webhooks.configured().to_vec(), registered: webhooks.registered() })
+}
+
+// Register a url that a `WebhookEvent` is POSTed to after every published tree
+#[utoipa::path(
+    post, path = "/admin/webhooks", tag = "admin", params(WebhookQuery), security(("admin_token" = [])),
+    responses(
+        (status = 200, body = AdminWebhooksResponse),
+        (status = 400, body = ErrorResponse),
+        (status = 401, body = ErrorResponse),
+        (status = 500, description = "Registered webhooks not writable", body = ErrorResponse),
+    ),
+)]
+async fn admin_add_webhook(
+    State(webhooks): State<Arc<Webhooks>>,
+    ApiQuery(query): ApiQuery<WebhookQuery>,
+) -> Result<Json<AdminWebhooksResponse>, ApiError> {
+    let message = if webhooks.register(&query.url)? { MSG_WEBHOOK_ADDED } else { MSG_WEBHOOK_EXISTS };
+    Ok(Json(AdminWebhooksResponse { message, configured: webhooks.configured().to_vec(), registered: webhooks.registered() }))
+}
+
+#[utoipa::path(
+    delete, path = "/admin/webhooks", tag = "admin", params(WebhookQuery), security(("admin_token" = [])),
+    responses(
+        (status = 200, body = AdminWebhooksResponse),
+        (status = 400, description = "The webhook is configured, not registered", body = ErrorResponse),
+        (status = 401, body = ErrorResponse),
+        (status = 404, body = ErrorResponse),
+        (status = 500, description = "Registered webhooks not writable", body = ErrorResponse),
+    ),
+)]
+async fn admin_remove_webhook(
+    State(webhooks): State<Arc<Webhooks>>,
+    ApiQuery(query): ApiQuery<WebhookQuery>,
+) -> Result<Json<AdminWebhooksResponse>, ApiError> {
+    if !webhooks.unregister(&query.url)? {
+        return Err(ApiError::new(ErrorCode::NotFound, MSG_WEBHOOK_NOT_FOUND));
+    }
+    Ok(Json(AdminWebhooksResponse { message: MSG_WEBHOOK_REMOVED, configured: webhooks.configured().to_vec(), registered: webhooks.registered() }))
+}
+
+async fn read_only() -> ApiError {
+    ApiError::new(ErrorCode::ReadOnly, MSG_READ_ONLY)
+}
+
+// Replicas rebuild trees from the leaf logs, which aren't in the order of a mountain range
+fn check_replicable(service: &TimestampingService<INDEX_SIZE, PREFIX_SIZE>) -> Result<(), ApiError> {
+    if service.uses_mountain_range() {
+        return Err(ApiError::new(ErrorCode::ReplicationUnsupported, MSG_REPLICATION_UNSUPPORTED));
+    }
+    Ok(())
+}
+
+#[utoipa::path(
+    get, path = "/replication/head", tag = "replication",
+    responses(
+        (status = 200, body = ReplicationHeadResponse),
+        (status = 404, description = "No tree published yet", body = ErrorResponse),
+        (status = 501, description = "Trees are published from a mountain range", body = ErrorResponse),
+    ),
+)]
+async fn get_replication_head(
+    State(service): State<Arc<TimestampingService<INDEX_SIZE, PREFIX_SIZE>>>,
+) -> Result<Json<ReplicationHeadResponse>, ApiError> {
+    check_replicable(&service)?;
+    let (head, leaf_log_lengths) = service.get_published_head()
+        .ok_or_else(|| ApiError::new(ErrorCode::NotFound, MSG_NO_TREE))?;
+    Ok(Json(ReplicationHeadResponse {
+        version: head.version,
+        root: head.root.map(|root| root.to_bytes()),
+        tree_size: head.tree_size,
+        leaf_count: head.leaf_count,
+        timestamp: head.timestamp,
+        leaf_log_lengths,
+    }))
+}
+
+#[utoipa::path(
+    get, path = "/replication/leaves/{worker}", tag = "replication",
+    params(("worker" = usize, Path, description = "Index of the worker thread"), ReplicationLeavesQuery),
+    responses(
+        (status = 200, description = "Concatenated 64 byte salted hashes", body = Vec<u8>, content_type = "application/octet-stream"),
+        (status = 404, description = "No tree published yet, or no such worker", body = ErrorResponse),
+        (status = 500, body = ErrorResponse),
+        (status = 501, description = "Trees are published from a mountain range", body = ErrorResponse),
+    ),
+)]
+async fn get_replication_leaves(
+    State(service): State<Arc<TimestampingService<INDEX_SIZE, PREFIX_SIZE>>>,
+    ApiPath(worker): ApiPath<usize>,
+    ApiQuery(query): ApiQuery<ReplicationLeavesQuery>,
+) -> Result<Vec<u8>, ApiError> {
+    check_replicable(&service)?;
+    let limit = query.limit.unwrap_or(MAX_REPLICATION_LEAVES).min(MAX_REPLICATION_LEAVES);
+    let leaves = service.get_published_leaves(worker, query.offset.unwrap_or(0), limit)
+        .ok_or_else(|| ApiError::new(ErrorCode::NotFound, MSG_WORKER_NOT_FOUND))?
+        .map_err(|_| ApiError::new(ErrorCode::Internal, MSG_READ_LEAVES_FAILED))?;
+    Ok(leaves.iter().flat_map(|leaf| leaf.to_bytes()).collect())
+}
+
+// The salt is part of every proof anyway, publishing it lets verifiers recompute leaves up front
+// Nothing in it changes while the server runs, so it was last modified when it started
+#[utoipa::path(
+    get, path = "/info", tag = "info",
+    responses((status = 200, body = InfoResponse), (status = 304, description = "Unchanged since the request's ETag or date")),
+)]
+async fn get_info(
+    State(service): State<Arc<TimestampingService<INDEX_SIZE, PREFIX_SIZE>>>,
+    headers: HeaderMap,
+) -> Response {
+    let (boot, started_at) = *SERVER_START;
+    with_validators(&headers, &format!("info-{:08x}", boot), false, Some(started_at), || Json(InfoResponse {
+        version: version_response(&service),
+        salt: service.hash_store.salt().to_bytes(),
+        index_size: service.hash_store.initial_index_size(),
+        prefix_size: PREFIX_SIZE,
+        threads: service.hash_store.num_threads(),
+        partitioning: service.hash_store.partitioning(),
+        proof_format: PROOF_FORMAT,
+        accumulator: if service.uses_mountain_range() { "mmr" } else { "tree" },
+        leaf_order: LEAF_ORDER,
+        public_key: service.verifying_key().map(|key| key.to_bytes().to_vec()),
+        retention: service.retention().clone(),
+    }))
+}
+
+#[utoipa::path(get, path = "/test-vectors", tag = "info", responses((status = 200, body = TestVectors)))]
+async fn get_test_vectors() -> Json<TestVectors> {
+    Json(test_vectors::generate())
+}
+
+#[utoipa::path(get, path = "/spec", tag = "info", responses((status = 200, body = ProofSpec)))]
+async fn get_spec() -> Json<ProofSpec> {
+    Json(spec::generate())
+}
+
+// Answers 503 while a worker doesn't respond. Restarted workers are reported as alerts, as the hashes of
+// commands they were handling when they panicked may not have been stored.
+#[utoipa::path(
+    get, path = "/healthz", tag = "stats",
+    responses((status = 200, body = HealthResponse), (status = 503, description = "A worker didn't respond", body = HealthResponse)),
+)]
+async fn get_healthz(
+    State(service): State<Arc<TimestampingService<INDEX_SIZE, PREFIX_SIZE>>>,
+) -> (StatusCode, Json<HealthResponse>) {
+    let store = Arc::clone(&service.hash_store);
+    let responsive_workers = tokio::task::spawn_blocking(move || store.worker_health(WORKER_HEALTH_TIMEOUT)).await.unwrap();
+    let worker_restarts = service.hash_store.stats().worker_restarts;
+    let mut alerts = Vec::new();
+    for (worker, responsive) in responsive_workers.iter().enumerate() {
+        if !responsive {
+            alerts.push(format!("Worker {} didn't respond within {:?}", worker, WORKER_HEALTH_TIMEOUT));
+        }
+    }
+    for (worker, &restarts) in worker_restarts.iter().enumerate() {
+        if restarts > 0 {
+            alerts.push(format!("Worker {} panicked and was restarted {} times", worker, restarts));
+        }
+    }
+    let healthy = responsive_workers.iter().all(|&responsive| responsive);
+    let status = if healthy { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    (status, Json(HealthResponse { healthy, responsive_workers, worker_restarts, alerts }))
+}
+
+#[utoipa::path(
+    get, path = "/stats/epochs", tag = "stats", params(EpochsQuery),
+    responses((status = 200, body = GetEpochsResponse), (status = 400, body = ErrorResponse)),
+)]
+async fn get_epochs(
+    State(service): State<Arc<TimestampingService<INDEX_SIZE, PREFIX_SIZE>>>,
+    ApiQuery(query): ApiQuery<EpochsQuery>,
+) -> Json<GetEpochsResponse> {
+    let offset = query.offset.unwrap_or(0);
+    let limit = query.limit.unwrap_or(DEFAULT_EPOCHS_LIMIT).min(MAX_EPOCHS_LIMIT);
+    let epochs = service.epochs.read().unwrap();
+
+    Json(GetEpochsResponse {
+        total: epochs.len(),
+        offset,
+        limit,
+        epochs: epochs.page(offset, limit).to_vec(),
+    })
+}
+
+#[utoipa::path(
+    get, path = "/hashes", tag = "stats", params(HashesQuery),
+    responses(
+        (status = 200, body = GetHashesResponse),
+        (status = 400, body = ErrorResponse),
+        (status = 503, description = "A worker didn't answer, retry after the Retry-After header", body = ErrorResponse),
+    ),
+)]
+async fn get_hashes(
+    State(service): State<Arc<TimestampingService<INDEX_SIZE, PREFIX_SIZE>>>,
+    ApiQuery(query): ApiQuery<HashesQuery>,
+) -> Result<Json<GetHashesResponse>, ApiError> {
+    let offset = query.offset.unwrap_or(0);
+    let limit = query.limit.unwrap_or(DEFAULT_HASHES_LIMIT).min(MAX_HASHES_LIMIT);
+    let hashes = service.hash_store.iter_range(offset, limit, query.since)?
+        .into_iter()
+        .map(|(salted_hash, metadata)| StoredHashEntry { salted_hash: salted_hash.to_bytes(), metadata })
+        .collect();
+
+    Ok(Json(GetHashesResponse {
+        total: service.hash_store.len(),
+        offset,
+        limit,
+        since: query.since,
+        hashes,
+    }))
+}
+
+#[utoipa::path(
+    get, path = "/feed", tag = "stats", params(FeedQuery),
+    responses(
+        (status = 200, content_type = "text/event-stream",
+            description = "A `hash` event with a `FeedEvent` for every stored hash. Clients that fall behind get a `lagged` event with the number of hashes they missed."),
+        (status = 400, body = ErrorResponse),
+    ),
+)]
+async fn get_feed(
+    State(service): State<Arc<TimestampingService<INDEX_SIZE, PREFIX_SIZE>>>,
+    ApiQuery(query): ApiQuery<FeedQuery>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, ApiError> {
+    let prefix = match &query.prefix {
+        Some(prefix) => decode_hex(prefix.as_bytes()).filter(|prefix| prefix.len() <= 64)
+            .ok_or_else(|| ApiError::new(ErrorCode::InvalidRequest, MSG_INVALID_PREFIX))?,
+        None => Vec::new(),
+    };
+    let feed = service.hash_store.subscribe_feed();
+    let events = futures_util::stream::unfold((feed, prefix, query.namespace), |(mut feed, prefix, namespace)| async move {
+        loop {
+            let event = match feed.recv().await {
+                Ok(accepted) => {
+                    let in_namespace = namespace.is_none() || accepted.metadata.as_ref().and_then(|metadata| metadata.namespace.as_ref()) == namespace.as_ref();
+                    if !in_namespace || !accepted.salted_hash.to_bytes().starts_with(&prefix) {
+                        continue;
+                    }
+                    Event::default().event("hash").json_data(FeedEvent::from(accepted)).unwrap()
+                }
+                // A mirror has to catch up with /hashes after missing some
+                Err(RecvError::Lagged(missed)) => Event::default().event("lagged").data(missed.to_string()),
+                Err(RecvError::Closed) => return None,
+            };
+            return Some((Ok(event), (feed, prefix, namespace)));
+        }
+    });
+    // Ended on shutdown, which otherwise waits for every subscriber to disconnect
+    Ok(Sse::new(events.take_until(shutdown_signal())).keep_alive(KeepAlive::default()))
+}
+
+// Validated by the revision of the epoch log, which changes when a tree is published and when a root is anchored or cosigned
+#[utoipa::path(
+    get, path = "/roots", tag = "tree", params(EpochsQuery),
+    responses(
+        (status = 200, body = GetRootsResponse), (status = 304, description = "Unchanged since the request's ETag or date"),
+        (status = 400, body = ErrorResponse),
+    ),
+)]
+async fn get_roots(
+    State(service): State<Arc<TimestampingService<INDEX_SIZE, PREFIX_SIZE>>>,
+    ApiQuery(query): ApiQuery<EpochsQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let offset = query.offset.unwrap_or(0);
+    let limit = query.limit.unwrap_or(DEFAULT_EPOCHS_LIMIT).min(MAX_EPOCHS_LIMIT);
+    let epochs = service.epochs.read().unwrap();
+    let (boot, started_at) = *SERVER_START;
+    let tag = format!("roots-{:08x}-{}-{}", boot, epochs.latest().map_or(0, |summary| summary.epoch + 1), epochs.revision());
+
+    with_validators(&headers, &tag, false, Some(epochs.modified_at().unwrap_or(started_at)), || Json(GetRootsResponse {
+        total: epochs.len(),
+        offset,
+        limit,
+        roots: epochs.page(offset, limit).iter().map(RootEntry::from).collect(),
+    }))
+}
+
+#[utoipa::path(
+    get, path = "/roots/{version}", tag = "tree",
+    params(("version" = u64, Path, description = "Tree version")),
+    responses((status = 200, body = GetRootResponse), (status = 404, body = ErrorResponse)),
+)]
+async fn get_root(
+    State(service): State<Arc<TimestampingService<INDEX_SIZE, PREFIX_SIZE>>>,
+    ApiPath(version): ApiPath<u64>,
+) -> Result<Json<GetRootResponse>, ApiError> {
+    let epochs = service.epochs.read().unwrap();
+    let summary = epochs.get(version).ok_or_else(|| ApiError::new(ErrorCode::NotFound, MSG_ROOT_NOT_FOUND))?;
+    Ok(Json(GetRootResponse { message: MSG_ROOT_FOUND, root: RootEntry::from(summary) }))
+}
+
+// Every published tree head in order, each entry committing to the one before it, see `root_log.rs`
+#[utoipa::path(
+    get, path = "/audit-log", tag = "tree", params(EpochsQuery),
+    responses((status = 200, body = GetAuditLogResponse), (status = 400, body = ErrorResponse)),
+)]
+async fn get_audit_log(
+    State(service): State<Arc<TimestampingService<INDEX_SIZE, PREFIX_SIZE>>>,
+    ApiQuery(query): ApiQuery<EpochsQuery>,
+) -> Json<GetAuditLogResponse> {
+    let offset = query.offset.unwrap_or(0);
+    let limit = query.limit.unwrap_or(DEFAULT_EPOCHS_LIMIT).min(MAX_EPOCHS_LIMIT);
+    let log = service.root_log.read().unwrap();
+
+    Json(GetAuditLogResponse {
+        total: log.len(),
+        offset,
+        limit,
+        entries: log.page(offset, limit).to_vec(),
+    })
+}
+
+#[utoipa::path(
+    get, path = "/tenants", tag = "tenants",
+    responses((status = 200, body = GetTenantsResponse)),
+)]
+async fn get_tenants(
+    State(tenants): State<Arc<Tenants<INDEX_SIZE, PREFIX_SIZE>>>,
+) -> Json<GetTenantsResponse> {
+    Json(match tenants.tree() {
+        Some(tree) => GetTenantsResponse::from(&*tree),
+        None => GetTenantsResponse {
+            version: None,
+            merkle_tree_root: None,
+            tenants: tenants.services().map(|(name, _)| TenantEntry::from(&(name.clone(), None))).collect(),
+        },
+    })
+}
+
+#[utoipa::path(
+    get, path = "/tenants/{name}/proof", tag = "tenants",
+    params(("name" = String, Path, description = "Tenant name")),
+    responses((status = 200, body = TenantProofResponse), (status = 404, description = "No tenant tree yet or unknown tenant", body = ErrorResponse)),
+)]
+async fn get_tenant_proof(
+    State(tenants): State<Arc<Tenants<INDEX_SIZE, PREFIX_SIZE>>>,
+    ApiPath(name): ApiPath<String>,
+) -> Result<Json<TenantProofResponse>, ApiError> {
+    let tree = tenants.tree().ok_or_else(|| ApiError::new(ErrorCode::NotFound, MSG_NO_TREE))?;
+    let (index, proof) = tree.position(&name).zip(tree.proof(&name))
+        .ok_or_else(|| ApiError::new(ErrorCode::NotFound, MSG_TENANT_NOT_FOUND))?;
+    Ok(Json(TenantProofResponse {
+        version: tree.version,
+        merkle_tree_root: tree.root().map(|root| root.to_bytes()),
+        tenant: TenantEntry::from(&tree.tenants[index]),
+        proof: proof.iter().map(|(left, right)| (left.to_bytes(), right.to_bytes())).collect(),
+    }))
+}
+
+#[utoipa::path(
+    post, path = "/tenants/update-tree", tag = "tenants",
+    responses(
+        (status = 200, description = "Trees of all tenants and the tree over their roots updated", body = GetTenantsResponse),
+        (status = 503, description = "A worker didn't answer, retry after the Retry-After header", body = ErrorResponse),
+    ),
+)]
+async fn update_tenant_trees(
+    State(tenants): State<Arc<Tenants<INDEX_SIZE, PREFIX_SIZE>>>,
+) -> Result<Json<GetTenantsResponse>, ApiError> {
+    let tree = publish(&tenants, |tenants| tenants.update_tree()).await?;
+    Ok(Json(GetTenantsResponse::from(&*tree)))
+}
+
+#[utoipa::path(
+    post, path = "/cosign", tag = "peering",
+    request_body(content = SignedTreeHead, content_type = "application/json"),
+    responses(
+        (status = 200, description = "Cosignature of the tree head by this server", body = Cosignature),
+        (status = 400, body = ErrorResponse),
+        (status = 403, description = "Not signed by a peer of this server", body = ErrorResponse),
+        (status = 409, description = "Timestamp too far from this server's clock, or the peer signed a different root or a newer version before", body = ErrorResponse),
+    ),
+)]
+async fn cosign(
+    State(witness): State<Arc<Witness>>,
+    body: Bytes,
+) -> Result<Json<Cosignature>, ApiError> {
+    let head: SignedTreeHead = serde_json::from_slice(&body)
+        .map_err(|e| ApiError::new(ErrorCode::InvalidRequest, MSG_INVALID_TREE_HEAD).with_details(e.to_string().into()))?;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |duration| duration.as_secs());
+    Ok(Json(witness.cosign(&head, now)?))
+}
+
+#[utoipa::path(
+    get, path = "/ws", tag = "tree",
+    responses((status = 101, description = "WebSocket sending a `TreeUpdateEvent` for every new tree")),
+)]
+async fn ws(
+    State(service): State<Arc<TimestampingService<INDEX_SIZE, PREFIX_SIZE>>>,
+    upgrade: WebSocketUpgrade,
+) -> Response {
+    // Subscribe before upgrading so no update between the request and the handshake is missed
+    let updates = service.subscribe_tree_updates();
+    upgrade.on_upgrade(move |socket| stream_tree_updates(socket, service, updates))
+}
+
+async fn stream_tree_updates(
+    mut socket: WebSocket,
+    service: Arc<TimestampingService<INDEX_SIZE, PREFIX_SIZE>>,
+    mut updates: broadcast::Receiver<TreeHead>,
+) {
+    loop {
+        tokio::select! {
+            update = updates.recv() => {
+                let head = match update {
+                    Ok(head) => head,
+                    // Slow client missed some updates, continue with the most recent ones
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => break,
+                };
+                let event = serde_json::to_string(&TreeUpdateEvent::new(head, &service)).unwrap();
+                if socket.send(Message::Text(event.into())).await.is_err() {
+                    break;
+                }
+            }
+            incoming = socket.recv() => {
+                // Incoming messages are ignored, stop once the client disconnects
+                match incoming {
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    Some(Ok(_)) => {}
+                }
+            }
+        }
+    }
+}
+
+#[cfg(not(feature = "client"))]
+async fn run_coordinator(_shards: Vec<String>) {
+    unreachable!("TIMESTAMPING_SHARDS is refused by check_client_settings");
+}
+
+#[cfg(feature = "client")]
+async fn run_coordinator(shards: Vec<String>) {
+    let coordinator = Arc::new(coordinator::ClusterCoordinator::connect(&shards).await.unwrap());
+
+    let app = Router::new()
+        .route("/add", post(coordinator::add).layer(middleware::from_fn_with_state(Arc::new(IdempotencyCache::new(idempotency_window())), idempotent)))
+        .route("/check", post(coordinator::check))
+        .route("/update-tree", post(coordinator::update_tree))
+        .route("/stats", get(coordinator::get_stats))
+        .route("/openapi.json", get(coordinator::get_openapi))
+        .route("/docs", get(get_docs))
+        .route("/docs/{asset}", get(get_docs_asset))
+        .with_state(coordinator);
+    let app = with_common_layers(app);
+
+    let (bind_address, tls_files) = listen_config();
+    println!("Coordinator starting on {}://{}", if tls_files.is_some() { "https" } else { "http" }, bind_address);
+    println!("Routing hashes by prefix to {} shards: {}", shards.len(), shards.join(", "));
+    println!("POST /add?leaf_version=&hash_algorithm=&encoding=raw|hex|base64 - Add multiple hashes to their shards (raw bytes, multiple of 64 bytes, 32 for sha256 and blake3, or whitespace separated hex or base64)");
+    println!("POST /check?leaf_version=&hash_algorithm=&encoding=raw|hex|base64 - Check if a hash exists on its shard and get its merkle proof up to the cluster root (one digest, raw bytes, hex or base64)");
+    println!("POST /update-tree - Update the trees of all shards and combine their roots");
+    println!("GET /stats - Get hash counts of all shards and the roots of the cluster tree");
+    println!("GET /openapi.json - Get the OpenAPI description of these routes, browsable at GET /docs");
+    check_swagger_ui_dir();
+
+    serve(app, &bind_address, tls_files).await;
+    println!("Shutdown complete");
+}
+
+#[cfg(not(feature = "sqlite"))]
+async fn run_sqlite_store() {
+    panic!("SQLite support is not compiled in, build with `--features sqlite`");
+}
+
+#[cfg(feature = "sqlite")]
+use sqlite_store::{HashStorage, SqliteStore};
+
+#[cfg(feature = "sqlite")]
+async fn run_sqlite_store() {
+    let dir = Path::new(DATA_DIR).join("sqlite");
+    std::fs::create_dir_all(&dir).unwrap();
+    let store = SqliteStore::open(&dir.join("hashes.db"), None, hasher_from_name(TREE_HASHER).unwrap())
+        .unwrap_or_else(|e| panic!("Failed to open the SQLite store: {}", e));
+    let service = TimestampingService::<INDEX_SIZE, PREFIX_SIZE, SqliteStore>::from_store(store)
+        .with_signing_key(load_or_create_signing_key(&Path::new(DATA_DIR).join(SIGNING_KEY_FILE)).unwrap())
+        .with_epoch_log(EpochLog::open(&dir.join("epochs.jsonl")).unwrap_or_else(|e| panic!("Failed to load the epoch log: {}", e)))
+        .with_root_log(RootLog::open(&dir.join("roots.jsonl")).unwrap_or_else(|e| panic!("Failed to load the root log: {}", e)));
+    let service = Arc::new(service);
+
+    let app = sqlite_store::routes().with_state(Arc::clone(&service));
+    let app = with_common_layers(app);
+
+    let (bind_address, tls_files) = listen_config();
+    println!("Server starting on {}://{}", if tls_files.is_some() { "https" } else { "http" }, bind_address);
+    println!("Storing hashes in SQLite at {}", dir.join("hashes.db").display());
+    println!("POST /add?hash_algorithm=&encoding=raw|hex|base64&submitter=&label=&content_type=&namespace= - Add multiple hashes (raw bytes, multiple of 64 bytes, 32 for sha256 and blake3, or whitespace separated hex or base64)");
+    println!("POST /check?tree_version=&hash_algorithm=&encoding=raw|hex|base64 - Check if a hash exists and get its merkle proof (one digest, raw bytes, hex or base64)");
+    println!("POST /update-tree - Publish a merkle tree of all hashes");
+    println!("GET /stats - Get the hash count and the latest tree");
+    println!("GET /roots/{{version}} - Get the head of a published tree");
+    println!("GET /openapi.json - Get the OpenAPI description of these routes, browsable at GET /docs");
+    check_swagger_ui_dir();
+    println!("Restored {} hashes", service.hash_store.len());
+
+    serve(app, &bind_address, tls_files).await;
+
+    println!("Shutting down, publishing final merkle tree...");
+    publish(&service, |service| {
+        service.shutdown();
+        sqlite_store::record_head(service);
+    }).await;
+    println!("Shutdown complete");
+}
+
+// Handlers of a server storing its hashes in SQLite, which only has the basic routes: the store has no leaf logs
+// to replicate, audit or export, and no admin actions
+#[cfg(feature = "sqlite")]
+mod sqlite_store {
+    use super::*;
+    pub use timestamping::sqlite::SqliteStore;
+    pub use timestamping::storage::HashStorage;
+
+    type SqliteService = TimestampingService<INDEX_SIZE, PREFIX_SIZE, SqliteStore>;
+
+    #[derive(OpenApi)]
+    #[openapi(
+        info(title = "Timestamping server (SQLite)", description = "Timestamps hashes stored in an SQLite database"),
+        paths(add, check, update_tree, get_stats, get_root),
+        components(schemas(ErrorCode)),
+    )]
+    struct SqliteApiDoc;
+
+    pub fn routes() -> Router<Arc<SqliteService>> {
+        Router::new()
+            .route("/add", post(add).layer(middleware::from_fn_with_state(Arc::new(IdempotencyCache::new(idempotency_window())), idempotent)))
+            .route("/check", post(check))
+            .route("/update-tree", post(update_tree))
+            .route("/stats", get(get_stats))
+            .route("/roots/{version}", get(get_root))
+            .route("/openapi.json", get(get_openapi))
+            .route("/docs", get(get_docs))
+            .route("/docs/{asset}", get(get_docs_asset))
+    }
+
+    async fn get_openapi() -> Json<utoipa::openapi::OpenApi> {
+        Json(SqliteApiDoc::openapi())
+    }
+
+    // Keep the head of the published tree in the database next to its hashes
+    pub fn record_head(service: &SqliteService) {
+        if let Some((head, _)) = service.get_published_head()
+            && let Err(e) = service.hash_store.record_tree_head(&head)
+        {
+            log_request(format!("Recording tree head {} failed: {}", head.version, e));
+        }
+    }
+
+    #[derive(Debug, Serialize, ToSchema)]
+    pub struct SqliteStatsResponse {
+        count: usize,
+        merkle_tree_version: Option<u64>,
+        merkle_tree_size: usize,
+        merkle_tree_root: Option<Vec<u8>>,
+        last_tree_update: Option<u64>,
+    }
+
+    #[derive(Debug, Serialize, ToSchema)]
+    pub struct SqliteRootResponse {
+        message: &'static str,
+        version: u64,
+        root: Option<Vec<u8>>,
+        tree_size: usize,
+        leaf_count: usize,
+        timestamp: u64,
+    }
+
+    // The store only keeps the default leaf encoding
+    #[utoipa::path(
+        post, path = "/add", tag = "add",
+        params(AddQuery, ("Idempotency-Key" = Option<String>, Header, description = "Replay the response of an earlier request with this key instead of adding again")),
+        request_body(
+            description = "Concatenated raw digests, or whitespace separated hex or base64 digests",
+            content((Vec<u8> = "application/octet-stream"), (String = "text/plain")),
+        ),
+        responses(
+            (status = 200, body = AddBatchResponse),
+            (status = 400, body = ErrorResponse),
+            (status = 409, description = "A request with this idempotency key is still running", body = ErrorResponse),
+            (status = 422, description = "The idempotency key was used for a different request", body = ErrorResponse),
+        ),
+    )]
+    async fn add(
+        State(service): State<Arc<SqliteService>>,
+        ApiQuery(query): ApiQuery<AddQuery>,
+        headers: HeaderMap,
+        bytes: Bytes,
+    ) -> Result<Json<AddBatchResponse>, ApiError> {
+        let algorithm = query.hash_algorithm;
+        let input_encoding = InputEncoding::negotiate(query.encoding, &headers, &bytes, algorithm);
+        let encoding = leaf_encoding(query.leaf_version)?;
+        if encoding != LeafEncoding::default() {
+            return Err(ApiError::new(ErrorCode::UnsupportedLeafVersion, format!("The SQLite store only keeps leaf version {}", LeafEncoding::default().version())));
+        }
+        let hashes = input_encoding.decode_hashes(&bytes, algorithm)?;
+        let metadata = submitted_metadata(query.submitter, query.label, query.content_type, query.namespace, algorithm)?;
+        let added = service.hash_store.add_batch_with_metadata(&hashes, metadata.as_ref())?;
+
+        let mut seen = HashSet::new();
+        let statuses: Vec<AddStatus> = hashes.iter().zip(&added)
+            .map(|(hash, &new)| match (seen.insert(*hash), new) {
+                (false, _) => AddStatus::Duplicate,
+                (true, true) => AddStatus::New,
+                (true, false) => AddStatus::Existing,
+            })
+            .collect();
+        let new_hashes = statuses.iter().filter(|status| **status == AddStatus::New).count();
+        let existing_hashes = statuses.iter().filter(|status| **status == AddStatus::Existing).count();
+        let message = format!(
+            "Batch processed: {} total, {} new, {} existing, {} duplicate",
+            hashes.len(), new_hashes, existing_hashes, hashes.len() - new_hashes - existing_hashes
+        );
+        Ok(Json(AddBatchResponse::new(
+            AddResponse {
+                message,
+                total_hashes: hashes.len(),
+                new_hashes,
+                existing_hashes,
+                leaf_version: encoding.version(),
+                hash_algorithm: algorithm,
+            },
+            statuses,
+            input_encoding,
+            Vec::new(),
+        )))
+    }
+
+    // Proofs are only available for the latest tree
+    #[utoipa::path(
+        post, path = "/check", tag = "check", params(CheckQuery),
+        request_body(
+            description = "One raw digest, or one hex or base64 digest",
+            content((Vec<u8> = "application/octet-stream"), (String = "text/plain")),
+        ),
+        responses(
+            (status = 200, body = CheckHashResponse),
+            (status = 400, body = ErrorResponse),
+            (status = 404, description = "The requested tree version isn't the latest tree", body = ErrorResponse),
+        ),
+    )]
+    async fn check(
+        State(service): State<Arc<SqliteService>>,
+        ApiQuery(query): ApiQuery<CheckQuery>,
+        headers: HeaderMap,
+        bytes: Bytes,
+    ) -> Result<Json<CheckHashResponse>, ApiError> {
+        let input_encoding = InputEncoding::negotiate(query.encoding, &headers, &bytes, query.hash_algorithm);
+        let hash = input_encoding.decode_hash(&bytes, query.hash_algorithm)?;
+        let encoding = leaf_encoding(query.leaf_version)?;
+        let tree_version = service.get_merkle_tree_version();
+        if query.tree_version.is_some() && query.tree_version != tree_version {
+            return Err(tree_version_unavailable());
+        }
+
+        let exists = service.hash_store.contains(&hash)?;
+        Ok(Json(CheckHashResponse {
+            message: if exists { MSG_HASH_FOUND } else { MSG_HASH_NOT_FOUND },
+            exists,
+            merkle_proof: if exists { service.get_merkle_proof_with_encoding(&hash, encoding) } else { None },
+            metadata: service.hash_store.metadata(&hash)?,
+            leaf_version: encoding.version(),
+            tree_version,
+            hash_algorithm: query.hash_algorithm,
+            tree_hasher: service.hash_store.hasher().name(),
+            sequence: None,
+            resubmissions: None,
+            input_encoding: Some(input_encoding),
+        }))
+    }
+
+    #[utoipa::path(
+        post, path = "/update-tree", tag = "tree",
+        responses((status = 200, body = UpdateTreeResponse), (status = 500, body = ErrorResponse)),
+    )]
+    async fn update_tree(
+        State(service): State<Arc<SqliteService>>,
+    ) -> Result<Json<UpdateTreeResponse>, ApiError> {
+        let head = publish(&service, |service| {
+            let head = service.update_merkle_tree()?;
+            record_head(service);
+            Ok::<_, StorageError>(head)
+        }).await?;
+        Ok(Json(UpdateTreeResponse {
+            message: format!("Merkle tree updated with {} hashes", head.leaf_count),
+            tree_size: head.tree_size,
+            hash_count: head.leaf_count,
+        }))
+    }
+
+    #[utoipa::path(
+        get, path = "/stats", tag = "stats",
+        responses((status = 200, body = SqliteStatsResponse)),
+    )]
+    async fn get_stats(
+        State(service): State<Arc<SqliteService>>,
+    ) -> Json<SqliteStatsResponse> {
+        let published = service.published_tree();
+        Json(SqliteStatsResponse {
+            count: service.hash_store.len(),
+            merkle_tree_version: published.as_ref().map(|published| published.head.version),
+            merkle_tree_size: published.as_ref().map_or(0, |published| published.head.tree_size),
+            merkle_tree_root: published.as_ref().and_then(|published| published.head.root).map(|root| root.to_bytes()),
+            last_tree_update: published.as_ref().map(|published| published.updated_at),
+        })
+    }
+
+    #[utoipa::path(
+        get, path = "/roots/{version}", tag = "tree",
+        params(("version" = u64, Path, description = "Version of a published tree")),
+        responses((status = 200, body = SqliteRootResponse), (status = 404, body = ErrorResponse)),
+    )]
+    async fn get_root(
+        State(service): State<Arc<SqliteService>>,
+        ApiPath(version): ApiPath<u64>,
+    ) -> Result<Json<SqliteRootResponse>, ApiError> {
+        let head = service.hash_store.tree_head(version)?.ok_or_else(|| ApiError::new(ErrorCode::NotFound, MSG_ROOT_NOT_FOUND))?;
+        Ok(Json(SqliteRootResponse {
+            message: MSG_ROOT_FOUND,
+            version: head.version,
+            root: head.root.map(|root| root.to_bytes()),
+            tree_size: head.tree_size,
+            leaf_count: head.leaf_count,
+            timestamp: head.timestamp,
+        }))
+    }
+}
+
+// Handlers of the coordinator, which forwards requests to the shards of a cluster
+#[cfg(feature = "client")]
+mod coordinator {
+    use super::*;
+    pub use timestamping::cluster::ClusterCoordinator;
+    use timestamping::client::ClientError;
+
+    const MSG_SHARD_UNAVAILABLE: &str = "A shard of the cluster failed to answer - try again later";
+
+    #[derive(OpenApi)]
+    #[openapi(
+        info(title = "Timestamping coordinator", description = "Routes hashes to the shards of a cluster and proves them up to the cluster root"),
+        paths(add, check, update_tree, get_stats),
+        components(schemas(ErrorCode)),
+    )]
+    struct CoordinatorApiDoc;
+
+    pub async fn get_openapi() -> Json<utoipa::openapi::OpenApi> {
+        Json(CoordinatorApiDoc::openapi())
+    }
+
+    fn shard_unavailable(action: &str, error: ClientError) -> ApiError {
+        log_request(format!("{} failed: {}", action, error));
+        ApiError::new(ErrorCode::ShardUnavailable, MSG_SHARD_UNAVAILABLE)
+    }
+
+    #[derive(Debug, Serialize, ToSchema)]
+    pub struct ShardEntry {
+        url: String,
+        count: usize,
+        tree_version: Option<u64>,
+        merkle_tree_root: Option<Vec<u8>>,
+    }
+
+    #[derive(Debug, Serialize, ToSchema)]
+    pub struct ClusterStatsResponse {
+        count: usize,
+        merkle_tree_version: Option<u64>,
+        merkle_tree_root: Option<Vec<u8>>,
+        shards: Vec<ShardEntry>,
+    }
+
+    #[utoipa::path(
+        post, path = "/add", tag = "add",
+        params(AddQuery, ("Idempotency-Key" = Option<String>, Header, description = "Replay the response of an earlier request with this key instead of adding again")),
+        request_body(
+            description = "Concatenated raw digests, or whitespace separated hex or base64 digests",
+            content((Vec<u8> = "application/octet-stream"), (String = "text/plain")),
+        ),
+        responses(
+            (status = 200, body = AddBatchResponse),
+            (status = 400, body = ErrorResponse),
+            (status = 502, description = "A shard failed", body = ErrorResponse),
+            (status = 409, description = "A request with this idempotency key is still running", body = ErrorResponse),
+            (status = 422, description = "The idempotency key was used for a different request", body = ErrorResponse),
+        ),
+    )]
+    pub async fn add(
+        State(coordinator): State<Arc<ClusterCoordinator>>,
+        ApiQuery(query): ApiQuery<AddQuery>,
+        headers: HeaderMap,
+        bytes: Bytes,
+    ) -> Result<Json<AddBatchResponse>, ApiError> {
+        let algorithm = query.hash_algorithm;
+        let input_encoding = InputEncoding::negotiate(query.encoding, &headers, &bytes, algorithm);
+        let encoding = leaf_encoding(query.leaf_version)?;
+        let hashes = input_encoding.decode_hashes(&bytes, algorithm)?;
+        let result = coordinator.add_batch(&hashes, encoding).await.map_err(|e| shard_unavailable("Adding to shards", e))?;
+
+        let message = format!(
+            "Batch processed: {} total, {} new, {} existing, {} duplicate",
+            result.total_hashes, result.new_hashes, result.existing_hashes, result.duplicate_hashes
+        );
+        Ok(Json(AddBatchResponse::new(
+            AddResponse {
+                message,
+                total_hashes: result.total_hashes,
+                new_hashes: result.new_hashes,
+                existing_hashes: result.existing_hashes,
+                leaf_version: result.leaf_version,
+                hash_algorithm: algorithm,
+            },
+            result.statuses,
+            input_encoding,
+            result.acknowledgments,
+        )))
+    }
+
+    // Proofs are only available for the latest cluster tree, older shard trees may be gone already
+    #[utoipa::path(
+        post, path = "/check", tag = "check", params(CheckQuery),
+        request_body(
+            description = "One raw digest, or one hex or base64 digest",
+            content((Vec<u8> = "application/octet-stream"), (String = "text/plain")),
+        ),
+        responses(
+            (status = 200, body = CheckHashResponse),
+            (status = 400, body = ErrorResponse),
+            (status = 404, description = "The requested tree version isn't the current cluster tree", body = ErrorResponse),
+            (status = 502, description = "A shard failed", body = ErrorResponse),
+        ),
+    )]
+    pub async fn check(
+        State(coordinator): State<Arc<ClusterCoordinator>>,
+        ApiQuery(query): ApiQuery<CheckQuery>,
+        headers: HeaderMap,
+        bytes: Bytes,
+    ) -> Result<Json<CheckHashResponse>, ApiError> {
+        let input_encoding = InputEncoding::negotiate(query.encoding, &headers, &bytes, query.hash_algorithm);
+        let hash = input_encoding.decode_hash(&bytes, query.hash_algorithm)?;
+        let encoding = leaf_encoding(query.leaf_version)?;
+        if query.tree_version.is_some() && query.tree_version != coordinator.tree().map(|tree| tree.version) {
+            return Err(tree_version_unavailable());
+        }
+
+        let result = coordinator.check(&hash, encoding).await.map_err(|e| shard_unavailable("Checking on shard", e))?;
+        Ok(Json(CheckHashResponse {
+            message: if result.exists { MSG_HASH_FOUND } else { MSG_HASH_NOT_FOUND },
+            exists: result.exists,
+            merkle_proof: result.merkle_proof.map(|proof| {
+                proof.iter().map(|(left, right)| (left.to_bytes(), right.to_bytes())).collect()
+            }),
+            metadata: result.metadata,
+            leaf_version: encoding.version(),
+            tree_version: result.tree_version,
+            hash_algorithm: query.hash_algorithm,
+            tree_hasher: coordinator.hasher().name(),
+            // Numbered by the shard, so only comparable with hashes of the same shard
+            sequence: result.sequence,
+            resubmissions: result.resubmissions,
+            input_encoding: Some(input_encoding),
+        }))
+    }
+
+    #[utoipa::path(
+        post, path = "/update-tree", tag = "tree",
+        responses((status = 200, body = UpdateTreeResponse), (status = 502, description = "A shard failed", body = ErrorResponse)),
+    )]
+    pub async fn update_tree(
+        State(coordinator): State<Arc<ClusterCoordinator>>,
+    ) -> Result<Json<UpdateTreeResponse>, ApiError> {
+        let tree = coordinator.update_tree().await.map_err(|e| shard_unavailable("Updating shard trees", e))?;
+        Ok(Json(UpdateTreeResponse {
+            message: format!("Cluster tree {} updated with {} hashes", tree.version, tree.leaf_count()),
+            tree_size: tree.tree.size(),
+            hash_count: tree.leaf_count(),
+        }))
+    }
+
+    #[utoipa::path(
+        get, path = "/stats", tag = "stats",
+        responses((status = 200, body = ClusterStatsResponse), (status = 502, description = "A shard failed", body = ErrorResponse)),
+    )]
+    pub async fn get_stats(
+        State(coordinator): State<Arc<ClusterCoordinator>>,
+    ) -> Result<Json<ClusterStatsResponse>, ApiError> {
+        let stats = coordinator.stats().await.map_err(|e| shard_unavailable("Getting shard stats", e))?;
+        let tree = coordinator.tree();
+        let shards = coordinator.urls().iter().enumerate()
+            .map(|(index, url)| {
+                let head = tree.as_ref().and_then(|tree| tree.shards[index].as_ref());
+                ShardEntry {
+                    url: url.clone(),
+                    count: stats.shard_counts[index],
+                    tree_version: head.map(|head| head.version),
+                    merkle_tree_root: head.and_then(|head| head.root).map(|root| root.to_bytes()),
+                }
+            })
+            .collect();
+        Ok(Json(ClusterStatsResponse {
+            count: stats.count,
+            merkle_tree_version: tree.as_ref().map(|tree| tree.version),
+            merkle_tree_root: tree.as_ref().and_then(|tree| tree.root()).map(|root| root.to_bytes()),
+            shards,
+        }))
+    }
 }
@@ -0,0 +1,183 @@
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+use sha2::{Digest, Sha512};
+use crate::verify::{Hash512, Hash512Ops};
+
+// First line of every manifest, followed by the format version
+const MANIFEST_HEADER: &str = "timestamping manifest v1";
+
+#[derive(Debug, thiserror::Error)]
+pub enum ManifestError {
+    #[error("{0}: {1}")]
+    Io(PathBuf, io::Error),
+    // Paths have to be UTF-8 without line breaks to be written one per line
+    #[error("{0}: path is not UTF-8 or contains a line break")]
+    UnsupportedPath(PathBuf),
+    // Links could point outside of the directory, so only regular files are covered
+    #[error("{0}: symbolic links are not supported")]
+    Symlink(PathBuf),
+    #[error("Invalid manifest: {0}")]
+    InvalidFormat(&'static str),
+}
+
+// SHA-512 digest of every regular file below a directory, by its path relative to it with `/` separators.
+// Its canonical form, `to_bytes`, is what gets timestamped: the header line, then a line of the hex digest,
+// two spaces and the path for every file, sorted by the bytes of the paths.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Manifest {
+    pub files: Vec<(String, Hash512)>,
+}
+
+// Files that differ between a manifest and the current state of its directory
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ManifestDiff {
+    pub changed: Vec<String>,
+    pub missing: Vec<String>,
+    pub added: Vec<String>,
+}
+
+impl ManifestDiff {
+    pub fn is_empty(&self) -> bool {
+        self.changed.is_empty() && self.missing.is_empty() && self.added.is_empty()
+    }
+}
+
+impl Manifest {
+    // Hash every file below `dir`
+    pub fn build(dir: &Path) -> Result<Self, ManifestError> {
+        let mut files = Vec::new();
+        collect_files(dir, "", &mut files)?;
+        files.sort();
+        Ok(Self { files })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut text = format!("{}\n", MANIFEST_HEADER);
+        for (path, hash) in &self.files {
+            text.push_str(&format!("{}  {}\n", hash, path));
+        }
+        text.into_bytes()
+    }
+
+    // Parse the canonical form, refusing anything `to_bytes` wouldn't write
+    pub fn parse(bytes: &[u8]) -> Result<Self, ManifestError> {
+        let text = std::str::from_utf8(bytes).map_err(|_| ManifestError::InvalidFormat("not UTF-8"))?;
+        let body = text.strip_prefix(MANIFEST_HEADER).and_then(|rest| rest.strip_prefix('\n'))
+            .ok_or(ManifestError::InvalidFormat("missing header"))?;
+        let files = body.split_terminator('\n')
+            .map(|line| {
+                let (hash, path) = line.split_once("  ").filter(|(_, path)| !path.is_empty())
+                    .ok_or(ManifestError::InvalidFormat("line is not a digest and a path"))?;
+                let hash = Some(hash).filter(|hash| hash.len() == 128).and_then(|hash| hash.parse::<Hash512>().ok())
+                    .ok_or(ManifestError::InvalidFormat("digest is not 128 hex characters"))?;
+                Ok((path.to_string(), hash))
+            })
+            .collect::<Result<Vec<_>, ManifestError>>()?;
+        if !files.windows(2).all(|pair| pair[0].0 < pair[1].0) {
+            return Err(ManifestError::InvalidFormat("paths are not sorted and unique"));
+        }
+        let manifest = Self { files };
+        if manifest.to_bytes() != bytes {
+            return Err(ManifestError::InvalidFormat("not in canonical form"));
+        }
+        Ok(manifest)
+    }
+
+    // The hash that is submitted for the manifest, SHA-512 of its canonical form
+    pub fn digest(&self) -> Hash512 {
+        Hash512::from_bytes(&Sha512::digest(self.to_bytes())).unwrap()
+    }
+
+    // What changed from this manifest to `current`
+    pub fn diff(&self, current: &Manifest) -> ManifestDiff {
+        let mut diff = ManifestDiff::default();
+        for (path, hash) in &self.files {
+            match current.files.binary_search_by(|(other, _)| other.cmp(path)) {
+                Ok(index) if current.files[index].1 != *hash => diff.changed.push(path.clone()),
+                Ok(_) => {}
+                Err(_) => diff.missing.push(path.clone()),
+            }
+        }
+        for (path, _) in &current.files {
+            if self.files.binary_search_by(|(other, _)| other.cmp(path)).is_err() {
+                diff.added.push(path.clone());
+            }
+        }
+        diff
+    }
+}
+
+fn collect_files(dir: &Path, prefix: &str, files: &mut Vec<(String, Hash512)>) -> Result<(), ManifestError> {
+    let entries = std::fs::read_dir(dir).map_err(|e| ManifestError::Io(dir.to_path_buf(), e))?;
+    for entry in entries {
+        let entry = entry.map_err(|e| ManifestError::Io(dir.to_path_buf(), e))?;
+        let path = entry.path();
+        let name = entry.file_name().into_string().ok()
+            .filter(|name| !name.contains(['\n', '\r']))
+            .ok_or_else(|| ManifestError::UnsupportedPath(path.clone()))?;
+        let relative = format!("{}{}", prefix, name);
+        let file_type = entry.file_type().map_err(|e| ManifestError::Io(path.clone(), e))?;
+        if file_type.is_symlink() {
+            return Err(ManifestError::Symlink(path));
+        } else if file_type.is_dir() {
+            collect_files(&path, &format!("{}/", relative), files)?;
+        } else {
+            let mut hasher = Sha512::new();
+            File::open(&path).and_then(|file| io::copy(&mut BufReader::new(file), &mut hasher))
+                .map_err(|e| ManifestError::Io(path.clone(), e))?;
+            files.push((relative, Hash512::from_bytes(&hasher.finalize()).unwrap()));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_manifest() {
+        let dir = std::env::temp_dir().join(format!("timestamping-manifest-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("b/c")).unwrap();
+        std::fs::write(dir.join("a.txt"), b"first").unwrap();
+        std::fs::write(dir.join("b/c/d.bin"), b"second").unwrap();
+        std::fs::write(dir.join("b.txt"), b"third").unwrap();
+
+        let manifest = Manifest::build(&dir).unwrap();
+        let paths: Vec<&str> = manifest.files.iter().map(|(path, _)| path.as_str()).collect();
+        assert_eq!(paths, ["a.txt", "b.txt", "b/c/d.bin"]);
+        assert_eq!(manifest.files[0].1, Hash512::from_bytes(&Sha512::digest(b"first")).unwrap());
+        assert_eq!(Manifest::parse(&manifest.to_bytes()).unwrap(), manifest);
+        assert_eq!(manifest.digest(), Hash512::from_bytes(&Sha512::digest(manifest.to_bytes())).unwrap());
+        assert!(manifest.diff(&manifest).is_empty());
+
+        std::fs::write(dir.join("a.txt"), b"changed").unwrap();
+        std::fs::remove_file(dir.join("b.txt")).unwrap();
+        std::fs::write(dir.join("e.txt"), b"new").unwrap();
+        let diff = manifest.diff(&Manifest::build(&dir).unwrap());
+        assert_eq!(diff, ManifestDiff { changed: vec!["a.txt".to_string()], missing: vec!["b.txt".to_string()], added: vec!["e.txt".to_string()] });
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_manifest_parse() {
+        let hash = Hash512([7; 8]).to_string();
+        let valid = format!("{}\n{}  a\n{}  b\n", MANIFEST_HEADER, hash, hash);
+        assert_eq!(Manifest::parse(valid.as_bytes()).unwrap().files, vec![("a".to_string(), Hash512([7; 8])), ("b".to_string(), Hash512([7; 8]))]);
+
+        // Every manifest has exactly one canonical form, so its digest identifies it
+        for invalid in [
+            format!("{}\n{}  b\n{}  a\n", MANIFEST_HEADER, hash, hash),
+            format!("{}\n{}  a\n{}  a\n", MANIFEST_HEADER, hash, hash),
+            format!("{}\n{}  a", MANIFEST_HEADER, hash),
+            format!("{}\n{}  a\n", MANIFEST_HEADER, Hash512([u64::MAX; 8]).to_string().to_uppercase()),
+            format!("{}\n{} a\n", MANIFEST_HEADER, hash),
+            format!("{}  a\n", hash),
+        ] {
+            assert!(matches!(Manifest::parse(invalid.as_bytes()), Err(ManifestError::InvalidFormat(_))), "{}", invalid);
+        }
+    }
+}
@@ -0,0 +1,189 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use serde::Serialize;
+use utoipa::ToSchema;
+
+// Latencies are counted in buckets of a quarter power of two microseconds, so percentiles are within 25%
+const SUB_BUCKETS: usize = 4;
+// The last bucket also takes everything slower than about 2^40µs, which is almost two weeks
+const BUCKETS: usize = 40 * SUB_BUCKETS;
+// Percentiles cover the requests of the current and the previous window
+pub const LATENCY_WINDOW: Duration = Duration::from_secs(60);
+
+// Rolling histogram of request latencies
+#[derive(Debug)]
+pub struct LatencyHistogram {
+    window: Duration,
+    windows: Mutex<Windows>,
+}
+
+#[derive(Debug)]
+struct Windows {
+    started: Instant,
+    current: [u64; BUCKETS],
+    previous: [u64; BUCKETS],
+}
+
+// Percentiles of a histogram in microseconds, the upper bounds of the buckets they fall into
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, ToSchema)]
+pub struct LatencySummary {
+    pub count: u64,
+    pub p50_us: Option<u64>,
+    pub p95_us: Option<u64>,
+    pub p99_us: Option<u64>,
+}
+
+impl LatencyHistogram {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            windows: Mutex::new(Windows { started: Instant::now(), current: [0; BUCKETS], previous: [0; BUCKETS] }),
+        }
+    }
+
+    pub fn record(&self, latency: Duration) {
+        let mut windows = self.windows.lock().unwrap();
+        windows.rotate(self.window, Instant::now());
+        windows.current[bucket(latency.as_micros().min(u64::MAX as u128) as u64)] += 1;
+    }
+
+    pub fn summary(&self) -> LatencySummary {
+        let mut windows = self.windows.lock().unwrap();
+        windows.rotate(self.window, Instant::now());
+        let counts: Vec<u64> = windows.current.iter().zip(&windows.previous).map(|(current, previous)| current + previous).collect();
+        let count = counts.iter().sum();
+        LatencySummary {
+            count,
+            p50_us: percentile(&counts, count, 50),
+            p95_us: percentile(&counts, count, 95),
+            p99_us: percentile(&counts, count, 99),
+        }
+    }
+}
+
+impl Windows {
+    fn rotate(&mut self, window: Duration, now: Instant) {
+        let elapsed = now.duration_since(self.started);
+        if elapsed < window {
+            return;
+        }
+        // Nothing was recorded in the previous window if more than one passed
+        self.previous = if elapsed < 2 * window { self.current } else { [0; BUCKETS] };
+        self.current = [0; BUCKETS];
+        self.started = now;
+    }
+}
+
+fn bucket(us: u64) -> usize {
+    if us < SUB_BUCKETS as u64 {
+        return us as usize;
+    }
+    let exponent = 63 - us.leading_zeros() as usize;
+    let sub_bucket = (us >> (exponent - 2)) as usize & (SUB_BUCKETS - 1);
+    ((exponent - 1) * SUB_BUCKETS + sub_bucket).min(BUCKETS - 1)
+}
+
+// Smallest latency not in the bucket or any before it
+fn upper_bound(bucket: usize) -> u64 {
+    if bucket < SUB_BUCKETS {
+        return bucket as u64 + 1;
+    }
+    let exponent = bucket / SUB_BUCKETS + 1;
+    (SUB_BUCKETS + bucket % SUB_BUCKETS + 1) as u64 * (1 << (exponent - 2))
+}
+
+fn percentile(counts: &[u64], count: u64, percent: u64) -> Option<u64> {
+    if count == 0 {
+        return None;
+    }
+    let rank = (count * percent).div_ceil(100);
+    let mut seen = 0;
+    counts.iter().position(|&bucket_count| {
+        seen += bucket_count;
+        seen >= rank
+    }).map(upper_bound)
+}
+
+// Latencies of the endpoints that ingest hashes, answer with proofs and build the trees
+#[derive(Debug, Clone)]
+pub struct RequestLatencies {
+    pub add: Arc<LatencyHistogram>,
+    pub check: Arc<LatencyHistogram>,
+    pub update_tree: Arc<LatencyHistogram>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, ToSchema)]
+pub struct RequestLatencySummary {
+    pub add: LatencySummary,
+    pub check: LatencySummary,
+    pub update_tree: LatencySummary,
+}
+
+impl Default for RequestLatencies {
+    fn default() -> Self {
+        Self {
+            add: Arc::new(LatencyHistogram::new(LATENCY_WINDOW)),
+            check: Arc::new(LatencyHistogram::new(LATENCY_WINDOW)),
+            update_tree: Arc::new(LatencyHistogram::new(LATENCY_WINDOW)),
+        }
+    }
+}
+
+impl RequestLatencies {
+    pub fn summary(&self) -> RequestLatencySummary {
+        RequestLatencySummary {
+            add: self.add.summary(),
+            check: self.check.summary(),
+            update_tree: self.update_tree.summary(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buckets() {
+        for us in [0, 1, 3, 4, 5, 7, 8, 9, 100, 1000, 123_456, 1 << 39] {
+            let bucket = bucket(us);
+            assert!(us < upper_bound(bucket), "{}", us);
+            assert!(bucket == 0 || us >= upper_bound(bucket - 1), "{}", us);
+        }
+        assert_eq!(bucket(u64::MAX), BUCKETS - 1);
+        // Buckets are at most a quarter wider than their lower bound
+        assert_eq!((bucket(1000), upper_bound(bucket(1000))), (bucket(1023), 1024));
+    }
+
+    #[test]
+    fn test_percentiles() {
+        let histogram = LatencyHistogram::new(LATENCY_WINDOW);
+        assert_eq!(histogram.summary(), LatencySummary::default());
+        for _ in 0..90 {
+            histogram.record(Duration::from_micros(100));
+        }
+        for _ in 0..9 {
+            histogram.record(Duration::from_millis(10));
+        }
+        histogram.record(Duration::from_secs(1));
+
+        let summary = histogram.summary();
+        assert_eq!(summary.count, 100);
+        assert_eq!(summary.p50_us, Some(upper_bound(bucket(100))));
+        assert_eq!(summary.p95_us, Some(upper_bound(bucket(10_000))));
+        assert_eq!(summary.p99_us, Some(upper_bound(bucket(10_000))));
+        assert!(summary.p99_us.unwrap() > 10_000 && summary.p99_us.unwrap() <= 12_500);
+    }
+
+    #[test]
+    fn test_windows() {
+        let histogram = LatencyHistogram::new(Duration::from_millis(50));
+        histogram.record(Duration::from_micros(100));
+        std::thread::sleep(Duration::from_millis(60));
+        // The previous window still counts
+        histogram.record(Duration::from_micros(100));
+        assert_eq!(histogram.summary().count, 2);
+        std::thread::sleep(Duration::from_millis(110));
+        assert_eq!(histogram.summary().count, 0);
+    }
+}
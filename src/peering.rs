@@ -0,0 +1,142 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use crate::receipt::{Cosignature, ReceiptError, SigningKey, VerifyingKey, deserialize_bytes, serialize_bytes, tree_head_message};
+use crate::verify::Hash512;
+
+// Another timestamping server, which cosigns the tree heads of this one and has its own cosigned
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Peer {
+    pub url: String,
+    pub public_key: VerifyingKey,
+}
+
+// Tree head as a server sends it to its peers to be cosigned, the fields of `receipt::tree_head_message`
+// with the signature and the key it was made with
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct SignedTreeHead {
+    pub tree_hasher: String,
+    pub version: u64,
+    pub leaf_count: u64,
+    pub timestamp: u64,
+    #[schema(value_type = String)]
+    pub root: Hash512,
+    pub time_source: Option<String>,
+    #[serde(serialize_with = "serialize_bytes", deserialize_with = "deserialize_bytes")]
+    #[schema(value_type = String)]
+    pub public_key: Vec<u8>,
+    #[serde(serialize_with = "serialize_bytes", deserialize_with = "deserialize_bytes")]
+    #[schema(value_type = String)]
+    pub signature: Vec<u8>,
+}
+
+impl SignedTreeHead {
+    pub fn message(&self) -> Vec<u8> {
+        tree_head_message(&self.tree_hasher, self.version, self.leaf_count, self.timestamp, &self.root, self.time_source.as_deref())
+    }
+
+    fn verify(&self, key: &VerifyingKey) -> Result<(), ReceiptError> {
+        let signature = ed25519_dalek::Signature::from_slice(&self.signature).map_err(|_| ReceiptError::InvalidSignature)?;
+        ed25519_dalek::Verifier::verify(key, &self.message(), &signature).map_err(|_| ReceiptError::InvalidSignature)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum CosignError {
+    #[error("The key is not one of a configured peer")]
+    UnknownPeer,
+    #[error("The tree head signature is invalid for the peer's key")]
+    InvalidSignature,
+    // The head is back-dated or from the future by the clock of the witness
+    #[error("The tree head timestamp {timestamp} is too far from the current time {now}")]
+    Skewed { timestamp: u64, now: u64 },
+    // The peer signed another root for the same version before
+    #[error("A different root was cosigned for tree version {0}")]
+    Equivocation(u64),
+    #[error("Tree version {version} is older than the cosigned version {latest}")]
+    Rollback { version: u64, latest: u64 },
+}
+
+// Cosigns the tree heads of the configured peers. A head is only cosigned if its timestamp is close to the
+// witness's own clock and it is consistent with the last head cosigned for the same peer, which is
+// forgotten on restart.
+pub struct Witness {
+    key: SigningKey,
+    peers: Vec<Peer>,
+    max_skew: Duration,
+    // Version and root of the latest head cosigned for each peer, by its key
+    latest: Mutex<HashMap<[u8; 32], (u64, Hash512)>>,
+}
+
+impl Witness {
+    pub fn new(key: SigningKey, peers: Vec<Peer>, max_skew: Duration) -> Self {
+        Self { key, peers, max_skew, latest: Mutex::new(HashMap::new()) }
+    }
+
+    pub fn peers(&self) -> &[Peer] {
+        &self.peers
+    }
+
+    // Cosign `head` at `now`, unix time of the witness's clock
+    pub fn cosign(&self, head: &SignedTreeHead, now: u64) -> Result<Cosignature, CosignError> {
+        let peer = self.peers.iter()
+            .find(|peer| head.public_key == peer.public_key.as_bytes())
+            .ok_or(CosignError::UnknownPeer)?;
+        head.verify(&peer.public_key).map_err(|_| CosignError::InvalidSignature)?;
+        if head.timestamp.abs_diff(now) > self.max_skew.as_secs() {
+            return Err(CosignError::Skewed { timestamp: head.timestamp, now });
+        }
+        let mut latest = self.latest.lock().unwrap();
+        match latest.get(peer.public_key.as_bytes()) {
+            Some(&(version, root)) if version == head.version && root != head.root => return Err(CosignError::Equivocation(version)),
+            Some(&(version, _)) if version > head.version => return Err(CosignError::Rollback { version: head.version, latest: version }),
+            _ => {}
+        }
+        latest.insert(peer.public_key.to_bytes(), (head.version, head.root));
+        Ok(Cosignature::sign(&self.key, &head.message(), now))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::receipt::sign_tree_head;
+
+    fn head(key: &SigningKey, version: u64, timestamp: u64, root: Hash512) -> SignedTreeHead {
+        SignedTreeHead {
+            tree_hasher: "sha512".to_string(),
+            version,
+            leaf_count: version * 10,
+            timestamp,
+            root,
+            time_source: None,
+            public_key: key.verifying_key().to_bytes().to_vec(),
+            signature: sign_tree_head(key, "sha512", version, version * 10, timestamp, &root, None),
+        }
+    }
+
+    #[test]
+    fn test_witness() {
+        let peer_key = SigningKey::from_bytes(&[1; 32]);
+        let witness_key = SigningKey::from_bytes(&[2; 32]);
+        let peer = Peer { url: "http://peer.example".to_string(), public_key: peer_key.verifying_key() };
+        let witness = Witness::new(witness_key.clone(), vec![peer], Duration::from_secs(60));
+
+        let first = head(&peer_key, 3, 1_000, Hash512([3; 8]));
+        let cosignature = witness.cosign(&first, 1_030).unwrap();
+        assert_eq!(cosignature.verify(&witness_key.verifying_key(), &first.message()), Ok(()));
+        assert_eq!(cosignature.cosigned_at, 1_030);
+        // Retries of the same head are cosigned again
+        assert!(witness.cosign(&first, 1_040).is_ok());
+
+        assert_eq!(witness.cosign(&head(&witness_key, 4, 1_000, Hash512([4; 8])), 1_000), Err(CosignError::UnknownPeer));
+        let forged = SignedTreeHead { timestamp: 1_001, ..first.clone() };
+        assert_eq!(witness.cosign(&forged, 1_000), Err(CosignError::InvalidSignature));
+        assert_eq!(witness.cosign(&head(&peer_key, 4, 900, Hash512([4; 8])), 1_000), Err(CosignError::Skewed { timestamp: 900, now: 1_000 }));
+        assert_eq!(witness.cosign(&head(&peer_key, 3, 1_000, Hash512([5; 8])), 1_000), Err(CosignError::Equivocation(3)));
+        assert_eq!(witness.cosign(&head(&peer_key, 2, 1_000, Hash512([2; 8])), 1_000), Err(CosignError::Rollback { version: 2, latest: 3 }));
+        assert!(witness.cosign(&head(&peer_key, 4, 1_050, Hash512([4; 8])), 1_060).is_ok());
+    }
+}
@@ -0,0 +1,54 @@
+use sha2::{Digest, Sha512};
+use crate::storage::{Hash512, Hash512Ops};
+
+// Proof of work over the hashes of an add: a nonce such that SHA-512(challenge || nonce || hashes), with the
+// nonce as 8 bytes big-endian and the hashes as stored (see `HashAlgorithm::normalize`), starts with enough
+// zero bits. Each doubling of the number of hashes adds a bit, so splitting a batch doesn't make it cheaper.
+pub fn required_difficulty(difficulty: u32, hashes: usize) -> u32 {
+    difficulty + hashes.max(1).next_power_of_two().trailing_zeros()
+}
+
+pub fn work(challenge: &[u8], nonce: u64, hashes: &[Hash512]) -> [u8; 64] {
+    let mut hasher = Sha512::new();
+    hasher.update(challenge);
+    hasher.update(nonce.to_be_bytes());
+    for hash in hashes {
+        hasher.update(hash.to_bytes());
+    }
+    hasher.finalize().into()
+}
+
+pub fn leading_zero_bits(digest: &[u8]) -> u32 {
+    let zero_bytes = digest.iter().take_while(|&&byte| byte == 0).count();
+    let rest = digest.get(zero_bytes).map_or(0, |byte| byte.leading_zeros());
+    zero_bytes as u32 * 8 + rest
+}
+
+pub fn verify(challenge: &[u8], nonce: u64, hashes: &[Hash512], difficulty: u32) -> bool {
+    leading_zero_bits(&work(challenge, nonce, hashes)) >= required_difficulty(difficulty, hashes.len())
+}
+
+// Find a nonce by trying them in order, which takes about 2^difficulty hashes of the batch
+pub fn solve(challenge: &[u8], hashes: &[Hash512], difficulty: u32) -> u64 {
+    (0..).find(|&nonce| verify(challenge, nonce, hashes, difficulty)).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_solve_and_verify() {
+        assert_eq!(leading_zero_bits(&[0, 0, 0b0001_0000, 0xff]), 19);
+        assert_eq!(leading_zero_bits(&[0; 4]), 32);
+        assert_eq!((required_difficulty(8, 0), required_difficulty(8, 1), required_difficulty(8, 3)), (8, 8, 10));
+
+        let hashes = [Hash512([1u64, 0, 0, 0, 0, 0, 0, 0]), Hash512([2, 0, 0, 0, 0, 0, 0, 0])];
+        let nonce = solve(b"challenge", &hashes, 8);
+        assert!(verify(b"challenge", nonce, &hashes, 8));
+        assert!(leading_zero_bits(&work(b"challenge", nonce, &hashes)) >= 9);
+        // The work is bound to the challenge and the hashes
+        assert!(!verify(b"other", nonce, &hashes, 8));
+        assert!(!verify(b"challenge", nonce, &hashes[..1], 8));
+    }
+}
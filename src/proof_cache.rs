@@ -0,0 +1,133 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use serde::Serialize;
+use utoipa::ToSchema;
+use crate::storage::{Hash512, ProofBytes};
+
+// Hash, leaf version, tree version and leaf count of the tree of a proof
+type Key = (Hash512, u8, u64, usize);
+
+// Proofs of recently checked hashes, evicting the least recently used one once `capacity` are cached.
+// Proofs of hashes missing from a tree are cached as well. A replica can publish a version again with more
+// leaves, so keys include the leaf count, as readers may still make proofs from the tree published before.
+// The cache is cleared whenever a tree is published.
+#[derive(Debug)]
+pub struct ProofCache {
+    capacity: usize,
+    entries: Mutex<Entries>,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+}
+
+#[derive(Debug, Default)]
+struct Entries {
+    // Proof and last use of every key
+    proofs: HashMap<Key, (Option<ProofBytes>, u64)>,
+    // Keys by last use, oldest first
+    uses: BTreeMap<u64, Key>,
+    next_use: u64,
+}
+
+// Size and effectiveness of the proof cache, as reported in `/stats`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, ToSchema)]
+pub struct ProofCacheStats {
+    pub capacity: usize,
+    pub entries: usize,
+    pub hits: usize,
+    pub misses: usize,
+    // Share of lookups answered from the cache, 0 before the first one
+    pub hit_rate: f64,
+}
+
+impl ProofCache {
+    pub fn new(capacity: usize) -> Self {
+        Self { capacity, entries: Mutex::new(Entries::default()), hits: AtomicUsize::new(0), misses: AtomicUsize::new(0) }
+    }
+
+    // Cached proof of `hash` in tree `version` with `leaf_count` leaves, else the one `proof` makes, which is cached
+    pub fn get_or_insert_with(&self, hash: &Hash512, leaf_version: u8, version: u64, leaf_count: usize, proof: impl FnOnce() -> Option<ProofBytes>) -> Option<ProofBytes> {
+        let key = (*hash, leaf_version, version, leaf_count);
+        {
+            let mut entries = self.entries.lock().unwrap();
+            let use_index = entries.next_use;
+            if let Some((cached, last_use)) = entries.proofs.get_mut(&key) {
+                let (cached, previous_use) = (cached.clone(), std::mem::replace(last_use, use_index));
+                entries.uses.remove(&previous_use);
+                entries.uses.insert(use_index, key);
+                entries.next_use += 1;
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                return cached;
+            }
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        // Made without holding the lock, so concurrent misses don't wait for each other
+        let proof = proof();
+        if self.capacity == 0 {
+            return proof;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        let use_index = entries.next_use;
+        entries.next_use += 1;
+        if let Some((_, previous_use)) = entries.proofs.insert(key, (proof.clone(), use_index)) {
+            entries.uses.remove(&previous_use);
+        }
+        entries.uses.insert(use_index, key);
+        while entries.proofs.len() > self.capacity {
+            let (_, oldest) = entries.uses.pop_first().unwrap();
+            entries.proofs.remove(&oldest);
+        }
+        proof
+    }
+
+    pub fn clear(&self) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.proofs.clear();
+        entries.uses.clear();
+    }
+
+    pub fn stats(&self) -> ProofCacheStats {
+        let hits = self.hits.load(Ordering::Relaxed);
+        let misses = self.misses.load(Ordering::Relaxed);
+        ProofCacheStats {
+            capacity: self.capacity,
+            entries: self.entries.lock().unwrap().proofs.len(),
+            hits,
+            misses,
+            hit_rate: if hits + misses == 0 { 0.0 } else { hits as f64 / (hits + misses) as f64 },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn proof(byte: u8) -> Option<ProofBytes> {
+        Some(vec![(vec![byte; 64], vec![byte; 64])])
+    }
+
+    #[test]
+    fn test_least_recently_used_evicted() {
+        let cache = ProofCache::new(2);
+        assert_eq!(cache.get_or_insert_with(&Hash512([1; 8]), 2, 0, 1, || proof(1)), proof(1));
+        assert_eq!(cache.get_or_insert_with(&Hash512([2; 8]), 2, 0, 1, || None), None);
+        // Hits don't make a new proof
+        assert_eq!(cache.get_or_insert_with(&Hash512([1; 8]), 2, 0, 1, || unreachable!()), proof(1));
+        assert_eq!(cache.get_or_insert_with(&Hash512([2; 8]), 2, 0, 1, || unreachable!()), None);
+
+        // Other versions are cached separately, pushing out the least recently used hash
+        cache.get_or_insert_with(&Hash512([1; 8]), 2, 1, 1, || proof(3));
+        assert_eq!(cache.get_or_insert_with(&Hash512([2; 8]), 2, 0, 1, || unreachable!()), None);
+        assert_eq!(cache.get_or_insert_with(&Hash512([1; 8]), 2, 0, 1, || proof(4)), proof(4));
+        assert_eq!(cache.stats(), ProofCacheStats { capacity: 2, entries: 2, hits: 3, misses: 4, hit_rate: 3.0 / 7.0 });
+
+        cache.clear();
+        assert_eq!(cache.get_or_insert_with(&Hash512([1; 8]), 2, 0, 1, || proof(5)), proof(5));
+        assert_eq!(cache.stats().entries, 1);
+
+        // So is the same version with more leaves
+        assert_eq!(cache.get_or_insert_with(&Hash512([1; 8]), 2, 0, 2, || proof(6)), proof(6));
+        assert_eq!(cache.stats().entries, 2);
+    }
+}
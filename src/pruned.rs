@@ -0,0 +1,134 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use crate::storage::{Hash512, Hash512Ops};
+
+// Levels of a tree below the first level kept in memory once it is pruned to disk. A proof reads the block of
+// 2^BLOCK_LEVELS leaves holding its leaf, 4 KiB, and recomputes these levels from it.
+pub const BLOCK_LEVELS: usize = 6;
+const BLOCK_LEAVES: usize = 1 << BLOCK_LEVELS;
+const RECORD_SIZE: usize = 64;
+
+// Leaves of a pruned tree in `LEAF_ORDER`, as a file of raw 64 byte records. Like in the cold index, the first leaf
+// of every block stays in memory, so finding a leaf reads a single block. The file belongs to the tree and is
+// removed once the tree is dropped.
+#[derive(Debug)]
+pub struct LeafFile {
+    path: PathBuf,
+    file: Mutex<File>,
+    fences: Vec<Hash512>,
+    len: usize,
+}
+
+impl LeafFile {
+    pub fn path(dir: &Path, version: u64) -> PathBuf {
+        dir.join(format!("pruned-{}.bin", version))
+    }
+
+    // Remove the files of trees published before a restart, which are rebuilt rather than reloaded
+    pub fn remove_all(dir: &Path) -> io::Result<()> {
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            if name.starts_with("pruned-") && (name.ends_with(".bin") || name.ends_with(".bin.tmp")) {
+                std::fs::remove_file(entry.path())?;
+            }
+        }
+        Ok(())
+    }
+
+    // Write `leaves`, which have to be in `LEAF_ORDER`, to `path` and open it
+    pub fn write(path: &Path, leaves: &[Hash512]) -> io::Result<Self> {
+        let tmp_path = path.with_extension("bin.tmp");
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        for leaf in leaves {
+            writer.write_all(&leaf.to_bytes())?;
+        }
+        writer.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        std::fs::rename(&tmp_path, path)?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            file: Mutex::new(File::open(path)?),
+            fences: leaves.iter().step_by(BLOCK_LEAVES).copied().collect(),
+            len: leaves.len(),
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // Position of `leaf`, `None` if it isn't in the file
+    pub fn position(&self, leaf: &Hash512) -> io::Result<Option<usize>> {
+        let block = match self.fences.partition_point(|first| first <= leaf) {
+            0 => return Ok(None),
+            after => after - 1,
+        };
+        let leaves = self.block(block)?;
+        Ok(leaves.binary_search(leaf).ok().map(|index| block * BLOCK_LEAVES + index))
+    }
+
+    // Leaves of the block with the given index, the last one may be shorter
+    pub fn block(&self, block: usize) -> io::Result<Vec<Hash512>> {
+        let leaves = BLOCK_LEAVES.min(self.len.saturating_sub(block * BLOCK_LEAVES));
+        let mut bytes = vec![0u8; leaves * RECORD_SIZE];
+        {
+            let mut file = self.file.lock().unwrap();
+            file.seek(SeekFrom::Start((block * BLOCK_LEAVES * RECORD_SIZE) as u64))?;
+            file.read_exact(&mut bytes)?;
+        }
+        Ok(bytes.chunks_exact(RECORD_SIZE).map(|record| Hash512::from_bytes(record).unwrap()).collect())
+    }
+
+    // Bytes of the fence pointers, the leaves themselves stay on disk
+    pub fn memory_usage(&self) -> usize {
+        self.fences.capacity() * size_of::<Hash512>()
+    }
+}
+
+impl Drop for LeafFile {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            eprintln!("Failed to remove {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_leaf_file() {
+        let dir = std::env::temp_dir().join(format!("timestamping-pruned-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut leaves: Vec<Hash512> = (0..1000).map(|_| Hash512(rand::random())).collect();
+        leaves.sort_unstable();
+        let path = LeafFile::path(&dir, 3);
+        let file = LeafFile::write(&path, &leaves).unwrap();
+        assert_eq!(file.len(), 1000);
+        assert_eq!(file.memory_usage(), 16 * size_of::<Hash512>());
+        for (index, leaf) in leaves.iter().enumerate() {
+            assert_eq!(file.position(leaf).unwrap(), Some(index));
+        }
+        assert_eq!(file.position(&Hash512([0; 8])).unwrap(), None);
+        assert_eq!(file.position(&Hash512(rand::random())).unwrap(), None);
+        assert_eq!(file.block(15).unwrap(), leaves[960..]);
+
+        // Dropping the tree's file removes it, leftovers of a restart are removed as a whole
+        drop(file);
+        assert!(!path.exists());
+        std::fs::write(LeafFile::path(&dir, 4), b"").unwrap();
+        std::fs::write(dir.join("leaves-4.bin"), b"").unwrap();
+        LeafFile::remove_all(&dir).unwrap();
+        assert!(!LeafFile::path(&dir, 4).exists());
+        assert!(dir.join("leaves-4.bin").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
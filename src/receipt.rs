@@ -0,0 +1,837 @@
+use ed25519_dalek::{Signer, Verifier};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+#[cfg(feature = "server")]
+use utoipa::ToSchema;
+use crate::verify::{Hash512, Hash512Ops, HashAlgorithm, LeafEncoding, decode_hex, encode_hex, hasher_from_name, verify_proof_with_hasher};
+
+pub use ed25519_dalek::{SigningKey, VerifyingKey};
+
+// Start of every binary receipt, followed by the format version
+pub const MAGIC: &[u8; 4] = b"TSRC";
+pub const FORMAT_VERSION: u8 = 4;
+// Receipts of this version have no time source and are still read
+const FIRST_FORMAT_VERSION: u8 = 1;
+// Receipts before this version have no hash algorithm, their hashes are SHA-512 digests
+const HASH_ALGORITHM_FORMAT_VERSION: u8 = 3;
+// Receipts before this version have no cosignatures
+const COSIGNATURE_FORMAT_VERSION: u8 = 4;
+
+// Prefix of the message signed for a tree head, so the signature can't be taken for anything else
+const TREE_HEAD_CONTEXT: &[u8] = b"timestamping tree head v1\n";
+const ACKNOWLEDGMENT_CONTEXT: &[u8] = b"timestamping acknowledgment v1\n";
+const SALT_MIGRATION_CONTEXT: &[u8] = b"timestamping salt migration v1\n";
+const COSIGNATURE_CONTEXT: &[u8] = b"timestamping cosignature v1\n";
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum ReceiptError {
+    #[error("Invalid receipt: {0}")]
+    InvalidFormat(&'static str),
+    #[error("Unsupported receipt format version {0}")]
+    UnsupportedVersion(u8),
+    #[error("Unknown tree hasher {0}")]
+    UnknownTreeHasher(String),
+    // The proof doesn't lead from the hash to the root of the receipt
+    #[error("Merkle proof doesn't match the root of the receipt")]
+    InvalidProof,
+    // The receipt is missing the tree head fields or the signature needed to check it
+    #[error("Receipt has no signed tree head")]
+    Unsigned,
+    #[error("Tree head signature is invalid for the trusted key")]
+    InvalidSignature,
+    // The digest of the document isn't the hash the receipt is for
+    #[error("Receipt is for another document")]
+    DigestMismatch,
+    #[error("Receipt has no cosignature of the witness")]
+    NotCosigned,
+}
+
+// Message a server signs for every published tree head. The summary of how the timestamp was obtained,
+// see `clock::TimeAttestation::summary`, is appended if the server recorded one.
+pub fn tree_head_message(tree_hasher: &str, version: u64, leaf_count: u64, timestamp: u64, root: &Hash512, time_source: Option<&str>) -> Vec<u8> {
+    let mut message = TREE_HEAD_CONTEXT.to_vec();
+    message.push(tree_hasher.len() as u8);
+    message.extend_from_slice(tree_hasher.as_bytes());
+    message.extend_from_slice(&version.to_be_bytes());
+    message.extend_from_slice(&leaf_count.to_be_bytes());
+    message.extend_from_slice(&timestamp.to_be_bytes());
+    message.extend_from_slice(&root.to_bytes());
+    if let Some(time_source) = time_source {
+        message.extend_from_slice(&(time_source.len() as u16).to_be_bytes());
+        message.extend_from_slice(time_source.as_bytes());
+    }
+    message
+}
+
+pub fn sign_tree_head(key: &SigningKey, tree_hasher: &str, version: u64, leaf_count: u64, timestamp: u64, root: &Hash512, time_source: Option<&str>) -> Vec<u8> {
+    key.sign(&tree_head_message(tree_hasher, version, leaf_count, timestamp, root, time_source)).to_bytes().to_vec()
+}
+
+// Message a server signs when it accepts a hash, before any tree includes it
+pub fn acknowledgment_message(hash: &Hash512, leaf_version: u8, timestamp: u64, sequence: Option<u64>) -> Vec<u8> {
+    let mut message = ACKNOWLEDGMENT_CONTEXT.to_vec();
+    message.extend_from_slice(&hash.to_bytes());
+    message.push(leaf_version);
+    message.extend_from_slice(&timestamp.to_be_bytes());
+    write_optional(&mut message, sequence.map(u64::to_be_bytes).as_ref().map(|bytes| &bytes[..]));
+    message
+}
+
+// Signed evidence that a server accepted a hash at `timestamp`, the first phase of timestamping. Once a tree
+// includes the hash, `/wait` or `/check` with the same hash and leaf version upgrade it to a `Receipt`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(ToSchema))]
+pub struct Acknowledgment {
+    #[cfg_attr(feature = "server", schema(value_type = String))]
+    pub hash: Hash512,
+    pub leaf_version: u8,
+    // Unix time of the server's clock when it accepted the hash
+    pub timestamp: u64,
+    // Number the hash was stored with, see `storage::HashStore::sequence_with_encoding`, missing if it wasn't stored
+    pub sequence: Option<u64>,
+    // Ed25519 signature over `acknowledgment_message`
+    #[serde(serialize_with = "serialize_bytes", deserialize_with = "deserialize_bytes")]
+    #[cfg_attr(feature = "server", schema(value_type = String))]
+    pub signature: Vec<u8>,
+}
+
+impl Acknowledgment {
+    pub fn sign(key: &SigningKey, hash: Hash512, leaf_encoding: LeafEncoding, timestamp: u64, sequence: Option<u64>) -> Self {
+        let signature = key.sign(&acknowledgment_message(&hash, leaf_encoding.version(), timestamp, sequence)).to_bytes().to_vec();
+        Self { hash, leaf_version: leaf_encoding.version(), timestamp, sequence, signature }
+    }
+
+    // Check that the server with `trusted_key` signed the acknowledgment
+    pub fn verify(&self, trusted_key: &VerifyingKey) -> Result<(), ReceiptError> {
+        let signature = ed25519_dalek::Signature::from_slice(&self.signature).map_err(|_| ReceiptError::InvalidSignature)?;
+        let message = acknowledgment_message(&self.hash, self.leaf_version, self.timestamp, self.sequence);
+        trusted_key.verify(&message, &signature).map_err(|_| ReceiptError::InvalidSignature)
+    }
+}
+
+// Message a server signs when it moves its hashes to a new salt, see `SaltMigration`
+pub fn salt_migration_message(migration: &SaltMigration) -> Vec<u8> {
+    let mut message = SALT_MIGRATION_CONTEXT.to_vec();
+    message.push(migration.tree_hasher.len() as u8);
+    message.extend_from_slice(migration.tree_hasher.as_bytes());
+    message.extend_from_slice(&migration.old_version.to_be_bytes());
+    message.extend_from_slice(&migration.old_leaf_count.to_be_bytes());
+    message.extend_from_slice(&migration.old_root.to_bytes());
+    message.extend_from_slice(&migration.new_salt.to_bytes());
+    message.extend_from_slice(&migration.new_leaf_count.to_be_bytes());
+    message.extend_from_slice(&migration.new_root.to_bytes());
+    message.extend_from_slice(&migration.missing_hashes.to_be_bytes());
+    message.extend_from_slice(&migration.timestamp.to_be_bytes());
+    message
+}
+
+// Signed link from a published tree to the first tree of a store with a new salt, made when the old salt is
+// suspected compromised. Receipts of the old tree stay valid against `old_root`. Every leaf of `new_root` is a hash
+// that was stored under the old salt, `missing_hashes` of the stored hashes weren't carried over.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(ToSchema))]
+pub struct SaltMigration {
+    pub tree_hasher: String,
+    // Latest tree of the old store when it was migrated
+    pub old_version: u64,
+    pub old_leaf_count: u64,
+    #[cfg_attr(feature = "server", schema(value_type = String))]
+    pub old_root: Hash512,
+    #[cfg_attr(feature = "server", schema(value_type = String))]
+    pub new_salt: Hash512,
+    pub new_leaf_count: u64,
+    #[cfg_attr(feature = "server", schema(value_type = String))]
+    pub new_root: Hash512,
+    pub missing_hashes: u64,
+    pub timestamp: u64,
+    // Ed25519 signature over `salt_migration_message`
+    #[serde(serialize_with = "serialize_bytes", deserialize_with = "deserialize_bytes")]
+    #[cfg_attr(feature = "server", schema(value_type = String))]
+    pub signature: Vec<u8>,
+}
+
+impl SaltMigration {
+    // Set `signature` to the signature of `key` over the other fields
+    pub fn sign(mut self, key: &SigningKey) -> Self {
+        self.signature = key.sign(&salt_migration_message(&self)).to_bytes().to_vec();
+        self
+    }
+
+    // Check that the server with `trusted_key` signed the migration
+    pub fn verify(&self, trusted_key: &VerifyingKey) -> Result<(), ReceiptError> {
+        let signature = ed25519_dalek::Signature::from_slice(&self.signature).map_err(|_| ReceiptError::InvalidSignature)?;
+        trusted_key.verify(&salt_migration_message(self), &signature).map_err(|_| ReceiptError::InvalidSignature)
+    }
+}
+
+// Message a peer signs when it cosigns the tree head with `tree_head_message`, see `Cosignature`
+pub fn cosignature_message(tree_head: &[u8], cosigned_at: u64) -> Vec<u8> {
+    let mut message = COSIGNATURE_CONTEXT.to_vec();
+    message.extend_from_slice(&cosigned_at.to_be_bytes());
+    message.extend_from_slice(tree_head);
+    message
+}
+
+// Signature of another timestamping server over a tree head, made when it received the head at `cosigned_at`
+// by its own clock. It only cosigns heads with a timestamp close to that, so a root can't be back-dated
+// without the peers noticing.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(ToSchema))]
+pub struct Cosignature {
+    // Ed25519 key of the peer, 32 bytes
+    #[serde(serialize_with = "serialize_bytes", deserialize_with = "deserialize_bytes")]
+    #[cfg_attr(feature = "server", schema(value_type = String))]
+    pub public_key: Vec<u8>,
+    pub cosigned_at: u64,
+    // Ed25519 signature over `cosignature_message`
+    #[serde(serialize_with = "serialize_bytes", deserialize_with = "deserialize_bytes")]
+    #[cfg_attr(feature = "server", schema(value_type = String))]
+    pub signature: Vec<u8>,
+}
+
+impl Cosignature {
+    pub fn sign(key: &SigningKey, tree_head: &[u8], cosigned_at: u64) -> Self {
+        Self {
+            public_key: key.verifying_key().to_bytes().to_vec(),
+            cosigned_at,
+            signature: key.sign(&cosignature_message(tree_head, cosigned_at)).to_bytes().to_vec(),
+        }
+    }
+
+    // Check that the peer with `witness_key` cosigned the tree head with `tree_head_message`
+    pub fn verify(&self, witness_key: &VerifyingKey, tree_head: &[u8]) -> Result<(), ReceiptError> {
+        if self.public_key != witness_key.as_bytes() {
+            return Err(ReceiptError::InvalidSignature);
+        }
+        let signature = ed25519_dalek::Signature::from_slice(&self.signature).map_err(|_| ReceiptError::InvalidSignature)?;
+        witness_key.verify(&cosignature_message(tree_head, self.cosigned_at), &signature).map_err(|_| ReceiptError::InvalidSignature)
+    }
+}
+
+pub(crate) fn serialize_bytes<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&encode_hex(bytes))
+}
+
+pub(crate) fn deserialize_bytes<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+    decode_hex(String::deserialize(deserializer)?.as_bytes()).ok_or(serde::de::Error::custom("invalid hex"))
+}
+
+// Everything needed to show that a hash was included in a published tree, without the server.
+// The tree head fields are optional because receipts written before they were recorded lack them,
+// such receipts can only be checked against a root from a trusted source.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Receipt {
+    // The digest as stored, see `HashAlgorithm::normalize`
+    pub hash: Hash512,
+    // Algorithm the submitted digest was computed with, needed to recompute `hash` from the document
+    pub hash_algorithm: HashAlgorithm,
+    pub leaf_encoding: LeafEncoding,
+    // Name of the hasher the tree was built with, see `storage::hasher_from_name`
+    pub tree_hasher: String,
+    // In the format of `MerkleTree::get`, starting with the hash and the salt
+    pub merkle_proof: Vec<(Hash512, Hash512)>,
+    pub merkle_tree_root: Hash512,
+    pub tree_version: Option<u64>,
+    pub leaf_count: Option<u64>,
+    pub timestamp: Option<u64>,
+    // Ed25519 signature over `tree_head_message`
+    pub signature: Option<Vec<u8>>,
+    pub anchor_txid: Option<String>,
+    pub server_version: Option<String>,
+    // Signed summary of the clock the timestamp came from, e.g. "ntp pool.ntp.org:123 ±12ms"
+    pub time_source: Option<String>,
+    // Cosignatures of peers over the tree head, see `verify_cosignature`
+    pub cosignatures: Vec<Cosignature>,
+}
+
+// JSON form, all hashes as hex. Receipts written by earlier versions of the CLI have the same fields.
+#[derive(Debug, Serialize, Deserialize)]
+struct ReceiptJson {
+    #[serde(default = "default_format_version")]
+    format_version: u8,
+    hash: String,
+    leaf_version: u8,
+    merkle_proof: Vec<(String, String)>,
+    merkle_tree_root: String,
+    last_tree_update: Option<u64>,
+    #[serde(default)]
+    tree_version: Option<u64>,
+    #[serde(default)]
+    leaf_count: Option<u64>,
+    // Receipts from before the tree hasher was recorded were always built with SHA-512
+    #[serde(default = "default_tree_hasher")]
+    tree_hasher: String,
+    #[serde(default)]
+    salt: Option<String>,
+    #[serde(default)]
+    signature: Option<String>,
+    #[serde(default)]
+    anchor_txid: Option<String>,
+    #[serde(default)]
+    server_version: Option<String>,
+    #[serde(default)]
+    time_source: Option<String>,
+    #[serde(default)]
+    hash_algorithm: HashAlgorithm,
+    #[serde(default)]
+    cosignatures: Vec<Cosignature>,
+}
+
+fn default_format_version() -> u8 {
+    FORMAT_VERSION
+}
+
+fn default_tree_hasher() -> String {
+    "sha512".to_string()
+}
+
+// Only hex, the encoding receipts are written with, not the others `Hash512::from_str` takes
+fn hash_from_hex(hex: &str) -> Result<Hash512, ReceiptError> {
+    if hex.len() != 128 {
+        return Err(ReceiptError::InvalidFormat("hash is not 64 bytes"));
+    }
+    hex.parse().map_err(|_| ReceiptError::InvalidFormat("invalid hex"))
+}
+
+impl Receipt {
+    // Canonical binary form, all integers big-endian:
+    // magic, format version, leaf version, tree hasher (u8 length + name), hash, proof (u16 count + pairs), root,
+    // then tree version, leaf count and timestamp (each a presence byte + u64), signature (presence byte + 64 bytes),
+    // anchor txid, server version and time source (each a presence byte + u16 length + UTF-8), hash algorithm (u8 length + name),
+    // cosignatures (u8 count + public key, cosigned at as u64 and signature, 104 bytes each).
+    // Receipts of format version 1 end before the time source, those of version 2 before the hash algorithm,
+    // those of version 3 before the cosignatures.
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.push(FORMAT_VERSION);
+        bytes.push(self.leaf_encoding.version());
+        bytes.push(self.tree_hasher.len() as u8);
+        bytes.extend_from_slice(self.tree_hasher.as_bytes());
+        bytes.extend_from_slice(&self.hash.to_bytes());
+        bytes.extend_from_slice(&(self.merkle_proof.len() as u16).to_be_bytes());
+        for (left, right) in &self.merkle_proof {
+            bytes.extend_from_slice(&left.to_bytes());
+            bytes.extend_from_slice(&right.to_bytes());
+        }
+        bytes.extend_from_slice(&self.merkle_tree_root.to_bytes());
+        for value in [self.tree_version, self.leaf_count, self.timestamp] {
+            write_optional(&mut bytes, value.map(u64::to_be_bytes).as_ref().map(|value| &value[..]));
+        }
+        write_optional(&mut bytes, self.signature.as_deref());
+        for text in [&self.anchor_txid, &self.server_version, &self.time_source] {
+            let field = text.as_ref().map(|text| [&(text.len() as u16).to_be_bytes()[..], text.as_bytes()].concat());
+            write_optional(&mut bytes, field.as_deref());
+        }
+        bytes.push(self.hash_algorithm.name().len() as u8);
+        bytes.extend_from_slice(self.hash_algorithm.name().as_bytes());
+        bytes.push(self.cosignatures.len() as u8);
+        for cosignature in &self.cosignatures {
+            bytes.extend_from_slice(&cosignature.public_key);
+            bytes.extend_from_slice(&cosignature.cosigned_at.to_be_bytes());
+            bytes.extend_from_slice(&cosignature.signature);
+        }
+        bytes
+    }
+
+    // Parse a receipt in the binary form of `serialize` or the JSON form of `to_json`
+    pub fn parse(bytes: &[u8]) -> Result<Self, ReceiptError> {
+        if bytes.starts_with(MAGIC) {
+            Self::parse_binary(&bytes[MAGIC.len()..])
+        } else {
+            let json = std::str::from_utf8(bytes).map_err(|_| ReceiptError::InvalidFormat("neither binary nor JSON"))?;
+            Self::from_json(json)
+        }
+    }
+
+    fn parse_binary(bytes: &[u8]) -> Result<Self, ReceiptError> {
+        let mut reader = Reader { bytes };
+        let version = reader.byte()?;
+        if !(FIRST_FORMAT_VERSION..=FORMAT_VERSION).contains(&version) {
+            return Err(ReceiptError::UnsupportedVersion(version));
+        }
+        let leaf_version = reader.byte()?;
+        let leaf_encoding = LeafEncoding::from_version(leaf_version)
+            .ok_or(ReceiptError::InvalidFormat("unknown leaf encoding version"))?;
+        let hasher_len = reader.byte()? as usize;
+        let tree_hasher = reader.text(hasher_len)?;
+        let hash = reader.hash()?;
+        let proof_len = u16::from_be_bytes(reader.take(2)?.try_into().unwrap());
+        let merkle_proof = (0..proof_len)
+            .map(|_| Ok((reader.hash()?, reader.hash()?)))
+            .collect::<Result<Vec<_>, ReceiptError>>()?;
+        let merkle_tree_root = reader.hash()?;
+        let mut numbers = [None; 3];
+        for number in &mut numbers {
+            if reader.present()? {
+                *number = Some(u64::from_be_bytes(reader.take(8)?.try_into().unwrap()));
+            }
+        }
+        let signature = if reader.present()? { Some(reader.take(64)?.to_vec()) } else { None };
+        let mut texts = [None, None, None];
+        let text_count = if version == FIRST_FORMAT_VERSION { 2 } else { 3 };
+        for text in &mut texts[..text_count] {
+            if reader.present()? {
+                let len = u16::from_be_bytes(reader.take(2)?.try_into().unwrap()) as usize;
+                *text = Some(reader.text(len)?);
+            }
+        }
+        let hash_algorithm = if version < HASH_ALGORITHM_FORMAT_VERSION {
+            HashAlgorithm::Sha512
+        } else {
+            let len = reader.byte()? as usize;
+            HashAlgorithm::from_name(&reader.text(len)?).ok_or(ReceiptError::InvalidFormat("unknown hash algorithm"))?
+        };
+        let cosignature_count = if version < COSIGNATURE_FORMAT_VERSION { 0 } else { reader.byte()? };
+        let cosignatures = (0..cosignature_count)
+            .map(|_| Ok(Cosignature {
+                public_key: reader.take(32)?.to_vec(),
+                cosigned_at: u64::from_be_bytes(reader.take(8)?.try_into().unwrap()),
+                signature: reader.take(64)?.to_vec(),
+            }))
+            .collect::<Result<Vec<_>, ReceiptError>>()?;
+        if !reader.bytes.is_empty() {
+            return Err(ReceiptError::InvalidFormat("trailing bytes"));
+        }
+
+        let [tree_version, leaf_count, timestamp] = numbers;
+        let [anchor_txid, server_version, time_source] = texts;
+        Ok(Self {
+            hash,
+            hash_algorithm,
+            leaf_encoding,
+            tree_hasher,
+            merkle_proof,
+            merkle_tree_root,
+            tree_version,
+            leaf_count,
+            timestamp,
+            signature,
+            anchor_txid,
+            server_version,
+            time_source,
+            cosignatures,
+        })
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(&self.receipt_json()).unwrap()
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, ReceiptError> {
+        let json: ReceiptJson = serde_json::from_str(json).map_err(|_| ReceiptError::InvalidFormat("invalid JSON"))?;
+        Self::from_receipt_json(json)
+    }
+
+    fn receipt_json(&self) -> ReceiptJson {
+        ReceiptJson {
+            format_version: FORMAT_VERSION,
+            hash: self.hash.to_string(),
+            leaf_version: self.leaf_encoding.version(),
+            merkle_proof: self.merkle_proof.iter().map(|(left, right)| (left.to_string(), right.to_string())).collect(),
+            merkle_tree_root: self.merkle_tree_root.to_string(),
+            last_tree_update: self.timestamp,
+            tree_version: self.tree_version,
+            leaf_count: self.leaf_count,
+            tree_hasher: self.tree_hasher.clone(),
+            salt: self.salt().map(|salt| salt.to_string()),
+            signature: self.signature.as_deref().map(encode_hex),
+            anchor_txid: self.anchor_txid.clone(),
+            server_version: self.server_version.clone(),
+            time_source: self.time_source.clone(),
+            hash_algorithm: self.hash_algorithm,
+            cosignatures: self.cosignatures.clone(),
+        }
+    }
+
+    fn from_receipt_json(json: ReceiptJson) -> Result<Self, ReceiptError> {
+        if !(FIRST_FORMAT_VERSION..=FORMAT_VERSION).contains(&json.format_version) {
+            return Err(ReceiptError::UnsupportedVersion(json.format_version));
+        }
+        let receipt = Self {
+            hash: hash_from_hex(&json.hash)?,
+            hash_algorithm: json.hash_algorithm,
+            leaf_encoding: LeafEncoding::from_version(json.leaf_version)
+                .ok_or(ReceiptError::InvalidFormat("unknown leaf encoding version"))?,
+            tree_hasher: json.tree_hasher,
+            merkle_proof: json.merkle_proof.iter()
+                .map(|(left, right)| Ok((hash_from_hex(left)?, hash_from_hex(right)?)))
+                .collect::<Result<_, ReceiptError>>()?,
+            merkle_tree_root: hash_from_hex(&json.merkle_tree_root)?,
+            tree_version: json.tree_version,
+            leaf_count: json.leaf_count,
+            timestamp: json.last_tree_update,
+            signature: json.signature.as_deref().map(|signature| decode_hex(signature.as_bytes()).ok_or(ReceiptError::InvalidFormat("invalid hex"))).transpose()?,
+            anchor_txid: json.anchor_txid,
+            server_version: json.server_version,
+            time_source: json.time_source,
+            cosignatures: json.cosignatures,
+        };
+        // Binary receipts can't hold anything else, so a JSON receipt converts losslessly
+        if receipt.signature.as_ref().is_some_and(|signature| signature.len() != 64) {
+            return Err(ReceiptError::InvalidFormat("signature is not 64 bytes"));
+        }
+        if receipt.cosignatures.iter().any(|cosignature| cosignature.public_key.len() != 32 || cosignature.signature.len() != 64) {
+            return Err(ReceiptError::InvalidFormat("cosignature key is not 32 bytes or signature not 64 bytes"));
+        }
+        if receipt.tree_hasher.len() > u8::MAX as usize
+            || receipt.merkle_proof.len() > u16::MAX as usize
+            || receipt.cosignatures.len() > u8::MAX as usize
+            || [&receipt.anchor_txid, &receipt.server_version, &receipt.time_source].into_iter().flatten().any(|text| text.len() > u16::MAX as usize)
+        {
+            return Err(ReceiptError::InvalidFormat("field too long"));
+        }
+        // The salt is only stored for readers of the JSON, the proof is what counts
+        if let Some(salt) = &json.salt
+            && Some(hash_from_hex(salt)?) != receipt.salt()
+        {
+            return Err(ReceiptError::InvalidFormat("salt doesn't match the proof"));
+        }
+        Ok(receipt)
+    }
+
+    // Salt of the store the hash was added to, taken from the proof
+    pub fn salt(&self) -> Option<Hash512> {
+        self.merkle_proof.first().map(|(_, salt)| *salt)
+    }
+
+    // Check that the proof leads from the hash to the root of the receipt.
+    // On its own this only shows inclusion if the root is known to be genuine, see `verify`.
+    pub fn verify_proof(&self) -> Result<(), ReceiptError> {
+        let hasher = hasher_from_name(&self.tree_hasher).ok_or_else(|| ReceiptError::UnknownTreeHasher(self.tree_hasher.clone()))?;
+        let valid = self.merkle_proof.first().is_some_and(|(hash, _)| *hash == self.hash)
+            && verify_proof_with_hasher(&self.hash, &self.merkle_proof, &self.merkle_tree_root, self.leaf_encoding, &*hasher);
+        if valid { Ok(()) } else { Err(ReceiptError::InvalidProof) }
+    }
+
+    // Check that the receipt is for the document with `digest`, computed with the receipt's hash algorithm
+    pub fn verify_digest(&self, digest: &[u8]) -> Result<(), ReceiptError> {
+        match self.hash_algorithm.normalize(digest) {
+            Ok(hash) if hash == self.hash => Ok(()),
+            _ => Err(ReceiptError::DigestMismatch),
+        }
+    }
+
+    // Check the proof and that the server with `trusted_key` signed the tree head with this root
+    pub fn verify(&self, trusted_key: &VerifyingKey) -> Result<(), ReceiptError> {
+        self.verify_proof()?;
+        let (Some(message), Some(signature)) = (self.tree_head_message(), &self.signature) else {
+            return Err(ReceiptError::Unsigned);
+        };
+        let signature = ed25519_dalek::Signature::from_slice(signature).map_err(|_| ReceiptError::InvalidSignature)?;
+        trusted_key.verify(&message, &signature).map_err(|_| ReceiptError::InvalidSignature)
+    }
+
+    // Check that the peer with `witness_key` cosigned the tree head of the receipt and return when it did.
+    // Only shows the tree existed then together with `verify`, which checks the proof and the server's signature.
+    pub fn verify_cosignature(&self, witness_key: &VerifyingKey) -> Result<u64, ReceiptError> {
+        let message = self.tree_head_message().ok_or(ReceiptError::Unsigned)?;
+        let cosignature = self.cosignatures.iter()
+            .find(|cosignature| cosignature.public_key == witness_key.as_bytes())
+            .ok_or(ReceiptError::NotCosigned)?;
+        cosignature.verify(witness_key, &message)?;
+        Ok(cosignature.cosigned_at)
+    }
+
+    fn tree_head_message(&self) -> Option<Vec<u8>> {
+        let (Some(version), Some(leaf_count), Some(timestamp)) = (self.tree_version, self.leaf_count, self.timestamp) else {
+            return None;
+        };
+        Some(tree_head_message(&self.tree_hasher, version, leaf_count, timestamp, &self.merkle_tree_root, self.time_source.as_deref()))
+    }
+}
+
+// The JSON form of `to_json` in human-readable formats, the binary form of `Receipt::serialize` otherwise
+impl Serialize for Receipt {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            self.receipt_json().serialize(serializer)
+        } else {
+            serializer.serialize_bytes(&Receipt::serialize(self))
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Receipt {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct BinaryReceiptVisitor;
+
+        impl serde::de::Visitor<'_> for BinaryReceiptVisitor {
+            type Value = Receipt;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("a binary receipt")
+            }
+
+            fn visit_bytes<E: serde::de::Error>(self, bytes: &[u8]) -> Result<Receipt, E> {
+                Receipt::parse(bytes).map_err(E::custom)
+            }
+        }
+
+        if deserializer.is_human_readable() {
+            Self::from_receipt_json(ReceiptJson::deserialize(deserializer)?).map_err(serde::de::Error::custom)
+        } else {
+            deserializer.deserialize_bytes(BinaryReceiptVisitor)
+        }
+    }
+}
+
+fn write_optional(bytes: &mut Vec<u8>, value: Option<&[u8]>) {
+    match value {
+        Some(value) => {
+            bytes.push(1);
+            bytes.extend_from_slice(value);
+        }
+        None => bytes.push(0),
+    }
+}
+
+// Cursor over the remaining bytes of a binary receipt
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], ReceiptError> {
+        if self.bytes.len() < len {
+            return Err(ReceiptError::InvalidFormat("truncated"));
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn byte(&mut self) -> Result<u8, ReceiptError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn present(&mut self) -> Result<bool, ReceiptError> {
+        match self.byte()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(ReceiptError::InvalidFormat("invalid presence flag")),
+        }
+    }
+
+    fn hash(&mut self) -> Result<Hash512, ReceiptError> {
+        Ok(Hash512::from_bytes(self.take(64)?).unwrap())
+    }
+
+    fn text(&mut self, len: usize) -> Result<String, ReceiptError> {
+        String::from_utf8(self.take(len)?.to_vec()).map_err(|_| ReceiptError::InvalidFormat("text is not UTF-8"))
+    }
+}
+
+// The receipts are built from trees of the server's store
+#[cfg(all(test, feature = "server"))]
+mod tests {
+    use super::*;
+    use crate::storage::MerkleTree;
+
+    const SALT: Hash512 = Hash512([7; 8]);
+
+    fn signed_receipt(key: &SigningKey) -> Receipt {
+        let hashes: Vec<Hash512> = (0..5).map(|i| Hash512([i, 1, 2, 3, 4, 5, 6, 7])).collect();
+        let tree = MerkleTree::new(hashes.iter().map(|hash| LeafEncoding::V2.leaf(hash, &SALT)).collect(), SALT);
+        let root = tree.root().unwrap();
+        Receipt {
+            hash: hashes[3],
+            hash_algorithm: HashAlgorithm::Sha3_512,
+            leaf_encoding: LeafEncoding::V2,
+            tree_hasher: "sha512".to_string(),
+            merkle_proof: tree.get_with_encoding(&hashes[3], LeafEncoding::V2).unwrap(),
+            merkle_tree_root: root,
+            tree_version: Some(4),
+            leaf_count: Some(5),
+            timestamp: Some(1_700_000_000),
+            signature: Some(sign_tree_head(key, "sha512", 4, 5, 1_700_000_000, &root, Some("ntp pool.ntp.org:123 ±12ms"))),
+            anchor_txid: None,
+            server_version: Some("0.1.0".to_string()),
+            time_source: Some("ntp pool.ntp.org:123 ±12ms".to_string()),
+            cosignatures: Vec::new(),
+        }
+    }
+
+    fn cosigned_receipt(key: &SigningKey, witness: &SigningKey) -> Receipt {
+        let receipt = signed_receipt(key);
+        let cosignature = Cosignature::sign(witness, &receipt.tree_head_message().unwrap(), 1_700_000_005);
+        Receipt { cosignatures: vec![cosignature], ..receipt }
+    }
+
+    #[test]
+    fn test_receipt_round_trip() {
+        let receipt = cosigned_receipt(&SigningKey::from_bytes(&[1; 32]), &SigningKey::from_bytes(&[3; 32]));
+        assert_eq!(Receipt::parse(&receipt.serialize()).unwrap(), receipt);
+        assert_eq!(Receipt::parse(receipt.to_json().as_bytes()).unwrap(), receipt);
+
+        let unsigned = Receipt { tree_version: None, leaf_count: None, timestamp: None, signature: None, server_version: None, time_source: None, cosignatures: Vec::new(), ..receipt.clone() };
+        assert_eq!(Receipt::parse(&unsigned.serialize()).unwrap(), unsigned);
+
+        // Version 3 receipts end before the cosignatures, version 2 receipts also before the hash algorithm,
+        // version 1 receipts also before the time source
+        let mut bytes = unsigned.serialize();
+        bytes.pop();
+        bytes[MAGIC.len()] = 3;
+        assert_eq!(Receipt::parse(&bytes).unwrap(), unsigned);
+        let unsigned = Receipt { hash_algorithm: HashAlgorithm::Sha512, ..unsigned };
+        let mut bytes = unsigned.serialize();
+        bytes.truncate(bytes.len() - 2 - "sha512".len());
+        bytes[MAGIC.len()] = 2;
+        assert_eq!(Receipt::parse(&bytes).unwrap(), unsigned);
+        bytes[MAGIC.len()] = FIRST_FORMAT_VERSION;
+        bytes.pop();
+        assert_eq!(Receipt::parse(&bytes).unwrap(), unsigned);
+
+        let mut bytes = receipt.serialize();
+        bytes.push(0);
+        assert_eq!(Receipt::parse(&bytes), Err(ReceiptError::InvalidFormat("trailing bytes")));
+        assert_eq!(Receipt::parse(&receipt.serialize()[..100]), Err(ReceiptError::InvalidFormat("truncated")));
+        bytes[MAGIC.len()] = FORMAT_VERSION + 1;
+        assert_eq!(Receipt::parse(&bytes), Err(ReceiptError::UnsupportedVersion(FORMAT_VERSION + 1)));
+    }
+
+    #[test]
+    fn test_receipt_serde() {
+        let receipt = cosigned_receipt(&SigningKey::from_bytes(&[1; 32]), &SigningKey::from_bytes(&[3; 32]));
+        let json = serde_json::to_value(&receipt).unwrap();
+        assert_eq!(json, serde_json::from_str::<serde_json::Value>(&receipt.to_json()).unwrap());
+        assert_eq!(serde_json::from_value::<Receipt>(json.clone()).unwrap(), receipt);
+
+        // Binary formats hold the binary receipt
+        let mut cbor = Vec::new();
+        ciborium::into_writer(&receipt, &mut cbor).unwrap();
+        assert!(cbor.ends_with(&receipt.serialize()));
+        assert_eq!(ciborium::from_reader::<Receipt, _>(&cbor[..]).unwrap(), receipt);
+
+        // Receipts are checked like by `from_json`
+        let mut tampered = json;
+        tampered["salt"] = serde_json::Value::String(Hash512([8; 8]).to_string());
+        let error = serde_json::from_value::<Receipt>(tampered).unwrap_err();
+        assert!(error.to_string().contains("salt doesn't match the proof"), "{}", error);
+    }
+
+    #[test]
+    fn test_receipt_verify() {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let receipt = signed_receipt(&key);
+        assert_eq!(receipt.verify_proof(), Ok(()));
+        assert_eq!(receipt.verify(&key.verifying_key()), Ok(()));
+        assert_eq!(receipt.verify(&SigningKey::from_bytes(&[2; 32]).verifying_key()), Err(ReceiptError::InvalidSignature));
+
+        // The signature covers the whole tree head, not only the root
+        let tampered = Receipt { timestamp: Some(1_800_000_000), ..receipt.clone() };
+        assert_eq!(tampered.verify_proof(), Ok(()));
+        assert_eq!(tampered.verify(&key.verifying_key()), Err(ReceiptError::InvalidSignature));
+
+        let tampered = Receipt { time_source: Some("roughtime".to_string()), ..receipt.clone() };
+        assert_eq!(tampered.verify(&key.verifying_key()), Err(ReceiptError::InvalidSignature));
+
+        let tampered = Receipt { hash: Hash512([9; 8]), ..receipt.clone() };
+        assert_eq!(tampered.verify(&key.verifying_key()), Err(ReceiptError::InvalidProof));
+
+        // The digest is normalized with the receipt's algorithm before it's compared
+        let digest = receipt.hash.to_bytes();
+        assert_eq!(receipt.verify_digest(&digest), Err(ReceiptError::DigestMismatch));
+        assert_eq!(Receipt { hash_algorithm: HashAlgorithm::Sha512, ..receipt.clone() }.verify_digest(&digest), Ok(()));
+        assert_eq!(receipt.verify_digest(&[0; 32]), Err(ReceiptError::DigestMismatch));
+
+        let unsigned = Receipt { signature: None, ..receipt };
+        assert_eq!(unsigned.verify(&key.verifying_key()), Err(ReceiptError::Unsigned));
+    }
+
+    #[test]
+    fn test_receipt_cosignature() {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let witness = SigningKey::from_bytes(&[3; 32]).verifying_key();
+        let receipt = cosigned_receipt(&key, &SigningKey::from_bytes(&[3; 32]));
+        assert_eq!(receipt.verify_cosignature(&witness), Ok(1_700_000_005));
+        assert_eq!(receipt.verify_cosignature(&key.verifying_key()), Err(ReceiptError::NotCosigned));
+        assert_eq!(signed_receipt(&key).verify_cosignature(&witness), Err(ReceiptError::NotCosigned));
+
+        // The cosignature covers the tree head and when it was made
+        let tampered = Receipt { timestamp: Some(1_600_000_000), ..receipt.clone() };
+        assert_eq!(tampered.verify_cosignature(&witness), Err(ReceiptError::InvalidSignature));
+        let mut tampered = receipt.clone();
+        tampered.cosignatures[0].cosigned_at = 1_800_000_000;
+        assert_eq!(tampered.verify_cosignature(&witness), Err(ReceiptError::InvalidSignature));
+
+        let unsigned = Receipt { leaf_count: None, ..receipt };
+        assert_eq!(unsigned.verify_cosignature(&witness), Err(ReceiptError::Unsigned));
+    }
+
+    #[test]
+    fn test_receipt_legacy_json() {
+        // Written by the CLI before receipts had a format version, leaf count or signature
+        let receipt = Receipt {
+            leaf_count: None,
+            signature: None,
+            time_source: None,
+            tree_hasher: "sha512".to_string(),
+            hash_algorithm: HashAlgorithm::Sha512,
+            ..signed_receipt(&SigningKey::from_bytes(&[1; 32]))
+        };
+        let mut json: serde_json::Value = serde_json::from_str(&receipt.to_json()).unwrap();
+        for field in ["format_version", "leaf_count", "signature", "anchor_txid", "tree_hasher", "time_source", "hash_algorithm", "cosignatures"] {
+            json.as_object_mut().unwrap().remove(field);
+        }
+        assert_eq!(Receipt::parse(json.to_string().as_bytes()).unwrap(), receipt);
+
+        json["salt"] = serde_json::Value::String(Hash512([0; 8]).to_string());
+        assert_eq!(Receipt::parse(json.to_string().as_bytes()), Err(ReceiptError::InvalidFormat("salt doesn't match the proof")));
+    }
+
+    #[test]
+    fn test_binding_fixture() {
+        // Issued by a server with the signing key [1; 32] for the SHA-256 of the document, the bindings' smoke
+        // tests verify the same receipt
+        use sha2::{Digest, Sha256};
+        let receipt = Receipt::parse(include_bytes!("../bindings/fixtures/receipt.json")).unwrap();
+        let document = include_bytes!("../bindings/fixtures/document.txt");
+        assert_eq!(receipt.verify(&SigningKey::from_bytes(&[1; 32]).verifying_key()), Ok(()));
+        assert_eq!(receipt.verify_digest(&Sha256::digest(document)), Ok(()));
+        assert_eq!(Receipt::parse(&receipt.serialize()).unwrap(), receipt);
+    }
+
+    #[test]
+    fn test_acknowledgment() {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let ack = Acknowledgment::sign(&key, Hash512([7; 8]), LeafEncoding::V2, 1_700_000_000, Some(41));
+        assert_eq!(ack.verify(&key.verifying_key()), Ok(()));
+        assert_eq!(ack.verify(&SigningKey::from_bytes(&[2; 32]).verifying_key()), Err(ReceiptError::InvalidSignature));
+
+        let json = serde_json::to_string(&ack).unwrap();
+        assert!(json.contains(&Hash512([7; 8]).to_string()));
+        assert_eq!(serde_json::from_str::<Acknowledgment>(&json).unwrap(), ack);
+
+        // The sequence number is signed too, also whether there is one
+        for tampered in [Acknowledgment { sequence: Some(40), ..ack.clone() }, Acknowledgment { sequence: None, ..ack.clone() }, Acknowledgment { timestamp: 1, ..ack.clone() }] {
+            assert_eq!(tampered.verify(&key.verifying_key()), Err(ReceiptError::InvalidSignature));
+        }
+        // Can't be passed off as a tree head signature
+        assert_ne!(acknowledgment_message(&ack.hash, 2, 0, None), tree_head_message("", 0, 0, 0, &ack.hash, None));
+    }
+
+    #[test]
+    fn test_salt_migration() {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let migration = SaltMigration {
+            tree_hasher: "sha512".to_string(),
+            old_version: 3,
+            old_leaf_count: 10,
+            old_root: Hash512([1; 8]),
+            new_salt: Hash512([2; 8]),
+            new_leaf_count: 9,
+            new_root: Hash512([3; 8]),
+            missing_hashes: 1,
+            timestamp: 1_700_000_000,
+            signature: Vec::new(),
+        }.sign(&key);
+        assert_eq!(migration.verify(&key.verifying_key()), Ok(()));
+        assert_eq!(migration.verify(&SigningKey::from_bytes(&[2; 32]).verifying_key()), Err(ReceiptError::InvalidSignature));
+
+        let json = serde_json::to_string(&migration).unwrap();
+        assert_eq!(serde_json::from_str::<SaltMigration>(&json).unwrap(), migration);
+
+        for tampered in [SaltMigration { new_root: Hash512([4; 8]), ..migration.clone() }, SaltMigration { missing_hashes: 0, ..migration.clone() }] {
+            assert_eq!(tampered.verify(&key.verifying_key()), Err(ReceiptError::InvalidSignature));
+        }
+    }
+}
@@ -0,0 +1,200 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use utoipa::ToSchema;
+use crate::storage::{Hash512, Hash512Ops, TreeHead, encode_hex};
+
+// Hash the first entry of a log links to
+pub const GENESIS_HASH: [u8; 32] = [0; 32];
+
+// A published tree head as recorded in the root log. Every entry links to the hash of the one before it,
+// so rewriting a root that was published once changes the hash of every later entry.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct RootLogEntry {
+    pub version: u64,
+    #[schema(value_type = Option<String>)]
+    pub root: Option<Hash512>,
+    pub tree_size: usize,
+    pub leaf_count: usize,
+    pub timestamp: u64,
+    pub signature: Option<Vec<u8>>,
+    // Hex encoded SHA-256 of the previous entry, zeros for the first one
+    pub previous_hash: String,
+    // Hex encoded SHA-256 of `previous_hash` and the fields above, see `entry_hash`
+    pub hash: String,
+}
+
+// SHA-256 over the previous hash and the entry's fields, integers big-endian and the optional ones
+// prefixed with whether they are present
+pub fn entry_hash(previous_hash: &[u8; 32], head: &TreeHead, signature: Option<&[u8]>) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(previous_hash);
+    hasher.update(head.version.to_be_bytes());
+    match &head.root {
+        Some(root) => {
+            hasher.update([1]);
+            hasher.update(root.to_bytes());
+        }
+        None => hasher.update([0]),
+    }
+    hasher.update((head.tree_size as u64).to_be_bytes());
+    hasher.update((head.leaf_count as u64).to_be_bytes());
+    hasher.update(head.timestamp.to_be_bytes());
+    match signature {
+        Some(signature) => {
+            hasher.update([1]);
+            hasher.update((signature.len() as u64).to_be_bytes());
+            hasher.update(signature);
+        }
+        None => hasher.update([0]),
+    }
+    hasher.finalize().into()
+}
+
+impl RootLogEntry {
+    pub fn new(previous_hash: &[u8; 32], head: &TreeHead, signature: Option<Vec<u8>>) -> Self {
+        let hash = entry_hash(previous_hash, head, signature.as_deref());
+        Self {
+            version: head.version,
+            root: head.root,
+            tree_size: head.tree_size,
+            leaf_count: head.leaf_count,
+            timestamp: head.timestamp,
+            signature,
+            previous_hash: encode_hex(previous_hash),
+            hash: encode_hex(&hash),
+        }
+    }
+
+    fn head(&self) -> TreeHead {
+        TreeHead { version: self.version, root: self.root, tree_size: self.tree_size, leaf_count: self.leaf_count, timestamp: self.timestamp }
+    }
+}
+
+// Hash of the last entry, or the index of the first one that doesn't link to the one before it or whose hash
+// doesn't match its fields
+pub fn verify_chain(entries: &[RootLogEntry]) -> Result<[u8; 32], usize> {
+    let mut previous_hash = GENESIS_HASH;
+    for (index, entry) in entries.iter().enumerate() {
+        let hash = entry_hash(&previous_hash, &entry.head(), entry.signature.as_deref());
+        if entry.previous_hash != encode_hex(&previous_hash) || entry.hash != encode_hex(&hash) {
+            return Err(index);
+        }
+        previous_hash = hash;
+    }
+    Ok(previous_hash)
+}
+
+// Hash-chained record of every published tree head, optionally persisted as one JSON object per line.
+// Unlike the epoch log, entries are never amended.
+#[derive(Debug)]
+pub struct RootLog {
+    entries: Vec<RootLogEntry>,
+    last_hash: [u8; 32],
+    file: Option<File>,
+}
+
+impl Default for RootLog {
+    fn default() -> Self {
+        Self { entries: Vec::new(), last_hash: GENESIS_HASH, file: None }
+    }
+}
+
+impl RootLog {
+    pub fn in_memory() -> Self {
+        Self::default()
+    }
+
+    // Load the entries stored at `path` and append new ones to it. Fails if the chain is broken.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).read(true).append(true).open(path)?;
+        let mut entries = Vec::new();
+        for line in BufReader::new(&file).lines() {
+            let line = line?;
+            if !line.trim().is_empty() {
+                entries.push(serde_json::from_str::<RootLogEntry>(&line).map_err(io::Error::other)?);
+            }
+        }
+        let last_hash = verify_chain(&entries).map_err(|index| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("entry {} of {} doesn't match the chain", index, path.display()))
+        })?;
+        Ok(Self { entries, last_hash, file: Some(file) })
+    }
+
+    // Entries are synced to disk before they are added
+    pub fn append(&mut self, head: &TreeHead, signature: Option<Vec<u8>>) -> io::Result<&RootLogEntry> {
+        let entry = RootLogEntry::new(&self.last_hash, head, signature);
+        if let Some(file) = &mut self.file {
+            let mut line = serde_json::to_string(&entry).map_err(io::Error::other)?;
+            line.push('\n');
+            file.write_all(line.as_bytes())?;
+            file.sync_all()?;
+        }
+        self.last_hash = entry_hash(&self.last_hash, head, entry.signature.as_deref());
+        self.entries.push(entry);
+        Ok(self.entries.last().unwrap())
+    }
+
+    pub fn entries(&self) -> &[RootLogEntry] {
+        &self.entries
+    }
+
+    pub fn page(&self, offset: usize, limit: usize) -> &[RootLogEntry] {
+        let start = offset.min(self.entries.len());
+        let end = start.saturating_add(limit).min(self.entries.len());
+        &self.entries[start..end]
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn head(version: u64) -> TreeHead {
+        TreeHead { version, root: Some(Hash512([version; 8])), tree_size: 2 * version as usize + 1, leaf_count: version as usize + 1, timestamp: 1000 + version }
+    }
+
+    #[test]
+    fn test_root_log() {
+        let path = std::env::temp_dir().join(format!("timestamping-roots-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut log = RootLog::open(&path).unwrap();
+        log.append(&head(0), None).unwrap();
+        log.append(&head(1), Some(vec![7; 64])).unwrap();
+        assert_eq!(log.entries()[0].previous_hash, encode_hex(&GENESIS_HASH));
+        assert_eq!(log.entries()[1].previous_hash, log.entries()[0].hash);
+        assert_eq!(verify_chain(log.entries()).map(|hash| encode_hex(&hash)), Ok(log.entries()[1].hash.clone()));
+
+        // The chain continues across restarts
+        let mut reopened = RootLog::open(&path).unwrap();
+        assert_eq!(reopened.entries(), log.entries());
+        reopened.append(&head(2), None).unwrap();
+        assert_eq!(reopened.entries()[2].previous_hash, log.entries()[1].hash);
+        assert_eq!(RootLog::open(&path).unwrap().len(), 3);
+
+        // Rewriting a root breaks the chain at its entry
+        let mut entries = reopened.entries().to_vec();
+        entries[1].root = Some(Hash512([9; 8]));
+        assert_eq!(verify_chain(&entries), Err(1));
+        let lines: Vec<String> = entries.iter().map(|entry| serde_json::to_string(entry).unwrap()).collect();
+        std::fs::write(&path, lines.join("\n")).unwrap();
+        assert_eq!(RootLog::open(&path).unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        // Recomputing the rewritten entry's hash breaks the link of the next one instead
+        let mut previous_hash = [0; 32];
+        previous_hash.copy_from_slice(&crate::storage::decode_hex(entries[0].hash.as_bytes()).unwrap());
+        entries[1] = RootLogEntry::new(&previous_hash, &entries[1].head(), entries[1].signature.clone());
+        assert_eq!(verify_chain(&entries), Err(2));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,350 @@
+use std::io::{self, Read, Write};
+use crate::storage::{Hash512, Hash512Ops};
+
+// Canonical export of a store's salted leaves, all integers big-endian:
+// - header: magic, format version, record size, records per segment, record count, the salt commitment
+//   and a BLAKE3 checksum of the header fields before it
+// - the records in `LEAF_ORDER`, 64 bytes each, split into segments that are followed by the BLAKE3 hash of their records
+// - trailer: the merkle root over the segment checksums, the record count again and an end magic
+// A reader checks every part as it gets to it, so truncated or corrupted files are refused before their records are used.
+const MAGIC: &[u8; 8] = b"TSSNAPSH";
+const END_MAGIC: &[u8; 8] = b"TSSNAPEN";
+pub const SNAPSHOT_VERSION: u16 = 1;
+const RECORD_SIZE: usize = 64;
+pub const SEGMENT_RECORDS: u32 = 4096;
+const MAX_SEGMENT_RECORDS: u32 = 1 << 20; // Bounds the memory a reader needs for one segment
+const HEADER_FIELDS_SIZE: usize = 56;
+const CHECKSUM_SIZE: usize = 32;
+const TRAILER_SIZE: usize = 48;
+const SALT_COMMITMENT_CONTEXT: &str = "timestamping snapshot salt commitment v1";
+
+#[derive(Debug, thiserror::Error)]
+pub enum SnapshotError {
+    #[error("{0}")]
+    Io(#[from] io::Error),
+    #[error("Not a snapshot")]
+    NotASnapshot,
+    #[error("Unsupported snapshot version {0}")]
+    UnsupportedVersion(u16),
+    #[error("Header checksum mismatch")]
+    HeaderChecksum,
+    #[error("Invalid header: {0}")]
+    InvalidHeader(&'static str),
+    #[error("Snapshot ends early, in {0}")]
+    Truncated(&'static str),
+    #[error("Checksum mismatch in segment {0}")]
+    SegmentChecksum(u64),
+    #[error("Invalid trailer: {0}")]
+    InvalidTrailer(&'static str),
+    #[error("Snapshot is of a store with another salt")]
+    SaltMismatch,
+}
+
+// Commits to the salt without revealing it, so an import can check it restores into the right store
+pub fn salt_commitment(salt: &Hash512) -> [u8; 32] {
+    blake3::derive_key(SALT_COMMITMENT_CONTEXT, &salt.to_bytes())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SnapshotHeader {
+    pub version: u16,
+    pub segment_records: u32,
+    pub count: u64,
+    pub salt_commitment: [u8; 32],
+}
+
+impl SnapshotHeader {
+    fn segments(&self) -> u64 {
+        self.count.div_ceil(self.segment_records as u64)
+    }
+}
+
+fn leaf_hash(checksum: &[u8; 32]) -> [u8; 32] {
+    *blake3::Hasher::new().update(&[0]).update(checksum).finalize().as_bytes()
+}
+
+fn node_hash(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    *blake3::Hasher::new().update(&[1]).update(left).update(right).finalize().as_bytes()
+}
+
+// Merkle root over the checksums of the segments, split like RFC 6962 at the largest power of two below their number
+pub fn segments_root(checksums: &[[u8; 32]]) -> [u8; 32] {
+    match checksums {
+        [] => *blake3::hash(&[]).as_bytes(),
+        [checksum] => leaf_hash(checksum),
+        _ => {
+            let split = checksums.len().next_power_of_two() / 2;
+            node_hash(&segments_root(&checksums[..split]), &segments_root(&checksums[split..]))
+        }
+    }
+}
+
+// Write a snapshot of `leaves`, which have to be in `LEAF_ORDER`, and return its root
+pub fn write_snapshot<W: Write>(writer: &mut W, salt: &Hash512, leaves: &[Hash512]) -> io::Result<[u8; 32]> {
+    let mut snapshot = SnapshotWriter::new(writer, salt, leaves.len() as u64)?;
+    snapshot.write(leaves)?;
+    Ok(snapshot.finish()?.1)
+}
+
+// Writes a snapshot of `count` leaves, which are given in `LEAF_ORDER` over any number of calls, so they don't
+// have to be in memory at once. The header is written right away, segments as they fill up.
+pub struct SnapshotWriter<W> {
+    writer: W,
+    count: u64,
+    written: u64,
+    segment: blake3::Hasher,
+    checksums: Vec<[u8; 32]>,
+}
+
+impl<W: Write> SnapshotWriter<W> {
+    pub fn new(mut writer: W, salt: &Hash512, count: u64) -> io::Result<Self> {
+        let mut header = Vec::with_capacity(HEADER_FIELDS_SIZE + CHECKSUM_SIZE);
+        header.extend_from_slice(MAGIC);
+        header.extend_from_slice(&SNAPSHOT_VERSION.to_be_bytes());
+        header.extend_from_slice(&(RECORD_SIZE as u16).to_be_bytes());
+        header.extend_from_slice(&SEGMENT_RECORDS.to_be_bytes());
+        header.extend_from_slice(&count.to_be_bytes());
+        header.extend_from_slice(&salt_commitment(salt));
+        let checksum = blake3::hash(&header);
+        header.extend_from_slice(checksum.as_bytes());
+        writer.write_all(&header)?;
+        Ok(Self { writer, count, written: 0, segment: blake3::Hasher::new(), checksums: Vec::new() })
+    }
+
+    pub fn write(&mut self, leaves: &[Hash512]) -> io::Result<()> {
+        if self.written + leaves.len() as u64 > self.count {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "more leaves than the snapshot was started with"));
+        }
+        for leaf in leaves {
+            let bytes = leaf.to_bytes();
+            self.segment.update(&bytes);
+            self.writer.write_all(&bytes)?;
+            self.written += 1;
+            if self.written.is_multiple_of(SEGMENT_RECORDS as u64) {
+                self.end_segment()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn end_segment(&mut self) -> io::Result<()> {
+        let checksum = *self.segment.finalize().as_bytes();
+        self.segment.reset();
+        self.writer.write_all(&checksum)?;
+        self.checksums.push(checksum);
+        Ok(())
+    }
+
+    // What was written so far, e.g. to send it on before writing more
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.writer
+    }
+
+    // Write the trailer once all leaves are written, returning the writer and the root of the snapshot
+    pub fn finish(mut self) -> io::Result<(W, [u8; 32])> {
+        if self.written != self.count {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "fewer leaves than the snapshot was started with"));
+        }
+        if !self.written.is_multiple_of(SEGMENT_RECORDS as u64) {
+            self.end_segment()?;
+        }
+        let root = segments_root(&self.checksums);
+        self.writer.write_all(&root)?;
+        self.writer.write_all(&self.count.to_be_bytes())?;
+        self.writer.write_all(END_MAGIC)?;
+        Ok((self.writer, root))
+    }
+}
+
+// Fill `buffer`, telling a file that ends in the middle of `part` apart from other read errors
+fn read_part<R: Read>(reader: &mut R, buffer: &mut [u8], part: &'static str) -> Result<(), SnapshotError> {
+    reader.read_exact(buffer).map_err(|e| match e.kind() {
+        io::ErrorKind::UnexpectedEof => SnapshotError::Truncated(part),
+        _ => SnapshotError::Io(e),
+    })
+}
+
+// Reads a snapshot segment by segment, checking each before returning its records
+pub struct SnapshotReader<R> {
+    reader: R,
+    header: SnapshotHeader,
+    checksums: Vec<[u8; 32]>,
+    root: Option<[u8; 32]>,
+}
+
+impl<R: Read> SnapshotReader<R> {
+    pub fn new(mut reader: R) -> Result<Self, SnapshotError> {
+        let mut header = [0u8; HEADER_FIELDS_SIZE + CHECKSUM_SIZE];
+        read_part(&mut reader, &mut header[..MAGIC.len()], "the header")?;
+        if header[..MAGIC.len()] != MAGIC[..] {
+            return Err(SnapshotError::NotASnapshot);
+        }
+        read_part(&mut reader, &mut header[MAGIC.len()..], "the header")?;
+        let (fields, checksum) = header.split_at(HEADER_FIELDS_SIZE);
+        if blake3::hash(fields).as_bytes()[..] != checksum[..] {
+            return Err(SnapshotError::HeaderChecksum);
+        }
+        let version = u16::from_be_bytes(fields[8..10].try_into().unwrap());
+        if version != SNAPSHOT_VERSION {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+        if u16::from_be_bytes(fields[10..12].try_into().unwrap()) as usize != RECORD_SIZE {
+            return Err(SnapshotError::InvalidHeader("record size is not 64 bytes"));
+        }
+        let segment_records = u32::from_be_bytes(fields[12..16].try_into().unwrap());
+        if segment_records == 0 || segment_records > MAX_SEGMENT_RECORDS {
+            return Err(SnapshotError::InvalidHeader("segments have no or too many records"));
+        }
+        let header = SnapshotHeader {
+            version,
+            segment_records,
+            count: u64::from_be_bytes(fields[16..24].try_into().unwrap()),
+            salt_commitment: fields[24..56].try_into().unwrap(),
+        };
+        Ok(Self { reader, header, checksums: Vec::new(), root: None })
+    }
+
+    pub fn header(&self) -> &SnapshotHeader {
+        &self.header
+    }
+
+    // Records of the next segment, `None` once the trailer was read and checked
+    pub fn next_segment(&mut self) -> Result<Option<Vec<Hash512>>, SnapshotError> {
+        let index = self.checksums.len() as u64;
+        if index == self.header.segments() {
+            if self.root.is_none() {
+                self.root = Some(self.read_trailer()?);
+            }
+            return Ok(None);
+        }
+        let records = (self.header.count - index * self.header.segment_records as u64).min(self.header.segment_records as u64) as usize;
+        let mut bytes = vec![0u8; records * RECORD_SIZE];
+        read_part(&mut self.reader, &mut bytes, "the records")?;
+        let mut checksum = [0u8; CHECKSUM_SIZE];
+        read_part(&mut self.reader, &mut checksum, "a segment checksum")?;
+        if *blake3::hash(&bytes).as_bytes() != checksum {
+            return Err(SnapshotError::SegmentChecksum(index));
+        }
+        self.checksums.push(checksum);
+        Ok(Some(bytes.chunks_exact(RECORD_SIZE).map(|record| Hash512::from_bytes(record).unwrap()).collect()))
+    }
+
+    fn read_trailer(&mut self) -> Result<[u8; 32], SnapshotError> {
+        let mut trailer = [0u8; TRAILER_SIZE];
+        read_part(&mut self.reader, &mut trailer, "the trailer")?;
+        if trailer[40..] != END_MAGIC[..] {
+            return Err(SnapshotError::InvalidTrailer("missing end marker"));
+        }
+        if u64::from_be_bytes(trailer[32..40].try_into().unwrap()) != self.header.count {
+            return Err(SnapshotError::InvalidTrailer("record count differs from the header"));
+        }
+        let root: [u8; 32] = trailer[..32].try_into().unwrap();
+        if root != segments_root(&self.checksums) {
+            return Err(SnapshotError::InvalidTrailer("root doesn't match the segments"));
+        }
+        if self.reader.read(&mut [0u8; 1])? != 0 {
+            return Err(SnapshotError::InvalidTrailer("data after the trailer"));
+        }
+        Ok(root)
+    }
+}
+
+// Read and check a whole snapshot before returning its records. With a `salt`, it also has to be of a store with that salt.
+pub fn read_snapshot<R: Read>(reader: R, salt: Option<&Hash512>) -> Result<(SnapshotHeader, Vec<Hash512>), SnapshotError> {
+    let mut reader = SnapshotReader::new(reader)?;
+    if salt.is_some_and(|salt| salt_commitment(salt) != reader.header().salt_commitment) {
+        return Err(SnapshotError::SaltMismatch);
+    }
+    let mut leaves = Vec::new();
+    while let Some(segment) = reader.next_segment()? {
+        leaves.extend(segment);
+    }
+    Ok((reader.header, leaves))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SALT: Hash512 = Hash512([9; 8]);
+
+    fn leaves(count: u64) -> Vec<Hash512> {
+        (0..count).map(|i| Hash512([i, 0, 0, 0, 0, 0, 0, 0])).collect()
+    }
+
+    #[test]
+    fn test_snapshot_roundtrip() {
+        for count in [0, 1, SEGMENT_RECORDS as u64, SEGMENT_RECORDS as u64 * 2 + 3] {
+            let mut bytes = Vec::new();
+            let root = write_snapshot(&mut bytes, &SALT, &leaves(count)).unwrap();
+            assert_eq!(bytes.len() as u64, 88 + count * 64 + count.div_ceil(SEGMENT_RECORDS as u64) * 32 + 48);
+            assert_eq!(bytes[bytes.len() - 48..bytes.len() - 16], root);
+
+            let (header, read) = read_snapshot(bytes.as_slice(), Some(&SALT)).unwrap();
+            assert_eq!((header.count, header.salt_commitment), (count, salt_commitment(&SALT)));
+            assert_eq!(read, leaves(count));
+            assert!(matches!(read_snapshot(bytes.as_slice(), Some(&Hash512([8; 8]))), Err(SnapshotError::SaltMismatch)));
+        }
+    }
+
+    #[test]
+    fn test_snapshot_writer() {
+        let count = SEGMENT_RECORDS as u64 * 2 + 3;
+        let mut bytes = Vec::new();
+        let root = write_snapshot(&mut bytes, &SALT, &leaves(count)).unwrap();
+
+        // Written in pieces that don't line up with the segments, the snapshot is the same
+        let mut writer = SnapshotWriter::new(Vec::new(), &SALT, count).unwrap();
+        let mut streamed = Vec::new();
+        for piece in leaves(count).chunks(1000) {
+            writer.write(piece).unwrap();
+            streamed.append(writer.get_mut());
+        }
+        let (trailer, streamed_root) = writer.finish().unwrap();
+        streamed.extend(trailer);
+        assert_eq!((streamed, streamed_root), (bytes, root));
+
+        let mut writer = SnapshotWriter::new(Vec::new(), &SALT, 2).unwrap();
+        assert!(writer.write(&leaves(3)).is_err());
+        writer.write(&leaves(1)).unwrap();
+        assert!(writer.finish().is_err());
+    }
+
+    #[test]
+    fn test_segments_root() {
+        let checksums: Vec<[u8; 32]> = (0..5).map(|i| [i; 32]).collect();
+        let leaf = |i: usize| leaf_hash(&checksums[i]);
+        // Five segments split into the first four and the last one
+        let expected = node_hash(&node_hash(&node_hash(&leaf(0), &leaf(1)), &node_hash(&leaf(2), &leaf(3))), &leaf(4));
+        assert_eq!(segments_root(&checksums), expected);
+        assert_eq!(segments_root(&checksums[..1]), leaf(0));
+        assert_ne!(segments_root(&[]), segments_root(&checksums[..1]));
+    }
+
+    #[test]
+    fn test_snapshot_corruption() {
+        let count = SEGMENT_RECORDS as u64 + 10;
+        let mut bytes = Vec::new();
+        write_snapshot(&mut bytes, &SALT, &leaves(count)).unwrap();
+        let read = |bytes: &[u8]| read_snapshot(bytes, None).map(|_| ());
+
+        // Cut anywhere, the file is refused
+        for len in [0, 4, 40, 88, 1000, 88 + SEGMENT_RECORDS as usize * 64 + 10, bytes.len() - 1] {
+            assert!(matches!(read(&bytes[..len]), Err(SnapshotError::Truncated(_))), "{}", len);
+        }
+        let flipped = |index: usize| {
+            let mut corrupted = bytes.clone();
+            corrupted[index] ^= 1;
+            read(&corrupted)
+        };
+        assert!(matches!(flipped(0), Err(SnapshotError::NotASnapshot)));
+        assert!(matches!(flipped(20), Err(SnapshotError::HeaderChecksum)));
+        assert!(matches!(flipped(100), Err(SnapshotError::SegmentChecksum(0))));
+        assert!(matches!(flipped(88 + SEGMENT_RECORDS as usize * 64 + 32 + 64), Err(SnapshotError::SegmentChecksum(1))));
+        assert!(matches!(flipped(bytes.len() - 40), Err(SnapshotError::InvalidTrailer(_))));
+        assert!(matches!(flipped(bytes.len() - 1), Err(SnapshotError::InvalidTrailer(_))));
+        let mut extended = bytes.clone();
+        extended.push(0);
+        assert!(matches!(read(&extended), Err(SnapshotError::InvalidTrailer(_))));
+    }
+}
@@ -0,0 +1,319 @@
+use std::sync::Arc;
+use serde::Serialize;
+use utoipa::ToSchema;
+use crate::storage::{HASHER_NAMES, Hash512, Hash512Ops, HashAlgorithm, Hasher, LEAF_ORDER, LeafEncoding, MerkleTree, encode_hex, hasher_from_name, sort_leaves};
+use crate::test_vectors::{input_hash, input_salt};
+
+// Machine-readable description of how receipts and proofs are computed, for implementing verifiers in other languages.
+// Prefixes and hash functions are read from the hashers and encodings the server uses, and the examples are computed
+// by the same code, so the description can't drift from the implementation. All bytes are hex.
+pub const SPEC_VERSION: u8 = 1;
+const HASH_SIZE: usize = 64;
+const EXAMPLE_SIZE: usize = 3; // Leaves of every example, the last one is promoted
+const ODD_NODES: &str = "The last node of a level with an odd number of nodes is promoted unchanged to the next level, nothing is \
+    hashed in its place and nothing is padded. This gives the shape of the unbalanced trees of RFC 6962.";
+const PROOF_FORMAT: &[&str] = &[
+    "A proof is a list of pairs of 64-byte values. The first pair is (hash, salt) and starts the proof at the leaf of the hash.",
+    "Every following pair is (left, right), the children of the next node on the way to the root. One of them is the current value, otherwise the proof is invalid.",
+    "The next current value is the node computed from the pair, levels where the node was promoted have no pair.",
+    "The proof is valid if the last current value is the root.",
+];
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct ProofSpec {
+    pub spec_version: u8,
+    // Size of hashes, salts and nodes, which are always sent and hashed as these bytes
+    pub hash_size: usize,
+    // How submitted digests become the 64-byte hashes that are salted into leaves
+    pub digest_algorithms: Vec<DigestSpec>,
+    pub leaf_encodings: Vec<LeafSpec>,
+    pub tree_hashers: Vec<TreeHasherSpec>,
+    pub leaf_order: &'static str,
+    pub odd_nodes: &'static str,
+    pub proof_format: Vec<&'static str>,
+    pub examples: Vec<SpecExample>,
+}
+
+// A part of the bytes that are hashed: constant bytes, or the bytes of a named input
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum InputPart {
+    Bytes(String),
+    Input(&'static str),
+}
+
+fn input(prefix: &[u8], inputs: &[&'static str]) -> Vec<InputPart> {
+    let prefix = (!prefix.is_empty()).then(|| InputPart::Bytes(encode_hex(prefix)));
+    prefix.into_iter().chain(inputs.iter().map(|&name| InputPart::Input(name))).collect()
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct DigestSpec {
+    pub name: &'static str,
+    pub digest_len: usize,
+    // SHA-512 of these parts, `None` for digests used as they are
+    pub normalization: Option<Vec<InputPart>>,
+    // The digest of the bytes 0, 1, 2, ... and what it is normalized to
+    pub example_digest: String,
+    pub example_hash: String,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct LeafSpec {
+    pub version: u8,
+    pub description: &'static str,
+    // Hashed with the hash function of the tree hasher
+    pub input: Vec<InputPart>,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct TreeHasherSpec {
+    pub name: &'static str,
+    pub hash_function: &'static str,
+    pub node_description: &'static str,
+    pub node_input: Vec<InputPart>,
+}
+
+// A tree over the first hashes of the test vectors, see `test_vectors::input_hash`
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct SpecExample {
+    pub tree_hasher: &'static str,
+    pub leaf_version: u8,
+    pub salt: String,
+    // In the order the hashes were submitted
+    pub leaves: Vec<ExampleHash>,
+    // From the leaves in `leaf_order` up to the level with just the root
+    pub levels: Vec<Vec<String>>,
+    // Every node that was hashed, level by level
+    pub nodes: Vec<ExampleHash>,
+    pub root: String,
+    // Proof of the first submitted hash, checked step by step
+    pub proof: ExampleProof,
+}
+
+// Bytes that were hashed and the result, with the submitted hash for leaves
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct ExampleHash {
+    pub hash: Option<String>,
+    pub input: String,
+    pub output: String,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct ExampleProof {
+    pub merkle_proof: Vec<(String, String)>,
+    // The leaf, then every node computed from a pair of the proof
+    pub steps: Vec<ExampleHash>,
+    pub valid: bool,
+}
+
+fn concat(prefix: &[u8], values: &[&Hash512]) -> String {
+    let mut bytes = prefix.to_vec();
+    for value in values {
+        bytes.extend(value.to_bytes());
+    }
+    encode_hex(&bytes)
+}
+
+fn node(hasher: &dyn Hasher, left: &Hash512, right: &Hash512) -> ExampleHash {
+    ExampleHash { hash: None, input: concat(hasher.node_prefix(), &[left, right]), output: hasher.node(left, right).to_string() }
+}
+
+fn example(hasher: Arc<dyn Hasher>, encoding: LeafEncoding) -> SpecExample {
+    let salt = input_salt();
+    let hashes: Vec<Hash512> = (0..EXAMPLE_SIZE).map(input_hash).collect();
+    let leaf = |hash: &Hash512| ExampleHash {
+        hash: Some(hash.to_string()),
+        input: concat(encoding.prefix(), &[hash, &salt]),
+        output: encoding.leaf_with_hasher(&*hasher, hash, &salt).to_string(),
+    };
+    let mut leaves: Vec<Hash512> = hashes.iter().map(|hash| encoding.leaf_with_hasher(&*hasher, hash, &salt)).collect();
+    sort_leaves(&mut leaves);
+    let tree = MerkleTree::with_hasher(leaves, salt, Arc::clone(&hasher));
+    let nodes = tree.levels.iter()
+        .flat_map(|level| level.chunks_exact(2).map(|pair| node(&*hasher, &pair[0], &pair[1])))
+        .collect();
+
+    let merkle_proof = tree.get_with_encoding(&hashes[0], encoding).unwrap();
+    let steps = std::iter::once(leaf(&hashes[0]))
+        .chain(merkle_proof[1..].iter().map(|(left, right)| node(&*hasher, left, right)))
+        .collect();
+    SpecExample {
+        tree_hasher: hasher.name(),
+        leaf_version: encoding.version(),
+        salt: salt.to_string(),
+        leaves: hashes.iter().map(leaf).collect(),
+        levels: tree.levels.iter().map(|level| level.iter().map(Hash512::to_string).collect()).collect(),
+        nodes,
+        root: tree.root().unwrap().to_string(),
+        proof: ExampleProof {
+            merkle_proof: merkle_proof.iter().map(|(left, right)| (left.to_string(), right.to_string())).collect(),
+            steps,
+            valid: MerkleTree::verify_proof_with_hasher(&hashes[0], &merkle_proof, &tree.root().unwrap(), encoding, &*hasher),
+        },
+    }
+}
+
+pub fn generate() -> ProofSpec {
+    let hashers: Vec<Arc<dyn Hasher>> = HASHER_NAMES.iter().map(|name| hasher_from_name(name).unwrap()).collect();
+    let digest_algorithms = HashAlgorithm::ALL.iter()
+        .map(|algorithm| {
+            let digest: Vec<u8> = (0..algorithm.digest_len() as u8).collect();
+            DigestSpec {
+                name: algorithm.name(),
+                digest_len: algorithm.digest_len(),
+                normalization: algorithm.normalization_prefix().map(|prefix| input(prefix, &["digest"])),
+                example_digest: encode_hex(&digest),
+                example_hash: algorithm.normalize(&digest).unwrap().to_string(),
+            }
+        })
+        .collect();
+    ProofSpec {
+        spec_version: SPEC_VERSION,
+        hash_size: HASH_SIZE,
+        digest_algorithms,
+        leaf_encodings: LeafEncoding::ALL.iter()
+            .map(|encoding| LeafSpec { version: encoding.version(), description: encoding.description(), input: input(encoding.prefix(), &["hash", "salt"]) })
+            .collect(),
+        tree_hashers: hashers.iter()
+            .map(|hasher| TreeHasherSpec {
+                name: hasher.name(),
+                hash_function: hasher.hash_function(),
+                node_description: hasher.node_description(),
+                node_input: input(hasher.node_prefix(), &["left", "right"]),
+            })
+            .collect(),
+        leaf_order: LEAF_ORDER,
+        odd_nodes: ODD_NODES,
+        proof_format: PROOF_FORMAT.to_vec(),
+        examples: hashers.iter()
+            .flat_map(|hasher| LeafEncoding::ALL.map(|encoding| example(Arc::clone(hasher), encoding)))
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use sha2::{Digest, Sha512};
+    use super::*;
+    use crate::storage::decode_hex;
+
+    // The hash functions as the spec names them, implemented without the hashers
+    fn hash_function(name: &str, bytes: &[u8]) -> Vec<u8> {
+        match name {
+            "SHA-512" => Sha512::digest(bytes).to_vec(),
+            "BLAKE3 in extended output mode, the first 64 bytes" => {
+                let mut output = [0u8; 64];
+                blake3::Hasher::new().update(bytes).finalize_xof().fill(&mut output);
+                output.to_vec()
+            }
+            other => panic!("Unknown hash function {}", other),
+        }
+    }
+
+    fn assemble(parts: &[InputPart], inputs: &HashMap<&str, Vec<u8>>) -> Vec<u8> {
+        parts.iter()
+            .flat_map(|part| match part {
+                InputPart::Bytes(bytes) => decode_hex(bytes.as_bytes()).unwrap(),
+                InputPart::Input(name) => inputs[name].clone(),
+            })
+            .collect()
+    }
+
+    fn bytes(hex: &str) -> Vec<u8> {
+        decode_hex(hex.as_bytes()).unwrap()
+    }
+
+    // Only the spec is used below, the way a verifier in another language would
+    #[test]
+    fn test_spec() {
+        let spec = generate();
+        assert_eq!(spec.examples.len(), HASHER_NAMES.len() * LeafEncoding::ALL.len());
+
+        for algorithm in &spec.digest_algorithms {
+            let digest = bytes(&algorithm.example_digest);
+            let normalized = match &algorithm.normalization {
+                Some(parts) => Sha512::digest(assemble(parts, &HashMap::from([("digest", digest)]))).to_vec(),
+                None => digest,
+            };
+            assert_eq!(normalized, bytes(&algorithm.example_hash), "{}", algorithm.name);
+        }
+
+        for example in &spec.examples {
+            let hasher = spec.tree_hashers.iter().find(|hasher| hasher.name == example.tree_hasher).unwrap();
+            let encoding = spec.leaf_encodings.iter().find(|encoding| encoding.version == example.leaf_version).unwrap();
+            let hash = |bytes: &[u8]| hash_function(hasher.hash_function, bytes);
+            let node = |left: &[u8], right: &[u8]| {
+                let input = assemble(&hasher.node_input, &HashMap::from([("left", left.to_vec()), ("right", right.to_vec())]));
+                (input.clone(), hash(&input))
+            };
+
+            let mut level: Vec<Vec<u8>> = Vec::new();
+            for leaf in &example.leaves {
+                let inputs = HashMap::from([("hash", bytes(leaf.hash.as_ref().unwrap())), ("salt", bytes(&example.salt))]);
+                let input = assemble(&encoding.input, &inputs);
+                assert_eq!((encode_hex(&input), encode_hex(&hash(&input))), (leaf.input.clone(), leaf.output.clone()));
+                level.push(hash(&input));
+            }
+            // Sorted by the leaf order: eight little-endian 64-bit words, compared word by word
+            let words = |leaf: &Vec<u8>| leaf.chunks(8).map(|word| u64::from_le_bytes(word.try_into().unwrap())).collect::<Vec<_>>();
+            level.sort_by_key(words);
+
+            let mut nodes = Vec::new();
+            let mut levels = vec![level.clone()];
+            while level.len() > 1 {
+                level = level.chunks(2)
+                    .map(|pair| match pair {
+                        [left, right] => {
+                            let (input, parent) = node(left, right);
+                            nodes.push((encode_hex(&input), encode_hex(&parent)));
+                            parent
+                        }
+                        [promoted] => promoted.clone(),
+                        _ => unreachable!(),
+                    })
+                    .collect();
+                levels.push(level.clone());
+            }
+            let to_hex_levels: Vec<Vec<String>> = levels.iter().map(|level| level.iter().map(|node| encode_hex(node)).collect()).collect();
+            assert_eq!(to_hex_levels, example.levels);
+            assert_eq!(nodes, example.nodes.iter().map(|node| (node.input.clone(), node.output.clone())).collect::<Vec<_>>());
+            assert_eq!(encode_hex(&level[0]), example.root);
+
+            // The proof, by the steps of the proof format
+            let (first, pairs) = example.proof.merkle_proof.split_first().unwrap();
+            assert_eq!((&first.0, &first.1), (example.leaves[0].hash.as_ref().unwrap(), &example.salt));
+            let mut current = bytes(&example.leaves[0].output);
+            for ((left, right), step) in pairs.iter().zip(&example.proof.steps[1..]) {
+                assert!(current == bytes(left) || current == bytes(right));
+                let (input, parent) = node(&bytes(left), &bytes(right));
+                assert_eq!((encode_hex(&input), encode_hex(&parent)), (step.input.clone(), step.output.clone()));
+                current = parent;
+            }
+            assert_eq!(encode_hex(&current), example.root);
+            assert!(example.proof.valid);
+        }
+    }
+
+    // Every hasher and leaf encoding computes what the spec says for inputs that aren't in the examples
+    #[test]
+    fn test_spec_matches_code() {
+        let spec = generate();
+        for hasher_spec in &spec.tree_hashers {
+            let hasher = hasher_from_name(hasher_spec.name).unwrap();
+            let (left, right) = (Hash512(rand::random()), Hash512(rand::random()));
+            let inputs = HashMap::from([("left", left.to_bytes()), ("right", right.to_bytes())]);
+            let expected = hash_function(hasher_spec.hash_function, &assemble(&hasher_spec.node_input, &inputs));
+            assert_eq!(hasher.node(&left, &right).to_bytes(), expected, "{}", hasher_spec.name);
+
+            for encoding_spec in &spec.leaf_encodings {
+                let encoding = LeafEncoding::from_version(encoding_spec.version).unwrap();
+                let (hash, salt) = (Hash512(rand::random()), Hash512(rand::random()));
+                let inputs = HashMap::from([("hash", hash.to_bytes()), ("salt", salt.to_bytes())]);
+                let expected = hash_function(hasher_spec.hash_function, &assemble(&encoding_spec.input, &inputs));
+                assert_eq!(encoding.leaf_with_hasher(&*hasher, &hash, &salt).to_bytes(), expected);
+            }
+        }
+    }
+}
@@ -0,0 +1,304 @@
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use rusqlite::{Connection, OptionalExtension, params};
+use crate::storage::{Hash512, Hash512Ops, HashMetadata, HashStorage, Hasher, ITER_CHUNK_SIZE, LeafEncoding, StorageError, StoreSnapshot, TreeHead};
+
+// Tables of a store: its salt and tree hasher, the salted hashes in the order they were stored with the unix time
+// they were stored at and the metadata they were submitted with as JSON, and the heads of the trees published from it
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS config (salt BLOB NOT NULL, tree_hasher TEXT NOT NULL);
+    CREATE TABLE IF NOT EXISTS hashes (
+        sequence INTEGER PRIMARY KEY,
+        leaf BLOB NOT NULL UNIQUE,
+        stored_at INTEGER NOT NULL,
+        metadata TEXT
+    );
+    CREATE TABLE IF NOT EXISTS tree_heads (
+        version INTEGER PRIMARY KEY,
+        root BLOB,
+        tree_size INTEGER NOT NULL,
+        leaf_count INTEGER NOT NULL,
+        timestamp INTEGER NOT NULL
+    );
+";
+
+// Store of salted hashes in an SQLite database, for deployments that would rather back up and inspect a database
+// than the leaf logs of `MultiThreadedHashStore`. It is slower, but implements `HashStorage`, so trees are built
+// from it the same way. Every call takes the one connection of the store in turn.
+pub struct SqliteStore {
+    connection: Mutex<Connection>,
+    salt: Hash512,
+    hasher: Arc<dyn Hasher>,
+}
+
+impl std::fmt::Debug for SqliteStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SqliteStore").field("salt", &self.salt).field("hasher", &self.hasher).finish_non_exhaustive()
+    }
+}
+
+impl SqliteStore {
+    // Open the database at `path`, created with `salt` (a new random one if not given) and `hasher` on first use.
+    // Fails if it was created with a different salt or hasher.
+    pub fn open(path: &Path, salt: Option<Hash512>, hasher: Arc<dyn Hasher>) -> io::Result<Self> {
+        let connection = Connection::open(path).map_err(io::Error::other)?;
+        connection.execute_batch(SCHEMA).map_err(io::Error::other)?;
+        let config = connection.query_row("SELECT salt, tree_hasher FROM config", [], |row| Ok((row.get::<_, Vec<u8>>(0)?, row.get::<_, String>(1)?)))
+            .optional()
+            .map_err(io::Error::other)?;
+        let salt = match config {
+            Some((stored_salt, tree_hasher)) => {
+                let stored_salt = Hash512::from_bytes(&stored_salt)
+                    .map_err(|_| invalid_data(format!("{} has no valid salt", path.display())))?;
+                if salt.is_some_and(|salt| salt != stored_salt) {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} was created with a different salt", path.display())));
+                }
+                if tree_hasher != hasher.name() {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} was created with the {} tree hasher", path.display(), tree_hasher)));
+                }
+                stored_salt
+            }
+            None => {
+                let salt = salt.unwrap_or_else(|| Hash512(rand::random()));
+                connection.execute("INSERT INTO config (salt, tree_hasher) VALUES (?1, ?2)", params![salt.to_bytes(), hasher.name()])
+                    .map_err(io::Error::other)?;
+                salt
+            }
+        };
+        Ok(Self { connection: Mutex::new(connection), salt, hasher })
+    }
+
+    pub fn leaf(&self, hash: &Hash512) -> Hash512 {
+        LeafEncoding::default().leaf_with_hasher(&*self.hasher, hash, &self.salt)
+    }
+
+    // Add a hash with the metadata it was submitted with, which a hash stored before keeps. Returns whether it is new.
+    pub fn add_with_metadata(&self, hash: Hash512, metadata: Option<&HashMetadata>) -> Result<bool, StorageError> {
+        Ok(self.add_batch_with_metadata(&[hash], metadata)?[0])
+    }
+
+    // Like `add_with_metadata` for every hash of a batch, in one transaction, so a batch is stored completely or not at all
+    pub fn add_batch_with_metadata(&self, hashes: &[Hash512], metadata: Option<&HashMetadata>) -> Result<Vec<bool>, StorageError> {
+        let metadata = metadata.map(serde_json::to_string).transpose().map_err(backend_error)?;
+        let stored_at = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+        let added = {
+            let mut insert = transaction.prepare_cached("INSERT OR IGNORE INTO hashes (leaf, stored_at, metadata) VALUES (?1, ?2, ?3)")?;
+            hashes.iter()
+                .map(|hash| Ok(insert.execute(params![self.leaf(hash).to_bytes(), stored_at as i64, metadata])? > 0))
+                .collect::<Result<Vec<bool>, StorageError>>()?
+        };
+        transaction.commit()?;
+        Ok(added)
+    }
+
+    // Unix time a hash was stored at, `None` if it isn't stored
+    pub fn stored_at(&self, hash: &Hash512) -> Result<Option<u64>, StorageError> {
+        let stored_at = self.connection.lock().unwrap()
+            .query_row("SELECT stored_at FROM hashes WHERE leaf = ?1", [self.leaf(hash).to_bytes()], |row| row.get::<_, i64>(0))
+            .optional()?;
+        Ok(stored_at.map(|stored_at| stored_at as u64))
+    }
+
+    pub fn metadata(&self, hash: &Hash512) -> Result<Option<HashMetadata>, StorageError> {
+        let metadata = self.connection.lock().unwrap()
+            .query_row("SELECT metadata FROM hashes WHERE leaf = ?1", [self.leaf(hash).to_bytes()], |row| row.get::<_, Option<String>>(0))
+            .optional()?;
+        metadata.flatten()
+            .map(|metadata| serde_json::from_str(&metadata).map_err(backend_error))
+            .transpose()
+    }
+
+    // Record the head of a tree published from the store, replacing one recorded for the same version
+    pub fn record_tree_head(&self, head: &TreeHead) -> Result<(), StorageError> {
+        self.connection.lock().unwrap().execute(
+            "INSERT OR REPLACE INTO tree_heads (version, root, tree_size, leaf_count, timestamp) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![head.version as i64, head.root.map(|root| root.to_bytes()), head.tree_size as i64, head.leaf_count as i64, head.timestamp as i64],
+        )?;
+        Ok(())
+    }
+
+    // Head of the tree published as `version`, `None` if none was recorded
+    pub fn tree_head(&self, version: u64) -> Result<Option<TreeHead>, StorageError> {
+        let row = self.connection.lock().unwrap().query_row(
+            "SELECT root, tree_size, leaf_count, timestamp FROM tree_heads WHERE version = ?1",
+            [version as i64],
+            |row| Ok((row.get::<_, Option<Vec<u8>>>(0)?, row.get::<_, i64>(1)?, row.get::<_, i64>(2)?, row.get::<_, i64>(3)?)),
+        ).optional()?;
+        row.map(|(root, tree_size, leaf_count, timestamp)| {
+            let root = root.map(|root| Hash512::from_bytes(&root)).transpose().map_err(backend_error)?;
+            Ok(TreeHead { version, root, tree_size: tree_size as usize, leaf_count: leaf_count as usize, timestamp: timestamp as u64 })
+        }).transpose()
+    }
+
+    // Salted hashes in the order they were stored
+    fn leaves(&self) -> Result<Vec<Hash512>, StorageError> {
+        let connection = self.connection.lock().unwrap();
+        let mut select = connection.prepare_cached("SELECT leaf FROM hashes ORDER BY sequence")?;
+        let leaves = select.query_map([], |row| row.get::<_, Vec<u8>>(0))?;
+        leaves.map(|leaf| parse_leaf(&leaf?)).collect()
+    }
+
+    // Up to ITER_CHUNK_SIZE salted hashes stored after the one numbered `after`, with the number of the last one
+    fn leaves_after(&self, after: i64) -> Result<(Vec<Hash512>, i64), StorageError> {
+        let connection = self.connection.lock().unwrap();
+        let mut select = connection.prepare_cached("SELECT sequence, leaf FROM hashes WHERE sequence > ?1 ORDER BY sequence LIMIT ?2")?;
+        let rows = select.query_map([after, ITER_CHUNK_SIZE as i64], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, Vec<u8>>(1)?)))?;
+        let mut leaves = Vec::new();
+        let mut last = after;
+        for row in rows {
+            let (sequence, leaf) = row?;
+            leaves.push(parse_leaf(&leaf)?);
+            last = sequence;
+        }
+        Ok((leaves, last))
+    }
+}
+
+impl HashStorage for SqliteStore {
+    fn add(&self, hash: Hash512) -> Result<bool, StorageError> {
+        self.add_with_metadata(hash, None)
+    }
+
+    fn add_batch(&self, hashes: &[Hash512]) -> Result<Vec<bool>, StorageError> {
+        self.add_batch_with_metadata(hashes, None)
+    }
+
+    fn contains(&self, hash: &Hash512) -> Result<bool, StorageError> {
+        Ok(self.stored_at(hash)?.is_some())
+    }
+
+    // A database that can't be read counts as empty
+    fn len(&self) -> usize {
+        let count = self.connection.lock().unwrap().query_row("SELECT COUNT(*) FROM hashes", [], |row| row.get::<_, i64>(0));
+        count.map(|count| count as usize).unwrap_or_else(|e| {
+            eprintln!("Failed to count hashes in SQLite: {}", e);
+            0
+        })
+    }
+
+    // One query per chunk, so other calls get the connection in between
+    fn iter(&self) -> Box<dyn Iterator<Item = Result<Vec<Hash512>, StorageError>> + '_> {
+        let mut after = Some(0);
+        Box::new(std::iter::from_fn(move || {
+            let chunk = self.leaves_after(after?);
+            after = match &chunk {
+                Ok((leaves, last)) if !leaves.is_empty() => Some(*last),
+                Ok(_) => return None,
+                Err(_) => None,
+            };
+            Some(chunk.map(|(leaves, _)| leaves))
+        }))
+    }
+
+    fn snapshot(&self) -> Result<StoreSnapshot, StorageError> {
+        Ok(StoreSnapshot { hashes: self.leaves()?, leaf_log_lengths: Vec::new() })
+    }
+
+    fn salt(&self) -> &Hash512 {
+        &self.salt
+    }
+
+    fn hasher(&self) -> &Arc<dyn Hasher> {
+        &self.hasher
+    }
+}
+
+impl From<rusqlite::Error> for StorageError {
+    fn from(error: rusqlite::Error) -> Self {
+        backend_error(error)
+    }
+}
+
+fn parse_leaf(leaf: &[u8]) -> Result<Hash512, StorageError> {
+    Hash512::from_bytes(leaf).map_err(|_| backend_error("invalid leaf"))
+}
+
+fn backend_error(error: impl std::fmt::Display) -> StorageError {
+    eprintln!("SQLite store failed: {}", error);
+    StorageError::Backend("SQLite")
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{HashStore, MerkleTree, Sha512Hasher, hasher_from_name};
+
+    const SALT: Hash512 = Hash512([7, 6, 5, 4, 3, 2, 1, 0]);
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("timestamping-sqlite-{}-{}.db", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn test_add_and_reopen() {
+        let path = temp_path("reopen");
+        let store = SqliteStore::open(&path, Some(SALT), Arc::new(Sha512Hasher)).unwrap();
+        let hashes: Vec<Hash512> = (0..5u64).map(|i| Hash512([i, 1, 2, 3, 4, 5, 6, 7])).collect();
+        assert!(store.is_empty());
+        assert_eq!(store.add_batch(&hashes[..3]).unwrap(), vec![true; 3]);
+        assert_eq!(store.add_batch(&hashes[2..]).unwrap(), vec![false, true, true]);
+        assert!(!store.add(hashes[0]).unwrap());
+        assert_eq!(store.len(), 5);
+        assert!(store.contains(&hashes[4]).unwrap());
+        assert!(!store.contains(&Hash512([9; 8])).unwrap());
+        assert!(store.stored_at(&hashes[0]).unwrap().is_some());
+
+        // Metadata is kept from the first submission
+        let metadata = HashMetadata::new(Some("alice".to_string()), Some("report".to_string()), None);
+        let hash = Hash512([9; 8]);
+        assert!(store.add_with_metadata(hash, Some(&metadata)).unwrap());
+        assert!(!store.add_with_metadata(hash, Some(&HashMetadata::default())).unwrap());
+        assert_eq!(store.metadata(&hash).unwrap(), Some(metadata));
+        assert_eq!(store.metadata(&hashes[0]).unwrap(), None);
+        let leaves: Vec<Hash512> = hashes.iter().chain([&hash]).map(|hash| store.leaf(hash)).collect();
+        assert_eq!(HashStorage::iter(&store).collect::<Result<Vec<_>, _>>().unwrap().concat(), leaves);
+        drop(store);
+
+        // The salt is taken from the database, which refuses a different salt or hasher
+        let store = SqliteStore::open(&path, None, Arc::new(Sha512Hasher)).unwrap();
+        assert_eq!((store.salt, store.len()), (SALT, 6));
+        assert_eq!(store.snapshot().unwrap().hashes, leaves);
+        drop(store);
+        let error = SqliteStore::open(&path, Some(Hash512([1; 8])), Arc::new(Sha512Hasher)).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        let error = SqliteStore::open(&path, None, hasher_from_name("blake3").unwrap()).unwrap_err();
+        assert!(error.to_string().contains("sha512"), "{}", error);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_tree_and_heads() {
+        let path = temp_path("tree");
+        let store = SqliteStore::open(&path, Some(SALT), Arc::new(Sha512Hasher)).unwrap();
+        let memory = HashStore::<4, 0>::new(SALT);
+        for i in 0..50u64 {
+            let hash = Hash512([i << 58, i, 0, 0, 0, 0, 0, 0]);
+            store.add(hash).unwrap();
+            memory.add_hash(hash).unwrap();
+        }
+
+        // Trees are built from either store alike
+        let (tree, leaf_log_lengths) = MerkleTree::from_storage(&store).unwrap();
+        assert_eq!(tree.root(), MerkleTree::from_storage(&memory).unwrap().0.root());
+        assert!(leaf_log_lengths.is_empty());
+
+        let head = TreeHead { version: 3, root: tree.root(), tree_size: tree.size(), leaf_count: tree.leaf_count, timestamp: 1_700_000_000 };
+        assert_eq!(store.tree_head(3).unwrap(), None);
+        store.record_tree_head(&head).unwrap();
+        store.record_tree_head(&TreeHead { version: 4, root: None, ..head }).unwrap();
+        assert_eq!(store.tree_head(3).unwrap(), Some(head));
+        assert_eq!(store.tree_head(4).unwrap().unwrap().root, None);
+        drop(store);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,509 +1,5094 @@
-use std::sync::{Arc, RwLock};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::ops::Range;
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 use std::thread;
-use std::sync::mpsc::{channel, Sender, Receiver};
-use sha2::{Digest, Sha512};
+use std::sync::mpsc::{channel, Receiver, SendError, Sender};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use arc_swap::ArcSwapOption;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use crate::bloom::{BloomFilter, BloomFilterStats};
+use crate::cold::ColdIndex;
+use crate::pruned::{BLOCK_LEVELS, LeafFile};
+use crate::root_log::RootLog;
+use crate::proof_cache::{ProofCache, ProofCacheStats};
+use crate::clock::{SystemTimeSource, TimeAttestation, TimeSource};
+use crate::metrics::RequestLatencies;
+use crate::receipt::{Acknowledgment, Cosignature, Receipt, SaltMigration, SigningKey, VerifyingKey, sign_tree_head};
+use tokio::sync::broadcast;
+use std::time::Duration;
+use std::panic::{self, AssertUnwindSafe};
+
+use crate::verify;
+pub use crate::verify::{Blake3Hasher, DomainSeparatedHasher, HASHER_NAMES, Hash512, Hash512Error, Hash512Ops, HashAlgorithm, Hasher, LeafEncoding, Sha512Hasher, decode_hex, encode_hex, hasher_from_name};
+
+#[derive(Debug, thiserror::Error)]
+pub enum MergeError {
+    #[error("Cannot merge stores with different salts")]
+    SaltMismatch,
+    #[error("Cannot merge stores with different hashers")]
+    HasherMismatch,
+    #[error("Cannot merge a store partitioned by unsalted prefix into one with more threads")]
+    ShardCountMismatch,
+    #[error("Cannot merge stores with different partitionings")]
+    PartitioningMismatch,
+    #[error("Cannot import salted hashes into a store partitioned by unsalted prefix")]
+    UnsaltedPrefix,
+    #[error(transparent)]
+    Storage(#[from] StorageError),
+}
 
-pub type Hash512 = [u64; 8];
+// A store operation that failed. New hashes are rejected once the store reached one of its `StoreLimits`,
+// or while the queue of a worker is at its capacity. The latter is temporary, the add can be retried once
+// the worker caught up. Any command fails with `WorkerUnavailable` if its worker didn't answer, e.g. because
+// it panicked while handling it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum StorageError {
+    #[error("Store is full: limit of {0} hashes reached")]
+    MaxHashes(usize),
+    #[error("Store is full: limit of {0} bytes reached")]
+    MaxMemory(usize),
+    #[error("Store is busy: queue of {0} commands is full")]
+    Saturated(usize),
+    #[error("Store is unavailable: worker {0} didn't answer")]
+    WorkerUnavailable(usize),
+    #[error("Bucket is full: limit of {0} hashes per bucket reached")]
+    BucketFull(usize),
+    // A store other than the in-memory one failed, e.g. `SqliteStore`, which logs why
+    #[error("Storage backend {0} failed")]
+    Backend(&'static str),
+    #[error("Store doesn't support {0}")]
+    Unsupported(&'static str),
+}
 
-#[derive(Debug)]
-pub enum Hash512Error {
-    InvalidLengthError,
+// A request to the service that failed
+#[derive(Debug, thiserror::Error)]
+pub enum ServiceError {
+    #[error(transparent)]
+    Storage(#[from] StorageError),
+    #[error("Tree version {0} is no longer or not yet available")]
+    TreeVersionUnavailable(u64),
+    #[error("Failed to append to the admin log: {0}")]
+    AdminLog(#[from] io::Error),
+    #[error("Salt migration refused: {0}")]
+    ResaltRefused(&'static str),
+    // Hashes given for a salt migration that aren't stored under the current salt
+    #[error("{0} of the hashes are not stored")]
+    UnknownHashes(usize),
+    #[error("Failed to create the re-salted store: {0}")]
+    Resalt(io::Error),
 }
 
-impl std::fmt::Display for Hash512Error {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Hash512Error::InvalidLengthError => write!(f, "Invalid hash length"),
+// Fewest hashes each thread salts when a batch is salted in parallel, smaller batches use fewer threads
+const SALTING_CHUNK_SIZE: usize = 1024;
+
+// Leaves of a batch of hashes, salted on up to `threads` threads so large batches aren't bound by one core
+pub fn salt_batch(hasher: &dyn Hasher, hashes: &[Hash512], salt: &Hash512, encoding: LeafEncoding, threads: usize) -> Vec<Hash512> {
+    let threads = threads.min(hashes.len().div_ceil(SALTING_CHUNK_SIZE)).max(1);
+    if threads == 1 {
+        return hashes.iter().map(|hash| encoding.leaf_with_hasher(hasher, hash, salt)).collect();
+    }
+    let mut leaves = vec![Hash512::default(); hashes.len()];
+    let chunk_size = hashes.len().div_ceil(threads);
+    thread::scope(|scope| {
+        for (hashes, leaves) in hashes.chunks(chunk_size).zip(leaves.chunks_mut(chunk_size)) {
+            scope.spawn(move || {
+                for (leaf, hash) in leaves.iter_mut().zip(hashes) {
+                    *leaf = encoding.leaf_with_hasher(hasher, hash, salt);
+                }
+            });
         }
+    });
+    leaves
+}
+
+// Optional information about a hash, given by whoever submitted it first
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct HashMetadata {
+    pub submitter: Option<String>,
+    pub label: Option<String>,
+    pub content_type: Option<String>,
+    pub submitted_at: u64,
+    // Hashes of a namespace with a retention are removed once they are older, see `TimestampingService::with_retention`
+    pub namespace: Option<String>,
+    // Algorithm the submitter computed the digest with, missing for metadata recorded before it was tagged
+    pub hash_algorithm: Option<HashAlgorithm>,
+}
+
+impl HashMetadata {
+    // Metadata submitted now
+    pub fn new(submitter: Option<String>, label: Option<String>, content_type: Option<String>) -> Self {
+        Self { submitter, label, content_type, submitted_at: unix_timestamp(SystemTime::now()), namespace: None, hash_algorithm: None }
+    }
+
+    pub fn with_namespace(mut self, namespace: Option<String>) -> Self {
+        self.namespace = namespace;
+        self
+    }
+
+    pub fn with_hash_algorithm(mut self, algorithm: HashAlgorithm) -> Self {
+        self.hash_algorithm = Some(algorithm);
+        self
     }
 }
 
-impl std::error::Error for Hash512Error {}
+// Submissions of a stored hash after its first one, with the unix time of the latest
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct Resubmissions {
+    pub count: u64,
+    pub last_seen: u64,
+}
+
+// Stored (salted) hash with the metadata it was submitted with, as listed by `iter_range`
+pub type StoredHash = (Hash512, Option<HashMetadata>);
+
+// Salted hashes with their sequence numbers and the leaf log length of every worker, see `snapshot_numbered`
+pub type NumberedSnapshot = (Vec<(u64, Hash512)>, Vec<u64>);
+
+// Hash a worker just stored, sent to the subscribers of `MultiThreadedHashStore::subscribe_feed`
+#[derive(Debug, Clone)]
+pub struct AcceptedHash {
+    pub salted_hash: Hash512,
+    pub sequence: Option<u64>,
+    pub metadata: Option<Arc<HashMetadata>>,
+}
+
+// Accepted hashes buffered per feed subscriber before slow subscribers start skipping them
+const FEED_CHANNEL_CAPACITY: usize = 4096;
+
+// Salted hash and the sequence number it was stored with, see `HashStore::sequence`
+type Entry = (Hash512, u64);
 
-// Trait for Hash512 operations
-pub trait Hash512Ops {
-    fn from_bytes(bytes: &[u8]) -> Result<Self, Hash512Error> where Self: Sized;
-    fn to_bytes(&self) -> Vec<u8>;
-    fn to_index(&self, prefix_size: usize, index_size: usize) -> usize;
+// Entries of one bucket, sorted by hash. Boxed so that empty buckets only take up a null pointer.
+type Bucket = Option<Box<Vec<Entry>>>;
+
+// When sequence numbers were handed out, to list hashes by the time they were first seen. Keeps the first number
+// of every second in which a new hash was stored, and is shared by the workers of a `MultiThreadedHashStore`
+// like the numbers themselves.
+#[derive(Debug)]
+struct SequenceClock {
+    // Second of the last mark, so stores within a second don't take the lock
+    last_second: AtomicU64,
+    // (unix second, first sequence number handed out in it or later), increasing in both
+    marks: Mutex<Vec<(u64, u64)>>,
 }
 
-impl Hash512Ops for Hash512 {
-    fn from_bytes(bytes: &[u8]) -> Result<Self, Hash512Error> {
-        if bytes.len() != 64 {
-            return Err(Hash512Error::InvalidLengthError);
+impl SequenceClock {
+    // Hashes numbered below `next_sequence`, e.g. those restored from leaf logs, count as first seen at `now`
+    fn starting_at(now: u64, next_sequence: u64) -> Self {
+        Self { last_second: AtomicU64::new(now), marks: Mutex::new(vec![(now, next_sequence)]) }
+    }
+
+    // Called before a number is taken from `next_sequence` at `now`
+    fn record(&self, now: u64, next_sequence: &AtomicU64) {
+        if now <= self.last_second.load(Ordering::Relaxed) {
+            return;
         }
+        let mut marks = self.marks.lock().unwrap();
+        if marks.last().is_none_or(|&(second, _)| second < now) {
+            marks.push((now, next_sequence.load(Ordering::Relaxed)));
+        }
+        self.last_second.fetch_max(now, Ordering::Relaxed);
+    }
 
-        // Convert Vec<u8> to [u64; 8] by reading 8 bytes at a time
-        let mut hash_array = [0u64; 8];
-        for i in 0..8 {
-            let start = i * 8;
-            hash_array[i] = u64::from_le_bytes([
-                bytes[start], bytes[start + 1], bytes[start + 2], bytes[start + 3],
-                bytes[start + 4], bytes[start + 5], bytes[start + 6], bytes[start + 7]
-            ]);
+    // Lowest sequence number of the hashes first seen at `since` or later
+    fn first_sequence_since(&self, since: u64) -> u64 {
+        let marks = self.marks.lock().unwrap();
+        match marks.partition_point(|&(second, _)| second < since) {
+            0 => 0,
+            index if index == marks.len() => u64::MAX,
+            index => marks[index].1,
         }
-        Ok(hash_array)
+    }
+}
+
+// Entries of `HashStore::chain_length_histogram`, the last one counts all longer chains too
+pub const CHAIN_LENGTH_HISTOGRAM_SIZE: usize = 16;
+
+fn chain_length_entry(len: usize) -> usize {
+    len.min(CHAIN_LENGTH_HISTOGRAM_SIZE - 1)
+}
+
+// Buckets are split into up to 2^LOCK_SHARD_BITS consecutive ranges with one lock each,
+// so that concurrent writers only contend if they hit the same range
+const LOCK_SHARD_BITS: usize = 6;
+
+// Hashes passed to the callback of `HashStore::for_each_chunk` and returned by `HashStorage::iter` at a time
+pub const ITER_CHUNK_SIZE: usize = 4096;
+
+// Buckets of one lock shard, indexed by `index_size` bits of the salted hash. Starts with the store's initial
+// index size and gains one whenever the table grows, see `HashStore::grow`.
+#[derive(Debug)]
+struct BucketShard {
+    index_size: usize,
+    // Key of the index within the shard once the store rotates bucket salts, see `HashStore::rotate_bucket_salt`
+    bucket_salt: Option<[u8; 32]>,
+    buckets: Vec<Bucket>,
+    // Hashes inserted so far, to notice inserts while the shard is split outside its write lock
+    inserts: usize,
+}
+
+// Filled buckets, heap bytes and chain lengths of a shard, to adjust the counters of the store when it is replaced
+#[derive(Debug, Clone, Copy, Default)]
+struct ShardUsage {
+    buckets_filled: usize,
+    node_bytes: usize,
+    chain_lengths: [usize; CHAIN_LENGTH_HISTOGRAM_SIZE],
+}
+
+impl BucketShard {
+    fn hashes(&self) -> Vec<Hash512> {
+        self.buckets.iter().flatten().flat_map(|bucket| bucket.iter().map(|(hash, _)| *hash)).collect()
     }
 
-    fn to_bytes(&self) -> Vec<u8> {
-        self.iter().flat_map(|&u64_val| u64_val.to_le_bytes()).collect()
+    fn usage(&self) -> ShardUsage {
+        let mut usage = ShardUsage::default();
+        for bucket in &self.buckets {
+            usage.chain_lengths[chain_length_entry(bucket.as_ref().map_or(0, |bucket| bucket.len()))] += 1;
+            if let Some(bucket) = bucket {
+                usage.buckets_filled += 1;
+                usage.node_bytes += size_of::<Vec<Entry>>() + bucket.capacity() * size_of::<Entry>();
+            }
+        }
+        usage
     }
+}
+
+// When a store doubles its bucket table, see `HashStore::grow`. Tables never shrink.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ResizePolicy {
+    // Average number of hashes per bucket slot above which the table grows
+    pub max_chain_length: usize,
+    // Index bits at which the table stops growing
+    pub max_index_size: usize,
+}
 
-    fn to_index(&self, prefix_size: usize, index_size: usize) -> usize {
-        // Extract index_size bits starting from prefix_size, assuming prefix_size + index_size <= 64
-        if prefix_size + index_size > 64 { panic!("Prefix size + index size must be less than or equal to 64"); }
-        if index_size == 0 { return 0; }
+// Most hashes a single bucket holds, see `HashStore::set_chain_limit`. Unlike the average of the resize policy,
+// it bounds the lookups of every hash, also when hashes pile up in a few buckets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChainLimit {
+    pub max_length: usize,
+    pub overflow: ChainOverflow,
+}
+
+// What happens to a new hash whose bucket is at the `ChainLimit`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChainOverflow {
+    // Insert it anyway and grow the table, until the resize policy's maximum index size
+    Resize,
+    // Fail the add with `StorageError::BucketFull`
+    Reject,
+    // Keep it in a sorted map next to the buckets, which every lookup of a hash missing its bucket reads too
+    Spill,
+}
+
+impl ChainOverflow {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ChainOverflow::Resize => "resize",
+            ChainOverflow::Reject => "reject",
+            ChainOverflow::Spill => "spill",
+        }
+    }
 
-        // Only use the first u64
-        ((self[0] << prefix_size) >> (64 - index_size)) as usize
+    pub fn from_name(name: &str) -> Option<Self> {
+        [ChainOverflow::Resize, ChainOverflow::Reject, ChainOverflow::Spill].into_iter().find(|overflow| overflow.name() == name)
     }
 }
 
-#[derive(Debug, Clone)]
-pub struct HashLL {
-    pub hash: Hash512,
-    pub next: Option<Box<HashLL>>,
+// Progress of a table resize of one worker, as reported in `StoreStats`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+pub struct ResizeProgress {
+    pub worker: usize,
+    // Index bits of the table being built
+    pub index_size: usize,
+    pub resized_shards: usize,
+    pub shards: usize,
 }
 
-impl HashLL {
-    pub fn new(hash: Hash512, next: Option<Box<HashLL>>) -> Self {
-        Self { hash, next }
+// Bytes used for the hashes of a store and the trees built from them. Only the memory growing
+// with the number of hashes is counted, metadata values shared by a batch are counted once per entry.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, ToSchema)]
+pub struct MemoryUsage {
+    // Slot array of all buckets, allocated up front
+    pub buckets: usize,
+    // Allocated bucket vectors, including their spare capacity
+    pub nodes: usize,
+    pub metadata: usize,
+    // Filled in by `TimestampingService::memory_usage`, a store alone has no trees
+    pub merkle_trees: usize,
+    // Shared by all workers, counted once by `MultiThreadedHashStore::stats`
+    pub bloom_filter: usize,
+    // Fence pointers of the hashes moved to disk, see `HashStore::move_to_cold`
+    pub cold_index: usize,
+}
+
+impl MemoryUsage {
+    pub fn total(&self) -> usize {
+        self.buckets + self.nodes + self.metadata + self.merkle_trees + self.bloom_filter + self.cold_index
     }
 }
 
-fn hash512(a: Hash512, b: Hash512) -> Hash512 {
-    let mut hasher = Sha512::new();
-    hasher.update(&a.to_bytes());
-    hasher.update(&b.to_bytes());
-    let result = hasher.finalize();
-    Hash512::from_bytes(&result).unwrap()
+// Limits above which new hashes are rejected with `StorageError`, unlimited by default.
+// Hashes restored from leaf logs or replicated from a primary are always accepted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StoreLimits {
+    pub max_hashes: Option<usize>,
+    // Compared to the nodes and metadata of `MemoryUsage`. The bucket slots are left out, they are
+    // allocated up front and when the table grows.
+    pub max_memory: Option<usize>,
+}
+
+impl StoreLimits {
+    // Share of each of `workers` stores the hashes are spread over evenly
+    fn per_worker(&self, workers: usize) -> Self {
+        Self {
+            max_hashes: self.max_hashes.map(|limit| limit / workers),
+            max_memory: self.max_memory.map(|limit| limit / workers),
+        }
+    }
 }
 
+const METADATA_ENTRY_SIZE: usize = size_of::<Hash512>() + size_of::<Arc<HashMetadata>>();
+const RESUBMISSIONS_ENTRY_SIZE: usize = size_of::<Hash512>() + size_of::<Resubmissions>();
+// Rough size of an entry of the spilled hashes, with its share of the map's nodes
+const SPILLED_ENTRY_SIZE: usize = 2 * size_of::<Entry>();
+
 #[derive(Debug)]
 pub struct HashStore<const INDEX_SIZE: usize, const PREFIX_SIZE: usize> {
-    data: Arc<RwLock<Vec<Option<Box<HashLL>>>>>,
+    shards: Vec<RwLock<BucketShard>>,
     salt: Hash512,
-    num_elements: Arc<RwLock<usize>>,
-    buckets_filled: Arc<RwLock<usize>>,
+    hasher: Arc<dyn Hasher>,
+    num_elements: AtomicUsize,
+    buckets_filled: AtomicUsize,
+    // Heap bytes of all allocated buckets
+    node_bytes: AtomicUsize,
+    // Bucket slots by the number of hashes they hold, see `chain_length_histogram`
+    chain_lengths: [AtomicUsize; CHAIN_LENGTH_HISTOGRAM_SIZE],
+    // Only hashes submitted with metadata have an entry, keyed by salted hash
+    metadata: RwLock<HashMap<Hash512, Arc<HashMetadata>>>,
+    // Only hashes submitted again have an entry, keyed by salted hash. Kept in memory like the metadata.
+    resubmissions: RwLock<HashMap<Hash512, Resubmissions>>,
+    // Removed salted hashes. They stay in the buckets and in every tree, but are no longer reported as stored.
+    tombstones: RwLock<HashSet<Hash512>>,
+    limits: RwLock<StoreLimits>,
+    bucket_slots: AtomicUsize,
+    // Index bits of every shard once no resize is running
+    index_size: AtomicUsize,
+    resize_policy: RwLock<Option<ResizePolicy>>,
+    // Index size being resized to, 0 while no resize is running and `RESIZE_STARTING` until it is known
+    resize_target: AtomicUsize,
+    resized_shards: AtomicUsize,
+    // Every new salted hash is inserted, see `set_bloom_filter`
+    bloom_filter: RwLock<Option<Arc<BloomFilter>>>,
+    // Sequence number of the next new hash. Shared by the workers of a `MultiThreadedHashStore`,
+    // so the numbers are unique across them.
+    next_sequence: Arc<AtomicU64>,
+    // Shared along with `next_sequence`
+    sequence_clock: Arc<SequenceClock>,
+    // Hashes moved out of the buckets, see `move_to_cold`. Still counted in `num_elements`.
+    cold: RwLock<Option<ColdIndex>>,
+    chain_limit: RwLock<Option<ChainLimit>>,
+    // New hashes that found their bucket at the chain limit
+    chain_overflows: AtomicUsize,
+    // Set when a bucket overflowed with `ChainOverflow::Resize`, until the table grew
+    chain_overflowed: AtomicBool,
+    // Salted hashes and sequence numbers of those that didn't fit their bucket with `ChainOverflow::Spill`.
+    // Counted in `num_elements`, they stay here when the table grows.
+    spilled: RwLock<BTreeMap<Hash512, u64>>,
 }
 
 impl<const INDEX_SIZE: usize, const PREFIX_SIZE: usize> HashStore<INDEX_SIZE, PREFIX_SIZE> {
+    const SHARD_BITS: usize = if INDEX_SIZE < LOCK_SHARD_BITS { INDEX_SIZE } else { LOCK_SHARD_BITS };
+    const RESIZE_STARTING: usize = usize::MAX;
+
     pub fn new(salt: Hash512) -> Self {
-        let total_buckets = 1 << INDEX_SIZE;
+        Self::with_hasher(salt, Arc::new(Sha512Hasher))
+    }
+
+    pub fn with_hasher(salt: Hash512, hasher: Arc<dyn Hasher>) -> Self {
+        Self::with_index_size(salt, hasher, INDEX_SIZE)
+    }
+
+    // Table starting with `index_size` index bits instead of INDEX_SIZE. It needs at least as many as there are
+    // lock shards, and grows beyond it like any other, see `grow`.
+    pub fn with_index_size(salt: Hash512, hasher: Arc<dyn Hasher>, index_size: usize) -> Self {
+        assert!(
+            (Self::SHARD_BITS..=64 - PREFIX_SIZE).contains(&index_size),
+            "Index size must be between {} and {}", Self::SHARD_BITS, 64 - PREFIX_SIZE,
+        );
+        let buckets_per_shard = 1 << (index_size - Self::SHARD_BITS);
         Self {
-            data: Arc::new(RwLock::new(vec![None; total_buckets])),
+            shards: (0..1 << Self::SHARD_BITS)
+                .map(|_| RwLock::new(BucketShard { index_size, bucket_salt: None, buckets: vec![None; buckets_per_shard], inserts: 0 }))
+                .collect(),
             salt,
-            num_elements: Arc::new(RwLock::new(0)),
-            buckets_filled: Arc::new(RwLock::new(0)),
+            hasher,
+            num_elements: AtomicUsize::new(0),
+            buckets_filled: AtomicUsize::new(0),
+            node_bytes: AtomicUsize::new(0),
+            chain_lengths: std::array::from_fn(|len| AtomicUsize::new(if len == 0 { 1 << index_size } else { 0 })),
+            metadata: RwLock::new(HashMap::new()),
+            resubmissions: RwLock::new(HashMap::new()),
+            tombstones: RwLock::new(HashSet::new()),
+            limits: RwLock::new(StoreLimits::default()),
+            bucket_slots: AtomicUsize::new(1 << index_size),
+            index_size: AtomicUsize::new(index_size),
+            resize_policy: RwLock::new(None),
+            resize_target: AtomicUsize::new(0),
+            resized_shards: AtomicUsize::new(0),
+            bloom_filter: RwLock::new(None),
+            next_sequence: Arc::new(AtomicU64::new(0)),
+            sequence_clock: Arc::new(SequenceClock::starting_at(unix_timestamp(SystemTime::now()), 0)),
+            cold: RwLock::new(None),
+            chain_limit: RwLock::new(None),
+            chain_overflows: AtomicUsize::new(0),
+            chain_overflowed: AtomicBool::new(false),
+            spilled: RwLock::new(BTreeMap::new()),
         }
     }
 
-    pub fn add_hash(&self, hash: Hash512) -> bool {
-        let salted_hash = hash512(hash, self.salt);
-        let index = salted_hash.to_index(PREFIX_SIZE, INDEX_SIZE);
-        let mut data = self.data.write().unwrap();
-
-        if data[index].is_none() {
-            // Add hash to new bucket
-            data[index] = Some(Box::new(HashLL::new(salted_hash, None)));
-            *self.buckets_filled.write().unwrap() += 1;
-            *self.num_elements.write().unwrap() += 1;
-            return true;
+    // Make the locks usable again after a thread panicked while holding one, see `MultiThreadedHashStore::supervise_worker`
+    fn clear_poison(&self) {
+        for shard in &self.shards {
+            shard.clear_poison();
         }
+        self.metadata.clear_poison();
+        self.resubmissions.clear_poison();
+        self.tombstones.clear_poison();
+        self.limits.clear_poison();
+        self.resize_policy.clear_poison();
+        self.bloom_filter.clear_poison();
+        self.cold.clear_poison();
+        self.chain_limit.clear_poison();
+        self.spilled.clear_poison();
+    }
 
-        // Check if hash already exists and find insertion point
-        {
-            let bucket = data[index].as_ref().unwrap();
-            if salted_hash == bucket.hash {
-                return false; // Hash already exists
-            }
+    // Take sequence numbers from `counter` instead of counting on its own, noting when in `clock`
+    fn sharing_sequence(mut self, counter: Arc<AtomicU64>, clock: Arc<SequenceClock>) -> Self {
+        self.next_sequence = counter;
+        self.sequence_clock = clock;
+        self
+    }
 
-            if salted_hash < bucket.hash {
-                // Insert at the front
-                let old_bucket = data[index].take().unwrap();
-                data[index] = Some(Box::new(HashLL::new(salted_hash, Some(old_bucket))));
-                *self.num_elements.write().unwrap() += 1;
-                return true;
-            }
-        }
+    pub fn with_limits(self, limits: StoreLimits) -> Self {
+        self.set_limits(limits);
+        self
+    }
 
-        // Traverse the linked list to find the correct insertion point
-        let bucket = data[index].as_mut().unwrap();
-        let mut current = bucket;
+    pub fn set_limits(&self, limits: StoreLimits) {
+        *self.limits.write().unwrap() = limits;
+    }
 
-        loop {
-            if let Some(next_node) = &current.next {
-                if salted_hash == next_node.hash {
-                    return false; // Hash already exists
-                }
-                if salted_hash < next_node.hash {
-                    // Insert between current and next
-                    let old_next = current.next.take();
-                    current.next = Some(Box::new(HashLL::new(salted_hash, old_next)));
-                    *self.num_elements.write().unwrap() += 1;
-                    return true;
-                }
-                // Move to next node
-                current = current.next.as_mut().unwrap();
-            } else {
-                // Insert at the end
-                current.next = Some(Box::new(HashLL::new(salted_hash, None)));
-                *self.num_elements.write().unwrap() += 1;
-                return true;
-            }
-        }
+    // Grow the table according to `policy`, see `needs_resize`
+    pub fn set_resize_policy(&self, policy: Option<ResizePolicy>) {
+        *self.resize_policy.write().unwrap() = policy;
     }
 
-    pub fn len(&self) -> usize {
-        *self.num_elements.read().unwrap()
+    // Handle new hashes whose bucket holds `limit.max_length` hashes already as `limit.overflow` says
+    pub fn set_chain_limit(&self, limit: Option<ChainLimit>) {
+        *self.chain_limit.write().unwrap() = limit;
     }
 
-    pub fn occupied_slots(&self) -> usize {
-        *self.buckets_filled.read().unwrap()
+    // New hashes that found their bucket full, see `set_chain_limit`
+    pub fn chain_overflows(&self) -> usize {
+        self.chain_overflows.load(Ordering::Relaxed)
     }
 
-    pub fn contains(&self, hash: &Hash512) -> bool {
-        let salted_hash = hash512(*hash, self.salt);
-        let index = salted_hash.to_index(PREFIX_SIZE, INDEX_SIZE);
-        let data = self.data.read().unwrap();
+    // Hashes kept outside the buckets with `ChainOverflow::Spill`
+    pub fn spilled_len(&self) -> usize {
+        self.spilled.read().unwrap().len()
+    }
 
-        if let Some(node) = &data[index] {
-            let mut current = node;
-            loop {
-                if current.hash == salted_hash {
-                    return true;
-                }
-                match &current.next {
-                    Some(next) => current = next,
-                    None => break,
-                }
-            }
-        }
-        false
+    // Hashes in the bucket of a salted hash
+    fn chain_length(&self, salted_hash: &Hash512) -> usize {
+        let shard = self.shards[Self::shard_index(salted_hash)].read().unwrap();
+        shard.buckets[Self::position(&shard, salted_hash)].as_ref().map_or(0, |bucket| bucket.len())
     }
 
-    pub fn to_array(&self) -> Vec<Hash512> {
-        let mut hashes = Vec::new();
-        let data = self.data.read().unwrap();
-
-        for bucket in data.iter() {
-            if let Some(node) = bucket {
-                let mut current = node;
-                loop {
-                    hashes.push(current.hash);
-                    match &current.next {
-                        Some(next) => current = next,
-                        None => break,
-                    }
-                }
+    // Insert every stored and every new salted hash into `filter`, which may be shared with other stores.
+    // Adds running concurrently may be missed, so the filter should be set before the store is used.
+    pub fn set_bloom_filter(&self, filter: Arc<BloomFilter>) {
+        *self.bloom_filter.write().unwrap() = Some(Arc::clone(&filter));
+        self.for_each_chunk(|chunk| {
+            for salted_hash in chunk {
+                filter.insert(salted_hash);
             }
-        }
+        });
+    }
 
-        hashes
+    // Salted leaf a submitted hash is stored as
+    fn leaf(&self, hash: &Hash512, encoding: LeafEncoding) -> Hash512 {
+        encoding.leaf_with_hasher(&*self.hasher, hash, &self.salt)
     }
-}
 
-#[derive(Debug)]
-pub struct MultiThreadedHashStore<const INDEX_SIZE: usize, const PREFIX_SIZE: usize> {
-    threads: Vec<Sender<HashCommand>>,
-    salt: Hash512,
-}
+    // Lock shard of a salted hash, from the top bits of its index
+    fn shard_index(salted_hash: &Hash512) -> usize {
+        salted_hash.to_index(PREFIX_SIZE, Self::SHARD_BITS)
+    }
 
-#[derive(Debug)]
-enum HashCommand {
-    AddHash(Hash512),
-    Contains(Hash512, Sender<bool>),
-    GetArray(Sender<Vec<Hash512>>),
-    GetLen(Sender<usize>),
-    GetOccupiedSlots(Sender<usize>),
-}
+    // Position of a salted hash's bucket within its shard
+    fn position(shard: &BucketShard, salted_hash: &Hash512) -> usize {
+        Self::index(shard.bucket_salt.as_ref(), salted_hash, shard.index_size) & (shard.buckets.len() - 1)
+    }
 
-impl<const INDEX_SIZE: usize, const PREFIX_SIZE: usize> MultiThreadedHashStore<INDEX_SIZE, PREFIX_SIZE> {
-    pub fn new(num_threads: usize, salt: Hash512) -> Self {
-        // Ensure num_threads is a power of 2
-        if !num_threads.is_power_of_two() {
-            panic!("Number of threads must be a power of 2");
+    // `index_size` bits of the salted hash, or of its keyed hash with a bucket salt. Like the salted hash itself,
+    // the keyed hash gains one bit at the bottom when the index grows by one.
+    fn index(bucket_salt: Option<&[u8; 32]>, salted_hash: &Hash512, index_size: usize) -> usize {
+        let Some(bucket_salt) = bucket_salt else {
+            return salted_hash.to_index(PREFIX_SIZE, index_size);
+        };
+        let mut hasher = blake3::Hasher::new_keyed(bucket_salt);
+        for word in salted_hash.0 {
+            hasher.update(&word.to_le_bytes());
         }
-        let mut threads = Vec::new();
+        let keyed = u64::from_le_bytes(hasher.finalize().as_bytes()[..8].try_into().unwrap());
+        Hash512([keyed, 0, 0, 0, 0, 0, 0, 0]).to_index(0, index_size)
+    }
 
-        for _ in 0..num_threads {
-            let (tx, rx) = channel();
-            threads.push(tx);
+    // Add a hash and return whether it was new. Fails only for new hashes once a limit is reached.
+    pub fn add_hash(&self, hash: Hash512) -> Result<bool, StorageError> {
+        self.add_hash_with_encoding(hash, LeafEncoding::default())
+    }
 
-            let store = HashStore::<INDEX_SIZE, PREFIX_SIZE>::new(salt);
+    pub fn add_hash_with_encoding(&self, hash: Hash512, encoding: LeafEncoding) -> Result<bool, StorageError> {
+        self.add_salted_hash_with_metadata(self.leaf(&hash, encoding), None)
+    }
 
-            thread::spawn(move || {
-                Self::hash_store_worker(store, rx);
-            });
-        }
+    // Like `add_hash_with_encoding`, storing `metadata` if the hash is new
+    pub fn add_hash_with_metadata(&self, hash: Hash512, encoding: LeafEncoding, metadata: HashMetadata) -> Result<bool, StorageError> {
+        self.add_salted_hash_with_metadata(self.leaf(&hash, encoding), Some(Arc::new(metadata)))
+    }
 
-        Self {
-            threads,
-            salt,
+    // Metadata is only kept for the first submission of a hash, later ones are counted in its `Resubmissions`
+    fn add_salted_hash_with_metadata(&self, salted_hash: Hash512, metadata: Option<Arc<HashMetadata>>) -> Result<bool, StorageError> {
+        let is_new = self.try_add_salted_hash(salted_hash)?;
+        match metadata {
+            Some(metadata) if is_new => {
+                self.metadata.write().unwrap().insert(salted_hash, metadata);
+            }
+            _ if !is_new && !self.is_tombstoned(&salted_hash) => {
+                let mut resubmissions = self.resubmissions.write().unwrap();
+                let resubmissions = resubmissions.entry(salted_hash).or_default();
+                resubmissions.count += 1;
+                resubmissions.last_seen = unix_timestamp(SystemTime::now());
+            }
+            _ => {}
         }
+        Ok(is_new)
     }
 
-    fn hash_store_worker(store: HashStore<INDEX_SIZE, PREFIX_SIZE>, rx: Receiver<HashCommand>) {
-        while let Ok(cmd) = rx.recv() {
-            match cmd {
-                HashCommand::AddHash(hash) => {
-                    let _is_new = store.add_hash(hash);
+    // `add_salted_hash` within the limits. Checked before inserting, so concurrent writers can
+    // overshoot a limit by a few hashes.
+    fn try_add_salted_hash(&self, salted_hash: Hash512) -> Result<bool, StorageError> {
+        let full = match *self.limits.read().unwrap() {
+            StoreLimits { max_hashes: Some(limit), .. } if self.len() >= limit => Some(StorageError::MaxHashes(limit)),
+            StoreLimits { max_memory: Some(limit), .. } if self.growing_memory() + size_of::<Entry>() > limit => Some(StorageError::MaxMemory(limit)),
+            _ => match *self.chain_limit.read().unwrap() {
+                Some(ChainLimit { max_length, overflow: ChainOverflow::Reject }) if self.chain_length(&salted_hash) >= max_length => {
+                    Some(StorageError::BucketFull(max_length))
                 }
-                HashCommand::Contains(hash, tx) => {
-                    let exists = store.contains(&hash);
-                    let _ = tx.send(exists);
+                _ => None,
+            },
+        };
+        match full {
+            // Resubmitting a stored hash still works in a full store or bucket
+            Some(full) if !self.is_stored(&salted_hash) => {
+                if let StorageError::BucketFull(_) = full {
+                    self.chain_overflows.fetch_add(1, Ordering::Relaxed);
                 }
-                HashCommand::GetArray(tx) => {
-                    let array = store.to_array();
-                    let _ = tx.send(array);
+                Err(full)
+            }
+            Some(_) => Ok(false),
+            None => Ok(self.add_salted_hash(salted_hash)),
+        }
+    }
+
+    // Insert an already salted hash with the next sequence number, keeping the bucket sorted
+    fn add_salted_hash(&self, salted_hash: Hash512) -> bool {
+        self.insert_salted_hash(salted_hash, None)
+    }
+
+    // Like `add_salted_hash`, with the given sequence number if it is restored from a log
+    fn insert_salted_hash(&self, salted_hash: Hash512, sequence: Option<u64>) -> bool {
+        let mut shard = self.shards[Self::shard_index(&salted_hash)].write().unwrap();
+        let position = Self::position(&shard, &salted_hash);
+
+        let bucket = shard.buckets[position].get_or_insert_with(|| {
+            self.buckets_filled.fetch_add(1, Ordering::Relaxed);
+            self.node_bytes.fetch_add(size_of::<Vec<Entry>>(), Ordering::Relaxed);
+            Box::default()
+        });
+        match bucket.binary_search_by(|(hash, _)| hash.cmp(&salted_hash)) {
+            Ok(_) => false, // Hash already exists
+            // Restored hashes with a number are above the bound of the cold index, so they can't be in it
+            Err(_) if sequence.is_none() && self.cold_sequence(&salted_hash).is_some() => false,
+            Err(_) if self.spilled_sequence(&salted_hash).is_some() => false,
+            Err(insert_position) => {
+                let sequence = match sequence {
+                    Some(sequence) => {
+                        self.next_sequence.fetch_max(sequence + 1, Ordering::Relaxed);
+                        sequence
+                    }
+                    None => {
+                        self.sequence_clock.record(unix_timestamp(SystemTime::now()), &self.next_sequence);
+                        self.next_sequence.fetch_add(1, Ordering::Relaxed)
+                    }
+                };
+                let overflow = self.chain_limit.read().unwrap()
+                    .filter(|limit| bucket.len() >= limit.max_length)
+                    .map(|limit| limit.overflow);
+                if overflow.is_some() {
+                    self.chain_overflows.fetch_add(1, Ordering::Relaxed);
                 }
-                HashCommand::GetLen(tx) => {
-                    let len = store.len();
-                    let _ = tx.send(len);
+                match overflow {
+                    Some(ChainOverflow::Spill) => {
+                        self.spilled.write().unwrap().insert(salted_hash, sequence);
+                        self.num_elements.fetch_add(1, Ordering::Relaxed);
+                        if let Some(filter) = &*self.bloom_filter.read().unwrap() {
+                            filter.insert(&salted_hash);
+                        }
+                        return true;
+                    }
+                    Some(ChainOverflow::Resize) => self.chain_overflowed.store(true, Ordering::Relaxed),
+                    // Rejected before, only hashes restored from a log get here
+                    Some(ChainOverflow::Reject) | None => {}
                 }
-                HashCommand::GetOccupiedSlots(tx) => {
-                    let slots = store.occupied_slots();
-                    let _ = tx.send(slots);
+                let capacity = bucket.capacity();
+                bucket.insert(insert_position, (salted_hash, sequence));
+                self.chain_lengths[chain_length_entry(bucket.len())].fetch_add(1, Ordering::Relaxed);
+                self.chain_lengths[chain_length_entry(bucket.len() - 1)].fetch_sub(1, Ordering::Relaxed);
+                self.node_bytes.fetch_add((bucket.capacity() - capacity) * size_of::<Entry>(), Ordering::Relaxed);
+                self.num_elements.fetch_add(1, Ordering::Relaxed);
+                shard.inserts += 1;
+                if let Some(filter) = &*self.bloom_filter.read().unwrap() {
+                    filter.insert(&salted_hash);
                 }
+                true
             }
         }
     }
 
-    pub fn add_hash(&self, hash: Hash512) -> bool {
-        let thread_index = hash.to_index(0, (self.threads.len() as f64).log2().ceil() as usize);
-        let tx = &self.threads[thread_index];
+    fn growing_memory(&self) -> usize {
+        let memory = self.memory_usage();
+        memory.nodes + memory.metadata
+    }
+
+    pub fn memory_usage(&self) -> MemoryUsage {
+        MemoryUsage {
+            buckets: self.bucket_slots() * size_of::<Bucket>(),
+            nodes: self.node_bytes.load(Ordering::Relaxed) + self.spilled_len() * SPILLED_ENTRY_SIZE,
+            metadata: self.metadata.read().unwrap().len() * METADATA_ENTRY_SIZE
+                + self.resubmissions.read().unwrap().len() * RESUBMISSIONS_ENTRY_SIZE,
+            merkle_trees: 0,
+            bloom_filter: 0,
+            cold_index: self.cold.read().unwrap().as_ref().map_or(0, ColdIndex::memory_usage),
+        }
+    }
 
-        let _ = tx.send(HashCommand::AddHash(hash));
+    pub fn len(&self) -> usize {
+        self.num_elements.load(Ordering::Relaxed)
+    }
 
-        // TODO: return the result of the add_hash operation
-        true
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
     }
 
-    pub fn contains(&self, hash: &Hash512) -> bool {
-        let thread_index = hash.to_index(0, (self.threads.len() as f64).log2().ceil() as usize);
-        let tx = &self.threads[thread_index];
-        let (response_tx, response_rx) = channel();
+    pub fn occupied_slots(&self) -> usize {
+        self.buckets_filled.load(Ordering::Relaxed)
+    }
 
-        let _ = tx.send(HashCommand::Contains(*hash, response_tx));
-        response_rx.recv().unwrap_or(false)
+    pub fn bucket_slots(&self) -> usize {
+        self.bucket_slots.load(Ordering::Relaxed)
     }
 
-    pub fn len(&self) -> usize {
-        let mut total = 0;
-        for tx in &self.threads {
-            let (response_tx, response_rx) = channel();
-            let _ = tx.send(HashCommand::GetLen(response_tx));
-            total += response_rx.recv().unwrap_or(0);
-        }
-        total
+    // Index bits of the table, the initial index size until it grows
+    pub fn index_size(&self) -> usize {
+        self.index_size.load(Ordering::Relaxed)
     }
 
-    pub fn occupied_slots(&self) -> usize {
-        let mut total = 0;
-        for tx in &self.threads {
-            let (response_tx, response_rx) = channel();
-            let _ = tx.send(HashCommand::GetOccupiedSlots(response_tx));
-            total += response_rx.recv().unwrap_or(0);
+    // Whether the average chain is longer than the resize policy allows, or a bucket overflowed with `ChainOverflow::Resize`,
+    // and the table may still grow
+    pub fn needs_resize(&self) -> bool {
+        let Some(policy) = *self.resize_policy.read().unwrap() else {
+            return false;
+        };
+        self.index_size() < policy.max_index_size.min(64 - PREFIX_SIZE)
+            && (self.len() > policy.max_chain_length.saturating_mul(self.bucket_slots()) || self.chain_overflowed.load(Ordering::Relaxed))
+    }
+
+    // Shards resized so far and the index size they get, `None` if no resize is running
+    pub fn resize_progress(&self) -> Option<(usize, usize)> {
+        match self.resize_target.load(Ordering::Relaxed) {
+            0 | Self::RESIZE_STARTING => None,
+            target => Some((self.resized_shards.load(Ordering::Relaxed), target)),
         }
-        total
     }
 
-    pub fn to_array(&self) -> Vec<Hash512> {
-        let mut all_hashes = Vec::new();
+    // Double the bucket table by splitting every bucket in two, one shard at a time. Reads of a shard only
+    // wait while its split buckets are swapped in, writes to it wait until it is split.
+    // Returns false if another resize is running or the store can't grow.
+    pub fn grow(&self) -> bool {
+        self.grow_with(false, |_| {})
+    }
 
-        // Collect arrays from all threads
-        for tx in &self.threads {
-            let (response_tx, response_rx) = channel();
-            let _ = tx.send(HashCommand::GetArray(response_tx));
-            if let Ok(array) = response_rx.recv() {
-                all_hashes.extend(array);
-            }
+    // Like `grow`, only if `needs_resize` once no other resize is running, calling `progress` after every shard
+    fn grow_with(&self, only_if_needed: bool, mut progress: impl FnMut(&Self)) -> bool {
+        if self.resize_target.compare_exchange(0, Self::RESIZE_STARTING, Ordering::Relaxed, Ordering::Relaxed).is_err() {
+            return false;
         }
+        let target = self.index_size() + 1;
+        if target > 64 || (only_if_needed && !self.needs_resize()) {
+            self.resize_target.store(0, Ordering::Relaxed);
+            return false;
+        }
+        self.resize_target.store(target, Ordering::Relaxed);
+        self.resized_shards.store(0, Ordering::Relaxed);
+        for shard in &self.shards {
+            self.grow_shard(shard, target);
+            self.resized_shards.fetch_add(1, Ordering::Relaxed);
+            progress(self);
+        }
+        self.index_size.store(target, Ordering::Relaxed);
+        self.chain_overflowed.store(false, Ordering::Relaxed);
+        self.resize_target.store(0, Ordering::Relaxed);
+        progress(self);
+        true
+    }
 
-        all_hashes
+    fn grow_shard(&self, shard: &RwLock<BucketShard>, target: usize) {
+        if shard.read().unwrap().index_size < target {
+            self.rebuild_shard(shard, Self::split);
+        }
     }
-}
 
-#[derive(Debug, Clone)]
-pub struct MerkleTree {
-    pub data: Vec<Hash512>,
-    pub salt: Hash512,
-    pub depth: usize,
-    pub leaf_count: usize,
-}
+    // Replace a shard by `rebuild` of it. It is rebuilt under the read lock and swapped in under the write lock,
+    // rebuilding it again if it was inserted into between the locks, which only the worker owning the store does.
+    fn rebuild_shard(&self, shard: &RwLock<BucketShard>, rebuild: impl Fn(&BucketShard) -> BucketShard) {
+        let (rebuilt, inserts, usage) = {
+            let shard = shard.read().unwrap();
+            (rebuild(&shard), shard.inserts, shard.usage())
+        };
+        let mut shard = shard.write().unwrap();
+        let (rebuilt, usage) = if shard.inserts == inserts { (rebuilt, usage) } else { (rebuild(&shard), shard.usage()) };
+        let rebuilt_usage = rebuilt.usage();
+        self.bucket_slots.fetch_add(rebuilt.buckets.len() - shard.buckets.len(), Ordering::Relaxed);
+        self.buckets_filled.fetch_add(rebuilt_usage.buckets_filled, Ordering::Relaxed);
+        self.buckets_filled.fetch_sub(usage.buckets_filled, Ordering::Relaxed);
+        self.node_bytes.fetch_add(rebuilt_usage.node_bytes, Ordering::Relaxed);
+        self.node_bytes.fetch_sub(usage.node_bytes, Ordering::Relaxed);
+        self.replace_chain_lengths(&usage, &rebuilt_usage);
+        *shard = rebuilt;
+    }
 
-impl MerkleTree {
-    pub fn new(data: Vec<Hash512>, salt: Hash512) -> Self {
-        let n = data.len();
-        if n == 0 {
-            return Self {
-                data: vec![],
-                salt,
-                depth: 0,
-                leaf_count: 0,
+    // Shard with one more index bit, every bucket split into the two it covers. The split buckets are
+    // allocated to fit, which also compacts the spare capacity of the old ones.
+    fn split(shard: &BucketShard) -> BucketShard {
+        let index_size = shard.index_size + 1;
+        let mut buckets: Vec<Bucket> = vec![None; shard.buckets.len() * 2];
+        for (position, bucket) in shard.buckets.iter().enumerate() {
+            let Some(bucket) = bucket else {
+                continue;
             };
+            // Both halves stay sorted. Without a bucket salt the hashes without the new index bit also sort first.
+            let (low, high): (Vec<Entry>, Vec<Entry>) = bucket.iter()
+                .partition(|(hash, _)| Self::index(shard.bucket_salt.as_ref(), hash, index_size) & 1 == 0);
+            for (half, mut hashes) in [low, high].into_iter().enumerate() {
+                if !hashes.is_empty() {
+                    hashes.shrink_to_fit();
+                    buckets[2 * position + half] = Some(Box::new(hashes));
+                }
+            }
         }
-        let depth = (n as f64).log2().ceil() as usize;
-        let tree_size = (1 << (depth + 1)) - 1;
+        BucketShard { index_size, bucket_salt: shard.bucket_salt, buckets, inserts: shard.inserts }
+    }
 
-        let mut tree_data = vec![[0u64; 8]; tree_size];
+    // Index the buckets by a keyed hash of the salted hashes under a new random bucket salt, moving every hash
+    // to its new bucket one shard at a time like `grow`. Anyone who learned the salt of the store can craft hashes
+    // that share a bucket in a table indexed by the salted hashes themselves, or under a bucket salt they found
+    // out, but not under one that replaced it. Reads and writes wait like they do for `grow`.
+    // Returns false if a resize is running, the next rotation gets its turn.
+    pub fn rotate_bucket_salt(&self) -> bool {
+        if self.resize_target.compare_exchange(0, Self::RESIZE_STARTING, Ordering::Relaxed, Ordering::Relaxed).is_err() {
+            return false;
+        }
+        for shard in &self.shards {
+            let bucket_salt: [u8; 32] = rand::random();
+            self.rebuild_shard(shard, |shard| Self::rekey(shard, bucket_salt));
+        }
+        self.resize_target.store(0, Ordering::Relaxed);
+        true
+    }
 
-        // Copy data to leaves (rightmost part of the tree)
-        let leaf_start = (1 << depth) - 1;
-        tree_data[leaf_start..leaf_start + n].copy_from_slice(&data[..n]);
+    fn rekey(shard: &BucketShard, bucket_salt: [u8; 32]) -> BucketShard {
+        let mut buckets: Vec<Bucket> = vec![None; shard.buckets.len()];
+        let mask = buckets.len() - 1;
+        for (hash, sequence) in shard.buckets.iter().flatten().flat_map(|bucket| bucket.iter()) {
+            let position = Self::index(Some(&bucket_salt), hash, shard.index_size) & mask;
+            buckets[position].get_or_insert_with(Box::default).push((*hash, *sequence));
+        }
+        for bucket in buckets.iter_mut().flatten() {
+            bucket.sort_unstable();
+            bucket.shrink_to_fit();
+        }
+        BucketShard { index_size: shard.index_size, bucket_salt: Some(bucket_salt), buckets, inserts: shard.inserts }
+    }
 
-        // Build tree from bottom up
-        for level in (0..depth).rev() {
-            let level_start = (1 << level) - 1;
-            let child_level_start = (1 << (level + 1)) - 1;
+    pub fn contains(&self, hash: &Hash512) -> bool {
+        self.contains_with_encoding(hash, LeafEncoding::default())
+    }
 
-            for i in 0..(1 << level) {
-                let parent_idx = level_start + i;
-                let left_child_idx = child_level_start + 2 * i;
-                let right_child_idx = child_level_start + 2 * i + 1;
+    pub fn contains_with_encoding(&self, hash: &Hash512, encoding: LeafEncoding) -> bool {
+        self.contains_salted(&self.leaf(hash, encoding))
+    }
 
-                tree_data[parent_idx] = hash512(tree_data[left_child_idx], tree_data[right_child_idx]);
-            }
-        }
-        Self {
-            data: tree_data,
-            salt,
-            depth,
-            leaf_count: n,
-        }
+    fn contains_salted(&self, salted_hash: &Hash512) -> bool {
+        self.is_stored(salted_hash) && !self.is_tombstoned(salted_hash)
     }
 
-    pub fn get(&self, hash: &Hash512) -> Option<Vec<(Hash512, Hash512)>> {
-        if self.leaf_count == 0 {
-            return None;
-        }
+    // Whether the salted hash is in its bucket, even if it was removed
+    fn is_stored(&self, salted_hash: &Hash512) -> bool {
+        self.stored_sequence(salted_hash).is_some()
+    }
 
-        let salted_hash = hash512(*hash, self.salt);
+    // Sequence number of a stored salted hash, even if it was removed
+    fn stored_sequence(&self, salted_hash: &Hash512) -> Option<u64> {
+        self.bucket_sequence(salted_hash).or_else(|| self.spilled_sequence(salted_hash)).or_else(|| self.cold_sequence(salted_hash))
+    }
 
-        // Find the hash in the leaves
-        let leaf_start = (1 << self.depth) - 1;
-        let mut hash_idx = None;
+    fn bucket_sequence(&self, salted_hash: &Hash512) -> Option<u64> {
+        let shard = self.shards[Self::shard_index(salted_hash)].read().unwrap();
+        let position = Self::position(&shard, salted_hash);
 
-        for i in 0..self.leaf_count {
-            if self.data[leaf_start + i] == salted_hash {
-                hash_idx = Some(i);
-                break;
-            }
-        }
+        let bucket = shard.buckets[position].as_ref()?;
+        bucket.binary_search_by(|(hash, _)| hash.cmp(salted_hash)).ok().map(|index| bucket[index].1)
+    }
 
-        let hash_idx = hash_idx?;
+    fn spilled_sequence(&self, salted_hash: &Hash512) -> Option<u64> {
+        self.spilled.read().unwrap().get(salted_hash).copied()
+    }
 
-        // Generate proof path from leaf to root
-        let mut proof = Vec::with_capacity(self.depth);
-        proof.push((*hash, self.salt.clone()));
-        let mut current_idx = hash_idx;
+    // Only hashes moved out of the buckets, an unreadable index counts as not storing the hash
+    fn cold_sequence(&self, salted_hash: &Hash512) -> Option<u64> {
+        let cold = self.cold.read().unwrap();
+        cold.as_ref()?.get(salted_hash).unwrap_or_else(|e| {
+            eprintln!("Failed to read cold index: {}", e);
+            None
+        })
+    }
 
-        for level in (0..self.depth).rev() {
-            let level_start = (1 << level) - 1;
-            let left_child_idx = level_start + (1 << level) + (current_idx & !1);
-            let right_child_idx = left_child_idx + 1;
+    // Hashes moved to disk
+    pub fn cold_len(&self) -> usize {
+        self.cold.read().unwrap().as_ref().map_or(0, ColdIndex::len)
+    }
 
-            if left_child_idx < self.data.len() && right_child_idx < self.data.len() {
-                proof.push((self.data[left_child_idx], self.data[right_child_idx]));
+    // Move every hash numbered below `bound` from the buckets to the cold index at `path`, which is rewritten with
+    // the hashes it already has. Lookups of moved hashes read a block from disk instead, adds of new hashes do so too.
+    // Returns how many hashes were moved, none if the index already has a bound at least as high.
+    pub fn move_to_cold(&self, path: &Path, bound: u64) -> io::Result<usize> {
+        let mut records = match &*self.cold.read().unwrap() {
+            Some(cold) if cold.bound() >= bound => return Ok(0),
+            Some(cold) => cold.records()?,
+            None => Vec::new(),
+        };
+        let cold_len = records.len();
+        for shard in &self.shards {
+            let shard = shard.read().unwrap();
+            for bucket in shard.buckets.iter().flatten() {
+                records.extend(bucket.iter().filter(|(_, sequence)| *sequence < bound));
             }
-
-            current_idx /= 2;
         }
+        let moved = records.len() - cold_len;
+        records.sort_unstable();
+        // The index has them before they leave the buckets, so lookups always find them in one of both
+        let index = ColdIndex::write(path, bound, &records)?;
+        *self.cold.write().unwrap() = Some(index);
+
+        for shard in &self.shards {
+            let mut shard = shard.write().unwrap();
+            let usage = shard.usage();
+            for bucket in shard.buckets.iter_mut() {
+                if let Some(entries) = bucket {
+                    entries.retain(|(_, sequence)| *sequence >= bound);
+                    entries.shrink_to_fit();
+                    if entries.is_empty() {
+                        *bucket = None;
+                    }
+                }
+            }
+            // Counted as an insert, so a split running meanwhile is redone, see `grow_shard`
+            shard.inserts += 1;
+            let remaining = shard.usage();
+            self.buckets_filled.fetch_sub(usage.buckets_filled - remaining.buckets_filled, Ordering::Relaxed);
+            self.node_bytes.fetch_sub(usage.node_bytes - remaining.node_bytes, Ordering::Relaxed);
+            self.replace_chain_lengths(&usage, &remaining);
+        }
+        Ok(moved)
+    }
 
-        Some(proof)
+    // Use the cold index of a restored store, whose hashes aren't added to the buckets again
+    fn set_cold_index(&self, index: ColdIndex) {
+        self.num_elements.fetch_add(index.len(), Ordering::Relaxed);
+        *self.cold.write().unwrap() = Some(index);
     }
 
-    pub fn root(&self) -> Option<Hash512> {
-        if self.data.is_empty() {
-            None
-        } else {
-            Some(self.data[0])
-        }
+    // Spilled hashes in order, then those of the cold index
+    fn outside_hashes(&self) -> impl Iterator<Item = Hash512> + use<INDEX_SIZE, PREFIX_SIZE> {
+        let spilled: Vec<Hash512> = self.spilled.read().unwrap().keys().copied().collect();
+        spilled.into_iter().chain(self.cold_hashes())
     }
 
-    pub fn size(&self) -> usize {
-        self.data.len()
+    // Hashes of the cold index, read from disk as they are iterated, none without one.
+    // A store that can't read them would publish trees missing them.
+    fn cold_hashes(&self) -> impl Iterator<Item = Hash512> + use<INDEX_SIZE, PREFIX_SIZE> {
+        let records = self.cold.read().unwrap().as_ref().map(|cold| cold.iter().expect("Failed to read cold index"));
+        records.into_iter().flatten().map(|record| record.expect("Failed to read cold index").0)
     }
-}
 
-#[derive(Debug, Clone)]
-pub struct TimestampingService<const INDEX_SIZE: usize, const PREFIX_SIZE: usize> {
-    pub hash_store: Arc<MultiThreadedHashStore<INDEX_SIZE, PREFIX_SIZE>>,
-    pub merkle_tree: Arc<RwLock<Option<MerkleTree>>>,
-    pub last_tree_update: Arc<RwLock<Option<SystemTime>>>,
-}
+    pub fn sequence(&self, hash: &Hash512) -> Option<u64> {
+        self.sequence_with_encoding(hash, LeafEncoding::default())
+    }
 
-impl<const INDEX_SIZE: usize, const PREFIX_SIZE: usize> TimestampingService<INDEX_SIZE, PREFIX_SIZE> {
-    pub fn with_threads(num_threads: usize) -> Self {
-        let salt = [rand::random(), rand::random(), rand::random(), rand::random(),
-                    rand::random(), rand::random(), rand::random(), rand::random()];
-        Self {
-            hash_store: Arc::new(MultiThreadedHashStore::new(num_threads, salt)),
-            merkle_tree: Arc::new(RwLock::new(None)),
-            last_tree_update: Arc::new(RwLock::new(None)),
-        }
+    // Number given to the hash when it was stored. Numbers increase with every new hash, so they show
+    // the order hashes were submitted in, and are kept across restarts. `None` if the hash isn't stored.
+    pub fn sequence_with_encoding(&self, hash: &Hash512, encoding: LeafEncoding) -> Option<u64> {
+        self.salted_sequence(&self.leaf(hash, encoding))
     }
 
-    pub fn update_merkle_tree(&self) {
-        let new_tree = MerkleTree::new(self.hash_store.to_array(), self.hash_store.salt);
+    fn salted_sequence(&self, salted_hash: &Hash512) -> Option<u64> {
+        self.stored_sequence(salted_hash).filter(|_| !self.is_tombstoned(salted_hash))
+    }
 
-        *self.merkle_tree.write().unwrap() = Some(new_tree);
-        *self.last_tree_update.write().unwrap() = Some(SystemTime::now());
+    fn is_tombstoned(&self, salted_hash: &Hash512) -> bool {
+        self.tombstones.read().unwrap().contains(salted_hash)
     }
 
-    pub fn get_merkle_proof(&self, hash: &Hash512) -> Option<Vec<(Vec<u8>, Vec<u8>)>> {
-        self.merkle_tree
-        .read().unwrap().as_ref()?
-        .get(hash)
-        .map(|proof| {
-            proof.into_iter()
-                .map(|(left, right)| (left.to_bytes(), right.to_bytes()))
-                .collect()
-        })
+    pub fn remove(&self, hash: &Hash512) -> bool {
+        self.remove_with_encoding(hash, LeafEncoding::default())
     }
 
-    pub fn get_merkle_tree_root_bytes(&self) -> Option<Vec<u8>> {
-        self.get_merkle_tree_root().map(|root| root.to_bytes())
+    // Remove a hash by adding a tombstone and dropping its metadata. The salted hash stays in the
+    // buckets, so trees built afterwards still have the same leaves and earlier proofs stay valid.
+    // Returns false if the hash isn't stored or was already removed.
+    pub fn remove_with_encoding(&self, hash: &Hash512, encoding: LeafEncoding) -> bool {
+        self.remove_salted(self.leaf(hash, encoding))
     }
 
-    pub fn get_last_update_timestamp(&self) -> Option<u64> {
-        self.last_tree_update
-            .read()
-            .unwrap()
-            .as_ref()
-            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
-            .map(|duration| duration.as_secs())
+    fn remove_salted(&self, salted_hash: Hash512) -> bool {
+        if !self.is_stored(&salted_hash) || !self.tombstones.write().unwrap().insert(salted_hash) {
+            return false;
+        }
+        self.metadata.write().unwrap().remove(&salted_hash);
+        self.resubmissions.write().unwrap().remove(&salted_hash);
+        true
     }
 
-    pub fn get_merkle_tree_size(&self) -> usize {
-        self.merkle_tree
-            .read()
-            .unwrap()
-            .as_ref()
-            .map(|tree| tree.size())
-            .unwrap_or(0)
+    // Remove every stored hash, returning the salted hashes that weren't removed before
+    fn remove_all(&self) -> Vec<Hash512> {
+        let mut removed = Vec::new();
+        self.for_each_chunk(|chunk| removed.extend(chunk.iter().filter(|hash| !self.is_tombstoned(hash))));
+        self.tombstones.write().unwrap().extend(removed.iter().copied());
+        self.metadata.write().unwrap().clear();
+        self.resubmissions.write().unwrap().clear();
+        removed
     }
 
-    pub fn get_merkle_tree_root(&self) -> Option<Hash512> {
-        self.merkle_tree
-            .read()
-            .unwrap()
-            .as_ref()
-            .and_then(|tree| tree.root())
+    // Remove the hashes of namespaces in `retention` (seconds by namespace) submitted that long before `now`,
+    // returning the salted hashes with their namespace
+    fn expire(&self, retention: &HashMap<String, u64>, now: u64) -> Vec<(Hash512, String)> {
+        let expired: Vec<(Hash512, String)> = self.metadata.read().unwrap().iter()
+            .filter_map(|(salted_hash, metadata)| {
+                let namespace = metadata.namespace.as_ref()?;
+                let expired = metadata.submitted_at.saturating_add(*retention.get(namespace)?) <= now;
+                expired.then(|| (*salted_hash, namespace.clone()))
+            })
+            .collect();
+        expired.into_iter().filter(|(salted_hash, _)| self.remove_salted(*salted_hash)).collect()
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::time::Duration;
-    static SALT: Hash512 = [0, 0, 0, 0, 0, 0, 0, 0];
+    pub fn tombstone_count(&self) -> usize {
+        self.tombstones.read().unwrap().len()
+    }
+
+    pub fn metadata(&self, hash: &Hash512) -> Option<HashMetadata> {
+        self.metadata_with_encoding(hash, LeafEncoding::default())
+    }
+
+    // `None` if the hash was submitted only once, isn't stored or was removed
+    pub fn resubmissions_with_encoding(&self, hash: &Hash512, encoding: LeafEncoding) -> Option<Resubmissions> {
+        self.resubmissions.read().unwrap().get(&self.leaf(hash, encoding)).copied()
+    }
+
+    pub fn metadata_with_encoding(&self, hash: &Hash512, encoding: LeafEncoding) -> Option<HashMetadata> {
+        self.salted_metadata(&self.leaf(hash, encoding)).map(|metadata| (*metadata).clone())
+    }
+
+    fn salted_metadata(&self, salted_hash: &Hash512) -> Option<Arc<HashMetadata>> {
+        self.metadata.read().unwrap().get(salted_hash).cloned()
+    }
+
+    // Number of bucket slots holding 0, 1, 2... hashes, kept up to date on every insert, so reading it doesn't
+    // scan the table. A few long chains at a low average mean the salted hashes are skewed, by a weak salt or
+    // inputs crafted to collide. Spilled hashes and those moved to cold storage aren't in any bucket.
+    pub fn chain_length_histogram(&self) -> Vec<usize> {
+        self.chain_lengths.iter().map(|count| count.load(Ordering::Relaxed)).collect()
+    }
+
+    // Account for the buckets of a shard changing from `before` to `after`
+    fn replace_chain_lengths(&self, before: &ShardUsage, after: &ShardUsage) {
+        for (count, (before, after)) in self.chain_lengths.iter().zip(before.chain_lengths.iter().zip(&after.chain_lengths)) {
+            count.fetch_add(*after, Ordering::Relaxed);
+            count.fetch_sub(*before, Ordering::Relaxed);
+        }
+    }
+
+    pub fn to_array(&self) -> Vec<Hash512> {
+        let mut hashes = Vec::with_capacity(self.len());
+        self.append_to(&mut hashes);
+        hashes
+    }
+
+    // The hashes of `to_array` without copying them all at once: the buckets of one lock shard are copied under its
+    // read lock, and moved hashes are read from disk as they are needed. Hashes added or moved to disk meanwhile
+    // may be missed or returned twice.
+    pub fn iter(&self) -> impl Iterator<Item = Hash512> + '_ {
+        self.shards.iter().flat_map(|shard| shard.read().unwrap().hashes()).chain(self.outside_hashes())
+    }
+
+    // Like `iter`, owning the store
+    fn into_hashes(store: Arc<Self>) -> impl Iterator<Item = Hash512> {
+        let outside_hashes = store.outside_hashes();
+        (0..store.shards.len()).flat_map(move |index| store.shards[index].read().unwrap().hashes()).chain(outside_hashes)
+    }
+
+    // Call `f` with the hashes of `to_array`, up to ITER_CHUNK_SIZE at a time. A shard stays read locked while `f`
+    // gets its hashes, so adds to it wait for one chunk at most, and `f` must not add to this store.
+    // Hashes added or moved to disk meanwhile may be missed or passed twice.
+    pub fn for_each_chunk(&self, mut f: impl FnMut(&[Hash512])) {
+        let mut chunk = Vec::with_capacity(ITER_CHUNK_SIZE);
+        for shard in &self.shards {
+            let shard = shard.read().unwrap();
+            for (hash, _) in shard.buckets.iter().flatten().flat_map(|bucket| bucket.iter()) {
+                chunk.push(*hash);
+                if chunk.len() == ITER_CHUNK_SIZE {
+                    f(&chunk);
+                    chunk.clear();
+                }
+            }
+        }
+        for hash in self.outside_hashes() {
+            chunk.push(hash);
+            if chunk.len() == ITER_CHUNK_SIZE {
+                f(&chunk);
+                chunk.clear();
+            }
+        }
+        if !chunk.is_empty() {
+            f(&chunk);
+        }
+    }
+
+    // Hashes in the buckets, spilled and in the cold index, and what is wrong with the buckets: hashes out of order, stored twice
+    // or in another bucket than their index points to, and a number of hashes that differs from `len`
+    fn check_buckets(&self) -> (usize, Vec<String>) {
+        let mut hashes = self.spilled_len() + self.cold_len();
+        let mut problems = Vec::new();
+        for (shard_index, shard) in self.shards.iter().enumerate() {
+            let shard = shard.read().unwrap();
+            for (position, bucket) in shard.buckets.iter().enumerate() {
+                let Some(bucket) = bucket else {
+                    continue;
+                };
+                hashes += bucket.len();
+                if bucket.windows(2).any(|pair| pair[0].0 == pair[1].0) {
+                    problems.push(format!("bucket {} of shard {} holds a hash twice", position, shard_index));
+                } else if !bucket.is_sorted_by(|a, b| a.0 < b.0) {
+                    problems.push(format!("bucket {} of shard {} isn't sorted", position, shard_index));
+                }
+                if bucket.iter().any(|(hash, _)| Self::shard_index(hash) != shard_index || Self::position(&shard, hash) != position) {
+                    problems.push(format!("bucket {} of shard {} holds hashes of another bucket", position, shard_index));
+                }
+            }
+        }
+        if hashes != self.len() {
+            problems.push(format!("{} hashes are stored, but {} are counted", hashes, self.len()));
+        }
+        (hashes, problems)
+    }
+
+    // Append the hashes in the order of `to_array` to `hashes`, which may hold those of other stores already
+    fn append_to(&self, hashes: &mut Vec<Hash512>) {
+        // Opened first, so failing to open the cold index leaves `hashes` as it was
+        let outside_hashes = self.outside_hashes();
+        hashes.reserve(self.len());
+
+        // Shards cover consecutive bucket ranges, so this visits buckets in index order
+        for shard in &self.shards {
+            let shard = shard.read().unwrap();
+            for bucket in shard.buckets.iter().flatten() {
+                hashes.extend(bucket.iter().map(|(hash, _)| *hash));
+            }
+        }
+        hashes.extend(outside_hashes);
+    }
+
+    // Append the salted hashes numbered from `first` up to `bound` with their numbers, in no particular order
+    fn append_numbered(&self, first: u64, bound: u64, entries: &mut Vec<(u64, Hash512)>) {
+        self.for_each_numbered(first, bound, |sequence, hash| entries.push((sequence, hash)));
+    }
+
+    // Call `f` with the salted hashes numbered from `first` up to `bound` and their numbers, in no particular order
+    fn for_each_numbered(&self, first: u64, bound: u64, mut f: impl FnMut(u64, Hash512)) {
+        let numbered = |sequence: u64| (first..bound).contains(&sequence);
+        for shard in &self.shards {
+            let shard = shard.read().unwrap();
+            for (hash, sequence) in shard.buckets.iter().flatten().flat_map(|bucket| bucket.iter()) {
+                if numbered(*sequence) {
+                    f(*sequence, *hash);
+                }
+            }
+        }
+        for (hash, sequence) in self.spilled.read().unwrap().iter() {
+            if numbered(*sequence) {
+                f(*sequence, *hash);
+            }
+        }
+        // The cold index only has hashes below its bound, so it is only read if some of them are asked for
+        let records = self.cold.read().unwrap().as_ref()
+            .filter(|cold| first < cold.bound())
+            .map(|cold| cold.iter().expect("Failed to read cold index"));
+        for (hash, sequence) in records.into_iter().flatten().map(|record| record.expect("Failed to read cold index")) {
+            if numbered(sequence) {
+                f(sequence, hash);
+            }
+        }
+    }
+
+    // Up to `limit` stored hashes from position `offset` on, in the order of `to_array`.
+    // With `since`, only hashes first stored at or after that unix time are listed and counted for `offset`.
+    // Hashes restored at startup count as stored when the store was opened.
+    // Removed hashes are skipped.
+    pub fn iter_range(&self, offset: usize, limit: usize, since: Option<u64>) -> Vec<StoredHash> {
+        let mut skip = offset;
+        self.range_from(&mut skip, limit, since)
+    }
+
+    // `iter_range` continuing over several stores, `skip` is reduced by the hashes skipped in this one
+    fn range_from(&self, skip: &mut usize, limit: usize, since: Option<u64>) -> Vec<StoredHash> {
+        let first_sequence = since.map(|since| self.sequence_clock.first_sequence_since(since));
+        let mut range = Vec::new();
+        for shard in &self.shards {
+            if range.len() >= limit {
+                break;
+            }
+            let shard = shard.read().unwrap();
+            let metadata = self.metadata.read().unwrap();
+            let tombstones = self.tombstones.read().unwrap();
+            for bucket in shard.buckets.iter().flatten() {
+                // Without a filter or removed hashes whole buckets can be skipped
+                if since.is_none() && tombstones.is_empty() && *skip >= bucket.len() {
+                    *skip -= bucket.len();
+                    continue;
+                }
+                for (hash, sequence) in bucket.iter() {
+                    if first_sequence.is_some_and(|first| *sequence < first) || tombstones.contains(hash) {
+                        continue;
+                    }
+                    let hash_metadata = metadata.get(hash);
+                    if *skip > 0 {
+                        *skip -= 1;
+                        continue;
+                    }
+                    range.push((*hash, hash_metadata.map(|m| (**m).clone())));
+                    if range.len() >= limit {
+                        return range;
+                    }
+                }
+            }
+        }
+        // Spilled and moved hashes come last, as in `to_array`
+        let metadata = self.metadata.read().unwrap();
+        let tombstones = self.tombstones.read().unwrap();
+        let outside_len = self.spilled_len() + self.cold_len();
+        if range.len() >= limit || (since.is_none() && tombstones.is_empty() && *skip >= outside_len) {
+            *skip -= outside_len.min(*skip);
+            return range;
+        }
+        for hash in self.outside_hashes() {
+            let older = first_sequence.is_some_and(|first| self.stored_sequence(&hash).is_none_or(|sequence| sequence < first));
+            if older || tombstones.contains(&hash) {
+                continue;
+            }
+            let hash_metadata = metadata.get(&hash);
+            if *skip > 0 {
+                *skip -= 1;
+                continue;
+            }
+            range.push((hash, hash_metadata.map(|m| (**m).clone())));
+            if range.len() >= limit {
+                break;
+            }
+        }
+        range
+    }
+
+    // Add all hashes of `other` to this store with their metadata and return how many of them were new
+    pub fn merge(&self, other: &Self) -> Result<usize, MergeError> {
+        if self.salt != other.salt {
+            return Err(MergeError::SaltMismatch);
+        }
+        if self.hasher.name() != other.hasher.name() {
+            return Err(MergeError::HasherMismatch);
+        }
+        Ok(other.entries().into_iter().filter(|(hash, metadata)| self.add_merged(*hash, metadata.clone())).count())
+    }
+
+    // Salted hashes in the order of `to_array`, with the metadata they were submitted with
+    fn entries(&self) -> Vec<(Hash512, Option<Arc<HashMetadata>>)> {
+        let array = self.to_array();
+        let metadata = self.metadata.read().unwrap();
+        array.into_iter().map(|hash| (hash, metadata.get(&hash).cloned())).collect()
+    }
+
+    // Add a salted hash of another store with its metadata, which a stored hash without metadata takes over too.
+    // Returns whether the hash is new.
+    fn add_merged(&self, salted_hash: Hash512, metadata: Option<Arc<HashMetadata>>) -> bool {
+        let is_new = self.add_salted_hash(salted_hash);
+        if let Some(metadata) = metadata
+            && (is_new || !self.is_tombstoned(&salted_hash))
+        {
+            self.metadata.write().unwrap().entry(salted_hash).or_insert(metadata);
+        }
+        is_new
+    }
+}
+
+// Append-only file of the salted hashes added to one worker, as raw 64 byte records in insertion order.
+// Their sequence numbers are kept in a parallel file of 8 byte big-endian records, and the metadata they were
+// submitted with and their resubmissions in a JSON lines file of `MetadataRecord`s, so the records themselves keep
+// their fixed size.
+// The tombstones of a worker are kept in another file of the same format as the leaves, without the other two.
+#[derive(Debug)]
+struct LeafLog {
+    writer: BufWriter<File>,
+    len: u64,
+    sequences: Option<BufWriter<File>>,
+    metadata: Option<BufWriter<File>>,
+}
+
+// Line of a worker's metadata log: the metadata a salted hash of its leaf log was submitted with, or its
+// resubmissions so far, of which the last line counts
+#[derive(Debug, Serialize, Deserialize)]
+struct MetadataRecord {
+    leaf: Hash512,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    metadata: Option<HashMetadata>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    resubmissions: Option<Resubmissions>,
+}
+
+impl LeafLog {
+    fn path(dir: &Path, thread_index: usize) -> PathBuf {
+        dir.join(format!("leaves-{}.bin", thread_index))
+    }
+
+    fn tombstone_path(dir: &Path, thread_index: usize) -> PathBuf {
+        dir.join(format!("tombstones-{}.bin", thread_index))
+    }
+
+    fn sequence_path(dir: &Path, thread_index: usize) -> PathBuf {
+        dir.join(format!("sequences-{}.bin", thread_index))
+    }
+
+    fn metadata_path(dir: &Path, thread_index: usize) -> PathBuf {
+        dir.join(format!("metadata-{}.jsonl", thread_index))
+    }
+
+    fn create(path: &Path) -> io::Result<Self> {
+        let file = File::create(path)?;
+        Ok(Self { writer: BufWriter::new(file), len: 0, sequences: None, metadata: None })
+    }
+
+    // Leaf log of a worker with its sequence numbers and metadata
+    fn create_with_sequences(dir: &Path, thread_index: usize) -> io::Result<Self> {
+        let sequences = File::create(Self::sequence_path(dir, thread_index))?;
+        let metadata = File::create(Self::metadata_path(dir, thread_index))?;
+        Ok(Self { sequences: Some(BufWriter::new(sequences)), metadata: Some(BufWriter::new(metadata)), ..Self::create(&Self::path(dir, thread_index))? })
+    }
+
+    // Open the log at `path` for appending and return the records it already has.
+    // A partial record left behind by a crash is cut off.
+    fn open(path: &Path) -> io::Result<(Self, Vec<Hash512>)> {
+        let mut file = OpenOptions::new().read(true).append(true).create(true).open(path)?;
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)?;
+        let records = bytes.chunks_exact(64);
+        let complete_len = bytes.len() - records.remainder().len();
+        let hashes: Vec<Hash512> = records.map(|record| Hash512::from_bytes(record).unwrap()).collect();
+        file.set_len(complete_len as u64)?;
+        Ok((Self { writer: BufWriter::new(file), len: hashes.len() as u64, sequences: None, metadata: None }, hashes))
+    }
+
+    // Like `open` for the leaf log of a worker, also returning the sequence numbers of its records.
+    // There may be fewer numbers than records if they weren't recorded yet or were lost in a crash,
+    // the missing ones have to be appended with `append_sequence` before any new record.
+    // The metadata is read by `read_metadata`, a partial line left behind by a crash is cut off here.
+    fn open_with_sequences(dir: &Path, thread_index: usize) -> io::Result<(Self, Vec<Hash512>, Vec<u64>)> {
+        let (log, hashes) = Self::open(&Self::path(dir, thread_index))?;
+        let mut file = OpenOptions::new().read(true).append(true).create(true).open(Self::sequence_path(dir, thread_index))?;
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)?;
+        let sequences: Vec<u64> = bytes.chunks_exact(8).take(hashes.len()).map(|record| u64::from_be_bytes(record.try_into().unwrap())).collect();
+        file.set_len(sequences.len() as u64 * 8)?;
+        let mut metadata = OpenOptions::new().read(true).append(true).create(true).open(Self::metadata_path(dir, thread_index))?;
+        let mut bytes = Vec::new();
+        metadata.read_to_end(&mut bytes)?;
+        metadata.set_len(bytes.iter().rposition(|&byte| byte == b'\n').map_or(0, |end| end + 1) as u64)?;
+        Ok((Self { sequences: Some(BufWriter::new(file)), metadata: Some(BufWriter::new(metadata)), ..log }, hashes, sequences))
+    }
+
+    fn append(&mut self, salted_hash: &Hash512, sequence: Option<u64>) -> io::Result<()> {
+        self.writer.write_all(&salted_hash.to_bytes())?;
+        self.len += 1;
+        match sequence {
+            Some(sequence) => self.append_sequence(sequence),
+            None => Ok(()),
+        }
+    }
+
+    fn append_sequence(&mut self, sequence: u64) -> io::Result<()> {
+        match &mut self.sequences {
+            Some(sequences) => sequences.write_all(&sequence.to_be_bytes()),
+            None => Ok(()),
+        }
+    }
+
+    fn append_metadata(&mut self, record: &MetadataRecord) -> io::Result<()> {
+        match &mut self.metadata {
+            Some(metadata) => {
+                serde_json::to_writer(&mut *metadata, record)?;
+                metadata.write_all(b"\n")
+            }
+            None => Ok(()),
+        }
+    }
+
+    // Record a newly added salted hash in the leaf log, if there is one
+    fn record(log: &mut Option<LeafLog>, salted_hash: &Hash512, sequence: Option<u64>) {
+        if let Some(log) = log
+            && let Err(e) = log.append(salted_hash, sequence)
+        {
+            eprintln!("Failed to write to leaf log: {}", e);
+        }
+    }
+
+    // Record the metadata a logged salted hash was submitted with or its resubmissions, if there is a leaf log
+    fn record_metadata(log: &mut Option<LeafLog>, record: MetadataRecord) {
+        if let Some(log) = log
+            && let Err(e) = log.append_metadata(&record)
+        {
+            eprintln!("Failed to write to metadata log: {}", e);
+        }
+    }
+
+    // Flush buffered records and return the number of records in the file
+    fn flush(&mut self) -> io::Result<u64> {
+        self.writer.flush()?;
+        if let Some(sequences) = &mut self.sequences {
+            sequences.flush()?;
+        }
+        if let Some(metadata) = &mut self.metadata {
+            metadata.flush()?;
+        }
+        Ok(self.len)
+    }
+
+    // Flush the log of a worker, if there is one, and return its number of records, 0 without one
+    fn flushed_len(log: &mut Option<LeafLog>) -> u64 {
+        match log {
+            Some(log) => log.flush().unwrap_or_else(|e| {
+                eprintln!("Failed to flush leaf log: {}", e);
+                log.len
+            }),
+            None => 0,
+        }
+    }
+
+    // Flush and make sure the records reached the disk
+    fn sync(&mut self) -> io::Result<()> {
+        self.flush()?;
+        if let Some(sequences) = &self.sequences {
+            sequences.get_ref().sync_all()?;
+        }
+        if let Some(metadata) = &self.metadata {
+            metadata.get_ref().sync_all()?;
+        }
+        self.writer.get_ref().sync_all()
+    }
+
+    // Read the first `count` records of the log at `path`
+    fn read(path: &Path, count: u64) -> io::Result<Vec<Hash512>> {
+        Self::read_range(path, 0, count)
+    }
+
+    // Sequence numbers of the first `count` records of a worker's leaf log
+    fn read_sequences(dir: &Path, thread_index: usize, count: u64) -> io::Result<Vec<u64>> {
+        let mut bytes = vec![0u8; count as usize * 8];
+        File::open(Self::sequence_path(dir, thread_index))?.read_exact(&mut bytes)?;
+        Ok(bytes.chunks_exact(8).map(|record| u64::from_be_bytes(record.try_into().unwrap())).collect())
+    }
+
+    // Metadata records of a worker's leaf log, none for logs written before metadata was recorded
+    fn read_metadata(dir: &Path, thread_index: usize) -> io::Result<Vec<MetadataRecord>> {
+        let file = match File::open(Self::metadata_path(dir, thread_index)) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        BufReader::new(file).lines()
+            .map(|line| serde_json::from_str(&line?).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)))
+            .collect()
+    }
+
+    // Read `count` records starting at record `offset`
+    fn read_range(path: &Path, offset: u64, count: u64) -> io::Result<Vec<Hash512>> {
+        let mut file = File::open(path)?;
+        file.seek(SeekFrom::Start(offset * 64))?;
+        let mut reader = BufReader::new(file);
+        let mut record = [0u8; 64];
+        let mut hashes = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            reader.read_exact(&mut record)?;
+            hashes.push(Hash512::from_bytes(&record).unwrap());
+        }
+        Ok(hashes)
+    }
+}
+
+// Leaves of a tree built when the leaf log of every worker in `dir` had the given number of records,
+// in the order of `sort_leaves`
+pub fn read_leaf_logs(dir: &Path, leaf_log_lengths: &[u64]) -> io::Result<Vec<Hash512>> {
+    let mut all_hashes = Vec::new();
+    for (thread_index, &len) in leaf_log_lengths.iter().enumerate() {
+        all_hashes.extend(LeafLog::read(&LeafLog::path(dir, thread_index), len)?);
+    }
+    sort_leaves(&mut all_hashes);
+    Ok(all_hashes)
+}
+
+// Sequence numbers of the first records of every worker's leaf log in `dir`, as many as `leaf_log_lengths` gives.
+// Within a log they increase, and no number appears in two logs.
+pub fn read_leaf_sequences(dir: &Path, leaf_log_lengths: &[u64]) -> io::Result<Vec<Vec<u64>>> {
+    leaf_log_lengths.iter().enumerate().map(|(thread_index, &len)| LeafLog::read_sequences(dir, thread_index, len)).collect()
+}
+
+// Number of records in the leaf log of each of `threads` workers in `dir`
+pub fn leaf_log_lengths(dir: &Path, threads: usize) -> io::Result<Vec<u64>> {
+    (0..threads).map(|thread_index| Ok(std::fs::metadata(LeafLog::path(dir, thread_index))?.len() / 64)).collect()
+}
+
+// Parameters the stored hashes depend on, saved next to the leaf logs so that a restart
+// salts new hashes the same way and can restore the old ones
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StoreConfig {
+    pub salt: Hash512,
+    pub hasher: String,
+    pub threads: usize,
+    // Missing in the configs of stores created before it was configurable
+    #[serde(default)]
+    pub partitioning: Partitioning,
+}
+
+impl StoreConfig {
+    pub fn path(dir: &Path) -> PathBuf {
+        dir.join("store.json")
+    }
+
+    // Load the config in `dir`, or save one with `salt` (a new random one if not given) on first start.
+    // Fails if the existing config is for a different hasher, number of threads, partitioning or salt.
+    pub fn load_or_create(dir: &Path, threads: usize, hasher: &dyn Hasher, partitioning: Partitioning, salt: Option<Hash512>) -> io::Result<Self> {
+        let path = Self::path(dir);
+        if path.exists() {
+            let config: Self = serde_json::from_str(&std::fs::read_to_string(&path)?)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            if config.hasher != hasher.name() || config.threads != threads {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+                    "{} is for {} threads with hasher {}, not {} threads with {}",
+                    path.display(), config.threads, config.hasher, threads, hasher.name()
+                )));
+            }
+            if config.partitioning != partitioning {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+                    "{} is for {} partitioning, not {}", path.display(), config.partitioning.name(), partitioning.name()
+                )));
+            }
+            if salt.is_some_and(|salt| salt != config.salt) {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} has a different salt", path.display())));
+            }
+            return Ok(config);
+        }
+
+        let config = Self { salt: salt.unwrap_or_else(random_salt), hasher: hasher.name().to_string(), threads, partitioning };
+        // Write to a temporary file first so a crash can't leave a truncated config behind
+        let tmp_path = path.with_extension("json.tmp");
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(serde_json::to_string_pretty(&config).unwrap().as_bytes())?;
+        file.sync_all()?;
+        std::fs::rename(&tmp_path, &path)?;
+        Ok(config)
+    }
+}
+
+// Load the tree head signing key stored at `path`, or create a new random one there on first start.
+// The file holds the 32 byte secret key and is only readable by its owner.
+pub fn load_or_create_signing_key(path: &Path) -> io::Result<SigningKey> {
+    if path.exists() {
+        let bytes: [u8; 32] = std::fs::read(path)?
+            .try_into()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, format!("{} is not a 32 byte key", path.display())))?;
+        return Ok(SigningKey::from_bytes(&bytes));
+    }
+
+    let key = SigningKey::generate(&mut rand::rngs::OsRng);
+    let tmp_path = path.with_extension("tmp");
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(&tmp_path)?;
+    file.write_all(&key.to_bytes())?;
+    file.sync_all()?;
+    std::fs::rename(&tmp_path, path)?;
+    Ok(key)
+}
+
+fn random_salt() -> Hash512 {
+    Hash512(rand::random())
+}
+
+// Consistent view of all workers: their hashes and how many records each worker had logged at that point
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StoreSnapshot {
+    pub hashes: Vec<Hash512>,
+    pub leaf_log_lengths: Vec<u64>,
+}
+
+// What building and publishing trees needs from a store, so `TimestampingService` works with any backend and with
+// mocks in tests. Hashes are added unsalted with the default leaf encoding, `iter` and `snapshot` return the salted
+// leaves. The methods with defaults are for what only some stores can do, the others keep them.
+pub trait HashStorage: Send + Sync {
+    fn add(&self, hash: Hash512) -> Result<bool, StorageError>;
+    fn add_batch(&self, hashes: &[Hash512]) -> Result<Vec<bool>, StorageError>;
+    fn contains(&self, hash: &Hash512) -> Result<bool, StorageError>;
+    fn len(&self) -> usize;
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    // The salted leaves in chunks of up to ITER_CHUNK_SIZE, read as they are iterated. Hashes added meanwhile may
+    // be missed or returned twice, and iterating stops after an error.
+    fn iter(&self) -> Box<dyn Iterator<Item = Result<Vec<Hash512>, StorageError>> + '_>;
+    // Leaf log lengths are empty for stores without leaf logs
+    fn snapshot(&self) -> Result<StoreSnapshot, StorageError>;
+    fn salt(&self) -> &Hash512;
+    fn hasher(&self) -> &Arc<dyn Hasher>;
+
+    // Number the next new hash gets, `None` if the store doesn't number its hashes as publishing from a mountain
+    // range needs, see `MultiThreadedHashStore::next_sequence`
+    fn next_sequence(&self) -> Option<u64> {
+        None
+    }
+    // Salted hashes numbered from `first` up to `bound`, see `MultiThreadedHashStore::snapshot_numbered`
+    fn snapshot_numbered(&self, _first: u64, _bound: u64) -> Result<NumberedSnapshot, StorageError> {
+        Err(StorageError::Unsupported("numbered hashes"))
+    }
+    // Number of a salted hash of `hash`, see `MultiThreadedHashStore::stored_sequence`
+    fn stored_sequence(&self, _hash: &Hash512, _salted_hash: Hash512) -> Result<Option<u64>, StorageError> {
+        Err(StorageError::Unsupported("numbered hashes"))
+    }
+    // Where pruned trees keep their leaves, next to the leaf logs
+    fn leaf_log_dir(&self) -> Option<&Path> {
+        None
+    }
+    // See `MultiThreadedHashStore::move_to_cold`, stores without a cold index keep every hash where it is
+    fn move_to_cold(&self, _bound: u64) -> io::Result<usize> {
+        Ok(0)
+    }
+    // See `MultiThreadedHashStore::expire`, stores without namespaces have nothing to expire
+    fn expire(&self, _retention: &Arc<HashMap<String, u64>>, _now: u64) -> Result<HashMap<String, usize>, StorageError> {
+        Ok(HashMap::new())
+    }
+    fn rotate_bucket_salts(&self) {}
+    // Flush what the store buffers, it isn't used anymore afterwards
+    fn shutdown(&self) {}
+}
+
+// Up to ITER_CHUNK_SIZE hashes of `hashes` at a time
+fn chunked(mut hashes: impl Iterator<Item = Hash512>) -> impl Iterator<Item = Vec<Hash512>> {
+    std::iter::from_fn(move || {
+        let chunk: Vec<Hash512> = hashes.by_ref().take(ITER_CHUNK_SIZE).collect();
+        (!chunk.is_empty()).then_some(chunk)
+    })
+}
+
+impl<const INDEX_SIZE: usize, const PREFIX_SIZE: usize> HashStorage for HashStore<INDEX_SIZE, PREFIX_SIZE> {
+    fn add(&self, hash: Hash512) -> Result<bool, StorageError> {
+        self.add_hash(hash)
+    }
+
+    fn add_batch(&self, hashes: &[Hash512]) -> Result<Vec<bool>, StorageError> {
+        hashes.iter().map(|hash| self.add_hash(*hash)).collect()
+    }
+
+    fn contains(&self, hash: &Hash512) -> Result<bool, StorageError> {
+        Ok(HashStore::contains(self, hash))
+    }
+
+    fn len(&self) -> usize {
+        HashStore::len(self)
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = Result<Vec<Hash512>, StorageError>> + '_> {
+        Box::new(chunked(HashStore::iter(self)).map(Ok))
+    }
+
+    fn snapshot(&self) -> Result<StoreSnapshot, StorageError> {
+        Ok(StoreSnapshot { hashes: self.to_array(), leaf_log_lengths: Vec::new() })
+    }
+
+    fn salt(&self) -> &Hash512 {
+        &self.salt
+    }
+
+    fn hasher(&self) -> &Arc<dyn Hasher> {
+        &self.hasher
+    }
+}
+
+impl<const INDEX_SIZE: usize, const PREFIX_SIZE: usize> HashStorage for MultiThreadedHashStore<INDEX_SIZE, PREFIX_SIZE> {
+    fn add(&self, hash: Hash512) -> Result<bool, StorageError> {
+        self.add_hash(hash)
+    }
+
+    fn add_batch(&self, hashes: &[Hash512]) -> Result<Vec<bool>, StorageError> {
+        MultiThreadedHashStore::add_batch(self, hashes)
+    }
+
+    fn contains(&self, hash: &Hash512) -> Result<bool, StorageError> {
+        MultiThreadedHashStore::contains(self, hash)
+    }
+
+    fn len(&self) -> usize {
+        MultiThreadedHashStore::len(self)
+    }
+
+    // The workers' stores are read directly, one lock shard at a time, see `HashStore::iter`
+    fn iter(&self) -> Box<dyn Iterator<Item = Result<Vec<Hash512>, StorageError>> + '_> {
+        match self.worker_stores() {
+            Ok(stores) => Box::new(chunked(stores.into_iter().flat_map(HashStore::into_hashes)).map(Ok)),
+            Err(e) => Box::new(std::iter::once(Err(e))),
+        }
+    }
+
+    fn snapshot(&self) -> Result<StoreSnapshot, StorageError> {
+        MultiThreadedHashStore::snapshot(self)
+    }
+
+    fn salt(&self) -> &Hash512 {
+        MultiThreadedHashStore::salt(self)
+    }
+
+    fn hasher(&self) -> &Arc<dyn Hasher> {
+        MultiThreadedHashStore::hasher(self)
+    }
+
+    fn next_sequence(&self) -> Option<u64> {
+        Some(MultiThreadedHashStore::next_sequence(self))
+    }
+
+    fn snapshot_numbered(&self, first: u64, bound: u64) -> Result<NumberedSnapshot, StorageError> {
+        MultiThreadedHashStore::snapshot_numbered(self, first, bound)
+    }
+
+    fn stored_sequence(&self, hash: &Hash512, salted_hash: Hash512) -> Result<Option<u64>, StorageError> {
+        MultiThreadedHashStore::stored_sequence(self, hash, salted_hash)
+    }
+
+    fn leaf_log_dir(&self) -> Option<&Path> {
+        MultiThreadedHashStore::leaf_log_dir(self)
+    }
+
+    fn move_to_cold(&self, bound: u64) -> io::Result<usize> {
+        MultiThreadedHashStore::move_to_cold(self, bound)
+    }
+
+    fn expire(&self, retention: &Arc<HashMap<String, u64>>, now: u64) -> Result<HashMap<String, usize>, StorageError> {
+        MultiThreadedHashStore::expire(self, retention, now)
+    }
+
+    fn rotate_bucket_salts(&self) {
+        MultiThreadedHashStore::rotate_bucket_salts(self)
+    }
+
+    fn shutdown(&self) {
+        MultiThreadedHashStore::shutdown(self)
+    }
+}
+
+// Counters of one worker, updated by the worker itself so reading them never waits for queued commands
+#[derive(Debug, Default)]
+struct WorkerStats {
+    hashes: AtomicUsize,
+    occupied_slots: AtomicUsize,
+    adds: AtomicUsize,
+    duplicates: AtomicUsize,
+    bucket_bytes: AtomicUsize,
+    node_bytes: AtomicUsize,
+    metadata_bytes: AtomicUsize,
+    tombstones: AtomicUsize,
+    cold_hashes: AtomicUsize,
+    cold_index_bytes: AtomicUsize,
+    // Commands sent to the worker that it didn't start on yet
+    queued: AtomicUsize,
+    bucket_slots: AtomicUsize,
+    index_size: AtomicUsize,
+    // Index size and shards done of the running resize, the index size is 0 while none is running
+    resize_target: AtomicUsize,
+    resized_shards: AtomicUsize,
+    // Times the worker panicked and was restarted
+    restarts: AtomicUsize,
+    chain_lengths: [AtomicUsize; CHAIN_LENGTH_HISTOGRAM_SIZE],
+    chain_overflows: AtomicUsize,
+    spilled_hashes: AtomicUsize,
+}
+
+impl WorkerStats {
+    // Account for `submitted` add requests of which `added` were new
+    fn record<const INDEX_SIZE: usize, const PREFIX_SIZE: usize>(&self, store: &HashStore<INDEX_SIZE, PREFIX_SIZE>, submitted: usize, added: usize) {
+        self.hashes.store(store.len(), Ordering::Relaxed);
+        self.metadata_bytes.store(store.memory_usage().metadata, Ordering::Relaxed);
+        self.tombstones.store(store.tombstone_count(), Ordering::Relaxed);
+        self.cold_hashes.store(store.cold_len(), Ordering::Relaxed);
+        self.cold_index_bytes.store(store.memory_usage().cold_index, Ordering::Relaxed);
+        self.chain_overflows.store(store.chain_overflows(), Ordering::Relaxed);
+        self.spilled_hashes.store(store.spilled_len(), Ordering::Relaxed);
+        self.adds.fetch_add(submitted, Ordering::Relaxed);
+        self.duplicates.fetch_add(submitted - added, Ordering::Relaxed);
+        self.record_table(store);
+    }
+
+    // Account for the bucket table, which a resize changes outside of the worker
+    fn record_table<const INDEX_SIZE: usize, const PREFIX_SIZE: usize>(&self, store: &HashStore<INDEX_SIZE, PREFIX_SIZE>) {
+        self.occupied_slots.store(store.occupied_slots(), Ordering::Relaxed);
+        self.bucket_slots.store(store.bucket_slots(), Ordering::Relaxed);
+        self.bucket_bytes.store(store.bucket_slots() * size_of::<Bucket>(), Ordering::Relaxed);
+        self.node_bytes.store(store.node_bytes.load(Ordering::Relaxed), Ordering::Relaxed);
+        self.index_size.store(store.index_size(), Ordering::Relaxed);
+        for (count, store_count) in self.chain_lengths.iter().zip(store.chain_length_histogram()) {
+            count.store(store_count, Ordering::Relaxed);
+        }
+        let (resized_shards, target) = store.resize_progress().unwrap_or((0, 0));
+        self.resized_shards.store(resized_shards, Ordering::Relaxed);
+        self.resize_target.store(target, Ordering::Relaxed);
+    }
+}
+
+// Outcome of adding one hash of a batch
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum AddStatus {
+    New,
+    // Stored before the batch was added
+    Existing,
+    // Repeats an earlier hash of the same batch
+    Duplicate,
+}
+
+// Aggregated counters of all workers
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct StoreStats {
+    pub hashes: usize,
+    pub occupied_slots: usize,
+    pub adds: usize,
+    pub duplicates: usize,
+    pub worker_hashes: Vec<usize>,
+    pub memory: MemoryUsage,
+    // Removed hashes, still counted in `hashes` since they stay in the trees
+    pub tombstones: usize,
+    pub worker_queue_depths: Vec<usize>,
+    pub bucket_slots: usize,
+    pub worker_index_sizes: Vec<usize>,
+    // Workers currently growing their bucket table
+    pub resizes: Vec<ResizeProgress>,
+    pub bloom_filter: Option<BloomFilterStats>,
+    // Hashes moved to disk, still counted in `hashes`
+    pub cold_hashes: usize,
+    // Times every worker panicked and was restarted
+    pub worker_restarts: Vec<usize>,
+    // Bucket slots of all workers by the number of hashes they hold, see `HashStore::chain_length_histogram`
+    pub chain_lengths: Vec<usize>,
+    // New hashes whose bucket was full, see `HashStore::set_chain_limit`
+    pub chain_overflows: usize,
+    // Hashes kept outside the buckets with `ChainOverflow::Spill`, still counted in `hashes`
+    pub spilled_hashes: usize,
+}
+
+// Commands a worker queues by default before it rejects adds, see `MultiThreadedHashStore::set_queue_capacity`
+pub const DEFAULT_QUEUE_CAPACITY: usize = 1024;
+
+// Channel to a worker thread, counting the commands waiting in it
+#[derive(Debug)]
+struct WorkerQueue<const INDEX_SIZE: usize, const PREFIX_SIZE: usize> {
+    tx: Sender<HashCommand<INDEX_SIZE, PREFIX_SIZE>>,
+    stats: Arc<WorkerStats>,
+}
+
+impl<const INDEX_SIZE: usize, const PREFIX_SIZE: usize> WorkerQueue<INDEX_SIZE, PREFIX_SIZE> {
+    fn send(&self, cmd: HashCommand<INDEX_SIZE, PREFIX_SIZE>) -> Result<(), SendError<HashCommand<INDEX_SIZE, PREFIX_SIZE>>> {
+        self.stats.queued.fetch_add(1, Ordering::Relaxed);
+        let result = self.tx.send(cmd);
+        if result.is_err() {
+            self.release();
+        }
+        result
+    }
+
+    // Take a place in the queue unless `capacity` commands are waiting already
+    fn reserve(&self, capacity: usize) -> bool {
+        self.stats.queued.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |queued| (queued < capacity).then_some(queued + 1)).is_ok()
+    }
+
+    // Send a command into the place taken with `reserve`. The place is given back if the worker is gone.
+    fn send_reserved(&self, cmd: HashCommand<INDEX_SIZE, PREFIX_SIZE>) {
+        if self.tx.send(cmd).is_err() {
+            self.release();
+        }
+    }
+
+    fn release(&self) {
+        self.stats.queued.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// Answer of a worker to a command, `WorkerUnavailable` if it dropped the command without answering
+fn answer<T>(thread_index: usize, response_rx: Receiver<T>) -> Result<T, StorageError> {
+    response_rx.recv().map_err(|_| StorageError::WorkerUnavailable(thread_index))
+}
+
+// How the hashes are spread over the workers of a `MultiThreadedHashStore`. Fixed for a store, as lookups have to
+// find each hash at the worker it was added to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "kebab-case")]
+pub enum Partitioning {
+    // Top bits of the submitted hash, so anyone can send all their hashes to one worker. Stores created before
+    // the partitioning was configurable use it.
+    #[default]
+    UnsaltedPrefix,
+    // Bottom bits of the salted hash. The buckets of a worker are indexed by its top bits, which this leaves spread.
+    SaltedPrefix,
+    // New hashes go to the workers in turn, whatever they are. Lookups ask every worker, and adds first ask every
+    // worker whether it stores the hashes already, one add at a time.
+    RoundRobin,
+    // Worker with the highest keyed hash of the salted hash and the worker index (highest random weight)
+    Rendezvous,
+    // Top bits of the salted hash, which the bucket index skips as the store's PREFIX_SIZE, so each worker
+    // holds one range of the salted hashes. Needs a PREFIX_SIZE of at least log2 of the number of workers.
+    Prefix,
+}
+
+impl Partitioning {
+    pub const ALL: [Partitioning; 5] = [
+        Partitioning::UnsaltedPrefix,
+        Partitioning::SaltedPrefix,
+        Partitioning::RoundRobin,
+        Partitioning::Rendezvous,
+        Partitioning::Prefix,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Partitioning::UnsaltedPrefix => "unsalted-prefix",
+            Partitioning::SaltedPrefix => "salted-prefix",
+            Partitioning::RoundRobin => "round-robin",
+            Partitioning::Rendezvous => "rendezvous",
+            Partitioning::Prefix => "prefix",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|partitioning| partitioning.name() == name)
+    }
+}
+
+// Score of a worker for a salted hash with rendezvous partitioning
+fn rendezvous_score(salted_hash: &Hash512, thread_index: usize) -> u64 {
+    let mut hasher = blake3::Hasher::new();
+    for word in salted_hash.0 {
+        hasher.update(&word.to_le_bytes());
+    }
+    hasher.update(&(thread_index as u64).to_le_bytes());
+    u64::from_le_bytes(hasher.finalize().as_bytes()[..8].try_into().unwrap())
+}
+
+#[derive(Debug)]
+pub struct MultiThreadedHashStore<const INDEX_SIZE: usize, const PREFIX_SIZE: usize> {
+    threads: Vec<WorkerQueue<INDEX_SIZE, PREFIX_SIZE>>,
+    stats: Vec<Arc<WorkerStats>>,
+    salt: Hash512,
+    hasher: Arc<dyn Hasher>,
+    partitioning: Partitioning,
+    // Worker the next new hash goes to with round-robin partitioning, locked while an add is assigned to workers
+    next_worker: Mutex<usize>,
+    leaf_log_dir: Option<PathBuf>,
+    // Index bits every worker's table starts with, see `HashStore::with_index_size`
+    index_size: usize,
+    limits: RwLock<StoreLimits>,
+    queue_capacity: AtomicUsize,
+    salting_threads: AtomicUsize,
+    // Consulted before lookups are sent to a worker, see `set_bloom_filter`
+    bloom_filter: RwLock<Option<Arc<BloomFilter>>>,
+    // Shared with every worker's store, see `HashStore::sequence_with_encoding`
+    next_sequence: Arc<AtomicU64>,
+    feed: broadcast::Sender<AcceptedHash>,
+}
+
+#[derive(Debug)]
+enum HashCommand<const INDEX_SIZE: usize, const PREFIX_SIZE: usize> {
+    AddHash(Hash512, LeafEncoding, Sender<Result<bool, StorageError>>),
+    // Salted before they are sent, see `salt_batch`
+    AddBatch(Vec<Hash512>, Option<Arc<HashMetadata>>, Sender<Result<Vec<bool>, StorageError>>),
+    AddSalted(Vec<Hash512>, Sender<Result<usize, StorageError>>),
+    Contains(Hash512, LeafEncoding, Sender<bool>),
+    ContainsSalted(Hash512, Sender<bool>),
+    // Whether each salted hash is in the buckets or the cold index, even if it was removed
+    Stored(Vec<Hash512>, Sender<Vec<bool>>),
+    GetMetadata(Hash512, LeafEncoding, Sender<Option<Arc<HashMetadata>>>),
+    GetSequence(Hash512, LeafEncoding, Sender<Option<u64>>),
+    GetResubmissions(Hash512, LeafEncoding, Sender<Option<Resubmissions>>),
+    // Answers the salted hashes with their metadata, see `HashStore::entries`
+    GetEntries(Sender<Vec<(Hash512, Option<Arc<HashMetadata>>)>>),
+    // Answers the worker's store, to read it while the worker keeps handling commands
+    GetStore(Sender<Arc<HashStore<INDEX_SIZE, PREFIX_SIZE>>>),
+    IterRange(usize, usize, Option<u64>, Sender<(Vec<StoredHash>, usize)>),
+    // Appends the hashes to the shared leaves and answers the leaf log length, see `MultiThreadedHashStore::snapshot`
+    Snapshot(Arc<Mutex<Vec<Hash512>>>, Sender<u64>),
+    // Like `Snapshot`, for the hashes numbered from the first up to the second number, see `HashStore::append_numbered`
+    SnapshotNumbered(u64, u64, Arc<Mutex<Vec<(u64, Hash512)>>>, Sender<u64>),
+    // Answers how many hashes numbered below the bound are in each of the ranges, see `MultiThreadedHashStore::part_sizes`
+    CountParts(u64, usize, Sender<Vec<usize>>),
+    // Answers the hashes numbered below the bound in one of the ranges, see `MultiThreadedHashStore::sorted_part`
+    GetPart(u64, usize, usize, Sender<Vec<Hash512>>),
+    // Answers the sequence number of a salted hash, even if it was removed
+    GetStoredSequence(Hash512, Sender<Option<u64>>),
+    // Answers the hashes found in the buckets and what is wrong with them, see `HashStore::check_buckets`
+    CheckBuckets(Sender<(usize, Vec<String>)>),
+    // Salted hashes of another store with their metadata, see `HashStore::add_merged`
+    MergeSalted(Vec<(Hash512, Option<Arc<HashMetadata>>)>, Sender<usize>),
+    // Remove one salted hash, or all hashes of the worker for `None`
+    Remove(Option<Hash512>, Sender<usize>),
+    // Remove the hashes whose retention ended, see `HashStore::expire`, answering how many of each namespace
+    Expire(Arc<HashMap<String, u64>>, u64, Sender<HashMap<String, usize>>),
+    SetLimits(StoreLimits, Sender<()>),
+    SetResizePolicy(Option<ResizePolicy>, Sender<()>),
+    SetChainLimit(Option<ChainLimit>, Sender<()>),
+    SetBloomFilter(Arc<BloomFilter>, Sender<()>),
+    MoveToCold(PathBuf, u64, Sender<io::Result<usize>>),
+    // Not answered, the worker rotates its bucket salt in the background, see `HashStore::rotate_bucket_salt`
+    RotateBucketSalt,
+    // Answered right away, to tell whether the worker is alive, see `worker_health`
+    Ping(Sender<()>),
+    Shutdown(Sender<()>),
+    #[cfg(test)]
+    InjectFault(Fault),
+}
+
+// Failures a worker can be told to simulate, to test how the store behaves when workers fail
+#[cfg(test)]
+#[derive(Debug)]
+enum Fault {
+    Panic,
+    // Drop the next commands, and with them their response channels, without handling them
+    DropResponses(usize),
+    // Wait this long before handling every following command
+    Delay(Duration),
+}
+
+impl<const INDEX_SIZE: usize, const PREFIX_SIZE: usize> MultiThreadedHashStore<INDEX_SIZE, PREFIX_SIZE> {
+    pub fn new(num_threads: usize, salt: Hash512) -> Self {
+        Self::with_hasher(num_threads, salt, None, Arc::new(Sha512Hasher)).unwrap()
+    }
+
+    // Like `new`, but every worker additionally records its new salted hashes in a leaf log in `dir`.
+    // Existing logs in `dir` are replaced.
+    pub fn with_leaf_logs(num_threads: usize, salt: Hash512, dir: &Path) -> io::Result<Self> {
+        Self::with_hasher(num_threads, salt, Some(dir), Arc::new(Sha512Hasher))
+    }
+
+    // Store using `hasher` for salting, with leaf logs in `leaf_log_dir` if given
+    pub fn with_hasher(num_threads: usize, salt: Hash512, leaf_log_dir: Option<&Path>, hasher: Arc<dyn Hasher>) -> io::Result<Self> {
+        Self::spawn(num_threads, salt, leaf_log_dir, hasher, false, INDEX_SIZE)
+    }
+
+    // Like `with_hasher` with leaf logs in `dir`, but keeps existing logs and restores their hashes.
+    // The logs have to be written with the same salt, hasher and number of threads, see `StoreConfig`.
+    // Metadata is restored from the metadata logs next to the leaf logs.
+    pub fn open(num_threads: usize, salt: Hash512, dir: &Path, hasher: Arc<dyn Hasher>) -> io::Result<Self> {
+        Self::open_with_index_size(num_threads, salt, dir, hasher, INDEX_SIZE)
+    }
+
+    // Like `open`, with the tables of the workers starting at `index_size` bits
+    pub fn open_with_index_size(num_threads: usize, salt: Hash512, dir: &Path, hasher: Arc<dyn Hasher>, index_size: usize) -> io::Result<Self> {
+        Self::spawn(num_threads, salt, Some(dir), hasher, true, index_size)
+    }
+
+    fn spawn(num_threads: usize, salt: Hash512, leaf_log_dir: Option<&Path>, hasher: Arc<dyn Hasher>, restore: bool, index_size: usize) -> io::Result<Self> {
+        let leaf_log_dir = leaf_log_dir.map(Path::to_path_buf);
+        // Ensure num_threads is a power of 2
+        if !num_threads.is_power_of_two() {
+            panic!("Number of threads must be a power of 2");
+        }
+        let mut threads = Vec::new();
+        let mut stats = Vec::new();
+
+        // All logs are read before restoring, so hashes without a recorded sequence number get one after the highest of any worker
+        let mut restored = Vec::new();
+        if let Some(dir) = leaf_log_dir.as_ref().filter(|_| restore) {
+            for thread_index in 0..num_threads {
+                restored.push(LeafLog::open_with_sequences(dir, thread_index)?);
+            }
+        }
+        let next_sequence = restored.iter().filter_map(|(_, _, sequences)| sequences.iter().max()).max().map_or(0, |max| max + 1);
+        let sequence_clock = Arc::new(SequenceClock::starting_at(unix_timestamp(SystemTime::now()), next_sequence));
+        let next_sequence = Arc::new(AtomicU64::new(next_sequence));
+        let mut restored = restored.into_iter();
+        let feed = broadcast::channel(FEED_CHANNEL_CAPACITY).0;
+
+        for thread_index in 0..num_threads {
+            let (tx, rx) = channel();
+            let worker_stats = Arc::new(WorkerStats::default());
+            threads.push(WorkerQueue { tx, stats: Arc::clone(&worker_stats) });
+            stats.push(Arc::clone(&worker_stats));
+
+            let store = HashStore::<INDEX_SIZE, PREFIX_SIZE>::with_index_size(salt, Arc::clone(&hasher), index_size).sharing_sequence(Arc::clone(&next_sequence), Arc::clone(&sequence_clock));
+            let (leaf_log, tombstone_log) = match &leaf_log_dir {
+                Some(dir) if restore => {
+                    let (mut log, salted_hashes, sequences) = restored.next().unwrap();
+                    let (tombstone_log, tombstones) = LeafLog::open(&LeafLog::tombstone_path(dir, thread_index))?;
+                    Self::restore_store(&store, dir, thread_index, &mut log, salted_hashes, sequences, tombstones)?;
+                    worker_stats.record(&store, 0, 0);
+                    (Some(log), Some(tombstone_log))
+                }
+                Some(dir) => {
+                    // Left behind by an earlier store, whose logs are replaced
+                    match std::fs::remove_file(ColdIndex::path(dir, thread_index)) {
+                        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                        _ => {}
+                    }
+                    (Some(LeafLog::create_with_sequences(dir, thread_index)?), Some(LeafLog::create(&LeafLog::tombstone_path(dir, thread_index))?))
+                }
+                None => (None, None),
+            };
+
+            worker_stats.record_table(&store);
+            let (dir, feed) = (leaf_log_dir.clone(), feed.clone());
+            thread::spawn(move || {
+                Self::supervise_worker(thread_index, Arc::new(store), rx, worker_stats, leaf_log, tombstone_log, dir, feed);
+            });
+        }
+
+        Ok(Self {
+            threads,
+            stats,
+            salt,
+            hasher,
+            partitioning: Partitioning::default(),
+            next_worker: Mutex::new(0),
+            leaf_log_dir,
+            index_size,
+            limits: RwLock::new(StoreLimits::default()),
+            queue_capacity: AtomicUsize::new(DEFAULT_QUEUE_CAPACITY),
+            salting_threads: AtomicUsize::new(thread::available_parallelism().map_or(1, |threads| threads.get())),
+            bloom_filter: RwLock::new(None),
+            next_sequence,
+            feed,
+        })
+    }
+
+    // Fill `store` from the logs of its worker in `dir`: the cold index, the logged hashes above its bound, their
+    // metadata and resubmissions, and the tombstones. Hashes logged without a sequence number get the next one, which is appended to `log`.
+    fn restore_store(
+        store: &HashStore<INDEX_SIZE, PREFIX_SIZE>,
+        dir: &Path,
+        thread_index: usize,
+        log: &mut LeafLog,
+        salted_hashes: Vec<Hash512>,
+        sequences: Vec<u64>,
+        tombstones: Vec<Hash512>,
+    ) -> io::Result<()> {
+        // Hashes numbered below the bound of the cold index stay on disk
+        let cold_bound = match ColdIndex::open(&ColdIndex::path(dir, thread_index))? {
+            Some(index) => {
+                let bound = index.bound();
+                store.set_cold_index(index);
+                bound
+            }
+            None => 0,
+        };
+        for (index, salted_hash) in salted_hashes.into_iter().enumerate() {
+            match sequences.get(index) {
+                Some(&sequence) if sequence < cold_bound => {}
+                Some(&sequence) => {
+                    store.insert_salted_hash(salted_hash, Some(sequence));
+                }
+                // Logged before sequence numbers were recorded, or the number was lost in a crash
+                None => {
+                    store.add_salted_hash(salted_hash);
+                    log.append_sequence(store.stored_sequence(&salted_hash).unwrap())?;
+                }
+            }
+        }
+        // Cold hashes keep their metadata in memory like the others
+        let (mut metadata, mut resubmissions) = (store.metadata.write().unwrap(), store.resubmissions.write().unwrap());
+        for record in LeafLog::read_metadata(dir, thread_index)? {
+            if !store.is_stored(&record.leaf) {
+                continue;
+            }
+            if let Some(submitted) = record.metadata {
+                metadata.entry(record.leaf).or_insert_with(|| Arc::new(submitted));
+            }
+            if let Some(counted) = record.resubmissions {
+                resubmissions.insert(record.leaf, counted);
+            }
+        }
+        drop((metadata, resubmissions));
+        for salted_hash in tombstones {
+            store.remove_salted(salted_hash);
+        }
+        Ok(())
+    }
+
+    // Run a worker and restart it whenever it panics. The command it was handling is lost, its sender sees the
+    // channel closed. With leaf logs the store is rebuilt from them, otherwise the old store is kept as it was.
+    #[allow(clippy::too_many_arguments)]
+    fn supervise_worker(
+        thread_index: usize,
+        mut store: Arc<HashStore<INDEX_SIZE, PREFIX_SIZE>>,
+        rx: Receiver<HashCommand<INDEX_SIZE, PREFIX_SIZE>>,
+        stats: Arc<WorkerStats>,
+        mut leaf_log: Option<LeafLog>,
+        mut tombstone_log: Option<LeafLog>,
+        leaf_log_dir: Option<PathBuf>,
+        feed: broadcast::Sender<AcceptedHash>,
+    ) {
+        while panic::catch_unwind(AssertUnwindSafe(|| Self::hash_store_worker(&store, &rx, &stats, &mut leaf_log, &mut tombstone_log, &feed))).is_err() {
+            stats.restarts.fetch_add(1, Ordering::Relaxed);
+            store.clear_poison();
+            if let (Some(dir), Some(log), Some(tombstone_log)) = (&leaf_log_dir, &mut leaf_log, &mut tombstone_log) {
+                match Self::recover_store(&store, dir, thread_index, log, tombstone_log) {
+                    Ok(recovered) => store = Arc::new(recovered),
+                    Err(e) => eprintln!("Failed to recover worker {} from its leaf log, keeping its hashes in memory: {}", thread_index, e),
+                }
+            }
+            stats.record(&store, 0, 0);
+            stats.record_table(&store);
+            eprintln!("Worker {} panicked and was restarted", thread_index);
+        }
+    }
+
+    // Store for a worker that panicked, with the hashes, metadata and resubmissions of its logs and the settings of its old store
+    fn recover_store(
+        old: &HashStore<INDEX_SIZE, PREFIX_SIZE>,
+        dir: &Path,
+        thread_index: usize,
+        log: &mut LeafLog,
+        tombstone_log: &mut LeafLog,
+    ) -> io::Result<HashStore<INDEX_SIZE, PREFIX_SIZE>> {
+        log.sync()?;
+        tombstone_log.sync()?;
+        let store = HashStore::with_index_size(old.salt, Arc::clone(&old.hasher), old.index_size()).sharing_sequence(Arc::clone(&old.next_sequence), Arc::clone(&old.sequence_clock));
+        store.set_limits(*old.limits.read().unwrap());
+        store.set_resize_policy(*old.resize_policy.read().unwrap());
+        *store.bloom_filter.write().unwrap() = old.bloom_filter.read().unwrap().clone();
+        let salted_hashes = LeafLog::read(&LeafLog::path(dir, thread_index), log.len)?;
+        let sequences = LeafLog::read_sequences(dir, thread_index, log.len)?;
+        let tombstones = LeafLog::read(&LeafLog::tombstone_path(dir, thread_index), tombstone_log.len)?;
+        Self::restore_store(&store, dir, thread_index, log, salted_hashes, sequences, tombstones)?;
+        Ok(store)
+    }
+
+    fn log_tombstones(tombstone_log: &mut Option<LeafLog>, removed: &[Hash512]) {
+        for salted_hash in removed {
+            LeafLog::record(tombstone_log, salted_hash, None);
+        }
+        // Removals are rare, so every one is persisted before it is acknowledged
+        if let Some(log) = tombstone_log
+            && let Err(e) = log.sync()
+        {
+            eprintln!("Failed to flush tombstone log: {}", e);
+        }
+    }
+
+    // Log a hash the worker just stored and send it to the feed subscribers
+    fn record_added(
+        store: &HashStore<INDEX_SIZE, PREFIX_SIZE>,
+        leaf_log: &mut Option<LeafLog>,
+        feed: &broadcast::Sender<AcceptedHash>,
+        salted_hash: &Hash512,
+        metadata: Option<&Arc<HashMetadata>>,
+    ) {
+        let sequence = store.stored_sequence(salted_hash);
+        LeafLog::record(leaf_log, salted_hash, sequence);
+        if let Some(metadata) = metadata {
+            LeafLog::record_metadata(leaf_log, MetadataRecord { leaf: *salted_hash, metadata: Some((**metadata).clone()), resubmissions: None });
+        }
+        if feed.receiver_count() > 0 {
+            let _ = feed.send(AcceptedHash { salted_hash: *salted_hash, sequence, metadata: metadata.cloned() });
+        }
+    }
+
+    // Log the resubmissions of a hash the worker just counted another submission of
+    fn record_resubmission(store: &HashStore<INDEX_SIZE, PREFIX_SIZE>, leaf_log: &mut Option<LeafLog>, salted_hash: &Hash512) {
+        let resubmissions = store.resubmissions.read().unwrap().get(salted_hash).copied();
+        if resubmissions.is_some() {
+            LeafLog::record_metadata(leaf_log, MetadataRecord { leaf: *salted_hash, metadata: None, resubmissions });
+        }
+    }
+
+    // Handle commands until the store is shut down or dropped
+    fn hash_store_worker(
+        store: &Arc<HashStore<INDEX_SIZE, PREFIX_SIZE>>,
+        rx: &Receiver<HashCommand<INDEX_SIZE, PREFIX_SIZE>>,
+        stats: &Arc<WorkerStats>,
+        leaf_log: &mut Option<LeafLog>,
+        tombstone_log: &mut Option<LeafLog>,
+        feed: &broadcast::Sender<AcceptedHash>,
+    ) {
+        #[cfg(test)]
+        let (mut dropped, mut delay) = (0, Duration::ZERO);
+        while let Ok(cmd) = rx.recv() {
+            stats.queued.fetch_sub(1, Ordering::Relaxed);
+            #[cfg(test)]
+            let cmd = match cmd {
+                HashCommand::InjectFault(Fault::Panic) => panic!("Injected worker panic"),
+                HashCommand::InjectFault(Fault::DropResponses(count)) => {
+                    dropped = count;
+                    continue;
+                }
+                HashCommand::InjectFault(Fault::Delay(duration)) => {
+                    delay = duration;
+                    continue;
+                }
+                _ if dropped > 0 => {
+                    dropped -= 1;
+                    continue;
+                }
+                cmd => {
+                    thread::sleep(delay);
+                    cmd
+                }
+            };
+            match cmd {
+                HashCommand::AddHash(hash, encoding, tx) => {
+                    let salted_hash = store.leaf(&hash, encoding);
+                    let result = store.add_salted_hash_with_metadata(salted_hash, None);
+                    match result {
+                        Ok(true) => Self::record_added(store, leaf_log, feed, &salted_hash, None),
+                        Ok(false) => Self::record_resubmission(store, leaf_log, &salted_hash),
+                        Err(_) => {}
+                    }
+                    stats.record(store, result.is_ok() as usize, (result == Ok(true)) as usize);
+                    let _ = tx.send(result);
+                }
+                HashCommand::AddBatch(salted_hashes, metadata, tx) => {
+                    // Stops at the first hash rejected by a limit, the ones before it stay added
+                    let mut results = Vec::with_capacity(salted_hashes.len());
+                    let mut full = None;
+                    for salted_hash in salted_hashes {
+                        match store.add_salted_hash_with_metadata(salted_hash, metadata.clone()) {
+                            Ok(is_new) => {
+                                if is_new {
+                                    Self::record_added(store, leaf_log, feed, &salted_hash, metadata.as_ref());
+                                } else {
+                                    Self::record_resubmission(store, leaf_log, &salted_hash);
+                                }
+                                results.push(is_new);
+                            }
+                            Err(e) => {
+                                full = Some(e);
+                                break;
+                            }
+                        }
+                    }
+                    stats.record(store, results.len(), results.iter().filter(|&&is_new| is_new).count());
+                    let _ = tx.send(full.map_or(Ok(results), Err));
+                }
+                HashCommand::AddSalted(salted_hashes, tx) => {
+                    let mut added = 0;
+                    let mut full = None;
+                    for salted_hash in salted_hashes {
+                        match store.try_add_salted_hash(salted_hash) {
+                            Ok(true) => {
+                                LeafLog::record(leaf_log, &salted_hash, store.stored_sequence(&salted_hash));
+                                added += 1;
+                            }
+                            Ok(false) => {}
+                            Err(e) => {
+                                full = Some(e);
+                                break;
+                            }
+                        }
+                    }
+                    stats.record(store, added, added);
+                    let _ = tx.send(full.map_or(Ok(added), Err));
+                }
+                HashCommand::Contains(hash, encoding, tx) => {
+                    let exists = store.contains_with_encoding(&hash, encoding);
+                    let _ = tx.send(exists);
+                }
+                HashCommand::ContainsSalted(salted_hash, tx) => {
+                    let exists = store.contains_salted(&salted_hash);
+                    let _ = tx.send(exists);
+                }
+                HashCommand::Stored(salted_hashes, tx) => {
+                    let _ = tx.send(salted_hashes.iter().map(|salted_hash| store.is_stored(salted_hash)).collect());
+                }
+                HashCommand::GetMetadata(hash, encoding, tx) => {
+                    let metadata = store.salted_metadata(&store.leaf(&hash, encoding));
+                    let _ = tx.send(metadata);
+                }
+                HashCommand::GetResubmissions(hash, encoding, tx) => {
+                    let _ = tx.send(store.resubmissions_with_encoding(&hash, encoding));
+                }
+                HashCommand::GetSequence(hash, encoding, tx) => {
+                    let sequence = store.sequence_with_encoding(&hash, encoding);
+                    let _ = tx.send(sequence);
+                }
+                HashCommand::GetEntries(tx) => {
+                    let _ = tx.send(store.entries());
+                }
+                HashCommand::GetStore(tx) => {
+                    let _ = tx.send(Arc::clone(store));
+                }
+                HashCommand::IterRange(mut skip, limit, since, tx) => {
+                    let range = store.range_from(&mut skip, limit, since);
+                    let _ = tx.send((range, skip));
+                }
+                HashCommand::Snapshot(leaves, tx) => {
+                    store.append_to(&mut leaves.lock().unwrap());
+                    let _ = tx.send(LeafLog::flushed_len(leaf_log));
+                }
+                HashCommand::SnapshotNumbered(first, bound, entries, tx) => {
+                    store.append_numbered(first, bound, &mut entries.lock().unwrap());
+                    let _ = tx.send(LeafLog::flushed_len(leaf_log));
+                }
+                HashCommand::CountParts(bound, parts, tx) => {
+                    let mut sizes = vec![0; parts];
+                    store.for_each_numbered(0, bound, |_, salted_hash| sizes[leaf_part(&salted_hash, parts)] += 1);
+                    let _ = tx.send(sizes);
+                }
+                HashCommand::GetPart(bound, parts, part, tx) => {
+                    let mut salted_hashes = Vec::new();
+                    store.for_each_numbered(0, bound, |_, salted_hash| if leaf_part(&salted_hash, parts) == part {
+                        salted_hashes.push(salted_hash);
+                    });
+                    let _ = tx.send(salted_hashes);
+                }
+                HashCommand::GetStoredSequence(salted_hash, tx) => {
+                    let _ = tx.send(store.stored_sequence(&salted_hash));
+                }
+                HashCommand::CheckBuckets(tx) => {
+                    let _ = tx.send(store.check_buckets());
+                }
+                HashCommand::MergeSalted(entries, tx) => {
+                    let submitted = entries.len();
+                    let mut added = 0;
+                    for (salted_hash, metadata) in entries {
+                        if store.add_merged(salted_hash, metadata.clone()) {
+                            Self::record_added(store, leaf_log, feed, &salted_hash, metadata.as_ref());
+                            added += 1;
+                        }
+                    }
+                    stats.record(store, submitted, added);
+                    let _ = tx.send(added);
+                }
+                HashCommand::Remove(salted_hash, tx) => {
+                    let removed = match salted_hash {
+                        Some(salted_hash) if store.remove_salted(salted_hash) => vec![salted_hash],
+                        Some(_) => Vec::new(),
+                        None => store.remove_all(),
+                    };
+                    Self::log_tombstones(tombstone_log, &removed);
+                    stats.record(store, 0, 0);
+                    let _ = tx.send(removed.len());
+                }
+                HashCommand::Expire(retention, now, tx) => {
+                    let expired = store.expire(&retention, now);
+                    let salted_hashes: Vec<Hash512> = expired.iter().map(|(salted_hash, _)| *salted_hash).collect();
+                    Self::log_tombstones(tombstone_log, &salted_hashes);
+                    stats.record(store, 0, 0);
+                    let mut counts = HashMap::new();
+                    for (_, namespace) in expired {
+                        *counts.entry(namespace).or_insert(0) += 1;
+                    }
+                    let _ = tx.send(counts);
+                }
+                HashCommand::SetLimits(limits, tx) => {
+                    store.set_limits(limits);
+                    let _ = tx.send(());
+                }
+                HashCommand::SetResizePolicy(policy, tx) => {
+                    store.set_resize_policy(policy);
+                    let _ = tx.send(());
+                }
+                HashCommand::SetChainLimit(limit, tx) => {
+                    store.set_chain_limit(limit);
+                    let _ = tx.send(());
+                }
+                HashCommand::SetBloomFilter(filter, tx) => {
+                    store.set_bloom_filter(filter);
+                    let _ = tx.send(());
+                }
+                HashCommand::MoveToCold(path, bound, tx) => {
+                    let moved = store.move_to_cold(&path, bound);
+                    stats.record(store, 0, 0);
+                    stats.record_table(store);
+                    let _ = tx.send(moved);
+                }
+                HashCommand::RotateBucketSalt => Self::rotate_in_background(store, stats),
+                HashCommand::Ping(tx) => {
+                    let _ = tx.send(());
+                }
+                HashCommand::Shutdown(tx) => {
+                    if let Some(log) = leaf_log
+                        && let Err(e) = log.sync()
+                    {
+                        eprintln!("Failed to flush leaf log: {}", e);
+                    }
+                    let _ = tx.send(());
+                    break;
+                }
+                #[cfg(test)]
+                HashCommand::InjectFault(_) => unreachable!(),
+            }
+            Self::grow_in_background(store, stats);
+        }
+    }
+
+    // Start growing the bucket table of a worker on its own thread if its chains got too long, so the
+    // worker keeps serving commands meanwhile
+    fn grow_in_background(store: &Arc<HashStore<INDEX_SIZE, PREFIX_SIZE>>, stats: &Arc<WorkerStats>) {
+        if !store.needs_resize() || store.resize_progress().is_some() {
+            return;
+        }
+        let (store, stats) = (Arc::clone(store), Arc::clone(stats));
+        thread::spawn(move || {
+            store.grow_with(true, |store| stats.record_table(store));
+        });
+    }
+
+    fn rotate_in_background(store: &Arc<HashStore<INDEX_SIZE, PREFIX_SIZE>>, stats: &Arc<WorkerStats>) {
+        let (store, stats) = (Arc::clone(store), Arc::clone(stats));
+        thread::spawn(move || {
+            if store.rotate_bucket_salt() {
+                stats.record_table(&store);
+            }
+        });
+    }
+
+    pub fn add_hash(&self, hash: Hash512) -> Result<bool, StorageError> {
+        self.add_hash_with_encoding(hash, LeafEncoding::default())
+    }
+
+    // Grow the bucket table of every worker according to `policy`, see `HashStore::set_resize_policy`
+    pub fn set_resize_policy(&self, policy: Option<ResizePolicy>) {
+        let acks: Vec<_> = self.threads.iter().map(|tx| {
+            let (response_tx, response_rx) = channel();
+            let _ = tx.send(HashCommand::SetResizePolicy(policy, response_tx));
+            response_rx
+        }).collect();
+        for ack in acks {
+            let _ = ack.recv();
+        }
+    }
+
+    // Limit the hashes of every bucket of every worker, see `HashStore::set_chain_limit`
+    pub fn set_chain_limit(&self, limit: Option<ChainLimit>) {
+        let acks: Vec<_> = self.threads.iter().map(|tx| {
+            let (response_tx, response_rx) = channel();
+            let _ = tx.send(HashCommand::SetChainLimit(limit, response_tx));
+            response_rx
+        }).collect();
+        for ack in acks {
+            let _ = ack.recv();
+        }
+    }
+
+    // Limit the number of hashes or the memory of the whole store, see `StoreLimits`.
+    // Every worker gets an equal share, so the store counts as full once any worker is.
+    pub fn set_limits(&self, limits: StoreLimits) {
+        *self.limits.write().unwrap() = limits;
+        let limits = limits.per_worker(self.threads.len());
+        let acks: Vec<_> = self.threads.iter().map(|tx| {
+            let (response_tx, response_rx) = channel();
+            let _ = tx.send(HashCommand::SetLimits(limits, response_tx));
+            response_rx
+        }).collect();
+        for ack in acks {
+            let _ = ack.recv();
+        }
+    }
+
+    pub fn limits(&self) -> StoreLimits {
+        *self.limits.read().unwrap()
+    }
+
+    // Reject adds with `StorageError::Saturated` while `capacity` commands wait for a worker they go to,
+    // instead of queueing them without bound when the workers fall behind. Lookups are always queued.
+    pub fn set_queue_capacity(&self, capacity: usize) {
+        self.queue_capacity.store(capacity, Ordering::Relaxed);
+    }
+
+    pub fn queue_capacity(&self) -> usize {
+        self.queue_capacity.load(Ordering::Relaxed)
+    }
+
+    // Answer lookups of hashes `filter` rules out without a round trip to their worker. The workers fill it
+    // with their stored hashes first and insert every new one, lookups only use it once all of them are done.
+    pub fn set_bloom_filter(&self, filter: BloomFilter) {
+        let filter = Arc::new(filter);
+        let acks: Vec<_> = self.threads.iter().map(|tx| {
+            let (response_tx, response_rx) = channel();
+            let _ = tx.send(HashCommand::SetBloomFilter(Arc::clone(&filter), response_tx));
+            response_rx
+        }).collect();
+        for ack in acks {
+            let _ = ack.recv();
+        }
+        *self.bloom_filter.write().unwrap() = Some(filter);
+    }
+
+    fn bloom_filter(&self) -> Option<Arc<BloomFilter>> {
+        self.bloom_filter.read().unwrap().clone()
+    }
+
+    // Threads a batch is salted on before it is sent to the workers, one per core by default
+    pub fn set_salting_threads(&self, threads: usize) {
+        self.salting_threads.store(threads, Ordering::Relaxed);
+    }
+
+    pub fn salting_threads(&self) -> usize {
+        self.salting_threads.load(Ordering::Relaxed)
+    }
+
+    // Take a place in the queue of every worker in `thread_indices`, or of none if any is saturated
+    fn reserve(&self, thread_indices: &[usize]) -> Result<(), StorageError> {
+        let capacity = self.queue_capacity();
+        for (reserved, &thread_index) in thread_indices.iter().enumerate() {
+            if !self.threads[thread_index].reserve(capacity) {
+                for &thread_index in &thread_indices[..reserved] {
+                    self.threads[thread_index].release();
+                }
+                return Err(StorageError::Saturated(capacity));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn hasher(&self) -> &Arc<dyn Hasher> {
+        &self.hasher
+    }
+
+    pub fn salt(&self) -> &Hash512 {
+        &self.salt
+    }
+
+    // Directory of the leaf logs, `None` for stores kept in memory only
+    pub fn leaf_log_dir(&self) -> Option<&Path> {
+        self.leaf_log_dir.as_deref()
+    }
+
+    // Hashes stored from now on, added with the store salt or replicated. Hashes added with `add_blinded` aren't sent.
+    pub fn subscribe_feed(&self) -> broadcast::Receiver<AcceptedHash> {
+        self.feed.subscribe()
+    }
+
+    pub fn num_threads(&self) -> usize {
+        self.threads.len()
+    }
+
+    #[cfg(test)]
+    fn inject_fault(&self, thread_index: usize, fault: Fault) {
+        let _ = self.threads[thread_index].send(HashCommand::InjectFault(fault));
+    }
+
+    // Spread the hashes over the workers by `partitioning` instead of by their unsalted prefix.
+    // A restored store has to use the partitioning its hashes were added with, see `StoreConfig`.
+    pub fn with_partitioning(mut self, partitioning: Partitioning) -> Self {
+        if partitioning == Partitioning::Prefix && self.threads.len() > 1 << PREFIX_SIZE {
+            panic!("Prefix partitioning over {} threads needs a PREFIX_SIZE of at least {}", self.threads.len(), self.threads.len().trailing_zeros());
+        }
+        self.partitioning = partitioning;
+        self
+    }
+
+    pub fn partitioning(&self) -> Partitioning {
+        self.partitioning
+    }
+
+    // Index bits the tables of the workers started with
+    pub fn initial_index_size(&self) -> usize {
+        self.index_size
+    }
+
+    fn leaf(&self, hash: &Hash512, encoding: LeafEncoding) -> Hash512 {
+        encoding.leaf_with_hasher(&*self.hasher, hash, &self.salt)
+    }
+
+    // Worker storing a hash, from the hash or its salted hash, which is only computed if the partitioning needs it.
+    // `None` with round-robin partitioning, where any worker may store it.
+    fn thread_index(&self, hash: &Hash512, salted_hash: impl FnOnce() -> Hash512) -> Option<usize> {
+        let bits = self.threads.len().trailing_zeros() as usize;
+        match self.partitioning {
+            Partitioning::UnsaltedPrefix => Some(hash.to_index(0, bits)),
+            Partitioning::SaltedPrefix => Some(salted_hash().0[7] as usize & (self.threads.len() - 1)),
+            Partitioning::RoundRobin => None,
+            Partitioning::Rendezvous => {
+                let salted_hash = salted_hash();
+                (0..self.threads.len()).max_by_key(|&thread_index| rendezvous_score(&salted_hash, thread_index))
+            }
+            Partitioning::Prefix => Some(salted_hash().to_index(0, bits)),
+        }
+    }
+
+    // Workers that may store a hash, see `thread_index`
+    fn candidate_workers(&self, hash: &Hash512, salted_hash: impl FnOnce() -> Hash512) -> Range<usize> {
+        match self.thread_index(hash, salted_hash) {
+            Some(thread_index) => thread_index..thread_index + 1,
+            None => 0..self.threads.len(),
+        }
+    }
+
+    // Send the command made by `command` to each of `workers`, without waiting for their answers
+    fn ask<T>(&self, workers: Range<usize>, command: impl Fn(Sender<T>) -> HashCommand<INDEX_SIZE, PREFIX_SIZE>) -> Vec<(usize, Receiver<T>)> {
+        workers.map(|thread_index| {
+            let (response_tx, response_rx) = channel();
+            let _ = self.threads[thread_index].send(command(response_tx));
+            (thread_index, response_rx)
+        }).collect()
+    }
+
+    // Worker each of the salted hashes of an add goes to. With round-robin partitioning, a hash some worker stores
+    // already goes to that worker and the others to the workers in turn. The returned lock has to be held until
+    // the hashes are sent, so that a concurrent add of the same hash finds them.
+    fn assign_workers(&self, hashes: &[Hash512], salted_hashes: &[Hash512]) -> Result<(Vec<usize>, Option<MutexGuard<'_, usize>>), StorageError> {
+        if self.partitioning != Partitioning::RoundRobin {
+            let workers = hashes.iter().zip(salted_hashes)
+                .map(|(hash, salted_hash)| self.thread_index(hash, || *salted_hash).unwrap())
+                .collect();
+            return Ok((workers, None));
+        }
+        let mut next_worker = self.next_worker.lock().unwrap();
+        let mut assigned: HashMap<Hash512, usize> = HashMap::new();
+        for (thread_index, response_rx) in self.ask(0..self.threads.len(), |tx| HashCommand::Stored(salted_hashes.to_vec(), tx)) {
+            let stored = answer(thread_index, response_rx)?;
+            for (salted_hash, _) in salted_hashes.iter().zip(stored).filter(|(_, stored)| *stored) {
+                assigned.insert(*salted_hash, thread_index);
+            }
+        }
+        let workers = salted_hashes.iter()
+            .map(|salted_hash| *assigned.entry(*salted_hash).or_insert_with(|| {
+                let thread_index = *next_worker;
+                *next_worker = (thread_index + 1) % self.threads.len();
+                thread_index
+            }))
+            .collect();
+        Ok((workers, Some(next_worker)))
+    }
+
+    pub fn add_hash_with_encoding(&self, hash: Hash512, encoding: LeafEncoding) -> Result<bool, StorageError> {
+        // Only workers partitioned by the unsalted hash salt it themselves
+        if self.partitioning != Partitioning::UnsaltedPrefix {
+            return Ok(self.add_batch_with_encoding(&[hash], encoding)?[0]);
+        }
+        let thread_index = self.thread_index(&hash, || unreachable!()).unwrap();
+        let (response_tx, response_rx) = channel();
+
+        self.reserve(&[thread_index])?;
+        self.threads[thread_index].send_reserved(HashCommand::AddHash(hash, encoding, response_tx));
+        response_rx.recv().unwrap_or(Err(StorageError::WorkerUnavailable(thread_index)))
+    }
+
+    pub fn add_batch(&self, hashes: &[Hash512]) -> Result<Vec<bool>, StorageError> {
+        self.add_batch_with_encoding(hashes, LeafEncoding::default())
+    }
+
+    // Add many hashes with a single message per worker and return for each hash whether it was new.
+    // If a worker is full, the hashes it accepted before and those of the other workers stay added.
+    // If a worker is saturated, none of the hashes are added.
+    pub fn add_batch_with_encoding(&self, hashes: &[Hash512], encoding: LeafEncoding) -> Result<Vec<bool>, StorageError> {
+        self.add_batch_with_metadata(hashes, encoding, None)
+    }
+
+    // Like `add_batch_with_encoding`, attaching `metadata` to every hash of the batch that is new
+    pub fn add_batch_with_metadata(&self, hashes: &[Hash512], encoding: LeafEncoding, metadata: Option<HashMetadata>) -> Result<Vec<bool>, StorageError> {
+        let metadata = metadata.map(Arc::new);
+        // The salting is done here so it isn't limited to one core per worker
+        let leaves = salt_batch(&*self.hasher, hashes, &self.salt, encoding, self.salting_threads());
+        let (workers, next_worker) = self.assign_workers(hashes, &leaves)?;
+        let mut partitions = vec![Vec::new(); self.threads.len()];
+        for (&thread_index, leaf) in workers.iter().zip(leaves) {
+            partitions[thread_index].push(leaf);
+        }
+
+        let used: Vec<usize> = (0..partitions.len()).filter(|&thread_index| !partitions[thread_index].is_empty()).collect();
+        self.reserve(&used)?;
+        let responses: Vec<_> = partitions.into_iter().enumerate().map(|(thread_index, partition)| {
+            let (response_tx, response_rx) = channel();
+            if partition.is_empty() {
+                let _ = response_tx.send(Ok(Vec::new()));
+            } else {
+                self.threads[thread_index].send_reserved(HashCommand::AddBatch(partition, metadata.clone(), response_tx));
+            }
+            response_rx
+        }).collect();
+        drop(next_worker);
+        let mut results = Vec::with_capacity(responses.len());
+        for (thread_index, response_rx) in responses.into_iter().enumerate() {
+            results.push(response_rx.recv().unwrap_or(Err(StorageError::WorkerUnavailable(thread_index))));
+        }
+        let mut results = results.into_iter()
+            .map(|result| result.map(Vec::into_iter))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // Every worker answers in the order its hashes were sent, so results can be taken in input order
+        Ok(workers.iter()
+            .map(|&thread_index| results[thread_index].next().unwrap_or(false))
+            .collect())
+    }
+
+    // Like `add_batch_with_metadata`, but every hash is only added once and its later copies in the batch
+    // are reported as `AddStatus::Duplicate` instead of as already stored
+    pub fn add_batch_deduplicated(&self, hashes: &[Hash512], encoding: LeafEncoding, metadata: Option<HashMetadata>) -> Result<Vec<AddStatus>, StorageError> {
+        let mut seen = HashSet::with_capacity(hashes.len());
+        let unique: Vec<Hash512> = hashes.iter().filter(|hash| seen.insert(**hash)).copied().collect();
+        let mut results = self.add_batch_with_metadata(&unique, encoding, metadata)?.into_iter();
+
+        seen.clear();
+        Ok(hashes.iter()
+            .map(|hash| match seen.insert(*hash) {
+                false => AddStatus::Duplicate,
+                true if results.next().unwrap() => AddStatus::New,
+                true => AddStatus::Existing,
+            })
+            .collect())
+    }
+
+    pub fn contains(&self, hash: &Hash512) -> Result<bool, StorageError> {
+        self.contains_with_encoding(hash, LeafEncoding::default())
+    }
+
+    pub fn contains_with_encoding(&self, hash: &Hash512, encoding: LeafEncoding) -> Result<bool, StorageError> {
+        // The filter and most partitionings need the salted hash, otherwise the worker salts it
+        if self.bloom_filter.read().unwrap().is_some() || self.partitioning != Partitioning::UnsaltedPrefix {
+            return self.contains_salted(hash, self.leaf(hash, encoding));
+        }
+        let thread_index = self.thread_index(hash, || unreachable!()).unwrap();
+        let (response_tx, response_rx) = channel();
+
+        let _ = self.threads[thread_index].send(HashCommand::Contains(*hash, encoding, response_tx));
+        answer(thread_index, response_rx)
+    }
+
+    // Whether the worker of `hash` stores `salted_hash`, only asking it if the Bloom filter can't rule it out
+    fn contains_salted(&self, hash: &Hash512, salted_hash: Hash512) -> Result<bool, StorageError> {
+        let filter = self.bloom_filter();
+        if filter.as_ref().is_some_and(|filter| !filter.may_contain(&salted_hash)) {
+            return Ok(false);
+        }
+        let mut exists = false;
+        for (thread_index, response_rx) in self.ask(self.candidate_workers(hash, || salted_hash), |tx| HashCommand::ContainsSalted(salted_hash, tx)) {
+            exists |= answer(thread_index, response_rx)?;
+        }
+        if !exists && let Some(filter) = filter {
+            filter.record_false_positive();
+        }
+        Ok(exists)
+    }
+
+    // Add hashes in privacy mode: every hash is salted with its own random nonce instead of the store salt,
+    // so only whoever knows the nonce can look it up. Returns the nonces in input order.
+    pub fn add_blinded(&self, hashes: &[Hash512], encoding: LeafEncoding) -> Result<Vec<Hash512>, StorageError> {
+        let nonces: Vec<Hash512> = hashes.iter().map(|_| random_salt()).collect();
+        // Partitioned like other leaves, so lookups with the nonce can be routed the same way
+        let leaves: Vec<Hash512> = hashes.iter().zip(&nonces).map(|(hash, nonce)| encoding.leaf_with_hasher(&*self.hasher, hash, nonce)).collect();
+        let (workers, next_worker) = self.assign_workers(hashes, &leaves)?;
+        let mut partitions = vec![Vec::new(); self.threads.len()];
+        for (&thread_index, leaf) in workers.iter().zip(leaves) {
+            partitions[thread_index].push(leaf);
+        }
+
+        let used: Vec<usize> = (0..partitions.len()).filter(|&thread_index| !partitions[thread_index].is_empty()).collect();
+        self.reserve(&used)?;
+        let responses: Vec<_> = partitions.into_iter().enumerate().filter(|(_, partition)| !partition.is_empty()).map(|(thread_index, partition)| {
+            let (response_tx, response_rx) = channel();
+            self.threads[thread_index].send_reserved(HashCommand::AddSalted(partition, response_tx));
+            (thread_index, response_rx)
+        }).collect();
+        drop(next_worker);
+        let mut full = None;
+        for (thread_index, response_rx) in responses {
+            match response_rx.recv() {
+                Ok(Err(e)) => full = Some(e),
+                Err(_) => full = Some(StorageError::WorkerUnavailable(thread_index)),
+                Ok(Ok(_)) => {}
+            }
+        }
+        full.map_or(Ok(nonces), Err)
+    }
+
+    // Remove a hash, see `HashStore::remove_with_encoding`. With leaf logs, the tombstone is persisted next to them.
+    pub fn remove_with_encoding(&self, hash: &Hash512, encoding: LeafEncoding) -> Result<bool, StorageError> {
+        self.remove_salted(hash, encoding.leaf_with_hasher(&*self.hasher, hash, &self.salt))
+    }
+
+    // Remove a hash added with `add_blinded`
+    pub fn remove_blinded(&self, hash: &Hash512, nonce: &Hash512, encoding: LeafEncoding) -> Result<bool, StorageError> {
+        self.remove_salted(hash, encoding.leaf_with_hasher(&*self.hasher, hash, nonce))
+    }
+
+    fn remove_salted(&self, hash: &Hash512, salted_hash: Hash512) -> Result<bool, StorageError> {
+        let mut removed = 0;
+        for (thread_index, response_rx) in self.ask(self.candidate_workers(hash, || salted_hash), |tx| HashCommand::Remove(Some(salted_hash), tx)) {
+            removed += answer(thread_index, response_rx)?;
+        }
+        Ok(removed > 0)
+    }
+
+    // Remove every stored hash and return how many were removed. Fails if a worker didn't answer,
+    // the other workers' hashes are removed anyway.
+    pub fn remove_all(&self) -> Result<usize, StorageError> {
+        let responses: Vec<_> = self.threads.iter().map(|tx| {
+            let (response_tx, response_rx) = channel();
+            let _ = tx.send(HashCommand::Remove(None, response_tx));
+            response_rx
+        }).collect();
+        let removed: Vec<_> = responses.into_iter().enumerate().map(|(thread_index, response_rx)| answer(thread_index, response_rx)).collect();
+        removed.into_iter().sum()
+    }
+
+    // Remove the hashes of namespaces in `retention` (seconds by namespace) submitted that long before `now`
+    // and return how many of each namespace were removed. Like `remove_all`, the other workers' hashes are
+    // removed even if one didn't answer.
+    pub fn expire(&self, retention: &Arc<HashMap<String, u64>>, now: u64) -> Result<HashMap<String, usize>, StorageError> {
+        let responses: Vec<_> = self.threads.iter().map(|tx| {
+            let (response_tx, response_rx) = channel();
+            let _ = tx.send(HashCommand::Expire(Arc::clone(retention), now, response_tx));
+            response_rx
+        }).collect();
+        let mut expired = HashMap::new();
+        let mut result = Ok(());
+        for (thread_index, response_rx) in responses.into_iter().enumerate() {
+            match answer(thread_index, response_rx) {
+                Ok(counts) => counts.into_iter().for_each(|(namespace, count)| *expired.entry(namespace).or_insert(0) += count),
+                Err(e) => result = Err(e),
+            }
+        }
+        result.map(|_| expired)
+    }
+
+    // Whether `hash` was added with `add_blinded` and got `nonce`
+    pub fn contains_blinded(&self, hash: &Hash512, nonce: &Hash512, encoding: LeafEncoding) -> Result<bool, StorageError> {
+        self.contains_salted(hash, encoding.leaf_with_hasher(&*self.hasher, hash, nonce))
+    }
+
+    // Check many hashes, sending all lookups before waiting for the first answer
+    pub fn contains_batch_with_encoding(&self, hashes: &[Hash512], encoding: LeafEncoding) -> Result<Vec<bool>, StorageError> {
+        let filter = self.bloom_filter();
+        if filter.is_some() || self.partitioning != Partitioning::UnsaltedPrefix {
+            let leaves = salt_batch(&*self.hasher, hashes, &self.salt, encoding, self.salting_threads());
+            let responses: Vec<_> = hashes.iter().zip(leaves).map(|(hash, leaf)| {
+                match &filter {
+                    Some(filter) if !filter.may_contain(&leaf) => Vec::new(),
+                    _ => self.ask(self.candidate_workers(hash, || leaf), |tx| HashCommand::ContainsSalted(leaf, tx)),
+                }
+            }).collect();
+            return responses.into_iter()
+                .map(|responses| {
+                    // Ruled out by the filter
+                    if responses.is_empty() {
+                        return Ok(false);
+                    }
+                    let mut exists = false;
+                    for (thread_index, response_rx) in responses {
+                        exists |= answer(thread_index, response_rx)?;
+                    }
+                    if let Some(filter) = filter.as_ref().filter(|_| !exists) {
+                        filter.record_false_positive();
+                    }
+                    Ok(exists)
+                })
+                .collect();
+        }
+        let responses: Vec<_> = hashes.iter().map(|hash| {
+            let thread_index = self.thread_index(hash, || unreachable!()).unwrap();
+            let (response_tx, response_rx) = channel();
+            let _ = self.threads[thread_index].send(HashCommand::Contains(*hash, encoding, response_tx));
+            (thread_index, response_rx)
+        }).collect();
+        responses.into_iter()
+            .map(|(thread_index, response_rx)| answer(thread_index, response_rx))
+            .collect()
+    }
+
+    pub fn metadata(&self, hash: &Hash512) -> Result<Option<HashMetadata>, StorageError> {
+        self.metadata_with_encoding(hash, LeafEncoding::default())
+    }
+
+    pub fn metadata_with_encoding(&self, hash: &Hash512, encoding: LeafEncoding) -> Result<Option<HashMetadata>, StorageError> {
+        let mut found = None;
+        for (thread_index, response_rx) in self.ask(self.candidate_workers(hash, || self.leaf(hash, encoding)), |tx| HashCommand::GetMetadata(*hash, encoding, tx)) {
+            found = found.or(answer(thread_index, response_rx)?);
+        }
+        Ok(found.map(|metadata| (*metadata).clone()))
+    }
+
+    // Sequence number the next new hash gets
+    pub fn next_sequence(&self) -> u64 {
+        self.next_sequence.load(Ordering::Relaxed)
+    }
+
+    // Move every hash numbered below `bound` to a cold index next to the leaf logs, see `HashStore::move_to_cold`.
+    // Workers move their hashes in parallel and don't take other commands meanwhile. Returns how many were moved,
+    // none for a store without leaf logs.
+    pub fn move_to_cold(&self, bound: u64) -> io::Result<usize> {
+        let Some(dir) = &self.leaf_log_dir else {
+            return Ok(0);
+        };
+        let responses: Vec<_> = self.threads.iter().enumerate().map(|(thread_index, tx)| {
+            let (response_tx, response_rx) = channel();
+            let _ = tx.send(HashCommand::MoveToCold(ColdIndex::path(dir, thread_index), bound, response_tx));
+            response_rx
+        }).collect();
+        let mut moved = 0;
+        for response_rx in responses {
+            moved += response_rx.recv().unwrap_or(Ok(0))?;
+        }
+        Ok(moved)
+    }
+
+    // Let every worker rotate its bucket salt in the background, see `HashStore::rotate_bucket_salt`
+    pub fn rotate_bucket_salts(&self) {
+        for tx in &self.threads {
+            let _ = tx.send(HashCommand::RotateBucketSalt);
+        }
+    }
+
+    // Later submissions of a hash, see `HashStore::resubmissions_with_encoding`
+    pub fn resubmissions_with_encoding(&self, hash: &Hash512, encoding: LeafEncoding) -> Result<Option<Resubmissions>, StorageError> {
+        let mut found = None;
+        for (thread_index, response_rx) in self.ask(self.candidate_workers(hash, || self.leaf(hash, encoding)), |tx| HashCommand::GetResubmissions(*hash, encoding, tx)) {
+            found = found.or(answer(thread_index, response_rx)?);
+        }
+        Ok(found)
+    }
+
+    // Sequence number of a stored hash, see `HashStore::sequence_with_encoding`
+    pub fn sequence_with_encoding(&self, hash: &Hash512, encoding: LeafEncoding) -> Result<Option<u64>, StorageError> {
+        Ok(self.sequences_with_encoding(std::slice::from_ref(hash), encoding)?[0])
+    }
+
+    // Sequence numbers of many hashes, sending all lookups before waiting for the first answer
+    pub fn sequences_with_encoding(&self, hashes: &[Hash512], encoding: LeafEncoding) -> Result<Vec<Option<u64>>, StorageError> {
+        let responses: Vec<_> = hashes.iter()
+            .map(|hash| self.ask(self.candidate_workers(hash, || self.leaf(hash, encoding)), |tx| HashCommand::GetSequence(*hash, encoding, tx)))
+            .collect();
+        responses.into_iter()
+            .map(|responses| {
+                let mut sequence = None;
+                for (thread_index, response_rx) in responses {
+                    sequence = sequence.or(answer(thread_index, response_rx)?);
+                }
+                Ok(sequence)
+            })
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.stats.iter().map(|stats| stats.hashes.load(Ordering::Relaxed)).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn occupied_slots(&self) -> usize {
+        self.stats.iter().map(|stats| stats.occupied_slots.load(Ordering::Relaxed)).sum()
+    }
+
+    // Counters of all workers, read without contacting them.
+    // Every add that returned before this call is included.
+    pub fn stats(&self) -> StoreStats {
+        let mut total = StoreStats { chain_lengths: vec![0; CHAIN_LENGTH_HISTOGRAM_SIZE], ..StoreStats::default() };
+        for stats in &self.stats {
+            let hashes = stats.hashes.load(Ordering::Relaxed);
+            total.hashes += hashes;
+            total.occupied_slots += stats.occupied_slots.load(Ordering::Relaxed);
+            total.adds += stats.adds.load(Ordering::Relaxed);
+            total.duplicates += stats.duplicates.load(Ordering::Relaxed);
+            total.worker_hashes.push(hashes);
+            total.memory.buckets += stats.bucket_bytes.load(Ordering::Relaxed);
+            total.memory.nodes += stats.node_bytes.load(Ordering::Relaxed);
+            total.memory.metadata += stats.metadata_bytes.load(Ordering::Relaxed);
+            total.tombstones += stats.tombstones.load(Ordering::Relaxed);
+            total.cold_hashes += stats.cold_hashes.load(Ordering::Relaxed);
+            total.chain_overflows += stats.chain_overflows.load(Ordering::Relaxed);
+            total.spilled_hashes += stats.spilled_hashes.load(Ordering::Relaxed);
+            total.memory.cold_index += stats.cold_index_bytes.load(Ordering::Relaxed);
+            total.worker_queue_depths.push(stats.queued.load(Ordering::Relaxed));
+            total.bucket_slots += stats.bucket_slots.load(Ordering::Relaxed);
+            total.worker_index_sizes.push(stats.index_size.load(Ordering::Relaxed));
+            total.worker_restarts.push(stats.restarts.load(Ordering::Relaxed));
+            for (total, count) in total.chain_lengths.iter_mut().zip(&stats.chain_lengths) {
+                *total += count.load(Ordering::Relaxed);
+            }
+            let target = stats.resize_target.load(Ordering::Relaxed);
+            if target != 0 {
+                total.resizes.push(ResizeProgress {
+                    worker: total.worker_index_sizes.len() - 1,
+                    index_size: target,
+                    resized_shards: stats.resized_shards.load(Ordering::Relaxed),
+                    shards: 1 << HashStore::<INDEX_SIZE, PREFIX_SIZE>::SHARD_BITS,
+                });
+            }
+        }
+        if let Some(filter) = self.bloom_filter() {
+            total.memory.bloom_filter = filter.memory_usage();
+            total.bloom_filter = Some(filter.stats());
+        }
+        total
+    }
+
+    // Whether every worker answers within `timeout`. One busy with a long queue or stuck on a command doesn't,
+    // nor does one whose thread is gone.
+    pub fn worker_health(&self, timeout: Duration) -> Vec<bool> {
+        let responses: Vec<_> = self.threads.iter().map(|tx| {
+            let (response_tx, response_rx) = channel();
+            let _ = tx.send(HashCommand::Ping(response_tx));
+            response_rx
+        }).collect();
+        let deadline = Instant::now() + timeout;
+        responses.into_iter().map(|response_rx| response_rx.recv_timeout(deadline.saturating_duration_since(Instant::now())).is_ok()).collect()
+    }
+
+    pub fn to_array(&self) -> Result<Vec<Hash512>, StorageError> {
+        let mut all_hashes = Vec::with_capacity(self.len());
+        self.for_each_chunk(|chunk| all_hashes.extend_from_slice(chunk))?;
+        Ok(all_hashes)
+    }
+
+    // Stores of the workers, in worker order
+    fn worker_stores(&self) -> Result<Vec<Arc<HashStore<INDEX_SIZE, PREFIX_SIZE>>>, StorageError> {
+        self.ask(0..self.threads.len(), HashCommand::GetStore).into_iter()
+            .map(|(thread_index, response_rx)| answer(thread_index, response_rx))
+            .collect()
+    }
+
+    // Call `f` with the hashes of `to_array` in chunks, see `HashStore::for_each_chunk`. The workers' stores are
+    // read directly, so workers keep handling commands and only wait for a chunk when adding to the shard it is from.
+    pub fn for_each_chunk(&self, mut f: impl FnMut(&[Hash512])) -> Result<(), StorageError> {
+        for store in self.worker_stores()? {
+            store.for_each_chunk(&mut f);
+        }
+        Ok(())
+    }
+
+    // Page through the stored hashes in the order of `to_array`, see `HashStore::iter_range`
+    pub fn iter_range(&self, offset: usize, limit: usize, since: Option<u64>) -> Result<Vec<StoredHash>, StorageError> {
+        let mut range = Vec::new();
+        let mut skip = offset;
+        for (thread_index, tx) in self.threads.iter().enumerate() {
+            if range.len() >= limit {
+                break;
+            }
+            let (response_tx, response_rx) = channel();
+            let _ = tx.send(HashCommand::IterRange(skip, limit - range.len(), since, response_tx));
+            let (worker_range, remaining_skip) = answer(thread_index, response_rx)?;
+            range.extend(worker_range);
+            skip = remaining_skip;
+        }
+        Ok(range)
+    }
+
+    // Like `to_array`, but also returns the length of every worker's leaf log at the time its hashes were collected.
+    // Workers append their hashes one after another into one allocation, which the tree takes as its leaves,
+    // so a rebuild holds no other copy of them.
+    pub fn snapshot(&self) -> Result<StoreSnapshot, StorageError> {
+        let leaves = Arc::new(Mutex::new(Vec::with_capacity(self.len())));
+        let mut leaf_log_lengths = Vec::with_capacity(self.threads.len());
+
+        // One worker after the other, so the hashes are appended in worker order
+        for (thread_index, tx) in self.threads.iter().enumerate() {
+            let (response_tx, response_rx) = channel();
+            let _ = tx.send(HashCommand::Snapshot(Arc::clone(&leaves), response_tx));
+            leaf_log_lengths.push(answer(thread_index, response_rx)?);
+        }
+
+        let hashes = std::mem::take(&mut *leaves.lock().unwrap());
+        Ok(StoreSnapshot { hashes, leaf_log_lengths })
+    }
+
+    // Like `snapshot`, for the salted hashes numbered from `first` up to `bound`, ordered by their numbers. Workers
+    // answer after the adds sent to them before, so every hash numbered below `bound` once it was read is included.
+    pub fn snapshot_numbered(&self, first: u64, bound: u64) -> Result<NumberedSnapshot, StorageError> {
+        let entries = Arc::new(Mutex::new(Vec::new()));
+        let leaf_log_lengths = self.ask(0..self.threads.len(), |tx| HashCommand::SnapshotNumbered(first, bound, Arc::clone(&entries), tx))
+            .into_iter()
+            .map(|(thread_index, response_rx)| answer(thread_index, response_rx))
+            .collect::<Result<Vec<u64>, StorageError>>()?;
+
+        let mut entries = std::mem::take(&mut *entries.lock().unwrap());
+        entries.sort_unstable();
+        Ok((entries, leaf_log_lengths))
+    }
+
+    // Number of salted hashes numbered below `bound` in each of `parts` equal ranges of their first word, which
+    // follow each other in `LEAF_ORDER`. Salted hashes are spread evenly, so the ranges hold about as many each.
+    pub fn part_sizes(&self, bound: u64, parts: usize) -> Result<Vec<usize>, StorageError> {
+        let mut sizes = vec![0; parts];
+        for (thread_index, response_rx) in self.ask(0..self.threads.len(), |tx| HashCommand::CountParts(bound, parts, tx)) {
+            sizes.iter_mut().zip(answer(thread_index, response_rx)?).for_each(|(size, worker_size)| *size += worker_size);
+        }
+        Ok(sizes)
+    }
+
+    // The salted hashes numbered below `bound` in range `part` of `part_sizes`, in `LEAF_ORDER`, so the store can be
+    // listed in order holding one range at a time. Hashes added later are numbered from `bound` on and left out.
+    pub fn sorted_part(&self, bound: u64, parts: usize, part: usize) -> Result<Vec<Hash512>, StorageError> {
+        let mut salted_hashes = Vec::new();
+        for (thread_index, response_rx) in self.ask(0..self.threads.len(), |tx| HashCommand::GetPart(bound, parts, part, tx)) {
+            salted_hashes.extend(answer(thread_index, response_rx)?);
+        }
+        sort_leaves(&mut salted_hashes);
+        Ok(salted_hashes)
+    }
+
+    // Sequence number of a salted hash of `hash`, even if it was removed
+    pub fn stored_sequence(&self, hash: &Hash512, salted_hash: Hash512) -> Result<Option<u64>, StorageError> {
+        let mut sequence = None;
+        for (thread_index, response_rx) in self.ask(self.candidate_workers(hash, || salted_hash), |tx| HashCommand::GetStoredSequence(salted_hash, tx)) {
+            sequence = sequence.or(answer(thread_index, response_rx)?);
+        }
+        Ok(sequence)
+    }
+
+    // Check the buckets of every worker between its adds, see `HashStore::check_buckets`.
+    // Answers the hashes found in all of them and the problems, prefixed with their worker.
+    pub fn check_buckets(&self) -> Result<(usize, Vec<String>), StorageError> {
+        let mut hashes = 0;
+        let mut problems = Vec::new();
+        for (thread_index, response_rx) in self.ask(0..self.threads.len(), HashCommand::CheckBuckets) {
+            let (worker_hashes, worker_problems) = answer(thread_index, response_rx)?;
+            hashes += worker_hashes;
+            problems.extend(worker_problems.into_iter().map(|problem| format!("Worker {}: {}", thread_index, problem)));
+        }
+        Ok((hashes, problems))
+    }
+
+    // Rebuild the content of `to_array` at the time a snapshot with the given log lengths was taken,
+    // using only the leaf logs. Returns `None` if the store doesn't keep leaf logs.
+    pub fn rebuild_from_leaf_logs(&self, leaf_log_lengths: &[u64]) -> Option<io::Result<Vec<Hash512>>> {
+        let dir = self.leaf_log_dir.as_ref()?;
+        Some(read_leaf_logs(dir, leaf_log_lengths))
+    }
+
+    // Records `offset..offset + count` of the leaf log of one worker. Returns `None` if the store doesn't keep leaf logs.
+    pub fn read_leaf_log(&self, thread_index: usize, offset: u64, count: u64) -> Option<io::Result<Vec<Hash512>>> {
+        let dir = self.leaf_log_dir.as_ref()?;
+        Some(LeafLog::read_range(&LeafLog::path(dir, thread_index), offset, count))
+    }
+
+    // Add salted hashes read from the leaf log of the same worker of another store with the same salt,
+    // returning how many were new
+    pub fn add_replicated(&self, thread_index: usize, salted_hashes: Vec<Hash512>) -> Result<usize, StorageError> {
+        let (response_tx, response_rx) = channel();
+        let entries = salted_hashes.into_iter().map(|salted_hash| (salted_hash, None)).collect();
+        let _ = self.threads[thread_index].send(HashCommand::MergeSalted(entries, response_tx));
+        answer(thread_index, response_rx)
+    }
+
+    // Stop all workers after they processed every command sent before, flushing their leaf logs.
+    // The store can't be used anymore afterwards.
+    pub fn shutdown(&self) {
+        let acks: Vec<_> = self.threads.iter().map(|tx| {
+            let (response_tx, response_rx) = channel();
+            let _ = tx.send(HashCommand::Shutdown(response_tx));
+            response_rx
+        }).collect();
+        for ack in acks {
+            let _ = ack.recv();
+        }
+    }
+
+    // Add all hashes of `other` to this store with their metadata and return how many of them were new.
+    // Both stores need the same salt. With the same number of threads, each thread's content is merged into its
+    // counterpart, otherwise the hashes are assigned to this store's workers again. Only the salted hashes are
+    // stored, so hashes partitioned by their unsalted prefix can only go to a store with at most as many threads,
+    // whose worker is the one holding the top bits of the other store's worker index.
+    pub fn merge(&self, other: &Self) -> Result<usize, MergeError> {
+        if self.salt != other.salt {
+            return Err(MergeError::SaltMismatch);
+        }
+        if self.hasher.name() != other.hasher.name() {
+            return Err(MergeError::HasherMismatch);
+        }
+        if self.partitioning != other.partitioning {
+            return Err(MergeError::PartitioningMismatch);
+        }
+        if self.partitioning == Partitioning::UnsaltedPrefix && self.threads.len() > other.threads.len() {
+            return Err(MergeError::ShardCountMismatch);
+        }
+        let dropped_bits = other.threads.len().trailing_zeros().saturating_sub(self.threads.len().trailing_zeros());
+
+        let mut added = 0;
+        for (other_index, other_tx) in other.threads.iter().enumerate() {
+            let (entries_tx, entries_rx) = channel();
+            let _ = other_tx.send(HashCommand::GetEntries(entries_tx));
+            let entries = answer(other_index, entries_rx)?;
+
+            if self.partitioning == Partitioning::RoundRobin || (self.partitioning != Partitioning::UnsaltedPrefix && self.threads.len() != other.threads.len()) {
+                added += self.merge_reassigned(entries)?;
+                continue;
+            }
+            let thread_index = other_index >> dropped_bits;
+            let (response_tx, response_rx) = channel();
+            let _ = self.threads[thread_index].send(HashCommand::MergeSalted(entries, response_tx));
+            added += answer(thread_index, response_rx)?;
+        }
+        Ok(added)
+    }
+
+    // Merge salted hashes into the workers they are assigned to, for when a hash of one worker of the other
+    // store may belong to any worker of this one
+    fn merge_reassigned(&self, entries: Vec<(Hash512, Option<Arc<HashMetadata>>)>) -> Result<usize, StorageError> {
+        let salted_hashes: Vec<Hash512> = entries.iter().map(|(salted_hash, _)| *salted_hash).collect();
+        let (workers, next_worker) = self.assign_workers(&salted_hashes, &salted_hashes)?;
+        let mut partitions = vec![Vec::new(); self.threads.len()];
+        for (&thread_index, entry) in workers.iter().zip(entries) {
+            partitions[thread_index].push(entry);
+        }
+        let responses: Vec<_> = partitions.into_iter().zip(&self.threads).map(|(partition, tx)| {
+            let (response_tx, response_rx) = channel();
+            let _ = tx.send(HashCommand::MergeSalted(partition, response_tx));
+            response_rx
+        }).collect();
+        drop(next_worker);
+        responses.into_iter().enumerate().map(|(thread_index, response_rx)| answer(thread_index, response_rx)).sum()
+    }
+
+    // Add the salted hashes of a store with the same salt, e.g. read from a snapshot, and return how many of them
+    // were new. A store partitioned by unsalted prefix can't tell which worker they go to.
+    pub fn import_salted(&self, salted_hashes: Vec<Hash512>) -> Result<usize, MergeError> {
+        if self.partitioning == Partitioning::UnsaltedPrefix {
+            return Err(MergeError::UnsaltedPrefix);
+        }
+        Ok(self.merge_reassigned(salted_hashes.into_iter().map(|salted_hash| (salted_hash, None)).collect())?)
+    }
+}
+
+// Order of the leaves of every published tree, independent of how the hashes are spread over workers and buckets,
+// so stores with the same salted hashes publish the same root
+pub const LEAF_ORDER: &str = "ascending by salted hash, read as eight little-endian 64-bit words and compared word by word";
+
+// Which of `parts` equal ranges of the first word a salted hash is in. Leaves are compared by their first word
+// first, so the leaves of a range all come before those of the next one in `LEAF_ORDER`.
+fn leaf_part(salted_hash: &Hash512, parts: usize) -> usize {
+    ((salted_hash.0[0] as u128 * parts as u128) >> 64) as usize
+}
+
+// Put leaves into `LEAF_ORDER`. Leaves are unique, so an unstable sort gives the same order, and it sorts in place
+// where a stable one would allocate half of the leaves again.
+pub fn sort_leaves(leaves: &mut [Hash512]) {
+    leaves.sort_unstable();
+}
+
+// Merkle tree storing only real nodes, level by level from the leaves to the root.
+// A level with an odd number of nodes promotes its last node unchanged to the next level,
+// which gives the same shape as the unbalanced trees of RFC 6962.
+#[derive(Debug, Clone)]
+pub struct MerkleTree {
+    pub levels: Vec<Vec<Hash512>>,
+    pub salt: Hash512,
+    pub hasher: Arc<dyn Hasher>,
+    pub depth: usize,
+    pub leaf_count: usize,
+    // Position of every salted leaf, so proofs don't need to scan the leaves. Left empty for leaves in `LEAF_ORDER`,
+    // like those of every published tree, which are binary searched instead of being kept a second time.
+    pub leaf_index: HashMap<Hash512, usize>,
+    // Set once the lower levels were dropped from memory, see `prune`
+    pub pruned: Option<PrunedLeaves>,
+}
+
+// What is left of the leaves of a pruned tree
+#[derive(Debug, Clone)]
+pub enum PrunedLeaves {
+    // The levels below BLOCK_LEVELS are recomputed from the leaves on disk for every proof
+    OnDisk(Arc<LeafFile>),
+    // Only the root was kept, no more proofs can be made
+    Discarded,
+}
+
+impl MerkleTree {
+    pub fn new(data: Vec<Hash512>, salt: Hash512) -> Self {
+        Self::with_hasher(data, salt, Arc::new(Sha512Hasher))
+    }
+
+    // Tree over a snapshot of `storage` in `LEAF_ORDER`, with the leaf log lengths of the snapshot
+    pub fn from_storage<S: HashStorage + ?Sized>(storage: &S) -> Result<(Self, Vec<u64>), StorageError> {
+        let StoreSnapshot { mut hashes, leaf_log_lengths } = storage.snapshot()?;
+        sort_leaves(&mut hashes);
+        Ok((Self::with_hasher(hashes, *storage.salt(), Arc::clone(storage.hasher())), leaf_log_lengths))
+    }
+
+    // Tree over leaves salted with `hasher`, combining nodes with the same hasher
+    pub fn with_hasher(data: Vec<Hash512>, salt: Hash512, hasher: Arc<dyn Hasher>) -> Self {
+        let leaf_count = data.len();
+        if leaf_count == 0 {
+            return Self {
+                levels: vec![],
+                salt,
+                hasher,
+                depth: 0,
+                leaf_count: 0,
+                leaf_index: HashMap::new(),
+                pruned: None,
+            };
+        }
+
+        let mut leaf_index = HashMap::new();
+        if !data.is_sorted() {
+            leaf_index.reserve(leaf_count);
+            for (idx, leaf) in data.iter().enumerate() {
+                leaf_index.entry(*leaf).or_insert(idx);
+            }
+        }
+
+        // Build tree from bottom up, an odd last node is promoted unchanged so the shape is the
+        // RFC 6962 tree hash: split at the largest power of two below the number of leaves
+        let mut levels = vec![data];
+        while levels.last().unwrap().len() > 1 {
+            let level = levels.last().unwrap();
+            let parents = level
+                .chunks(2)
+                .map(|pair| match pair {
+                    [left, right] => hasher.node(left, right),
+                    [single] => *single,
+                    _ => unreachable!(),
+                })
+                .collect();
+            levels.push(parents);
+        }
+
+        Self {
+            depth: levels.len() - 1,
+            levels,
+            salt,
+            hasher,
+            leaf_count,
+            leaf_index,
+            pruned: None,
+        }
+    }
+
+    // Copy of this tree without its lower levels in memory. With `file`, the leaves are written to it and only the
+    // levels from BLOCK_LEVELS up are kept, so proofs read one block of leaves from disk. Without, only the root
+    // is kept and no proofs can be made anymore. Only trees over leaves in `LEAF_ORDER` can be pruned to disk.
+    pub fn prune(&self, file: Option<&Path>) -> io::Result<Self> {
+        if self.pruned.is_some() || self.leaf_count == 0 {
+            return Ok(self.clone());
+        }
+        let (pruned, kept_from) = match file {
+            Some(_) if !self.leaf_index.is_empty() => return Err(io::Error::new(io::ErrorKind::InvalidInput, "leaves are not in LEAF_ORDER")),
+            Some(path) => (PrunedLeaves::OnDisk(Arc::new(LeafFile::write(path, &self.levels[0])?)), BLOCK_LEVELS.min(self.depth)),
+            None => (PrunedLeaves::Discarded, self.depth),
+        };
+        let levels = self.levels.iter().enumerate()
+            .map(|(height, level)| if height < kept_from { Vec::new() } else { level.clone() })
+            .collect();
+        Ok(Self { levels, leaf_index: HashMap::new(), pruned: Some(pruned), ..self.clone() })
+    }
+
+    // The levels below the root a proof of the leaf at `index` passes through, each with the index of its first node.
+    // Levels a pruned tree dropped are recomputed from the block of leaves holding it, from its first node on.
+    fn proof_levels(&self, index: usize) -> Option<Vec<(usize, Cow<'_, [Hash512]>)>> {
+        let mut levels: Vec<(usize, Cow<[Hash512]>)> = self.levels[..self.depth].iter().map(|level| (0, Cow::Borrowed(level.as_slice()))).collect();
+        match &self.pruned {
+            None => {}
+            Some(PrunedLeaves::Discarded) => return None,
+            Some(PrunedLeaves::OnDisk(file)) => {
+                let block = index >> BLOCK_LEVELS;
+                let mut level = file.block(block).map_err(|e| eprintln!("Failed to read pruned leaves: {}", e)).ok()?;
+                for (height, (first, nodes)) in levels.iter_mut().enumerate().take(BLOCK_LEVELS) {
+                    let parents = level.chunks(2)
+                        .map(|pair| match pair {
+                            [left, right] => self.hasher.node(left, right),
+                            [single] => *single,
+                            _ => unreachable!(),
+                        })
+                        .collect();
+                    *first = block << (BLOCK_LEVELS - height);
+                    *nodes = Cow::Owned(std::mem::replace(&mut level, parents));
+                }
+            }
+        }
+        Some(levels)
+    }
+
+    // Proof of inclusion: the hash and salt, then the (left, right) children of every node on the
+    // RFC 6962 audit path. Levels where the node was promoted are skipped, no placeholder is hashed.
+    pub fn get(&self, hash: &Hash512) -> Option<Vec<(Hash512, Hash512)>> {
+        self.get_with_encoding(hash, LeafEncoding::default())
+    }
+
+    pub fn get_with_encoding(&self, hash: &Hash512, encoding: LeafEncoding) -> Option<Vec<(Hash512, Hash512)>> {
+        self.get_with_salt(hash, &self.salt, encoding)
+    }
+
+    // Proof for a leaf salted with something else than the tree salt, like the nonce of a blinded hash.
+    // The proof starts with `(hash, salt)`, so it verifies like any other.
+    pub fn get_with_salt(&self, hash: &Hash512, salt: &Hash512, encoding: LeafEncoding) -> Option<Vec<(Hash512, Hash512)>> {
+        let salted_hash = encoding.leaf_with_hasher(&*self.hasher, hash, salt);
+
+        let index = self.leaf_position(&salted_hash)?;
+
+        let mut proof = Vec::with_capacity(self.depth + 1);
+        proof.push((*hash, *salt));
+        proof.extend(self.proof_at(index)?);
+        Some(proof)
+    }
+
+    // Proofs of `hashes` in their order, each built only when the iterator reaches it, so proving many
+    // hashes doesn't hold all of their proofs at once
+    pub fn proofs<I: IntoIterator<Item = Hash512>>(&self, hashes: I, encoding: LeafEncoding) -> impl Iterator<Item = Option<Vec<(Hash512, Hash512)>>> {
+        hashes.into_iter().map(move |hash| self.get_with_encoding(&hash, encoding))
+    }
+
+    // The (left, right) pairs of a proof for the leaf at `index`, without the leading (hash, salt).
+    // Appended to a proof whose root is that leaf, they extend it up to the root of this tree.
+    pub fn proof_at(&self, index: usize) -> Option<Vec<(Hash512, Hash512)>> {
+        if index >= self.leaf_count {
+            return None;
+        }
+
+        // Generate proof path from leaf to root, promoted nodes have no sibling on their level
+        let mut pairs = Vec::with_capacity(self.depth);
+        let mut current_idx = index;
+        for (first, level) in self.proof_levels(index)? {
+            let left_child_idx = (current_idx & !1) - first;
+            if left_child_idx + 1 < level.len() {
+                pairs.push((level[left_child_idx], level[left_child_idx + 1]));
+            }
+            current_idx /= 2;
+        }
+        Some(pairs)
+    }
+
+    // Sibling hashes on the way from the leaf at `index` to the root, the audit path of RFC 6962 section 2.1.1.
+    // The pairs returned by `get` contain exactly these siblings next to the nodes on the path.
+    pub fn audit_path(&self, index: usize) -> Option<Vec<Hash512>> {
+        if index >= self.leaf_count {
+            return None;
+        }
+        let mut path = Vec::with_capacity(self.depth);
+        let mut current_idx = index;
+        for (first, level) in self.proof_levels(index)? {
+            let sibling_idx = (current_idx ^ 1) - first;
+            if sibling_idx < level.len() {
+                path.push(level[sibling_idx]);
+            }
+            current_idx /= 2;
+        }
+        Some(path)
+    }
+
+    // Verify an audit path for the (salted) leaf at `index` of a tree with `leaf_count` leaves,
+    // following the inclusion proof verification of RFC 9162 section 2.1.3.2
+    pub fn verify_audit_path(leaf: &Hash512, index: usize, leaf_count: usize, path: &[Hash512], root: &Hash512, hasher: &dyn Hasher) -> bool {
+        verify::verify_audit_path(leaf, index, leaf_count, path, root, hasher)
+    }
+
+    // Check a proof as returned by `get`: the first entry is (hash, salt), every following entry is a
+    // pair of siblings one level closer to the root, one of which is the hash of the previous entry
+    pub fn verify_proof(hash: &Hash512, proof: &[(Hash512, Hash512)], root: &Hash512) -> bool {
+        Self::verify_proof_with_encoding(hash, proof, root, LeafEncoding::default())
+    }
+
+    pub fn verify_proof_with_encoding(hash: &Hash512, proof: &[(Hash512, Hash512)], root: &Hash512, encoding: LeafEncoding) -> bool {
+        Self::verify_proof_with_hasher(hash, proof, root, encoding, &Sha512Hasher)
+    }
+
+    pub fn verify_proof_with_hasher(hash: &Hash512, proof: &[(Hash512, Hash512)], root: &Hash512, encoding: LeafEncoding, hasher: &dyn Hasher) -> bool {
+        verify::verify_proof_with_hasher(hash, proof, root, encoding, hasher)
+    }
+
+    // Index of the first leaf equal to `leaf`
+    pub fn leaf_position(&self, leaf: &Hash512) -> Option<usize> {
+        match &self.pruned {
+            Some(PrunedLeaves::OnDisk(file)) => return file.position(leaf).unwrap_or_else(|e| {
+                eprintln!("Failed to read pruned leaves: {}", e);
+                None
+            }),
+            Some(PrunedLeaves::Discarded) => return None,
+            None => {}
+        }
+        if !self.leaf_index.is_empty() {
+            return self.leaf_index.get(leaf).copied();
+        }
+        let leaves = self.levels.first()?;
+        let index = leaves.partition_point(|other| other < leaf);
+        (leaves.get(index) == Some(leaf)).then_some(index)
+    }
+
+    // Heap bytes of the levels and the leaf index, or the fence pointers of pruned leaves
+    pub fn memory_usage(&self) -> usize {
+        let levels: usize = self.levels.iter().map(|level| level.capacity() * size_of::<Hash512>()).sum();
+        let fences = match &self.pruned {
+            Some(PrunedLeaves::OnDisk(file)) => file.memory_usage(),
+            _ => 0,
+        };
+        levels + self.leaf_index.capacity() * size_of::<(Hash512, usize)>() + fences
+    }
+
+    pub fn root(&self) -> Option<Hash512> {
+        self.levels.last().map(|level| level[0])
+    }
+
+    // Number of stored nodes, a promoted node is counted on every level it appears on
+    pub fn size(&self) -> usize {
+        self.levels.iter().map(Vec::len).sum()
+    }
+}
+
+// Serialized form of a merkle tree: the leaves and what rebuilding the levels above them takes. The root is only
+// there to be checked against the rebuilt one.
+#[derive(Serialize, Deserialize)]
+struct MerkleTreeData<'a> {
+    tree_hasher: Cow<'a, str>,
+    salt: Hash512,
+    root: Option<Hash512>,
+    leaves: Cow<'a, [Hash512]>,
+}
+
+// Trees with pruned leaves can't be serialized
+impl Serialize for MerkleTree {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if self.pruned.is_some() {
+            return Err(serde::ser::Error::custom("the leaves of a pruned tree aren't in memory"));
+        }
+        MerkleTreeData {
+            tree_hasher: Cow::Borrowed(self.hasher.name()),
+            salt: self.salt,
+            root: self.root(),
+            leaves: Cow::Borrowed(self.levels.first().map_or(&[], Vec::as_slice)),
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for MerkleTree {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let data = MerkleTreeData::deserialize(deserializer)?;
+        let hasher = hasher_from_name(&data.tree_hasher)
+            .ok_or_else(|| serde::de::Error::custom(format!("unknown tree hasher {}", data.tree_hasher)))?;
+        let tree = Self::with_hasher(data.leaves.into_owned(), data.salt, hasher);
+        if tree.root() != data.root {
+            return Err(serde::de::Error::custom("the root doesn't match the leaves"));
+        }
+        Ok(tree)
+    }
+}
+
+// Merkle mountain range: perfect trees over consecutive leaves, stored in post-order, so appending a
+// leaf only adds it and the parents it completes instead of rebuilding the tree. Nodes never change
+// once appended, so the range at any earlier leaf count is a prefix of it and proofs against earlier
+// roots can still be made. Bagging the peaks from right to left gives the same root and proofs as a
+// `MerkleTree` over the leaves in append order. Leaves are appended in the order of their sequence
+// numbers, which find the leaf of a stored hash without indexing the leaves again.
+#[derive(Debug, Clone)]
+pub struct MerkleMountainRange {
+    nodes: Vec<Hash512>,
+    pub salt: Hash512,
+    pub hasher: Arc<dyn Hasher>,
+    leaf_count: usize,
+    // Sequence number of every leaf, increasing
+    sequences: Vec<u64>,
+}
+
+impl MerkleMountainRange {
+    pub fn new(salt: Hash512, hasher: Arc<dyn Hasher>) -> Self {
+        Self { nodes: Vec::new(), salt, hasher, leaf_count: 0, sequences: Vec::new() }
+    }
+
+    // Append a salted leaf with its sequence number and return its index.
+    // Panics unless the number is above those of the leaves appended before.
+    pub fn append(&mut self, leaf: Hash512, sequence: u64) -> usize {
+        assert!(self.sequences.last().is_none_or(|&last| last < sequence), "leaves have to be appended in sequence order");
+        self.sequences.push(sequence);
+        let index = self.leaf_count;
+        self.nodes.push(leaf);
+        // Every trailing one bit of the index is a mountain of the same height to merge with
+        let mut height = 0;
+        while (index >> height) & 1 == 1 {
+            let right = self.nodes.len() - 1;
+            let left = right + 1 - (2 << height);
+            let parent = self.hasher.node(&self.nodes[left], &self.nodes[right]);
+            self.nodes.push(parent);
+            height += 1;
+        }
+        self.leaf_count += 1;
+        index
+    }
+
+    pub fn leaf_count(&self) -> usize {
+        self.leaf_count
+    }
+
+    // Index of the leaf appended with `sequence`
+    pub fn index_of(&self, sequence: u64) -> Option<usize> {
+        self.sequences.binary_search(&sequence).ok()
+    }
+
+    // Leaf at `index`. A mountain over 2^h leaves has 2^(h+1) - 1 nodes, so the leaves before it take
+    // twice their number minus one node per mountain, i.e. per one bit of `index`.
+    fn leaf(&self, index: usize) -> Option<Hash512> {
+        (index < self.leaf_count).then(|| self.nodes[2 * index - index.count_ones() as usize])
+    }
+
+    // Positions and heights of the peaks of the range when it had `leaf_count` leaves, from left to right
+    fn peaks(leaf_count: usize) -> Vec<(usize, usize)> {
+        let mut peaks = Vec::new();
+        let mut offset = 0;
+        for height in (0..usize::BITS as usize).rev() {
+            if (leaf_count >> height) & 1 == 1 {
+                offset += (2 << height) - 1;
+                peaks.push((offset - 1, height));
+            }
+        }
+        peaks
+    }
+
+    fn bag(&self, peaks: &[Hash512]) -> Option<Hash512> {
+        peaks.iter().rev().copied().reduce(|right, left| self.hasher.node(&left, &right))
+    }
+
+    pub fn root(&self) -> Option<Hash512> {
+        self.root_at(self.leaf_count)
+    }
+
+    // Root of the range when it had `leaf_count` leaves
+    pub fn root_at(&self, leaf_count: usize) -> Option<Hash512> {
+        if leaf_count > self.leaf_count {
+            return None;
+        }
+        let peaks: Vec<Hash512> = Self::peaks(leaf_count).iter().map(|&(position, _)| self.nodes[position]).collect();
+        self.bag(&peaks)
+    }
+
+    // Proof for a salted leaf appended with `sequence`, in the range when it had `leaf_count` leaves, in the format of
+    // `MerkleTree::get_with_salt`. `None` if the leaf appended with that number is another one.
+    pub fn get_with_salt(&self, hash: &Hash512, salt: &Hash512, encoding: LeafEncoding, sequence: u64, leaf_count: usize) -> Option<Vec<(Hash512, Hash512)>> {
+        let index = self.index_of(sequence)?;
+        if self.leaf(index)? != encoding.leaf_with_hasher(&*self.hasher, hash, salt) {
+            return None;
+        }
+        let mut proof = vec![(*hash, *salt)];
+        proof.extend(self.proof_at(index, leaf_count)?);
+        Some(proof)
+    }
+
+    // The (left, right) pairs from the leaf at `index` up to its peak, then through the bagged peaks
+    // up to the root of the range when it had `leaf_count` leaves
+    pub fn proof_at(&self, index: usize, leaf_count: usize) -> Option<Vec<(Hash512, Hash512)>> {
+        if index >= leaf_count || leaf_count > self.leaf_count {
+            return None;
+        }
+        let peaks = Self::peaks(leaf_count);
+        let mut pairs = Vec::new();
+        let mut mountain = 0;
+        let mut first_leaf = 0;
+        for (peak_index, &(peak, height)) in peaks.iter().enumerate() {
+            if index >= first_leaf + (1 << height) {
+                first_leaf += 1 << height;
+                continue;
+            }
+            // Walk down from the peak, then reverse the children into leaf to peak order
+            let (mut node, local) = (peak, index - first_leaf);
+            for level in (0..height).rev() {
+                let (left, right) = (node - (2 << level), node - 1);
+                pairs.push((self.nodes[left], self.nodes[right]));
+                node = if (local >> level) & 1 == 1 { right } else { left };
+            }
+            pairs.reverse();
+            mountain = peak_index;
+            break;
+        }
+
+        let peaks: Vec<Hash512> = peaks.iter().map(|&(position, _)| self.nodes[position]).collect();
+        if let Some(right) = self.bag(&peaks[mountain + 1..]) {
+            pairs.push((peaks[mountain], right));
+        }
+        let mut current = self.bag(&peaks[mountain..]).unwrap();
+        for left in peaks[..mountain].iter().rev() {
+            pairs.push((*left, current));
+            current = self.hasher.node(left, &current);
+        }
+        Some(pairs)
+    }
+
+    // Heap bytes of the nodes and the sequence numbers
+    pub fn memory_usage(&self) -> usize {
+        self.nodes.capacity() * size_of::<Hash512>() + self.sequences.capacity() * size_of::<u64>()
+    }
+
+    // Number of stored nodes
+    pub fn size(&self) -> usize {
+        self.nodes.len()
+    }
+}
+
+// Summary of a published merkle tree, sent to subscribers after every tree update
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TreeHead {
+    pub version: u64,
+    pub root: Option<Hash512>,
+    pub tree_size: usize,
+    pub leaf_count: usize,
+    pub timestamp: u64,
+}
+
+// Summary of a single merkle tree build. The epoch doubles as the version of the published tree.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct EpochSummary {
+    pub epoch: u64,
+    pub timestamp: u64,
+    pub leaf_count: usize,
+    pub new_hashes: usize,
+    pub build_duration_ms: u64,
+    // Fields below are missing in logs written before roots were recorded
+    #[serde(default)]
+    #[schema(value_type = Option<String>)]
+    pub root: Option<Hash512>,
+    #[serde(default)]
+    pub tree_size: usize,
+    // Transaction that anchored the root externally, e.g. on a blockchain
+    #[serde(default)]
+    pub anchor_txid: Option<String>,
+    // Signature of the server over the tree head, see `receipt::tree_head_message`
+    #[serde(default)]
+    pub signature: Option<Vec<u8>>,
+    // Where an external transparency log recorded the signed tree head
+    #[serde(default)]
+    pub transparency_log: Option<TransparencyLogEntry>,
+    // How the timestamp was obtained, its summary is part of the signed tree head. Missing in replicas,
+    // which take the timestamp of their primary.
+    #[serde(default)]
+    pub time_source: Option<TimeAttestation>,
+    // Records of every worker's leaf log the tree was built from, so it can be rebuilt offline, see `read_leaf_logs`.
+    // Empty in logs written before they were recorded, all zero for stores without leaf logs.
+    #[serde(default)]
+    pub leaf_log_lengths: Vec<u64>,
+    // Every hash with a lower sequence number was stored before the tree was built, so it is one of its leaves.
+    // Missing in logs written before it was recorded.
+    #[serde(default)]
+    pub sequence_bound: Option<u64>,
+    // Cosignatures of peers over the signed tree head, at most one per peer, see `receipt::Cosignature`
+    #[serde(default)]
+    pub cosignatures: Vec<Cosignature>,
+}
+
+// Entry of a signed tree head in a transparency log with the API of Sigstore's Rekor, third-party
+// evidence that the root existed when the log integrated it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct TransparencyLogEntry {
+    pub log_url: String,
+    pub uuid: String,
+    pub log_index: u64,
+    pub integrated_time: u64,
+    pub log_id: String,
+    // Signature of the log over the entry, base64 encoded
+    pub signed_entry_timestamp: Option<String>,
+    // Proof of the entry in the log's own tree, in the format of the log
+    #[schema(value_type = Option<Object>)]
+    pub inclusion_proof: Option<serde_json::Value>,
+}
+
+// History of all tree builds, optionally persisted as one JSON object per line.
+// A line for an epoch that was already read amends it.
+#[derive(Debug, Default)]
+pub struct EpochLog {
+    summaries: Vec<EpochSummary>,
+    file: Option<File>,
+    // Summaries appended or amended since the log was opened and the time of the last one, for HTTP validators
+    revision: u64,
+    modified_at: Option<u64>,
+}
+
+impl EpochLog {
+    pub fn in_memory() -> Self {
+        Self::default()
+    }
+
+    // Load the summaries stored at `path` and append new ones to it. New epochs are numbered by the number of
+    // summaries, so the stored ones have to be consecutive from 0.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        Self::load(path, false)
+    }
+
+    // Like `open`, for a replica. Its epochs are the versions of its primary, which it may have started following
+    // later and skips when it falls behind, so they only have to increase.
+    pub fn open_replica(path: &Path) -> io::Result<Self> {
+        Self::load(path, true)
+    }
+
+    fn load(path: &Path, gaps: bool) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).read(true).append(true).open(path)?;
+        let mut summaries: Vec<EpochSummary> = Vec::new();
+        for (number, line) in BufReader::new(&file).lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let summary: EpochSummary = serde_json::from_str(&line).map_err(io::Error::other)?;
+            if let Some(index) = position(&summaries, summary.epoch) {
+                summaries[index] = summary;
+                continue;
+            }
+            let expected = summaries.last().map_or(0, |last| last.epoch + 1);
+            let in_order = match summaries.last() {
+                Some(last) if gaps => summary.epoch > last.epoch,
+                None if gaps => true,
+                _ => summary.epoch == expected,
+            };
+            if !in_order {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+                    "{} line {}: epoch {} follows epoch {}, expected {}{}",
+                    path.display(), number + 1, summary.epoch,
+                    summaries.last().map_or("none".to_string(), |last| last.epoch.to_string()),
+                    expected, if gaps { " or later" } else { "" },
+                )));
+            }
+            summaries.push(summary);
+        }
+        Ok(Self { summaries, file: Some(file), ..Self::default() })
+    }
+
+    // A summary for an epoch that exists already replaces it, like amended lines do in `open`
+    pub fn append(&mut self, summary: EpochSummary) -> io::Result<()> {
+        self.write(&summary)?;
+        match position(&self.summaries, summary.epoch) {
+            Some(index) => self.summaries[index] = summary,
+            None => self.summaries.push(summary),
+        }
+        Ok(())
+    }
+
+    // Record the transaction anchoring the root of `epoch`. Returns false if the epoch doesn't exist.
+    pub fn set_anchor(&mut self, epoch: u64, txid: String) -> io::Result<bool> {
+        let Some(mut summary) = self.get(epoch).cloned() else {
+            return Ok(false);
+        };
+        summary.anchor_txid = Some(txid);
+        self.write(&summary)?;
+        let index = position(&self.summaries, epoch).unwrap();
+        self.summaries[index] = summary;
+        Ok(true)
+    }
+
+    // Record where a transparency log included the tree head of `epoch`. Returns false if the epoch doesn't exist.
+    pub fn set_transparency_log(&mut self, epoch: u64, entry: TransparencyLogEntry) -> io::Result<bool> {
+        let Some(mut summary) = self.get(epoch).cloned() else {
+            return Ok(false);
+        };
+        summary.transparency_log = Some(entry);
+        self.write(&summary)?;
+        let index = position(&self.summaries, epoch).unwrap();
+        self.summaries[index] = summary;
+        Ok(true)
+    }
+
+    // Record a peer's cosignature of the tree head of `epoch`, replacing an earlier one of the same peer.
+    // Returns false if the epoch doesn't exist.
+    pub fn add_cosignature(&mut self, epoch: u64, cosignature: Cosignature) -> io::Result<bool> {
+        let Some(mut summary) = self.get(epoch).cloned() else {
+            return Ok(false);
+        };
+        summary.cosignatures.retain(|existing| existing.public_key != cosignature.public_key);
+        summary.cosignatures.push(cosignature);
+        self.write(&summary)?;
+        let index = position(&self.summaries, epoch).unwrap();
+        self.summaries[index] = summary;
+        Ok(true)
+    }
+
+    fn write(&mut self, summary: &EpochSummary) -> io::Result<()> {
+        if let Some(file) = &mut self.file {
+            let mut line = serde_json::to_string(summary).map_err(io::Error::other)?;
+            line.push('\n');
+            file.write_all(line.as_bytes())?;
+        }
+        self.revision += 1;
+        self.modified_at = Some(unix_timestamp(SystemTime::now()));
+        Ok(())
+    }
+
+    pub fn revision(&self) -> u64 {
+        self.revision
+    }
+
+    // `None` if nothing changed since the log was opened
+    pub fn modified_at(&self) -> Option<u64> {
+        self.modified_at
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        match &mut self.file {
+            Some(file) => file.sync_all(),
+            None => Ok(()),
+        }
+    }
+
+    pub fn latest(&self) -> Option<&EpochSummary> {
+        self.summaries.last()
+    }
+
+    pub fn get(&self, epoch: u64) -> Option<&EpochSummary> {
+        self.summaries.get(position(&self.summaries, epoch)?)
+    }
+
+    // Summaries in epoch order, starting at `offset` and containing at most `limit` entries
+    pub fn page(&self, offset: usize, limit: usize) -> &[EpochSummary] {
+        let start = offset.min(self.summaries.len());
+        let end = start.saturating_add(limit).min(self.summaries.len());
+        &self.summaries[start..end]
+    }
+
+    pub fn len(&self) -> usize {
+        self.summaries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.summaries.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum AdminActionKind {
+    Remove,
+    RemoveAll,
+    // Hashes of a namespace removed once its retention ended, recorded by the service itself
+    Expire,
+    // Hashes moved to a store with a new salt, see `TimestampingService::resalt`
+    Resalt,
+}
+
+// Administrative change of the store, as recorded in the `AdminLog`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct AdminAction {
+    pub timestamp: u64,
+    pub action: AdminActionKind,
+    // Salted hash targeted by `Remove`, the submitted hash itself is not recorded
+    #[schema(value_type = Option<String>)]
+    pub leaf: Option<Hash512>,
+    pub removed: usize,
+    // Latest tree when the action was taken. Its proofs and those of older trees stay valid.
+    pub tree_version: Option<u64>,
+    pub reason: Option<String>,
+}
+
+// Append-only record of administrative actions, optionally persisted as one JSON object per line
+#[derive(Debug, Default)]
+pub struct AdminLog {
+    actions: Vec<AdminAction>,
+    file: Option<File>,
+}
+
+impl AdminLog {
+    pub fn in_memory() -> Self {
+        Self::default()
+    }
+
+    // Load the actions stored at `path` and append new ones to it
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).read(true).append(true).open(path)?;
+        let mut actions = Vec::new();
+        for line in BufReader::new(&file).lines() {
+            let line = line?;
+            if !line.trim().is_empty() {
+                actions.push(serde_json::from_str(&line).map_err(io::Error::other)?);
+            }
+        }
+        Ok(Self { actions, file: Some(file) })
+    }
+
+    // Actions are synced to disk before they are added
+    pub fn append(&mut self, action: AdminAction) -> io::Result<()> {
+        if let Some(file) = &mut self.file {
+            let mut line = serde_json::to_string(&action).map_err(io::Error::other)?;
+            line.push('\n');
+            file.write_all(line.as_bytes())?;
+            file.sync_all()?;
+        }
+        self.actions.push(action);
+        Ok(())
+    }
+
+    pub fn actions(&self) -> &[AdminAction] {
+        &self.actions
+    }
+}
+
+// Result of rebuilding the published merkle tree from the leaf logs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+pub struct AuditReport {
+    pub audited_at: u64,
+    pub tree_timestamp: u64,
+    pub leaf_count: usize,
+    pub matches: bool,
+}
+
+// Result of checking the invariants of a store, see `TimestampingService::verify_store`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, ToSchema)]
+pub struct StoreCheck {
+    pub checked_at: u64,
+    // Hashes found in the buckets, spilled maps and cold indexes of all workers
+    pub hashes: usize,
+    // Version of the tree that was rebuilt, `None` if there is none or it couldn't be rebuilt
+    pub tree_version: Option<u64>,
+    pub root_matches: Option<bool>,
+    // Everything that is wrong, empty for a consistent store
+    pub discrepancies: Vec<String>,
+}
+
+impl StoreCheck {
+    pub fn is_consistent(&self) -> bool {
+        self.discrepancies.is_empty()
+    }
+}
+
+// Merkle proof with every hash as bytes, as returned by the HTTP API
+pub type ProofBytes = Vec<(Vec<u8>, Vec<u8>)>;
+
+// Signed `SaltMigration` in the directory of a re-salted store
+pub const SALT_MIGRATION_FILE: &str = "salt-migration.json";
+
+// Number of tree heads buffered per subscriber before slow subscribers start skipping updates
+const TREE_UPDATE_CHANNEL_CAPACITY: usize = 16;
+
+// Publishes trees of the hashes in a store, by default a `MultiThreadedHashStore`. Any other `HashStorage` can be
+// published from too, the configuration and administration of the store are only there for the default one.
+#[derive(Debug)]
+pub struct TimestampingService<const INDEX_SIZE: usize, const PREFIX_SIZE: usize, S = MultiThreadedHashStore<INDEX_SIZE, PREFIX_SIZE>> {
+    pub hash_store: Arc<S>,
+    // Swapped as a whole when a tree is published, see `PublishedTree`
+    published: Arc<ArcSwapOption<PublishedTree>>,
+    pub epochs: Arc<RwLock<EpochLog>>,
+    pub last_audit: Arc<RwLock<Option<AuditReport>>>,
+    pub audit_divergences: Arc<RwLock<usize>>,
+    // Replicated trees whose root differed from the one the primary published
+    pub replication_divergences: Arc<RwLock<usize>>,
+    // Recorded by the server around the add, check and update-tree handlers
+    pub latencies: RequestLatencies,
+    // Trees published before the current one, newest first
+    previous_trees: Arc<RwLock<VecDeque<Arc<PublishedTree>>>>,
+    retained_trees: usize,
+    tree_updates: broadcast::Sender<TreeHead>,
+    signing_key: Option<Arc<SigningKey>>,
+    admin_log: Arc<RwLock<AdminLog>>,
+    pub root_log: Arc<RwLock<RootLog>>,
+    // Appended to instead of rebuilding the tree, see `with_mountain_range`
+    mountain_range: Option<Arc<RwLock<PublishedRange>>>,
+    time_source: Arc<dyn TimeSource>,
+    cold_after_epochs: Option<u64>,
+    // How published trees drop their leaves and whether they wait for an anchor first, see `with_leaf_pruning`
+    leaf_pruning: Option<(LeafPruning, bool)>,
+    rotate_bucket_salts: bool,
+    proof_cache: Option<Arc<ProofCache>>,
+    // Seconds hashes of a namespace are kept, see `with_retention`
+    retention: Arc<HashMap<String, u64>>,
+}
+
+#[derive(Debug)]
+struct PublishedRange {
+    range: MerkleMountainRange,
+    // Version and leaf count of every tree head published from the range, by version
+    versions: Vec<(u64, usize)>,
+    // Hashes numbered below it are in the range, the `sequence_bound` of the last version published from it
+    sequence_bound: u64,
+}
+
+// Tree that proofs of a version are taken from
+#[derive(Clone, Copy)]
+enum TreeView<'a> {
+    Tree(&'a MerkleTree),
+    // With the leaf count of the version and a lookup of the sequence number of a hash and its salted leaf
+    Range(&'a MerkleMountainRange, usize, &'a dyn Fn(&Hash512, Hash512) -> Option<u64>),
+}
+
+impl TreeView<'_> {
+    fn get_with_salt(&self, hash: &Hash512, salt: Option<&Hash512>, encoding: LeafEncoding) -> Option<Vec<(Hash512, Hash512)>> {
+        match self {
+            TreeView::Tree(tree) => tree.get_with_salt(hash, salt.unwrap_or(&tree.salt), encoding),
+            TreeView::Range(range, leaf_count, sequence) => {
+                let salt = salt.unwrap_or(&range.salt);
+                let sequence = sequence(hash, encoding.leaf_with_hasher(&*range.hasher, hash, salt))?;
+                range.get_with_salt(hash, salt, encoding, sequence, *leaf_count)
+            }
+        }
+    }
+
+    fn leaf_count(&self) -> usize {
+        match self {
+            TreeView::Tree(tree) => tree.leaf_count,
+            TreeView::Range(_, leaf_count, _) => *leaf_count,
+        }
+    }
+}
+
+// Index of `epoch` in summaries sorted by epoch. Epochs are consecutive from 0,
+// except in replicas that started following their primary later.
+fn position(summaries: &[EpochSummary], epoch: u64) -> Option<usize> {
+    summaries.binary_search_by_key(&epoch, |summary| summary.epoch).ok()
+}
+
+// What published trees do with their leaves once they are pruned, see `TimestampingService::with_leaf_pruning`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LeafPruning {
+    // Keep them in a file next to the leaf logs, proofs read them from there
+    Disk,
+    // Drop them and keep only the root, for deployments whose clients fetch their receipts right after publication
+    Discard,
+}
+
+// A published tree with its head, the leaf log lengths of every worker it was built from and the time it was
+// published. Publishing swaps in a new one without waiting for readers, and readers that loaded one keep a
+// consistent root, size, timestamp and proofs while newer trees are published.
+#[derive(Debug)]
+pub struct PublishedTree {
+    // `None` if trees are published from a mountain range
+    pub tree: Option<MerkleTree>,
+    pub head: TreeHead,
+    pub leaf_log_lengths: Vec<u64>,
+    pub updated_at: u64,
+}
+
+fn unix_timestamp(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+impl<const INDEX_SIZE: usize, const PREFIX_SIZE: usize, S> Clone for TimestampingService<INDEX_SIZE, PREFIX_SIZE, S> {
+    fn clone(&self) -> Self {
+        Self {
+            hash_store: Arc::clone(&self.hash_store),
+            published: Arc::clone(&self.published),
+            epochs: Arc::clone(&self.epochs),
+            last_audit: Arc::clone(&self.last_audit),
+            audit_divergences: Arc::clone(&self.audit_divergences),
+            replication_divergences: Arc::clone(&self.replication_divergences),
+            latencies: self.latencies.clone(),
+            previous_trees: Arc::clone(&self.previous_trees),
+            retained_trees: self.retained_trees,
+            tree_updates: self.tree_updates.clone(),
+            signing_key: self.signing_key.clone(),
+            admin_log: Arc::clone(&self.admin_log),
+            root_log: Arc::clone(&self.root_log),
+            mountain_range: self.mountain_range.clone(),
+            time_source: Arc::clone(&self.time_source),
+            cold_after_epochs: self.cold_after_epochs,
+            leaf_pruning: self.leaf_pruning,
+            rotate_bucket_salts: self.rotate_bucket_salts,
+            proof_cache: self.proof_cache.clone(),
+            retention: Arc::clone(&self.retention),
+        }
+    }
+}
+
+impl<const INDEX_SIZE: usize, const PREFIX_SIZE: usize> TimestampingService<INDEX_SIZE, PREFIX_SIZE> {
+    pub fn with_threads(num_threads: usize) -> Self {
+        Self::from_store(MultiThreadedHashStore::new(num_threads, random_salt()))
+    }
+
+    // Keep leaf logs in `dir`, which allows auditing published trees with `audit_published_tree`
+    pub fn with_leaf_logs(num_threads: usize, dir: &Path) -> io::Result<Self> {
+        Ok(Self::from_store(MultiThreadedHashStore::with_leaf_logs(num_threads, random_salt(), dir)?))
+    }
+
+    // Salt leaves and build trees with `hasher` instead of SHA-512, optionally keeping leaf logs in `leaf_log_dir`
+    pub fn with_hasher(num_threads: usize, leaf_log_dir: Option<&Path>, hasher: Arc<dyn Hasher>) -> io::Result<Self> {
+        Ok(Self::from_store(MultiThreadedHashStore::with_hasher(num_threads, random_salt(), leaf_log_dir, hasher)?))
+    }
+
+    // Keep the store in `dir` across restarts: the salt is saved in its `StoreConfig` on first start,
+    // and later starts reuse it and restore the hashes from the leaf logs
+    pub fn open(num_threads: usize, dir: &Path, hasher: Arc<dyn Hasher>) -> io::Result<Self> {
+        Self::open_partitioned(num_threads, dir, hasher, Partitioning::default(), INDEX_SIZE)
+    }
+
+    // Like `open`, spreading the hashes over the workers by `partitioning`, with tables starting at `index_size` bits
+    pub fn open_partitioned(num_threads: usize, dir: &Path, hasher: Arc<dyn Hasher>, partitioning: Partitioning, index_size: usize) -> io::Result<Self> {
+        let config = StoreConfig::load_or_create(dir, num_threads, &*hasher, partitioning, None)?;
+        let store = MultiThreadedHashStore::open_with_index_size(num_threads, config.salt, dir, hasher, index_size)?;
+        Ok(Self::from_store(store.with_partitioning(partitioning)))
+    }
+
+    // Like `open_partitioned`, for a replica that has to use the salt and partitioning of its primary
+    pub fn open_replica(num_threads: usize, dir: &Path, hasher: Arc<dyn Hasher>, partitioning: Partitioning, salt: Hash512, index_size: usize) -> io::Result<Self> {
+        StoreConfig::load_or_create(dir, num_threads, &*hasher, partitioning, Some(salt))?;
+        let store = MultiThreadedHashStore::open_with_index_size(num_threads, salt, dir, hasher, index_size)?;
+        Ok(Self::from_store(store.with_partitioning(partitioning)))
+    }
+
+    // Remove a hash from the store, see `HashStore::remove_with_encoding`, and record it in the admin log.
+    // Hashes added with `add_blinded` are removed with their nonce. `ServiceError::AdminLog` means the hash was
+    // removed, but the action couldn't be recorded.
+    pub fn remove_hash(&self, hash: &Hash512, nonce: Option<&Hash512>, encoding: LeafEncoding, reason: Option<String>) -> Result<bool, ServiceError> {
+        let store = &self.hash_store;
+        let (removed, leaf) = match nonce {
+            Some(nonce) => (store.remove_blinded(hash, nonce, encoding)?, encoding.leaf_with_hasher(&**store.hasher(), hash, nonce)),
+            None => (store.remove_with_encoding(hash, encoding)?, encoding.leaf_with_hasher(&**store.hasher(), hash, store.salt())),
+        };
+        if removed {
+            self.record_admin_action(AdminActionKind::Remove, Some(leaf), 1, reason)?;
+        }
+        Ok(removed)
+    }
+
+    // Remove every stored hash and record it in the admin log
+    pub fn remove_all_hashes(&self, reason: Option<String>) -> Result<usize, ServiceError> {
+        let removed = self.hash_store.remove_all()?;
+        self.record_admin_action(AdminActionKind::RemoveAll, None, removed, reason)?;
+        Ok(removed)
+    }
+
+    // Move `hashes` to a new store in `dir` with a new random salt and publish its first tree, for when the salt is
+    // suspected compromised. Only salted hashes are stored, so the submitted hashes have to be given again; any
+    // that aren't stored under the current salt are refused, so the new tree only has hashes the old store had.
+    // Returns the signed link from the current tree to the new one, which is also saved in `dir`. This service keeps
+    // its salt, the server switches to the new store when it is restarted on `dir`.
+    pub fn resalt(&self, hashes: &[Hash512], encoding: LeafEncoding, dir: &Path, reason: Option<String>) -> Result<SaltMigration, ServiceError> {
+        let key = self.signing_key.as_ref().ok_or(ServiceError::ResaltRefused("no signing key to sign the migration"))?;
+        let published = self.published_tree().filter(|published| published.head.root.is_some())
+            .ok_or(ServiceError::ResaltRefused("no tree published yet"))?;
+        if hashes.is_empty() {
+            return Err(ServiceError::ResaltRefused("no hashes given"));
+        }
+        if StoreConfig::path(dir).exists() {
+            return Err(ServiceError::ResaltRefused("the directory already has a store"));
+        }
+        let unknown = self.hash_store.contains_batch_with_encoding(hashes, encoding)?.into_iter().filter(|&stored| !stored).count();
+        if unknown > 0 {
+            return Err(ServiceError::UnknownHashes(unknown));
+        }
+
+        let store = &self.hash_store;
+        let hasher = Arc::clone(store.hasher());
+        let salt = random_salt();
+        std::fs::create_dir_all(dir).map_err(ServiceError::Resalt)?;
+        StoreConfig::load_or_create(dir, store.num_threads(), &*hasher, store.partitioning(), Some(salt)).map_err(ServiceError::Resalt)?;
+        let mut resalted = Self::from_store(
+            MultiThreadedHashStore::open_with_index_size(store.num_threads(), salt, dir, hasher, store.initial_index_size())
+                .map_err(ServiceError::Resalt)?
+                .with_partitioning(store.partitioning())
+        );
+        if self.uses_mountain_range() {
+            resalted = resalted.with_mountain_range();
+        }
+        let added = resalted.hash_store.add_batch_with_encoding(hashes, encoding);
+        let resalted_head = resalted.update_merkle_tree();
+        resalted.close();
+        let carried = added?.into_iter().filter(|&is_new| is_new).count();
+        resalted_head?;
+        let new_head = resalted.get_published_head().unwrap().0;
+
+        let live_hashes = store.len() - store.stats().tombstones;
+        let migration = SaltMigration {
+            tree_hasher: store.hasher().name().to_string(),
+            old_version: published.head.version,
+            old_leaf_count: published.head.leaf_count as u64,
+            old_root: published.head.root.unwrap(),
+            new_salt: salt,
+            new_leaf_count: new_head.leaf_count as u64,
+            new_root: new_head.root.unwrap(),
+            missing_hashes: live_hashes.saturating_sub(carried) as u64,
+            timestamp: unix_timestamp(SystemTime::now()),
+            signature: Vec::new(),
+        }.sign(key);
+        let json = serde_json::to_string_pretty(&migration).unwrap();
+        std::fs::write(dir.join(SALT_MIGRATION_FILE), json).map_err(ServiceError::Resalt)?;
+        self.record_admin_action(AdminActionKind::Resalt, None, 0, reason)?;
+        Ok(migration)
+    }
+
+    // Signed acknowledgments of hashes just added, in the order of `hashes`, or none without a signing key
+    pub fn acknowledge(&self, hashes: &[Hash512], encoding: LeafEncoding) -> Result<Vec<Acknowledgment>, StorageError> {
+        let Some(key) = &self.signing_key else {
+            return Ok(Vec::new());
+        };
+        let timestamp = unix_timestamp(SystemTime::now());
+        let sequences = self.hash_store.sequences_with_encoding(hashes, encoding)?;
+        Ok(hashes.iter().zip(sequences).map(|(&hash, sequence)| Acknowledgment::sign(key, hash, encoding, timestamp, sequence)).collect())
+    }
+
+    // Reject new hashes once the store reaches `limits`, see `MultiThreadedHashStore::set_limits`
+    pub fn with_store_limits(self, limits: StoreLimits) -> Self {
+        self.hash_store.set_limits(limits);
+        self
+    }
+
+    // Grow the bucket tables in the background when chains get longer than `policy` allows
+    pub fn with_resize_policy(self, policy: ResizePolicy) -> Self {
+        self.hash_store.set_resize_policy(Some(policy));
+        self
+    }
+
+    // Handle hashes whose bucket is full as `limit` says, see `HashStore::set_chain_limit`
+    pub fn with_chain_limit(self, limit: ChainLimit) -> Self {
+        self.hash_store.set_chain_limit(Some(limit));
+        self
+    }
+
+    // Reject adds while a worker has `capacity` commands queued, see `MultiThreadedHashStore::set_queue_capacity`
+    pub fn with_queue_capacity(self, capacity: usize) -> Self {
+        self.hash_store.set_queue_capacity(capacity);
+        self
+    }
+
+    // Rule out lookups of hashes that aren't stored with a Bloom filter for `capacity` hashes,
+    // see `MultiThreadedHashStore::set_bloom_filter`
+    pub fn with_bloom_filter(self, capacity: usize, false_positive_rate: f64) -> Self {
+        self.hash_store.set_bloom_filter(BloomFilter::new(capacity, false_positive_rate));
+        self
+    }
+
+    // Give the bucket table of every worker a new bucket salt after each published tree, see `HashStore::rotate_bucket_salt`
+    pub fn with_bucket_salt_rotation(mut self) -> Self {
+        self.rotate_bucket_salts = true;
+        self
+    }
+
+    // Remove hashes submitted in a namespace of `retention` once they are older than its duration. They are
+    // removed whenever a tree is published, like with `remove_hash` they keep their leaves and earlier proofs
+    // stay valid. Namespaces are metadata, so only hashes added since the last start expire.
+    pub fn with_retention(mut self, retention: HashMap<String, Duration>) -> Self {
+        self.retention = Arc::new(retention.into_iter().map(|(namespace, duration)| (namespace, duration.as_secs())).collect());
+        self
+    }
+
+    // Leaf log records of one worker from `offset` on, at most `limit` and only those included in the published tree.
+    // `None` for trees published from a mountain range, whose leaves a replica couldn't order the same way.
+    pub fn get_published_leaves(&self, thread_index: usize, offset: u64, limit: u64) -> Option<io::Result<Vec<Hash512>>> {
+        if self.mountain_range.is_some() {
+            return None;
+        }
+        let (_, lengths) = self.get_published_head()?;
+        let end = (*lengths.get(thread_index)?).min(offset.saturating_add(limit));
+        self.hash_store.read_leaf_log(thread_index, offset, end.saturating_sub(offset))
+    }
+
+    // Rebuild the last published tree from the leaf logs and compare its root with the published one.
+    // Returns `None` if no tree was published yet, the store doesn't keep leaf logs or trees are
+    // published from a mountain range.
+    pub fn audit_published_tree(&self) -> Option<AuditReport> {
+        if self.mountain_range.is_some() {
+            return None;
+        }
+        let published = self.published.load_full()?;
+        let head = published.head;
+        let matches = match self.hash_store.rebuild_from_leaf_logs(&published.leaf_log_lengths)? {
+            Ok(hashes) => MerkleTree::with_hasher(hashes, self.hash_store.salt, Arc::clone(self.hash_store.hasher())).root() == head.root,
+            Err(e) => {
+                eprintln!("Failed to read leaf logs: {}", e);
+                false
+            }
+        };
+
+        let report = AuditReport {
+            audited_at: unix_timestamp(SystemTime::now()),
+            tree_timestamp: head.timestamp,
+            leaf_count: head.leaf_count,
+            matches,
+        };
+        if !report.matches {
+            *self.audit_divergences.write().unwrap() += 1;
+            eprintln!("ALERT: rebuilt merkle root does not match the tree published at {}", head.timestamp);
+        }
+        *self.last_audit.write().unwrap() = Some(report);
+        Some(report)
+    }
+
+    // Check the invariants of the store, e.g. after restoring it from a backup: the buckets of every worker are sorted,
+    // free of duplicates and hold as many hashes as the worker counts, no hash is stored by two workers, and the
+    // latest published tree is rebuilt with the same root. Hashes added since that tree are left out using the leaf
+    // logs, without them the tree is only rebuilt if none were added. Trees published from a mountain range aren't.
+    pub fn verify_store(&self) -> Result<StoreCheck, StorageError> {
+        let (hashes, mut discrepancies) = self.hash_store.check_buckets()?;
+        let mut leaves = self.hash_store.snapshot()?.hashes;
+        sort_leaves(&mut leaves);
+        let stored_twice = leaves.windows(2).filter(|pair| pair[0] == pair[1]).count();
+        if stored_twice > 0 {
+            discrepancies.push(format!("{} salted hashes are stored by more than one worker", stored_twice));
+        }
+
+        // Summaries logged before roots were recorded have none to compare with
+        let latest = self.epochs.read().unwrap().latest().cloned().filter(|summary| summary.root.is_some() || summary.leaf_count == 0);
+        let (mut tree_version, mut root_matches) = (None, None);
+        if let Some(summary) = latest.filter(|_| self.mountain_range.is_none()) {
+            let tree_leaves = if leaves.len() == summary.leaf_count {
+                Some(leaves)
+            } else {
+                match self.hash_store.rebuild_from_leaf_logs(&summary.leaf_log_lengths) {
+                    Some(Ok(tree_leaves)) => {
+                        let missing = tree_leaves.iter().filter(|leaf| leaves.binary_search(leaf).is_err()).count();
+                        if missing > 0 {
+                            discrepancies.push(format!("{} leaves of tree version {} are not stored", missing, summary.epoch));
+                        }
+                        Some(tree_leaves)
+                    }
+                    Some(Err(e)) => {
+                        discrepancies.push(format!("Failed to read the leaf logs: {}", e));
+                        None
+                    }
+                    None => {
+                        if leaves.len() < summary.leaf_count {
+                            discrepancies.push(format!("Tree version {} has {} leaves, but only {} hashes are stored", summary.epoch, summary.leaf_count, leaves.len()));
+                        }
+                        None
+                    }
+                }
+            };
+            if let Some(tree_leaves) = tree_leaves {
+                let matches = MerkleTree::with_hasher(tree_leaves, self.hash_store.salt, Arc::clone(self.hash_store.hasher())).root() == summary.root;
+                if !matches {
+                    discrepancies.push(format!("The rebuilt root of tree version {} doesn't match the published one", summary.epoch));
+                }
+                tree_version = Some(summary.epoch);
+                root_matches = Some(matches);
+            }
+        }
+
+        Ok(StoreCheck { checked_at: unix_timestamp(SystemTime::now()), hashes, tree_version, root_matches, discrepancies })
+    }
+
+    // Periodically audit the published tree in a background thread
+    pub fn spawn_audit_job(&self, interval: Duration) -> thread::JoinHandle<()> {
+        let service = self.clone();
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            service.audit_published_tree();
+        })
+    }
+
+    // Memory of the store, the current tree and the retained previous trees
+    pub fn memory_usage(&self) -> MemoryUsage {
+        let mut memory = self.hash_store.stats().memory;
+        let current = match &self.mountain_range {
+            Some(published) => published.read().unwrap().range.memory_usage(),
+            None => self.published.load().as_ref().and_then(|published| published.tree.as_ref()).map_or(0, MerkleTree::memory_usage),
+        };
+        let previous: usize = self.previous_trees.read().unwrap().iter()
+            .filter_map(|previous| previous.tree.as_ref())
+            .map(MerkleTree::memory_usage)
+            .sum();
+        memory.merkle_trees = current + previous;
+        memory
+    }
+}
+
+impl<const INDEX_SIZE: usize, const PREFIX_SIZE: usize, S: HashStorage> TimestampingService<INDEX_SIZE, PREFIX_SIZE, S> {
+    // Publish trees of the hashes in `hash_store`, which keeps its own configuration
+    pub fn from_store(hash_store: S) -> Self {
+        Self {
+            hash_store: Arc::new(hash_store),
+            published: Arc::new(ArcSwapOption::empty()),
+            epochs: Arc::new(RwLock::new(EpochLog::in_memory())),
+            last_audit: Arc::new(RwLock::new(None)),
+            audit_divergences: Arc::new(RwLock::new(0)),
+            replication_divergences: Arc::new(RwLock::new(0)),
+            latencies: RequestLatencies::default(),
+            previous_trees: Arc::new(RwLock::new(VecDeque::new())),
+            retained_trees: 0,
+            tree_updates: broadcast::channel(TREE_UPDATE_CHANNEL_CAPACITY).0,
+            signing_key: None,
+            admin_log: Arc::new(RwLock::new(AdminLog::in_memory())),
+            root_log: Arc::new(RwLock::new(RootLog::in_memory())),
+            mountain_range: None,
+            time_source: Arc::new(SystemTimeSource),
+            cold_after_epochs: None,
+            leaf_pruning: None,
+            rotate_bucket_salts: false,
+            proof_cache: None,
+            retention: Arc::new(HashMap::new()),
+        }
+    }
+
+    // Record epoch summaries in the given log instead of only keeping them in memory. A mountain range gets the
+    // versions of the log back, see `with_mountain_range`.
+    pub fn with_epoch_log(mut self, epochs: EpochLog) -> Self {
+        self.epochs = Arc::new(RwLock::new(epochs));
+        self.restore_mountain_range();
+        self
+    }
+
+    // Publish trees by appending the new hashes to a merkle mountain range instead of rebuilding a
+    // `MerkleTree`, so proofs can be made against every version published since. The range is kept
+    // in memory and rebuilt from the stored hashes and the epoch log on startup, see `restore_mountain_range`.
+    // Leaf logs aren't in the order of the range, so `audit_published_tree` and `get_published_leaves`
+    // refuse it and replicas can't follow it. Needs a store that numbers its hashes, see `HashStorage::next_sequence`.
+    pub fn with_mountain_range(mut self) -> Self {
+        assert!(self.hash_store.next_sequence().is_some(), "Publishing from a mountain range needs a store that numbers its hashes");
+        let range = MerkleMountainRange::new(*self.hash_store.salt(), Arc::clone(self.hash_store.hasher()));
+        self.mountain_range = Some(Arc::new(RwLock::new(PublishedRange { range, versions: Vec::new(), sequence_bound: 0 })));
+        self.restore_mountain_range();
+        self
+    }
+
+    // Rebuild the mountain range from the stored hashes numbered below the `sequence_bound` of the latest logged
+    // version, in order, and take back every logged version whose root it reproduces. Removed hashes stay stored,
+    // so that is every version published from a range. Versions published as trees, or before their hashes
+    // were renumbered, have no proofs.
+    fn restore_mountain_range(&self) {
+        let Some(published) = &self.mountain_range else {
+            return;
+        };
+        let epochs = self.epochs.read().unwrap();
+        let Some(bound) = epochs.latest().and_then(|summary| summary.sequence_bound) else {
+            return;
+        };
+        let (entries, _) = self.hash_store.snapshot_numbered(0, bound)
+            .unwrap_or_else(|e| panic!("Failed to restore the mountain range: {}", e));
+        let mut range = MerkleMountainRange::new(*self.hash_store.salt(), Arc::clone(self.hash_store.hasher()));
+        for (sequence, leaf) in entries {
+            range.append(leaf, sequence);
+        }
+        let mut versions = Vec::new();
+        for summary in epochs.page(0, epochs.len()) {
+            let Some(version_bound) = summary.sequence_bound else {
+                continue;
+            };
+            let leaf_count = range.sequences.partition_point(|&sequence| sequence < version_bound);
+            if leaf_count == summary.leaf_count && range.root_at(leaf_count) == summary.root {
+                versions.push((summary.epoch, leaf_count));
+            } else {
+                eprintln!("ALERT: the restored mountain range doesn't reproduce the root of version {}, it has no proofs", summary.epoch);
+            }
+        }
+        *published.write().unwrap() = PublishedRange { range, versions, sequence_bound: bound };
+    }
+
+    pub fn uses_mountain_range(&self) -> bool {
+        self.mountain_range.is_some()
+    }
+
+    // Record administrative actions in the given log instead of only keeping them in memory
+    pub fn with_admin_log(mut self, log: AdminLog) -> Self {
+        self.admin_log = Arc::new(RwLock::new(log));
+        self
+    }
+
+    fn record_admin_action(&self, action: AdminActionKind, leaf: Option<Hash512>, removed: usize, reason: Option<String>) -> io::Result<()> {
+        self.admin_log.write().unwrap().append(AdminAction {
+            timestamp: unix_timestamp(SystemTime::now()),
+            action,
+            leaf,
+            removed,
+            tree_version: self.get_merkle_tree_version(),
+            reason,
+        })
+    }
+
+    pub fn admin_actions(&self) -> Vec<AdminAction> {
+        self.admin_log.read().unwrap().actions().to_vec()
+    }
+
+    // Record every published tree head in the given hash-chained log instead of only keeping them in memory
+    pub fn with_root_log(mut self, log: RootLog) -> Self {
+        self.root_log = Arc::new(RwLock::new(log));
+        self
+    }
+
+    // Keep the last `count` trees before the current one in memory, so proofs can still be generated for them.
+    // Every tree holds all hashes it was built from, so each retained tree costs as much memory as the current one,
+    // unless trees are pruned, see `with_leaf_pruning`.
+    pub fn with_retained_trees(mut self, count: usize) -> Self {
+        self.retained_trees = count;
+        self
+    }
+
+    // Sign the head of every tree published from now on, so receipts can be verified with the public key
+    pub fn with_signing_key(mut self, key: SigningKey) -> Self {
+        self.signing_key = Some(Arc::new(key));
+        self
+    }
+
+    pub fn verifying_key(&self) -> Option<VerifyingKey> {
+        self.signing_key.as_ref().map(|key| key.verifying_key())
+    }
+
+    // Signature over a new tree head. Replicated heads were signed by the primary and empty trees can't have receipts.
+    fn sign(&self, head: &TreeHead, time_source: Option<&TimeAttestation>, replicated: bool) -> Option<Vec<u8>> {
+        let key = self.signing_key.as_ref().filter(|_| !replicated)?;
+        let root = head.root?;
+        let time_source = time_source.map(TimeAttestation::summary);
+        Some(sign_tree_head(key, self.hash_store.hasher().name(), head.version, head.leaf_count as u64, head.timestamp, &root, time_source.as_deref()))
+    }
+
+    // Timestamp tree heads with `time_source` instead of the unverified system time
+    pub fn with_time_source(mut self, time_source: Arc<dyn TimeSource>) -> Self {
+        self.time_source = time_source;
+        self
+    }
+
+    // Move hashes out of memory into a sorted file next to the leaf logs once `epochs` newer trees were published
+    // since the first tree including them, see `MultiThreadedHashStore::move_to_cold`. Only memory of the buckets
+    // is freed, the current merkle tree still holds every leaf unless it is pruned, see `with_leaf_pruning`.
+    // Stores without leaf logs keep all hashes in memory.
+    pub fn with_cold_storage(mut self, epochs: u64) -> Self {
+        self.cold_after_epochs = Some(epochs);
+        self
+    }
+
+    // Drop the leaves of every published tree from memory once its head is signed, and with `after_anchor` only once
+    // it is also anchored by a transaction or a transparency log entry, see `prune_tree`. Trees published from a
+    // mountain range aren't pruned, and stores without leaf logs have no place for the leaves and keep them
+    // in memory with `LeafPruning::Disk`.
+    pub fn with_leaf_pruning(mut self, pruning: LeafPruning, after_anchor: bool) -> Self {
+        // Trees are rebuilt after a restart, the leaves of the trees before it aren't needed anymore
+        if let Some(dir) = self.hash_store.leaf_log_dir().filter(|_| pruning == LeafPruning::Disk)
+            && let Err(e) = LeafFile::remove_all(dir)
+        {
+            eprintln!("Failed to remove the leaves of earlier trees: {}", e);
+        }
+        self.leaf_pruning = Some((pruning, after_anchor));
+        self
+    }
+
+    // Keep the proofs of the `capacity` most recently checked hashes, see `ProofCache`. Blinded proofs aren't cached.
+    pub fn with_proof_cache(mut self, capacity: usize) -> Self {
+        self.proof_cache = Some(Arc::new(ProofCache::new(capacity)));
+        self
+    }
+
+    pub fn proof_cache_stats(&self) -> Option<ProofCacheStats> {
+        self.proof_cache.as_ref().map(|cache| cache.stats())
+    }
+
+    pub fn retention(&self) -> &HashMap<String, u64> {
+        &self.retention
+    }
+
+    // Publish a tree of all stored hashes. Fails without publishing anything if a worker didn't answer, as the tree
+    // would lack its hashes.
+    pub fn update_merkle_tree(&self) -> Result<TreeHead, StorageError> {
+        self.publish_tree(None)
+    }
+
+    // Publish the tree of the replicated hashes under the version and timestamp of the primary's head.
+    // Returns whether both roots match, which they do once all leaves of the primary's tree were replicated.
+    pub fn publish_replicated_tree(&self, primary: &TreeHead) -> Result<bool, StorageError> {
+        let matches = self.publish_tree(Some(primary))?.root == primary.root;
+        if !matches {
+            *self.replication_divergences.write().unwrap() += 1;
+        }
+        Ok(matches)
+    }
+
+    fn publish_tree(&self, primary: Option<&TreeHead>) -> Result<TreeHead, StorageError> {
+        let build_start = Instant::now();
+        let sequence_bound = self.hash_store.next_sequence();
+        let (new_tree, new_leaves, leaf_log_lengths) = match &self.mountain_range {
+            Some(published) => {
+                // Only the hashes added since the last version, which a concurrent update may have appended meanwhile
+                let first = published.read().unwrap().sequence_bound;
+                let (entries, leaf_log_lengths) = self.hash_store.snapshot_numbered(first, sequence_bound.unwrap_or(first))?;
+                (None, entries, leaf_log_lengths)
+            }
+            None => {
+                let (tree, leaf_log_lengths) = MerkleTree::from_storage(&*self.hash_store)?;
+                (Some(tree), Vec::new(), leaf_log_lengths)
+            }
+        };
+        let mut build_duration = build_start.elapsed();
+        // Asked before taking the epoch log, as time sources may wait for a server
+        let (now, time_source) = match primary {
+            Some(_) => (SystemTime::now(), None),
+            None => {
+                let (now, attestation) = self.time_source.now();
+                (now, Some(attestation))
+            }
+        };
+
+        // Holding the epoch log until the summary is appended keeps versions unique across concurrent updates
+        let mut epochs = self.epochs.write().unwrap();
+        let version = primary.map_or(epochs.len() as u64, |primary| primary.version);
+        let (root, tree_size, leaf_count) = match (&new_tree, &self.mountain_range) {
+            (Some(tree), _) => (tree.root(), tree.size(), tree.leaf_count),
+            (None, Some(published)) => {
+                // Appended under the epoch log lock, so later versions never have fewer leaves
+                let append_start = Instant::now();
+                let mut published = published.write().unwrap();
+                let first = published.sequence_bound;
+                for (sequence, leaf) in new_leaves.into_iter().filter(|&(sequence, _)| sequence >= first) {
+                    published.range.append(leaf, sequence);
+                }
+                published.sequence_bound = sequence_bound.map_or(first, |bound| first.max(bound));
+                let leaf_count = published.range.leaf_count();
+                published.versions.push((version, leaf_count));
+                build_duration += append_start.elapsed();
+                (published.range.root(), published.range.size(), leaf_count)
+            }
+            (None, None) => unreachable!(),
+        };
+        let head = TreeHead {
+            version,
+            root,
+            tree_size,
+            leaf_count,
+            timestamp: primary.map_or(unix_timestamp(now), |primary| primary.timestamp),
+        };
+
+        // Retained before the swap, so its version stays available to readers throughout
+        if self.retained_trees > 0
+            && let Some(current) = self.published.load_full().filter(|current| current.tree.is_some())
+        {
+            let mut previous_trees = self.previous_trees.write().unwrap();
+            previous_trees.push_front(current);
+            previous_trees.truncate(self.retained_trees);
+        }
+        self.published.store(Some(Arc::new(PublishedTree {
+            tree: new_tree,
+            head,
+            leaf_log_lengths: leaf_log_lengths.clone(),
+            updated_at: unix_timestamp(now),
+        })));
+        if let Some(cache) = &self.proof_cache {
+            cache.clear();
+        }
+
+        {
+            let signature = self.sign(&head, time_source.as_ref(), primary.is_some());
+            // Appended under the epoch log lock too, so entries are in version order
+            if let Err(e) = self.root_log.write().unwrap().append(&head, signature.clone()) {
+                eprintln!("Failed to append to root log: {}", e);
+            }
+            let previous_leaf_count = epochs.latest().map(|summary| summary.leaf_count).unwrap_or(0);
+            let summary = EpochSummary {
+                epoch: head.version,
+                timestamp: head.timestamp,
+                leaf_count: head.leaf_count,
+                new_hashes: head.leaf_count.saturating_sub(previous_leaf_count),
+                build_duration_ms: build_duration.as_millis() as u64,
+                root: head.root,
+                tree_size: head.tree_size,
+                anchor_txid: None,
+                signature,
+                transparency_log: None,
+                time_source,
+                leaf_log_lengths,
+                sequence_bound,
+                cosignatures: Vec::new(),
+            };
+            if let Err(e) = epochs.append(summary) {
+                eprintln!("Failed to persist epoch summary: {}", e);
+            }
+        }
+        drop(epochs);
+
+        // Sending only fails if nobody is subscribed, which is fine
+        let _ = self.tree_updates.send(head);
+        self.prune_tree(head.version);
+        self.move_cold_hashes(head.version);
+        self.expire_hashes(unix_timestamp(now));
+        if self.rotate_bucket_salts {
+            self.hash_store.rotate_bucket_salts();
+        }
+        Ok(head)
+    }
+
+    // Remove the hashes whose retention ended by `now`, see `with_retention`, recording every namespace in the admin log
+    fn expire_hashes(&self, now: u64) {
+        if self.retention.is_empty() {
+            return;
+        }
+        let expired = match self.hash_store.expire(&self.retention, now) {
+            Ok(expired) => expired,
+            Err(e) => {
+                eprintln!("Failed to remove expired hashes: {}", e);
+                return;
+            }
+        };
+        for (namespace, count) in expired {
+            let reason = format!("Retention of namespace {} ended", namespace);
+            if let Err(e) = self.record_admin_action(AdminActionKind::Expire, None, count, Some(reason)) {
+                eprintln!("Failed to write admin log: {}", e);
+            }
+        }
+    }
+
+    // Prune the tree published as `version`, see `MerkleTree::prune`, if it is the current or a retained one and its head
+    // is signed and anchored as `with_leaf_pruning` requires. Returns whether it was pruned now.
+    pub fn prune_tree(&self, version: u64) -> bool {
+        let Some((pruning, after_anchor)) = self.leaf_pruning else {
+            return false;
+        };
+        {
+            let epochs = self.epochs.read().unwrap();
+            let Some(summary) = epochs.get(version) else {
+                return false;
+            };
+            let signed = summary.signature.is_some() || self.signing_key.is_none();
+            let anchored = summary.anchor_txid.is_some() || summary.transparency_log.is_some();
+            if !signed || (after_anchor && !anchored) {
+                return false;
+            }
+        }
+        let prune = |published: &PublishedTree| {
+            let tree = published.tree.as_ref().filter(|tree| tree.pruned.is_none() && tree.leaf_count > 0)?;
+            let file = match pruning {
+                LeafPruning::Disk => Some(LeafFile::path(self.hash_store.leaf_log_dir()?, version)),
+                LeafPruning::Discard => None,
+            };
+            match tree.prune(file.as_deref()) {
+                Ok(tree) => Some(Arc::new(PublishedTree { tree: Some(tree), leaf_log_lengths: published.leaf_log_lengths.clone(), ..*published })),
+                Err(e) => {
+                    eprintln!("Failed to prune tree {}: {}", version, e);
+                    None
+                }
+            }
+        };
+
+        let current = self.published.load_full();
+        if let Some(published) = current.as_ref().filter(|published| published.head.version == version) {
+            let Some(pruned) = prune(published) else {
+                return false;
+            };
+            // If a newer tree was published meanwhile, this one was retained unpruned and is pruned there
+            let previous = self.published.compare_and_swap(&current, Some(pruned));
+            if previous.as_ref().is_some_and(|previous| Arc::ptr_eq(previous, published)) {
+                return true;
+            }
+        }
+        let mut previous_trees = self.previous_trees.write().unwrap();
+        let Some(previous) = previous_trees.iter_mut().find(|previous| previous.head.version == version) else {
+            return false;
+        };
+        match prune(previous) {
+            Some(pruned) => {
+                *previous = pruned;
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Move the hashes of the tree `cold_after_epochs` versions before `version` to disk, see `with_cold_storage`
+    fn move_cold_hashes(&self, version: u64) {
+        let Some(epoch) = self.cold_after_epochs.and_then(|epochs| version.checked_sub(epochs)) else {
+            return;
+        };
+        let Some(bound) = self.epochs.read().unwrap().get(epoch).and_then(|summary| summary.sequence_bound) else {
+            return;
+        };
+        if let Err(e) = self.hash_store.move_to_cold(bound) {
+            eprintln!("Failed to move hashes to the cold index: {}", e);
+        }
+    }
+
+    // Published head and the leaf log lengths of every worker its tree was built from,
+    // which is what a replica needs to catch up to it
+    pub fn get_published_head(&self) -> Option<(TreeHead, Vec<u64>)> {
+        self.published.load().as_ref().map(|published| (published.head, published.leaf_log_lengths.clone()))
+    }
+
+    // The current tree, `None` before the first one was published
+    pub fn published_tree(&self) -> Option<Arc<PublishedTree>> {
+        self.published.load_full()
+    }
+
+    // Publish a final tree containing every hash added so far, then stop the store and flush all persisted state
+    pub fn shutdown(&self) {
+        if let Err(e) = self.update_merkle_tree() {
+            eprintln!("Failed to publish the final tree: {}", e);
+        }
+        self.close();
+    }
+
+    // Stop the store and flush the epoch log without publishing a final tree, as replicas do
+    pub fn close(&self) {
+        self.hash_store.shutdown();
+        if let Err(e) = self.epochs.write().unwrap().flush() {
+            eprintln!("Failed to flush epoch log: {}", e);
+        }
+    }
+
+    // Subscribe to tree heads published by future calls to `update_merkle_tree`
+    pub fn subscribe_tree_updates(&self) -> broadcast::Receiver<TreeHead> {
+        self.tree_updates.subscribe()
+    }
+
+    pub fn get_merkle_proof(&self, hash: &Hash512) -> Option<ProofBytes> {
+        self.get_merkle_proof_with_encoding(hash, LeafEncoding::default())
+    }
+
+    pub fn get_merkle_proof_with_encoding(&self, hash: &Hash512, encoding: LeafEncoding) -> Option<ProofBytes> {
+        self.get_merkle_proof_from(&*self.published.load_full()?, hash, None, encoding)
+    }
+
+    // Proof from a tree loaded with `published_tree`, also after newer trees were published.
+    // Hashes added with `MultiThreadedHashStore::add_blinded` are looked up with their nonce.
+    pub fn get_merkle_proof_from(&self, published: &PublishedTree, hash: &Hash512, nonce: Option<&Hash512>, encoding: LeafEncoding) -> Option<ProofBytes> {
+        self.with_published_tree(published, |tree| match nonce {
+            Some(nonce) => proof_to_bytes(tree.get_with_salt(hash, Some(nonce), encoding)),
+            None => self.cached_proof(tree, hash, encoding, published.head.version),
+        })
+    }
+
+    fn cached_proof(&self, tree: TreeView, hash: &Hash512, encoding: LeafEncoding, version: u64) -> Option<ProofBytes> {
+        match &self.proof_cache {
+            Some(cache) => cache.get_or_insert_with(hash, encoding.version(), version, tree.leaf_count(), || proof_bytes(tree, hash, encoding)),
+            None => proof_bytes(tree, hash, encoding),
+        }
+    }
+
+    // A published range only grows, so a tree head published from it sees the leaves up to its leaf count
+    fn with_published_tree<R>(&self, published: &PublishedTree, f: impl FnOnce(TreeView) -> R) -> R {
+        match (&published.tree, &self.mountain_range) {
+            (Some(tree), _) => f(TreeView::Tree(tree)),
+            (None, Some(range)) => {
+                let range = range.read().unwrap();
+                f(TreeView::Range(&range.range, published.head.leaf_count, &|hash, leaf| self.leaf_sequence(hash, leaf)))
+            }
+            (None, None) => unreachable!(),
+        }
+    }
+
+    // Proof of inclusion in the tree published as `version`.
+    // Fails with `ServiceError::TreeVersionUnavailable` if that tree is neither the current one nor retained,
+    // see `with_retained_trees`.
+    pub fn get_merkle_proof_at_version(&self, hash: &Hash512, encoding: LeafEncoding, version: u64) -> Result<Option<ProofBytes>, ServiceError> {
+        self.with_tree_at_version(version, |tree| self.cached_proof(tree, hash, encoding, version))
+            .ok_or(ServiceError::TreeVersionUnavailable(version))
+    }
+
+    // Proofs for many hashes from the same tree, taking its lock only once
+    pub fn get_merkle_proofs_at_version(&self, hashes: &[Hash512], encoding: LeafEncoding, version: u64) -> Result<Vec<Option<ProofBytes>>, ServiceError> {
+        self.with_tree_at_version(version, |tree| {
+            hashes.iter().map(|hash| self.cached_proof(tree, hash, encoding, version)).collect()
+        }).ok_or(ServiceError::TreeVersionUnavailable(version))
+    }
+
+    // Proof for a hash added with `MultiThreadedHashStore::add_blinded`, see `get_merkle_proof_at_version`
+    pub fn get_blinded_merkle_proof_at_version(&self, hash: &Hash512, nonce: &Hash512, encoding: LeafEncoding, version: u64) -> Result<Option<ProofBytes>, ServiceError> {
+        self.with_tree_at_version(version, |tree| proof_to_bytes(tree.get_with_salt(hash, Some(nonce), encoding)))
+            .ok_or(ServiceError::TreeVersionUnavailable(version))
+    }
+
+    // Receipt for a hash in the current tree with its signed tree head, `None` if the hash isn't included in it.
+    // The hash is taken to be a SHA-512 digest, the store doesn't know which algorithm a normalized hash came from.
+    pub fn get_receipt(&self, hash: &Hash512, encoding: LeafEncoding) -> Option<Receipt> {
+        let published = self.published.load_full()?;
+        let version = published.head.version;
+        // The range lock is released before the epoch log is read, as publishing takes them the other way around
+        let merkle_proof = self.with_published_tree(&published, |tree| tree.get_with_salt(hash, None, encoding))?;
+        let epochs = self.epochs.read().unwrap();
+        let summary = epochs.get(version)?;
+        Some(Receipt {
+            hash: *hash,
+            hash_algorithm: HashAlgorithm::Sha512,
+            leaf_encoding: encoding,
+            tree_hasher: self.hash_store.hasher().name().to_string(),
+            merkle_proof,
+            merkle_tree_root: summary.root?,
+            tree_version: Some(version),
+            leaf_count: Some(summary.leaf_count as u64),
+            timestamp: Some(summary.timestamp),
+            signature: summary.signature.clone(),
+            anchor_txid: summary.anchor_txid.clone(),
+            server_version: Some(env!("CARGO_PKG_VERSION").to_string()),
+            time_source: summary.time_source.as_ref().map(TimeAttestation::summary),
+            cosignatures: summary.cosignatures.clone(),
+        })
+    }
+
+    // Sequence number of the leaf of a hash in a mountain range, a store that can't be asked has no proofs
+    fn leaf_sequence(&self, hash: &Hash512, leaf: Hash512) -> Option<u64> {
+        self.hash_store.stored_sequence(hash, leaf).ok().flatten()
+    }
+
+    // A mountain range has every version published from it, trees only the current and retained ones
+    fn with_tree_at_version<R>(&self, version: u64, f: impl FnOnce(TreeView) -> R) -> Option<R> {
+        if let Some(published) = &self.mountain_range {
+            let published = published.read().unwrap();
+            let index = published.versions.binary_search_by_key(&version, |&(version, _)| version).ok()?;
+            return Some(f(TreeView::Range(&published.range, published.versions[index].1, &|hash, leaf| self.leaf_sequence(hash, leaf))));
+        }
+        if let Some(current) = self.published.load_full().filter(|current| current.head.version == version) {
+            return Some(self.with_published_tree(&current, f));
+        }
+        let previous = self.previous_trees.read().unwrap().iter().find(|previous| previous.head.version == version).cloned()?;
+        Some(self.with_published_tree(&previous, f))
+    }
+
+    // Version of the current merkle tree, `None` before the first tree was published
+    pub fn get_merkle_tree_version(&self) -> Option<u64> {
+        self.published.load().as_ref().map(|published| published.head.version)
+    }
+
+    pub fn get_merkle_tree_root_bytes(&self) -> Option<Vec<u8>> {
+        self.get_merkle_tree_root().map(|root| root.to_bytes())
+    }
+
+    pub fn get_last_update_timestamp(&self) -> Option<u64> {
+        self.published.load().as_ref().map(|published| published.updated_at)
+    }
+
+    pub fn get_merkle_tree_size(&self) -> usize {
+        self.published.load().as_ref().map_or(0, |published| published.head.tree_size)
+    }
+
+    pub fn get_merkle_tree_root(&self) -> Option<Hash512> {
+        self.published.load().as_ref().and_then(|published| published.head.root)
+    }
+}
+
+fn proof_bytes(tree: TreeView, hash: &Hash512, encoding: LeafEncoding) -> Option<ProofBytes> {
+    proof_to_bytes(tree.get_with_salt(hash, None, encoding))
+}
+
+fn proof_to_bytes(proof: Option<Vec<(Hash512, Hash512)>>) -> Option<ProofBytes> {
+    proof.map(|proof| {
+        proof.into_iter()
+            .map(|(left, right)| (left.to_bytes(), right.to_bytes()))
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    static SALT: Hash512 = Hash512([0, 0, 0, 0, 0, 0, 0, 0]);
+
+    fn hash512(a: Hash512, b: Hash512) -> Hash512 {
+        Sha512Hasher.combine(&a, &b)
+    }
 
     #[test]
     fn test_hash512_byte_conversion() {
         // convert hash to bytes
-        let hash = [1u64, 2u64, 3u64, 4u64, 5u64, 6u64, 7u64, 8u64];
+        let hash = Hash512([1u64, 2u64, 3u64, 4u64, 5u64, 6u64, 7u64, 8u64]);
         let bytes = hash.to_bytes();
         assert_eq!(bytes.len(), 64);
 
@@ -518,7 +5103,7 @@ mod tests {
 
     #[test]
     fn test_hash512_to_index() {
-        let hash = [0x1234567890ABCDEFu64, 0, 0, 0, 0, 0, 0, 0];
+        let hash = Hash512([0x1234567890ABCDEFu64, 0, 0, 0, 0, 0, 0, 0]);
 
         assert_eq!(hash.to_index(0, 8), 0x12);
         assert_eq!(hash.to_index(8, 8), 0x34);
@@ -529,7 +5114,7 @@ mod tests {
     #[test]
     #[should_panic(expected = "Prefix size + index size must be less than or equal to 64")]
     fn test_hash512_to_index_panic() {
-        let hash = [0u64; 8];
+        let hash = Hash512([0u64; 8]);
         hash.to_index(32, 33); // This should panic
     }
 
@@ -541,40 +5126,1070 @@ mod tests {
         assert_eq!(store.len(), 0);
         assert_eq!(store.occupied_slots(), 0);
 
-        // Create a test hash
-        let hash = [1u64, 2u64, 3u64, 4u64, 5u64, 6u64, 7u64, 8u64];
+        // Create a test hash
+        let hash = Hash512([1u64, 2u64, 3u64, 4u64, 5u64, 6u64, 7u64, 8u64]);
+
+        // Test adding hash
+        assert!(store.add_hash(hash).unwrap());
+        assert_eq!(store.len(), 1);
+        assert_eq!(store.occupied_slots(), 1);
+        assert!(store.contains(&hash));
+
+        // Test adding duplicate
+        assert!(!store.add_hash(hash).unwrap());
+        assert_eq!(store.len(), 1);
+        assert_eq!(store.occupied_slots(), 1);
+
+        // Test adding different hash
+        let hash2 = Hash512([u64::MAX, 10u64, 11u64, 12u64, 13u64, 14u64, 15u64, 16u64]);
+        assert!(store.add_hash(hash2).unwrap());
+        assert_eq!(store.len(), 2);
+        assert_eq!(store.occupied_slots(), 2);
+    }
+
+    #[test]
+    fn test_hash_store_ordering() {
+        let store = HashStore::<8, 0>::new(SALT);
+
+        for i in 0..10 {
+            let hash = Hash512([i as u64, 0, 0, 0, 0, 0, 0, 0]);
+            store.add_hash(hash).unwrap();
+        }
+
+        assert_eq!(store.to_array().len(), 10);
+        let mut array = store.to_array();
+        array.sort_by(|a, b| a.0[0].cmp(&b.0[0]));
+        assert_eq!(array, store.to_array());
+    }
+
+    #[test]
+    fn test_for_each_chunk() {
+        let store = HashStore::<8, 0>::new(SALT);
+        let hashes: Vec<Hash512> = (0..10_000u64).map(|i| Hash512([i << 50, i, 0, 0, 0, 0, 0, 0])).collect();
+        for hash in &hashes {
+            store.add_hash(*hash).unwrap();
+        }
+        let path = std::env::temp_dir().join(format!("timestamping-test-chunks-{}.bin", std::process::id()));
+        assert_eq!(store.move_to_cold(&path, 3000).unwrap(), 3000);
+
+        // Moved hashes come from disk, after those in the buckets, as in `to_array`
+        let mut chunks = Vec::new();
+        store.for_each_chunk(|chunk| chunks.push(chunk.to_vec()));
+        assert_eq!(chunks.iter().map(Vec::len).collect::<Vec<_>>(), [ITER_CHUNK_SIZE, ITER_CHUNK_SIZE, 10_000 - 2 * ITER_CHUNK_SIZE]);
+        assert_eq!(chunks.concat(), store.to_array());
+        assert_eq!(store.iter().collect::<Vec<_>>(), store.to_array());
+        std::fs::remove_file(&path).unwrap();
+
+        let threaded = MultiThreadedHashStore::<8, 0>::new(4, SALT);
+        threaded.add_batch(&hashes).unwrap();
+        let mut visited = Vec::new();
+        threaded.for_each_chunk(|chunk| visited.extend_from_slice(chunk)).unwrap();
+        assert_eq!(visited.len(), hashes.len());
+        let chunks = HashStorage::iter(&threaded).collect::<Result<Vec<_>, _>>().unwrap();
+        assert!(chunks.iter().all(|chunk| chunk.len() <= ITER_CHUNK_SIZE));
+        assert_eq!(chunks.concat(), visited);
+        let mut expected: Vec<Hash512> = hashes.iter().map(|hash| LeafEncoding::default().leaf(hash, &SALT)).collect();
+        expected.sort();
+        visited.sort();
+        assert_eq!(visited, expected);
+        threaded.shutdown();
+    }
+
+    #[test]
+    fn test_hash_store_concurrent_access() {
+        let store = Arc::new(HashStore::<10, 0>::new(SALT));
+        let handles: Vec<_> = (0..8).map(|i| {
+            let store = Arc::clone(&store);
+            std::thread::spawn(move || {
+                // Every thread also adds the hashes of its neighbour to create duplicates
+                for j in 0..1000 {
+                    store.add_hash(Hash512([(i * 1000 + j) as u64, 0, 0, 0, 0, 0, 0, 0])).unwrap();
+                    store.add_hash(Hash512([(((i + 1) % 8) * 1000 + j) as u64, 0, 0, 0, 0, 0, 0, 0])).unwrap();
+                }
+            })
+        }).collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        assert_eq!(store.len(), 8000);
+        assert_eq!(store.to_array().len(), 8000);
+        for i in 0..8000 {
+            assert!(store.contains(&Hash512([i as u64, 0, 0, 0, 0, 0, 0, 0])));
+        }
+    }
+
+    #[test]
+    fn test_hash_store_small_index() {
+        // Fewer buckets than lock shards
+        let store = HashStore::<1, 0>::new(SALT);
+        for i in 0..10 {
+            store.add_hash(Hash512([i as u64, 0, 0, 0, 0, 0, 0, 0])).unwrap();
+        }
+        assert_eq!(store.len(), 10);
+        assert_eq!(store.occupied_slots(), 2);
+    }
+
+    #[test]
+    fn test_hash_store_merge() {
+        let store = HashStore::<2, 0>::new(SALT);
+        let other = HashStore::<2, 0>::new(SALT);
+
+        for i in 0..10 {
+            store.add_hash(Hash512([i as u64, 0, 0, 0, 0, 0, 0, 0])).unwrap();
+        }
+        for i in 5..20 {
+            other.add_hash(Hash512([i as u64, 0, 0, 0, 0, 0, 0, 0])).unwrap();
+        }
+
+        assert_eq!(store.merge(&other).unwrap(), 10);
+        assert_eq!(store.len(), 20);
+        assert_eq!(store.occupied_slots(), 4);
+        for i in 0..20 {
+            assert!(store.contains(&Hash512([i as u64, 0, 0, 0, 0, 0, 0, 0])));
+        }
+
+        // Merging again adds nothing
+        assert_eq!(store.merge(&other).unwrap(), 0);
+        assert_eq!(store.len(), 20);
+
+        // Metadata comes along, also for stored hashes without any, but doesn't replace the metadata of a hash
+        let metadata = HashMetadata { namespace: Some("docs".to_string()), ..Default::default() };
+        let own = HashMetadata { namespace: Some("own".to_string()), ..Default::default() };
+        store.add_hash_with_metadata(Hash512([30, 0, 0, 0, 0, 0, 0, 0]), LeafEncoding::default(), own.clone()).unwrap();
+        for i in [3, 25, 30] {
+            other.add_hash_with_metadata(Hash512([i, 0, 0, 0, 0, 0, 0, 0]), LeafEncoding::default(), metadata.clone()).unwrap();
+        }
+        assert_eq!(store.merge(&other).unwrap(), 1);
+        assert_eq!(store.metadata(&Hash512([3, 0, 0, 0, 0, 0, 0, 0])), Some(metadata.clone()));
+        assert_eq!(store.metadata(&Hash512([25, 0, 0, 0, 0, 0, 0, 0])), Some(metadata));
+        assert_eq!(store.metadata(&Hash512([30, 0, 0, 0, 0, 0, 0, 0])), Some(own));
+        assert_eq!(store.metadata(&Hash512([6, 0, 0, 0, 0, 0, 0, 0])), None);
+
+        // Buckets stay sorted
+        let array = store.to_array();
+        let mut sorted = array.clone();
+        sorted.sort_by_key(|hash| (hash.to_index(0, 2), *hash));
+        assert_eq!(array, sorted);
+    }
+
+    #[test]
+    fn test_hash_store_merge_salt_mismatch() {
+        let store = HashStore::<8, 0>::new(SALT);
+        let other = HashStore::<8, 0>::new(Hash512([1, 0, 0, 0, 0, 0, 0, 0]));
+        other.add_hash(Hash512([1u64, 0, 0, 0, 0, 0, 0, 0])).unwrap();
+
+        assert!(matches!(store.merge(&other), Err(MergeError::SaltMismatch)));
+        assert_eq!(store.len(), 0);
+    }
+
+    #[test]
+    fn test_multi_threaded_hash_store_add_result() {
+        let store = MultiThreadedHashStore::<8, 0>::new(4, SALT);
+        let hash = Hash512([1u64, 2, 3, 4, 5, 6, 7, 8]);
+
+        assert!(store.add_hash(hash).unwrap());
+        assert!(!store.add_hash(hash).unwrap());
+        assert_eq!(store.len(), 1);
+    }
+
+    #[test]
+    fn test_multi_threaded_hash_store_add_batch() {
+        let store = MultiThreadedHashStore::<8, 0>::new(4, SALT);
+        store.add_hash(Hash512([1 << 62, 0, 0, 0, 0, 0, 0, 0])).unwrap();
+
+        // Hashes for all workers, including one that already exists and a duplicate within the batch
+        let batch: Vec<Hash512> = vec![
+            Hash512([3 << 62, 0, 0, 0, 0, 0, 0, 0]),
+            Hash512([1 << 62, 0, 0, 0, 0, 0, 0, 0]),
+            Hash512([0, 0, 0, 0, 0, 0, 0, 0]),
+            Hash512([2 << 62, 0, 0, 0, 0, 0, 0, 0]),
+            Hash512([3 << 62, 0, 0, 0, 0, 0, 0, 0]),
+            Hash512([(3 << 62) + 1, 0, 0, 0, 0, 0, 0, 0]),
+        ];
+        assert_eq!(store.add_batch(&batch).unwrap(), vec![true, false, true, true, false, true]);
+        assert_eq!(store.len(), 5);
+        for hash in &batch {
+            assert!(store.contains(hash).unwrap());
+        }
+
+        assert_eq!(store.add_batch(&[]).unwrap(), Vec::<bool>::new());
+    }
+
+    #[test]
+    fn test_partitioning() {
+        // All with the same unsalted prefix, as an attacker would send them to load a single worker
+        let hashes: Vec<Hash512> = (0..4000u64).map(|i| Hash512([i, i * 7, 0, 0, 0, 0, 0, 0])).collect();
+        for partitioning in Partitioning::ALL {
+            let store = MultiThreadedHashStore::<8, 2>::new(4, SALT).with_partitioning(partitioning);
+            assert_eq!(store.add_batch(&hashes[..3000]).unwrap(), vec![true; 3000]);
+            for hash in &hashes[3000..] {
+                assert!(store.add_hash(*hash).unwrap());
+            }
+            // Stored hashes stay where they are, within a batch as well
+            assert!(!store.add_hash(hashes[0]).unwrap());
+            assert_eq!(store.add_batch(&[hashes[1], hashes[4000 - 1], Hash512([1, 0, 0, 0, 0, 0, 1, 0]), Hash512([1, 0, 0, 0, 0, 0, 1, 0])]).unwrap(), vec![false, false, true, false]);
+            assert_eq!(store.len(), 4001);
+
+            let counts = store.stats().worker_hashes;
+            if partitioning == Partitioning::UnsaltedPrefix {
+                assert_eq!(counts, vec![4001, 0, 0, 0]);
+            } else {
+                assert!(counts.iter().all(|&count| count.abs_diff(1000) < 100), "{} spreads them as {:?}", partitioning.name(), counts);
+            }
+
+            assert_eq!(store.contains_batch_with_encoding(&hashes[..100], LeafEncoding::default()).unwrap(), vec![true; 100]);
+            assert!(!store.contains(&Hash512([2, 0, 0, 0, 0, 0, 0, 0])).unwrap());
+            assert_eq!(store.sequences_with_encoding(&hashes[..2], LeafEncoding::default()).unwrap().iter().filter(|sequence| sequence.is_some()).count(), 2);
+            let metadata = HashMetadata { namespace: Some("docs".to_string()), ..Default::default() };
+            store.add_batch_with_metadata(&[Hash512([3, 0, 0, 0, 0, 0, 0, 0])], LeafEncoding::default(), Some(metadata.clone())).unwrap();
+            assert_eq!(store.metadata(&Hash512([3, 0, 0, 0, 0, 0, 0, 0])).unwrap(), Some(metadata));
+            assert!(store.remove_with_encoding(&hashes[0], LeafEncoding::default()).unwrap());
+            assert!(!store.contains(&hashes[0]).unwrap());
+            let nonces = store.add_blinded(&[Hash512([4, 0, 0, 0, 0, 0, 0, 0])], LeafEncoding::default()).unwrap();
+            assert!(store.contains_blinded(&Hash512([4, 0, 0, 0, 0, 0, 0, 0]), &nonces[0], LeafEncoding::default()).unwrap());
+
+            // Merging keeps every hash at a single worker
+            let other = MultiThreadedHashStore::<8, 2>::new(4, SALT).with_partitioning(partitioning);
+            other.add_batch(&hashes[3990..]).unwrap();
+            other.add_hash(Hash512([5, 0, 0, 0, 0, 0, 0, 0])).unwrap();
+            assert_eq!(store.merge(&other).unwrap(), 1);
+            assert_eq!(store.len(), 4004);
+            assert_eq!(store.to_array().unwrap().len(), 4004);
+            // Each worker holds one range of the salted hashes, in order with a prefix of just the worker bits
+            if partitioning == Partitioning::Prefix {
+                assert!(store.to_array().unwrap().is_sorted());
+            }
+        }
+
+        let store = MultiThreadedHashStore::<8, 0>::new(4, SALT);
+        let other = MultiThreadedHashStore::<8, 0>::new(4, SALT).with_partitioning(Partitioning::RoundRobin);
+        assert!(matches!(store.merge(&other), Err(MergeError::PartitioningMismatch)));
+    }
+
+    #[test]
+    #[should_panic(expected = "needs a PREFIX_SIZE of at least 3")]
+    fn test_prefix_partitioning_needs_prefix() {
+        MultiThreadedHashStore::<8, 2>::new(8, SALT).with_partitioning(Partitioning::Prefix);
+    }
+
+    #[test]
+    fn test_store_limits() {
+        let store = HashStore::<8, 0>::new(SALT).with_limits(StoreLimits { max_hashes: Some(2), max_memory: None });
+        assert!(store.add_hash(Hash512([1u64, 0, 0, 0, 0, 0, 0, 0])).unwrap());
+        assert!(store.add_hash(Hash512([2u64, 0, 0, 0, 0, 0, 0, 0])).unwrap());
+        assert_eq!(store.add_hash(Hash512([3u64, 0, 0, 0, 0, 0, 0, 0])), Err(StorageError::MaxHashes(2)));
+        // Stored hashes can still be resubmitted
+        assert_eq!(store.add_hash(Hash512([1u64, 0, 0, 0, 0, 0, 0, 0])), Ok(false));
+        assert_eq!(store.len(), 2);
+
+        let memory = store.memory_usage();
+        assert_eq!(memory.buckets, 256 * size_of::<Bucket>());
+        assert!(memory.nodes >= 2 * size_of::<Hash512>());
+        let store = HashStore::<8, 0>::new(SALT).with_limits(StoreLimits { max_hashes: None, max_memory: Some(memory.nodes) });
+        assert!(store.add_hash(Hash512([1u64, 0, 0, 0, 0, 0, 0, 0])).unwrap());
+        assert!(store.add_hash(Hash512([2u64, 0, 0, 0, 0, 0, 0, 0])).unwrap());
+        assert_eq!(store.add_hash(Hash512([3u64, 0, 0, 0, 0, 0, 0, 0])), Err(StorageError::MaxMemory(memory.nodes)));
+
+        // Every worker gets an equal share of the limit, a full worker fails the batch but keeps what it added
+        let service = TimestampingService::<8, 0>::with_threads(2)
+            .with_store_limits(StoreLimits { max_hashes: Some(4), max_memory: None });
+        let hashes: Vec<Hash512> = [0, 1, 1 << 63].iter().map(|&i| Hash512([i, 0, 0, 0, 0, 0, 0, 0])).collect();
+        assert_eq!(service.hash_store.add_batch(&hashes).unwrap(), vec![true; 3]);
+        assert_eq!(service.hash_store.add_hash(Hash512([2u64, 0, 0, 0, 0, 0, 0, 0])), Err(StorageError::MaxHashes(2)));
+        assert_eq!(service.hash_store.add_blinded(&hashes[..1], LeafEncoding::V1), Err(StorageError::MaxHashes(2)));
+        let batch = [Hash512([3u64, 0, 0, 0, 0, 0, 0, 0]), Hash512([(1 << 63) + 1, 0, 0, 0, 0, 0, 0, 0])];
+        assert_eq!(service.hash_store.add_batch(&batch), Err(StorageError::MaxHashes(2)));
+        assert!(service.hash_store.contains(&batch[1]).unwrap());
+        assert_eq!(service.hash_store.len(), 4);
+        assert_eq!(service.hash_store.limits().max_hashes, Some(4));
+
+        assert_eq!(service.memory_usage().merkle_trees, 0);
+        service.update_merkle_tree().unwrap();
+        let memory = service.memory_usage();
+        assert!(memory.merkle_trees >= 4 * size_of::<Hash512>());
+        assert_eq!(memory.buckets, 2 * 256 * size_of::<Bucket>());
+        assert_eq!(memory.total(), memory.buckets + memory.nodes + memory.metadata + memory.merkle_trees);
+    }
+
+    #[test]
+    fn test_chain_limit() {
+        let hashes: Vec<Hash512> = (0..32u64).map(|i| Hash512([i, 0, 0, 0, 0, 0, 0, 0])).collect();
+        let longest_chain = |store: &HashStore<2, 0>| store.chain_length_histogram().iter().rposition(|&count| count > 0).unwrap();
+
+        // New hashes for a full bucket are rejected, stored ones can still be resubmitted
+        let store = HashStore::<2, 0>::new(SALT);
+        store.set_chain_limit(Some(ChainLimit { max_length: 2, overflow: ChainOverflow::Reject }));
+        let results: Vec<_> = hashes.iter().map(|hash| store.add_hash(*hash)).collect();
+        let rejected = results.iter().filter(|result| **result == Err(StorageError::BucketFull(2))).count();
+        assert_eq!(store.len() + rejected, 32);
+        assert!(rejected >= 24);
+        assert_eq!(store.chain_overflows(), rejected);
+        assert_eq!(longest_chain(&store), 2);
+        let stored = results.iter().position(|result| *result == Ok(true)).unwrap();
+        assert_eq!(store.add_hash(hashes[stored]), Ok(false));
+
+        // Or spilled next to the buckets, where they are found, deduplicated and listed like the others
+        let store = HashStore::<2, 0>::new(SALT);
+        store.set_chain_limit(Some(ChainLimit { max_length: 2, overflow: ChainOverflow::Spill }));
+        assert!(hashes.iter().all(|hash| store.add_hash(*hash).unwrap()));
+        assert_eq!(store.len(), 32);
+        assert!(store.spilled_len() >= 24);
+        assert_eq!(store.chain_overflows(), store.spilled_len());
+        assert_eq!(longest_chain(&store), 2);
+        assert!(hashes.iter().all(|hash| store.contains(hash) && store.sequence(hash).is_some()));
+        assert!(hashes.iter().all(|hash| !store.add_hash(*hash).unwrap()));
+        let mut leaves = store.to_array();
+        sort_leaves(&mut leaves);
+        leaves.dedup();
+        assert_eq!(leaves.len(), 32);
+        assert_eq!(store.iter().count(), 32);
+        assert_eq!(store.iter_range(30, 10, None).len(), 2);
+        assert!(store.memory_usage().nodes >= store.spilled_len() * SPILLED_ENTRY_SIZE);
+        // They stay spilled when the table grows
+        assert!(store.grow());
+        assert!(hashes.iter().all(|hash| !store.add_hash(*hash).unwrap()));
+        assert_eq!(store.check_buckets(), (32, Vec::new()));
+
+        // Or inserted anyway, growing the table up to the maximum index size of the resize policy
+        let store = HashStore::<2, 0>::new(SALT);
+        store.set_resize_policy(Some(ResizePolicy { max_chain_length: 100, max_index_size: 3 }));
+        store.set_chain_limit(Some(ChainLimit { max_length: 2, overflow: ChainOverflow::Resize }));
+        let mut added = hashes.iter();
+        while store.chain_overflows() == 0 {
+            assert!(!store.needs_resize());
+            store.add_hash(*added.next().unwrap()).unwrap();
+        }
+        assert_eq!(longest_chain(&store), 3);
+        assert!(store.needs_resize());
+        assert!(store.grow());
+        assert!(!store.needs_resize());
+        for hash in added {
+            store.add_hash(*hash).unwrap();
+        }
+        // At the maximum index size, hashes go to full buckets without growing the table
+        assert_eq!(store.len(), 32);
+        assert!(longest_chain(&store) > 2);
+        assert!(!store.needs_resize());
+
+        // Rejections are counted in the stats of the workers
+        let service = TimestampingService::<2, 0>::with_threads(2)
+            .with_chain_limit(ChainLimit { max_length: 1, overflow: ChainOverflow::Reject });
+        assert_eq!(service.hash_store.add_batch(&hashes), Err(StorageError::BucketFull(1)));
+        assert!(service.hash_store.stats().chain_overflows > 0);
+    }
+
+    #[test]
+    fn test_salt_batch() {
+        let hashes: Vec<Hash512> = (0..5000u64).map(|i| Hash512([i, 1, 2, 3, 4, 5, 6, 7])).collect();
+        for hasher in [Arc::new(Sha512Hasher) as Arc<dyn Hasher>, Arc::new(Blake3Hasher)] {
+            for encoding in [LeafEncoding::V1, LeafEncoding::V2] {
+                let serial: Vec<Hash512> = hashes.iter().map(|hash| encoding.leaf_with_hasher(&*hasher, hash, &SALT)).collect();
+                assert_eq!(salt_batch(&*hasher, &hashes, &SALT, encoding, 1), serial);
+                assert_eq!(salt_batch(&*hasher, &hashes, &SALT, encoding, 8), serial);
+            }
+        }
+        assert!(salt_batch(&Sha512Hasher, &[], &SALT, LeafEncoding::V1, 8).is_empty());
+
+        // Batches salted before reaching the workers end up as the same leaves
+        let store = MultiThreadedHashStore::<8, 0>::new(2, SALT);
+        store.set_salting_threads(4);
+        assert_eq!(store.add_batch_with_encoding(&hashes, LeafEncoding::V2).unwrap(), vec![true; hashes.len()]);
+        assert!(hashes.iter().all(|hash| store.contains_with_encoding(hash, LeafEncoding::V2).unwrap()));
+        assert!(!store.contains_with_encoding(&hashes[0], LeafEncoding::V1).unwrap());
+    }
+
+    #[test]
+    fn test_grow() {
+        let store = HashStore::<4, 0>::new(SALT);
+        let hashes: Vec<Hash512> = (0..1000u64).map(|i| Hash512([i.wrapping_mul(0x9e37_79b9_7f4a_7c15), i, 0, 0, 0, 0, 0, 0])).collect();
+        for hash in &hashes {
+            store.add_hash(*hash).unwrap();
+        }
+        assert!(!store.needs_resize());
+        store.set_resize_policy(Some(ResizePolicy { max_chain_length: 8, max_index_size: 6 }));
+        assert!(store.needs_resize());
+
+        let array = store.to_array();
+        assert!(store.grow());
+        assert_eq!(store.index_size(), 5);
+        assert_eq!(store.bucket_slots(), 32);
+        assert_eq!(store.resize_progress(), None);
+        // Splitting keeps the order and with it the roots of trees built from the store
+        assert_eq!(store.to_array(), array);
+        assert!(hashes.iter().all(|hash| store.contains(hash)));
+        assert_eq!(store.memory_usage().buckets, 32 * size_of::<Bucket>());
+        let usage: Vec<ShardUsage> = store.shards.iter().map(|shard| shard.read().unwrap().usage()).collect();
+        assert_eq!(usage.iter().map(|usage| usage.buckets_filled).sum::<usize>(), store.occupied_slots());
+        assert_eq!(usage.iter().map(|usage| usage.node_bytes).sum::<usize>(), store.memory_usage().nodes);
+        let chain_lengths: Vec<usize> = (0..CHAIN_LENGTH_HISTOGRAM_SIZE).map(|len| usage.iter().map(|usage| usage.chain_lengths[len]).sum()).collect();
+        assert_eq!(store.chain_length_histogram(), chain_lengths);
+        assert_eq!(chain_lengths.iter().sum::<usize>(), 32);
+
+        assert!(store.add_hash(Hash512([1, 2, 3, 4, 5, 6, 7, 8])).unwrap());
+        assert!(store.grow());
+        assert!(!store.needs_resize());
+        assert_eq!(store.len(), 1001);
+        assert!(store.contains(&Hash512([1, 2, 3, 4, 5, 6, 7, 8])));
+
+        // Stores with a prefix grow as well, into the order of a table built with the larger index
+        let store = HashStore::<4, 8>::new(SALT);
+        let larger = HashStore::<5, 8>::new(SALT);
+        for hash in &hashes {
+            store.add_hash(*hash).unwrap();
+            larger.add_hash(*hash).unwrap();
+        }
+        assert!(store.grow());
+        assert_eq!(store.to_array(), larger.to_array());
+
+        // A table starting smaller than INDEX_SIZE holds the same hashes in the same order
+        let store = HashStore::<10, 8>::with_index_size(SALT, Arc::new(Sha512Hasher), 6);
+        let smaller = HashStore::<6, 8>::new(SALT);
+        for hash in &hashes {
+            store.add_hash(*hash).unwrap();
+            smaller.add_hash(*hash).unwrap();
+        }
+        assert_eq!((store.index_size(), store.memory_usage().buckets), (6, 64 * size_of::<Bucket>()));
+        assert_eq!(store.to_array(), smaller.to_array());
+    }
+
+    #[test]
+    fn test_bucket_salt_rotation() {
+        // Salted hashes an attacker who knows the salt can produce, all with the same top 12 bits
+        let store = HashStore::<12, 0>::new(SALT);
+        let crafted: Vec<Hash512> = (0..128u64).map(|i| Hash512([(0xabc << 52) | i, i, 0, 0, 0, 0, 0, 0])).collect();
+        for hash in &crafted {
+            assert!(store.add_salted_hash(*hash));
+        }
+        let longest = CHAIN_LENGTH_HISTOGRAM_SIZE - 1;
+        assert_eq!(store.chain_length_histogram()[longest], 1);
+        let mut array = store.to_array();
+
+        // A bucket salt spreads them over the 64 buckets of their shard
+        assert!(store.rotate_bucket_salt());
+        assert_eq!(store.chain_length_histogram()[longest], 0);
+        assert!(crafted.iter().all(|hash| store.contains_salted(hash)));
+        assert_eq!(store.len(), 128);
+        let mut rotated = store.to_array();
+        rotated.sort();
+        array.sort();
+        assert_eq!(rotated, array);
+        let usage: Vec<ShardUsage> = store.shards.iter().map(|shard| shard.read().unwrap().usage()).collect();
+        assert_eq!(usage.iter().map(|usage| usage.buckets_filled).sum::<usize>(), store.occupied_slots());
+        assert_eq!(usage.iter().map(|usage| usage.node_bytes).sum::<usize>(), store.memory_usage().nodes);
+        let chain_lengths: Vec<usize> = (0..CHAIN_LENGTH_HISTOGRAM_SIZE).map(|len| usage.iter().map(|usage| usage.chain_lengths[len]).sum()).collect();
+        assert_eq!(store.chain_length_histogram(), chain_lengths);
+
+        // Knowing the bucket salt as well, they can fill one bucket again, but only until the next rotation
+        let shard_index = HashStore::<12, 0>::shard_index(&crafted[0]);
+        let leaked: Vec<Hash512> = {
+            let shard = store.shards[shard_index].read().unwrap();
+            (0..).map(|i: u64| Hash512([(0xabc << 52) | i, 1 << 32, i, 0, 0, 0, 0, 0]))
+                .filter(|hash| HashStore::<12, 0>::position(&shard, hash) == 0)
+                .take(32)
+                .collect()
+        };
+        for hash in &leaked {
+            assert!(store.add_salted_hash(*hash));
+        }
+        assert_eq!(store.chain_length_histogram()[longest], 1);
+        assert!(store.rotate_bucket_salt());
+        assert_eq!(store.chain_length_histogram()[longest], 0);
+        assert!(crafted.iter().chain(&leaked).all(|hash| store.contains_salted(hash)));
+
+        // Growing keeps the bucket salt
+        store.set_resize_policy(Some(ResizePolicy { max_chain_length: 0, max_index_size: 13 }));
+        assert!(store.grow());
+        assert_eq!(store.index_size(), 13);
+        assert_eq!(store.chain_length_histogram()[longest], 0);
+        assert!(crafted.iter().chain(&leaked).all(|hash| store.contains_salted(hash)));
+        assert!(store.shards.iter().all(|shard| shard.read().unwrap().bucket_salt.is_some()));
+
+        // Workers keep answering while they rotate in the background
+        let store = MultiThreadedHashStore::<8, 0>::new(2, SALT);
+        let hashes: Vec<Hash512> = (0..100u64).map(|i| Hash512([i << 56, i, 0, 0, 0, 0, 0, 0])).collect();
+        store.add_batch(&hashes).unwrap();
+        store.rotate_bucket_salts();
+        assert!(hashes.iter().all(|hash| store.contains(hash).unwrap()));
+        assert_eq!(store.len(), 100);
+    }
+
+    #[test]
+    fn test_grow_in_background() {
+        let store = MultiThreadedHashStore::<2, 0>::new(2, SALT);
+        let unresized = MultiThreadedHashStore::<2, 0>::new(2, SALT);
+        store.set_resize_policy(Some(ResizePolicy { max_chain_length: 4, max_index_size: 8 }));
+        let hashes: Vec<Hash512> = (0..2000u64).map(|i| Hash512([i.wrapping_mul(0x9e37_79b9_7f4a_7c15), i, 0, 0, 0, 0, 0, 0])).collect();
+        for chunk in hashes.chunks(100) {
+            store.add_batch(chunk).unwrap();
+            unresized.add_batch(chunk).unwrap();
+        }
+
+        let start = Instant::now();
+        while store.stats().worker_index_sizes != vec![8, 8] {
+            assert!(start.elapsed() < Duration::from_secs(10), "tables didn't grow: {:?}", store.stats());
+            std::thread::sleep(Duration::from_millis(10));
+            // The size is checked after every command
+            store.contains(&hashes[0]).unwrap();
+        }
+        let stats = store.stats();
+        assert!(stats.resizes.is_empty());
+        assert_eq!(stats.bucket_slots, 2 * 256);
+        assert_eq!(stats.memory.buckets, 2 * 256 * size_of::<Bucket>());
+        assert_eq!(unresized.stats().worker_index_sizes, vec![2, 2]);
+        assert_eq!(store.to_array(), unresized.to_array());
+        assert!(hashes.iter().all(|hash| store.contains(hash).unwrap()));
+    }
+
+    // Clock stuck at a fixed time, attested as coming from a server
+    #[derive(Debug)]
+    struct FixedTimeSource;
+
+    impl TimeSource for FixedTimeSource {
+        fn now(&self) -> (SystemTime, TimeAttestation) {
+            let attestation = TimeAttestation { source: "ntp".to_string(), server: Some("time.example:123".to_string()), radius_ms: Some(5), roughtime_nonce: None, roughtime_response: None };
+            (UNIX_EPOCH + Duration::from_secs(1_700_000_000), attestation)
+        }
+    }
+
+    #[test]
+    fn test_time_source() {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let service = TimestampingService::<8, 0>::with_threads(2)
+            .with_signing_key(key.clone())
+            .with_time_source(Arc::new(FixedTimeSource));
+        service.hash_store.add_hash(Hash512([1, 2, 3, 4, 5, 6, 7, 8])).unwrap();
+        std::thread::sleep(Duration::from_millis(50));
+        service.update_merkle_tree().unwrap();
+
+        let summary = service.epochs.read().unwrap().latest().cloned().unwrap();
+        assert_eq!(summary.timestamp, 1_700_000_000);
+        let attestation = summary.time_source.unwrap();
+        assert_eq!(attestation.summary(), "ntp time.example:123 ±5ms");
+
+        // The attestation is part of the signed tree head
+        let signature = ed25519_dalek::Signature::from_slice(&summary.signature.unwrap()).unwrap();
+        let message = crate::receipt::tree_head_message("sha512", summary.epoch, 1, summary.timestamp, &summary.root.unwrap(), Some(&attestation.summary()));
+        assert!(ed25519_dalek::Verifier::verify(&key.verifying_key(), &message, &signature).is_ok());
+        let unattested = crate::receipt::tree_head_message("sha512", summary.epoch, 1, summary.timestamp, &summary.root.unwrap(), None);
+        assert!(ed25519_dalek::Verifier::verify(&key.verifying_key(), &unattested, &signature).is_err());
+    }
+
+    #[test]
+    fn test_get_receipt() {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let service = TimestampingService::<8, 0>::with_threads(2).with_signing_key(key.clone());
+        let hash = Hash512([1, 2, 3, 4, 5, 6, 7, 8]);
+        service.hash_store.add_hash(hash).unwrap();
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(service.get_receipt(&hash, LeafEncoding::V1).is_none());
+
+        service.update_merkle_tree().unwrap();
+        let receipt = service.get_receipt(&hash, LeafEncoding::V1).unwrap();
+        assert_eq!(receipt.tree_version, service.get_merkle_tree_version());
+        assert!(receipt.verify(&key.verifying_key()).is_ok());
+        assert!(service.get_receipt(&Hash512([8, 7, 6, 5, 4, 3, 2, 1]), LeafEncoding::V1).is_none());
+    }
+
+    #[test]
+    fn test_acknowledge() {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let service = TimestampingService::<8, 0>::with_threads(2);
+        let hashes = [Hash512([1, 2, 3, 4, 5, 6, 7, 8]), Hash512([8, 7, 6, 5, 4, 3, 2, 1]), Hash512([1, 2, 3, 4, 5, 6, 7, 8])];
+        service.hash_store.add_batch_deduplicated(&hashes[..2], LeafEncoding::V1, None).unwrap();
+        assert!(service.acknowledge(&hashes[..2], LeafEncoding::V1).unwrap().is_empty());
+
+        let service = service.with_signing_key(key.clone());
+        service.hash_store.add_batch_deduplicated(&hashes, LeafEncoding::V2, None).unwrap();
+        let acknowledgments = service.acknowledge(&hashes, LeafEncoding::V2).unwrap();
+        let mut sequences: Vec<u64> = acknowledgments.iter().map(|ack| ack.sequence.unwrap()).collect();
+        assert_eq!(sequences[0], sequences[2]);
+        sequences.sort();
+        sequences.dedup();
+        assert_eq!(sequences, vec![2, 3]);
+        assert_eq!(acknowledgments[0], acknowledgments[2]);
+        assert!(acknowledgments.iter().all(|ack| ack.verify(&key.verifying_key()).is_ok() && ack.leaf_version == 2));
+
+        // Hashes stored before keep their number
+        let again = service.acknowledge(&hashes[..1], LeafEncoding::V2).unwrap();
+        assert_eq!((again[0].hash, again[0].sequence), (hashes[0], acknowledgments[0].sequence));
+        assert_eq!(service.acknowledge(&[Hash512([9; 8])], LeafEncoding::V2).unwrap()[0].sequence, None);
+    }
+
+    #[test]
+    fn test_sequence_numbers() {
+        let dir = std::env::temp_dir().join(format!("timestamping-test-sequences-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let hashes: Vec<Hash512> = (0..20u64).map(|i| Hash512([i, i * 7, 0, 0, 0, 0, 0, i << 60])).collect();
+        let store = MultiThreadedHashStore::<8, 0>::with_leaf_logs(4, SALT, &dir).unwrap();
+        for hash in &hashes[..10] {
+            store.add_hash(*hash).unwrap();
+        }
+        store.add_batch(&hashes[10..]).unwrap();
+        // Hashes added one after another are numbered in that order, across workers
+        let sequences: Vec<u64> = hashes.iter().map(|hash| store.sequence_with_encoding(hash, LeafEncoding::V1).unwrap().unwrap()).collect();
+        assert_eq!(sequences[..10], (0..10).collect::<Vec<u64>>());
+        let mut batch = sequences[10..].to_vec();
+        batch.sort();
+        assert_eq!(batch, (10..20).collect::<Vec<u64>>());
+        assert!(store.remove_with_encoding(&hashes[0], LeafEncoding::V1).unwrap());
+        assert_eq!(store.sequence_with_encoding(&hashes[0], LeafEncoding::V1).unwrap(), None);
+        let lengths = store.snapshot().unwrap().leaf_log_lengths;
+        store.shutdown();
+
+        // Each log is in sequence order, and no number is in two logs
+        let logged = read_leaf_sequences(&dir, &lengths).unwrap();
+        assert!(logged.iter().all(|log| log.is_sorted()));
+        let mut all: Vec<u64> = logged.concat();
+        all.sort();
+        assert_eq!(all, (0..20).collect::<Vec<u64>>());
+
+        // Restored with their numbers, new hashes continue after the highest
+        let store = MultiThreadedHashStore::<8, 0>::open(4, SALT, &dir, Arc::new(Sha512Hasher)).unwrap();
+        assert_eq!(store.sequence_with_encoding(&hashes[5], LeafEncoding::V1).unwrap(), Some(5));
+        store.add_hash(Hash512([1; 8])).unwrap();
+        assert_eq!(store.sequence_with_encoding(&Hash512([1; 8]), LeafEncoding::V1).unwrap(), Some(20));
+        store.shutdown();
+
+        // Logs from before sequence numbers were recorded get them on restore
+        for thread_index in 0..4 {
+            std::fs::remove_file(LeafLog::sequence_path(&dir, thread_index)).unwrap();
+        }
+        let store = MultiThreadedHashStore::<8, 0>::open(4, SALT, &dir, Arc::new(Sha512Hasher)).unwrap();
+        let mut restored: Vec<u64> = hashes[1..].iter().chain([&Hash512([1; 8])]).map(|hash| store.sequence_with_encoding(hash, LeafEncoding::V1).unwrap().unwrap()).collect();
+        restored.sort();
+        restored.dedup();
+        assert_eq!((restored.len(), restored.last()), (20, Some(&20)));
+        let lengths = store.snapshot().unwrap().leaf_log_lengths;
+        store.shutdown();
+        assert_eq!(read_leaf_sequences(&dir, &lengths).unwrap().concat().len(), 21);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_cold_storage() {
+        let dir = std::env::temp_dir().join(format!("timestamping-test-cold-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let service = TimestampingService::<8, 0>::with_leaf_logs(2, &dir).unwrap().with_cold_storage(1);
+        let hashes: Vec<Hash512> = (0..200u64).map(|i| Hash512([i << 56, i, 0, 0, 0, 0, 0, 0])).collect();
+        service.hash_store.add_batch(&hashes[..100]).unwrap();
+        service.update_merkle_tree().unwrap();
+        assert_eq!(service.hash_store.stats().cold_hashes, 0);
+        let nodes = service.hash_store.stats().memory.nodes;
+
+        // The hashes of the first tree are moved once a second tree is published
+        service.hash_store.add_batch(&hashes[100..]).unwrap();
+        service.update_merkle_tree().unwrap();
+        let root = service.get_merkle_tree_root();
+        let stats = service.hash_store.stats();
+        assert_eq!((stats.cold_hashes, stats.hashes), (100, 200));
+        assert!(stats.memory.nodes < 2 * nodes);
+        assert!(stats.memory.cold_index > 0);
+        assert!(service.hash_store.contains(&hashes[3]).unwrap());
+        assert_eq!(service.hash_store.sequence_with_encoding(&hashes[3], LeafEncoding::default()).unwrap(), Some(3));
+        assert_eq!(service.hash_store.add_batch(&hashes[..2]).unwrap(), vec![false; 2]);
+        assert!(service.get_merkle_proof(&hashes[3]).is_some());
+        service.update_merkle_tree().unwrap();
+        assert_eq!(service.get_merkle_tree_root(), root);
+        let stats = service.hash_store.stats();
+        assert_eq!(stats.cold_hashes, 200);
+        // No hash is left in a bucket
+        assert_eq!(stats.chain_lengths[0], stats.bucket_slots);
+        service.shutdown();
+
+        // Restored from the cold index and the rest of the leaf logs
+        let store = MultiThreadedHashStore::<8, 0>::open(2, *service.hash_store.salt(), &dir, Arc::new(Sha512Hasher)).unwrap();
+        assert_eq!(store.len(), 200);
+        assert_eq!(store.stats().cold_hashes, 200);
+        assert!(hashes.iter().all(|hash| store.contains(hash).unwrap()));
+        assert_eq!(store.add_hash(Hash512([7; 8])), Ok(true));
+        assert_eq!(store.sequence_with_encoding(&Hash512([7; 8]), LeafEncoding::default()).unwrap(), Some(200));
+        store.shutdown();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_queue_capacity() {
+        let store = MultiThreadedHashStore::<8, 0>::new(2, SALT);
+        assert_eq!(store.queue_capacity(), DEFAULT_QUEUE_CAPACITY);
+        store.set_queue_capacity(1);
+        let hashes = [Hash512([0u64, 0, 0, 0, 0, 0, 0, 0]), Hash512([1 << 63, 0, 0, 0, 0, 0, 0, 0])];
+
+        // A saturated worker refuses the whole batch, also the hashes of the other worker
+        assert!(store.threads[1].reserve(1));
+        assert_eq!(store.stats().worker_queue_depths, vec![0, 1]);
+        assert_eq!(store.add_batch(&hashes), Err(StorageError::Saturated(1)));
+        assert_eq!(store.add_blinded(&hashes, LeafEncoding::V1), Err(StorageError::Saturated(1)));
+        assert_eq!(store.add_hash(hashes[1]), Err(StorageError::Saturated(1)));
+        assert_eq!(store.stats().worker_queue_depths, vec![0, 1]);
+        assert_eq!(store.len(), 0);
+        // Lookups still queue
+        assert!(!store.contains(&hashes[1]).unwrap());
+
+        store.threads[1].release();
+        assert_eq!(store.add_batch(&hashes).unwrap(), vec![true; 2]);
+        assert_eq!(store.stats().worker_queue_depths, vec![0, 0]);
+
+        store.set_queue_capacity(0);
+        assert_eq!(store.add_hash(hashes[0]), Err(StorageError::Saturated(0)));
+    }
+
+    #[test]
+    fn test_worker_faults() {
+        let store = MultiThreadedHashStore::<8, 0>::new(2, SALT);
+        let hashes = [Hash512([0u64, 0, 0, 0, 0, 0, 0, 0]), Hash512([1 << 63, 0, 0, 0, 0, 0, 0, 0])];
+
+        // A lost answer fails the add instead of reporting the hash as stored
+        store.inject_fault(1, Fault::DropResponses(1));
+        assert_eq!(store.add_hash(hashes[1]), Err(StorageError::WorkerUnavailable(1)));
+        assert!(!store.contains(&hashes[1]).unwrap());
+        assert_eq!(store.add_hash(hashes[1]), Ok(true));
+
+        // So do lookups and removals, rather than reporting the hash as missing
+        store.inject_fault(1, Fault::DropResponses(3));
+        assert_eq!(store.contains(&hashes[1]), Err(StorageError::WorkerUnavailable(1)));
+        assert_eq!(store.contains_batch_with_encoding(&hashes, LeafEncoding::default()), Err(StorageError::WorkerUnavailable(1)));
+        assert_eq!(store.remove_with_encoding(&hashes[1], LeafEncoding::default()), Err(StorageError::WorkerUnavailable(1)));
+        assert_eq!(store.contains_batch_with_encoding(&hashes, LeafEncoding::default()), Ok(vec![false, true]));
+
+        // A slow worker only slows down its own hashes
+        store.inject_fault(0, Fault::Delay(Duration::from_millis(50)));
+        let start = Instant::now();
+        assert_eq!(store.add_hash(Hash512([2; 8])), Ok(true));
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        store.inject_fault(0, Fault::Delay(Duration::ZERO));
+
+        // A worker that panicked is restarted with its hashes
+        store.inject_fault(1, Fault::Panic);
+        assert_eq!(store.add_hash(Hash512([1 << 63; 8])), Ok(true));
+        assert!(store.contains(&hashes[1]).unwrap());
+        assert_eq!(store.add_batch(&[Hash512([2; 8]), hashes[0]]).unwrap(), vec![false, true]);
+        let stats = store.stats();
+        assert_eq!((stats.worker_restarts, stats.worker_queue_depths), (vec![0, 1], vec![0, 0]));
+
+        // A worker that doesn't answer in time is reported as unhealthy
+        assert_eq!(store.worker_health(Duration::from_secs(1)), vec![true; 2]);
+        store.inject_fault(0, Fault::Delay(Duration::from_millis(200)));
+        assert_eq!(store.worker_health(Duration::from_millis(50)), vec![false, true]);
+        store.inject_fault(0, Fault::Delay(Duration::ZERO));
+    }
+
+    #[test]
+    fn test_worker_recovery() {
+        let dir = std::env::temp_dir().join(format!("timestamping-test-recovery-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let store = MultiThreadedHashStore::<8, 0>::with_leaf_logs(2, SALT, &dir).unwrap();
+        store.set_limits(StoreLimits { max_hashes: Some(8), max_memory: None });
+        let hashes: Vec<Hash512> = (0..5u64).map(|i| Hash512([1 << 63 | i, 0, 0, 0, 0, 0, 0, 0])).collect();
+        let metadata = HashMetadata::new(Some("alice".to_string()), None, None);
+        store.add_batch_with_metadata(&hashes[..3], LeafEncoding::default(), Some(metadata.clone())).unwrap();
+        assert!(store.remove_with_encoding(&hashes[0], LeafEncoding::default()).unwrap());
+        let sequence = store.sequence_with_encoding(&hashes[1], LeafEncoding::default());
+        assert_eq!(store.add_hash(hashes[2]), Ok(false));
+
+        // Rebuilt from the logs, with the tombstones, sequence numbers, metadata, resubmissions and limits it had
+        store.inject_fault(1, Fault::Panic);
+        assert!(!store.contains(&hashes[0]).unwrap());
+        assert!(store.contains(&hashes[1]).unwrap() && store.contains(&hashes[2]).unwrap());
+        assert_eq!(store.sequence_with_encoding(&hashes[1], LeafEncoding::default()), sequence);
+        assert_eq!(store.metadata(&hashes[1]).unwrap(), Some(metadata));
+        assert_eq!(store.metadata(&hashes[0]).unwrap(), None);
+        assert_eq!(store.resubmissions_with_encoding(&hashes[2], LeafEncoding::default()).unwrap().map(|resubmissions| resubmissions.count), Some(1));
+        assert_eq!(store.add_hash(hashes[3]), Ok(true));
+        assert_eq!(store.add_hash(hashes[4]), Err(StorageError::MaxHashes(4)));
+        assert_eq!(store.stats().worker_restarts, vec![0, 1]);
+        store.shutdown();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_multi_threaded_hash_store_stats() {
+        let store = MultiThreadedHashStore::<8, 0>::new(4, SALT);
+        assert_eq!(store.stats(), StoreStats {
+            worker_hashes: vec![0; 4],
+            worker_queue_depths: vec![0; 4],
+            memory: MemoryUsage { buckets: 4 * 256 * size_of::<Bucket>(), ..Default::default() },
+            bucket_slots: 4 * 256,
+            worker_index_sizes: vec![8; 4],
+            worker_restarts: vec![0; 4],
+            chain_lengths: {
+                let mut chain_lengths = vec![0; CHAIN_LENGTH_HISTOGRAM_SIZE];
+                chain_lengths[0] = 4 * 256;
+                chain_lengths
+            },
+            ..Default::default()
+        });
+
+        store.add_hash(Hash512([0, 0, 0, 0, 0, 0, 0, 0])).unwrap();
+        store.add_hash(Hash512([0, 0, 0, 0, 0, 0, 0, 0])).unwrap();
+        store.add_batch(&[Hash512([3 << 62, 0, 0, 0, 0, 0, 0, 0]), Hash512([(3 << 62) + 1, 0, 0, 0, 0, 0, 0, 0]), Hash512([0, 0, 0, 0, 0, 0, 0, 0])]).unwrap();
+
+        let stats = store.stats();
+        assert_eq!(stats.hashes, 3);
+        assert_eq!(stats.adds, 5);
+        assert_eq!(stats.duplicates, 2);
+        assert_eq!(stats.worker_hashes, vec![1, 0, 0, 2]);
+        assert_eq!(stats.occupied_slots, store.occupied_slots());
+        assert_eq!(store.len(), 3);
+        assert_eq!(stats.chain_lengths.iter().sum::<usize>(), stats.bucket_slots);
+        assert_eq!(stats.chain_lengths.iter().enumerate().map(|(len, count)| len * count).sum::<usize>(), 3);
+    }
+
+    #[test]
+    fn test_bloom_filter() {
+        let store = MultiThreadedHashStore::<8, 0>::new(4, SALT);
+        let stored: Vec<Hash512> = (0..100u64).map(|i| Hash512([i << 56, i, 0, 0, 0, 0, 0, 0])).collect();
+        store.add_batch(&stored[..50]).unwrap();
+        // Hashes stored before the filter is set are inserted by the workers
+        store.set_bloom_filter(BloomFilter::new(1000, 0.01));
+        store.add_batch(&stored[50..]).unwrap();
+        assert!(stored.iter().all(|hash| store.contains(hash).unwrap()));
+
+        let missing: Vec<Hash512> = (0..1000u64).map(|i| Hash512([i << 54, i, 1, 0, 0, 0, 0, 0])).collect();
+        assert_eq!(store.contains_batch_with_encoding(&missing, LeafEncoding::default()).unwrap(), vec![false; 1000]);
+        assert_eq!(store.contains_batch_with_encoding(&stored, LeafEncoding::default()).unwrap(), vec![true; 100]);
+
+        let stats = store.stats();
+        let filter = stats.bloom_filter.unwrap();
+        assert_eq!(filter.items, 100);
+        assert_eq!(filter.negatives + filter.false_positives, 1000);
+        assert!(filter.false_positives < 10, "{}", filter.false_positives);
+        assert_eq!(stats.memory.bloom_filter, filter.bits / 8);
+
+        // Blinded hashes and removed hashes stay in the filter
+        let nonces = store.add_blinded(&missing[..1], LeafEncoding::V1).unwrap();
+        assert!(store.contains_blinded(&missing[0], &nonces[0], LeafEncoding::V1).unwrap());
+        assert!(store.remove_with_encoding(&stored[0], LeafEncoding::default()).unwrap());
+        assert!(!store.contains(&stored[0]).unwrap());
+        assert_eq!(store.stats().bloom_filter.unwrap().false_positives, filter.false_positives + 1);
+    }
+
+    #[test]
+    fn test_blinded_hashes() {
+        let service = TimestampingService::<8, 0>::with_threads(4);
+        let hashes = [Hash512([1u64, 0, 0, 0, 0, 0, 0, 0]), Hash512([2u64 << 62, 0, 0, 0, 0, 0, 0, 0])];
+        let nonces = service.hash_store.add_blinded(&hashes, LeafEncoding::V2).unwrap();
+        assert_eq!(nonces.len(), 2);
+        assert_ne!(nonces[0], nonces[1]);
+        assert_eq!(service.hash_store.len(), 2);
+
+        // Without the nonce a blinded hash can't be found
+        assert!(!service.hash_store.contains_with_encoding(&hashes[0], LeafEncoding::V2).unwrap());
+        assert!(service.hash_store.contains_blinded(&hashes[0], &nonces[0], LeafEncoding::V2).unwrap());
+        assert!(!service.hash_store.contains_blinded(&hashes[0], &nonces[1], LeafEncoding::V2).unwrap());
+        assert!(!service.hash_store.contains_blinded(&hashes[0], &nonces[0], LeafEncoding::V1).unwrap());
+
+        // Adding the same hash again gives a different leaf
+        let again = service.hash_store.add_blinded(&hashes[..1], LeafEncoding::V2).unwrap();
+        assert_ne!(again[0], nonces[0]);
+        assert_eq!(service.hash_store.len(), 3);
+
+        service.update_merkle_tree().unwrap();
+        let root = service.get_merkle_tree_root().unwrap();
+        for (hash, nonce) in hashes.iter().zip(&nonces) {
+            let proof = service.get_blinded_merkle_proof_at_version(hash, nonce, LeafEncoding::V2, 0).unwrap().unwrap();
+            let proof: Vec<(Hash512, Hash512)> = proof.iter().map(|(l, r)| (Hash512::from_bytes(l).unwrap(), Hash512::from_bytes(r).unwrap())).collect();
+            assert_eq!(proof[0], (*hash, *nonce));
+            assert!(MerkleTree::verify_proof_with_encoding(hash, &proof, &root, LeafEncoding::V2));
+        }
+        assert_eq!(service.get_merkle_proof_with_encoding(&hashes[0], LeafEncoding::V2), None);
+    }
+
+    #[test]
+    fn test_iter_range() {
+        let store = MultiThreadedHashStore::<8, 0>::new(4, SALT);
+        let hashes: Vec<Hash512> = (0..40u64).map(|i| Hash512([i << 58, i, 0, 0, 0, 0, 0, 0])).collect();
+        let metadata = HashMetadata { submitted_at: 100, ..Default::default() };
+        store.add_batch_with_metadata(&hashes[..10], LeafEncoding::V1, Some(metadata.clone())).unwrap();
+        store.add_batch(&hashes[10..30]).unwrap();
+        // The rest is stored a while later, with and without metadata
+        let now = unix_timestamp(SystemTime::now());
+        let later = now + 100;
+        let worker = &store.worker_stores().unwrap()[0];
+        worker.sequence_clock.record(later, &worker.next_sequence);
+        store.add_batch(&hashes[30..35]).unwrap();
+        store.add_batch_with_metadata(&hashes[35..], LeafEncoding::V1, Some(metadata.clone())).unwrap();
+
+        // Pages are consecutive slices of `to_array`
+        let all = store.to_array().unwrap();
+        let listed: Vec<Hash512> = (0..5).flat_map(|page| store.iter_range(page * 9, 9, None).unwrap()).map(|(hash, _)| hash).collect();
+        assert_eq!(listed, all);
+        assert_eq!(store.iter_range(38, 10, None).unwrap().len(), 2);
+        assert!(store.iter_range(40, 10, None).unwrap().is_empty());
+        assert!(store.iter_range(0, 0, None).unwrap().is_empty());
+
+        // Filtering by the time hashes were first stored lists them with and without metadata
+        let recent = store.iter_range(0, 100, Some(later)).unwrap();
+        let mut listed: Vec<Hash512> = recent.iter().map(|(hash, _)| *hash).collect();
+        let mut expected: Vec<Hash512> = hashes[30..].iter().map(|hash| LeafEncoding::V1.leaf(hash, &SALT)).collect();
+        listed.sort();
+        expected.sort();
+        assert_eq!(listed, expected);
+        assert_eq!(recent.iter().filter(|(_, hash_metadata)| hash_metadata.as_ref() == Some(&metadata)).count(), 5);
+        assert_eq!(store.iter_range(4, 100, Some(later)).unwrap(), recent[4..]);
+        assert!(store.iter_range(0, 100, Some(later + 1)).unwrap().is_empty());
+        assert_eq!(store.iter_range(0, 100, Some(now)).unwrap().len(), 40);
+
+        let single = HashStore::<8, 0>::new(SALT);
+        for hash in &hashes {
+            single.add_hash(*hash).unwrap();
+        }
+        let listed: Vec<Hash512> = single.iter_range(5, 20, None).into_iter().map(|(hash, _)| hash).collect();
+        assert_eq!(listed, single.to_array()[5..25]);
+    }
+
+    #[test]
+    fn test_hash_metadata() {
+        let store = MultiThreadedHashStore::<8, 0>::new(4, SALT);
+        let first = HashMetadata {
+            submitter: Some("alice".to_string()),
+            label: Some("contract.pdf".to_string()),
+            content_type: Some("application/pdf".to_string()),
+            submitted_at: 1,
+            namespace: None,
+            hash_algorithm: Some(HashAlgorithm::Sha512),
+        };
+        let second = HashMetadata { submitter: Some("bob".to_string()), ..Default::default() };
+        let with_metadata = Hash512([1u64, 2, 3, 4, 5, 6, 7, 8]);
+        let without_metadata = Hash512([8u64, 7, 6, 5, 4, 3, 2, 1]);
+
+        assert_eq!(store.add_batch_with_metadata(&[with_metadata], LeafEncoding::V1, Some(first.clone())).unwrap(), vec![true]);
+        store.add_hash(without_metadata).unwrap();
+
+        // Only the first submission of a hash stores metadata
+        assert_eq!(store.add_batch_with_metadata(&[with_metadata, without_metadata], LeafEncoding::V1, Some(second)).unwrap(), vec![false, false]);
+        assert_eq!(store.metadata(&with_metadata).unwrap(), Some(first));
+        assert_eq!(store.metadata(&without_metadata).unwrap(), None);
+        assert_eq!(store.metadata_with_encoding(&with_metadata, LeafEncoding::V2).unwrap(), None);
+    }
+
+    #[test]
+    fn test_resubmissions() {
+        let store = MultiThreadedHashStore::<8, 0>::new(4, SALT);
+        let hash = Hash512([1u64, 2, 3, 4, 5, 6, 7, 8]);
+        let start = unix_timestamp(SystemTime::now());
+        assert!(store.add_hash(hash).unwrap());
+        assert_eq!(store.resubmissions_with_encoding(&hash, LeafEncoding::V1).unwrap(), None);
+
+        // Single adds and batches both count, with or without metadata
+        assert!(!store.add_hash(hash).unwrap());
+        store.add_batch_with_metadata(&[hash], LeafEncoding::V1, Some(HashMetadata::default())).unwrap();
+        let resubmissions = store.resubmissions_with_encoding(&hash, LeafEncoding::V1).unwrap().unwrap();
+        assert_eq!(resubmissions.count, 2);
+        assert!(resubmissions.last_seen >= start);
+        assert_eq!(store.resubmissions_with_encoding(&hash, LeafEncoding::V2).unwrap(), None);
+
+        // Forgotten with the hash, and removed hashes don't count
+        assert!(store.remove_with_encoding(&hash, LeafEncoding::V1).unwrap());
+        store.add_hash(hash).unwrap();
+        assert_eq!(store.resubmissions_with_encoding(&hash, LeafEncoding::V1).unwrap(), None);
+    }
+
+    #[test]
+    fn test_add_batch_deduplicated() {
+        let store = MultiThreadedHashStore::<8, 0>::new(4, SALT);
+        let a = Hash512([1u64, 2, 3, 4, 5, 6, 7, 8]);
+        let b = Hash512([8u64, 7, 6, 5, 4, 3, 2, 1]);
+        let c = Hash512([9u64; 8]);
+        store.add_hash(b).unwrap();
+
+        let statuses = store.add_batch_deduplicated(&[a, b, a, c, b], LeafEncoding::V1, None).unwrap();
+        assert_eq!(statuses, vec![AddStatus::New, AddStatus::Existing, AddStatus::Duplicate, AddStatus::New, AddStatus::Duplicate]);
+        assert_eq!(store.len(), 3);
+    }
+
+    #[test]
+    fn test_multi_threaded_hash_store_merge() {
+        let store = MultiThreadedHashStore::<8, 0>::new(4, SALT);
+        let other = MultiThreadedHashStore::<8, 0>::new(4, SALT);
+
+        for i in 0..50 {
+            store.add_hash(Hash512([i << 56, 0, 0, 0, 0, 0, 0, 0])).unwrap();
+        }
+        for i in 25..100 {
+            other.add_hash(Hash512([i << 56, 0, 0, 0, 0, 0, 0, 0])).unwrap();
+        }
+        std::thread::sleep(Duration::from_millis(10));
+
+        assert_eq!(store.merge(&other).unwrap(), 50);
+        assert_eq!(store.len(), 100);
+        for i in 0..100 {
+            assert!(store.contains(&Hash512([i << 56, 0, 0, 0, 0, 0, 0, 0])).unwrap());
+        }
 
-        // Test adding hash
-        assert!(store.add_hash(hash));
-        assert_eq!(store.len(), 1);
-        assert_eq!(store.occupied_slots(), 1);
-        assert!(store.contains(&hash));
+        let metadata = HashMetadata { submitted_at: 100, ..Default::default() };
+        other.add_batch_with_metadata(&[Hash512([200 << 56, 0, 0, 0, 0, 0, 0, 0])], LeafEncoding::default(), Some(metadata.clone())).unwrap();
+        assert_eq!(store.merge(&other).unwrap(), 1);
+        assert_eq!(store.metadata(&Hash512([200 << 56, 0, 0, 0, 0, 0, 0, 0])).unwrap(), Some(metadata));
 
-        // Test adding duplicate
-        assert!(!store.add_hash(hash));
-        assert_eq!(store.len(), 1);
-        assert_eq!(store.occupied_slots(), 1);
+        // The hashes of a worker of a store with fewer threads may belong to any of several workers of this one
+        let two_threads = MultiThreadedHashStore::<8, 0>::new(2, SALT);
+        assert!(matches!(store.merge(&two_threads), Err(MergeError::ShardCountMismatch)));
+        assert_eq!(two_threads.merge(&store).unwrap(), 101);
+    }
 
-        // Test adding different hash
-        let hash2 = [u64::MAX, 10u64, 11u64, 12u64, 13u64, 14u64, 15u64, 16u64];
-        assert!(store.add_hash(hash2));
-        assert_eq!(store.len(), 2);
-        assert_eq!(store.occupied_slots(), 2);
+    #[test]
+    fn test_merge_across_thread_counts() {
+        let hashes: Vec<Hash512> = (0..64).map(|i| Hash512([i << 58 | i, i, 0, 0, 0, 0, 0, 0])).collect();
+        for partitioning in Partitioning::ALL {
+            for (threads, other_threads) in [(2, 4), (4, 2), (1, 4)] {
+                if partitioning == Partitioning::UnsaltedPrefix && threads > other_threads {
+                    continue;
+                }
+                let store = MultiThreadedHashStore::<8, 2>::new(threads, SALT).with_partitioning(partitioning);
+                let other = MultiThreadedHashStore::<8, 2>::new(other_threads, SALT).with_partitioning(partitioning);
+                store.add_batch(&hashes[..16]).unwrap();
+                other.add_batch(&hashes[8..]).unwrap();
+
+                assert_eq!(store.merge(&other).unwrap(), 48, "{partitioning:?} {threads} <- {other_threads}");
+                assert_eq!(store.len(), 64);
+                for hash in &hashes {
+                    assert!(store.contains(hash).unwrap(), "{partitioning:?} {threads} <- {other_threads}");
+                }
+                // Every hash is where a lookup finds it, so merging again adds nothing
+                assert_eq!(store.merge(&other).unwrap(), 0);
+                let mut leaves = store.to_array().unwrap();
+                sort_leaves(&mut leaves);
+                let mut expected = salt_batch(&**store.hasher(), &hashes, &SALT, LeafEncoding::default(), 1);
+                sort_leaves(&mut expected);
+                assert_eq!(leaves, expected);
+            }
+        }
     }
 
     #[test]
-    fn test_hash_store_ordering() {
-        let store = HashStore::<8, 0>::new(SALT);
+    fn test_sorted_parts() {
+        let hashes: Vec<Hash512> = (0..1000u64).map(|i| Hash512([i.wrapping_mul(0x9e37_79b9_7f4a_7c15), i, 0, 0, 0, 0, 0, 0])).collect();
+        let store = MultiThreadedHashStore::<8, 2>::new(4, SALT);
+        store.add_batch(&hashes[..900]).unwrap();
+        let bound = store.next_sequence();
+        store.add_batch(&hashes[900..]).unwrap();
+
+        // The parts hold the hashes numbered below the bound, and follow each other in leaf order
+        let sizes = store.part_sizes(bound, 7).unwrap();
+        assert_eq!(sizes.iter().sum::<usize>(), 900);
+        let parts: Vec<Vec<Hash512>> = (0..7).map(|part| store.sorted_part(bound, 7, part).unwrap()).collect();
+        assert_eq!(parts.iter().map(Vec::len).collect::<Vec<_>>(), sizes);
+        let mut expected = salt_batch(&**store.hasher(), &hashes[..900], &SALT, LeafEncoding::default(), 1);
+        sort_leaves(&mut expected);
+        assert_eq!(parts.concat(), expected);
+    }
 
-        for i in 0..10 {
-            let hash = [i as u64, 0, 0, 0, 0, 0, 0, 0];
-            store.add_hash(hash);
+    #[test]
+    fn test_import_salted() {
+        let hashes: Vec<Hash512> = (0..32).map(|i| Hash512([i << 58 | i, i, 0, 0, 0, 0, 0, 0])).collect();
+        let leaves = salt_batch(&Sha512Hasher, &hashes, &SALT, LeafEncoding::default(), 1);
+        for partitioning in [Partitioning::SaltedPrefix, Partitioning::RoundRobin] {
+            let store = MultiThreadedHashStore::<8, 2>::new(4, SALT).with_partitioning(partitioning);
+            store.add_batch(&hashes[..8]).unwrap();
+            assert_eq!(store.import_salted(leaves.clone()).unwrap(), 24, "{partitioning:?}");
+            for hash in &hashes {
+                assert!(store.contains(hash).unwrap(), "{partitioning:?}");
+            }
+            assert_eq!(store.import_salted(leaves.clone()).unwrap(), 0);
         }
-
-        assert_eq!(store.to_array().len(), 10);
-        let mut array = store.to_array();
-        array.sort_by(|a, b| a[0].cmp(&b[0]));
-        assert_eq!(array, store.to_array());
+        let store = MultiThreadedHashStore::<8, 2>::new(4, SALT).with_partitioning(Partitioning::UnsaltedPrefix);
+        assert!(matches!(store.import_salted(leaves), Err(MergeError::UnsaltedPrefix)));
     }
 
     #[test]
@@ -585,35 +6200,35 @@ mod tests {
         assert_eq!(store.len(), 0);
         assert_eq!(store.occupied_slots(), 0);
 
-        let hash = [1u64, 2u64, 3u64, 4u64, 5u64, 6u64, 7u64, 8u64];
+        let hash = Hash512([1u64, 2u64, 3u64, 4u64, 5u64, 6u64, 7u64, 8u64]);
 
         // Test adding hash
-        store.add_hash(hash);
+        store.add_hash(hash).unwrap();
 
         // Give some time for the operation to complete
         std::thread::sleep(Duration::from_millis(10));
 
-        assert!(store.contains(&hash));
+        assert!(store.contains(&hash).unwrap());
 
         // Test adding duplicate
-        store.add_hash(hash);
+        store.add_hash(hash).unwrap();
         std::thread::sleep(Duration::from_millis(10));
 
         // Test adding different hash
-        let hash2 = [9u64, 10u64, 11u64, 12u64, 13u64, 14u64, 15u64, 16u64];
-        store.add_hash(hash2);
+        let hash2 = Hash512([9u64, 10u64, 11u64, 12u64, 13u64, 14u64, 15u64, 16u64]);
+        store.add_hash(hash2).unwrap();
         std::thread::sleep(Duration::from_millis(10));
 
-        assert!(store.contains(&hash2));
+        assert!(store.contains(&hash2).unwrap());
     }
 
     #[test]
     fn test_merkle_tree_basic() {
         let array = vec![
-            [1u64, 0, 0, 0, 0, 0, 0, 0],
-            [2u64, 0, 0, 0, 0, 0, 0, 0],
-            [3u64, 0, 0, 0, 0, 0, 0, 0],
-            [4u64, 0, 0, 0, 0, 0, 0, 0],
+            Hash512([1u64, 0, 0, 0, 0, 0, 0, 0]),
+            Hash512([2u64, 0, 0, 0, 0, 0, 0, 0]),
+            Hash512([3u64, 0, 0, 0, 0, 0, 0, 0]),
+            Hash512([4u64, 0, 0, 0, 0, 0, 0, 0]),
         ];
 
         let tree = MerkleTree::new(array, SALT);
@@ -621,7 +6236,218 @@ mod tests {
         assert_eq!(tree.leaf_count, 4);
         assert_eq!(tree.depth, 2);
         assert!(tree.root().is_some());
-        assert_eq!(tree.size(), 7); // 2^3 - 1 = 7 nodes
+        assert_eq!(tree.size(), 7); // 4 leaves + 2 + 1 nodes
+    }
+
+    #[test]
+    fn test_merkle_tree_leaf_position() {
+        let leaves: Vec<Hash512> = (0..5u64).map(|i| Hash512([i, 0, 0, 0, 0, 0, 0, 0])).collect();
+
+        // Sorted leaves are searched in the tree itself
+        let tree = MerkleTree::new(leaves.clone(), SALT);
+        assert!(tree.leaf_index.is_empty());
+        assert_eq!(tree.memory_usage(), tree.levels.iter().map(|level| level.capacity() * size_of::<Hash512>()).sum::<usize>());
+        for (index, leaf) in leaves.iter().enumerate() {
+            assert_eq!(tree.leaf_position(leaf), Some(index));
+        }
+        assert_eq!(tree.leaf_position(&Hash512([9, 0, 0, 0, 0, 0, 0, 0])), None);
+
+        let reversed: Vec<Hash512> = leaves.iter().rev().copied().collect();
+        let tree = MerkleTree::new(reversed, SALT);
+        assert_eq!(tree.leaf_index.len(), 5);
+        assert_eq!(tree.leaf_position(&leaves[0]), Some(4));
+        assert_eq!(tree.leaf_position(&Hash512([9, 0, 0, 0, 0, 0, 0, 0])), None);
+    }
+
+    // Reference definitions of RFC 6962 section 2.1: MTH and PATH, splitting at the largest power of two below n
+    fn rfc6962_root(leaves: &[Hash512]) -> Hash512 {
+        if leaves.len() == 1 {
+            return leaves[0];
+        }
+        let split = leaves.len().next_power_of_two() / 2;
+        hash512(rfc6962_root(&leaves[..split]), rfc6962_root(&leaves[split..]))
+    }
+
+    fn rfc6962_path(index: usize, leaves: &[Hash512]) -> Vec<Hash512> {
+        if leaves.len() == 1 {
+            return vec![];
+        }
+        let split = leaves.len().next_power_of_two() / 2;
+        let (mut path, sibling) = if index < split {
+            (rfc6962_path(index, &leaves[..split]), rfc6962_root(&leaves[split..]))
+        } else {
+            (rfc6962_path(index - split, &leaves[split..]), rfc6962_root(&leaves[..split]))
+        };
+        path.push(sibling);
+        path
+    }
+
+    #[test]
+    fn test_merkle_tree_matches_rfc6962() {
+        for leaf_count in 1..=40u64 {
+            let hashes: Vec<Hash512> = (0..leaf_count).map(|i| Hash512([i, leaf_count, 0, 0, 0, 0, 0, 0])).collect();
+            let leaves: Vec<Hash512> = hashes.iter().map(|hash| hash512(*hash, SALT)).collect();
+            let tree = MerkleTree::new(leaves.clone(), SALT);
+            let root = tree.root().unwrap();
+            assert_eq!(root, rfc6962_root(&leaves), "root for {} leaves", leaf_count);
+
+            for (index, hash) in hashes.iter().enumerate() {
+                assert_eq!(tree.leaf_position(&leaves[index]), Some(index));
+                let path = tree.audit_path(index).unwrap();
+                assert_eq!(path, rfc6962_path(index, &leaves), "path of leaf {} of {}", index, leaf_count);
+                assert!(MerkleTree::verify_audit_path(&leaves[index], index, leaves.len(), &path, &root, &Sha512Hasher));
+
+                // The pair proof of `get` carries the same siblings
+                let proof = tree.get(hash).unwrap();
+                assert!(MerkleTree::verify_proof(hash, &proof, &root));
+                assert_eq!(proof.len(), path.len() + 1);
+
+                // Wrong leaves or positions are rejected
+                if leaves.len() > 1 {
+                    let other = (index + 1) % leaves.len();
+                    assert!(!MerkleTree::verify_audit_path(&leaves[other], index, leaves.len(), &path, &root, &Sha512Hasher));
+                    assert!(!MerkleTree::verify_audit_path(&leaves[index], other, leaves.len(), &path, &root, &Sha512Hasher));
+                }
+            }
+            assert!(tree.audit_path(leaves.len()).is_none());
+        }
+    }
+
+    #[test]
+    fn test_pruned_merkle_tree() {
+        let dir = std::env::temp_dir().join(format!("timestamping-test-pruned-tree-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for leaf_count in [1u64, 2, 63, 64, 65, 200, 1000] {
+            let hashes: Vec<Hash512> = (0..leaf_count).map(|i| Hash512([i, leaf_count, 0, 0, 0, 0, 0, 0])).collect();
+            let mut leaves: Vec<Hash512> = hashes.iter().map(|hash| hash512(*hash, SALT)).collect();
+            sort_leaves(&mut leaves);
+            let tree = MerkleTree::new(leaves.clone(), SALT);
+            let path = LeafFile::path(&dir, leaf_count);
+            let pruned = tree.prune(Some(&path)).unwrap();
+            assert_eq!(pruned.root(), tree.root());
+            assert!(leaf_count < 64 || pruned.memory_usage() * 16 < tree.memory_usage());
+
+            // Proofs from the leaves on disk are the same as from memory
+            for (index, hash) in hashes.iter().enumerate() {
+                assert_eq!(pruned.get(hash), tree.get(hash), "proof of hash {} of {}", index, leaf_count);
+                assert_eq!(pruned.audit_path(index), tree.audit_path(index), "path of leaf {} of {}", index, leaf_count);
+            }
+            assert_eq!(pruned.get(&Hash512([u64::MAX; 8])), None);
+
+            let discarded = tree.prune(None).unwrap();
+            assert_eq!(discarded.root(), tree.root());
+            assert_eq!(discarded.get(&hashes[0]), None);
+            drop(pruned);
+            assert!(!path.exists());
+        }
+        // Leaves out of order can only be discarded
+        let unsorted = MerkleTree::new(vec![Hash512([2; 8]), Hash512([1; 8])], SALT);
+        assert_eq!(unsorted.prune(Some(&LeafFile::path(&dir, 0))).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_service_leaf_pruning() {
+        let dir = std::env::temp_dir().join(format!("timestamping-test-pruning-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let service = TimestampingService::<8, 0>::with_leaf_logs(2, &dir).unwrap()
+            .with_signing_key(SigningKey::from_bytes(&[1; 32]))
+            .with_retained_trees(1)
+            .with_leaf_pruning(LeafPruning::Disk, false);
+        let hashes: Vec<Hash512> = (0..300u64).map(|i| Hash512([i << 56, i, 0, 0, 0, 0, 0, 0])).collect();
+        service.hash_store.add_batch(&hashes).unwrap();
+        service.update_merkle_tree().unwrap();
+        let published = service.published_tree().unwrap();
+        assert!(matches!(published.tree.as_ref().unwrap().pruned, Some(PrunedLeaves::OnDisk(_))));
+        assert!(LeafFile::path(&dir, 0).exists());
+        let (unpruned, _) = MerkleTree::from_storage(&*service.hash_store).unwrap();
+        assert_eq!(service.get_merkle_tree_root(), unpruned.root());
+        for hash in &hashes {
+            assert_eq!(service.get_merkle_proof(hash), proof_to_bytes(unpruned.get(hash)));
+        }
+        drop(published);
+
+        // The leaves of a tree are removed with it, once it isn't retained anymore
+        service.update_merkle_tree().unwrap();
+        assert!(service.get_merkle_proof_at_version(&hashes[0], LeafEncoding::default(), 0).unwrap().is_some());
+        service.update_merkle_tree().unwrap();
+        assert!(!LeafFile::path(&dir, 0).exists());
+        assert!(LeafFile::path(&dir, 2).exists());
+        service.shutdown();
+
+        // Waiting for an anchor, trees keep their leaves until one is recorded
+        let service = TimestampingService::<8, 0>::with_threads(2).with_leaf_pruning(LeafPruning::Discard, true);
+        service.hash_store.add_batch(&hashes).unwrap();
+        service.update_merkle_tree().unwrap();
+        let root = service.get_merkle_tree_root();
+        assert!(service.get_merkle_proof(&hashes[0]).is_some());
+        assert!(!service.prune_tree(0));
+        service.epochs.write().unwrap().set_anchor(0, "abcd".to_string()).unwrap();
+        assert!(service.prune_tree(0));
+        assert!(!service.prune_tree(0));
+        assert!(service.get_merkle_proof(&hashes[0]).is_none());
+        assert_eq!(service.get_merkle_tree_root(), root);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_service_root_log() {
+        let service = TimestampingService::<8, 0>::with_threads(2).with_signing_key(SigningKey::from_bytes(&[1; 32]));
+        service.update_merkle_tree().unwrap();
+        service.hash_store.add_batch(&[Hash512([1, 2, 3, 4, 5, 6, 7, 8])]).unwrap();
+        service.update_merkle_tree().unwrap();
+        let entries = service.root_log.read().unwrap().entries().to_vec();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(crate::root_log::verify_chain(&entries).map(|_| ()), Ok(()));
+        let epochs = service.epochs.read().unwrap();
+        for (entry, summary) in entries.iter().zip(epochs.page(0, 2)) {
+            assert_eq!((entry.version, entry.root, entry.timestamp), (summary.epoch, summary.root, summary.timestamp));
+            assert_eq!(entry.signature, summary.signature);
+        }
+        assert_eq!(entries[1].leaf_count, 1);
+    }
+
+    #[test]
+    fn test_merkle_tree_of_roots() {
+        // Trees of several stores, combined by a tree over their roots
+        let trees: Vec<MerkleTree> = (0..3u64)
+            .map(|shard| MerkleTree::new((0..shard + 2).map(|i| hash512(Hash512([shard, i, 0, 0, 0, 0, 0, 0]), SALT)).collect(), SALT))
+            .collect();
+        let top = MerkleTree::new(trees.iter().map(|tree| tree.root().unwrap()).collect(), SALT);
+        let root = top.root().unwrap();
+
+        for (shard, tree) in trees.iter().enumerate() {
+            let hash = Hash512([shard as u64, 1, 0, 0, 0, 0, 0, 0]);
+            let mut proof = tree.get(&hash).unwrap();
+            proof.extend(top.proof_at(shard).unwrap());
+            assert!(MerkleTree::verify_proof(&hash, &proof, &root));
+            assert!(!MerkleTree::verify_proof(&hash, &proof, &tree.root().unwrap()));
+        }
+        assert!(top.proof_at(3).is_none());
+    }
+
+    #[test]
+    fn test_merkle_tree_unbalanced() {
+        let hashes: Vec<Hash512> = (0..7u64).map(|i| Hash512([i, 0, 0, 0, 0, 0, 0, 0])).collect();
+        let salted_hashes: Vec<Hash512> = hashes.iter().map(|hash| hash512(*hash, SALT)).collect();
+
+        // Only real nodes are stored: 7 + 4 + 2 + 1
+        let tree = MerkleTree::new(salted_hashes.clone(), SALT);
+        assert_eq!(tree.depth, 3);
+        assert_eq!(tree.size(), 14);
+
+        // Same root as the RFC 6962 split into the largest power of two and the rest
+        let s = &salted_hashes;
+        let left = hash512(hash512(s[0], s[1]), hash512(s[2], s[3]));
+        let right = hash512(hash512(s[4], s[5]), s[6]);
+        assert_eq!(tree.root(), Some(hash512(left, right)));
+
+        for hash in &hashes {
+            let proof = tree.get(hash).unwrap();
+            assert!(MerkleTree::verify_proof(hash, &proof, &tree.root().unwrap()));
+        }
+        // The promoted last leaf skips the level where it has no sibling
+        assert_eq!(tree.get(&hashes[6]).unwrap().len(), 3);
     }
 
     #[test]
@@ -637,7 +6463,7 @@ mod tests {
 
     #[test]
     fn test_merkle_tree_single_element() {
-        let hash = [1u64, 0, 0, 0, 0, 0, 0, 0];
+        let hash = Hash512([1u64, 0, 0, 0, 0, 0, 0, 0]);
         let array = vec![hash];
         let tree = MerkleTree::new(array, SALT);
 
@@ -647,13 +6473,44 @@ mod tests {
         assert_eq!(tree.root().unwrap(), hash);
     }
 
+    #[test]
+    fn test_merkle_tree_serde() {
+        let hashes: Vec<Hash512> = (0..7u64).map(|i| Hash512([i, 1, 0, 0, 0, 0, 0, 0])).collect();
+        let tree = MerkleTree::with_hasher(hashes.clone(), SALT, hasher_from_name("blake3-rfc6962").unwrap());
+        let json = serde_json::to_value(&tree).unwrap();
+        assert_eq!(json["tree_hasher"], "blake3-rfc6962");
+        assert_eq!(json["leaves"][0], Hash512([0, 1, 0, 0, 0, 0, 0, 0]).to_string());
+        let mut cbor = Vec::new();
+        ciborium::into_writer(&tree, &mut cbor).unwrap();
+        for decoded in [serde_json::from_value::<MerkleTree>(json.clone()).unwrap(), ciborium::from_reader(&cbor[..]).unwrap()] {
+            assert_eq!(decoded.levels, tree.levels);
+            assert_eq!(decoded.hasher.name(), "blake3-rfc6962");
+            assert_eq!(decoded.get(&hashes[3]), tree.get(&hashes[3]));
+        }
+        let empty = serde_json::to_string(&MerkleTree::new(Vec::new(), SALT)).unwrap();
+        assert_eq!(serde_json::from_str::<MerkleTree>(&empty).unwrap().root(), None);
+
+        // The root has to match the leaves, pruned leaves can't be written
+        let mut tampered = json;
+        tampered["leaves"][0] = serde_json::to_value(Hash512([9; 8])).unwrap();
+        assert!(serde_json::from_value::<MerkleTree>(tampered).is_err());
+        assert!(serde_json::to_string(&tree.prune(None).unwrap()).is_err());
+
+        let head = TreeHead { version: 3, root: tree.root(), tree_size: tree.size(), leaf_count: 7, timestamp: 1000 };
+        assert_eq!(serde_json::from_str::<TreeHead>(&serde_json::to_string(&head).unwrap()).unwrap(), head);
+        let snapshot = StoreSnapshot { hashes, leaf_log_lengths: vec![4, 3] };
+        let mut cbor = Vec::new();
+        ciborium::into_writer(&snapshot, &mut cbor).unwrap();
+        assert_eq!(ciborium::from_reader::<StoreSnapshot, _>(&cbor[..]).unwrap(), snapshot);
+    }
+
     #[test]
     fn test_merkle_proof() {
-        let hashes = vec![
-            [1u64, 0, 0, 0, 0, 0, 0, 0],
-            [2u64, 0, 0, 0, 0, 0, 0, 0],
-            [3u64, 0, 0, 0, 0, 0, 0, 0],
-            [4u64, 0, 0, 0, 0, 0, 0, 0],
+        let hashes = [
+            Hash512([1u64, 0, 0, 0, 0, 0, 0, 0]),
+            Hash512([2u64, 0, 0, 0, 0, 0, 0, 0]),
+            Hash512([3u64, 0, 0, 0, 0, 0, 0, 0]),
+            Hash512([4u64, 0, 0, 0, 0, 0, 0, 0]),
         ];
 
         let salted_hashes = hashes.iter().map(|hash| hash512(*hash, SALT)).collect();
@@ -664,9 +6521,173 @@ mod tests {
         assert!(proof.is_some());
 
         // Test proof for non-existent hash
-        let non_existent = [999u64, 0, 0, 0, 0, 0, 0, 0];
+        let non_existent = Hash512([999u64, 0, 0, 0, 0, 0, 0, 0]);
         let proof = tree.get(&non_existent);
         assert!(proof.is_none());
+
+        // The iterator proves hashes in the order they are given, missing ones included
+        let mut proofs = tree.proofs([hashes[2], non_existent, hashes[0]], LeafEncoding::default());
+        assert_eq!(proofs.next(), Some(tree.get(&hashes[2])));
+        assert_eq!(proofs.next(), Some(None));
+        assert_eq!(proofs.next(), Some(tree.get(&hashes[0])));
+        assert_eq!(proofs.next(), None);
+    }
+
+    #[test]
+    fn test_leaf_encoding_versions() {
+        for encoding in LeafEncoding::ALL {
+            assert_eq!(LeafEncoding::from_version(encoding.version()), Some(encoding));
+        }
+        assert_eq!(LeafEncoding::from_version(0), None);
+
+        let hash = Hash512([1u64, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(LeafEncoding::V1.leaf(&hash, &SALT), hash512(hash, SALT));
+        assert_ne!(LeafEncoding::V2.leaf(&hash, &SALT), LeafEncoding::V1.leaf(&hash, &SALT));
+    }
+
+    #[test]
+    fn test_hash_algorithms() {
+        let sha256_digest = [7u8; 32];
+        let normalized = HashAlgorithm::Sha256.normalize(&sha256_digest).unwrap();
+        assert!(HashAlgorithm::Sha256.normalize(&[7u8; 64]).is_err());
+        assert!(HashAlgorithm::Sha512.normalize(&sha256_digest).is_err());
+
+        // SHA-512 digests are stored unchanged, padding a SHA-256 digest doesn't give the same value
+        let mut padded = sha256_digest.to_vec();
+        padded.resize(64, 0);
+        assert_eq!(HashAlgorithm::Sha512.normalize(&padded).unwrap(), Hash512::from_bytes(&padded).unwrap());
+        assert_ne!(HashAlgorithm::Sha512.normalize(&padded).unwrap(), normalized);
+
+        let tree = MerkleTree::new(vec![LeafEncoding::V1.leaf(&normalized, &SALT)], SALT);
+        let proof = tree.get(&normalized).unwrap();
+        assert!(MerkleTree::verify_proof(&normalized, &proof, &tree.root().unwrap()));
+        assert_eq!(HashAlgorithm::from_name("sha256"), Some(HashAlgorithm::Sha256));
+        assert_eq!(HashAlgorithm::from_name("md5"), None);
+
+        // BLAKE3 digests keep their native 32 bytes, and differ from SHA-256 digests with the same bytes
+        let blake3_digest = blake3::hash(b"document");
+        assert_eq!(HashAlgorithm::from_name("blake3"), Some(HashAlgorithm::Blake3));
+        assert!(HashAlgorithm::Blake3.normalize(&[7u8; 64]).is_err());
+        let normalized = HashAlgorithm::Blake3.normalize(blake3_digest.as_bytes()).unwrap();
+        assert_ne!(normalized, HashAlgorithm::Sha256.normalize(blake3_digest.as_bytes()).unwrap());
+
+        // The same 64 bytes are different leaves depending on the algorithm they were declared with
+        let digest = [7u8; 64];
+        let normalized: Vec<Hash512> = HashAlgorithm::ALL.iter().filter_map(|algorithm| algorithm.normalize(&digest).ok()).collect();
+        assert_eq!(normalized.len(), 3);
+        assert!(normalized.iter().enumerate().all(|(i, hash)| normalized[i + 1..].iter().all(|other| other != hash)));
+        for algorithm in HashAlgorithm::ALL {
+            let json = serde_json::to_string(&algorithm).unwrap();
+            assert_eq!(json, format!("\"{}\"", algorithm.name()));
+            assert_eq!(serde_json::from_str::<HashAlgorithm>(&json).unwrap(), algorithm);
+        }
+    }
+
+    #[test]
+    fn test_hashers() {
+        let hashes: Vec<Hash512> = (0..5u64).map(|i| Hash512([i, 0, 0, 0, 0, 0, 0, 0])).collect();
+        let blake3: Arc<dyn Hasher> = Arc::new(Blake3Hasher);
+        assert_ne!(blake3.combine(&hashes[0], &SALT), hash512(hashes[0], SALT));
+        assert_eq!(hasher_from_name("blake3").unwrap().name(), "blake3");
+        assert!(hasher_from_name("md5").is_none());
+
+        let salted = hashes.iter().map(|hash| LeafEncoding::V1.leaf_with_hasher(&*blake3, hash, &SALT)).collect();
+        let tree = MerkleTree::with_hasher(salted, SALT, Arc::clone(&blake3));
+        let root = tree.root().unwrap();
+        let proof = tree.get(&hashes[3]).unwrap();
+        assert!(MerkleTree::verify_proof_with_hasher(&hashes[3], &proof, &root, LeafEncoding::V1, &Blake3Hasher));
+        assert!(!MerkleTree::verify_proof(&hashes[3], &proof, &root));
+
+        // The service salts and builds trees with its hasher
+        let service = TimestampingService::<8, 0>::with_hasher(2, None, Arc::clone(&blake3)).unwrap();
+        service.hash_store.add_hash(hashes[0]).unwrap();
+        assert!(service.hash_store.contains(&hashes[0]).unwrap());
+        service.update_merkle_tree().unwrap();
+        let proof = service.published_tree().unwrap().tree.as_ref().unwrap().get(&hashes[0]).unwrap();
+        assert!(MerkleTree::verify_proof_with_hasher(&hashes[0], &proof, &service.get_merkle_tree_root().unwrap(), LeafEncoding::V1, &Blake3Hasher));
+
+        let sha512_store = MultiThreadedHashStore::<8, 0>::new(2, SALT);
+        let blake3_store = MultiThreadedHashStore::<8, 0>::with_hasher(2, SALT, None, blake3).unwrap();
+        assert!(matches!(sha512_store.merge(&blake3_store), Err(MergeError::HasherMismatch)));
+    }
+
+    #[test]
+    fn test_domain_separated_hasher() {
+        let hashes: Vec<Hash512> = (0..5u64).map(|i| Hash512([i, 0, 0, 0, 0, 0, 0, 0])).collect();
+        let plain: Arc<dyn Hasher> = Arc::new(Sha512Hasher);
+        let separated = hasher_from_name("sha512-rfc6962").unwrap();
+        assert_eq!(separated.name(), "sha512-rfc6962");
+        assert_eq!(separated.combine(&hashes[0], &SALT), plain.combine(&hashes[0], &SALT));
+        assert_ne!(separated.node(&hashes[0], &hashes[1]), plain.node(&hashes[0], &hashes[1]));
+
+        let build = |hasher: &Arc<dyn Hasher>| {
+            let salted = hashes.iter().map(|hash| LeafEncoding::V1.leaf_with_hasher(&**hasher, hash, &SALT)).collect();
+            MerkleTree::with_hasher(salted, SALT, Arc::clone(hasher))
+        };
+        let (plain_tree, tree) = (build(&plain), build(&separated));
+        let root = tree.root().unwrap();
+        assert_ne!(root, plain_tree.root().unwrap());
+        let proof = tree.get(&hashes[3]).unwrap();
+        assert!(MerkleTree::verify_proof_with_hasher(&hashes[3], &proof, &root, LeafEncoding::V1, &*separated));
+        assert!(!MerkleTree::verify_proof_with_hasher(&hashes[3], &proof, &root, LeafEncoding::V1, &*plain));
+
+        // The children of an inner node can't be passed off as a submitted hash and its salt
+        let forge = |tree: &MerkleTree, hasher: &dyn Hasher| {
+            let proof = tree.get(&hashes[0]).unwrap();
+            let forged = &proof[1..];
+            MerkleTree::verify_proof_with_hasher(&forged[0].0, forged, &tree.root().unwrap(), LeafEncoding::V1, hasher)
+        };
+        assert!(forge(&plain_tree, &*plain));
+        assert!(!forge(&tree, &*separated));
+    }
+
+    #[test]
+    fn test_mixed_leaf_encodings() {
+        let store = HashStore::<8, 0>::new(SALT);
+        let hash = Hash512([1u64, 2, 3, 4, 5, 6, 7, 8]);
+
+        assert!(store.add_hash_with_encoding(hash, LeafEncoding::V2).unwrap());
+        assert!(store.add_hash(Hash512([2u64, 0, 0, 0, 0, 0, 0, 0])).unwrap());
+        assert!(store.contains_with_encoding(&hash, LeafEncoding::V2));
+        assert!(!store.contains(&hash));
+
+        let tree = MerkleTree::new(store.to_array(), SALT);
+        let root = tree.root().unwrap();
+        assert!(tree.get(&hash).is_none());
+        let proof = tree.get_with_encoding(&hash, LeafEncoding::V2).unwrap();
+        assert!(MerkleTree::verify_proof_with_encoding(&hash, &proof, &root, LeafEncoding::V2));
+        assert!(!MerkleTree::verify_proof_with_encoding(&hash, &proof, &root, LeafEncoding::V1));
+
+        // The same hash under another encoding is a separate leaf
+        assert!(store.add_hash(hash).unwrap());
+        assert_eq!(store.len(), 3);
+    }
+
+    #[test]
+    fn test_merkle_proof_verification() {
+        let hashes: Vec<Hash512> = (0..5).map(|i| Hash512([i as u64, 0, 0, 0, 0, 0, 0, 0])).collect();
+        let salted_hashes = hashes.iter().map(|hash| hash512(*hash, SALT)).collect();
+        let tree = MerkleTree::new(salted_hashes, SALT);
+        let root = tree.root().unwrap();
+
+        for hash in &hashes {
+            let proof = tree.get(hash).unwrap();
+            assert!(MerkleTree::verify_proof(hash, &proof, &root));
+
+            // Wrong root, wrong hash, and tampered path are rejected
+            assert!(!MerkleTree::verify_proof(hash, &proof, &Hash512([0u64; 8])));
+            assert!(!MerkleTree::verify_proof(&Hash512([999u64, 0, 0, 0, 0, 0, 0, 0]), &proof, &root));
+            let mut tampered = proof.clone();
+            tampered[1].0.0[0] ^= 1;
+            tampered[1].1.0[0] ^= 1;
+            assert!(!MerkleTree::verify_proof(hash, &tampered, &root));
+        }
+        assert!(!MerkleTree::verify_proof(&hashes[0], &[], &root));
+
+        // A single leaf is its own root
+        let single = MerkleTree::new(vec![hash512(hashes[0], SALT)], SALT);
+        let proof = single.get(&hashes[0]).unwrap();
+        assert!(MerkleTree::verify_proof(&hashes[0], &proof, &single.root().unwrap()));
     }
 
     #[test]
@@ -679,17 +6700,17 @@ mod tests {
         assert!(service.get_last_update_timestamp().is_none());
 
         // Add some hashes
-        let hash1 = [1u64, 0, 0, 0, 0, 0, 0, 0];
-        let hash2 = [2u64, 0, 0, 0, 0, 0, 0, 0];
+        let hash1 = Hash512([1u64, 0, 0, 0, 0, 0, 0, 0]);
+        let hash2 = Hash512([2u64, 0, 0, 0, 0, 0, 0, 0]);
 
-        service.hash_store.add_hash(hash1);
-        service.hash_store.add_hash(hash2);
+        service.hash_store.add_hash(hash1).unwrap();
+        service.hash_store.add_hash(hash2).unwrap();
 
         // Give time for operations to complete
         std::thread::sleep(Duration::from_millis(10));
 
         // Update merkle tree
-        service.update_merkle_tree();
+        service.update_merkle_tree().unwrap();
 
         // Test updated state
         assert!(service.get_merkle_tree_root().is_some());
@@ -706,28 +6727,915 @@ mod tests {
         assert_eq!(root_bytes.unwrap().len(), 64);
     }
 
+    #[test]
+    fn test_timestamping_service_tree_update_subscription() {
+        let service = TimestampingService::<8, 0>::with_threads(2);
+        let mut updates = service.subscribe_tree_updates();
+
+        service.hash_store.add_hash(Hash512([1u64, 0, 0, 0, 0, 0, 0, 0])).unwrap();
+        std::thread::sleep(Duration::from_millis(10));
+        service.update_merkle_tree().unwrap();
+
+        let head = updates.try_recv().unwrap();
+        assert_eq!(head.root, service.get_merkle_tree_root());
+        assert_eq!(head.tree_size, service.get_merkle_tree_size());
+        assert_eq!(head.leaf_count, 1);
+        assert_eq!(Some(head.timestamp), service.get_last_update_timestamp());
+        assert!(updates.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_timestamping_service_epochs() {
+        let service = TimestampingService::<8, 0>::with_threads(2);
+
+        service.hash_store.add_hash(Hash512([1u64, 0, 0, 0, 0, 0, 0, 0])).unwrap();
+        service.hash_store.add_hash(Hash512([2u64, 0, 0, 0, 0, 0, 0, 0])).unwrap();
+        std::thread::sleep(Duration::from_millis(10));
+        service.update_merkle_tree().unwrap();
+
+        service.hash_store.add_hash(Hash512([3u64, 0, 0, 0, 0, 0, 0, 0])).unwrap();
+        std::thread::sleep(Duration::from_millis(10));
+        service.update_merkle_tree().unwrap();
+
+        let epochs = service.epochs.read().unwrap();
+        assert_eq!(epochs.len(), 2);
+        let page = epochs.page(0, 10);
+        assert_eq!((page[0].epoch, page[0].leaf_count, page[0].new_hashes), (0, 2, 2));
+        assert_eq!((page[1].epoch, page[1].leaf_count, page[1].new_hashes), (1, 3, 1));
+        assert_eq!(epochs.page(1, 10), &page[1..]);
+        assert!(epochs.page(5, 10).is_empty());
+
+        // The latest epoch carries the currently published root, the older one stays available
+        assert_eq!(epochs.get(1).unwrap().root, service.get_merkle_tree_root());
+        assert_eq!(epochs.get(1).unwrap().tree_size, service.get_merkle_tree_size());
+        assert_ne!(epochs.get(0).unwrap().root, epochs.get(1).unwrap().root);
+        assert!(epochs.get(2).is_none());
+    }
+
+    #[test]
+    fn test_leaf_order() {
+        let hashes: Vec<Hash512> = (0..100u64).map(|i| Hash512([i.wrapping_mul(0x9e37_79b9_7f4a_7c15), i, 0, 0, 0, 0, 0, 0])).collect();
+        let two = TimestampingService::<8, 0>::from_store(MultiThreadedHashStore::<8, 0>::new(2, SALT));
+        let four = TimestampingService::<4, 2>::from_store(MultiThreadedHashStore::<4, 2>::new(4, SALT));
+        two.hash_store.add_batch(&hashes).unwrap();
+        // Submission order doesn't matter either
+        for hash in hashes.iter().rev() {
+            four.hash_store.add_hash(*hash).unwrap();
+        }
+        two.update_merkle_tree().unwrap();
+        four.update_merkle_tree().unwrap();
+        assert_ne!(two.hash_store.to_array(), four.hash_store.to_array());
+        assert_eq!(two.get_merkle_tree_root(), four.get_merkle_tree_root());
+
+        let leaves = two.published_tree().unwrap().tree.as_ref().unwrap().levels[0].clone();
+        assert!(leaves.is_sorted());
+    }
+
+    // Keeps salted leaves in a vector, in submission order
+    struct MockStorage {
+        leaves: RwLock<Vec<Hash512>>,
+        hasher: Arc<dyn Hasher>,
+    }
+
+    impl HashStorage for MockStorage {
+        fn add(&self, hash: Hash512) -> Result<bool, StorageError> {
+            let leaf = LeafEncoding::default().leaf_with_hasher(&*self.hasher, &hash, &SALT);
+            let mut leaves = self.leaves.write().unwrap();
+            let new = !leaves.contains(&leaf);
+            if new {
+                leaves.push(leaf);
+            }
+            Ok(new)
+        }
+
+        fn add_batch(&self, hashes: &[Hash512]) -> Result<Vec<bool>, StorageError> {
+            hashes.iter().map(|hash| self.add(*hash)).collect()
+        }
+
+        fn contains(&self, hash: &Hash512) -> Result<bool, StorageError> {
+            Ok(self.leaves.read().unwrap().contains(&LeafEncoding::default().leaf_with_hasher(&*self.hasher, hash, &SALT)))
+        }
+
+        fn len(&self) -> usize {
+            self.leaves.read().unwrap().len()
+        }
+
+        fn iter(&self) -> Box<dyn Iterator<Item = Result<Vec<Hash512>, StorageError>> + '_> {
+            Box::new(chunked(self.leaves.read().unwrap().clone().into_iter()).map(Ok))
+        }
+
+        fn snapshot(&self) -> Result<StoreSnapshot, StorageError> {
+            Ok(StoreSnapshot { hashes: self.leaves.read().unwrap().clone(), leaf_log_lengths: Vec::new() })
+        }
+
+        fn salt(&self) -> &Hash512 {
+            &SALT
+        }
+
+        fn hasher(&self) -> &Arc<dyn Hasher> {
+            &self.hasher
+        }
+    }
+
+    #[test]
+    fn test_tree_from_storage() {
+        let hashes: Vec<Hash512> = (0..50u64).map(|i| Hash512([i.wrapping_mul(0x9e37_79b9_7f4a_7c15), i, 0, 0, 0, 0, 0, 0])).collect();
+        let mock = MockStorage { leaves: RwLock::new(Vec::new()), hasher: Arc::new(Sha512Hasher) };
+        let single = HashStore::<8, 0>::new(SALT);
+        let threaded = MultiThreadedHashStore::<8, 0>::new(4, SALT);
+        let stores: [&dyn HashStorage; 3] = [&mock, &single, &threaded];
+        for store in stores {
+            assert!(store.is_empty());
+            assert_eq!(store.add_batch(&hashes[1..]).unwrap(), vec![true; 49]);
+            assert_eq!(store.add(hashes[0]), Ok(true));
+            assert_eq!(store.add(hashes[1]), Ok(false));
+            assert!(store.contains(&hashes[7]).unwrap() && !store.contains(&Hash512([7; 8])).unwrap());
+            assert_eq!(store.len(), 50);
+            assert_eq!(store.iter().map(|chunk| chunk.unwrap().len()).sum::<usize>(), 50);
+        }
+
+        // Every backend gives the same tree as the service publishes
+        let roots: Vec<Option<Hash512>> = stores.iter().map(|store| MerkleTree::from_storage(*store).unwrap().0.root()).collect();
+        assert_eq!(roots, vec![roots[0]; 3]);
+        assert_eq!(MerkleTree::from_storage(&threaded).unwrap().1.len(), 4);
+        let service = TimestampingService::<8, 0>::from_store(threaded);
+        service.update_merkle_tree().unwrap();
+        assert_eq!(service.get_merkle_tree_root(), roots[0]);
+
+        // So does a service publishing from another store
+        let mock = MockStorage { leaves: RwLock::new(Vec::new()), hasher: Arc::new(Sha512Hasher) };
+        mock.add_batch(&hashes).unwrap();
+        let service = TimestampingService::<8, 0, MockStorage>::from_store(mock);
+        assert_eq!(service.update_merkle_tree().unwrap().root, roots[0]);
+        assert!(service.get_merkle_proof(&hashes[3]).is_some());
+        assert!(service.get_merkle_proof(&Hash512([7; 8])).is_none());
+    }
+
+    #[test]
+    fn test_merkle_proof_at_version() {
+        let service = TimestampingService::<8, 0>::with_threads(2).with_retained_trees(1);
+        let first = Hash512([1u64, 0, 0, 0, 0, 0, 0, 0]);
+        let second = Hash512([2u64, 0, 0, 0, 0, 0, 0, 0]);
+        let parse = |proof: Vec<(Vec<u8>, Vec<u8>)>| -> Vec<(Hash512, Hash512)> {
+            proof.iter().map(|(l, r)| (Hash512::from_bytes(l).unwrap(), Hash512::from_bytes(r).unwrap())).collect()
+        };
+        assert_eq!(service.get_merkle_tree_version(), None);
+
+        service.hash_store.add_hash(first).unwrap();
+        service.update_merkle_tree().unwrap();
+        service.hash_store.add_hash(second).unwrap();
+        service.update_merkle_tree().unwrap();
+        assert_eq!(service.get_merkle_tree_version(), Some(1));
+
+        // A proof against the previous tree verifies against the root recorded for its version
+        let proof = parse(service.get_merkle_proof_at_version(&first, LeafEncoding::V1, 0).unwrap().unwrap());
+        let old_root = service.epochs.read().unwrap().get(0).unwrap().root.unwrap();
+        assert!(MerkleTree::verify_proof(&first, &proof, &old_root));
+        assert!(!MerkleTree::verify_proof(&first, &proof, &service.get_merkle_tree_root().unwrap()));
+        assert_eq!(service.get_merkle_proof_at_version(&second, LeafEncoding::V1, 0).unwrap(), None);
+        assert!(service.get_merkle_proof_at_version(&second, LeafEncoding::V1, 1).unwrap().is_some());
+
+        // Batches get the same proofs as single lookups
+        let missing = Hash512([3u64, 0, 0, 0, 0, 0, 0, 0]);
+        let proofs = service.get_merkle_proofs_at_version(&[second, missing, first], LeafEncoding::V1, 1).unwrap();
+        assert_eq!(proofs[0], service.get_merkle_proof_at_version(&second, LeafEncoding::V1, 1).unwrap());
+        assert_eq!(proofs[1], None);
+        assert_eq!(proofs[2], service.get_merkle_proof_at_version(&first, LeafEncoding::V1, 1).unwrap());
+        assert_eq!(service.hash_store.contains_batch_with_encoding(&[second, missing, first], LeafEncoding::V1).unwrap(), vec![true, false, true]);
+
+        // Only one previous tree is retained
+        service.update_merkle_tree().unwrap();
+        assert!(matches!(service.get_merkle_proof_at_version(&first, LeafEncoding::V1, 0), Err(ServiceError::TreeVersionUnavailable(0))));
+        assert!(service.get_merkle_proof_at_version(&first, LeafEncoding::V1, 1).unwrap().is_some());
+        assert!(service.get_merkle_proof_at_version(&first, LeafEncoding::V1, 3).is_err());
+    }
+
+    #[test]
+    fn test_feed() {
+        let store = MultiThreadedHashStore::<8, 0>::new(2, SALT);
+        store.add_hash(Hash512([1, 0, 0, 0, 0, 0, 0, 0])).unwrap();
+        let mut feed = store.subscribe_feed();
+        let metadata = HashMetadata::new(None, None, None).with_namespace(Some("logs".to_string()));
+        let hashes = [Hash512([1, 0, 0, 0, 0, 0, 0, 0]), Hash512([2, 0, 0, 0, 0, 0, 0, 0]), Hash512([u64::MAX, 0, 0, 0, 0, 0, 0, 0])];
+        store.add_batch_with_metadata(&hashes, LeafEncoding::default(), Some(metadata)).unwrap();
+        store.add_blinded(&[Hash512([3, 0, 0, 0, 0, 0, 0, 0])], LeafEncoding::default()).unwrap();
+
+        // Only new hashes added with the store salt, in the order of their worker
+        let mut accepted = Vec::new();
+        while let Ok(hash) = feed.try_recv() {
+            assert_eq!(hash.metadata.unwrap().namespace.as_deref(), Some("logs"));
+            assert!(hash.sequence.is_some_and(|sequence| sequence > 0));
+            accepted.push(hash.salted_hash);
+        }
+        accepted.sort();
+        let mut expected: Vec<Hash512> = hashes[1..].iter().map(|hash| LeafEncoding::default().leaf(hash, &SALT)).collect();
+        expected.sort();
+        assert_eq!(accepted, expected);
+    }
+
+    #[test]
+    fn test_retention() {
+        let retention = HashMap::from([("tmp".to_string(), Duration::ZERO), ("audit".to_string(), Duration::from_secs(3600))]);
+        let service = TimestampingService::<8, 0>::with_threads(2).with_retention(retention);
+        let hashes: Vec<Hash512> = (0..4u64).map(|i| Hash512([i << 62, 1, 0, 0, 0, 0, 0, 0])).collect();
+        let in_namespace = |namespace: &str| Some(HashMetadata::new(None, None, None).with_namespace(Some(namespace.to_string())));
+        let store = &service.hash_store;
+        store.add_batch_with_metadata(&hashes[..2], LeafEncoding::default(), in_namespace("tmp")).unwrap();
+        store.add_batch_with_metadata(&hashes[2..3], LeafEncoding::default(), in_namespace("audit")).unwrap();
+        store.add_hash(hashes[3]).unwrap();
+
+        // Expired at the next tree, which still includes their leaves
+        service.update_merkle_tree().unwrap();
+        let exists: Vec<bool> = hashes.iter().map(|hash| store.contains(hash).unwrap()).collect();
+        assert_eq!(exists, vec![false, false, true, true]);
+        assert_eq!(service.get_merkle_tree_size(), MerkleTree::new(store.to_array().unwrap(), SALT).size());
+        assert_eq!(store.stats().tombstones, 2);
+        let actions = service.admin_actions();
+        assert_eq!(actions.len(), 1);
+        assert_eq!((actions[0].action, actions[0].removed, actions[0].tree_version), (AdminActionKind::Expire, 2, Some(0)));
+
+        // Nothing left to expire
+        service.update_merkle_tree().unwrap();
+        assert_eq!(service.admin_actions().len(), 1);
+        assert_eq!(store.expire(&Arc::new(HashMap::from([("audit".to_string(), 3600)])), u64::MAX).unwrap(), HashMap::from([("audit".to_string(), 1)]));
+
+        // Namespaces are restored from the leaf logs, so hashes added before a restart still expire
+        let dir = std::env::temp_dir().join(format!("timestamping-retention-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let service = TimestampingService::<8, 0>::open(2, &dir, Arc::new(Sha512Hasher)).unwrap();
+        service.hash_store.add_batch_with_metadata(&hashes[..2], LeafEncoding::default(), in_namespace("tmp")).unwrap();
+        service.hash_store.add_hash(hashes[3]).unwrap();
+        service.shutdown();
+        let retention = HashMap::from([("tmp".to_string(), Duration::ZERO)]);
+        let service = TimestampingService::<8, 0>::open(2, &dir, Arc::new(Sha512Hasher)).unwrap().with_retention(retention.clone());
+        assert_eq!(service.hash_store.metadata(&hashes[0]).unwrap().unwrap().namespace.as_deref(), Some("tmp"));
+        service.update_merkle_tree().unwrap();
+        let exists: Vec<bool> = [0, 1, 3].map(|i| service.hash_store.contains(&hashes[i]).unwrap()).to_vec();
+        assert_eq!(exists, vec![false, false, true]);
+        service.shutdown();
+
+        // and stay removed, without their metadata
+        let service = TimestampingService::<8, 0>::open(2, &dir, Arc::new(Sha512Hasher)).unwrap().with_retention(retention);
+        assert!(!service.hash_store.contains(&hashes[0]).unwrap());
+        assert_eq!(service.hash_store.metadata(&hashes[0]).unwrap(), None);
+        assert_eq!(service.hash_store.expire(&service.retention, u64::MAX).unwrap(), HashMap::new());
+        service.shutdown();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_proof_cache() {
+        let service = TimestampingService::<8, 0>::with_threads(2).with_retained_trees(1).with_proof_cache(16);
+        let first = Hash512([1u64, 0, 0, 0, 0, 0, 0, 0]);
+        let second = Hash512([2u64, 0, 0, 0, 0, 0, 0, 0]);
+        service.hash_store.add_hash(first).unwrap();
+        service.update_merkle_tree().unwrap();
+
+        let proof = service.get_merkle_proof(&first);
+        assert!(proof.is_some());
+        assert_eq!(service.get_merkle_proof(&first), proof);
+        assert_eq!(service.get_merkle_proof_at_version(&first, LeafEncoding::default(), 0).unwrap(), proof);
+        let stats = service.proof_cache_stats().unwrap();
+        assert_eq!((stats.entries, stats.hits, stats.misses), (1, 2, 1));
+
+        // Cleared on tree updates, and proofs of the previous version aren't mixed up with the current ones
+        service.hash_store.add_hash(second).unwrap();
+        service.update_merkle_tree().unwrap();
+        assert_eq!(service.proof_cache_stats().unwrap().entries, 0);
+        assert_ne!(service.get_merkle_proof(&first), proof);
+        assert_eq!(service.get_merkle_proof_at_version(&first, LeafEncoding::default(), 0).unwrap(), proof);
+
+        // A replica publishing the same version again with more leaves doesn't answer from the old tree
+        let replica = TimestampingService::<8, 0>::from_store(MultiThreadedHashStore::<8, 0>::new(2, SALT)).with_proof_cache(16);
+        let head = TreeHead { version: 5, root: None, tree_size: 0, leaf_count: 0, timestamp: 0 };
+        replica.hash_store.add_hash(first).unwrap();
+        replica.publish_replicated_tree(&head).unwrap();
+        assert_eq!(replica.get_merkle_proof(&second), None);
+        replica.hash_store.add_hash(second).unwrap();
+        replica.publish_replicated_tree(&head).unwrap();
+        assert!(replica.get_merkle_proof(&second).is_some());
+    }
+
+    #[test]
+    fn test_published_tree_snapshots() {
+        let service = TimestampingService::<8, 0>::with_threads(2).with_retained_trees(1).with_proof_cache(16);
+        let hash = Hash512([1u64, 0, 0, 0, 0, 0, 0, 0]);
+        service.hash_store.add_hash(hash).unwrap();
+        service.update_merkle_tree().unwrap();
+        let first = service.published_tree().unwrap();
+
+        // Readers get a whole tree with its head while newer ones are published
+        let reader = {
+            let service = service.clone();
+            thread::spawn(move || {
+                for _ in 0..1000 {
+                    let published = service.published_tree().unwrap();
+                    let tree = published.tree.as_ref().unwrap();
+                    assert_eq!((tree.root(), tree.size(), tree.leaf_count), (published.head.root, published.head.tree_size, published.head.leaf_count));
+                    let proof: Vec<(Hash512, Hash512)> = service.get_merkle_proof_from(&published, &hash, None, LeafEncoding::default()).unwrap().iter()
+                        .map(|(left, right)| (Hash512::from_bytes(left).unwrap(), Hash512::from_bytes(right).unwrap()))
+                        .collect();
+                    assert!(MerkleTree::verify_proof(&hash, &proof, &published.head.root.unwrap()));
+                }
+            })
+        };
+        for i in 2..50u64 {
+            service.hash_store.add_hash(Hash512([i << 56, 0, 0, 0, 0, 0, 0, 0])).unwrap();
+            service.update_merkle_tree().unwrap();
+        }
+        reader.join().unwrap();
+        assert_eq!(service.published_tree().unwrap().head.leaf_count, 49);
+
+        // A loaded tree still makes proofs once it is neither current nor retained
+        assert!(service.get_merkle_proof_at_version(&hash, LeafEncoding::default(), 0).is_err());
+        let proof = service.get_merkle_proof_from(&first, &hash, None, LeafEncoding::default()).unwrap();
+        assert_eq!(proof.len(), 1);
+        assert_ne!(service.get_merkle_proof(&hash), Some(proof));
+    }
+
+    #[test]
+    fn test_mountain_range_matches_merkle_tree() {
+        let leaves: Vec<Hash512> = (0..33u64).map(|i| hash512(Hash512([i, 0, 0, 0, 0, 0, 0, 0]), SALT)).collect();
+        let mut range = MerkleMountainRange::new(SALT, Arc::new(Sha512Hasher));
+        assert_eq!(range.root(), None);
+        // Leaves are found by their sequence numbers, which may skip some
+        for (i, leaf) in leaves.iter().enumerate() {
+            assert_eq!(range.append(*leaf, 2 * i as u64), i);
+        }
+        assert_eq!(range.leaf_count(), 33);
+        assert_eq!(range.size(), 2 * 33 - 2);
+        assert_eq!((range.index_of(6), range.index_of(7)), (Some(3), None));
+        let proof = range.get_with_salt(&Hash512([3, 0, 0, 0, 0, 0, 0, 0]), &SALT, LeafEncoding::V1, 6, 33).unwrap();
+        assert_eq!(proof[1..], range.proof_at(3, 33).unwrap());
+        assert_eq!(range.get_with_salt(&Hash512([3, 0, 0, 0, 0, 0, 0, 0]), &SALT, LeafEncoding::V1, 8, 33), None);
+
+        // Every earlier leaf count still has the root and proofs of a tree over the leaves up to it
+        for leaf_count in 1..=leaves.len() {
+            let tree = MerkleTree::new(leaves[..leaf_count].to_vec(), SALT);
+            assert_eq!(range.root_at(leaf_count), tree.root());
+            for index in 0..leaf_count {
+                assert_eq!(range.proof_at(index, leaf_count), tree.proof_at(index));
+            }
+            assert_eq!(range.proof_at(leaf_count, leaf_count), None);
+        }
+        assert_eq!(range.root_at(34), None);
+    }
+
+    #[test]
+    fn test_service_mountain_range() {
+        let service = TimestampingService::<8, 0>::with_threads(2).with_mountain_range();
+        let first = Hash512([1u64, 0, 0, 0, 0, 0, 0, 0]);
+        let second = Hash512([1u64 << 63, 0, 0, 0, 0, 0, 0, 0]);
+        let parse = |proof: Vec<(Vec<u8>, Vec<u8>)>| -> Vec<(Hash512, Hash512)> {
+            proof.iter().map(|(l, r)| (Hash512::from_bytes(l).unwrap(), Hash512::from_bytes(r).unwrap())).collect()
+        };
+        assert!(service.uses_mountain_range());
+        assert_eq!(service.get_merkle_proof(&first), None);
+
+        service.hash_store.add_hash(first).unwrap();
+        service.update_merkle_tree().unwrap();
+        let proof = service.get_merkle_proof_at_version(&first, LeafEncoding::V1, 0).unwrap().unwrap();
+        service.hash_store.add_hash(second).unwrap();
+        service.update_merkle_tree().unwrap();
+        service.update_merkle_tree().unwrap();
+        assert_eq!(service.get_merkle_tree_size(), 3);
+        // Every update only appended the hashes added since the one before
+        let published = service.mountain_range.as_ref().unwrap().read().unwrap();
+        assert_eq!((published.sequence_bound, published.range.index_of(1)), (2, Some(1)));
+        drop(published);
+
+        // Proofs against every version are still the ones issued when it was published
+        let old_root = service.epochs.read().unwrap().get(0).unwrap().root.unwrap();
+        assert_eq!(service.get_merkle_proof_at_version(&first, LeafEncoding::V1, 0).unwrap().unwrap(), proof);
+        assert!(MerkleTree::verify_proof(&first, &parse(proof), &old_root));
+        assert_eq!(service.get_merkle_proof_at_version(&second, LeafEncoding::V1, 0).unwrap(), None);
+        let root = service.get_merkle_tree_root().unwrap();
+        for version in [1, 2] {
+            let proof = parse(service.get_merkle_proof_at_version(&second, LeafEncoding::V1, version).unwrap().unwrap());
+            assert!(MerkleTree::verify_proof(&second, &proof, &root));
+        }
+        assert!(matches!(service.get_merkle_proof_at_version(&first, LeafEncoding::V1, 3), Err(ServiceError::TreeVersionUnavailable(3))));
+        assert_eq!(service.epochs.read().unwrap().get(1).unwrap().leaf_count, 2);
+        assert!(service.audit_published_tree().is_none());
+        assert!(service.get_published_leaves(0, 0, 10).is_none());
+    }
+
+    #[test]
+    fn test_mountain_range_restart() {
+        let dir = std::env::temp_dir().join(format!("timestamping-mmr-restart-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let open = || TimestampingService::<8, 0>::open(2, &dir, Arc::new(Sha512Hasher)).unwrap()
+            .with_mountain_range()
+            .with_epoch_log(EpochLog::open(&dir.join("epochs.jsonl")).unwrap());
+        let hashes: Vec<Hash512> = (0..6u64).map(|i| Hash512([i << 62 | i, 0, 0, 0, 0, 0, 0, 0])).collect();
+
+        let service = open();
+        service.hash_store.add_batch(&hashes[..2]).unwrap();
+        service.update_merkle_tree().unwrap();
+        service.hash_store.add_batch(&hashes[2..4]).unwrap();
+        service.update_merkle_tree().unwrap();
+        // Removed hashes keep their leaves
+        assert!(service.remove_hash(&hashes[1], None, LeafEncoding::V1, None).unwrap());
+        let proofs = [0, 1].map(|version| service.get_merkle_proof_at_version(&hashes[0], LeafEncoding::V1, version).unwrap());
+        let root = service.get_merkle_tree_root();
+        service.close();
+
+        // Every version is rebuilt with the proofs issued before the restart, and new ones are appended after them
+        let service = open();
+        assert_eq!([0, 1].map(|version| service.get_merkle_proof_at_version(&hashes[0], LeafEncoding::V1, version).unwrap()), proofs);
+        assert!(service.get_merkle_proof_at_version(&hashes[3], LeafEncoding::V1, 1).unwrap().is_some());
+        service.hash_store.add_batch(&hashes[4..]).unwrap();
+        let head = service.update_merkle_tree().unwrap();
+        assert_eq!((head.version, head.leaf_count), (2, 6));
+        let published = service.mountain_range.as_ref().unwrap().read().unwrap();
+        assert_eq!(published.range.root_at(4), root);
+        drop(published);
+        service.close();
+
+        // A version the range doesn't reproduce, here because the epoch log claims a different root, has no proofs
+        let mut epochs = std::fs::read_to_string(dir.join("epochs.jsonl")).unwrap();
+        let first_root = root.unwrap().to_string();
+        assert!(epochs.contains(&first_root));
+        epochs = epochs.replace(&first_root, &Hash512([7; 8]).to_string());
+        std::fs::write(dir.join("epochs.jsonl"), epochs).unwrap();
+        let service = open();
+        assert!(matches!(service.get_merkle_proof_at_version(&hashes[0], LeafEncoding::V1, 1), Err(ServiceError::TreeVersionUnavailable(1))));
+        assert!(service.get_merkle_proof_at_version(&hashes[0], LeafEncoding::V1, 2).unwrap().is_some());
+        service.close();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_audit_published_tree() {
+        let dir = std::env::temp_dir().join(format!("timestamping-audit-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let service = TimestampingService::<4, 0>::with_leaf_logs(2, &dir).unwrap();
+
+        // Nothing to audit before the first tree
+        assert!(service.audit_published_tree().is_none());
+
+        for i in 0..50 {
+            service.hash_store.add_hash(Hash512([i << 58, i, 0, 0, 0, 0, 0, 0])).unwrap();
+        }
+        std::thread::sleep(Duration::from_millis(10));
+        service.update_merkle_tree().unwrap();
+
+        // The tree can be rebuilt offline from the logs and the lengths recorded for its epoch
+        let summary = service.epochs.read().unwrap().latest().cloned().unwrap();
+        assert_eq!(summary.leaf_log_lengths, leaf_log_lengths(&dir, 2).unwrap());
+        assert_eq!(summary.leaf_log_lengths.iter().sum::<u64>(), 50);
+        let leaves = read_leaf_logs(&dir, &summary.leaf_log_lengths).unwrap();
+        assert_eq!(MerkleTree::with_hasher(leaves, service.hash_store.salt, Arc::new(Sha512Hasher)).root(), summary.root);
+
+        // Hashes added after publishing don't affect the audit
+        service.hash_store.add_hash(Hash512([7u64, 7, 7, 7, 7, 7, 7, 7])).unwrap();
+        std::thread::sleep(Duration::from_millis(10));
+        let report = service.audit_published_tree().unwrap();
+        assert!(report.matches);
+        assert_eq!(report.leaf_count, 50);
+        assert_eq!(*service.audit_divergences.read().unwrap(), 0);
+
+        // Tampering with a logged leaf is detected
+        let path = LeafLog::path(&dir, 0);
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[0] ^= 1;
+        std::fs::write(&path, bytes).unwrap();
+        assert!(!service.audit_published_tree().unwrap().matches);
+        assert_eq!(*service.audit_divergences.read().unwrap(), 1);
+        assert_eq!(service.last_audit.read().unwrap().map(|report| report.matches), Some(false));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_verify_store() {
+        let dir = std::env::temp_dir().join(format!("timestamping-verify-store-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let service = TimestampingService::<4, 0>::with_leaf_logs(2, &dir).unwrap();
+        let check = service.verify_store().unwrap();
+        assert!(check.is_consistent());
+        assert_eq!((check.hashes, check.tree_version, check.root_matches), (0, None, None));
+
+        let hashes: Vec<Hash512> = (0..50u64).map(|i| Hash512([i << 58, i, 0, 0, 0, 0, 0, 0])).collect();
+        service.hash_store.add_batch(&hashes).unwrap();
+        service.update_merkle_tree().unwrap();
+        let check = service.verify_store().unwrap();
+        assert!(check.is_consistent(), "{:?}", check.discrepancies);
+        assert_eq!((check.hashes, check.tree_version, check.root_matches), (50, Some(0), Some(true)));
+
+        // Hashes added since the tree are left out using the leaf logs
+        service.hash_store.add_hash(Hash512([7; 8])).unwrap();
+        let check = service.verify_store().unwrap();
+        assert!(check.is_consistent(), "{:?}", check.discrepancies);
+        assert_eq!((check.hashes, check.root_matches), (51, Some(true)));
+
+        // Without leaf logs, the tree is only rebuilt if no hash was added since
+        let without_logs = TimestampingService::<4, 0>::with_threads(2);
+        without_logs.hash_store.add_batch(&hashes).unwrap();
+        without_logs.update_merkle_tree().unwrap();
+        assert_eq!(without_logs.verify_store().unwrap().root_matches, Some(true));
+        without_logs.hash_store.add_hash(Hash512([7; 8])).unwrap();
+        let check = without_logs.verify_store().unwrap();
+        assert!(check.is_consistent());
+        assert_eq!((check.tree_version, check.root_matches), (None, None));
+
+        // Unsorted buckets, duplicates and a wrong count are reported
+        let store = &service.hash_store.worker_stores().unwrap()[0];
+        {
+            let mut shards = store.shards.iter().map(|shard| shard.write().unwrap());
+            let mut shard = shards.find(|shard| shard.buckets.iter().flatten().any(|bucket| bucket.len() > 1)).unwrap();
+            let bucket = shard.buckets.iter_mut().flatten().find(|bucket| bucket.len() > 1).unwrap();
+            bucket.swap(0, 1);
+        }
+        let check = service.verify_store().unwrap();
+        assert_eq!(check.discrepancies.len(), 1, "{:?}", check.discrepancies);
+        assert!(check.discrepancies[0].starts_with("Worker 0: bucket") && check.discrepancies[0].ends_with("isn't sorted"));
+        {
+            let mut shards = store.shards.iter().map(|shard| shard.write().unwrap());
+            let mut shard = shards.find(|shard| shard.buckets.iter().flatten().any(|bucket| bucket.len() > 1)).unwrap();
+            let bucket = shard.buckets.iter_mut().flatten().find(|bucket| bucket.len() > 1).unwrap();
+            bucket.sort_unstable();
+            let duplicate = bucket[0];
+            bucket.insert(0, duplicate);
+        }
+        let check = service.verify_store().unwrap();
+        assert!(check.discrepancies.iter().any(|problem| problem.ends_with("holds a hash twice")));
+        assert!(check.discrepancies.iter().any(|problem| problem.ends_with("are counted")));
+        assert_eq!(check.root_matches, Some(true));
+
+        // So is a tree that doesn't match the stored hashes
+        let lost = TimestampingService::<4, 0>::with_threads(2);
+        lost.hash_store.add_batch(&hashes).unwrap();
+        lost.update_merkle_tree().unwrap();
+        let store = &lost.hash_store.worker_stores().unwrap()[1];
+        for shard in &store.shards {
+            for bucket in shard.write().unwrap().buckets.iter_mut().flatten() {
+                bucket[0].0.0[1] ^= 1;
+                bucket.sort_unstable();
+            }
+        }
+        let check = lost.verify_store().unwrap();
+        assert_eq!(check.root_matches, Some(false));
+        assert!(check.discrepancies.iter().any(|problem| problem.starts_with("The rebuilt root of tree version 0")));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_timestamping_service_restart() {
+        let dir = std::env::temp_dir().join(format!("timestamping-restart-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let hashes: Vec<Hash512> = (0..20u64).map(|i| Hash512([i << 59, i, 0, 0, 0, 0, 0, 0])).collect();
+
+        let service = TimestampingService::<8, 0>::open(4, &dir, Arc::new(Sha512Hasher)).unwrap();
+        service.hash_store.add_batch(&hashes[..10]).unwrap();
+        service.shutdown();
+        let root = service.get_merkle_tree_root();
+        let proof = service.get_merkle_proof(&hashes[3]);
+
+        // The salt is reused and the hashes are restored, so old proofs are reproduced
+        let service = TimestampingService::<8, 0>::open(4, &dir, Arc::new(Sha512Hasher)).unwrap();
+        assert_eq!(service.hash_store.len(), 10);
+        assert_eq!(service.hash_store.stats().worker_hashes.iter().sum::<usize>(), 10);
+        assert!(hashes[..10].iter().all(|hash| service.hash_store.contains(hash).unwrap()));
+        service.update_merkle_tree().unwrap();
+        assert_eq!(service.get_merkle_tree_root(), root);
+        assert_eq!(service.get_merkle_proof(&hashes[3]), proof);
+
+        // Metadata is logged with the hashes and read back, a partial line from a crash is cut off
+        let metadata = HashMetadata::new(Some("alice".to_string()), None, None).with_namespace(Some("ci".to_string()));
+        service.hash_store.add_batch_with_metadata(&hashes[10..12], LeafEncoding::V1, Some(metadata.clone())).unwrap();
+        // So are resubmissions, of which the latest count is restored
+        service.hash_store.add_hash(hashes[10]).unwrap();
+        service.hash_store.add_batch(&hashes[10..11]).unwrap();
+        let resubmissions = service.hash_store.resubmissions_with_encoding(&hashes[10], LeafEncoding::V1).unwrap();
+        assert_eq!(resubmissions.map(|resubmissions| resubmissions.count), Some(2));
+        service.shutdown();
+        let mut file = OpenOptions::new().append(true).open(LeafLog::metadata_path(&dir, 0)).unwrap();
+        file.write_all(b"{\"leaf\":").unwrap();
+        let service = TimestampingService::<8, 0>::open(4, &dir, Arc::new(Sha512Hasher)).unwrap();
+        assert_eq!(service.hash_store.metadata(&hashes[10]).unwrap(), Some(metadata.clone()));
+        assert_eq!(service.hash_store.metadata(&hashes[11]).unwrap(), Some(metadata));
+        assert_eq!(service.hash_store.metadata(&hashes[3]).unwrap(), None);
+        assert_eq!(service.hash_store.resubmissions_with_encoding(&hashes[10], LeafEncoding::V1).unwrap(), resubmissions);
+        assert_eq!(service.hash_store.resubmissions_with_encoding(&hashes[11], LeafEncoding::V1).unwrap(), None);
+        service.shutdown();
+        let service = TimestampingService::<8, 0>::open(4, &dir, Arc::new(Sha512Hasher)).unwrap();
+        assert_eq!(service.hash_store.len(), 12);
+        assert!(service.hash_store.metadata(&hashes[10]).unwrap().is_some());
+
+        // New hashes are appended to the existing logs
+        assert_eq!(service.hash_store.add_batch(&hashes[5..]).unwrap(), [vec![false; 7], vec![true; 8]].concat());
+        service.shutdown();
+        let service = TimestampingService::<8, 0>::open(4, &dir, Arc::new(Sha512Hasher)).unwrap();
+        assert_eq!(service.hash_store.len(), 20);
+        assert!(service.audit_published_tree().is_none());
+
+        // Other parameters would make the logs unreadable
+        assert!(TimestampingService::<8, 0>::open(2, &dir, Arc::new(Sha512Hasher)).is_err());
+        assert!(TimestampingService::<8, 0>::open(4, &dir, Arc::new(Blake3Hasher)).is_err());
+        assert!(TimestampingService::<8, 0>::open_partitioned(4, &dir, Arc::new(Sha512Hasher), Partitioning::RoundRobin, 8).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_remove_hashes() {
+        let dir = std::env::temp_dir().join(format!("timestamping-remove-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let hashes: Vec<Hash512> = (0..10u64).map(|i| Hash512([i << 59, i, 0, 0, 0, 0, 0, 0])).collect();
+        let metadata = HashMetadata::new(Some("alice".to_string()), None, None);
+
+        let service = TimestampingService::<8, 0>::open(4, &dir, Arc::new(Sha512Hasher)).unwrap()
+            .with_admin_log(AdminLog::open(&dir.join("admin.jsonl")).unwrap());
+        service.hash_store.add_batch_with_metadata(&hashes, LeafEncoding::V1, Some(metadata)).unwrap();
+        let blinded = Hash512([99, 0, 0, 0, 0, 0, 0, 0]);
+        let nonce = service.hash_store.add_blinded(&[blinded], LeafEncoding::V1).unwrap()[0];
+        service.update_merkle_tree().unwrap();
+        let root = service.get_merkle_tree_root();
+        let proof = service.get_merkle_proof(&hashes[3]);
+
+        assert!(service.remove_hash(&hashes[3], None, LeafEncoding::V1, Some("takedown".to_string())).unwrap());
+        assert!(!service.remove_hash(&hashes[3], None, LeafEncoding::V1, None).unwrap());
+        assert!(!service.remove_hash(&Hash512([1; 8]), None, LeafEncoding::V1, None).unwrap());
+        assert!(!service.hash_store.contains(&hashes[3]).unwrap());
+        assert!(service.hash_store.metadata(&hashes[3]).unwrap().is_none());
+        assert!(service.hash_store.metadata(&hashes[4]).unwrap().is_some());
+        assert_eq!(service.hash_store.iter_range(0, 100, None).unwrap().len(), 10);
+
+        // The leaf stays, so the tree and its proofs don't change
+        assert_eq!(service.hash_store.len(), 11);
+        assert_eq!(service.hash_store.stats().tombstones, 1);
+        service.update_merkle_tree().unwrap();
+        assert_eq!(service.get_merkle_tree_root(), root);
+        assert_eq!(service.get_merkle_proof(&hashes[3]), proof);
+
+        // Resubmitting doesn't bring a hash back
+        assert_eq!(service.hash_store.add_hash(hashes[3]), Ok(false));
+        assert!(!service.hash_store.contains(&hashes[3]).unwrap());
+
+        assert!(service.remove_hash(&blinded, Some(&nonce), LeafEncoding::V1, None).unwrap());
+        assert!(!service.hash_store.contains_blinded(&blinded, &nonce, LeafEncoding::V1).unwrap());
+        assert_eq!(service.remove_all_hashes(None).unwrap(), 9);
+        assert!(service.hash_store.iter_range(0, 100, None).unwrap().is_empty());
+        let actions: Vec<AdminActionKind> = service.admin_actions().iter().map(|action| action.action).collect();
+        assert_eq!(actions, [AdminActionKind::Remove, AdminActionKind::Remove, AdminActionKind::RemoveAll]);
+        assert_eq!(service.admin_actions()[0].reason.as_deref(), Some("takedown"));
+        assert_eq!(service.admin_actions()[0].tree_version, Some(0));
+        service.shutdown();
+
+        // Tombstones and the admin log survive a restart
+        let service = TimestampingService::<8, 0>::open(4, &dir, Arc::new(Sha512Hasher)).unwrap()
+            .with_admin_log(AdminLog::open(&dir.join("admin.jsonl")).unwrap());
+        assert_eq!(service.hash_store.len(), 11);
+        assert_eq!(service.hash_store.stats().tombstones, 11);
+        assert!(hashes.iter().all(|hash| !service.hash_store.contains(hash).unwrap()));
+        assert_eq!(service.admin_actions().len(), 3);
+        service.update_merkle_tree().unwrap();
+        assert_eq!(service.get_merkle_tree_root(), root);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_resalt() {
+        let dir = std::env::temp_dir().join(format!("timestamping-resalt-{}", std::process::id()));
+        let (old_dir, new_dir) = (dir.join("old"), dir.join("new"));
+        std::fs::create_dir_all(&old_dir).unwrap();
+        let hashes: Vec<Hash512> = (0..10u64).map(|i| Hash512([i << 59, i, 0, 0, 0, 0, 0, 0])).collect();
+        let key = SigningKey::from_bytes(&[1; 32]);
+
+        let service = TimestampingService::<8, 0>::open(4, &old_dir, Arc::new(Sha512Hasher)).unwrap();
+        service.hash_store.add_batch(&hashes).unwrap();
+        assert!(matches!(service.resalt(&hashes, LeafEncoding::V1, &new_dir, None), Err(ServiceError::ResaltRefused(_))));
+        let service = service.with_signing_key(key.clone());
+        assert!(matches!(service.resalt(&hashes, LeafEncoding::V1, &new_dir, None), Err(ServiceError::ResaltRefused(_))));
+        service.update_merkle_tree().unwrap();
+        service.remove_hash(&hashes[9], None, LeafEncoding::V1, None).unwrap();
+
+        // Only stored hashes can be moved, removed ones included
+        let unknown = [hashes[0], hashes[9], Hash512([7; 8])];
+        assert!(matches!(service.resalt(&unknown, LeafEncoding::V1, &new_dir, None), Err(ServiceError::UnknownHashes(2))));
+        assert!(!new_dir.exists());
+
+        let migration = service.resalt(&hashes[..8], LeafEncoding::V1, &new_dir, Some("leaked salt".to_string())).unwrap();
+        assert_eq!(migration.verify(&key.verifying_key()), Ok(()));
+        assert_eq!((migration.old_version, migration.old_leaf_count), (0, 10));
+        assert_eq!(Some(migration.old_root), service.get_merkle_tree_root());
+        assert_eq!((migration.new_leaf_count, migration.missing_hashes), (8, 1));
+        assert_eq!(service.admin_actions().last().unwrap().action, AdminActionKind::Resalt);
+        let saved: SaltMigration = serde_json::from_str(&std::fs::read_to_string(new_dir.join(SALT_MIGRATION_FILE)).unwrap()).unwrap();
+        assert_eq!(saved, migration);
+        assert!(matches!(service.resalt(&hashes[..8], LeafEncoding::V1, &new_dir, None), Err(ServiceError::ResaltRefused(_))));
+
+        // The server keeps its salt, a restart on the new store proves the hashes under the new salt and root
+        assert_ne!(service.hash_store.salt(), &migration.new_salt);
+        assert!(service.hash_store.contains(&hashes[0]).unwrap());
+        let resalted = TimestampingService::<8, 0>::open(4, &new_dir, Arc::new(Sha512Hasher)).unwrap();
+        assert_eq!(resalted.hash_store.salt(), &migration.new_salt);
+        resalted.update_merkle_tree().unwrap();
+        assert_eq!(resalted.get_merkle_tree_root(), Some(migration.new_root));
+        let proof = resalted.get_merkle_proof(&hashes[3]).unwrap();
+        assert_eq!(Hash512::from_bytes(&proof[0].1).unwrap(), migration.new_salt);
+        assert!(!resalted.hash_store.contains(&hashes[8]).unwrap());
+        resalted.close();
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_replica() {
+        let dir = std::env::temp_dir().join(format!("timestamping-replica-{}", std::process::id()));
+        let (primary_dir, replica_dir) = (dir.join("primary"), dir.join("replica"));
+        std::fs::create_dir_all(&primary_dir).unwrap();
+        std::fs::create_dir_all(&replica_dir).unwrap();
+        let hashes: Vec<Hash512> = (0..30u64).map(|i| Hash512([i << 59, i, 0, 0, 0, 0, 0, 0])).collect();
+
+        let primary = TimestampingService::<8, 0>::open(4, &primary_dir, Arc::new(Sha512Hasher)).unwrap();
+        let replica = TimestampingService::<8, 0>::open_replica(4, &replica_dir, Arc::new(Sha512Hasher), Partitioning::default(), *primary.hash_store.salt(), 8).unwrap();
+        let sync = |limit: u64| {
+            let (head, _) = primary.get_published_head().unwrap();
+            for thread_index in 0..4 {
+                let offset = replica.hash_store.stats().worker_hashes[thread_index] as u64;
+                let leaves = primary.get_published_leaves(thread_index, offset, limit).unwrap().unwrap();
+                replica.hash_store.add_replicated(thread_index, leaves).unwrap();
+            }
+            replica.publish_replicated_tree(&head).unwrap()
+        };
+
+        primary.hash_store.add_batch(&hashes[..10]).unwrap();
+        primary.update_merkle_tree().unwrap();
+        // Hashes added after publishing are not replicated before the next tree
+        primary.hash_store.add_batch(&hashes[10..20]).unwrap();
+        assert!(sync(u64::MAX));
+        assert_eq!(replica.hash_store.len(), 10);
+        assert_eq!(replica.get_merkle_tree_root(), primary.get_merkle_tree_root());
+        assert_eq!(replica.get_merkle_proof(&hashes[4]), primary.get_merkle_proof(&hashes[4]));
+
+        // A replica that missed versions takes over the primary's version numbers
+        primary.update_merkle_tree().unwrap();
+        primary.hash_store.add_batch(&hashes[20..]).unwrap();
+        primary.update_merkle_tree().unwrap();
+        assert!(!sync(1));
+        assert_eq!(*replica.replication_divergences.read().unwrap(), 1);
+        assert!(sync(u64::MAX));
+        assert_eq!(replica.get_merkle_tree_version(), Some(2));
+        assert_eq!(replica.get_merkle_tree_root(), primary.get_merkle_tree_root());
+        let epochs = replica.epochs.read().unwrap();
+        assert_eq!(epochs.get(2).unwrap().root, primary.get_merkle_tree_root());
+        assert!(epochs.get(1).is_none());
+        assert_eq!(epochs.len(), 2);
+        drop(epochs);
+
+        // The replica's own config pins the primary's salt
+        replica.close();
+        assert!(TimestampingService::<8, 0>::open_replica(4, &replica_dir, Arc::new(Sha512Hasher), Partitioning::default(), SALT, 8).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_timestamping_service_shutdown() {
+        let dir = std::env::temp_dir().join(format!("timestamping-shutdown-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let service = TimestampingService::<8, 0>::with_leaf_logs(2, &dir).unwrap();
+
+        // No sleep needed, shutdown processes everything that was queued before
+        for i in 0..100 {
+            service.hash_store.add_hash(Hash512([i << 57, 0, 0, 0, 0, 0, 0, 0])).unwrap();
+        }
+        service.shutdown();
+
+        assert_eq!(service.published_tree().unwrap().head.leaf_count, 100);
+        let logged_bytes: u64 = (0..2).map(|i| std::fs::metadata(LeafLog::path(&dir, i)).unwrap().len()).sum();
+        assert_eq!(logged_bytes, 100 * 64);
+
+        // Workers are gone
+        assert!(matches!(service.hash_store.contains(&Hash512([0u64; 8])), Err(StorageError::WorkerUnavailable(_))));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_publish_with_unavailable_worker() {
+        // A tree without the hashes of a worker that didn't answer is neither published nor signed
+        for service in [TimestampingService::<8, 0>::with_threads(2), TimestampingService::<8, 0>::with_threads(2).with_mountain_range()] {
+            service.hash_store.add_batch(&[Hash512([1, 0, 0, 0, 0, 0, 0, 0]), Hash512([1 << 63, 0, 0, 0, 0, 0, 0, 0])]).unwrap();
+            service.hash_store.inject_fault(1, Fault::DropResponses(1));
+            assert_eq!(service.update_merkle_tree(), Err(StorageError::WorkerUnavailable(1)));
+            assert_eq!(service.get_merkle_tree_version(), None);
+            assert_eq!(service.epochs.read().unwrap().len(), 0);
+            assert_eq!(service.update_merkle_tree().unwrap().leaf_count, 2);
+        }
+
+        // Nor is a merge reported as complete
+        let store = MultiThreadedHashStore::<8, 0>::new(2, SALT);
+        let other = MultiThreadedHashStore::<8, 0>::new(2, SALT);
+        other.add_hash(Hash512([1 << 63, 0, 0, 0, 0, 0, 0, 0])).unwrap();
+        other.inject_fault(1, Fault::DropResponses(1));
+        assert!(matches!(store.merge(&other), Err(MergeError::Storage(StorageError::WorkerUnavailable(1)))));
+        assert_eq!(store.merge(&other).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_epoch_log_persistence() {
+        let path = std::env::temp_dir().join(format!("timestamping-epochs-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut summary = EpochSummary {
+            epoch: 0,
+            timestamp: 42,
+            leaf_count: 10,
+            new_hashes: 10,
+            build_duration_ms: 3,
+            root: Some(Hash512([1u64, 2, 3, 4, 5, 6, 7, 8])),
+            tree_size: 31,
+            anchor_txid: None,
+            signature: None,
+            transparency_log: None,
+            time_source: None,
+            leaf_log_lengths: vec![4, 6],
+            sequence_bound: Some(10),
+            cosignatures: Vec::new(),
+        };
+        let entry = TransparencyLogEntry {
+            log_url: "https://rekor.example".to_string(),
+            uuid: "24296fb2".to_string(),
+            log_index: 7,
+            integrated_time: 43,
+            log_id: "c0d23d6a".to_string(),
+            signed_entry_timestamp: None,
+            inclusion_proof: Some(serde_json::json!({"logIndex": 7, "treeSize": 8})),
+        };
+        {
+            let mut log = EpochLog::open(&path).unwrap();
+            assert!(log.is_empty());
+            log.append(summary.clone()).unwrap();
+            assert!(log.set_anchor(0, "abcd".to_string()).unwrap());
+            assert!(!log.set_anchor(1, "abcd".to_string()).unwrap());
+            assert!(log.set_transparency_log(0, entry.clone()).unwrap());
+            assert!(!log.set_transparency_log(1, entry.clone()).unwrap());
+            assert!(log.add_cosignature(0, Cosignature::sign(&SigningKey::from_bytes(&[1; 32]), b"head", 44)).unwrap());
+            assert!(log.add_cosignature(0, Cosignature::sign(&SigningKey::from_bytes(&[2; 32]), b"head", 45)).unwrap());
+            assert!(!log.add_cosignature(1, Cosignature::sign(&SigningKey::from_bytes(&[2; 32]), b"head", 45)).unwrap());
+            // A peer cosigning again replaces its cosignature
+            assert!(log.add_cosignature(0, Cosignature::sign(&SigningKey::from_bytes(&[1; 32]), b"head", 46)).unwrap());
+            // Every change counts, those of epochs that don't exist don't
+            assert_eq!(log.revision(), 6);
+            assert!(log.modified_at().is_some());
+        }
+
+        let log = EpochLog::open(&path).unwrap();
+        assert_eq!((log.revision(), log.modified_at()), (0, None));
+        summary.anchor_txid = Some("abcd".to_string());
+        summary.transparency_log = Some(entry);
+        summary.cosignatures = vec![
+            Cosignature::sign(&SigningKey::from_bytes(&[2; 32]), b"head", 45),
+            Cosignature::sign(&SigningKey::from_bytes(&[1; 32]), b"head", 46),
+        ];
+        assert_eq!(log.page(0, 10), &[summary]);
+
+        // Summaries written before roots were recorded can still be read
+        let line = |epoch| format!("{{\"epoch\":{},\"timestamp\":42,\"leaf_count\":10,\"new_hashes\":10,\"build_duration_ms\":3}}\n", epoch);
+        std::fs::write(&path, line(0)).unwrap();
+        let log = EpochLog::open(&path).unwrap();
+        assert_eq!(log.get(0).unwrap().root, None);
+
+        // Missing epochs are reported, except in replicas, whose epochs only have to increase
+        std::fs::write(&path, [line(0), line(1), line(0), line(3)].concat()).unwrap();
+        let error = EpochLog::open(&path).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(error.to_string().contains("line 4: epoch 3 follows epoch 1, expected 2"), "{}", error);
+        let log = EpochLog::open_replica(&path).unwrap();
+        assert_eq!((log.len(), log.get(3).map(|summary| summary.epoch)), (3, Some(3)));
+        std::fs::write(&path, [line(5), line(4)].concat()).unwrap();
+        assert!(EpochLog::open(&path).is_err());
+        assert!(EpochLog::open_replica(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
     #[test]
     fn test_hash_store_collision_handling() {
         let store = HashStore::<2, 0>::new(SALT); // Only 4 buckets
 
         // Create hashes where some will collide in the same bucket
         for i in 0..10 {
-            let hash = [i as u64, 0, 0, 0, 0, 0, 0, 0];
-            store.add_hash(hash);
+            let hash = Hash512([i as u64, 0, 0, 0, 0, 0, 0, 0]);
+            store.add_hash(hash).unwrap();
         }
 
         assert_eq!(store.len(), 10);
         for i in 0..10 {
-            let hash = [i as u64, 0, 0, 0, 0, 0, 0, 0];
+            let hash = Hash512([i as u64, 0, 0, 0, 0, 0, 0, 0]);
             assert!(store.contains(&hash));
         }
     }
 
     #[test]
     fn test_hash512_equality() {
-        let hash1 = [1u64, 2u64, 3u64, 4u64, 5u64, 6u64, 7u64, 8u64];
-        let hash2 = [1u64, 2u64, 3u64, 4u64, 5u64, 6u64, 7u64, 8u64];
-        let hash3 = [9u64, 10u64, 11u64, 12u64, 13u64, 14u64, 15u64, 16u64];
+        let hash1 = Hash512([1u64, 2u64, 3u64, 4u64, 5u64, 6u64, 7u64, 8u64]);
+        let hash2 = Hash512([1u64, 2u64, 3u64, 4u64, 5u64, 6u64, 7u64, 8u64]);
+        let hash3 = Hash512([9u64, 10u64, 11u64, 12u64, 13u64, 14u64, 15u64, 16u64]);
 
         assert_eq!(hash1, hash2);
         assert_ne!(hash1, hash3);
@@ -735,9 +7643,9 @@ mod tests {
 
     #[test]
     fn test_hash512_ordering() {
-        let hash1 = [1u64, 0, 0, 0, 0, 0, 0, 0];
-        let hash2 = [2u64, 0, 0, 0, 0, 0, 0, 0];
-        let hash3 = [1u64, 1u64, 0, 0, 0, 0, 0, 0];
+        let hash1 = Hash512([1u64, 0, 0, 0, 0, 0, 0, 0]);
+        let hash2 = Hash512([2u64, 0, 0, 0, 0, 0, 0, 0]);
+        let hash3 = Hash512([1u64, 1u64, 0, 0, 0, 0, 0, 0]);
 
         assert!(hash1 < hash2);
         assert!(hash1 < hash3);
@@ -748,7 +7656,7 @@ mod tests {
     fn test_merkle_tree_large_dataset() {
         let mut hashes = Vec::new();
         for i in 0..100 {
-            hashes.push([i as u64, 0, 0, 0, 0, 0, 0, 0]);
+            hashes.push(Hash512([i as u64, 0, 0, 0, 0, 0, 0, 0]));
         }
         let salted_hashes = hashes.iter().map(|hash| hash512(*hash, SALT)).collect();
 
@@ -772,8 +7680,8 @@ mod tests {
             let store_clone = Arc::clone(&store);
             let handle = std::thread::spawn(move || {
                 for j in 0..100 {
-                    let hash = [(i * 100 + j) as u64, 0, 0, 0, 0, 0, 0, 0];
-                    store_clone.add_hash(hash);
+                    let hash = Hash512([(i * 100 + j) as u64, 0, 0, 0, 0, 0, 0, 0]);
+                    store_clone.add_hash(hash).unwrap();
                 }
             });
             handles.push(handle);
@@ -790,9 +7698,108 @@ mod tests {
         // Verify all hashes are present
         for i in 0..10 {
             for j in 0..100 {
-                let hash = [(i * 100 + j) as u64, 0, 0, 0, 0, 0, 0, 0];
-                assert!(store.contains(&hash));
+                let hash = Hash512([(i * 100 + j) as u64, 0, 0, 0, 0, 0, 0, 0]);
+                assert!(store.contains(&hash).unwrap());
+            }
+        }
+    }
+
+    // Operations of the property tests, applied to a store and to a set as the model
+    #[derive(Debug, Clone)]
+    enum StoreOp {
+        Insert(Hash512),
+        Contains(Hash512),
+        ToArray,
+    }
+
+    // Hashes differing only in few bits, so that duplicates and bucket collisions are common
+    fn colliding_hash() -> impl proptest::strategy::Strategy<Value = Hash512> {
+        use proptest::prelude::*;
+        (0u64..16, 0u64..4).prop_map(|(low, high)| Hash512([low | (high << 62), 0, 0, 0, 0, 0, 0, high]))
+    }
+
+    fn store_op() -> impl proptest::strategy::Strategy<Value = StoreOp> {
+        use proptest::prelude::*;
+        prop_oneof![
+            4 => colliding_hash().prop_map(StoreOp::Insert),
+            4 => any::<[u64; 8]>().prop_map(|words| StoreOp::Insert(Hash512(words))),
+            3 => colliding_hash().prop_map(StoreOp::Contains),
+            1 => Just(StoreOp::ToArray),
+        ]
+    }
+
+    // Run `ops` on a fresh store and check it against the model after every step: the count matches,
+    // nothing inserted is lost, and `to_array` lists every bucket sorted and the buckets in index order
+    fn check_store_model<const INDEX_SIZE: usize, const PREFIX_SIZE: usize>(ops: &[StoreOp]) -> Result<(), proptest::test_runner::TestCaseError> {
+        use proptest::prelude::*;
+        let store = HashStore::<INDEX_SIZE, PREFIX_SIZE>::new(Hash512([3; 8]));
+        // Submitted hash to the salted hash it should be stored as
+        let mut model = HashMap::new();
+
+        for op in ops {
+            match op {
+                StoreOp::Insert(hash) => {
+                    let salted = store.leaf(hash, LeafEncoding::default());
+                    prop_assert_eq!(store.add_hash(*hash).unwrap(), model.insert(*hash, salted).is_none());
+                }
+                StoreOp::Contains(hash) => prop_assert_eq!(store.contains(hash), model.contains_key(hash)),
+                StoreOp::ToArray => {
+                    let array = store.to_array();
+                    let keys: Vec<(usize, Hash512)> = array.iter().map(|hash| (hash.to_index(PREFIX_SIZE, INDEX_SIZE), *hash)).collect();
+                    prop_assert!(keys.windows(2).all(|pair| pair[0] < pair[1]));
+                    let mut salted: Vec<Hash512> = model.values().copied().collect();
+                    salted.sort_by_key(|hash| (hash.to_index(PREFIX_SIZE, INDEX_SIZE), *hash));
+                    prop_assert_eq!(array, salted);
+                }
+            }
+            prop_assert_eq!(store.len(), model.len());
+        }
+        prop_assert!(model.keys().all(|hash| store.contains(hash)));
+        prop_assert!(store.occupied_slots() <= model.len().min(1 << INDEX_SIZE));
+        Ok(())
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn proptest_store_matches_model(ops in proptest::collection::vec(store_op(), 0..100)) {
+            check_store_model::<1, 0>(&ops)?;
+            check_store_model::<4, 0>(&ops)?;
+            check_store_model::<8, 3>(&ops)?;
+            check_store_model::<12, 0>(&ops)?;
+        }
+
+        #[test]
+        fn proptest_hash512_from_bytes(bytes in proptest::collection::vec(proptest::num::u8::ANY, 0..130)) {
+            match Hash512::from_bytes(&bytes) {
+                Ok(hash) => proptest::prop_assert_eq!(hash.to_bytes(), bytes),
+                Err(_) => proptest::prop_assert_ne!(bytes.len(), 64),
             }
         }
+
+        #[test]
+        fn proptest_merkle_proofs(
+            hashes in proptest::collection::btree_set(proptest::arbitrary::any::<[u64; 8]>(), 1..40),
+            index in proptest::arbitrary::any::<proptest::sample::Index>(),
+            flip in proptest::arbitrary::any::<(proptest::sample::Index, usize, u8)>(),
+        ) {
+            let hashes: Vec<Hash512> = hashes.into_iter().map(Hash512).collect();
+            let encoding = LeafEncoding::V2;
+            let tree = MerkleTree::new(hashes.iter().map(|hash| encoding.leaf(hash, &SALT)).collect(), SALT);
+            let root = tree.root().unwrap();
+            for hash in &hashes {
+                let proof = tree.get_with_encoding(hash, encoding).unwrap();
+                proptest::prop_assert!(MerkleTree::verify_proof_with_encoding(hash, &proof, &root, encoding));
+            }
+
+            // Flipping any bit of a proof breaks it
+            let hash = index.get(&hashes);
+            let mut proof = tree.get_with_encoding(hash, encoding).unwrap();
+            let (entry, word, bit) = flip;
+            let position = entry.index(proof.len());
+            let pair = &mut proof[position];
+            let side = if word % 2 == 0 { &mut pair.0 } else { &mut pair.1 };
+            side.0[word / 2 % 8] ^= 1 << (bit % 64);
+            proptest::prop_assert!(!MerkleTree::verify_proof_with_encoding(hash, &proof, &root, encoding));
+        }
     }
 }
@@ -0,0 +1,123 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, RwLock};
+use crate::storage::{Hash512, Hasher, MerkleTree, StorageError, TimestampingService, TreeHead};
+
+// Root of a tenant's tree when it has no tree yet
+const EMPTY_TENANT_ROOT: Hash512 = Hash512([0; 8]);
+
+// Top-level tree over the roots of all tenants, in the order of their names. A proof from a tenant's
+// tree, extended by the path of that tenant's root in this tree, proves inclusion under the global root.
+#[derive(Debug, Clone)]
+pub struct TenantTree {
+    pub version: u64,
+    // Name and published head of every tenant, `None` for tenants whose tree is empty
+    pub tenants: Vec<(String, Option<TreeHead>)>,
+    pub tree: MerkleTree,
+}
+
+impl TenantTree {
+    pub fn root(&self) -> Option<Hash512> {
+        self.tree.root()
+    }
+
+    pub fn position(&self, name: &str) -> Option<usize> {
+        self.tenants.iter().position(|(tenant, _)| tenant == name)
+    }
+
+    // The (left, right) pairs that extend a proof against the tenant's root up to the global root
+    pub fn proof(&self, name: &str) -> Option<Vec<(Hash512, Hash512)>> {
+        self.tree.proof_at(self.position(name)?)
+    }
+}
+
+// Services of several tenants, each with its own store and trees, so the hashes of one tenant
+// neither show up in the checks nor grow the proofs of another
+#[derive(Debug)]
+pub struct Tenants<const INDEX_SIZE: usize, const PREFIX_SIZE: usize> {
+    services: BTreeMap<String, Arc<TimestampingService<INDEX_SIZE, PREFIX_SIZE>>>,
+    hasher: Arc<dyn Hasher>,
+    tree: RwLock<Option<Arc<TenantTree>>>,
+    // Serializes tree updates, so versions are assigned in order
+    update_lock: Mutex<()>,
+}
+
+impl<const INDEX_SIZE: usize, const PREFIX_SIZE: usize> Tenants<INDEX_SIZE, PREFIX_SIZE> {
+    // The tenants' trees are combined with `hasher`, which they have to build their own trees with as well
+    pub fn new(services: BTreeMap<String, Arc<TimestampingService<INDEX_SIZE, PREFIX_SIZE>>>, hasher: Arc<dyn Hasher>) -> Self {
+        Self { services, hasher, tree: RwLock::new(None), update_lock: Mutex::new(()) }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Arc<TimestampingService<INDEX_SIZE, PREFIX_SIZE>>> {
+        self.services.get(name)
+    }
+
+    pub fn services(&self) -> impl Iterator<Item = (&String, &Arc<TimestampingService<INDEX_SIZE, PREFIX_SIZE>>)> {
+        self.services.iter()
+    }
+
+    // The latest tenant tree, `None` before the first update
+    pub fn tree(&self) -> Option<Arc<TenantTree>> {
+        self.tree.read().unwrap().clone()
+    }
+
+    // Update the trees of all tenants and combine their roots into a new tenant tree. Fails without a new tenant
+    // tree if one of the tenants' trees couldn't be published.
+    pub fn update_tree(&self) -> Result<Arc<TenantTree>, StorageError> {
+        let _guard = self.update_lock.lock().unwrap();
+        let tenants: Vec<(String, Option<TreeHead>)> = self.services.iter()
+            .map(|(name, service)| {
+                service.update_merkle_tree()?;
+                Ok((name.clone(), service.get_published_head().map(|(head, _)| head).filter(|head| head.root.is_some())))
+            })
+            .collect::<Result<_, StorageError>>()?;
+        let leaves = tenants.iter()
+            .map(|(_, head)| head.and_then(|head| head.root).unwrap_or(EMPTY_TENANT_ROOT))
+            .collect();
+        let version = self.tree().map_or(0, |tree| tree.version + 1);
+        let tree = Arc::new(TenantTree {
+            version,
+            tenants,
+            // The leaves are roots rather than submitted hashes, so they aren't salted
+            tree: MerkleTree::with_hasher(leaves, EMPTY_TENANT_ROOT, Arc::clone(&self.hasher)),
+        });
+        *self.tree.write().unwrap() = Some(Arc::clone(&tree));
+        Ok(tree)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{Hash512Ops, LeafEncoding, Sha512Hasher};
+
+    #[test]
+    fn test_tenant_tree() {
+        let services: BTreeMap<String, Arc<TimestampingService<8, 0>>> = ["alice", "bob", "carol"].iter()
+            .map(|name| (name.to_string(), Arc::new(TimestampingService::with_threads(2))))
+            .collect();
+        let tenants = Tenants::new(services, Arc::new(Sha512Hasher));
+        assert!(tenants.tree().is_none());
+        let hash = Hash512([1u64, 0, 0, 0, 0, 0, 0, 0]);
+        tenants.get("alice").unwrap().hash_store.add_hash(hash).unwrap();
+        tenants.get("bob").unwrap().hash_store.add_hash(Hash512([2, 0, 0, 0, 0, 0, 0, 0])).unwrap();
+
+        let tree = tenants.update_tree().unwrap();
+        assert_eq!(tree.version, 0);
+        assert_eq!(tree.tenants.iter().map(|(name, head)| (name.as_str(), head.is_some())).collect::<Vec<_>>(),
+            vec![("alice", true), ("bob", true), ("carol", false)]);
+        // Other tenants don't see the hash
+        assert!(!tenants.get("bob").unwrap().hash_store.contains(&hash).unwrap());
+
+        // A proof from the tenant's tree, extended up to the global root
+        let alice = tenants.get("alice").unwrap();
+        let mut proof: Vec<(Hash512, Hash512)> = alice.get_merkle_proof_with_encoding(&hash, LeafEncoding::V1).unwrap().iter()
+            .map(|(left, right)| (Hash512::from_bytes(left).unwrap(), Hash512::from_bytes(right).unwrap()))
+            .collect();
+        assert!(MerkleTree::verify_proof(&hash, &proof, &alice.get_merkle_tree_root().unwrap()));
+        proof.extend(tree.proof("alice").unwrap());
+        assert!(MerkleTree::verify_proof(&hash, &proof, &tree.root().unwrap()));
+        assert!(tree.proof("dave").is_none());
+
+        assert_eq!(tenants.update_tree().unwrap().version, 1);
+    }
+}
@@ -0,0 +1,126 @@
+use std::sync::Arc;
+use serde::Serialize;
+use sha2::{Digest, Sha512};
+use utoipa::ToSchema;
+use crate::storage::{HASHER_NAMES, Hash512, Hash512Ops, LEAF_ORDER, LeafEncoding, MerkleTree, hasher_from_name, sort_leaves};
+
+// Numbers of hashes of the input sets, covering a single leaf, a full tree and promoted nodes
+const INPUT_SIZES: [usize; 4] = [1, 2, 3, 5];
+
+// Trees over fixed inputs for every tree hasher and leaf encoding, for checking other implementations of
+// the leaves, trees and proofs against this one. All hashes are hex.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct TestVectors {
+    pub leaf_order: &'static str,
+    pub vectors: Vec<TestVector>,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct TestVector {
+    pub tree_hasher: &'static str,
+    pub node_encoding: &'static str,
+    pub leaf_version: u8,
+    pub leaf_encoding: &'static str,
+    pub salt: String,
+    // The submitted hashes, in the order they were submitted
+    pub hashes: Vec<String>,
+    // Every level of the tree, from the leaves in `leaf_order` up to the level with just the root
+    pub levels: Vec<Vec<String>>,
+    pub root: String,
+    // A proof for every submitted hash, in the order of `hashes`
+    pub proofs: Vec<TestProof>,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct TestProof {
+    pub hash: String,
+    pub leaf_index: usize,
+    // In the format of `MerkleTree::get`, starting with the hash and the salt
+    pub merkle_proof: Vec<(String, String)>,
+    // Siblings on the way from the leaf to the root, see `MerkleTree::audit_path`
+    pub audit_path: Vec<String>,
+}
+
+// The i-th input hash: SHA-512 of "timestamping test vector <i>"
+pub fn input_hash(i: usize) -> Hash512 {
+    digest(&format!("timestamping test vector {i}"))
+}
+
+// The salt of all vectors: SHA-512 of "timestamping test vector salt"
+pub fn input_salt() -> Hash512 {
+    digest("timestamping test vector salt")
+}
+
+fn digest(input: &str) -> Hash512 {
+    Hash512::from_bytes(&Sha512::digest(input.as_bytes())).unwrap()
+}
+
+pub fn generate() -> TestVectors {
+    let salt = input_salt();
+    let mut vectors = Vec::new();
+    for name in HASHER_NAMES {
+        let hasher = hasher_from_name(name).unwrap();
+        for encoding in LeafEncoding::ALL {
+            for size in INPUT_SIZES {
+                let hashes: Vec<Hash512> = (0..size).map(input_hash).collect();
+                let mut leaves: Vec<Hash512> = hashes.iter().map(|hash| encoding.leaf_with_hasher(&*hasher, hash, &salt)).collect();
+                sort_leaves(&mut leaves);
+                let tree = MerkleTree::with_hasher(leaves, salt, Arc::clone(&hasher));
+                let proofs = hashes.iter()
+                    .map(|hash| {
+                        let leaf_index = tree.leaf_position(&encoding.leaf_with_hasher(&*hasher, hash, &salt)).unwrap();
+                        TestProof {
+                            hash: hash.to_string(),
+                            leaf_index,
+                            merkle_proof: tree.get_with_encoding(hash, encoding).unwrap().iter().map(|(left, right)| (left.to_string(), right.to_string())).collect(),
+                            audit_path: tree.audit_path(leaf_index).unwrap().iter().map(Hash512::to_string).collect(),
+                        }
+                    })
+                    .collect();
+                vectors.push(TestVector {
+                    tree_hasher: hasher.name(),
+                    node_encoding: hasher.node_description(),
+                    leaf_version: encoding.version(),
+                    leaf_encoding: encoding.description(),
+                    salt: salt.to_string(),
+                    hashes: hashes.iter().map(Hash512::to_string).collect(),
+                    levels: tree.levels.iter().map(|level| level.iter().map(Hash512::to_string).collect()).collect(),
+                    root: tree.root().unwrap().to_string(),
+                    proofs,
+                });
+            }
+        }
+    }
+    TestVectors { leaf_order: LEAF_ORDER, vectors }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vectors() {
+        let vectors = generate().vectors;
+        assert_eq!(vectors.len(), HASHER_NAMES.len() * LeafEncoding::ALL.len() * INPUT_SIZES.len());
+        for vector in &vectors {
+            let hasher = hasher_from_name(vector.tree_hasher).unwrap();
+            let encoding = LeafEncoding::from_version(vector.leaf_version).unwrap();
+            let root = vector.root.parse::<Hash512>().unwrap();
+            for (i, proof) in vector.proofs.iter().enumerate() {
+                let merkle_proof: Vec<(Hash512, Hash512)> = proof.merkle_proof.iter()
+                    .map(|(left, right)| (
+                        left.parse::<Hash512>().unwrap(),
+                        right.parse::<Hash512>().unwrap(),
+                    ))
+                    .collect();
+                assert!(MerkleTree::verify_proof_with_hasher(&input_hash(i), &merkle_proof, &root, encoding, &*hasher));
+            }
+        }
+
+        // Computed independently with Python's hashlib, so the vectors themselves can't drift
+        let sha512_v1 = vectors.iter().find(|vector| vector.tree_hasher == "sha512" && vector.leaf_version == 1 && vector.hashes.len() == 3).unwrap();
+        assert_eq!(sha512_v1.root, "be75bb33e78825aa73586e83eac499e9471b2a9c488a930e4270f34945307d0c72b4b73023ad6cd9bcf9fdd890ccced2d50dc07d5bd4563682052c41919d3948");
+        let rfc6962_v2 = vectors.iter().find(|vector| vector.tree_hasher == "sha512-rfc6962" && vector.leaf_version == 2 && vector.hashes.len() == 3).unwrap();
+        assert_eq!(rfc6962_v2.root, "40bfc2e5d5f39ab23dde3d2c9d8042ce75ea6755595b2f24f1592c023e21cb4bb98267d576f56191229695fafa1f4d93d6e449adfab43e84081a52fd4fcd22d7");
+    }
+}
@@ -0,0 +1,154 @@
+use std::collections::HashMap;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use serde::Deserialize;
+use serde_json::json;
+use crate::client::ClientError;
+use crate::receipt::VerifyingKey;
+use crate::storage::TransparencyLogEntry;
+
+// Rekor's API for creating entries, relative to the log's base url
+const ENTRIES_PATH: &str = "/api/v1/log/entries";
+
+// DER prefix of an Ed25519 SubjectPublicKeyInfo, followed by the 32 key bytes
+const ED25519_SPKI_PREFIX: [u8; 12] = [0x30, 0x2a, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x03, 0x21, 0x00];
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct LogEntryResponse {
+    log_index: u64,
+    integrated_time: u64,
+    #[serde(rename = "logID")]
+    log_id: String,
+    #[serde(default)]
+    verification: Option<VerificationResponse>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct VerificationResponse {
+    #[serde(default)]
+    inclusion_proof: Option<serde_json::Value>,
+    #[serde(default)]
+    signed_entry_timestamp: Option<String>,
+}
+
+// External transparency log with the API of Sigstore's Rekor. Signed tree heads are submitted as
+// `rekord` entries, the tree head message with its Ed25519 signature and the server's public key.
+#[derive(Debug, Clone)]
+pub struct TransparencyLog {
+    http: reqwest::Client,
+    base_url: String,
+}
+
+impl TransparencyLog {
+    pub fn new(base_url: &str) -> Self {
+        Self {
+            http: reqwest::Client::new(),
+            base_url: base_url.trim_end_matches('/').to_string(),
+        }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.base_url
+    }
+
+    // Submit a tree head message signed by `key`, see `receipt::tree_head_message`, and return where the log included it
+    pub async fn submit(&self, message: &[u8], signature: &[u8], key: &VerifyingKey) -> Result<TransparencyLogEntry, ClientError> {
+        let response = self.http
+            .post(format!("{}{}", self.base_url, ENTRIES_PATH))
+            .json(&rekord_entry(message, signature, key))
+            .send()
+            .await?;
+        let status = response.status();
+        if !status.is_success() {
+            let message = response.text().await.unwrap_or_default();
+            return Err(ClientError::Server { status: status.as_u16(), code: None, message });
+        }
+        parse_entry(&self.base_url, response.json().await?)
+    }
+}
+
+// Body of a request creating a `rekord` entry, all contents are base64 encoded
+pub fn rekord_entry(message: &[u8], signature: &[u8], key: &VerifyingKey) -> serde_json::Value {
+    json!({
+        "apiVersion": "0.0.1",
+        "kind": "rekord",
+        "spec": {
+            "signature": {
+                "format": "x509",
+                "content": STANDARD.encode(signature),
+                "publicKey": { "content": STANDARD.encode(public_key_pem(key)) },
+            },
+            "data": { "content": STANDARD.encode(message) },
+        },
+    })
+}
+
+// PEM encoded SubjectPublicKeyInfo of an Ed25519 key, as logs expect x509 public keys
+pub fn public_key_pem(key: &VerifyingKey) -> String {
+    let mut der = ED25519_SPKI_PREFIX.to_vec();
+    der.extend_from_slice(key.as_bytes());
+    format!("-----BEGIN PUBLIC KEY-----\n{}\n-----END PUBLIC KEY-----\n", STANDARD.encode(der))
+}
+
+// Rekor answers with a map from the uuid of the new entry to the entry
+fn parse_entry(log_url: &str, response: HashMap<String, LogEntryResponse>) -> Result<TransparencyLogEntry, ClientError> {
+    let mut entries = response.into_iter();
+    let (Some((uuid, entry)), None) = (entries.next(), entries.next()) else {
+        return Err(ClientError::InvalidResponse("transparency log didn't return exactly one entry"));
+    };
+    let verification = entry.verification.unwrap_or(VerificationResponse { inclusion_proof: None, signed_entry_timestamp: None });
+    Ok(TransparencyLogEntry {
+        log_url: log_url.to_string(),
+        uuid,
+        log_index: entry.log_index,
+        integrated_time: entry.integrated_time,
+        log_id: entry.log_id,
+        signed_entry_timestamp: verification.signed_entry_timestamp,
+        inclusion_proof: verification.inclusion_proof,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::receipt::SigningKey;
+
+    #[test]
+    fn test_rekord_entry() {
+        let key = SigningKey::from_bytes(&[7; 32]).verifying_key();
+        let entry = rekord_entry(b"tree head", &[1, 2, 3], &key);
+        assert_eq!(entry["kind"], "rekord");
+        assert_eq!(entry["spec"]["data"]["content"], STANDARD.encode(b"tree head"));
+        assert_eq!(entry["spec"]["signature"]["content"], STANDARD.encode([1, 2, 3]));
+
+        let pem = String::from_utf8(STANDARD.decode(entry["spec"]["signature"]["publicKey"]["content"].as_str().unwrap()).unwrap()).unwrap();
+        let der = STANDARD.decode(pem.lines().nth(1).unwrap()).unwrap();
+        assert_eq!(der[..12], ED25519_SPKI_PREFIX);
+        assert_eq!(der[12..], *key.as_bytes());
+    }
+
+    #[test]
+    fn test_parse_entry() {
+        let response = serde_json::from_value(json!({
+            "24296fb24b8ad77a": {
+                "body": "e30=",
+                "integratedTime": 1700000000,
+                "logID": "c0d23d6ad406973f",
+                "logIndex": 42,
+                "verification": {
+                    "inclusionProof": { "logIndex": 42, "treeSize": 43, "hashes": [] },
+                    "signedEntryTimestamp": "MEUCIQ==",
+                },
+            },
+        })).unwrap();
+        let entry = parse_entry("https://rekor.example", response).unwrap();
+        assert_eq!(entry.uuid, "24296fb24b8ad77a");
+        assert_eq!((entry.log_index, entry.integrated_time), (42, 1700000000));
+        assert_eq!(entry.signed_entry_timestamp.as_deref(), Some("MEUCIQ=="));
+        assert_eq!(entry.inclusion_proof.unwrap()["treeSize"], 43);
+
+        assert!(parse_entry("https://rekor.example", HashMap::new()).is_err());
+    }
+}
@@ -0,0 +1,542 @@
+use std::sync::Arc;
+use base64::Engine;
+use base64::engine::general_purpose;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha512};
+#[cfg(feature = "server")]
+use utoipa::ToSchema;
+
+// Everything needed to check proofs and receipts: hash encoding, leaf encodings, tree hashers and proof
+// verification. It doesn't depend on the server's runtime, so it also builds without the `server` feature,
+// e.g. for WebAssembly.
+
+// 512-bit hash as eight words, the bytes of each little-endian, see `Hash512Ops::to_bytes`. Ordered by its words,
+// the order leaves are sorted in. Serialized as a hex string, or as 64 bytes in formats that aren't human-readable.
+// Hex or base64 strings and arrays of eight words, as the store and the logs were written before, are read.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Hash512(pub [u64; 8]);
+
+#[derive(Debug, thiserror::Error)]
+pub enum Hash512Error {
+    #[error("Invalid hash length")]
+    InvalidLengthError,
+    #[error("Invalid hash - must be a hex or base64 encoded digest")]
+    InvalidEncodingError,
+}
+
+impl From<[u64; 8]> for Hash512 {
+    fn from(words: [u64; 8]) -> Self {
+        Self(words)
+    }
+}
+
+impl From<Hash512> for [u64; 8] {
+    fn from(hash: Hash512) -> Self {
+        hash.0
+    }
+}
+
+impl TryFrom<&[u8]> for Hash512 {
+    type Error = Hash512Error;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        Self::from_bytes(bytes)
+    }
+}
+
+// Lowercase hex of `bytes`
+pub fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// Bytes of hex digits of either case, `None` for an odd number of digits or anything else
+pub fn decode_hex(hex: &[u8]) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.iter().all(u8::is_ascii_hexdigit) {
+        return None;
+    }
+    // Only ascii hex digits, so the pairs are valid strs
+    hex.chunks_exact(2).map(|pair| u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()).collect()
+}
+
+// Lowercase hex of `to_bytes`
+impl std::fmt::Display for Hash512 {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&encode_hex(&self.to_bytes()))
+    }
+}
+
+// Hex, or base64 with either alphabet, padded or not
+impl std::str::FromStr for Hash512 {
+    type Err = Hash512Error;
+
+    fn from_str(encoded: &str) -> Result<Self, Self::Err> {
+        let encoded = encoded.trim();
+        let bytes = if encoded.len() == 128
+            && let Some(bytes) = decode_hex(encoded.as_bytes())
+        {
+            bytes
+        } else {
+            let unpadded = encoded.trim_end_matches('=');
+            [&general_purpose::STANDARD_NO_PAD, &general_purpose::URL_SAFE_NO_PAD]
+                .iter()
+                .find_map(|engine| engine.decode(unpadded).ok())
+                .ok_or(Hash512Error::InvalidEncodingError)?
+        };
+        Self::from_bytes(&bytes)
+    }
+}
+
+impl Serialize for Hash512 {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.collect_str(self)
+        } else {
+            serializer.serialize_bytes(&self.to_bytes())
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Hash512 {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Hash512Visitor;
+
+        impl<'de> serde::de::Visitor<'de> for Hash512Visitor {
+            type Value = Hash512;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("a hex or base64 encoded 512-bit hash, 64 bytes or eight 64-bit words")
+            }
+
+            fn visit_str<E: serde::de::Error>(self, encoded: &str) -> Result<Hash512, E> {
+                encoded.parse().map_err(E::custom)
+            }
+
+            fn visit_bytes<E: serde::de::Error>(self, bytes: &[u8]) -> Result<Hash512, E> {
+                Hash512::from_bytes(bytes).map_err(E::custom)
+            }
+
+            // Eight words, or 64 bytes from formats without a byte string type
+            fn visit_seq<A: serde::de::SeqAccess<'de>>(self, mut seq: A) -> Result<Hash512, A::Error> {
+                let mut values = Vec::with_capacity(64);
+                while let Some(value) = seq.next_element::<u64>()? {
+                    values.push(value);
+                }
+                match values.len() {
+                    8 => Ok(Hash512(values.try_into().unwrap())),
+                    64 if values.iter().all(|&value| value <= u8::MAX as u64) => {
+                        Ok(Hash512::from_bytes(&values.iter().map(|&value| value as u8).collect::<Vec<_>>()).unwrap())
+                    }
+                    _ => Err(serde::de::Error::invalid_length(values.len(), &self)),
+                }
+            }
+        }
+
+        if deserializer.is_human_readable() {
+            deserializer.deserialize_any(Hash512Visitor)
+        } else {
+            deserializer.deserialize_bytes(Hash512Visitor)
+        }
+    }
+}
+
+// Trait for Hash512 operations
+pub trait Hash512Ops {
+    fn from_bytes(bytes: &[u8]) -> Result<Self, Hash512Error> where Self: Sized;
+    fn to_bytes(&self) -> Vec<u8>;
+    fn to_index(&self, prefix_size: usize, index_size: usize) -> usize;
+}
+
+impl Hash512Ops for Hash512 {
+    fn from_bytes(bytes: &[u8]) -> Result<Self, Hash512Error> {
+        if bytes.len() != 64 {
+            return Err(Hash512Error::InvalidLengthError);
+        }
+
+        // Convert Vec<u8> to [u64; 8] by reading 8 bytes at a time
+        let mut hash_array = [0u64; 8];
+        for (i, word) in hash_array.iter_mut().enumerate() {
+            let start = i * 8;
+            *word = u64::from_le_bytes([
+                bytes[start], bytes[start + 1], bytes[start + 2], bytes[start + 3],
+                bytes[start + 4], bytes[start + 5], bytes[start + 6], bytes[start + 7]
+            ]);
+        }
+        Ok(Self(hash_array))
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        self.0.iter().flat_map(|&u64_val| u64_val.to_le_bytes()).collect()
+    }
+
+    fn to_index(&self, prefix_size: usize, index_size: usize) -> usize {
+        // Extract index_size bits starting from prefix_size, assuming prefix_size + index_size <= 64
+        if prefix_size + index_size > 64 { panic!("Prefix size + index size must be less than or equal to 64"); }
+        if index_size == 0 { return 0; }
+
+        // Only use the first u64
+        ((self.0[0] << prefix_size) >> (64 - index_size)) as usize
+    }
+}
+
+// Hash function used for salting leaves and combining merkle tree nodes.
+// All hashes of a store and the trees built from it have to use the same hasher.
+pub trait Hasher: std::fmt::Debug + Send + Sync {
+    fn name(&self) -> &'static str;
+
+    // The function behind `hash`, for implementations elsewhere
+    fn hash_function(&self) -> &'static str;
+
+    // 512-bit hash of the concatenation of all parts
+    fn hash(&self, parts: &[&[u8]]) -> Hash512;
+
+    fn combine(&self, a: &Hash512, b: &Hash512) -> Hash512 {
+        self.hash(&[&a.to_bytes(), &b.to_bytes()])
+    }
+
+    // Bytes hashed in front of the children of a node
+    fn node_prefix(&self) -> &'static [u8] {
+        &[]
+    }
+
+    // Parent of two nodes of a merkle tree
+    fn node(&self, left: &Hash512, right: &Hash512) -> Hash512 {
+        self.hash(&[self.node_prefix(), &left.to_bytes(), &right.to_bytes()])
+    }
+
+    fn node_description(&self) -> &'static str {
+        "H(left || right)"
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Sha512Hasher;
+
+impl Hasher for Sha512Hasher {
+    fn name(&self) -> &'static str {
+        "sha512"
+    }
+
+    fn hash_function(&self) -> &'static str {
+        "SHA-512"
+    }
+
+    fn hash(&self, parts: &[&[u8]]) -> Hash512 {
+        let mut hasher = Sha512::new();
+        for part in parts {
+            hasher.update(part);
+        }
+        Hash512::from_bytes(&hasher.finalize()).unwrap()
+    }
+}
+
+// BLAKE3 in extended output mode, producing 64 bytes
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Blake3Hasher;
+
+impl Hasher for Blake3Hasher {
+    fn name(&self) -> &'static str {
+        "blake3"
+    }
+
+    fn hash_function(&self) -> &'static str {
+        "BLAKE3 in extended output mode, the first 64 bytes"
+    }
+
+    fn hash(&self, parts: &[&[u8]]) -> Hash512 {
+        let mut hasher = blake3::Hasher::new();
+        for part in parts {
+            hasher.update(part);
+        }
+        let mut output = [0u8; 64];
+        hasher.finalize_xof().fill(&mut output);
+        Hash512::from_bytes(&output).unwrap()
+    }
+}
+
+// Hasher with the nodes prefixed like in RFC 6962, so no node can be passed off as a leaf of either encoding:
+// V1 leaves hash 128 bytes and V2 leaves start with 0x00, nodes hash 129 bytes starting with 0x01
+#[derive(Debug, Clone, Copy)]
+pub struct DomainSeparatedHasher<H> {
+    hasher: H,
+    name: &'static str,
+}
+
+impl<H: Hasher> DomainSeparatedHasher<H> {
+    pub const fn new(hasher: H, name: &'static str) -> Self {
+        Self { hasher, name }
+    }
+}
+
+impl<H: Hasher> Hasher for DomainSeparatedHasher<H> {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn hash_function(&self) -> &'static str {
+        self.hasher.hash_function()
+    }
+
+    fn hash(&self, parts: &[&[u8]]) -> Hash512 {
+        self.hasher.hash(parts)
+    }
+
+    fn node_prefix(&self) -> &'static [u8] {
+        &[1]
+    }
+
+    fn node_description(&self) -> &'static str {
+        "H(0x01 || left || right), domain separated from leaves"
+    }
+}
+
+pub const HASHER_NAMES: [&str; 4] = ["sha512", "blake3", "sha512-rfc6962", "blake3-rfc6962"];
+
+// Hasher with the given name, as returned by `Hasher::name`. The plain ones are kept for stores created
+// before nodes were domain separated.
+pub fn hasher_from_name(name: &str) -> Option<Arc<dyn Hasher>> {
+    match name {
+        "sha512" => Some(Arc::new(Sha512Hasher)),
+        "blake3" => Some(Arc::new(Blake3Hasher)),
+        "sha512-rfc6962" => Some(Arc::new(DomainSeparatedHasher::new(Sha512Hasher, "sha512-rfc6962"))),
+        "blake3-rfc6962" => Some(Arc::new(DomainSeparatedHasher::new(Blake3Hasher, "blake3-rfc6962"))),
+        _ => None,
+    }
+}
+
+// Versioned schemes for turning a submitted hash into a merkle tree leaf.
+// New versions can be added without changing how leaves of older versions are computed,
+// so proofs issued with an older version stay verifiable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LeafEncoding {
+    #[default]
+    V1,
+    V2,
+}
+
+impl LeafEncoding {
+    pub const ALL: [LeafEncoding; 2] = [LeafEncoding::V1, LeafEncoding::V2];
+
+    pub fn version(&self) -> u8 {
+        match self {
+            LeafEncoding::V1 => 1,
+            LeafEncoding::V2 => 2,
+        }
+    }
+
+    pub fn from_version(version: u8) -> Option<Self> {
+        Self::ALL.into_iter().find(|encoding| encoding.version() == version)
+    }
+
+    pub fn description(&self) -> &'static str {
+        match self {
+            LeafEncoding::V1 => "H(hash || salt)",
+            LeafEncoding::V2 => "H(0x00 || hash || salt), domain separated from inner nodes",
+        }
+    }
+
+    pub fn leaf(&self, hash: &Hash512, salt: &Hash512) -> Hash512 {
+        self.leaf_with_hasher(&Sha512Hasher, hash, salt)
+    }
+
+    // Bytes hashed in front of the hash and salt of a leaf
+    pub fn prefix(&self) -> &'static [u8] {
+        match self {
+            LeafEncoding::V1 => &[],
+            LeafEncoding::V2 => &[0],
+        }
+    }
+
+    pub fn leaf_with_hasher(&self, hasher: &dyn Hasher, hash: &Hash512, salt: &Hash512) -> Hash512 {
+        hasher.hash(&[self.prefix(), &hash.to_bytes(), &salt.to_bytes()])
+    }
+}
+
+// Digest algorithms accepted from clients. Everything internal works on 512-bit values,
+// so digests are normalized into one before they are stored or proven.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(ToSchema))]
+#[serde(rename_all = "lowercase")]
+pub enum HashAlgorithm {
+    #[default]
+    Sha512,
+    Sha256,
+    #[serde(rename = "sha3-512")]
+    Sha3_512,
+    #[serde(rename = "blake2b-512")]
+    Blake2b512,
+    Blake3,
+}
+
+impl HashAlgorithm {
+    pub const ALL: [HashAlgorithm; 5] = [HashAlgorithm::Sha512, HashAlgorithm::Sha256, HashAlgorithm::Sha3_512, HashAlgorithm::Blake2b512, HashAlgorithm::Blake3];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            HashAlgorithm::Sha512 => "sha512",
+            HashAlgorithm::Sha256 => "sha256",
+            HashAlgorithm::Sha3_512 => "sha3-512",
+            HashAlgorithm::Blake2b512 => "blake2b-512",
+            HashAlgorithm::Blake3 => "blake3",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|algorithm| algorithm.name() == name)
+    }
+
+    pub fn digest_len(&self) -> usize {
+        match self {
+            HashAlgorithm::Sha256 | HashAlgorithm::Blake3 => 32,
+            HashAlgorithm::Sha512 | HashAlgorithm::Sha3_512 | HashAlgorithm::Blake2b512 => 64,
+        }
+    }
+
+    pub fn description(&self) -> &'static str {
+        match self {
+            HashAlgorithm::Sha512 => "64-byte digest, stored as is",
+            HashAlgorithm::Sha256 => "32-byte digest, stored as SHA-512(\"sha256\" || digest)",
+            HashAlgorithm::Sha3_512 => "64-byte digest, stored as SHA-512(\"sha3-512\" || digest)",
+            HashAlgorithm::Blake2b512 => "64-byte digest, stored as SHA-512(\"blake2b-512\" || digest)",
+            HashAlgorithm::Blake3 => "32-byte digest, stored as SHA-512(\"blake3\" || digest)",
+        }
+    }
+
+    // Bytes hashed with SHA-512 in front of a digest to normalize it, `None` for digests stored as they are
+    pub fn normalization_prefix(&self) -> Option<&'static [u8]> {
+        match self {
+            HashAlgorithm::Sha512 => None,
+            HashAlgorithm::Sha256 | HashAlgorithm::Sha3_512 | HashAlgorithm::Blake2b512 | HashAlgorithm::Blake3 => Some(self.name().as_bytes()),
+        }
+    }
+
+    // The 512-bit value a digest is stored as. SHA-512 digests are used unchanged; digests of other algorithms are
+    // hashed together with the algorithm name, so the leaf tells them apart from the same bytes under another algorithm.
+    pub fn normalize(&self, digest: &[u8]) -> Result<Hash512, Hash512Error> {
+        if digest.len() != self.digest_len() {
+            return Err(Hash512Error::InvalidLengthError);
+        }
+        match self.normalization_prefix() {
+            None => Hash512::from_bytes(digest),
+            Some(prefix) => {
+                let mut hasher = Sha512::new();
+                hasher.update(prefix);
+                hasher.update(digest);
+                Hash512::from_bytes(&hasher.finalize())
+            }
+        }
+    }
+}
+
+// Check a proof as returned by `MerkleTree::get`: the first entry is (hash, salt), every following entry is a
+// pair of siblings one level closer to the root, one of which is the hash of the previous entry
+pub fn verify_proof(hash: &Hash512, proof: &[(Hash512, Hash512)], root: &Hash512, encoding: LeafEncoding) -> bool {
+    verify_proof_with_hasher(hash, proof, root, encoding, &Sha512Hasher)
+}
+
+pub fn verify_proof_with_hasher(hash: &Hash512, proof: &[(Hash512, Hash512)], root: &Hash512, encoding: LeafEncoding, hasher: &dyn Hasher) -> bool {
+    let Some(((leaf_hash, salt), path)) = proof.split_first() else {
+        return false;
+    };
+    if leaf_hash != hash {
+        return false;
+    }
+
+    let mut current = encoding.leaf_with_hasher(hasher, hash, salt);
+    for (left, right) in path {
+        if current != *left && current != *right {
+            return false;
+        }
+        current = hasher.node(left, right);
+    }
+    current == *root
+}
+
+// Verify an audit path for the (salted) leaf at `index` of a tree with `leaf_count` leaves,
+// following the inclusion proof verification of RFC 9162 section 2.1.3.2
+pub fn verify_audit_path(leaf: &Hash512, index: usize, leaf_count: usize, path: &[Hash512], root: &Hash512, hasher: &dyn Hasher) -> bool {
+    if index >= leaf_count {
+        return false;
+    }
+    let mut node_idx = index;
+    let mut last_idx = leaf_count - 1;
+    let mut current = *leaf;
+    for sibling in path {
+        if last_idx == 0 {
+            return false;
+        }
+        if node_idx & 1 == 1 || node_idx == last_idx {
+            current = hasher.node(sibling, &current);
+            // Skip the levels where the node was promoted without a sibling
+            while node_idx & 1 == 0 && node_idx != 0 {
+                node_idx >>= 1;
+                last_idx >>= 1;
+            }
+        } else {
+            current = hasher.node(&current, sibling);
+        }
+        node_idx >>= 1;
+        last_idx >>= 1;
+    }
+    last_idx == 0 && current == *root
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify_without_tree() {
+        let hasher = hasher_from_name("sha512-rfc6962").unwrap();
+        let salt = Hash512([9u64; 8]);
+        let (a, b, c) = (Hash512([1u64; 8]), Hash512([2u64; 8]), Hash512([3u64; 8]));
+        let leaves = [a, b, c].map(|hash| LeafEncoding::V2.leaf_with_hasher(&*hasher, &hash, &salt));
+        // Three leaves: the third is promoted to the level of the first two's parent
+        let left = hasher.node(&leaves[0], &leaves[1]);
+        let root = hasher.node(&left, &leaves[2]);
+
+        let proof = [(b, salt), (leaves[0], leaves[1]), (left, leaves[2])];
+        assert!(verify_proof_with_hasher(&b, &proof, &root, LeafEncoding::V2, &*hasher));
+        assert!(!verify_proof_with_hasher(&b, &proof, &root, LeafEncoding::V1, &*hasher));
+        assert!(!verify_proof(&b, &proof, &root, LeafEncoding::V2));
+        assert!(verify_audit_path(&leaves[1], 1, 3, &[leaves[0], leaves[2]], &root, &*hasher));
+        assert!(verify_audit_path(&leaves[2], 2, 3, &[left], &root, &*hasher));
+        assert!(!verify_audit_path(&leaves[2], 2, 4, &[left], &root, &*hasher));
+        assert_eq!(Hash512::from_bytes(&c.to_bytes()).unwrap(), c);
+    }
+
+    #[test]
+    fn test_hash512_encoding() {
+        let hash = Hash512([1, 2, 3, 4, 5, 6, 7, u64::MAX]);
+        let hex = hash.to_string();
+        assert_eq!(&hex[..18], "010000000000000002");
+        assert_eq!(hex.parse::<Hash512>().unwrap(), hash);
+        assert_eq!(hex.to_uppercase().parse::<Hash512>().unwrap(), hash);
+        let base64 = general_purpose::URL_SAFE.encode(hash.to_bytes());
+        assert_eq!(base64.parse::<Hash512>().unwrap(), hash);
+        assert_eq!(base64.trim_end_matches('=').parse::<Hash512>().unwrap(), hash);
+        assert!(matches!(hex[2..].parse::<Hash512>(), Err(Hash512Error::InvalidEncodingError)));
+        assert!(matches!(general_purpose::STANDARD.encode([1; 32]).parse::<Hash512>(), Err(Hash512Error::InvalidLengthError)));
+        assert_eq!(Hash512::try_from(&hash.to_bytes()[..]).unwrap(), hash);
+        assert_eq!(<[u64; 8]>::from(hash), hash.0);
+
+        // Hex in JSON, but arrays of words as written before are read too
+        let json = serde_json::to_string(&hash).unwrap();
+        assert_eq!(json, format!("\"{}\"", hex));
+        assert_eq!(serde_json::from_str::<Hash512>(&json).unwrap(), hash);
+        assert_eq!(serde_json::from_str::<Hash512>(&serde_json::to_string(&hash.0).unwrap()).unwrap(), hash);
+        assert_eq!(serde_json::from_str::<Hash512>(&serde_json::to_string(&hash.to_bytes()).unwrap()).unwrap(), hash);
+        assert!(serde_json::from_str::<Hash512>("[1, 2, 3]").is_err());
+
+        // Bytes in binary formats
+        #[cfg(feature = "server")]
+        {
+            let mut cbor = Vec::new();
+            ciborium::into_writer(&hash, &mut cbor).unwrap();
+            assert_eq!(cbor.len(), 2 + 64);
+            assert_eq!(ciborium::from_reader::<Hash512, _>(&cbor[..]).unwrap(), hash);
+        }
+
+        // Ordered like the words
+        assert!(Hash512([0, u64::MAX, 0, 0, 0, 0, 0, 0]) < Hash512([1, 0, 0, 0, 0, 0, 0, 0]));
+    }
+}
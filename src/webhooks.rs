@@ -0,0 +1,181 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use crate::peering::SignedTreeHead;
+
+pub const TREE_PUBLISHED: &str = "tree_published";
+// Attempts per event and url, waiting twice as long after every failed one
+pub const MAX_DELIVERY_ATTEMPTS: u32 = 8;
+const FIRST_RETRY_DELAY: Duration = Duration::from_secs(1);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(300);
+
+// Body POSTed to every webhook, the tree head carries the server's signature
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct WebhookEvent {
+    pub event: String,
+    pub tree_head: SignedTreeHead,
+}
+
+impl WebhookEvent {
+    pub fn tree_published(tree_head: SignedTreeHead) -> Self {
+        Self { event: TREE_PUBLISHED.to_string(), tree_head }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum WebhookError {
+    #[error("Webhook urls have to start with http:// or https://")]
+    InvalidUrl,
+    #[error("Configured webhooks can't be removed")]
+    Configured,
+    #[error("{0}")]
+    Io(#[from] io::Error),
+}
+
+pub fn is_valid_url(url: &str) -> bool {
+    ["http://", "https://"].iter().any(|scheme| url.strip_prefix(scheme).is_some_and(|rest| !rest.is_empty() && !rest.contains(char::is_whitespace)))
+}
+
+// Urls notified of every published tree: configured ones and those registered at runtime,
+// which are kept in a JSON file if there is one
+pub struct Webhooks {
+    configured: Vec<String>,
+    registered: RwLock<Vec<String>>,
+    path: Option<PathBuf>,
+}
+
+impl Webhooks {
+    pub fn in_memory(configured: Vec<String>) -> Self {
+        Self { configured, registered: RwLock::new(Vec::new()), path: None }
+    }
+
+    // Load the urls registered before from `path`, if it exists
+    pub fn open(path: &Path, configured: Vec<String>) -> io::Result<Self> {
+        let registered = if path.exists() {
+            serde_json::from_slice(&std::fs::read(path)?).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?
+        } else {
+            Vec::new()
+        };
+        Ok(Self { configured, registered: RwLock::new(registered), path: Some(path.to_path_buf()) })
+    }
+
+    pub fn configured(&self) -> &[String] {
+        &self.configured
+    }
+
+    pub fn registered(&self) -> Vec<String> {
+        self.registered.read().unwrap().clone()
+    }
+
+    // Every url to notify, each once
+    pub fn urls(&self) -> Vec<String> {
+        let mut urls = self.configured.clone();
+        for url in self.registered.read().unwrap().iter() {
+            if !urls.contains(url) {
+                urls.push(url.clone());
+            }
+        }
+        urls
+    }
+
+    // Returns false if the url was registered already
+    pub fn register(&self, url: &str) -> Result<bool, WebhookError> {
+        if !is_valid_url(url) {
+            return Err(WebhookError::InvalidUrl);
+        }
+        let mut registered = self.registered.write().unwrap();
+        if registered.iter().any(|existing| existing == url) {
+            return Ok(false);
+        }
+        let mut updated = registered.clone();
+        updated.push(url.to_string());
+        self.write(&updated)?;
+        *registered = updated;
+        Ok(true)
+    }
+
+    // Returns false if the url wasn't registered
+    pub fn unregister(&self, url: &str) -> Result<bool, WebhookError> {
+        let mut registered = self.registered.write().unwrap();
+        if !registered.iter().any(|existing| existing == url) {
+            return if self.configured.iter().any(|existing| existing == url) { Err(WebhookError::Configured) } else { Ok(false) };
+        }
+        let updated: Vec<String> = registered.iter().filter(|existing| *existing != url).cloned().collect();
+        self.write(&updated)?;
+        *registered = updated;
+        Ok(true)
+    }
+
+    fn write(&self, registered: &[String]) -> io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        // Write to a temporary file first so a crash can't leave a truncated list behind
+        let tmp_path = path.with_extension("json.tmp");
+        std::fs::write(&tmp_path, serde_json::to_vec_pretty(registered).unwrap())?;
+        std::fs::rename(&tmp_path, path)
+    }
+}
+
+// Delay before attempt `attempt + 1` of a delivery
+pub fn retry_delay(attempt: u32) -> Duration {
+    FIRST_RETRY_DELAY.saturating_mul(1 << attempt.min(16)).min(MAX_RETRY_DELAY)
+}
+
+// POST `event` to `url` until it is answered with a success status, giving up after MAX_DELIVERY_ATTEMPTS
+#[cfg(feature = "client")]
+pub async fn deliver(http: &reqwest::Client, url: &str, event: &WebhookEvent) -> Result<(), String> {
+    let mut attempt = 0;
+    loop {
+        let error = match http.post(url).json(event).send().await {
+            Ok(response) if response.status().is_success() => return Ok(()),
+            Ok(response) => format!("answered {}", response.status()),
+            Err(e) => e.to_string(),
+        };
+        attempt += 1;
+        if attempt == MAX_DELIVERY_ATTEMPTS {
+            return Err(format!("{} after {} attempts", error, attempt));
+        }
+        tokio::time::sleep(retry_delay(attempt - 1)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_webhooks() {
+        let path = std::env::temp_dir().join(format!("timestamping-webhooks-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let webhooks = Webhooks::open(&path, vec!["https://a.example/hook".to_string()]).unwrap();
+        assert!(webhooks.register("https://b.example/hook").unwrap());
+        assert!(!webhooks.register("https://b.example/hook").unwrap());
+        assert!(webhooks.register("https://a.example/hook").unwrap());
+        assert!(matches!(webhooks.register("ftp://c.example"), Err(WebhookError::InvalidUrl)));
+        assert!(matches!(webhooks.register("https://"), Err(WebhookError::InvalidUrl)));
+        assert_eq!(webhooks.urls(), ["https://a.example/hook", "https://b.example/hook"]);
+
+        // Registered urls survive a restart, configured ones come from the configuration only
+        let reopened = Webhooks::open(&path, Vec::new()).unwrap();
+        assert_eq!(reopened.registered(), ["https://b.example/hook", "https://a.example/hook"]);
+        assert!(reopened.unregister("https://a.example/hook").unwrap());
+        assert!(!reopened.unregister("https://a.example/hook").unwrap());
+        assert_eq!(Webhooks::open(&path, Vec::new()).unwrap().registered(), ["https://b.example/hook"]);
+
+        assert!(webhooks.unregister("https://a.example/hook").unwrap());
+        assert!(matches!(webhooks.unregister("https://a.example/hook"), Err(WebhookError::Configured)));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_retry_delay() {
+        assert_eq!(retry_delay(0), Duration::from_secs(1));
+        assert_eq!(retry_delay(3), Duration::from_secs(8));
+        assert_eq!(retry_delay(9), MAX_RETRY_DELAY);
+        assert_eq!(retry_delay(u32::MAX), MAX_RETRY_DELAY);
+    }
+}
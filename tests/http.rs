@@ -0,0 +1,820 @@
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use base64::Engine;
+use ed25519_dalek::Signature;
+use serde_json::Value;
+#[cfg(feature = "client")]
+use timestamping::receipt::{Receipt, SigningKey};
+use timestamping::clock::TimeAttestation;
+use timestamping::receipt::{VerifyingKey, tree_head_message};
+use timestamping::snapshot::{SnapshotError, read_snapshot};
+use timestamping::root_log::{RootLogEntry, verify_chain};
+use timestamping::verify::{Hash512, Hash512Ops, LeafEncoding, hasher_from_name, verify_proof_with_hasher};
+
+// End-to-end tests of the HTTP API: tests start the server binary on a free port with its own data directory
+// and talk JSON to it over plain HTTP/1.1. Tests that only read share one server, see `Server::shared`.
+
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(30);
+// Index bits the bucket tables of test servers start with, small enough for them to start and publish right away
+const TEST_INDEX_SIZE: &str = "12";
+
+// Server of the tests that don't change the store, while one of them runs
+static SHARED: Mutex<Weak<Server>> = Mutex::new(Weak::new());
+
+struct Server {
+    process: Child,
+    address: String,
+    dir: PathBuf,
+}
+
+impl Server {
+    fn start(name: &str) -> Self {
+        Self::start_with_env(name, &[])
+    }
+
+    // Server holding hash(1) to hash(5), all in the published tree version 0, for tests that don't change the
+    // store. Tests running at the same time share it, it stops once the last of them is done.
+    fn shared() -> Arc<Self> {
+        let mut shared = SHARED.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Some(server) = shared.upgrade() {
+            return server;
+        }
+        let server = Self::start(&format!("shared-{:?}", Instant::now()).replace(|c: char| !c.is_ascii_alphanumeric(), ""));
+        server.post("/add", &raw(&(1..=5).map(hash).collect::<Vec<_>>()));
+        server.post("/update-tree", &[]);
+        let server = Arc::new(server);
+        *shared = Arc::downgrade(&server);
+        server
+    }
+
+    fn start_with_env(name: &str, env: &[(&str, &str)]) -> Self {
+        Self::start_with_files(name, env, &[])
+    }
+
+    // Like `start_with_env`, with files written to the data directory before the start
+    fn start_with_files(name: &str, env: &[(&str, &str)], files: &[(&str, &[u8])]) -> Self {
+        let dir = std::env::temp_dir().join(format!("timestamping-http-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("data")).unwrap();
+        for (file, contents) in files {
+            std::fs::write(dir.join("data").join(file), contents).unwrap();
+        }
+        // Released right away for the server to bind, nothing else asks for this port in between
+        let address = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+        let process = Self::spawn(&dir, &address, env);
+        let mut server = Self { process, address, dir };
+        server.wait_for_startup();
+        server
+    }
+
+    // Stop the server and start it again on the same data directory and address
+    fn restart(&mut self, env: &[(&str, &str)]) {
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+        self.process = Self::spawn(&self.dir, &self.address, env);
+        self.wait_for_startup();
+    }
+
+    fn spawn(dir: &PathBuf, address: &str, env: &[(&str, &str)]) -> Child {
+        Command::new(env!("CARGO_BIN_EXE_timestamping"))
+            .current_dir(dir)
+            .env_clear()
+            .env("TIMESTAMPING_BIND", address)
+            .env("TIMESTAMPING_INDEX_SIZE", TEST_INDEX_SIZE)
+            .envs(env.iter().copied())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap()
+    }
+
+    fn wait_for_startup(&mut self) {
+        let start = Instant::now();
+        while TcpStream::connect(&self.address).is_err() {
+            assert!(self.process.try_wait().unwrap().is_none(), "Server exited during startup");
+            assert!(start.elapsed() < STARTUP_TIMEOUT, "Server didn't start within {:?}", STARTUP_TIMEOUT);
+            std::thread::sleep(Duration::from_millis(20));
+        }
+    }
+
+    // Status and body of the response
+    fn send(&self, method: &str, path: &str, body: &[u8]) -> (u16, Vec<u8>) {
+        self.send_as(None, method, path, body)
+    }
+
+    // Like `send`, with `key` as bearer token
+    fn send_as(&self, key: Option<&str>, method: &str, path: &str, body: &[u8]) -> (u16, Vec<u8>) {
+        let (status, _, body) = self.send_with_headers(key, method, path, &[], body);
+        (status, body)
+    }
+
+    // Like `send_as`, with extra request headers, also returning the response headers with lowercase names
+    fn send_with_headers(&self, key: Option<&str>, method: &str, path: &str, headers: &[(&str, &str)], body: &[u8]) -> (u16, Vec<(String, String)>, Vec<u8>) {
+        let mut stream = TcpStream::connect(&self.address).unwrap();
+        let authorization = key.map(|key| format!("Authorization: Bearer {}\r\n", key)).unwrap_or_default();
+        let mut extra: String = headers.iter().map(|(name, value)| format!("{}: {}\r\n", name, value)).collect();
+        if !headers.iter().any(|(name, _)| name.eq_ignore_ascii_case("accept")) {
+            extra.push_str("Accept: application/json\r\n");
+        }
+        let head = format!(
+            "{} {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/octet-stream\r\nContent-Length: {}\r\n{}{}Connection: close\r\n\r\n",
+            method, path, self.address, body.len(), authorization, extra,
+        );
+        stream.write_all(head.as_bytes()).unwrap();
+        stream.write_all(body).unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).unwrap();
+
+        let split = response.windows(4).position(|window| window == b"\r\n\r\n").expect("Response without header end");
+        let head = String::from_utf8_lossy(&response[..split]);
+        let status = head.split(' ').nth(1).and_then(|status| status.parse().ok()).expect("Response without status");
+        let headers = head.lines().skip(1)
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim().to_string()))
+            .collect::<Vec<_>>();
+        let body = &response[split + 4..];
+        if headers.iter().any(|(name, value)| name == "transfer-encoding" && value == "chunked") {
+            return (status, headers, dechunk(body));
+        }
+        (status, headers, body.to_vec())
+    }
+
+    // Status and JSON body of the response
+    fn request(&self, method: &str, path: &str, body: &[u8]) -> (u16, Value) {
+        let (status, response) = self.send(method, path, body);
+        let body = serde_json::from_slice(&response).unwrap_or_else(|e| panic!("Invalid JSON in response to {} {}: {}", method, path, e));
+        (status, body)
+    }
+
+    // Every line of an NDJSON response
+    fn post_lines(&self, path: &str, body: &[u8]) -> Vec<Value> {
+        let (status, response) = self.send("POST", path, body);
+        assert_eq!(status, 200, "POST {}: {}", path, String::from_utf8_lossy(&response));
+        response.split(|&byte| byte == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(|line| serde_json::from_slice(line).unwrap_or_else(|e| panic!("Invalid JSON line in response to POST {}: {}", path, e)))
+            .collect()
+    }
+
+    fn get(&self, path: &str) -> Value {
+        let (status, body) = self.request("GET", path, &[]);
+        assert_eq!(status, 200, "GET {}: {}", path, body);
+        body
+    }
+
+    fn post(&self, path: &str, body: &[u8]) -> Value {
+        let (status, body) = self.request("POST", path, body);
+        assert_eq!(status, 200, "POST {}: {}", path, body);
+        body
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+// Body of a response with chunked transfer encoding
+fn dechunk(mut body: &[u8]) -> Vec<u8> {
+    let mut data = Vec::new();
+    loop {
+        let end = body.windows(2).position(|window| window == b"\r\n").expect("Chunk without size");
+        let size = usize::from_str_radix(std::str::from_utf8(&body[..end]).unwrap().trim(), 16).unwrap();
+        if size == 0 {
+            return data;
+        }
+        data.extend_from_slice(&body[end + 2..end + 2 + size]);
+        body = &body[end + 4 + size..];
+    }
+}
+
+fn hash(i: u64) -> Hash512 {
+    Hash512([i, i << 32, 0, 0, 0, 0, 0, i])
+}
+
+// Raw body of digests, as /add and /check-batch take them
+fn raw(hashes: &[Hash512]) -> Vec<u8> {
+    hashes.iter().flat_map(Hash512Ops::to_bytes).collect()
+}
+
+fn bytes(value: &Value) -> Vec<u8> {
+    serde_json::from_value(value.clone()).unwrap()
+}
+
+fn hash512(value: &Value) -> Hash512 {
+    Hash512::from_bytes(&bytes(value)).unwrap()
+}
+
+// Whether the `merkle_proof` of a check response proves `hash` under `root`, with the response's leaf version and tree hasher
+fn verifies(response: &Value, entry: &Value, hash: &Hash512, root: &Hash512) -> bool {
+    let proof: Vec<(Hash512, Hash512)> = entry["merkle_proof"].as_array().unwrap().iter()
+        .map(|pair| (hash512(&pair[0]), hash512(&pair[1])))
+        .collect();
+    let encoding = LeafEncoding::from_version(response["leaf_version"].as_u64().unwrap() as u8).unwrap();
+    let hasher = hasher_from_name(response["tree_hasher"].as_str().unwrap()).unwrap();
+    verify_proof_with_hasher(hash, &proof, root, encoding, &*hasher)
+}
+
+#[test]
+fn test_add_check_and_update_tree() {
+    let mut server = Server::start("add-check");
+    let stats = server.get("/stats");
+    assert_eq!(stats["count"], 0);
+    assert_eq!(stats["merkle_tree_root"], Value::Null);
+
+    // Hex digests, one per line
+    let before = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    let hashes: Vec<Hash512> = (1..=5).map(hash).collect();
+    let hex: String = hashes[..3].iter().map(|hash| format!("{}\n", hash)).collect();
+    let added = server.post("/add?encoding=hex", hex.as_bytes());
+    assert_eq!((added["total_hashes"].as_u64(), added["new_hashes"].as_u64()), (Some(3), Some(3)));
+    assert_eq!(added["statuses"], serde_json::json!(["new", "new", "new"]));
+
+    // A batch with a stored hash, a new one and a repeated one
+    let batch = raw(&[hashes[2], hashes[3], hashes[3], hashes[4]]);
+    let added = server.post("/add", &batch);
+    assert_eq!(added["statuses"], serde_json::json!(["existing", "new", "duplicate", "new"]));
+    assert_eq!((added["new_hashes"].as_u64(), added["existing_hashes"].as_u64(), added["duplicate_hashes"].as_u64()), (Some(2), Some(1), Some(1)));
+
+    // Listed by the time they were first stored, also without metadata
+    let listed = server.get(&format!("/hashes?since={}", before));
+    assert_eq!(listed["hashes"].as_array().unwrap().len(), 5);
+    assert!(listed["hashes"].as_array().unwrap().iter().all(|entry| entry["metadata"].is_null()));
+    assert_eq!(server.get(&format!("/hashes?since={}", before + 3600))["hashes"], serde_json::json!([]));
+
+    // Stored, but not in a tree yet
+    let checked = server.post("/check", &hashes[0].to_bytes());
+    assert_eq!((&checked["exists"], &checked["merkle_proof"], &checked["tree_version"]), (&Value::Bool(true), &Value::Null, &Value::Null));
+    assert_eq!(checked["resubmissions"], Value::Null);
+    assert_eq!(server.post("/check", &hashes[2].to_bytes())["resubmissions"]["count"], 1);
+
+    let updated = server.post("/update-tree", &[]);
+    assert_eq!(updated["hash_count"], 5);
+    let stats = server.get("/stats");
+    assert_eq!(stats["count"], 5);
+    assert_eq!(stats["merkle_tree_size"], updated["tree_size"]);
+    let root = hash512(&stats["merkle_tree_root"]);
+    let checked = server.post("/check", &hashes[0].to_bytes());
+    assert_eq!(checked["tree_version"], 0);
+    assert!(verifies(&checked, &checked, &hashes[0], &root));
+
+    // Restored from the leaf logs after a restart
+    server.restart(&[]);
+    assert_eq!(server.get("/stats")["count"], 5);
+    assert_eq!(server.post("/check", &hashes[4].to_bytes())["exists"], true);
+}
+
+#[test]
+fn test_check_proofs() {
+    let server = Server::shared();
+    let hashes: Vec<Hash512> = (1..=5).map(hash).collect();
+    let root = hash512(&server.get("/stats")["merkle_tree_root"]);
+    for stored in &hashes {
+        let checked = server.post("/check", &stored.to_bytes());
+        assert_eq!((&checked["exists"], &checked["tree_version"]), (&Value::Bool(true), &serde_json::json!(0)));
+        assert!(verifies(&checked, &checked, stored, &root));
+        assert!(!verifies(&checked, &checked, &hash(99), &root));
+    }
+    let missing = server.post("/check", &hash(99).to_bytes());
+    assert_eq!((&missing["exists"], &missing["merkle_proof"]), (&Value::Bool(false), &Value::Null));
+
+    // All proofs of a batch come from the same tree
+    let checked = server.post("/check-batch", &raw(&[hashes[4], hash(99), hashes[0]]));
+    let results = checked["results"].as_array().unwrap();
+    assert_eq!(results.iter().map(|entry| entry["exists"].as_bool().unwrap()).collect::<Vec<_>>(), [true, false, true]);
+    assert!(verifies(&checked, &results[0], &hashes[4], &root));
+    assert_eq!(results[1]["merkle_proof"], Value::Null);
+    assert!(verifies(&checked, &results[2], &hashes[0], &root));
+
+    // Streamed, more hashes than are checked at once, with the results in the order of the body
+    let streamed: Vec<Hash512> = (0..600).map(|i| if i % 100 == 0 { hashes[i / 100 % 5] } else { hash(1000 + i as u64) }).collect();
+    let lines = server.post_lines("/check-stream", &raw(&streamed));
+    let (head, entries) = lines.split_first().unwrap();
+    assert_eq!(head["tree_version"], 0);
+    assert_eq!(entries.len(), streamed.len());
+    for (i, (entry, hash)) in entries.iter().zip(&streamed).enumerate() {
+        let stored = i % 100 == 0;
+        assert_eq!(entry["exists"], stored);
+        assert!(if stored { verifies(head, entry, hash, &root) } else { entry["merkle_proof"].is_null() });
+    }
+
+    // A bad record ends the stream with an error line after the results of the hashes before it
+    let mut body = raw(&hashes[..2]);
+    body.extend_from_slice(&[0; 10]);
+    let lines = server.post_lines("/check-stream", &body);
+    assert_eq!(lines.len(), 4);
+    assert_eq!(lines[3]["error"]["code"], "invalid_hash");
+    assert_eq!(lines[3]["error"]["details"]["checked_hashes"], 2);
+}
+
+#[test]
+fn test_encodings_and_errors() {
+    let server = Server::shared();
+    let first = hash(1).to_bytes();
+    let root = hash512(&server.get("/stats")["merkle_tree_root"]);
+
+    let encoded = base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(hash(2).to_bytes());
+    let checked = server.get(&format!("/hash/{}", encoded));
+    assert_eq!(checked["exists"], true);
+    assert!(verifies(&checked, &checked, &hash(2), &root));
+
+    // Malformed bodies are rejected with the code of the error
+    let (status, body) = server.request("POST", "/add", &first[..10]);
+    assert_eq!((status, &body["error"]["code"]), (400, &serde_json::json!("invalid_hash")));
+    let (status, body) = server.request("POST", "/check?leaf_version=9", &first);
+    assert_eq!((status, &body["error"]["details"]["supported_leaf_versions"]), (400, &serde_json::json!([1, 2])), "{}", body);
+    // Stored with leaf version 1, checking it with 2 says so instead of reporting it missing
+    let (status, body) = server.request("POST", "/check?leaf_version=2", &first);
+    assert_eq!((status, &body["error"]["code"]), (409, &serde_json::json!("leaf_version_mismatch")));
+    assert_eq!(body["error"]["details"]["stored_leaf_versions"], serde_json::json!([1]));
+    assert_eq!(server.post("/check?leaf_version=2", &hash(99).to_bytes())["exists"], false);
+    let (status, _) = server.request("POST", "/check?tree_version=7", &first);
+    assert_eq!(status, 404);
+    assert_eq!(server.get("/stats")["count"], 5);
+}
+
+#[test]
+fn test_add_batch_job() {
+    let server = Server::start("jobs");
+    server.post("/add", &hash(1).to_bytes());
+    let (status, job) = server.request("POST", "/add-batch", &raw(&[hash(1), hash(2), hash(2), hash(3)]));
+    assert_eq!(status, 202, "{}", job);
+    assert_eq!(job["total_hashes"], 4);
+
+    let path = format!("/jobs/{}", job["id"].as_str().unwrap());
+    let start = Instant::now();
+    let job = loop {
+        let job = server.get(&path);
+        if job["state"] != "running" {
+            break job;
+        }
+        assert!(start.elapsed() < STARTUP_TIMEOUT, "Job didn't finish within {:?}", STARTUP_TIMEOUT);
+        std::thread::sleep(Duration::from_millis(20));
+    };
+    assert_eq!(job["state"], "completed");
+    assert_eq!((&job["processed_hashes"], &job["new_hashes"], &job["existing_hashes"], &job["duplicate_hashes"]), (&4.into(), &2.into(), &1.into(), &1.into()));
+    assert_eq!(job["statuses"], serde_json::json!(["existing", "new", "duplicate", "new"]));
+    assert_eq!(server.get(&format!("{}?offset=1&limit=2", path))["statuses"], serde_json::json!(["new", "duplicate"]));
+    assert_eq!(server.post("/check", &hash(3).to_bytes())["exists"], true);
+
+    assert_eq!(server.request("GET", "/jobs/unknown", &[]).0, 404);
+    assert_eq!(server.request("POST", "/add-batch", &hash(1).to_bytes()[..10]).0, 400);
+
+    // A retry with the same Idempotency-Key gets the first job instead of starting another
+    let key = [("Idempotency-Key", "batch-1")];
+    let (status, headers, first) = server.send_with_headers(None, "POST", "/add-batch", &key, &raw(&[hash(6)]));
+    assert_eq!(status, 202);
+    let location = headers.iter().find(|(name, _)| name == "location").cloned();
+    let (status, headers, retry) = server.send_with_headers(None, "POST", "/add-batch", &key, &raw(&[hash(6)]));
+    assert_eq!((status, &first), (202, &retry));
+    assert!(headers.contains(&("idempotent-replayed".into(), "true".into())));
+    assert_eq!(headers.iter().find(|(name, _)| name == "location").cloned(), location);
+    assert_eq!(server.send_with_headers(None, "POST", "/add-batch", &key, &raw(&[hash(7)])).0, 422);
+
+    // JSON arrays of hex or base64 digests are decoded as they arrive, the hashes before an invalid entry are still added
+    let hex = hash(4).to_string();
+    let base64 = base64::engine::general_purpose::STANDARD.encode(hash(5).to_bytes());
+    let (status, job) = server.request("POST", "/add-batch?format=json", format!(" [\"{}\",\n\"{}\"] ", hex, base64).as_bytes());
+    assert_eq!((status, &job["total_hashes"]), (202, &2.into()));
+    let (status, error) = server.request("POST", "/add-batch?format=json", format!("[\"{}\", 7]", base64).as_bytes());
+    assert_eq!((status, &error["error"]["code"]), (400, &serde_json::json!("invalid_hash")));
+    assert_eq!(error["error"]["details"]["total_hashes"], 1);
+    let path = format!("/jobs/{}", error["error"]["details"]["job"].as_str().unwrap());
+    while server.get(&path)["state"] == "running" {
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    let job = server.get(&path);
+    assert_eq!((&job["state"], &job["existing_hashes"]), (&"failed".into(), &1.into()));
+    assert_eq!(server.post("/check", &hash(4).to_bytes())["exists"], true);
+}
+
+#[test]
+fn test_mountain_range() {
+    let env = [("TIMESTAMPING_ACCUMULATOR", "mmr")];
+    let mut server = Server::start_with_env("mmr", &env);
+    server.post("/add", &raw(&[hash(1), hash(2)]));
+    server.post("/update-tree", &[]);
+    let checked = server.post("/check?tree_version=0", &hash(1).to_bytes());
+    let (status, body) = server.request("GET", "/replication/head", &[]);
+    assert_eq!((status, &body["error"]["code"]), (501, &serde_json::json!("replication_unsupported")));
+    assert_eq!(server.request("GET", "/replication/leaves/0", &[]).0, 501);
+
+    // The range and its versions are rebuilt on restart
+    server.restart(&env);
+    assert_eq!(server.post("/check?tree_version=0", &hash(1).to_bytes())["merkle_proof"], checked["merkle_proof"]);
+}
+
+#[test]
+fn test_admin_export() {
+    let server = Server::start_with_env("export", &[("TIMESTAMPING_ADMIN_TOKEN", "secret")]);
+    server.post("/add", &raw(&[hash(1), hash(2), hash(3)]));
+    assert_eq!(server.send_as(None, "GET", "/admin/export", &[]).0, 401);
+    let (status, body) = server.send_as(Some("secret"), "GET", "/admin/export", &[]);
+    assert_eq!(status, 200);
+
+    // Unpublished hashes are exported too, as salted leaves of this store
+    let salt = Hash512::from_bytes(&bytes(&server.get("/info")["salt"])).unwrap();
+    let (header, leaves) = read_snapshot(body.as_slice(), Some(&salt)).unwrap();
+    assert_eq!(header.count, 3);
+    assert!(leaves.windows(2).all(|pair| pair[0] < pair[1]));
+    assert!(matches!(read_snapshot(&body[..body.len() - 1], Some(&salt)), Err(SnapshotError::Truncated(_))));
+
+    // Once published, the tree over the export has the published root
+    assert_eq!(server.send_as(Some("secret"), "POST", "/update-tree", &[]).0, 200);
+    let export = server.dir.join("export.bin");
+    std::fs::write(&export, server.send_as(Some("secret"), "GET", "/admin/export", &[]).1).unwrap();
+    let root = Hash512::from_bytes(&bytes(&server.get("/roots/0")["root"]["root"])).unwrap();
+    let verify = |root: &Hash512| Command::new(env!("CARGO_BIN_EXE_timestamping-verify")).arg(&export).args(["--root", &root.to_string()]).output().unwrap();
+    assert!(verify(&root).status.success());
+    assert_eq!(verify(&salt).status.code(), Some(1));
+
+    // It imports into a store with the same salt, but not into another one
+    let import = |store_config: Option<&PathBuf>| {
+        let dir = server.dir.join("import");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("data")).unwrap();
+        if let Some(store_config) = store_config {
+            std::fs::copy(store_config, dir.join("data/store.json")).unwrap();
+        }
+        let import = |dir: &PathBuf| Command::new(env!("CARGO_BIN_EXE_timestamping"))
+            .args(["--import", export.to_str().unwrap()])
+            .current_dir(dir)
+            .env_clear()
+            .env("TIMESTAMPING_INDEX_SIZE", TEST_INDEX_SIZE)
+            .output()
+            .unwrap();
+        [import(&dir), import(&dir)]
+    };
+    let [first, second] = import(Some(&server.dir.join("data/store.json")));
+    assert!(String::from_utf8_lossy(&first.stdout).contains("Imported 3 new of the 3 hashes"), "{:?}", first);
+    assert!(String::from_utf8_lossy(&second.stdout).contains("Imported 0 new of the 3 hashes"), "{:?}", second);
+    let [other, _] = import(None);
+    assert_eq!(other.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&other.stderr).contains("another salt"), "{:?}", other);
+}
+
+#[test]
+fn test_admin_verify_store() {
+    let mut server = Server::start_with_env("verify-store", &[("TIMESTAMPING_ADMIN_TOKEN", "secret")]);
+    server.post("/add", &raw(&[hash(1), hash(2), hash(3)]));
+    // With an admin token, publishing needs it too
+    assert_eq!(server.send_as(None, "POST", "/update-tree", &[]).0, 401);
+    assert_eq!(server.send_as(None, "POST", "/tenants/update-tree", &[]).0, 401);
+    assert_eq!(server.send_as(Some("secret"), "POST", "/update-tree", &[]).0, 200);
+    assert_eq!(server.send_as(None, "POST", "/admin/verify-store", &[]).0, 401);
+    let (status, body) = server.send_as(Some("secret"), "POST", "/admin/verify-store", &[]);
+    assert_eq!(status, 200);
+    let check: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(check["hashes"], 3);
+    assert_eq!((&check["tree_version"], &check["root_matches"]), (&0.into(), &true.into()));
+    assert_eq!(check["discrepancies"], serde_json::json!([]));
+
+    // The same check runs on the data directory with --self-check, which exits instead of serving
+    let _ = server.process.kill();
+    let _ = server.process.wait();
+    let output = Command::new(env!("CARGO_BIN_EXE_timestamping"))
+        .arg("--self-check")
+        .current_dir(&server.dir)
+        .env_clear()
+        .env("TIMESTAMPING_INDEX_SIZE", TEST_INDEX_SIZE)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("3 hashes stored") && stdout.contains("Tree version 0 rebuilt with the published root"), "{}", stdout);
+}
+
+#[test]
+fn test_audit_log() {
+    let server = Server::start("audit-log");
+    server.post("/add", &raw(&[hash(1)]));
+    server.post("/update-tree", &[]);
+    server.post("/add", &raw(&[hash(2)]));
+    server.post("/update-tree", &[]);
+
+    let log = server.get("/audit-log");
+    assert_eq!(log["total"], 2);
+    let entries: Vec<RootLogEntry> = serde_json::from_value(log["entries"].clone()).unwrap();
+    assert!(verify_chain(&entries).is_ok());
+    let roots = server.get("/roots");
+    for (entry, root) in entries.iter().zip(roots["roots"].as_array().unwrap()) {
+        assert_eq!(entry.version, root["version"]);
+        assert_eq!(entry.root.unwrap().to_bytes(), bytes(&root["root"]));
+        assert_eq!(entry.signature.as_deref().unwrap(), bytes(&root["signature"]));
+    }
+    assert_eq!(server.get("/audit-log?offset=1")["entries"][0]["previous_hash"].as_str(), Some(entries[0].hash.as_str()));
+}
+
+#[test]
+fn test_tree_update_subscription() {
+    let server = Server::start("subscription");
+    let mut socket = TcpStream::connect(&server.address).unwrap();
+    socket.set_read_timeout(Some(STARTUP_TIMEOUT)).unwrap();
+    let handshake = format!(
+        "GET /ws HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n",
+        server.address,
+    );
+    socket.write_all(handshake.as_bytes()).unwrap();
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        let mut byte = [0];
+        socket.read_exact(&mut byte).unwrap();
+        head.push(byte[0]);
+    }
+    assert!(head.starts_with(b"HTTP/1.1 101"), "{}", String::from_utf8_lossy(&head));
+
+    server.post("/add", &raw(&[hash(1), hash(2)]));
+    server.post("/update-tree", &[]);
+    // A single unmasked text frame from the server
+    let mut header = [0; 2];
+    socket.read_exact(&mut header).unwrap();
+    assert_eq!(header[0], 0x81);
+    let len = match header[1] {
+        126 => {
+            let mut len = [0; 2];
+            socket.read_exact(&mut len).unwrap();
+            u16::from_be_bytes(len) as usize
+        }
+        len => len as usize,
+    };
+    let mut payload = vec![0; len];
+    socket.read_exact(&mut payload).unwrap();
+    let event: Value = serde_json::from_slice(&payload).unwrap();
+    assert_eq!((&event["version"], &event["hash_count"]), (&0.into(), &2.into()));
+
+    // Signed like the head in /roots/{version}, by the key in /info
+    let root = server.get("/roots/0")["root"].clone();
+    assert_eq!(event["signature"], root["signature"]);
+    let info = server.get("/info");
+    assert_eq!(event["key_id"], info["public_key"]);
+    let key = VerifyingKey::from_bytes(&bytes(&event["key_id"]).try_into().unwrap()).unwrap();
+    let signature = Signature::from_slice(&bytes(&event["signature"])).unwrap();
+    let time_source: Option<TimeAttestation> = serde_json::from_value(root["time_source"].clone()).unwrap();
+    let time_source = time_source.as_ref().map(TimeAttestation::summary);
+    let message = tree_head_message(
+        info["tree_hasher"].as_str().unwrap(), 0, 2, event["timestamp"].as_u64().unwrap(), &hash512(&event["merkle_tree_root"]), time_source.as_deref(),
+    );
+    assert!(key.verify_strict(&message, &signature).is_ok());
+}
+
+#[test]
+fn test_conditional_requests() {
+    let server = Server::start("conditional");
+    let header = |headers: &[(String, String)], name: &str| headers.iter().find(|(found, _)| found == name).map(|(_, value)| value.clone());
+    let get = |path: &str, headers: &[(&str, &str)]| server.send_with_headers(None, "GET", path, headers, &[]);
+
+    let (status, headers, _) = get("/roots", &[]);
+    assert_eq!(status, 200);
+    assert_eq!(header(&headers, "cache-control").as_deref(), Some("no-cache"));
+    let etag = header(&headers, "etag").unwrap();
+    let (status, headers, body) = get("/roots", &[("If-None-Match", &etag)]);
+    assert_eq!((status, body.len()), (304, 0));
+    assert_eq!(header(&headers, "etag"), Some(etag.clone()));
+
+    // Publishing a tree changes the roots and stats, but not the info
+    let (_, stats_headers, _) = get("/stats", &[]);
+    let stats_etag = header(&stats_headers, "etag").unwrap();
+    assert!(stats_etag.starts_with("W/"));
+    assert_eq!(get("/stats", &[("If-None-Match", &stats_etag)]).0, 304);
+    // Dates within the second the server started aren't sent, later changes in that second would have the same one
+    std::thread::sleep(Duration::from_secs(1));
+    let (_, info_headers, _) = get("/info", &[]);
+    let info_etag = header(&info_headers, "etag").unwrap();
+    let last_modified = header(&info_headers, "last-modified").unwrap();
+    server.post("/add", &raw(&[hash(1)]));
+    server.post("/update-tree", &[]);
+    assert_eq!(get("/roots", &[("If-None-Match", &etag)]).0, 200);
+    assert_eq!(get("/stats", &[("If-None-Match", &stats_etag)]).0, 200);
+    assert_eq!(get("/info", &[("If-None-Match", &info_etag)]).0, 304);
+    assert_eq!(get("/info", &[("If-Modified-Since", &last_modified)]).0, 304);
+    assert_eq!(get("/info", &[("If-Modified-Since", "Thu, 01 Jan 1970 00:00:00 GMT")]).0, 200);
+
+    // CBOR responses are another representation with their own ETag
+    let (_, headers, _) = get("/info", &[("Accept", "application/cbor")]);
+    assert_ne!(header(&headers, "etag"), Some(info_etag));
+}
+
+#[test]
+fn test_compression() {
+    let server = Server::shared();
+    let header = |headers: &[(String, String)], name: &str| headers.iter().find(|(found, _)| found == name).map(|(_, value)| value.clone());
+    let (_, headers, plain) = server.send_with_headers(None, "GET", "/openapi.json", &[], &[]);
+    assert_eq!(header(&headers, "content-encoding"), None);
+    assert!(headers.iter().any(|(name, value)| name == "vary" && value.contains("accept-encoding")), "{:?}", headers);
+
+    for (accept_encoding, encoding, magic) in [("gzip", "gzip", &[0x1f, 0x8b][..]), ("gzip, zstd", "zstd", &[0x28, 0xb5, 0x2f, 0xfd][..])] {
+        let (status, headers, body) = server.send_with_headers(None, "GET", "/openapi.json", &[("Accept-Encoding", accept_encoding)], &[]);
+        assert_eq!(status, 200);
+        assert_eq!(header(&headers, "content-encoding").as_deref(), Some(encoding));
+        assert!(body.starts_with(magic) && body.len() < plain.len() / 2, "{} of {} bytes", body.len(), plain.len());
+    }
+
+    // Small responses aren't compressed
+    let (status, headers, _) = server.send_with_headers(None, "GET", "/roots", &[("Accept-Encoding", "gzip")], &[]);
+    assert_eq!(status, 200);
+    assert_eq!(header(&headers, "content-encoding"), None);
+}
+
+#[test]
+fn test_docs() {
+    // Without Swagger UI the docs are a page of their own, loading nothing from elsewhere
+    let server = Server::shared();
+    let (status, page) = server.send("GET", "/docs", &[]);
+    let page = String::from_utf8(page).unwrap();
+    assert_eq!(status, 200);
+    assert!(page.contains("openapi.json") && !page.contains("https://"), "{}", page);
+    assert_eq!(server.send("GET", "/docs/swagger-ui.css", &[]).0, 404);
+
+    let assets = std::env::temp_dir().join(format!("timestamping-http-swagger-ui-{}", std::process::id()));
+    std::fs::create_dir_all(&assets).unwrap();
+    std::fs::write(assets.join("swagger-ui.css"), "body {}").unwrap();
+    std::fs::write(assets.join("swagger-ui-bundle.js"), "// bundle").unwrap();
+    std::fs::write(assets.join("index.html"), "<html>").unwrap();
+    let server = Server::start_with_env("docs", &[("TIMESTAMPING_SWAGGER_UI_DIR", assets.to_str().unwrap())]);
+    let (_, page) = server.send("GET", "/docs", &[]);
+    assert!(String::from_utf8(page).unwrap().contains("src=\"docs/swagger-ui-bundle.js\""));
+    let (status, headers, body) = server.send_with_headers(None, "GET", "/docs/swagger-ui.css", &[], &[]);
+    assert_eq!((status, body.as_slice()), (200, &b"body {}"[..]));
+    assert!(headers.contains(&("content-type".to_string(), "text/css".to_string())));
+    // Only the assets of the page are served from the directory
+    assert_eq!(server.send("GET", "/docs/index.html", &[]).0, 404);
+    drop(server);
+    std::fs::remove_dir_all(&assets).unwrap();
+}
+
+#[test]
+#[cfg(not(feature = "client"))]
+fn test_client_settings_need_client_feature() {
+    let dir = std::env::temp_dir().join(format!("timestamping-http-client-settings-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_timestamping"))
+        .current_dir(&dir)
+        .env_clear()
+        .env("TIMESTAMPING_BACKUP_URL", "https://example.com/bucket")
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(2));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("TIMESTAMPING_BACKUP_URL need the `client` feature"), "{}", stderr);
+    // Refused before the data directory is created
+    assert!(!dir.join("data").exists());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_api_key_roles() {
+    let server = Server::start_with_env("roles", &[("TIMESTAMPING_API_KEYS", "reader=r-key,submitter=s-key,admin=a-key")]);
+    let status = |key, method, path: &str, body: &[u8]| server.send_as(key, method, path, body).0;
+    let hash = hash(1).to_bytes();
+
+    // Every route needs a key but the health check and the docs
+    assert_eq!(status(None, "GET", "/healthz", &[]), 200);
+    assert_eq!(status(None, "GET", "/openapi.json", &[]), 200);
+    assert_eq!(status(None, "GET", "/docs", &[]), 200);
+    assert_eq!(status(None, "GET", "/stats", &[]), 401);
+    assert_eq!(status(Some("wrong"), "GET", "/stats", &[]), 401);
+    assert_eq!(status(Some("r-key"), "GET", "/stats", &[]), 200);
+
+    // Each role can do what the roles below it can
+    assert_eq!(status(Some("r-key"), "POST", "/add", &hash), 403);
+    assert_eq!(status(Some("s-key"), "POST", "/add", &hash), 200);
+    assert_eq!(status(Some("s-key"), "POST", "/check", &hash), 200);
+    assert_eq!(status(Some("s-key"), "POST", "/update-tree", &[]), 403);
+    assert_eq!(status(Some("s-key"), "GET", "/admin/log", &[]), 403);
+    assert_eq!(status(Some("a-key"), "GET", "/admin/log", &[]), 200);
+    assert_eq!(status(Some("a-key"), "POST", "/add", &hash), 200);
+}
+
+#[test]
+#[cfg(feature = "client")]
+fn test_peer_cosigning() {
+    let key = SigningKey::from_bytes(&[1; 32]);
+    let peer_key = SigningKey::from_bytes(&[2; 32]);
+    let hex = |key: &SigningKey| timestamping::verify::encode_hex(&key.verifying_key().to_bytes());
+    // The peer only cosigns for the server here, it doesn't publish trees of its own
+    let peer = Server::start_with_files("cosign-peer", &[("TIMESTAMPING_PEERS", &format!("{}@http://127.0.0.1:1", hex(&key)))], &[("signing.key", &peer_key.to_bytes())]);
+    let server = Server::start_with_files("cosign", &[("TIMESTAMPING_PEERS", &format!("{}@http://{}", hex(&peer_key), peer.address))], &[("signing.key", &key.to_bytes())]);
+
+    server.post("/add", &hash(1).to_bytes());
+    server.post("/update-tree", &[]);
+    let start = Instant::now();
+    while server.get("/roots/0")["root"]["cosignatures"].as_array().unwrap().is_empty() {
+        assert!(start.elapsed() < STARTUP_TIMEOUT, "Tree head wasn't cosigned within {:?}", STARTUP_TIMEOUT);
+        std::thread::sleep(Duration::from_millis(20));
+    }
+
+    let (status, body) = server.send("POST", "/wait", &hash(1).to_bytes());
+    assert_eq!(status, 200);
+    let receipt = Receipt::from_json(std::str::from_utf8(&body).unwrap()).unwrap();
+    assert_eq!(receipt.verify(&key.verifying_key()), Ok(()));
+    assert!(receipt.verify_cosignature(&peer_key.verifying_key()).is_ok());
+
+    // Heads of other servers and back-dated heads aren't cosigned
+    let mut head: Value = serde_json::json!({
+        "tree_hasher": "sha512", "version": 0, "leaf_count": 1, "timestamp": 1_000_000_000,
+        "root": "00".repeat(64), "time_source": null, "public_key": hex(&key), "signature": "00".repeat(64),
+    });
+    assert_eq!(peer.request("POST", "/cosign", head.to_string().as_bytes()).0, 409);
+    head["public_key"] = Value::String(hex(&peer_key));
+    assert_eq!(peer.request("POST", "/cosign", head.to_string().as_bytes()).0, 403);
+}
+
+
+// Head and body of one request to `listener`, answered with `status`
+#[cfg(feature = "client")]
+fn receive(listener: &TcpListener, status: u16) -> (String, Value) {
+    let (mut stream, _) = listener.accept().unwrap();
+    let mut request = Vec::new();
+    let mut buffer = [0; 4096];
+    let (head, length) = loop {
+        let read = stream.read(&mut buffer).unwrap();
+        assert!(read > 0, "Connection closed before the end of the request");
+        request.extend_from_slice(&buffer[..read]);
+        if let Some(split) = request.windows(4).position(|window| window == b"\r\n\r\n") {
+            let head = String::from_utf8_lossy(&request[..split]).to_string();
+            let length: usize = head.lines()
+                .find_map(|line| line.to_ascii_lowercase().strip_prefix("content-length:").map(|value| value.trim().parse().unwrap()))
+                .unwrap_or(0);
+            request.drain(..split + 4);
+            break (head, length);
+        }
+    };
+    while request.len() < length {
+        let read = stream.read(&mut buffer).unwrap();
+        assert!(read > 0, "Connection closed before the end of the body");
+        request.extend_from_slice(&buffer[..read]);
+    }
+    stream.write_all(format!("HTTP/1.1 {} Status\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", status).as_bytes()).unwrap();
+    (head, serde_json::from_slice(&request).unwrap())
+}
+
+#[test]
+#[cfg(feature = "client")]
+fn test_webhooks() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/hook", listener.local_addr().unwrap());
+    let server = Server::start_with_env("webhooks", &[("TIMESTAMPING_WEBHOOKS", &url), ("TIMESTAMPING_ADMIN_TOKEN", "secret")]);
+
+    // Configured webhooks are listed, but can only be removed from the configuration
+    let (status, body) = server.send_as(Some("secret"), "GET", "/admin/webhooks", &[]);
+    assert_eq!(status, 200);
+    assert_eq!(serde_json::from_slice::<Value>(&body).unwrap()["configured"], serde_json::json!([url]));
+    assert_eq!(server.send_as(Some("secret"), "DELETE", &format!("/admin/webhooks?url={}", url), &[]).0, 400);
+    assert_eq!(server.send_as(Some("secret"), "POST", "/admin/webhooks?url=ftp://example", &[]).0, 400);
+    assert_eq!(server.send_as(None, "POST", "/admin/webhooks?url=http://example", &[]).0, 401);
+    let (status, body) = server.send_as(Some("secret"), "POST", "/admin/webhooks?url=http://127.0.0.1:1/other", &[]);
+    assert_eq!(status, 200);
+    assert_eq!(serde_json::from_slice::<Value>(&body).unwrap()["registered"], serde_json::json!(["http://127.0.0.1:1/other"]));
+    assert_eq!(server.send_as(Some("secret"), "DELETE", "/admin/webhooks?url=http://127.0.0.1:1/other", &[]).0, 200);
+    assert_eq!(server.send_as(Some("secret"), "DELETE", "/admin/webhooks?url=http://127.0.0.1:1/other", &[]).0, 404);
+
+    server.post("/add", &hash(1).to_bytes());
+    assert_eq!(server.send_as(Some("secret"), "POST", "/update-tree", &[]).0, 200);
+    // Failed deliveries are retried
+    let (_, first) = receive(&listener, 503);
+    let (head, event) = receive(&listener, 200);
+    assert_eq!(first, event);
+    assert!(head.starts_with("POST /hook "), "{}", head);
+    assert_eq!(event["event"], "tree_published");
+    assert_eq!(event["tree_head"]["version"], 0);
+    let root = bytes(&server.get("/roots/0")["root"]["root"]);
+    assert_eq!(event["tree_head"]["root"], timestamping::verify::encode_hex(&root));
+}
+
+#[test]
+#[cfg(feature = "sqlite")]
+fn test_sqlite_storage() {
+    let env = [("TIMESTAMPING_STORAGE", "sqlite")];
+    let mut server = Server::start_with_env("sqlite", &env);
+    let hashes: Vec<Hash512> = (1..=3).map(hash).collect();
+    let added = server.post("/add?label=invoice", &raw(&[hashes[0], hashes[1], hashes[1]]));
+    assert_eq!(added["statuses"], serde_json::json!(["new", "new", "duplicate"]));
+    assert_eq!(server.post("/add", &raw(&[hashes[1], hashes[2]]))["statuses"], serde_json::json!(["existing", "new"]));
+    assert_eq!(server.request("POST", "/add?leaf_version=2", &hashes[0].to_bytes()).0, 400);
+    // Only the basic routes exist
+    assert_eq!(server.send("POST", "/add-batch", &[]).0, 404);
+
+    let updated = server.post("/update-tree", &[]);
+    assert_eq!(updated["hash_count"], 3);
+    let root = hash512(&server.get("/stats")["merkle_tree_root"]);
+    assert_eq!(hash512(&server.get("/roots/0")["root"]), root);
+
+    // Hashes, metadata and tree heads are in the database after a restart
+    server.restart(&env);
+    assert_eq!(server.get("/stats")["count"], 3);
+    assert_eq!(hash512(&server.get("/roots/0")["root"]), root);
+    let checked = server.post("/update-tree", &[]);
+    assert_eq!(checked["hash_count"], 3);
+    let root = hash512(&server.get("/stats")["merkle_tree_root"]);
+    let checked = server.post("/check", &hashes[0].to_bytes());
+    assert_eq!(checked["metadata"]["label"], "invoice");
+    assert!(verifies(&checked, &checked, &hashes[0], &root));
+    assert_eq!(server.request("GET", "/roots/9", &[]).0, 404);
+}
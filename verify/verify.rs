@@ -0,0 +1,201 @@
+use std::io::BufReader;
+use std::path::Path;
+use timestamping::receipt::{Receipt, VerifyingKey, tree_head_message};
+use timestamping::snapshot::read_snapshot;
+use timestamping::storage::{EpochLog, EpochSummary, Hash512, MerkleTree, StoreConfig, decode_hex, hasher_from_name, leaf_log_lengths, read_leaf_logs, read_leaf_sequences};
+
+const USAGE: &str = "Usage: timestamping-verify <snapshot> [--root HEX] [--epoch N] [--key HEX] [--hasher NAME] [receipt...]
+
+Rebuilds a published merkle tree from a copy of a server's data directory (store.json, epochs.jsonl
+and the leaves-*.bin logs) and checks that its root matches the published one. The snapshot can also be
+a file from /admin/export, whose tree over all its hashes matches the latest root if nothing was added since.
+
+Options:
+  --root HEX     Root published by the server, e.g. from /roots or a transparency log. Defaults to the
+                 root recorded in epochs.jsonl, which only shows the snapshot is consistent with itself.
+                 Required for an export, which doesn't record roots
+  --epoch N      Tree version to rebuild, the one with the given root or the latest by default
+  --key HEX      Public key of the server from /info, to check the signature of the tree head and receipts
+  --hasher NAME  Tree hasher of the server from /info, for an export (default sha512-rfc6962)
+
+The sequence numbers of the leaves (sequences-*.bin) are checked to increase within each log and to be unique,
+as a server that only ever appends hashes writes them. Every given receipt is checked against the rebuilt root.";
+
+fn from_hex_key(hex: &str) -> Result<VerifyingKey, String> {
+    let bytes: [u8; 32] = decode_hex(hex.as_bytes()).and_then(|bytes| bytes.try_into().ok())
+        .ok_or(format!("Invalid key '{}': expected 64 hex characters", hex))?;
+    VerifyingKey::from_bytes(&bytes).map_err(|_| format!("Invalid key '{}': not an ed25519 public key", hex))
+}
+
+// Epoch to rebuild: the requested one, else the one with the trusted root, else the latest
+fn select_epoch<'a>(summaries: &'a [EpochSummary], epoch: Option<u64>, root: Option<&Hash512>) -> Result<&'a EpochSummary, String> {
+    match (epoch, root) {
+        (Some(epoch), _) => summaries.iter().find(|summary| summary.epoch == epoch).ok_or(format!("Epoch {} is not in the snapshot", epoch)),
+        (None, Some(root)) => summaries.iter().rev().find(|summary| summary.root.as_ref() == Some(root))
+            .ok_or("No epoch in the snapshot has this root, give its number with --epoch".to_string()),
+        (None, None) => summaries.last().ok_or("The snapshot has no published trees".to_string()),
+    }
+}
+
+fn verify_tree_head(summary: &EpochSummary, tree_hasher: &str, root: &Hash512, key: &VerifyingKey) -> bool {
+    let Some(signature) = summary.signature.as_deref().and_then(|signature| ed25519_dalek::Signature::from_slice(signature).ok()) else {
+        return false;
+    };
+    let time_source = summary.time_source.as_ref().map(|attestation| attestation.summary());
+    let message = tree_head_message(tree_hasher, summary.epoch, summary.leaf_count as u64, summary.timestamp, root, time_source.as_deref());
+    ed25519_dalek::Verifier::verify(key, &message, &signature).is_ok()
+}
+
+fn verify_receipt(path: &Path, root: &Hash512, key: Option<&VerifyingKey>) -> Result<(), String> {
+    let receipt = Receipt::parse(&std::fs::read(path).map_err(|e| e.to_string())?).map_err(|e| e.to_string())?;
+    if receipt.merkle_tree_root != *root {
+        return Err(format!("receipt is for root {}, not the rebuilt one", receipt.merkle_tree_root));
+    }
+    match key {
+        Some(key) => receipt.verify(key).map_err(|e| e.to_string()),
+        None => receipt.verify_proof().map_err(|e| e.to_string()),
+    }
+}
+
+// Whether the sequence numbers of every log increase and no number is given twice
+fn sequences_are_append_only(sequences: &[Vec<u64>]) -> bool {
+    let mut all: Vec<u64> = sequences.concat();
+    all.sort_unstable();
+    sequences.iter().all(|log| log.windows(2).all(|pair| pair[0] < pair[1])) && all.windows(2).all(|pair| pair[0] != pair[1])
+}
+
+// Rebuild the tree over every hash of an /admin/export snapshot and return its root and whether it matches `root`
+fn verify_export(path: &Path, root: &Hash512, hasher: &str) -> Result<(Hash512, bool), String> {
+    let hasher = hasher_from_name(hasher).ok_or(format!("Unknown tree hasher {}", hasher))?;
+    let file = std::fs::File::open(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    let (_, leaves) = read_snapshot(BufReader::new(file), None).map_err(|e| format!("Invalid export: {}", e))?;
+    // The salt only goes into proofs, the root is over the salted leaves
+    let tree = MerkleTree::with_hasher(leaves, Hash512([0; 8]), hasher);
+    let rebuilt = tree.root().ok_or("The export has no hashes")?;
+    println!("Rebuilt tree from {} exported leaves with root {}", tree.leaf_count, rebuilt);
+    if rebuilt == *root {
+        println!("Root matches the published root");
+        Ok((rebuilt, true))
+    } else {
+        println!("Root DOES NOT match the published root {}", root);
+        Ok((rebuilt, false))
+    }
+}
+
+// Returns whether every check passed
+fn run(args: Vec<String>) -> Result<bool, String> {
+    let mut root = None;
+    let mut epoch = None;
+    let mut key = None;
+    let mut hasher = "sha512-rfc6962".to_string();
+    let mut positional = Vec::new();
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--root" => {
+                let hex = args.next().ok_or("--root needs a hex hash")?;
+                root = Some(hex.parse::<Hash512>().map_err(|e| format!("Invalid hash '{}': {}", hex, e))?);
+            }
+            "--epoch" => epoch = Some(args.next().and_then(|epoch| epoch.parse::<u64>().ok()).ok_or("--epoch needs a number")?),
+            "--key" => key = Some(from_hex_key(&args.next().ok_or("--key needs a hex public key")?)?),
+            "--hasher" => hasher = args.next().ok_or("--hasher needs a name")?,
+            "-h" | "--help" => return Err(USAGE.to_string()),
+            _ => positional.push(arg),
+        }
+    }
+    let Some((snapshot, receipts)) = positional.split_first() else {
+        return Err(USAGE.to_string());
+    };
+    let dir = Path::new(snapshot);
+    let (rebuilt, mut valid) = if dir.is_file() {
+        if epoch.is_some() {
+            return Err("An export only has the latest hashes, --epoch needs a data directory".to_string());
+        }
+        verify_export(dir, &root.ok_or("An export doesn't record roots, give the published one with --root")?, &hasher)?
+    } else {
+        verify_data_dir(dir, root, epoch, key.as_ref())?
+    };
+
+    for receipt in receipts {
+        match verify_receipt(Path::new(receipt), &rebuilt, key.as_ref()) {
+            Ok(()) => println!("{}: valid", receipt),
+            Err(e) => {
+                println!("{}: INVALID, {}", receipt, e);
+                valid = false;
+            }
+        }
+    }
+    Ok(valid)
+}
+
+// Rebuild a published tree from a copy of the data directory and return its root and whether every check passed
+fn verify_data_dir(dir: &Path, root: Option<Hash512>, epoch: Option<u64>, key: Option<&VerifyingKey>) -> Result<(Hash512, bool), String> {
+    let config: StoreConfig = serde_json::from_str(&std::fs::read_to_string(StoreConfig::path(dir)).map_err(|e| format!("Failed to read store.json: {}", e))?)
+        .map_err(|e| format!("Invalid store.json: {}", e))?;
+    let hasher = hasher_from_name(&config.hasher).ok_or(format!("Unknown tree hasher {}", config.hasher))?;
+    let epochs = EpochLog::open(&dir.join("epochs.jsonl")).map_err(|e| format!("Failed to read epochs.jsonl: {}", e))?;
+    let summary = select_epoch(epochs.page(0, epochs.len()), epoch, root.as_ref())?;
+
+    // Epochs from before the log lengths were recorded can only be rebuilt if nothing was added since
+    let lengths = match summary.leaf_log_lengths.as_slice() {
+        [] => {
+            let lengths = leaf_log_lengths(dir, config.threads).map_err(|e| format!("Failed to read the leaf logs: {}", e))?;
+            if lengths.iter().sum::<u64>() != summary.leaf_count as u64 {
+                return Err(format!("Epoch {} doesn't record the lengths of the leaf logs, which grew since", summary.epoch));
+            }
+            lengths
+        }
+        lengths => lengths.to_vec(),
+    };
+    let leaves = read_leaf_logs(dir, &lengths).map_err(|e| format!("Failed to read the leaf logs: {}", e))?;
+    let tree = MerkleTree::with_hasher(leaves, config.salt, hasher);
+    let Some(rebuilt) = tree.root() else {
+        return Err(format!("Epoch {} has no leaves", summary.epoch));
+    };
+    println!("Rebuilt tree of epoch {} from {} leaves with root {}", summary.epoch, tree.leaf_count, rebuilt);
+
+    let mut valid = true;
+    let published = match root {
+        Some(root) => root,
+        None => {
+            eprintln!("Warning: no --root given, comparing to the root recorded in the snapshot");
+            summary.root.ok_or(format!("Epoch {} doesn't record its root", summary.epoch))?
+        }
+    };
+    if rebuilt == published {
+        println!("Root matches the published root");
+    } else {
+        println!("Root DOES NOT match the published root {}", published);
+        valid = false;
+    }
+    match read_leaf_sequences(dir, &lengths) {
+        Ok(sequences) if sequences_are_append_only(&sequences) => println!("Sequence numbers of the leaves are in append-only order"),
+        Ok(_) => {
+            println!("Sequence numbers of the leaves are NOT in append-only order");
+            valid = false;
+        }
+        // Snapshots of servers from before sequence numbers were recorded
+        Err(e) => eprintln!("Warning: sequence numbers not checked, {}", e),
+    }
+    if let Some(key) = key {
+        if verify_tree_head(summary, &config.hasher, &rebuilt, key) {
+            println!("Tree head is signed by the key");
+        } else {
+            println!("Tree head signature is INVALID or missing");
+            valid = false;
+        }
+    }
+    Ok((rebuilt, valid))
+}
+
+fn main() {
+    match run(std::env::args().skip(1).collect()) {
+        Ok(true) => {}
+        Ok(false) => std::process::exit(1),
+        Err(message) => {
+            eprintln!("{}", message);
+            std::process::exit(2);
+        }
+    }
+}